@@ -0,0 +1,191 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # The return value of `hf_vcpu_run`.
+//!
+//! The primary VM's scheduler is told what to do next with an `HfVCpuRunReturn`, which is packed
+//! into a single register as `hf_vcpu_run_return_encode()` in `inc/vmapi/hf/abi.h` does: the code
+//! is in bits [7:0], and the data of some codes in the bits above.  The C API returns the same
+//! value as `struct hf_vcpu_run_return`, which converts to and from `HfVCpuRunReturnRaw`.
+
+/// The code of an `HfVCpuRunReturn`, as `enum hf_vcpu_run_code`.
+const HF_VCPU_RUN_PREEMPTED: u32 = 0;
+const HF_VCPU_RUN_YIELD: u32 = 1;
+const HF_VCPU_RUN_WAIT_FOR_INTERRUPT: u32 = 2;
+const HF_VCPU_RUN_WAIT_FOR_MESSAGE: u32 = 3;
+const HF_VCPU_RUN_WAKE_UP: u32 = 4;
+const HF_VCPU_RUN_MESSAGE: u32 = 5;
+const HF_VCPU_RUN_NOTIFY_WAITERS: u32 = 6;
+const HF_VCPU_RUN_ABORTED: u32 = 7;
+const HF_VCPU_RUN_NOTIFICATION: u32 = 8;
+const HF_VCPU_RUN_WRONG_CPU: u32 = 9;
+
+/// The sleep duration of a vCPU that waits with no timeout, as `HF_SLEEP_INDEFINITE`.
+pub const HF_SLEEP_INDEFINITE: u64 = 0xff_ffff_ffff_ffff;
+
+/// What the primary VM's scheduler should do after running a vCPU. See `enum hf_vcpu_run_code`
+/// for the meaning of each.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HfVCpuRunReturn {
+    Preempted,
+    Yield,
+    WaitForInterrupt { ns: u64 },
+    WaitForMessage { ns: u64 },
+    WakeUp { vm_id: u16, vcpu: u16 },
+    Message { vm_id: u16 },
+    NotifyWaiters,
+    Aborted,
+    Notification { vm_id: u16 },
+    WrongCpu,
+}
+
+/// An `HfVCpuRunReturn` as `struct hf_vcpu_run_return`. The payload is the union, whose members
+/// all start at its first byte.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct HfVCpuRunReturnRaw {
+    code: u32,
+    payload: u64,
+}
+
+impl HfVCpuRunReturn {
+    fn code(&self) -> u32 {
+        match self {
+            HfVCpuRunReturn::Preempted => HF_VCPU_RUN_PREEMPTED,
+            HfVCpuRunReturn::Yield => HF_VCPU_RUN_YIELD,
+            HfVCpuRunReturn::WaitForInterrupt { .. } => HF_VCPU_RUN_WAIT_FOR_INTERRUPT,
+            HfVCpuRunReturn::WaitForMessage { .. } => HF_VCPU_RUN_WAIT_FOR_MESSAGE,
+            HfVCpuRunReturn::WakeUp { .. } => HF_VCPU_RUN_WAKE_UP,
+            HfVCpuRunReturn::Message { .. } => HF_VCPU_RUN_MESSAGE,
+            HfVCpuRunReturn::NotifyWaiters => HF_VCPU_RUN_NOTIFY_WAITERS,
+            HfVCpuRunReturn::Aborted => HF_VCPU_RUN_ABORTED,
+            HfVCpuRunReturn::Notification { .. } => HF_VCPU_RUN_NOTIFICATION,
+            HfVCpuRunReturn::WrongCpu => HF_VCPU_RUN_WRONG_CPU,
+        }
+    }
+
+    /// Packs the return value into a register. The duration of a sleep loses its top 8 bits.
+    pub fn encode(self) -> u64 {
+        let code = u64::from(self.code());
+        match self {
+            HfVCpuRunReturn::WakeUp { vm_id, vcpu } => {
+                code | u64::from(vm_id) << 32 | u64::from(vcpu) << 16
+            }
+            HfVCpuRunReturn::Message { vm_id } | HfVCpuRunReturn::Notification { vm_id } => {
+                code | u64::from(vm_id) << 8
+            }
+            HfVCpuRunReturn::WaitForInterrupt { ns } | HfVCpuRunReturn::WaitForMessage { ns } => {
+                code | ns << 8
+            }
+            _ => code,
+        }
+    }
+
+    /// Unpacks the return value from a register, ignoring the bits that are not used by its code.
+    /// Returns `None` if the code is unknown.
+    pub fn decode(res: u64) -> Option<Self> {
+        let ret = match (res & 0xff) as u32 {
+            HF_VCPU_RUN_PREEMPTED => HfVCpuRunReturn::Preempted,
+            HF_VCPU_RUN_YIELD => HfVCpuRunReturn::Yield,
+            HF_VCPU_RUN_WAIT_FOR_INTERRUPT => HfVCpuRunReturn::WaitForInterrupt { ns: res >> 8 },
+            HF_VCPU_RUN_WAIT_FOR_MESSAGE => HfVCpuRunReturn::WaitForMessage { ns: res >> 8 },
+            HF_VCPU_RUN_WAKE_UP => HfVCpuRunReturn::WakeUp {
+                vm_id: (res >> 32) as u16,
+                vcpu: (res >> 16) as u16,
+            },
+            HF_VCPU_RUN_MESSAGE => HfVCpuRunReturn::Message {
+                vm_id: (res >> 8) as u16,
+            },
+            HF_VCPU_RUN_NOTIFY_WAITERS => HfVCpuRunReturn::NotifyWaiters,
+            HF_VCPU_RUN_ABORTED => HfVCpuRunReturn::Aborted,
+            HF_VCPU_RUN_NOTIFICATION => HfVCpuRunReturn::Notification {
+                vm_id: (res >> 8) as u16,
+            },
+            HF_VCPU_RUN_WRONG_CPU => HfVCpuRunReturn::WrongCpu,
+            _ => return None,
+        };
+
+        Some(ret)
+    }
+
+    /// Converts the C representation, ignoring the bytes of the union that are not used by its
+    /// code. Returns `None` if the code is unknown.
+    pub fn from_raw(raw: HfVCpuRunReturnRaw) -> Option<Self> {
+        let payload = raw.payload;
+        let ret = match raw.code {
+            HF_VCPU_RUN_PREEMPTED => HfVCpuRunReturn::Preempted,
+            HF_VCPU_RUN_YIELD => HfVCpuRunReturn::Yield,
+            HF_VCPU_RUN_WAIT_FOR_INTERRUPT => HfVCpuRunReturn::WaitForInterrupt { ns: payload },
+            HF_VCPU_RUN_WAIT_FOR_MESSAGE => HfVCpuRunReturn::WaitForMessage { ns: payload },
+            HF_VCPU_RUN_WAKE_UP => HfVCpuRunReturn::WakeUp {
+                vm_id: payload as u16,
+                vcpu: (payload >> 16) as u16,
+            },
+            HF_VCPU_RUN_MESSAGE => HfVCpuRunReturn::Message {
+                vm_id: payload as u16,
+            },
+            HF_VCPU_RUN_NOTIFY_WAITERS => HfVCpuRunReturn::NotifyWaiters,
+            HF_VCPU_RUN_ABORTED => HfVCpuRunReturn::Aborted,
+            HF_VCPU_RUN_NOTIFICATION => HfVCpuRunReturn::Notification {
+                vm_id: payload as u16,
+            },
+            HF_VCPU_RUN_WRONG_CPU => HfVCpuRunReturn::WrongCpu,
+            _ => return None,
+        };
+
+        Some(ret)
+    }
+
+    /// Converts to the C representation, where the unused bytes of the union are zero.
+    pub fn into_raw(self) -> HfVCpuRunReturnRaw {
+        let payload = match self {
+            HfVCpuRunReturn::WaitForInterrupt { ns } | HfVCpuRunReturn::WaitForMessage { ns } => ns,
+            HfVCpuRunReturn::WakeUp { vm_id, vcpu } => u64::from(vm_id) | u64::from(vcpu) << 16,
+            HfVCpuRunReturn::Message { vm_id } | HfVCpuRunReturn::Notification { vm_id } => {
+                u64::from(vm_id)
+            }
+            _ => 0,
+        };
+
+        HfVCpuRunReturnRaw {
+            code: self.code(),
+            payload,
+        }
+    }
+}
+
+/// Packs the return value into a register, as `hf_vcpu_run_return_encode()`. Only the code is
+/// kept if it is unknown.
+#[no_mangle]
+pub extern "C" fn hf_vcpu_run_return_pack(res: HfVCpuRunReturnRaw) -> u64 {
+    match HfVCpuRunReturn::from_raw(res) {
+        Some(ret) => ret.encode(),
+        None => u64::from(res.code & 0xff),
+    }
+}
+
+/// Unpacks the return value from a register, as `hf_vcpu_run_return_decode()`. Only the code is
+/// kept if it is unknown.
+#[no_mangle]
+pub extern "C" fn hf_vcpu_run_return_unpack(res: u64) -> HfVCpuRunReturnRaw {
+    match HfVCpuRunReturn::decode(res) {
+        Some(ret) => ret.into_raw(),
+        None => HfVCpuRunReturnRaw {
+            code: (res & 0xff) as u32,
+            payload: 0,
+        },
+    }
+}
@@ -14,10 +14,31 @@
  * limitations under the License.
  */
 
+use core::ptr;
+use core::slice;
+
+use arrayvec::ArrayVec;
+
+use crate::frame;
+use crate::mm::*;
 use crate::mpool::*;
 use crate::page::*;
 use crate::types::*;
 
+/// The SPCI return codes, as in `vmapi/hf/spci.h`.
+pub const SPCI_SUCCESS: i32 = 0;
+pub const SPCI_NOT_SUPPORTED: i32 = -1;
+pub const SPCI_INVALID_PARAMETERS: i32 = -2;
+pub const SPCI_NO_MEMORY: i32 = -3;
+pub const SPCI_BUSY: i32 = -4;
+pub const SPCI_INTERRUPTED: i32 = -5;
+pub const SPCI_DENIED: i32 = -6;
+pub const SPCI_RETRY: i32 = -7;
+
+extern "C" {
+    fn arch_mm_write_back_dcache(base: *mut u8, size: usize);
+}
+
 // To eliminate the risk of deadlocks, we define a partial order for the acquisition of locks held
 // concurrently by the same physical CPU. Our current ordering requirements are as follows:
 //
@@ -42,3 +63,356 @@ impl Api {
         }
     }
 }
+
+/// Returns the SPCI error code for a failure to update page tables.
+fn spci_error(e: MmError) -> i32 {
+    match e {
+        MmError::OutOfMemory => SPCI_NO_MEMORY,
+        MmError::AccessDenied | MmError::WriteExecute | MmError::Overlap | MmError::Arch => {
+            SPCI_DENIED
+        }
+        MmError::OutOfRange
+        | MmError::NonUniform
+        | MmError::TooManyRanges
+        | MmError::AlreadyInitialized => SPCI_INVALID_PARAMETERS,
+    }
+}
+
+/// Checks that `[begin, end)` is a non-empty range of whole pages.
+fn check_range(begin: IpaAddr, end: IpaAddr) -> Result<(), MmError> {
+    if begin >= end || begin.addr() % PAGE_SIZE != 0 || end.addr() % PAGE_SIZE != 0 {
+        return Err(MmError::OutOfRange);
+    }
+
+    Ok(())
+}
+
+/// Checks that the frame table records the VM whose page table is `ptable` as the owner of
+/// `[begin, end)`, so that a VM can't pass on memory it doesn't own even if its page table is
+/// stale. Memory the frame table doesn't track is not checked.
+fn check_owner(ptable: &PageTable<Stage2>, begin: IpaAddr, end: IpaAddr) -> Result<(), MmError> {
+    let pa_begin = PhysAddr::from_ipa(begin);
+    let pa_end = PhysAddr::from_ipa(end);
+
+    if ptable.owner() == frame::NO_OWNER
+        || !frame::tracks(pa_begin, pa_end)
+        || frame::owns(ptable.owner(), pa_begin, pa_end)
+    {
+        Ok(())
+    } else {
+        Err(MmError::AccessDenied)
+    }
+}
+
+/// Zeroes the temporarily mapped memory and writes it back from the data cache, so that no VM or
+/// device can see its previous contents.
+fn clear_memory(mapping: &TempMapping) {
+    unsafe {
+        ptr::write_bytes(mapping.as_ptr(), 0, mapping.size());
+        arch_mm_write_back_dcache(mapping.as_ptr(), mapping.size());
+    }
+}
+
+/// Checks that the memory of the given mode is owned by the VM and lent or shared with another.
+fn is_lent(mode: Mode) -> bool {
+    !mode.contains(Mode::UNOWNED) && (mode.contains(Mode::INVALID) || mode.contains(Mode::SHARED))
+}
+
+/// How memory is shared with another VM, as `enum hf_share`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum HfShare {
+    /// Relinquish ownership and access to the memory and pass them to the recipient.
+    Give = 0,
+
+    /// Retain ownership of the memory but relinquish access to the recipient.
+    Lend = 1,
+
+    /// Retain ownership of the memory and share access with the recipient.
+    Share = 2,
+}
+
+impl HfShare {
+    /// Converts the untrusted value given by a VM. Returns `None` if it is not valid.
+    pub fn from_raw(share: u32) -> Option<Self> {
+        match share {
+            0 => Some(HfShare::Give),
+            1 => Some(HfShare::Lend),
+            2 => Some(HfShare::Share),
+            _ => None,
+        }
+    }
+
+    /// Returns the modes of the memory for the sender and the recipient after sharing, where the
+    /// recipient is given the `access` mode.
+    fn modes(self, access: Mode) -> (Mode, Mode) {
+        let rwx = Mode::R | Mode::W | Mode::X;
+        let access = access & rwx;
+        match self {
+            HfShare::Give => (Mode::INVALID | Mode::UNOWNED, access),
+            HfShare::Lend => (Mode::INVALID, access | Mode::UNOWNED),
+            HfShare::Share => (rwx | Mode::SHARED, access | Mode::UNOWNED | Mode::SHARED),
+        }
+    }
+}
+
+/// A range of intermediate physical addresses, `[begin, end)`. It has the same representation as
+/// `struct mem_range`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct MemRange {
+    pub begin: IpaAddr,
+    pub end: IpaAddr,
+}
+
+/// Shares the given ranges of the memory of the VM whose page table is `from` with the VM whose
+/// page table is `to`, which is given the `access` mode to them. The page tables of both VMs should
+/// be locked.
+///
+/// The sender must own the memory and have exclusive access to it, unless it is giving the memory
+/// back to its owner. The memory is zeroed so that no VM or device can see its previous contents.
+/// Either all the ranges are shared, or none is.
+pub fn share_memory(
+    from: &mut PageTable<Stage2>,
+    to: &mut PageTable<Stage2>,
+    ranges: &[MemRange],
+    share: HfShare,
+    access: Mode,
+    mpool: &MPool,
+) -> Result<(), MmError> {
+    if ranges.is_empty() {
+        return Err(MmError::OutOfRange);
+    }
+
+    if ranges.len() > MAP_TRANSACTION_MAX_RANGES {
+        return Err(MmError::TooManyRanges);
+    }
+
+    for range in ranges {
+        check_range(range.begin, range.end)?;
+
+        // The range should be mapped with the same mode for the sender. If it is invalid, the
+        // sender has either shared it with another VM already or has no claim to the memory.
+        let orig_from_mode = from.get_mode(range.begin, range.end)?;
+        if orig_from_mode.contains(Mode::INVALID) {
+            return Err(MmError::AccessDenied);
+        }
+
+        // Memory owned by the hypervisor stays with the VMs it is shared with.
+        if orig_from_mode.contains(Mode::HYP) {
+            return Err(MmError::AccessDenied);
+        }
+
+        if orig_from_mode.contains(Mode::UNOWNED) {
+            // Only the owner may receive memory the sender doesn't own.
+            let orig_to_mode = to.get_mode(range.begin, range.end)?;
+            if share != HfShare::Give || orig_to_mode.contains(Mode::UNOWNED) {
+                return Err(MmError::AccessDenied);
+            }
+            check_owner(to, range.begin, range.end)?;
+        } else if orig_from_mode.contains(Mode::SHARED) {
+            return Err(MmError::AccessDenied);
+        } else {
+            check_owner(from, range.begin, range.end)?;
+        }
+    }
+
+    let (from_mode, to_mode) = share.modes(access);
+
+    // Allocate everything that may fail before changing any mapping. Overlapping ranges are
+    // rejected by the transactions.
+    let mut from_tx = MapTransaction::new(from);
+    let mut to_tx = MapTransaction::new(to);
+    for range in ranges {
+        let pa_begin = PhysAddr::from_ipa(range.begin);
+        let pa_end = PhysAddr::from_ipa(range.end);
+        from_tx.identity_map(pa_begin, pa_end, from_mode)?;
+        to_tx.identity_map(pa_begin, pa_end, to_mode)?;
+    }
+
+    let from_tx = from_tx.prepare(mpool)?;
+    let to_tx = match to_tx.prepare(mpool) {
+        Ok(to_tx) => to_tx,
+        Err(e) => {
+            from_tx.abort(mpool);
+            return Err(e);
+        }
+    };
+
+    let mut mappings = ArrayVec::<[TempMapping; MAP_TRANSACTION_MAX_RANGES]>::new();
+    for range in ranges {
+        match TempMapping::new(
+            PhysAddr::from_ipa(range.begin),
+            range.end - range.begin,
+            Mode::R | Mode::W,
+            mpool,
+        ) {
+            Ok(mapping) => mappings.push(mapping),
+            Err(e) => {
+                from_tx.abort(mpool);
+                to_tx.abort(mpool);
+                return Err(e);
+            }
+        }
+    }
+
+    // Update the sender first so that the recipient never sees the previous contents.
+    from_tx.commit(mpool);
+    for mapping in &mappings {
+        clear_memory(mapping);
+    }
+    to_tx.commit(mpool);
+
+    Ok(())
+}
+
+/// Relinquishes `[begin, end)`, which the VM whose page table is `from` borrowed from the VM whose
+/// page table is `owner`. The page tables of both VMs should be locked.
+///
+/// The memory is unmapped from the borrower, but the owner doesn't regain access to it until it
+/// reclaims it with `reclaim_memory()`.
+pub fn relinquish_memory(
+    from: &mut PageTable<Stage2>,
+    owner: &PageTable<Stage2>,
+    begin: IpaAddr,
+    end: IpaAddr,
+    mpool: &MPool,
+) -> Result<(), MmError> {
+    check_range(begin, end)?;
+
+    // Memory owned by the hypervisor was not borrowed from another VM.
+    let from_mode = from.get_mode(begin, end)?;
+    if from_mode.intersects(Mode::INVALID | Mode::HYP) || !from_mode.contains(Mode::UNOWNED) {
+        return Err(MmError::AccessDenied);
+    }
+
+    if !is_lent(owner.get_mode(begin, end)?) {
+        return Err(MmError::AccessDenied);
+    }
+    check_owner(owner, begin, end)?;
+
+    from.unmap(PhysAddr::from_ipa(begin), PhysAddr::from_ipa(end), mpool)
+}
+
+/// Reclaims `[begin, end)`, which the VM whose page table is `owner` lent or shared with the VM
+/// whose page table is `borrower`. The page tables of both VMs should be locked.
+///
+/// Fails with `MmError::AccessDenied` while the borrower still has the memory mapped, i.e. until it
+/// relinquishes the memory. The memory is zeroed before the owner regains exclusive access to it,
+/// so that it doesn't see what the borrower left.
+pub fn reclaim_memory(
+    owner: &mut PageTable<Stage2>,
+    borrower: &PageTable<Stage2>,
+    begin: IpaAddr,
+    end: IpaAddr,
+    mpool: &MPool,
+) -> Result<(), MmError> {
+    check_range(begin, end)?;
+
+    if !is_lent(owner.get_mode(begin, end)?) {
+        return Err(MmError::AccessDenied);
+    }
+    check_owner(owner, begin, end)?;
+
+    if !borrower.get_mode(begin, end)?.contains(Mode::INVALID) {
+        return Err(MmError::AccessDenied);
+    }
+
+    let pa_begin = PhysAddr::from_ipa(begin);
+    let pa_end = PhysAddr::from_ipa(end);
+
+    let mut tx = MapTransaction::new(owner);
+    tx.identity_map(pa_begin, pa_end, Mode::R | Mode::W | Mode::X)?;
+    let tx = tx.prepare(mpool)?;
+
+    let mapping = match TempMapping::new(pa_begin, pa_end - pa_begin, Mode::R | Mode::W, mpool) {
+        Ok(mapping) => mapping,
+        Err(e) => {
+            tx.abort(mpool);
+            return Err(e);
+        }
+    };
+
+    clear_memory(&mapping);
+    tx.commit(mpool);
+
+    Ok(())
+}
+
+/// Shares memory between the VMs whose page tables are given, giving the recipient full access to
+/// it. See `share_memory()`.
+#[no_mangle]
+pub unsafe extern "C" fn api_share_memory_ptables(
+    from: *mut PageTable<Stage2>,
+    to: *mut PageTable<Stage2>,
+    begin: IpaAddr,
+    end: IpaAddr,
+    share: u32,
+    mpool: *const MPool,
+) -> bool {
+    let share = match HfShare::from_raw(share) {
+        Some(share) => share,
+        None => return false,
+    };
+
+    share_memory(
+        &mut *from,
+        &mut *to,
+        &[MemRange { begin, end }],
+        share,
+        Mode::R | Mode::W | Mode::X,
+        &*mpool,
+    )
+    .is_ok()
+}
+
+/// Shares the `count` ranges of memory at `ranges` between the VMs whose page tables are given,
+/// giving the recipient the access `mode` to them. See `share_memory()`.
+///
+/// Returns 0 on success, or the SPCI error code of the failure.
+#[no_mangle]
+pub unsafe extern "C" fn api_share_memory_ranges_ptables(
+    from: *mut PageTable<Stage2>,
+    to: *mut PageTable<Stage2>,
+    ranges: *const MemRange,
+    count: usize,
+    share: u32,
+    mode: c_int,
+    mpool: *const MPool,
+) -> i32 {
+    let share = match HfShare::from_raw(share) {
+        Some(share) => share,
+        None => return SPCI_INVALID_PARAMETERS,
+    };
+
+    let ranges = slice::from_raw_parts(ranges, count);
+    let mode = Mode::from_bits_truncate(mode as u32);
+    match share_memory(&mut *from, &mut *to, ranges, share, mode, &*mpool) {
+        Ok(()) => SPCI_SUCCESS,
+        Err(e) => spci_error(e),
+    }
+}
+
+/// Relinquishes memory borrowed from another VM. See `relinquish_memory()`.
+#[no_mangle]
+pub unsafe extern "C" fn api_memory_relinquish_ptables(
+    from: *mut PageTable<Stage2>,
+    owner: *const PageTable<Stage2>,
+    begin: IpaAddr,
+    end: IpaAddr,
+    mpool: *const MPool,
+) -> bool {
+    relinquish_memory(&mut *from, &*owner, begin, end, &*mpool).is_ok()
+}
+
+/// Reclaims memory lent or shared with another VM. See `reclaim_memory()`.
+#[no_mangle]
+pub unsafe extern "C" fn api_memory_reclaim_ptables(
+    owner: *mut PageTable<Stage2>,
+    borrower: *const PageTable<Stage2>,
+    begin: IpaAddr,
+    end: IpaAddr,
+    mpool: *const MPool,
+) -> bool {
+    reclaim_memory(&mut *owner, &*borrower, begin, end, &*mpool).is_ok()
+}
@@ -0,0 +1,90 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Architectural barriers.
+//!
+//! Rust's atomic fences order memory accesses among CPUs, but not with respect to other observers
+//! such as the MMU's table walkers, nor do they synchronise the instruction stream.  These
+//! barriers are used where the architecture requires them, e.g. to make a page table initialised
+//! before it is installed in a page table entry.  Off aarch64, e.g. on the host running unit
+//! tests, they fall back to the closest fences.
+
+#[cfg(not(target_arch = "aarch64"))]
+use core::sync::atomic::{compiler_fence, fence, Ordering};
+
+/// Orders the memory accesses before the barrier before those after it, for all observers in the
+/// inner shareable domain.
+#[inline]
+pub fn dmb_ish() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("dmb ish" : : : "memory" : "volatile");
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    fence(Ordering::SeqCst);
+}
+
+/// Orders the memory writes before the barrier before those after it, for all observers in the
+/// inner shareable domain, including table walkers.
+#[inline]
+pub fn dmb_ishst() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("dmb ishst" : : : "memory" : "volatile");
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    fence(Ordering::Release);
+}
+
+/// Waits until the memory accesses and maintenance operations, e.g. TLB invalidations, before the
+/// barrier are complete in the inner shareable domain.
+#[inline]
+pub fn dsb_ish() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("dsb ish" : : : "memory" : "volatile");
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    fence(Ordering::SeqCst);
+}
+
+/// Waits until the memory writes before the barrier are complete in the inner shareable domain.
+#[inline]
+pub fn dsb_ishst() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("dsb ishst" : : : "memory" : "volatile");
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    fence(Ordering::Release);
+}
+
+/// Flushes the pipeline, so that the instructions after the barrier see the effects of the
+/// context-changing operations before it, e.g. writes to system registers.
+#[inline]
+pub fn isb() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("isb" : : : "memory" : "volatile");
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    compiler_fence(Ordering::SeqCst);
+}
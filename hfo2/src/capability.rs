@@ -0,0 +1,102 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Hypercall capabilities.
+//!
+//! Some hypercalls are only allowed for VMs with the corresponding capability, so that specific
+//! VMs can be denied, e.g., sharing memory or forwarding SMCs to EL3.  The capabilities are read
+//! from `caps.txt` in the RAM disk, which has an entry `<kernel-filename> <capabilities>` per VM,
+//! where the capabilities are a bitmask of `HF_CAPABILITY_*` in `inc/hf/capability.h`.  A VM
+//! without any entry has the default capabilities, which are all but injecting interrupts into
+//! other VMs.
+
+use crate::cpio;
+use crate::cpu::CVCpu;
+use crate::memiter::MemIter;
+
+bitflags! {
+    /// The capabilities of a VM, as `HF_CAPABILITY_*`.
+    pub struct Capabilities: u32 {
+        /// Sharing, lending and giving memory to other VMs.
+        const MEMORY_SHARING = 0b0000_0001;
+
+        /// Inspecting the state of the hypervisor and other VMs.
+        const INTROSPECTION = 0b0000_0010;
+
+        /// Forwarding SMCs to EL3.
+        const SMC_FORWARDING = 0b0000_0100;
+
+        /// Injecting interrupts into other VMs, e.g. as the backend of a device.
+        const INTERRUPT_INJECTION = 0b0000_1000;
+
+        /// The capabilities of a VM without an entry in `caps.txt`.
+        const DEFAULT = Self::MEMORY_SHARING.bits
+            | Self::INTROSPECTION.bits
+            | Self::SMC_FORWARDING.bits;
+    }
+}
+
+extern "C" {
+    fn vcpu_vm_capabilities(vcpu: *const CVCpu) -> u32;
+}
+
+impl Capabilities {
+    /// Returns the capabilities of the VM of the given vCPU.
+    pub unsafe fn of(vcpu: *const CVCpu) -> Self {
+        Self::from_bits_truncate(vcpu_vm_capabilities(vcpu))
+    }
+}
+
+/// Parses the capabilities of the given VM from the entries of `caps.txt`. Fails if an entry is
+/// malformed.
+unsafe fn parse(it: &mut MemIter, name: &[u8]) -> Result<Capabilities, ()> {
+    let mut caps = None;
+
+    while let Some(entry_name) = it.parse_str() {
+        let bits = it.parse_uint().ok_or(())?;
+        if bits > u64::from(u32::max_value()) {
+            return Err(());
+        }
+
+        if entry_name.as_slice() == name {
+            let entry = Capabilities::from_bits(bits as u32).ok_or(())?;
+            caps = Some(caps.unwrap_or_else(Capabilities::empty) | entry);
+        }
+    }
+
+    Ok(caps.unwrap_or(Capabilities::DEFAULT))
+}
+
+/// Loads the capabilities of the VM whose kernel has the given name from the RAM disk to `caps`.
+/// Returns false if `caps.txt` is malformed.
+#[no_mangle]
+pub unsafe extern "C" fn capabilities_load(
+    cpio: *const MemIter,
+    name: *const MemIter,
+    caps: *mut u32,
+) -> bool {
+    let mut cpio = (*cpio).clone();
+    let loaded = match cpio::find_file(&mut cpio, "caps.txt\0".as_ptr()) {
+        Some(mut it) => match parse(&mut it, (*name).as_slice()) {
+            Ok(loaded) => loaded,
+            Err(()) => return false,
+        },
+        None => Capabilities::DEFAULT,
+    };
+
+    *caps = loaded.bits();
+    true
+}
@@ -0,0 +1,176 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The system register half of the vCPU world switch.
+//!
+//! The general-purpose registers are spilled and reloaded by the entry stub in `exceptions.S`,
+//! since they must be handled before any compiled code runs. Everything else that belongs to the
+//! guest's EL1 context, together with the EL2 registers configuring its trapping and stage-2
+//! translation, is switched here.
+
+use core::mem;
+
+/// Reads a system register.
+#[cfg(target_arch = "aarch64")]
+macro_rules! read_sysreg {
+    ($name: tt) => {{
+        let value: u64;
+        asm!(concat!("mrs $0, ", $name) : "=r"(value) : : : "volatile");
+        value
+    }};
+}
+
+/// Writes a system register.
+#[cfg(target_arch = "aarch64")]
+macro_rules! write_sysreg {
+    ($name: tt, $value: expr) => {{
+        let value: u64 = $value;
+        asm!(concat!("msr ", $name, ", $0") : : "r"(value) : : "volatile");
+    }};
+}
+
+/// The system registers of a vCPU which are switched on every world switch. This mirrors
+/// `struct arch_regs.lazy`, whose offset in `struct vcpu` is `VCPU_LAZY`.
+#[repr(C)]
+pub struct VCpuContext {
+    vmpidr_el2: u64,
+    csselr_el1: u64,
+    sctlr_el1: u64,
+    actlr_el1: u64,
+    cpacr_el1: u64,
+    ttbr0_el1: u64,
+    ttbr1_el1: u64,
+    tcr_el1: u64,
+    esr_el1: u64,
+    afsr0_el1: u64,
+    afsr1_el1: u64,
+    far_el1: u64,
+    mair_el1: u64,
+    vbar_el1: u64,
+    contextidr_el1: u64,
+    tpidr_el0: u64,
+    tpidrro_el0: u64,
+    tpidr_el1: u64,
+    amair_el1: u64,
+    cntkctl_el1: u64,
+    sp_el0: u64,
+    sp_el1: u64,
+    elr_el1: u64,
+    spsr_el1: u64,
+    par_el1: u64,
+    hcr_el2: u64,
+    cptr_el2: u64,
+    cnthctl_el2: u64,
+    vttbr_el2: u64,
+}
+
+const_assert!(vcpu_context_size; mem::size_of::<VCpuContext>() == 232);
+
+impl VCpuContext {
+    /// Saves the registers of the vCPU which is leaving this CPU.
+    #[cfg(target_arch = "aarch64")]
+    pub fn save(&mut self) {
+        unsafe {
+            self.vmpidr_el2 = read_sysreg!("vmpidr_el2");
+            self.csselr_el1 = read_sysreg!("csselr_el1");
+            self.sctlr_el1 = read_sysreg!("sctlr_el1");
+            self.actlr_el1 = read_sysreg!("actlr_el1");
+            self.cpacr_el1 = read_sysreg!("cpacr_el1");
+            self.ttbr0_el1 = read_sysreg!("ttbr0_el1");
+            self.ttbr1_el1 = read_sysreg!("ttbr1_el1");
+            self.tcr_el1 = read_sysreg!("tcr_el1");
+            self.esr_el1 = read_sysreg!("esr_el1");
+            self.afsr0_el1 = read_sysreg!("afsr0_el1");
+            self.afsr1_el1 = read_sysreg!("afsr1_el1");
+            self.far_el1 = read_sysreg!("far_el1");
+            self.mair_el1 = read_sysreg!("mair_el1");
+            self.vbar_el1 = read_sysreg!("vbar_el1");
+            self.contextidr_el1 = read_sysreg!("contextidr_el1");
+            self.tpidr_el0 = read_sysreg!("tpidr_el0");
+            self.tpidrro_el0 = read_sysreg!("tpidrro_el0");
+            self.tpidr_el1 = read_sysreg!("tpidr_el1");
+            self.amair_el1 = read_sysreg!("amair_el1");
+            self.cntkctl_el1 = read_sysreg!("cntkctl_el1");
+            self.sp_el0 = read_sysreg!("sp_el0");
+            self.sp_el1 = read_sysreg!("sp_el1");
+            self.elr_el1 = read_sysreg!("elr_el1");
+            self.spsr_el1 = read_sysreg!("spsr_el1");
+            self.par_el1 = read_sysreg!("par_el1");
+            self.hcr_el2 = read_sysreg!("hcr_el2");
+            self.cptr_el2 = read_sysreg!("cptr_el2");
+            self.cnthctl_el2 = read_sysreg!("cnthctl_el2");
+            self.vttbr_el2 = read_sysreg!("vttbr_el2");
+        }
+    }
+
+    /// Loads the registers of the vCPU which is about to run on this CPU.
+    #[cfg(target_arch = "aarch64")]
+    pub fn restore(&self) {
+        unsafe {
+            write_sysreg!("vmpidr_el2", self.vmpidr_el2);
+            write_sysreg!("csselr_el1", self.csselr_el1);
+            write_sysreg!("sctlr_el1", self.sctlr_el1);
+            write_sysreg!("actlr_el1", self.actlr_el1);
+            write_sysreg!("cpacr_el1", self.cpacr_el1);
+            write_sysreg!("ttbr0_el1", self.ttbr0_el1);
+            write_sysreg!("ttbr1_el1", self.ttbr1_el1);
+            write_sysreg!("tcr_el1", self.tcr_el1);
+            write_sysreg!("esr_el1", self.esr_el1);
+            write_sysreg!("afsr0_el1", self.afsr0_el1);
+            write_sysreg!("afsr1_el1", self.afsr1_el1);
+            write_sysreg!("far_el1", self.far_el1);
+            write_sysreg!("mair_el1", self.mair_el1);
+            write_sysreg!("vbar_el1", self.vbar_el1);
+            write_sysreg!("contextidr_el1", self.contextidr_el1);
+            write_sysreg!("tpidr_el0", self.tpidr_el0);
+            write_sysreg!("tpidrro_el0", self.tpidrro_el0);
+            write_sysreg!("tpidr_el1", self.tpidr_el1);
+            write_sysreg!("amair_el1", self.amair_el1);
+            write_sysreg!("cntkctl_el1", self.cntkctl_el1);
+            write_sysreg!("sp_el0", self.sp_el0);
+            write_sysreg!("sp_el1", self.sp_el1);
+            write_sysreg!("elr_el1", self.elr_el1);
+            write_sysreg!("spsr_el1", self.spsr_el1);
+            write_sysreg!("par_el1", self.par_el1);
+            write_sysreg!("hcr_el2", self.hcr_el2);
+            write_sysreg!("cptr_el2", self.cptr_el2);
+            write_sysreg!("cnthctl_el2", self.cnthctl_el2);
+            write_sysreg!("vttbr_el2", self.vttbr_el2);
+        }
+    }
+
+    /// There are no system registers to switch off aarch64, e.g. on the host running unit tests.
+    #[cfg(not(target_arch = "aarch64"))]
+    pub fn save(&mut self) {}
+
+    /// There are no system registers to switch off aarch64, e.g. on the host running unit tests.
+    #[cfg(not(target_arch = "aarch64"))]
+    pub fn restore(&self) {}
+}
+
+/// Saves the system registers of the current vCPU into `context`. Called from `vcpu_switch` after
+/// the general-purpose registers have been spilled.
+#[no_mangle]
+pub unsafe extern "C" fn vcpu_context_save(context: *mut VCpuContext) {
+    (*context).save();
+}
+
+/// Loads the system registers of the next vCPU from `context`. Called from
+/// `vcpu_restore_all_and_run` before the general-purpose registers are reloaded.
+#[no_mangle]
+pub unsafe extern "C" fn vcpu_context_restore(context: *const VCpuContext) {
+    (*context).restore();
+}
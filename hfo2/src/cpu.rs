@@ -14,16 +14,47 @@
  * limitations under the License.
  */
 
+use core::cmp;
+use core::mem;
 use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
+use crate::barriers::*;
 use crate::mm::Mode;
+use crate::mpool::MPool;
 use crate::spinlock::*;
+use crate::trap::TrapRegion;
 use crate::types::*;
+use crate::utils::*;
+use crate::vm::snapshot::{Reader, SnapshotError, Writer};
 use crate::vm::*;
 
+/// A VM of the C code, i.e. `struct vm`, which is only handled through pointers.
+pub enum CVm {}
+
+/// A vCPU whose lock is held.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct VCpuLocked {
+    vcpu: *mut CVCpu,
+}
+
 extern "C" {
     fn arch_irq_enable();
     fn arch_irq_disable();
+
+    static mut cpus: [Cpu; MAX_CPUS];
+
+    fn cpu_index(c: *const Cpu) -> usize;
+    fn cpu_get_count() -> usize;
+    fn vm_find(id: VmId) -> *mut CVm;
+    fn vm_get_vcpu(vm: *mut CVm, vcpu_index: VCpuIndex) -> *mut CVCpu;
+    pub fn vcpu_lock(vcpu: *mut CVCpu) -> VCpuLocked;
+    pub fn vcpu_unlock(locked: *mut VCpuLocked);
+    fn vcpu_on(vcpu: VCpuLocked, entry: IpaAddr, arg: uintreg_t);
+    fn vcpu_fp_cpu(vcpu: *const CVCpu) -> *const Cpu;
+    fn vcpu_set_fp_cpu(vcpu: *mut CVCpu, c: *const Cpu);
 }
 
 /// The number of bits in each element of the interrupt bitfields.
@@ -49,6 +80,31 @@ pub enum VCpuStatus {
     Aborted,
 }
 
+impl VCpuStatus {
+    fn to_u32(&self) -> u32 {
+        match self {
+            VCpuStatus::Off => 0,
+            VCpuStatus::Ready => 1,
+            VCpuStatus::Running => 2,
+            VCpuStatus::BlockedMailbox => 3,
+            VCpuStatus::BlockedInterrupt => 4,
+            VCpuStatus::Aborted => 5,
+        }
+    }
+
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(VCpuStatus::Off),
+            1 => Some(VCpuStatus::Ready),
+            2 => Some(VCpuStatus::Running),
+            3 => Some(VCpuStatus::BlockedMailbox),
+            4 => Some(VCpuStatus::BlockedInterrupt),
+            5 => Some(VCpuStatus::Aborted),
+            _ => None,
+        }
+    }
+}
+
 pub struct VCpuState {
     cpu: *const Cpu,
     status: VCpuStatus,
@@ -159,11 +215,26 @@ impl Interrupts {
     }
 }
 
+/// How a stage-2 page fault is handled.
+pub enum PageFault {
+    /// The access is allowed now, so the vCPU resumes.
+    Resume,
+
+    /// The access is to a trap region, so it should be emulated by the region's owner.
+    Emulate(TrapRegion),
+
+    /// The access is not allowed.
+    Abort,
+}
+
+/// A vCPU of the C code, i.e. `struct vcpu`, which is only handled through pointers.
+pub enum CVCpu {}
+
 #[repr(C)]
 pub struct VCpuFaultInfo {
-    ipaddr: usize,
-    vaddr: usize,
-    pc: usize,
+    ipaddr: IpaAddr,
+    vaddr: VirtAddr,
+    pc: VirtAddr,
     mode: Mode,
 }
 
@@ -193,6 +264,88 @@ impl VCpu {
         let state = self.state.get_mut().on(entry, arg);
     }
 
+    /// Returns whether the vCPU is running.
+    pub fn is_running(&self) -> bool {
+        match self.state.lock().status {
+            VCpuStatus::Running => true,
+            _ => false,
+        }
+    }
+
+    /// Writes the state and interrupts of the vCPU, which must not be running, to a snapshot: its
+    /// status as a `u32`, its registers as raw bytes, the enabled and pending interrupt bitfields
+    /// as `u32`s and the count of interrupts both enabled and pending as a `u32`.
+    pub fn save(&self, w: &mut Writer) -> Result<(), SnapshotError> {
+        let state = self.state.lock();
+        w.put_u32(state.status.to_u32())?;
+        // The registers are plain data, saved and restored as they are.
+        w.put_bytes(unsafe {
+            slice::from_raw_parts(
+                &state.regs as *const ArchRegs as *const u8,
+                mem::size_of::<ArchRegs>(),
+            )
+        })?;
+
+        let interrupts = self.interrupts.lock();
+        for word in interrupts.enabled.iter().chain(interrupts.pending.iter()) {
+            w.put_u32(*word)?;
+        }
+        w.put_u32(interrupts.enabled_and_pending_count)
+    }
+
+    /// Reads the state and interrupts of a vCPU from a snapshot, and returns them if they are
+    /// consistent.
+    fn read<'a>(r: &mut Reader<'a>) -> Result<(VCpuStatus, &'a [u8], Interrupts), SnapshotError> {
+        let status = match VCpuStatus::from_u32(r.get_u32()?) {
+            Some(VCpuStatus::Running) | None => return Err(SnapshotError::BadFormat),
+            Some(status) => status,
+        };
+        let regs = r.get_bytes(mem::size_of::<ArchRegs>())?;
+
+        let mut interrupts = Interrupts::new();
+        for word in interrupts.enabled.iter_mut() {
+            *word = r.get_u32()?;
+        }
+        for word in interrupts.pending.iter_mut() {
+            *word = r.get_u32()?;
+        }
+        interrupts.enabled_and_pending_count = r.get_u32()?;
+
+        let count: u32 = interrupts
+            .enabled
+            .iter()
+            .zip(interrupts.pending.iter())
+            .map(|(enabled, pending)| (enabled & pending).count_ones())
+            .sum();
+        if count != interrupts.enabled_and_pending_count {
+            return Err(SnapshotError::BadFormat);
+        }
+
+        Ok((status, regs, interrupts))
+    }
+
+    /// Checks the state and interrupts of a vCPU in a snapshot, without restoring them.
+    pub fn check(&self, r: &mut Reader) -> Result<(), SnapshotError> {
+        Self::read(r).map(|_| ())
+    }
+
+    /// Restores the state and interrupts of the vCPU, which must not be running, from a snapshot.
+    pub fn load(&self, r: &mut Reader) -> Result<(), SnapshotError> {
+        let (status, regs, interrupts) = Self::read(r)?;
+
+        let mut state = self.state.lock();
+        state.status = status;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                regs.as_ptr(),
+                &mut state.regs as *mut ArchRegs as *mut u8,
+                regs.len(),
+            );
+        }
+        *self.interrupts.lock() = interrupts;
+        Ok(())
+    }
+
     /// Starts a vCPU of a secondary VM.
     fn secondary_reset_start(&self, entry: usize, arg: uintreg_t) {
         assert!(true, "TODO: vcpu->vm->id != HF_PRIMARY_VM_ID");
@@ -211,7 +364,7 @@ impl VCpu {
         unsafe { &*self.vm }
     }
 
-    fn get_index(&self) -> usize {
+    fn get_index(&self) -> VCpuIndex {
         unsafe { self.get_vm().get_index(self) }
     }
 
@@ -220,8 +373,9 @@ impl VCpu {
     ///
     /// Returns true if the caller should resume the current vcpu, or false if its VM should be
     /// aborted.
-    fn handle_page_fault(&self, f: &VCpuFaultInfo) -> bool {
+    fn handle_page_fault(&self, f: &VCpuFaultInfo, mpool: &MPool) -> PageFault {
         let mask = f.mode | Mode::INVALID;
+        let mut state = self.get_vm().state.lock();
 
         // Check if this is a legitimate fault, i.e., if the page table doesn't allow the access
         // attemped by the VM.
@@ -230,27 +384,54 @@ impl VCpu {
         // table. It is responsible for issuing global TLB invalidations while holding the VM lock,
         // so we don't need to do anything else to recover from it. (Acquiring/releasing the lock
         // ensured that the invalidations have completed.)
-        let resume = self
-            .get_vm()
-            .state
-            .lock()
+        //
+        // A write to a page write-protected by dirty page tracking is recorded and resumed, too.
+        let resume = state
             .ptable
             .get_mode(f.ipaddr, f.ipaddr + 1)
             .map(|mode| mode & mask == f.mode)
-            .unwrap_or(false);
-
-        if !resume {
-            dlog!("Stage-2 page fault: pc={:X}, vmid={}, vcpu={}, vaddr={:X}, ipaddr={:X}, mode={:X}\n",
-		              f.pc,
-                  (unimplemented!("vm->id"), 0).1,
-                  self.get_index(),
-                  f.vaddr,
-                  f.ipaddr,
-                  f.mode,
-            );
+            .unwrap_or(false)
+            || (f.mode.contains(Mode::W) && state.handle_dirty_fault(f.ipaddr, mpool));
+
+        // The fault may be an access flag fault for a page whose access flag was cleared to track
+        // accesses.
+        if resume {
+            state.ptable.mark_accessed(f.ipaddr);
+            return PageFault::Resume;
         }
 
-        resume
+        // The access to a trap region is not a genuine fault.
+        if let Some(region) = state.find_trap(f.ipaddr) {
+            return PageFault::Emulate(region);
+        }
+
+        dlog!(
+            "Stage-2 page fault: pc={:X}, vmid={}, vcpu={}, vaddr={:X}, ipaddr={:X}, mode={:X}\n",
+            f.pc.addr(),
+            self.get_vm().id().id(),
+            self.get_index().index(),
+            f.vaddr.addr(),
+            f.ipaddr.addr(),
+            f.mode,
+        );
+
+        PageFault::Abort
+    }
+}
+
+bitflags! {
+    /// Requests sent from one physical CPU to others. They are serviced by the target CPU the
+    /// next time it enters the hypervisor from a VM, and acknowledged once they are.
+    pub struct IpiRequests: u32 {
+        /// Return to the primary VM's scheduler instead of resuming a secondary VM's vCPU.
+        const RESCHEDULE = 0b001;
+
+        /// Complete the TLB invalidations issued before the request, and stop using any
+        /// translation cached before it, so that memory unmapped from a VM can be reused.
+        const TLB_SYNC   = 0b010;
+
+        /// Stop running VMs for good, because the hypervisor is panicking.
+        const STOP       = 0b100;
     }
 }
 
@@ -270,8 +451,29 @@ pub struct Cpu {
     /// See api.c for the partial ordering on locks.
     lock: RawSpinLock,
 
-    /// Determines whether or not the cpu is currently on.
-    is_on: bool,
+    /// Determines whether or not the cpu is currently on. Only changed with `lock` held.
+    is_on: AtomicBool,
+
+    /// The vCPU whose FP/SIMD state was last loaded into the registers of the CPU, if any. Only
+    /// accessed by the CPU itself.
+    fp_owner: *const CVCpu,
+
+    /// The requests sent to the CPU which it has not acknowledged yet.
+    ipi_pending: AtomicU32,
+
+    /// The index of the cluster of the CPU, and of its core therein, found at boot.
+    cluster: u32,
+    core: u32,
+}
+
+/// Where a CPU is in the topology of the system, as reported to the primary VM. This mirrors
+/// `struct hf_cpu_topology`.
+#[repr(C)]
+pub struct CpuTopology {
+    /// The MPIDR of the CPU, from which the affinity levels can be read.
+    pub mpidr: u64,
+    pub cluster: u32,
+    pub core: u32,
 }
 
 impl Cpu {
@@ -281,7 +483,11 @@ impl Cpu {
             stack_bottom: ptr::null(),
             irq_disable_count: 0,
             lock: RawSpinLock::new(),
-            is_on: false,
+            is_on: AtomicBool::new(false),
+            fp_owner: ptr::null(),
+            ipi_pending: AtomicU32::new(0),
+            cluster: 0,
+            core: 0,
         }
     }
 
@@ -303,26 +509,217 @@ impl Cpu {
         self.irq_disable_count += 1;
     }
 
-    /// Turns CPU on and returns the previous state.
-    pub fn on(&mut self, entry: usize, arg: uintreg_t) -> bool {
+    /// Returns whether the CPU is on, or is about to be turned on.
+    pub fn is_on(&self) -> bool {
+        self.lock.lock();
+        let is_on = self.is_on.load(Ordering::Relaxed);
+        self.lock.unlock();
+        is_on
+    }
+
+    /// Turns CPU on and returns the previous state. If it was off, the vCPU of the primary VM for
+    /// the CPU is set up to start at `entry` with `arg` once the CPU enters the hypervisor on the
+    /// stack given by `stack_bottom`, which is left to the caller to arrange with the firmware.
+    pub fn on(&self, entry: IpaAddr, arg: uintreg_t) -> bool {
+        // Only CPUs found in the configuration have a stack to start on.
+        assert!(!self.stack_bottom.is_null());
+
         self.lock.lock();
-        let prev = self.is_on;
-        self.is_on = true;
+        let prev = self.is_on.swap(true, Ordering::Relaxed);
         self.lock.unlock();
 
         if !prev {
-            let vm = unimplemented!();
-            let vcpu: VCpu = unimplemented!();
-            vcpu.on(entry, arg);
+            unsafe {
+                // The primary VM has a vCPU for each CPU.
+                let vm = vm_find(HF_PRIMARY_VM_ID);
+                let index = VCpuIndex::new(cpu_index(self) as u32, cpu_get_count() as u32).unwrap();
+                let vcpu = vm_get_vcpu(vm, index);
+                let mut locked = vcpu_lock(vcpu);
+                vcpu_on(locked, entry, arg);
+                vcpu_unlock(&mut locked);
+            }
         }
 
         prev
     }
 
-    /// Prepares the CPU for turning itself off.
+    /// Prepares the CPU for turning itself off. It is called on the CPU itself, by the primary
+    /// VM's vCPU for it, so no vCPU of a secondary VM is running on it and their registers are
+    /// saved for them to be run on other CPUs. Interrupts are disabled again, as they are when the
+    /// CPU is first started, so that they are enabled once it is turned back on. The CPUs waiting
+    /// for it to acknowledge requests are woken up, as they no longer need to.
     pub fn off(&mut self) {
+        self.irq_disable_count = 1;
+        self.fp_forget();
+
         self.lock.lock();
-        self.is_on = false;
+        self.is_on.store(false, Ordering::Relaxed);
         self.lock.unlock();
+        send_event();
+    }
+
+    /// Returns whether the FP/SIMD registers of the CPU still hold the latest state of the vCPU,
+    /// in which case it can use them without them being loaded again. This is so if the state was
+    /// last loaded here, as a vCPU's state is saved whenever it stops running after using them
+    /// and the registers are only changed by loading another vCPU's state. Resetting a vCPU's
+    /// registers forgets where its state was last loaded, so the stale state isn't used.
+    pub unsafe fn fp_is_live(&self, vcpu: *const CVCpu) -> bool {
+        self.fp_owner == vcpu && vcpu_fp_cpu(vcpu) == self
+    }
+
+    /// Records that the state of the vCPU has been loaded into the FP/SIMD registers of the CPU.
+    pub unsafe fn fp_take(&mut self, vcpu: *mut CVCpu) {
+        self.fp_owner = vcpu;
+        vcpu_set_fp_cpu(vcpu, self);
     }
+
+    /// Records that the FP/SIMD registers of the CPU don't hold any vCPU's state, e.g. because
+    /// it has been powered down.
+    pub fn fp_forget(&mut self) {
+        self.fp_owner = ptr::null();
+    }
+
+    /// Returns where the CPU is in the topology of the system.
+    pub fn topology(&self) -> CpuTopology {
+        CpuTopology {
+            mpidr: self.id,
+            cluster: self.cluster,
+            core: self.core,
+        }
+    }
+
+    /// Sends the requests to the CPU, without waiting for it to service them.
+    pub fn ipi_send(&self, requests: IpiRequests) {
+        self.ipi_pending
+            .fetch_or(requests.bits(), Ordering::Release);
+        send_event();
+    }
+
+    /// Services the requests pending for the CPU, which must be the current one, and returns
+    /// them. Each request is acknowledged once it is serviced; a stop request is acknowledged
+    /// before the CPU stops, and never returns.
+    pub fn ipi_handle(&self) -> IpiRequests {
+        let requests = IpiRequests::from_bits_truncate(self.ipi_pending.load(Ordering::Acquire));
+        if requests.is_empty() {
+            return requests;
+        }
+
+        if requests.contains(IpiRequests::TLB_SYNC) {
+            dsb_ish();
+            isb();
+        }
+
+        self.ipi_pending
+            .fetch_and(!requests.bits(), Ordering::Release);
+        send_event();
+
+        if requests.contains(IpiRequests::STOP) {
+            spin_loop();
+        }
+
+        requests
+    }
+
+    /// Sends the requests to all the other CPUs which are on, without waiting for them to service
+    /// them.
+    pub unsafe fn ipi_send_others(&self, requests: IpiRequests) {
+        for other in cpus[..cpu_get_count()].iter() {
+            if other as *const _ != self as *const _ && other.is_on.load(Ordering::Relaxed) {
+                other.ipi_send(requests);
+            }
+        }
+    }
+
+    /// Sends the requests to all the other CPUs which are on, and waits until they have all
+    /// acknowledged them. The requests sent to this CPU in the meantime are serviced while
+    /// waiting, so that CPUs broadcasting at the same time don't wait for each other forever.
+    /// For the same reason, no lock may be held by the caller, as a CPU waiting for it would not
+    /// enter the hypervisor to service the requests.
+    pub unsafe fn ipi_broadcast(&self, requests: IpiRequests) {
+        self.ipi_send_others(requests);
+
+        for other in cpus[..cpu_get_count()].iter() {
+            if other as *const _ == self as *const _ {
+                continue;
+            }
+
+            while other.ipi_pending.load(Ordering::Acquire) & requests.bits() != 0 {
+                // A CPU turned off before servicing the requests doesn't run any VM anymore.
+                if !other.is_on.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                self.ipi_handle();
+                wait_for_event();
+            }
+        }
+    }
+}
+
+/// Turns CPU on and returns the previous state.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_on(c: *const Cpu, entry: IpaAddr, arg: uintreg_t) -> bool {
+    (*c).on(entry, arg)
+}
+
+/// Prepares the CPU for turning itself off.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_off(c: *mut Cpu) {
+    (*c).off()
+}
+
+/// Returns whether the CPU is on, or is about to be turned on.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_is_on(c: *const Cpu) -> bool {
+    (*c).is_on()
+}
+
+/// Returns whether the FP/SIMD registers of the CPU still hold the latest state of the vCPU.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_fp_is_live(c: *const Cpu, vcpu: *const CVCpu) -> bool {
+    (*c).fp_is_live(vcpu)
+}
+
+/// Records that the state of the vCPU has been loaded into the FP/SIMD registers of the CPU.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_fp_take(c: *mut Cpu, vcpu: *mut CVCpu) {
+    (*c).fp_take(vcpu)
+}
+
+/// Records that the FP/SIMD registers of the CPU don't hold any vCPU's state.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_fp_forget(c: *mut Cpu) {
+    (*c).fp_forget()
+}
+
+/// Sends the requests to the CPU, without waiting for it to service them.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_ipi_send(c: *const Cpu, requests: u32) {
+    (*c).ipi_send(IpiRequests::from_bits_truncate(requests))
+}
+
+/// Services the requests pending for the current CPU, and returns them.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_ipi_handle(c: *const Cpu) -> u32 {
+    (*c).ipi_handle().bits()
+}
+
+/// Sends the requests to all the other CPUs which are on, and waits until they have all
+/// acknowledged them.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_ipi_broadcast(c: *const Cpu, requests: u32) {
+    (*c).ipi_broadcast(IpiRequests::from_bits_truncate(requests))
+}
+
+/// Writes the topology of the first CPUs, in the order of their indices, to `topology`, which has
+/// room for `max` entries. Returns the number of entries written.
+#[no_mangle]
+pub unsafe extern "C" fn cpu_topology_get(topology: *mut CpuTopology, max: usize) -> usize {
+    let count = cmp::min(cpu_get_count(), max);
+
+    for (i, c) in cpus[..count].iter().enumerate() {
+        ptr::write(topology.add(i), c.topology());
+    }
+
+    count
 }
@@ -0,0 +1,294 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Dirty page tracking for stage-2 page tables.
+//!
+//! Writable pages in the tracked range are write-protected.  When the VM writes to such a page, the
+//! resulting stage-2 fault is resolved by recording the page in the dirty log and restoring the
+//! write permission.  `DirtyLog::collect()` reads and clears the log, write-protecting the
+//! collected pages again.
+//!
+//! The bitmap of the protected pages is owned by the page table, which forgets the protection of a
+//! page when it is remapped, e.g. shared or donated.  The write permission is only restored to a
+//! page that is still protected and that the frame table records as owned by the VM.
+
+use core::cmp;
+
+use crate::frame;
+use crate::mm::*;
+use crate::mpool::MPool;
+use crate::page::*;
+use crate::types::*;
+use crate::utils::*;
+
+/// Number of bits in a page.
+const BITS_PER_PAGE: usize = PAGE_SIZE * 8;
+
+/// A bitmap backed by pages allocated from a memory pool.
+struct Bitmap {
+    pages: Pages,
+}
+
+impl Bitmap {
+    /// Allocates a cleared bitmap with at least the given number of bits.
+    fn new(bits: usize, mpool: &MPool) -> Result<Self, MmError> {
+        let count = div_ceil(bits, BITS_PER_PAGE);
+        let mut pages = mpool.alloc_pages(count, 1).ok_or(MmError::OutOfMemory)?;
+        pages.clear();
+        Ok(Self { pages })
+    }
+
+    /// Frees the pages of the bitmap.
+    fn drop(self, mpool: &MPool) {
+        mpool.free_pages(self.pages);
+    }
+
+    fn get(&self, i: usize) -> bool {
+        let bit = i % BITS_PER_PAGE;
+        self.pages[i / BITS_PER_PAGE][bit / 8] & (1 << (bit % 8)) != 0
+    }
+
+    /// Returns the pages of the bitmap, e.g. to give them to a page table.
+    fn into_pages(self) -> Pages {
+        self.pages
+    }
+
+    fn set(&mut self, i: usize, value: bool) {
+        let bit = i % BITS_PER_PAGE;
+        let byte = &mut self.pages[i / BITS_PER_PAGE][bit / 8];
+        if value {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+    }
+}
+
+/// The dirty log of a range of intermediate physical addresses.
+pub struct DirtyLog {
+    begin: IpaAddr,
+    end: IpaAddr,
+
+    /// Pages that were written to since they were write-protected. The pages that were
+    /// write-protected are recorded in the page table.
+    dirty: Bitmap,
+}
+
+impl DirtyLog {
+    /// Starts tracking writes to `[begin, end)` in the given page table.
+    pub fn new(
+        ptable: &mut PageTable<Stage2>,
+        begin: IpaAddr,
+        end: IpaAddr,
+        mpool: &MPool,
+    ) -> Result<Self, MmError> {
+        let begin = IpaAddr::new(round_down(begin.addr(), PAGE_SIZE));
+        let end = IpaAddr::new(round_up(end.addr(), PAGE_SIZE));
+        if begin >= end {
+            return Err(MmError::OutOfRange);
+        }
+
+        let pages = (end - begin) / PAGE_SIZE;
+        let protected = Bitmap::new(pages, mpool)?;
+        let dirty = match Bitmap::new(pages, mpool) {
+            Ok(dirty) => dirty,
+            Err(e) => {
+                protected.drop(mpool);
+                return Err(e);
+            }
+        };
+
+        if let Err(protected) = ptable.set_write_protected(begin, end, protected.into_pages()) {
+            mpool.free_pages(protected);
+            dirty.drop(mpool);
+            return Err(MmError::Overlap);
+        }
+
+        let mut log = Self { begin, end, dirty };
+        if let Err(e) = log.protect(ptable, begin, end, mpool) {
+            log.drop(ptable, mpool);
+            return Err(e);
+        }
+
+        Ok(log)
+    }
+
+    /// Stops tracking writes, restoring the write permission of the pages that are still
+    /// write-protected, and frees the dirty log.
+    pub fn drop(mut self, ptable: &mut PageTable<Stage2>, mpool: &MPool) {
+        for page in (self.begin.addr()..self.end.addr()).step_by(PAGE_SIZE) {
+            let page = IpaAddr::new(page);
+            if ptable.is_write_protected(page) {
+                // This may fail only if the page was merged into a larger block in the meantime
+                // and splitting it again ran out of memory. Then the page stays read-only.
+                let _ = self.unprotect(ptable, page, mpool);
+            }
+        }
+
+        if let Some(protected) = ptable.take_write_protected() {
+            mpool.free_pages(protected);
+        }
+        self.dirty.drop(mpool);
+    }
+
+    fn index(&self, addr: IpaAddr) -> usize {
+        (addr - self.begin) / PAGE_SIZE
+    }
+
+    /// Returns the range tracked by this log.
+    pub fn range(&self) -> (IpaAddr, IpaAddr) {
+        (self.begin, self.end)
+    }
+
+    /// Returns whether the given address is tracked by this log.
+    pub fn contains(&self, addr: IpaAddr) -> bool {
+        self.begin <= addr && addr < self.end
+    }
+
+    /// Write-protects the writable pages in `[begin, end)`.
+    fn protect(
+        &mut self,
+        ptable: &mut PageTable<Stage2>,
+        begin: IpaAddr,
+        end: IpaAddr,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let mut addr = begin;
+
+        while addr < end {
+            let block = match ptable.lookup(addr) {
+                Some(block) => block,
+                None => {
+                    addr += PAGE_SIZE;
+                    continue;
+                }
+            };
+
+            let next = cmp::min(end, block.end);
+            let mode = Stage2::attrs_to_mode(block.attrs);
+
+            if !mode.contains(Mode::INVALID) && mode.contains(Mode::W) {
+                let pa = block.pa + (addr - block.begin);
+                ptable.map(addr, next, pa, mode - Mode::W, mpool)?;
+
+                for page in (addr.addr()..next.addr()).step_by(PAGE_SIZE) {
+                    ptable.mark_write_protected(IpaAddr::new(page), true);
+                }
+            }
+
+            addr = next;
+        }
+
+        Ok(())
+    }
+
+    /// Restores the write permission of the given write-protected page. Fails with
+    /// `MmError::AccessDenied`, dropping the protection, unless the frame table records the page as
+    /// still owned by the VM and it is mapped exclusively, e.g. if it has been shared read-only
+    /// since it was protected.
+    fn unprotect(
+        &mut self,
+        ptable: &mut PageTable<Stage2>,
+        page: IpaAddr,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let block = ptable.lookup(page).ok_or(MmError::OutOfRange)?;
+        let mode = Stage2::attrs_to_mode(block.attrs);
+        let pa = block.pa + (page - block.begin);
+
+        // Pages the frame table doesn't track are not known to be owned by the VM.
+        let owner = ptable.owner();
+        let owned = owner != frame::NO_OWNER
+            && frame::tracks(pa, pa + PAGE_SIZE)
+            && frame::owns(owner, pa, pa + PAGE_SIZE);
+        if mode.intersects(Mode::INVALID | Mode::UNOWNED | Mode::SHARED | Mode::W) || !owned {
+            ptable.mark_write_protected(page, false);
+            return Err(MmError::AccessDenied);
+        }
+
+        ptable.map(page, page + PAGE_SIZE, pa, mode | Mode::W, mpool)?;
+
+        ptable.mark_write_protected(page, false);
+        Ok(())
+    }
+
+    /// Handles a write fault at the given address. Returns true if the fault was caused by the
+    /// dirty log, in which case the page is recorded as dirty and made writable again.
+    pub fn handle_fault(
+        &mut self,
+        ptable: &mut PageTable<Stage2>,
+        addr: IpaAddr,
+        mpool: &MPool,
+    ) -> bool {
+        if !self.contains(addr) {
+            return false;
+        }
+
+        let page = IpaAddr::new(round_down(addr.addr(), PAGE_SIZE));
+        let index = self.index(page);
+        if !ptable.is_write_protected(page) {
+            return false;
+        }
+
+        if self.unprotect(ptable, page, mpool).is_err() {
+            return false;
+        }
+
+        self.dirty.set(index, true);
+        true
+    }
+
+    /// Reads and clears the dirty log of `[begin, end)`, write-protecting the dirty pages again.
+    ///
+    /// The i-th bit of `bitmap` is set if the i-th page of the range was dirty, and cleared
+    /// otherwise. Returns the number of dirty pages.
+    pub fn collect(
+        &mut self,
+        ptable: &mut PageTable<Stage2>,
+        begin: IpaAddr,
+        end: IpaAddr,
+        bitmap: &mut [u8],
+        mpool: &MPool,
+    ) -> Result<usize, MmError> {
+        let begin = IpaAddr::new(round_down(begin.addr(), PAGE_SIZE));
+        let end = IpaAddr::new(round_up(end.addr(), PAGE_SIZE));
+        if !(self.begin <= begin && begin <= end && end <= self.end) {
+            return Err(MmError::OutOfRange);
+        }
+        if bitmap.len() * 8 < (end - begin) / PAGE_SIZE {
+            return Err(MmError::OutOfRange);
+        }
+
+        let mut count = 0;
+
+        for (i, page) in (begin.addr()..end.addr()).step_by(PAGE_SIZE).enumerate() {
+            let page = IpaAddr::new(page);
+            let index = self.index(page);
+            let dirty = self.dirty.get(index);
+
+            if dirty {
+                self.protect(ptable, page, page + PAGE_SIZE, mpool)?;
+                self.dirty.set(index, false);
+                bitmap[i / 8] |= 1 << (i % 8);
+                count += 1;
+            } else {
+                bitmap[i / 8] &= !(1 << (i % 8));
+            }
+        }
+
+        Ok(count)
+    }
+}
@@ -14,34 +14,551 @@
  * limitations under the License.
  */
 
+//! # The hypervisor's log.
+//!
+//! Everything the hypervisor logs, from C with `dlog()` and from Rust with `dlog!`, is buffered by
+//! the CPU logging it until its line is complete. So that CPUs don't wait for each other to log, as
+//! they would with a lock around the console, each CPU has a buffer of its own. The complete lines
+//! of all CPUs are written to the console by whichever CPU finds no other CPU doing so. Until
+//! memory management is initialised, the hypervisor runs on a single CPU and characters are
+//! written to the console as they are logged.
+//!
+//! Each line is prefixed with when the invocation of the log that started it started, read from
+//! the generic counter and in seconds with microsecond resolution, and the index of the CPU that
+//! logged it, e.g. `[   12.345678 1] `, so that the log can be correlated with those of VMs.
+//!
+//! The lines are written to the console in timestamp order, and in the order they were started if
+//! their timestamps are the same. A complete line is held back until no CPU can log a line with an
+//! earlier timestamp, i.e., until every CPU that has a line in progress or is in an invocation of
+//! the log has started it later. So a CPU that leaves its line incomplete holds back the lines of
+//! the other CPUs until it completes it.
+//!
+//! When the hypervisor aborts, e.g. because it panicked, `flush_sync()` writes the lines buffered
+//! by all CPUs, complete or not, to the console straight away, as the CPU writing them may be
+//! stuck, and everything logged after that is written to the console as it is logged.
+//!
+//! The console output is also kept in a ring of the most recent `RING_SIZE` bytes, which the
+//! primary VM drains with `hf_dlog_drain()`. So messages logged at early boot or when panicking
+//! can be read even if the console isn't working or is too slow. The oldest bytes are overwritten
+//! when the ring is full.
+
+use core::cell::UnsafeCell;
+use core::cmp;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
+use crate::cpu::Cpu;
 use crate::spinlock::*;
+use crate::types::*;
+use crate::utils::Backoff;
 
 extern "C" {
     fn plat_console_putchar(c: u8);
+    fn arch_timer_now_ticks() -> u64;
+    fn arch_timer_ticks_to_us(ticks: u64) -> u64;
+    fn arch_cpu_id() -> u64;
+    fn cpu_find(id: u64) -> *const Cpu;
+    fn cpu_index(c: *const Cpu) -> usize;
 }
 
-struct Writer {}
+/// The size of the ring of the hypervisor's recent log.
+const RING_SIZE: usize = 4096;
+
+/// The recent bytes of the log, oldest first, overwritten when full.
+struct Ring {
+    buf: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+}
 
-impl Writer {
+impl Ring {
     const fn new() -> Self {
-        Self {}
+        Self {
+            buf: [0; RING_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, b: u8) {
+        if self.len == RING_SIZE {
+            self.start = (self.start + 1) % RING_SIZE;
+            self.len -= 1;
+        }
+
+        self.buf[(self.start + self.len) % RING_SIZE] = b;
+        self.len += 1;
+    }
+
+    /// Moves the oldest bytes to `out`, as many as fit, and returns how many were moved.
+    fn drain(&mut self, out: &mut [u8]) -> usize {
+        let count = cmp::min(self.len, out.len());
+
+        for (i, b) in out[..count].iter_mut().enumerate() {
+            *b = self.buf[(self.start + i) % RING_SIZE];
+        }
+
+        self.start = (self.start + count) % RING_SIZE;
+        self.len -= count;
+        count
     }
 }
 
-impl fmt::Write for Writer {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for byte in s.bytes() {
+static RING: SpinLock<Ring> = SpinLock::new(Ring::new());
+
+/// The maximum length of a line in a CPU's buffer, including its newline, beyond which it is split.
+const LINE_MAX: usize = 128;
+
+/// The number of lines a CPU buffers until they are flushed to the console.
+const LINES_PER_CPU: usize = 4;
+
+/// A line buffered by a CPU.
+#[derive(Clone, Copy)]
+struct Line {
+    /// The order of the line among those of all CPUs, taken when the line is started.
+    seq: usize,
+
+    /// When the invocation of the log that started the line started, in timer ticks.
+    ticks: u64,
+    len: usize,
+    buf: [u8; LINE_MAX],
+}
+
+impl Line {
+    const fn new() -> Self {
+        Self {
+            seq: 0,
+            ticks: 0,
+            len: 0,
+            buf: [0; LINE_MAX],
+        }
+    }
+}
+
+/// The lines each CPU logged and that are not yet written to the console.
+///
+/// The lines of a CPU are a queue with a single producer, the CPU itself, which writes the line at
+/// `head` and publishes it by incrementing `head`, and a single consumer, the CPU holding `flush`,
+/// which writes the lines from `tail` to `head` to the console and frees them by incrementing
+/// `tail`. So CPUs log without waiting for each other, unless their buffers are full.
+struct Buffers {
+    lines: UnsafeCell<[[Line; LINES_PER_CPU]; MAX_CPUS]>,
+    head: UnsafeCell<[usize; MAX_CPUS]>,
+    tail: UnsafeCell<[usize; MAX_CPUS]>,
+
+    /// When each CPU's current invocation of the log started, in timer ticks.
+    stamps: UnsafeCell<[u64; MAX_CPUS]>,
+
+    /// The earliest timestamp each CPU may still give a line it has not published, in timer
+    /// ticks: that of its line in progress if any, or else that of its current invocation of the
+    /// log, or else `u64::MAX`. Only the lines stamped no later than all of them are written.
+    floors: UnsafeCell<[u64; MAX_CPUS]>,
+
+    /// Incremented whenever a line is published or a floor is raised, so that a CPU writing the
+    /// lines to the console knows if it should try again.
+    events: AtomicUsize,
+
+    /// Held by the CPU writing the buffered lines to the console.
+    flush: RawSpinLock,
+}
+
+unsafe impl Sync for Buffers {}
+
+static BUFFERS: Buffers = Buffers {
+    lines: UnsafeCell::new([[Line::new(); LINES_PER_CPU]; MAX_CPUS]),
+    head: UnsafeCell::new([0; MAX_CPUS]),
+    tail: UnsafeCell::new([0; MAX_CPUS]),
+    stamps: UnsafeCell::new([0; MAX_CPUS]),
+    floors: UnsafeCell::new([u64::max_value(); MAX_CPUS]),
+    events: AtomicUsize::new(0),
+    flush: RawSpinLock::new(),
+};
+
+/// Returned by `dlog_begin()` if CPUs are not initialised yet.
+const NO_CPU: usize = usize::max_value();
+
+/// The order of the next line to be started.
+static SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the next character written to the console unbuffered starts a line.
+static UNBUFFERED_LINE_START: AtomicBool = AtomicBool::new(true);
+
+/// Whether the log is buffered per CPU, which it is not until memory management is initialised,
+/// as the hypervisor runs on a single CPU until then.
+static BUFFERS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the hypervisor is aborting, after which the log is written to the console as it is
+/// logged, without buffering or locking.
+static SYNCHRONOUS: AtomicBool = AtomicBool::new(false);
+
+/// The number of times `flush_sync()` tries to take `flush` before it writes the buffered lines
+/// without it.
+const FLUSH_SYNC_TRIES: usize = 1 << 16;
+
+impl Buffers {
+    fn head(&self, cpu: usize) -> &AtomicUsize {
+        // `AtomicUsize` has the same in-memory representation as `usize`.
+        unsafe { &*(&(*self.head.get())[cpu] as *const usize as *const AtomicUsize) }
+    }
+
+    fn tail(&self, cpu: usize) -> &AtomicUsize {
+        // `AtomicUsize` has the same in-memory representation as `usize`.
+        unsafe { &*(&(*self.tail.get())[cpu] as *const usize as *const AtomicUsize) }
+    }
+
+    fn floor(&self, cpu: usize) -> &AtomicU64 {
+        // `AtomicU64` has the same in-memory representation as `u64`.
+        unsafe { &*(&(*self.floors.get())[cpu] as *const u64 as *const AtomicU64) }
+    }
+
+    /// Returns when the current invocation of the log by `cpu` started, in timer ticks.
+    fn stamp(&self, cpu: usize) -> u64 {
+        unsafe { (*self.stamps.get())[cpu] }
+    }
+
+    /// Returns whether `cpu`, which must be the current CPU, has a line in progress. If its
+    /// buffer is full, the line at `head` is the oldest one the consumer has yet to write.
+    fn has_partial_line(&self, cpu: usize) -> bool {
+        let head = self.head(cpu).load(Ordering::Relaxed);
+        head.wrapping_sub(self.tail(cpu).load(Ordering::Acquire)) != LINES_PER_CPU
+            && unsafe { self.line(cpu, head) }.len != 0
+    }
+
+    /// Starts an invocation of the log by `cpu`, which must be the current CPU.
+    fn begin(&self, cpu: usize) {
+        // A line in progress keeps the floor at its timestamp, which is earlier. Otherwise, the
+        // floor is lowered before the timer is read, so that no line stamped later than the
+        // invocation is written meanwhile.
+        let partial = self.has_partial_line(cpu);
+        if !partial {
+            self.floor(cpu).store(0, Ordering::SeqCst);
+        }
+
+        let ticks = unsafe { arch_timer_now_ticks() };
+        unsafe {
+            (*self.stamps.get())[cpu] = ticks;
+        }
+
+        if !partial {
+            self.floor(cpu).store(ticks, Ordering::SeqCst);
+        }
+    }
+
+    /// Ends the invocation of the log by `cpu`, which must be the current CPU. Unless it leaves
+    /// its line incomplete, the lines it held back are written.
+    fn end(&self, cpu: usize) {
+        if self.has_partial_line(cpu) {
+            return;
+        }
+
+        self.floor(cpu).store(u64::max_value(), Ordering::SeqCst);
+        self.events.fetch_add(1, Ordering::SeqCst);
+        if self.pending() {
+            self.flush();
+        }
+    }
+
+    /// Returns the line at `index` in the queue of `cpu`. Only the producer may access the line at
+    /// `head`, and only the consumer those from `tail` to `head`.
+    unsafe fn line(&self, cpu: usize, index: usize) -> &mut Line {
+        &mut (*self.lines.get())[cpu][index % LINES_PER_CPU]
+    }
+
+    /// Appends a character to the current line of `cpu`, which must be the current CPU, and
+    /// flushes the line if it is complete.
+    fn push(&self, cpu: usize, c: u8) {
+        let head = self.head(cpu).load(Ordering::Relaxed);
+
+        // Waits for the consumer to free a line if all of them are in use.
+        let mut backoff = Backoff::new();
+        while head.wrapping_sub(self.tail(cpu).load(Ordering::Acquire)) == LINES_PER_CPU {
+            self.flush();
+            backoff.spin();
+        }
+
+        let line = unsafe { self.line(cpu, head) };
+        if line.len == 0 {
+            line.seq = SEQ.fetch_add(1, Ordering::Relaxed);
+            line.ticks = self.stamp(cpu);
+        }
+
+        line.buf[line.len] = c;
+        line.len += 1;
+
+        if c != b'\n' && line.len < LINE_MAX - 1 {
+            return;
+        }
+
+        // Splits the line if it is too long, so that lines of other CPUs are not written into it.
+        if c != b'\n' {
+            line.buf[line.len] = b'\n';
+            line.len += 1;
+        }
+
+        self.head(cpu)
+            .store(head.wrapping_add(1), Ordering::Release);
+
+        // The next line of the invocation is stamped with its start.
+        self.floor(cpu).store(self.stamp(cpu), Ordering::SeqCst);
+        self.events.fetch_add(1, Ordering::SeqCst);
+        self.flush();
+    }
+
+    /// Returns whether any CPU has lines not yet written to the console.
+    fn pending(&self) -> bool {
+        (0..MAX_CPUS).any(|cpu| {
+            self.tail(cpu).load(Ordering::Relaxed) != self.head(cpu).load(Ordering::Acquire)
+        })
+    }
+
+    /// Writes the buffered lines of all CPUs to the console, oldest first, unless another CPU is
+    /// already writing them, in which case it also writes those buffered meanwhile.
+    fn flush(&self) {
+        loop {
+            if SYNCHRONOUS.load(Ordering::Acquire) || !self.flush.try_lock() {
+                return;
+            }
+
+            let events = self.events.load(Ordering::SeqCst);
+            self.merge(false);
+            self.flush.unlock();
+
+            // A line may have been published, or a floor raised, after the merge but before the
+            // unlock, by a CPU whose own flush failed to take the lock.
+            if self.events.load(Ordering::SeqCst) == events {
+                return;
+            }
+        }
+    }
+
+    /// Writes the buffered lines of all CPUs to the console in timestamp order, and in the order
+    /// they were started if their timestamps are the same. Unless `force`, it stops at a line
+    /// stamped later than the floor of any CPU, as that CPU may still log an earlier line. The
+    /// caller must hold `flush`, unless the hypervisor is aborting.
+    fn merge(&self, force: bool) {
+        loop {
+            let mut next: Option<(usize, u64, usize)> = None;
+
+            for cpu in 0..MAX_CPUS {
+                let tail = self.tail(cpu).load(Ordering::Relaxed);
+                if tail == self.head(cpu).load(Ordering::Acquire) {
+                    continue;
+                }
+
+                let line = unsafe { self.line(cpu, tail) };
+                // Sequence numbers wrap around, so they are compared by their difference.
+                let earlier = next.map_or(true, |(_, ticks, seq)| {
+                    line.ticks < ticks
+                        || (line.ticks == ticks && (line.seq.wrapping_sub(seq) as isize) < 0)
+                });
+                if earlier {
+                    next = Some((cpu, line.ticks, line.seq));
+                }
+            }
+
+            let (cpu, ticks) = match next {
+                Some((cpu, ticks, _)) => (cpu, ticks),
+                None => return,
+            };
+
+            if !force
+                && (0..MAX_CPUS).any(|other| self.floor(other).load(Ordering::SeqCst) < ticks)
+            {
+                return;
+            }
+
+            let tail = self.tail(cpu).load(Ordering::Relaxed);
+            let line = unsafe { self.line(cpu, tail) };
+            write_prefix(line.ticks, cpu);
+            for &c in &line.buf[..line.len] {
+                console_putchar(c);
+            }
+            line.len = 0;
+            self.tail(cpu)
+                .store(tail.wrapping_add(1), Ordering::Release);
+        }
+    }
+}
+
+/// Writes the lines buffered by all CPUs to the console, including those that are not complete, and
+/// then writes everything logged to the console as it is logged. This is for when the hypervisor
+/// aborts, so that the reason is not lost in a buffer.
+///
+/// The CPU writing the buffered lines to the console may be stuck, so `flush` is only waited for a
+/// while, and the lock of the ring is not waited for at all. The other CPUs are expected to be
+/// stopped, and their lines may be garbled if they keep logging.
+pub fn flush_sync() {
+    if !BUFFERS_ENABLED.load(Ordering::Acquire) || SYNCHRONOUS.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let locked = (0..FLUSH_SYNC_TRIES).any(|_| BUFFERS.flush.try_lock());
+    BUFFERS.merge(true);
+
+    for cpu in 0..MAX_CPUS {
+        let head = BUFFERS.head(cpu).load(Ordering::Acquire);
+        let line = unsafe { BUFFERS.line(cpu, head) };
+        if line.len == 0 {
+            continue;
+        }
+
+        write_prefix(line.ticks, cpu);
+        for &c in &line.buf[..line.len] {
+            console_putchar(c);
+        }
+        console_putchar(b'\n');
+        line.len = 0;
+    }
+
+    UNBUFFERED_LINE_START.store(true, Ordering::Relaxed);
+    if locked {
+        BUFFERS.flush.unlock();
+    }
+}
+
+/// Returns the index of the current CPU, or `None` if CPUs are not initialised yet.
+fn current_cpu() -> Option<usize> {
+    unsafe {
+        let cpu = cpu_find(arch_cpu_id());
+        if cpu.is_null() {
+            None
+        } else {
+            Some(cpu_index(cpu))
+        }
+    }
+}
+
+/// Writes a character to the console and to the ring of the recent log.
+fn console_putchar(c: u8) {
+    unsafe {
+        plat_console_putchar(c);
+    }
+
+    if !BUFFERS_ENABLED.load(Ordering::Acquire) {
+        unsafe { RING.get_mut_unchecked() }.push(c);
+    } else if !SYNCHRONOUS.load(Ordering::Acquire) {
+        RING.lock().push(c);
+    } else {
+        // The CPU holding the lock may be stuck, e.g. because it panicked while holding it.
+        match RING.try_lock() {
+            Some(mut ring) => ring.push(c),
+            None => unsafe { RING.get_mut_unchecked() }.push(c),
+        }
+    }
+}
+
+/// Writes the prefix of a line logged by `cpu` at `ticks` to the console: the time since the
+/// counter started, in seconds with microsecond resolution, and the index of the CPU.
+fn write_prefix(ticks: u64, cpu: usize) {
+    use core::fmt::Write;
+
+    let us = unsafe { arch_timer_ticks_to_us(ticks) };
+    let _ = write!(
+        Console,
+        "[{:5}.{:06} {}] ",
+        us / 1_000_000,
+        us % 1_000_000,
+        cpu
+    );
+}
+
+/// Writes a character of a line logged by `cpu` to the console, without buffering it.
+fn write_unbuffered(cpu: usize, c: u8) {
+    if UNBUFFERED_LINE_START.load(Ordering::Relaxed) {
+        write_prefix(BUFFERS.stamp(cpu), cpu);
+    }
+    console_putchar(c);
+    UNBUFFERED_LINE_START.store(c == b'\n', Ordering::Relaxed);
+}
+
+/// Starts an invocation of the log by the current CPU, which timestamps the lines it starts.
+/// Returns the CPU, which the invocation passes to `dlog_putchar()` and `dlog_end()` so that it is
+/// looked up only once.
+#[no_mangle]
+pub extern "C" fn dlog_begin() -> usize {
+    match current_cpu() {
+        Some(cpu) => {
+            BUFFERS.begin(cpu);
+            cpu
+        }
+        None => {
+            // The hypervisor runs on a single CPU until CPUs are initialised, and logs unbuffered.
             unsafe {
-                plat_console_putchar(byte);
+                (*BUFFERS.stamps.get())[0] = arch_timer_now_ticks();
             }
+            NO_CPU
+        }
+    }
+}
+
+/// Ends the invocation of the log by the given CPU, as returned by `dlog_begin()`.
+#[no_mangle]
+pub extern "C" fn dlog_end(cpu: usize) {
+    if cpu != NO_CPU {
+        BUFFERS.end(cpu);
+    }
+}
+
+/// Enables the per-CPU buffers of the log, and the locks of the console and the ring.
+#[no_mangle]
+pub extern "C" fn dlog_enable_buffers() {
+    BUFFERS_ENABLED.store(true, Ordering::Release);
+}
+
+/// Logs a character in the invocation of the log by the given CPU, as returned by `dlog_begin()`.
+/// It is buffered by the CPU until its line is complete, and then written to the console along
+/// with the complete lines of other CPUs. It is written to the console straight away if the
+/// hypervisor is aborting.
+#[no_mangle]
+pub extern "C" fn dlog_putchar(cpu: usize, c: u8) {
+    if !BUFFERS_ENABLED.load(Ordering::Acquire) || SYNCHRONOUS.load(Ordering::Acquire) {
+        write_unbuffered(if cpu == NO_CPU { 0 } else { cpu }, c);
+        return;
+    }
+
+    if cpu == NO_CPU {
+        BUFFERS.flush.lock();
+        write_unbuffered(0, c);
+        BUFFERS.flush.unlock();
+    } else {
+        BUFFERS.push(cpu, c);
+    }
+}
+
+/// Moves the oldest bytes of the hypervisor's log to `buf`, as many as fit, and returns the number
+/// of bytes written.
+#[no_mangle]
+pub unsafe extern "C" fn dlog_drain(buf: *mut u8, size: usize) -> usize {
+    let out = core::slice::from_raw_parts_mut(buf, size);
+
+    RING.lock().drain(out)
+}
+
+/// Writes to the console directly, for the prefixes of lines.
+struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            console_putchar(byte);
         }
         Ok(())
     }
 }
 
-static WRITER: SpinLock<Writer> = SpinLock::new(Writer::new());
+/// Writes to the log in an invocation by the given CPU, as returned by `dlog_begin()`.
+struct Writer {
+    cpu: usize,
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            dlog_putchar(self.cpu, byte);
+        }
+        Ok(())
+    }
+}
 
 #[macro_export]
 macro_rules! dlog {
@@ -51,5 +568,7 @@ macro_rules! dlog {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+    let cpu = dlog_begin();
+    Writer { cpu }.write_fmt(args).unwrap();
+    dlog_end(cpu);
 }
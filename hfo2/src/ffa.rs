@@ -0,0 +1,422 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Arm Firmware Framework for A-profile (FF-A) front-end.
+//!
+//! FF-A calls are SMCs whose function ID is in the FF-A range, with their arguments and results in
+//! `x0`-`x7`.  They are decoded here and routed to the existing API handlers.  Only the SMC conduit
+//! is decoded as FF-A: HVCs keep the Hafnium ABI, whose SPCI alpha function IDs overlap with FF-A's,
+//! and only return `x0`.
+
+use crate::api::HfShare;
+use crate::capability::Capabilities;
+use crate::cpu::CVCpu;
+use crate::ffa_memory;
+use crate::page::*;
+use crate::types::*;
+
+extern "C" {
+    fn api_vm_get_id(current: *const CVCpu) -> u16;
+    fn api_spci_yield(current: *mut CVCpu, next: *mut *mut CVCpu) -> i32;
+    fn api_vm_configure(
+        send: IpaAddr,
+        recv: IpaAddr,
+        current: *mut CVCpu,
+        next: *mut *mut CVCpu,
+    ) -> i64;
+    fn api_vm_unconfigure(current: *mut CVCpu) -> i64;
+    fn api_mailbox_clear(current: *mut CVCpu, next: *mut *mut CVCpu) -> i64;
+    fn api_spci_msg_recv(
+        attributes: u32,
+        timeout: u64,
+        current: *mut CVCpu,
+        next: *mut *mut CVCpu,
+    ) -> i32;
+    fn api_ffa_msg_send_direct_req(
+        vm_id: u16,
+        args: *const uintreg_t,
+        current: *mut CVCpu,
+        next: *mut *mut CVCpu,
+    ) -> i32;
+    fn api_ffa_msg_send_direct_resp(
+        vm_id: u16,
+        args: *const uintreg_t,
+        current: *mut CVCpu,
+        next: *mut *mut CVCpu,
+    ) -> i32;
+    fn api_partition_info_get(current: *mut CVCpu, uuid: *const u32) -> i64;
+}
+
+/// The FF-A version implemented, 1.0.
+pub const FFA_VERSION_MAJOR: u32 = 1;
+pub const FFA_VERSION_MINOR: u32 = 0;
+const FFA_VERSION_MAJOR_OFFSET: u32 = 16;
+
+/// The function IDs of FF-A.
+pub const FFA_ERROR_32: u32 = 0x8400_0060;
+pub const FFA_SUCCESS_32: u32 = 0x8400_0061;
+pub const FFA_INTERRUPT_32: u32 = 0x8400_0062;
+pub const FFA_VERSION_32: u32 = 0x8400_0063;
+pub const FFA_FEATURES_32: u32 = 0x8400_0064;
+pub const FFA_RX_RELEASE_32: u32 = 0x8400_0065;
+pub const FFA_RXTX_MAP_32: u32 = 0x8400_0066;
+pub const FFA_RXTX_MAP_64: u32 = 0xc400_0066;
+pub const FFA_RXTX_UNMAP_32: u32 = 0x8400_0067;
+pub const FFA_PARTITION_INFO_GET_32: u32 = 0x8400_0068;
+pub const FFA_ID_GET_32: u32 = 0x8400_0069;
+pub const FFA_MSG_POLL_32: u32 = 0x8400_006a;
+pub const FFA_MSG_WAIT_32: u32 = 0x8400_006b;
+pub const FFA_YIELD_32: u32 = 0x8400_006c;
+pub const FFA_RUN_32: u32 = 0x8400_006d;
+pub const FFA_MSG_SEND_32: u32 = 0x8400_006e;
+pub const FFA_MSG_SEND_DIRECT_REQ_32: u32 = 0x8400_006f;
+pub const FFA_MSG_SEND_DIRECT_RESP_32: u32 = 0x8400_0070;
+pub const FFA_MEM_DONATE_32: u32 = 0x8400_0071;
+pub const FFA_MEM_LEND_32: u32 = 0x8400_0072;
+pub const FFA_MEM_SHARE_32: u32 = 0x8400_0073;
+pub const FFA_MEM_RETRIEVE_REQ_32: u32 = 0x8400_0074;
+pub const FFA_MEM_RETRIEVE_RESP_32: u32 = 0x8400_0075;
+pub const FFA_MEM_RELINQUISH_32: u32 = 0x8400_0076;
+pub const FFA_MEM_RECLAIM_32: u32 = 0x8400_0077;
+pub const FFA_MEM_FRAG_RX_32: u32 = 0x8400_007a;
+pub const FFA_MEM_FRAG_TX_32: u32 = 0x8400_007b;
+
+/// The size of a partition descriptor written by `FFA_PARTITION_INFO_GET`, i.e. `struct
+/// ffa_partition_info`.
+const PARTITION_INFO_SIZE: usize = 24;
+
+/// The attributes of `api_spci_msg_recv()` to block until a message is received.
+const SPCI_MSG_RECV_BLOCK: u32 = 0x1;
+
+/// Checks whether the function ID is in one of the ranges reserved for FF-A, for the SMC32 and
+/// SMC64 calling conventions.
+pub fn is_ffa_function(func: u32) -> bool {
+    (0x8400_0060..=0x8400_00ff).contains(&func) || (0xc400_0060..=0xc400_00ff).contains(&func)
+}
+
+/// The error codes of FF-A, returned in `w2` of `FFA_ERROR`. They are the same as SPCI's.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(i32)]
+pub enum FfaError {
+    NotSupported = -1,
+    InvalidParameters = -2,
+    NoMemory = -3,
+    Busy = -4,
+    Interrupted = -5,
+    Denied = -6,
+    Retry = -7,
+    Aborted = -8,
+}
+
+impl FfaError {
+    /// Converts an SPCI return code, which is the same as FF-A's. Returns `None` on success or an
+    /// unknown code.
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            -1 => Some(FfaError::NotSupported),
+            -2 => Some(FfaError::InvalidParameters),
+            -3 => Some(FfaError::NoMemory),
+            -4 => Some(FfaError::Busy),
+            -5 => Some(FfaError::Interrupted),
+            -6 => Some(FfaError::Denied),
+            -7 => Some(FfaError::Retry),
+            -8 => Some(FfaError::Aborted),
+            _ => None,
+        }
+    }
+}
+
+/// The arguments or results of an FF-A call, in `x0`-`x7`. It has the same representation as
+/// `struct ffa_value`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct FfaValue {
+    pub func: uintreg_t,
+    pub arg1: uintreg_t,
+    pub arg2: uintreg_t,
+    pub arg3: uintreg_t,
+    pub arg4: uintreg_t,
+    pub arg5: uintreg_t,
+    pub arg6: uintreg_t,
+    pub arg7: uintreg_t,
+}
+
+impl FfaValue {
+    /// Returns `FFA_SUCCESS` on `SPCI_SUCCESS`, or `FFA_ERROR` with the code otherwise.
+    pub fn from_code(code: i32) -> Self {
+        match FfaError::from_code(code) {
+            None => Self::success(0),
+            Some(e) => Self::error(e),
+        }
+    }
+
+    /// Returns `FFA_SUCCESS` with the given value in `w2`.
+    pub fn success(arg2: uintreg_t) -> Self {
+        Self {
+            func: FFA_SUCCESS_32 as uintreg_t,
+            arg2,
+            ..Default::default()
+        }
+    }
+
+    /// Returns `FFA_ERROR` with the given error code.
+    pub fn error(error: FfaError) -> Self {
+        Self {
+            func: FFA_ERROR_32 as uintreg_t,
+            // The error code is a signed 32-bit value in `w2`.
+            arg2: error as i32 as u32 as uintreg_t,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the function ID.
+    pub fn func(&self) -> u32 {
+        self.func as u32
+    }
+
+    /// Returns the registers, from `x0` to `x7`.
+    pub fn to_regs(&self) -> [uintreg_t; 8] {
+        [
+            self.func, self.arg1, self.arg2, self.arg3, self.arg4, self.arg5, self.arg6, self.arg7,
+        ]
+    }
+}
+
+/// Returns the implemented version, or `NOT_SUPPORTED` if the caller's version is malformed. The
+/// caller decides whether it is compatible with the implemented version.
+fn version(requested: u32) -> FfaValue {
+    if requested & 0x8000_0000 != 0 {
+        // `FFA_VERSION` returns the error code in `w0` rather than through `FFA_ERROR`.
+        return FfaValue {
+            func: FfaError::NotSupported as i32 as u32 as uintreg_t,
+            ..Default::default()
+        };
+    }
+
+    FfaValue {
+        func: ((FFA_VERSION_MAJOR << FFA_VERSION_MAJOR_OFFSET) | FFA_VERSION_MINOR) as uintreg_t,
+        ..Default::default()
+    }
+}
+
+/// The FF-A functions routed by `handle()`, which `FFA_FEATURES` reports as supported.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Function {
+    Version,
+    Features,
+    IdGet,
+    Yield,
+    RxTxMap,
+    RxTxUnmap,
+    RxRelease,
+    PartitionInfoGet,
+    MsgWait,
+    MsgSendDirectReq,
+    MsgSendDirectResp,
+    MemDonate,
+    MemLend,
+    MemShare,
+    MemFragTx,
+}
+
+impl Function {
+    /// Decodes the function ID. Returns `None` if the function is not supported.
+    fn from_id(func: u32) -> Option<Self> {
+        let function = match func {
+            FFA_VERSION_32 => Function::Version,
+            FFA_FEATURES_32 => Function::Features,
+            FFA_ID_GET_32 => Function::IdGet,
+            FFA_YIELD_32 => Function::Yield,
+            FFA_RXTX_MAP_32 | FFA_RXTX_MAP_64 => Function::RxTxMap,
+            FFA_RXTX_UNMAP_32 => Function::RxTxUnmap,
+            FFA_RX_RELEASE_32 => Function::RxRelease,
+            FFA_PARTITION_INFO_GET_32 => Function::PartitionInfoGet,
+            FFA_MSG_WAIT_32 => Function::MsgWait,
+            FFA_MSG_SEND_DIRECT_REQ_32 => Function::MsgSendDirectReq,
+            FFA_MSG_SEND_DIRECT_RESP_32 => Function::MsgSendDirectResp,
+            FFA_MEM_DONATE_32 => Function::MemDonate,
+            FFA_MEM_LEND_32 => Function::MemLend,
+            FFA_MEM_SHARE_32 => Function::MemShare,
+            FFA_MEM_FRAG_TX_32 => Function::MemFragTx,
+            _ => return None,
+        };
+
+        Some(function)
+    }
+
+    /// Returns whether the function shares memory, which needs the `MEMORY_SHARING` capability.
+    fn shares_memory(self) -> bool {
+        match self {
+            Function::MemDonate | Function::MemLend | Function::MemShare | Function::MemFragTx => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Reports whether the given function is supported.
+fn features(func: u32) -> FfaValue {
+    if Function::from_id(func).is_some() {
+        FfaValue::success(0)
+    } else {
+        FfaValue::error(FfaError::NotSupported)
+    }
+}
+
+/// Writes the descriptors of the partitions with the UUID in `w1`-`w4` to the RX buffer, or of all
+/// partitions if it is null. Returns their number in `w2`, and the size of a descriptor in `w3`.
+unsafe fn partition_info_get(current: *mut CVCpu, args: &FfaValue) -> FfaValue {
+    let uuid = [
+        args.arg1 as u32,
+        args.arg2 as u32,
+        args.arg3 as u32,
+        args.arg4 as u32,
+    ];
+
+    let ret = api_partition_info_get(current, uuid.as_ptr());
+    if ret < 0 {
+        return FfaValue::error(FfaError::from_code(ret as i32).unwrap_or(FfaError::Denied));
+    }
+
+    FfaValue {
+        arg3: PARTITION_INFO_SIZE,
+        ..FfaValue::success(ret as uintreg_t)
+    }
+}
+
+/// Sends a direct request or response in registers, whose sender and receiver are in `w1`. On
+/// success, the current vCPU is switched out, and the results returned here are overwritten when
+/// it receives a response or request.
+unsafe fn msg_send_direct(current: *mut CVCpu, args: &FfaValue, next: *mut *mut CVCpu) -> FfaValue {
+    let sender = (args.arg1 >> 16) as u16;
+    let receiver = args.arg1 as u16;
+    if sender != api_vm_get_id(current) {
+        return FfaValue::error(FfaError::InvalidParameters);
+    }
+
+    let regs = args.to_regs();
+    let ret = if args.func() == FFA_MSG_SEND_DIRECT_REQ_32 {
+        api_ffa_msg_send_direct_req(receiver, regs.as_ptr(), current, next)
+    } else {
+        api_ffa_msg_send_direct_resp(receiver, regs.as_ptr(), current, next)
+    };
+
+    match FfaError::from_code(ret) {
+        None => FfaValue::error(FfaError::Interrupted),
+        Some(e) => FfaValue::error(e),
+    }
+}
+
+/// Handles an FF-A call of the current vCPU. Returns `None` if the function ID isn't FF-A's.
+///
+/// `next` is set to the vCPU to run next if the current vCPU is to be switched out.
+pub unsafe fn handle(
+    current: *mut CVCpu,
+    args: &FfaValue,
+    next: *mut *mut CVCpu,
+) -> Option<FfaValue> {
+    let func = args.func();
+    if !is_ffa_function(func) {
+        return None;
+    }
+
+    let function = match Function::from_id(func) {
+        Some(function) => function,
+        None => return Some(FfaValue::error(FfaError::NotSupported)),
+    };
+
+    if function.shares_memory()
+        && !Capabilities::of(current).contains(Capabilities::MEMORY_SHARING)
+    {
+        return Some(FfaValue::error(FfaError::Denied));
+    }
+
+    let ret = match function {
+        Function::Version => version(args.arg1 as u32),
+        Function::Features => features(args.arg1 as u32),
+        Function::IdGet => FfaValue::success(api_vm_get_id(current) as uintreg_t),
+        Function::Yield => {
+            api_spci_yield(current, next);
+            FfaValue::success(0)
+        }
+        Function::RxTxMap => {
+            // The mailbox is a single page.
+            if args.arg3 != HF_MAILBOX_SIZE / PAGE_SIZE {
+                FfaValue::error(FfaError::InvalidParameters)
+            } else if api_vm_configure(
+                IpaAddr::new(args.arg1),
+                IpaAddr::new(args.arg2),
+                current,
+                next,
+            ) < 0
+            {
+                FfaValue::error(FfaError::InvalidParameters)
+            } else {
+                FfaValue::success(0)
+            }
+        }
+        Function::RxTxUnmap => {
+            // Bits [31:16] hold the ID of the VM whose buffers are unmapped, which can only be
+            // the caller's own.
+            let vm_id = (args.arg1 >> 16) as u16;
+            if (vm_id != 0 && vm_id != api_vm_get_id(current)) || api_vm_unconfigure(current) < 0 {
+                FfaValue::error(FfaError::InvalidParameters)
+            } else {
+                FfaValue::success(0)
+            }
+        }
+        Function::RxRelease => {
+            if api_mailbox_clear(current, next) < 0 {
+                FfaValue::error(FfaError::Denied)
+            } else {
+                FfaValue::success(0)
+            }
+        }
+        Function::PartitionInfoGet => partition_info_get(current, args),
+        Function::MsgWait => {
+            // A message in the RX buffer is delivered with `SPCI_SUCCESS` in `w0`.
+            FfaValue::from_code(api_spci_msg_recv(SPCI_MSG_RECV_BLOCK, 0, current, next))
+        }
+        Function::MsgSendDirectReq | Function::MsgSendDirectResp => {
+            msg_send_direct(current, args, next)
+        }
+        Function::MemDonate => ffa_memory::send(current, HfShare::Give, args),
+        Function::MemLend => ffa_memory::send(current, HfShare::Lend, args),
+        Function::MemShare => ffa_memory::send(current, HfShare::Share, args),
+        Function::MemFragTx => ffa_memory::frag_tx(current, args),
+    };
+
+    Some(ret)
+}
+
+/// Handles an FF-A call of the current vCPU, and writes the results to `ret`. Returns whether the
+/// function ID is FF-A's.
+#[no_mangle]
+pub unsafe extern "C" fn ffa_handler(
+    current: *mut CVCpu,
+    args: *const FfaValue,
+    ret: *mut FfaValue,
+    next: *mut *mut CVCpu,
+) -> bool {
+    match handle(current, &*args, next) {
+        Some(value) => {
+            *ret = value;
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,497 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # FF-A memory transaction descriptors.
+//!
+//! `FFA_MEM_DONATE`, `FFA_MEM_LEND` and `FFA_MEM_SHARE` describe the memory to send with a memory
+//! transaction descriptor in the sender's TX buffer: a header, the receivers and their permissions,
+//! and a composite memory region listing the constituent ranges of pages.  A descriptor larger
+//! than the TX buffer is sent in fragments, the first one with the call and the others with
+//! `FFA_MEM_FRAG_TX`, and the transaction is pending until all of them are received.
+//!
+//! The TX buffer is copied before it is parsed, and every field is read once, so the sender can't
+//! change a descriptor after it is validated.  The header and the composite memory region header
+//! should be in the first fragment, and the following fragments should hold whole constituents.
+
+use arrayvec::ArrayVec;
+
+use crate::api::{HfShare, MemRange};
+use crate::cpu::CVCpu;
+use crate::ffa::*;
+use crate::mm::{Mode, MAP_TRANSACTION_MAX_RANGES};
+use crate::page::*;
+use crate::spinlock::SpinLock;
+use crate::types::*;
+
+extern "C" {
+    fn api_vm_get_id(current: *const CVCpu) -> u16;
+    fn api_mailbox_send_copy(current: *mut CVCpu, buf: *mut u8, size: usize) -> bool;
+    fn api_share_memory_ranges(
+        vm_id: u16,
+        ranges: *const MemRange,
+        count: usize,
+        share: u32,
+        mode: c_int,
+        current: *mut CVCpu,
+    ) -> i32;
+}
+
+/// The size of the header of a memory transaction descriptor.
+const MEMORY_REGION_SIZE: usize = 32;
+
+/// The size of the permissions of a receiver.
+const MEMORY_ACCESS_SIZE: usize = 16;
+
+/// The size of the header of a composite memory region.
+const COMPOSITE_SIZE: usize = 16;
+
+/// The size of a constituent of a composite memory region.
+const CONSTITUENT_SIZE: usize = 16;
+
+/// The maximum number of constituents of a transaction. They are shared in a single page table
+/// transaction.
+pub const MAX_CONSTITUENTS: usize = MAP_TRANSACTION_MAX_RANGES;
+
+/// The maximum number of transactions whose fragments are being received.
+const MAX_PENDING: usize = 4;
+
+/// The data access permissions of a receiver, in bits [1:0] of its permissions.
+const DATA_ACCESS_MASK: u8 = 0x3;
+const DATA_ACCESS_NOT_SPECIFIED: u8 = 0x0;
+const DATA_ACCESS_RO: u8 = 0x1;
+const DATA_ACCESS_RW: u8 = 0x2;
+
+/// The instruction access permissions of a receiver, in bits [3:2] of its permissions.
+const INSTRUCTION_ACCESS_MASK: u8 = 0xc;
+const INSTRUCTION_ACCESS_NOT_SPECIFIED: u8 = 0x0;
+const INSTRUCTION_ACCESS_NX: u8 = 0x4;
+const INSTRUCTION_ACCESS_X: u8 = 0x8;
+
+/// The memory type, in bits [5:4] of the memory region attributes.
+const MEMORY_TYPE_MASK: u8 = 0x30;
+const MEMORY_TYPE_NOT_SPECIFIED: u8 = 0x00;
+const MEMORY_TYPE_DEVICE: u8 = 0x10;
+const MEMORY_TYPE_NORMAL: u8 = 0x20;
+
+/// Reads the little-endian fields of a descriptor, failing if they are out of bounds.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], FfaError> {
+        let end = offset.checked_add(len).ok_or(FfaError::InvalidParameters)?;
+        self.buf.get(offset..end).ok_or(FfaError::InvalidParameters)
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8, FfaError> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, FfaError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.bytes(offset, 2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, FfaError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(offset, 4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64, FfaError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(offset, 8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+/// A receiver of a memory transaction and its permissions.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Receiver {
+    pub id: u16,
+    pub permissions: u8,
+    pub flags: u8,
+}
+
+/// A range of pages of a memory transaction.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Constituent {
+    pub address: IpaAddr,
+    pub page_count: u32,
+}
+
+impl Constituent {
+    fn range(&self) -> MemRange {
+        MemRange {
+            begin: self.address,
+            end: self.address + self.page_count as usize * PAGE_SIZE,
+        }
+    }
+}
+
+/// A parsed memory transaction descriptor. The constituents may not be all received yet.
+pub struct MemoryRegion {
+    pub sender: u16,
+    pub attributes: u8,
+    pub flags: u32,
+    pub tag: u64,
+
+    /// The receiver. Only a single receiver is supported.
+    pub receiver: Receiver,
+
+    /// The total number of pages of the constituents.
+    pub page_count: u32,
+
+    /// The total number of constituents.
+    pub constituent_count: u32,
+
+    /// The constituents received so far.
+    pub constituents: ArrayVec<[Constituent; MAX_CONSTITUENTS]>,
+}
+
+impl MemoryRegion {
+    /// Parses the first fragment of a descriptor of `total_length` bytes.
+    pub fn parse(buf: &[u8], total_length: usize) -> Result<Self, FfaError> {
+        let reader = Reader { buf };
+
+        let sender = reader.u16(0)?;
+        let attributes = reader.u8(2)?;
+        let flags = reader.u32(4)?;
+        let handle = reader.u64(8)?;
+        let tag = reader.u64(16)?;
+        let receiver_count = reader.u32(28)?;
+
+        // The handle is allocated by the hypervisor.
+        if reader.u8(3)? != 0 || reader.u32(24)? != 0 || handle != 0 {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        match receiver_count {
+            0 => return Err(FfaError::InvalidParameters),
+            1 => (),
+            _ => return Err(FfaError::NotSupported),
+        }
+
+        let receiver = Receiver {
+            id: reader.u16(MEMORY_REGION_SIZE)?,
+            permissions: reader.u8(MEMORY_REGION_SIZE + 2)?,
+            flags: reader.u8(MEMORY_REGION_SIZE + 3)?,
+        };
+        let composite_offset = reader.u32(MEMORY_REGION_SIZE + 4)? as usize;
+        if reader.u64(MEMORY_REGION_SIZE + 8)? != 0 {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        // The composite memory region follows the receivers, aligned to 8 bytes.
+        if composite_offset < MEMORY_REGION_SIZE + MEMORY_ACCESS_SIZE || composite_offset % 8 != 0 {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        let page_count = reader.u32(composite_offset)?;
+        let constituent_count = reader.u32(composite_offset + 4)?;
+        if reader.u64(composite_offset + 8)? != 0 {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        let constituents_offset = composite_offset + COMPOSITE_SIZE;
+        let length = (constituent_count as usize)
+            .checked_mul(CONSTITUENT_SIZE)
+            .and_then(|size| size.checked_add(constituents_offset))
+            .ok_or(FfaError::InvalidParameters)?;
+        if constituent_count == 0 || length != total_length {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        if constituent_count as usize > MAX_CONSTITUENTS {
+            return Err(FfaError::NotSupported);
+        }
+
+        let mut region = Self {
+            sender,
+            attributes,
+            flags,
+            tag,
+            receiver,
+            page_count,
+            constituent_count,
+            constituents: ArrayVec::new(),
+        };
+        region.add_constituents(&buf[constituents_offset..])?;
+        Ok(region)
+    }
+
+    /// Parses the constituents of a fragment.
+    pub fn add_constituents(&mut self, buf: &[u8]) -> Result<(), FfaError> {
+        if buf.len() % CONSTITUENT_SIZE != 0 {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        let reader = Reader { buf };
+        for offset in (0..buf.len()).step_by(CONSTITUENT_SIZE) {
+            let address = reader.u64(offset)? as usize;
+            let page_count = reader.u32(offset + 8)?;
+            if reader.u32(offset + 12)? != 0 || address % PAGE_SIZE != 0 || page_count == 0 {
+                return Err(FfaError::InvalidParameters);
+            }
+
+            // The range should not wrap around.
+            (page_count as usize)
+                .checked_mul(PAGE_SIZE)
+                .and_then(|size| address.checked_add(size))
+                .ok_or(FfaError::InvalidParameters)?;
+
+            self.constituents
+                .try_push(Constituent {
+                    address: IpaAddr::new(address),
+                    page_count,
+                })
+                .map_err(|_| FfaError::InvalidParameters)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether all the constituents are received.
+    pub fn is_complete(&self) -> bool {
+        self.constituents.len() == self.constituent_count as usize
+    }
+
+    /// Validates the complete descriptor of a transaction from `sender`, and returns the mode to
+    /// map the memory for the receiver.
+    pub fn validate(&self, sender: u16, share: HfShare) -> Result<Mode, FfaError> {
+        if self.sender != sender || self.receiver.id == sender {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        let page_count = self
+            .constituents
+            .iter()
+            .map(|constituent| constituent.page_count as u64)
+            .sum::<u64>();
+        if page_count != self.page_count as u64 {
+            return Err(FfaError::InvalidParameters);
+        }
+
+        match self.attributes & MEMORY_TYPE_MASK {
+            MEMORY_TYPE_NOT_SPECIFIED | MEMORY_TYPE_NORMAL => (),
+            MEMORY_TYPE_DEVICE => return Err(FfaError::NotSupported),
+            _ => return Err(FfaError::InvalidParameters),
+        }
+
+        let mut mode = match self.receiver.permissions & DATA_ACCESS_MASK {
+            DATA_ACCESS_NOT_SPECIFIED | DATA_ACCESS_RW => Mode::R | Mode::W,
+            DATA_ACCESS_RO => Mode::R,
+            _ => return Err(FfaError::InvalidParameters),
+        };
+
+        match self.receiver.permissions & INSTRUCTION_ACCESS_MASK {
+            INSTRUCTION_ACCESS_NOT_SPECIFIED | INSTRUCTION_ACCESS_NX => (),
+            // Memory shared concurrently is not executable.
+            INSTRUCTION_ACCESS_X if share != HfShare::Share => mode |= Mode::X,
+            _ => return Err(FfaError::InvalidParameters),
+        }
+
+        Ok(mode)
+    }
+}
+
+/// A transaction whose fragments are being received.
+struct Pending {
+    handle: u64,
+    share: HfShare,
+    total_length: usize,
+    received_length: usize,
+    region: MemoryRegion,
+}
+
+struct PendingTable {
+    next_handle: u64,
+    pending: [Option<Pending>; MAX_PENDING],
+}
+
+static PENDING: SpinLock<PendingTable> = SpinLock::new(PendingTable {
+    next_handle: 1,
+    pending: [None, None, None, None],
+});
+
+/// The copy of a fragment in a TX buffer, where it is parsed.
+static FRAGMENT: SpinLock<[u8; HF_MAILBOX_SIZE]> = SpinLock::new([0; HF_MAILBOX_SIZE]);
+
+impl PendingTable {
+    fn alloc_handle(&mut self) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        handle
+    }
+}
+
+/// Returns `FFA_MEM_FRAG_RX`, asking for the fragment at `offset` of the given transaction.
+fn frag_rx(handle: u64, offset: usize) -> FfaValue {
+    FfaValue {
+        func: FFA_MEM_FRAG_RX_32 as uintreg_t,
+        arg1: handle as u32 as uintreg_t,
+        arg2: (handle >> 32) as u32 as uintreg_t,
+        arg3: offset,
+        ..Default::default()
+    }
+}
+
+/// Copies a fragment of `length` bytes from the TX buffer of the current vCPU's VM, and parses it
+/// with `f`.
+unsafe fn with_fragment<F, R>(current: *mut CVCpu, length: usize, f: F) -> Result<R, FfaError>
+where
+    F: FnOnce(&[u8]) -> Result<R, FfaError>,
+{
+    if length > HF_MAILBOX_SIZE {
+        return Err(FfaError::InvalidParameters);
+    }
+
+    let mut fragment = FRAGMENT.lock();
+    if !api_mailbox_send_copy(current, fragment.as_mut_ptr(), length) {
+        return Err(FfaError::Denied);
+    }
+
+    f(&fragment[..length])
+}
+
+/// Shares the memory of a complete transaction.
+unsafe fn commit(
+    current: *mut CVCpu,
+    handle: u64,
+    share: HfShare,
+    region: &MemoryRegion,
+) -> FfaValue {
+    let mode = match region.validate(api_vm_get_id(current), share) {
+        Ok(mode) => mode,
+        Err(e) => return FfaValue::error(e),
+    };
+
+    let ranges = region
+        .constituents
+        .iter()
+        .map(Constituent::range)
+        .collect::<ArrayVec<[MemRange; MAX_CONSTITUENTS]>>();
+
+    let ret = api_share_memory_ranges(
+        region.receiver.id,
+        ranges.as_ptr(),
+        ranges.len(),
+        share as u32,
+        mode.bits() as c_int,
+        current,
+    );
+    match FfaError::from_code(ret) {
+        None => FfaValue {
+            func: FFA_SUCCESS_32 as uintreg_t,
+            arg2: handle as u32 as uintreg_t,
+            arg3: (handle >> 32) as u32 as uintreg_t,
+            ..Default::default()
+        },
+        Some(e) => FfaValue::error(e),
+    }
+}
+
+/// Handles `FFA_MEM_DONATE`, `FFA_MEM_LEND` and `FFA_MEM_SHARE`, whose descriptor is in the TX
+/// buffer.
+pub unsafe fn send(current: *mut CVCpu, share: HfShare, args: &FfaValue) -> FfaValue {
+    let total_length = args.arg1 as u32 as usize;
+    let fragment_length = args.arg2 as u32 as usize;
+
+    // Descriptors in buffers other than the TX buffer are not supported.
+    if args.arg3 != 0 || args.arg4 != 0 {
+        return FfaValue::error(FfaError::NotSupported);
+    }
+
+    if fragment_length > total_length {
+        return FfaValue::error(FfaError::InvalidParameters);
+    }
+
+    let region = match with_fragment(current, fragment_length, |buf| {
+        MemoryRegion::parse(buf, total_length)
+    }) {
+        Ok(region) => region,
+        Err(e) => return FfaValue::error(e),
+    };
+
+    let mut table = PENDING.lock();
+    let handle = table.alloc_handle();
+
+    if fragment_length == total_length {
+        drop(table);
+        if !region.is_complete() {
+            return FfaValue::error(FfaError::InvalidParameters);
+        }
+        return commit(current, handle, share, &region);
+    }
+
+    let slot = match table.pending.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => slot,
+        None => return FfaValue::error(FfaError::NoMemory),
+    };
+    *slot = Some(Pending {
+        handle,
+        share,
+        total_length,
+        received_length: fragment_length,
+        region,
+    });
+
+    frag_rx(handle, fragment_length)
+}
+
+/// Handles `FFA_MEM_FRAG_TX`, which sends the next fragment of a pending transaction in the TX
+/// buffer.
+pub unsafe fn frag_tx(current: *mut CVCpu, args: &FfaValue) -> FfaValue {
+    let handle = (args.arg1 as u32 as u64) | ((args.arg2 as u32 as u64) << 32);
+    let fragment_length = args.arg3 as u32 as usize;
+    let sender = api_vm_get_id(current);
+
+    let mut table = PENDING.lock();
+    let slot = match table.pending.iter_mut().find(|slot| match slot {
+        Some(pending) => pending.handle == handle && pending.region.sender == sender,
+        None => false,
+    }) {
+        Some(slot) => slot,
+        None => return FfaValue::error(FfaError::InvalidParameters),
+    };
+
+    // The transaction is aborted if a fragment is invalid.
+    let mut pending = slot.take().unwrap();
+    if pending.received_length + fragment_length > pending.total_length {
+        return FfaValue::error(FfaError::InvalidParameters);
+    }
+
+    if let Err(e) = with_fragment(current, fragment_length, |buf| {
+        pending.region.add_constituents(buf)
+    }) {
+        return FfaValue::error(e);
+    }
+    pending.received_length += fragment_length;
+
+    if pending.received_length < pending.total_length {
+        let offset = pending.received_length;
+        *slot = Some(pending);
+        return frag_rx(handle, offset);
+    }
+
+    drop(table);
+    if !pending.region.is_complete() {
+        return FfaValue::error(FfaError::InvalidParameters);
+    }
+    commit(current, handle, pending.share, &pending.region)
+}
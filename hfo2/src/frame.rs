@@ -0,0 +1,173 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Ownership of physical frames.
+//!
+//! The frame table records the VM owning each physical page of memory, i.e., the VM whose stage-2
+//! page table maps the page as owned, so that the owner of a page is looked up without walking the
+//! page tables of all VMs.  It is updated whenever a stage-2 page table with an owner is updated.
+//! Pages out of the range of the frame table, e.g. device memory, are not tracked.
+
+use core::slice;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::mpool::MPool;
+use crate::once::Once;
+use crate::page::*;
+use crate::types::*;
+use crate::utils::*;
+
+/// The owner of the page table of no VM.
+pub const NO_OWNER: u32 = u32::max_value();
+
+/// The frame table. Each entry is 0 if the page has no owner, or the owner's ID plus one.
+struct FrameTable {
+    /// The physical address of the first page.
+    begin: usize,
+
+    /// The entries, one per page.
+    entries: &'static [AtomicU8],
+}
+
+static FRAME_TABLE: Once<FrameTable> = Once::new();
+
+const_assert!(max_vms_in_entry; MAX_VMS < u8::max_value() as usize);
+
+impl FrameTable {
+    /// Returns the entries of the pages in `[begin, end)` that are in the range of the frame
+    /// table.
+    fn entries(&self, begin: PhysAddr, end: PhysAddr) -> &[AtomicU8] {
+        let count = self.entries.len();
+        let first = begin.addr().saturating_sub(self.begin) / PAGE_SIZE;
+        let last = div_ceil(end.addr().saturating_sub(self.begin), PAGE_SIZE);
+        let last = if last > count { count } else { last };
+
+        if first >= last {
+            return &[];
+        }
+
+        &self.entries[first..last]
+    }
+}
+
+/// Returns the entries of the pages in `[begin, end)` that are in the range of the frame table,
+/// if it is initialised.
+fn entries(begin: PhysAddr, end: PhysAddr) -> &'static [AtomicU8] {
+    match FRAME_TABLE.get() {
+        Some(table) => table.entries(begin, end),
+        None => &[],
+    }
+}
+
+/// Initialises the frame table for the physical pages in `[begin, end)`, allocating it from the
+/// memory pool. Fails if the memory pool is exhausted or the frame table is already initialised.
+pub fn init(begin: PhysAddr, end: PhysAddr, mpool: &MPool) -> bool {
+    let begin = begin.addr() & !(PAGE_SIZE - 1);
+    let count = div_ceil(end.addr() - begin, PAGE_SIZE);
+
+    let mut initialised = false;
+    let _ = FRAME_TABLE.try_call_once(|| {
+        let mut pages = match mpool.alloc_pages(div_ceil(count, PAGE_SIZE), 1) {
+            Some(pages) => pages,
+            None => return Err(()),
+        };
+        pages.clear();
+
+        let entries = unsafe { slice::from_raw_parts(pages.into_raw() as *const _, count) };
+        initialised = true;
+        Ok(FrameTable { begin, entries })
+    });
+    initialised
+}
+
+/// Frees the frame table to the memory pool it was allocated from, leaving it uninitialised, e.g.
+/// so that each test starts with a table of its own.
+///
+/// # Safety
+///
+/// The frame table may not be used concurrently, and `mpool` should be the memory pool it was
+/// allocated from.
+pub unsafe fn fini(mpool: &MPool) {
+    if let Some(table) = FRAME_TABLE.take() {
+        let count = div_ceil(table.entries.len(), PAGE_SIZE);
+        mpool.free_pages(Pages::from_raw(table.entries.as_ptr() as *mut RawPage, count));
+    }
+}
+
+/// Returns the owner of the page containing the given address, if any.
+pub fn owner_of(pa: PhysAddr) -> Option<u32> {
+    let entry = entries(pa, pa + 1).first()?;
+    match entry.load(Ordering::Relaxed) {
+        0 => None,
+        id => Some(id as u32 - 1),
+    }
+}
+
+/// Checks whether all the pages in `[begin, end)` are owned by `owner`. Pages not tracked by the
+/// frame table are not owned by any VM.
+pub fn owns(owner: u32, begin: PhysAddr, end: PhysAddr) -> bool {
+    let entries = entries(begin, end);
+    entries.len() == div_ceil(end.addr(), PAGE_SIZE) - begin.addr() / PAGE_SIZE
+        && entries
+            .iter()
+            .all(|entry| entry.load(Ordering::Relaxed) as u32 == owner + 1)
+}
+
+/// Returns whether any page in `[begin, end)` is tracked by the frame table.
+pub fn tracks(begin: PhysAddr, end: PhysAddr) -> bool {
+    !entries(begin, end).is_empty()
+}
+
+/// Records `owner` as the owner of the pages in `[begin, end)`.
+pub fn set_owner(owner: u32, begin: PhysAddr, end: PhysAddr) {
+    for entry in entries(begin, end) {
+        entry.store(owner as u8 + 1, Ordering::Relaxed);
+    }
+}
+
+/// Records that the pages in `[begin, end)` owned by `owner` are no longer owned. The pages owned
+/// by another VM are left intact, as the VM may have taken the ownership already.
+pub fn clear_owner(owner: u32, begin: PhysAddr, end: PhysAddr) {
+    for entry in entries(begin, end) {
+        let _ = entry.compare_exchange(owner as u8 + 1, 0, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn frame_table_init(
+    begin: PhysAddr,
+    end: PhysAddr,
+    mpool: *const MPool,
+) -> bool {
+    init(begin, end, &*mpool)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn frame_table_fini(mpool: *const MPool) {
+    fini(&*mpool);
+}
+
+/// Gets the owner of the page containing the given address. Returns false if it has no owner.
+#[no_mangle]
+pub unsafe extern "C" fn frame_table_get_owner(pa: PhysAddr, owner: *mut u32) -> bool {
+    match owner_of(pa) {
+        Some(id) => {
+            *owner = id;
+            true
+        }
+        None => false,
+    }
+}
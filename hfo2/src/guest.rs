@@ -0,0 +1,129 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Access to the memory of VMs.
+//!
+//! The intermediate physical addresses of a VM are translated with its stage-2 page table block by
+//! block, so ranges crossing page or block boundaries are handled.  The physical memory is accessed
+//! through the hypervisor's mapping of it if any, or a temporary mapping otherwise.
+
+use core::cmp;
+use core::ptr;
+
+use crate::mm::*;
+use crate::mpool::MPool;
+use crate::types::*;
+
+/// Accessor of the memory of a VM through its stage-2 page table.
+pub struct GuestMemory<'a> {
+    ptable: &'a PageTable<Stage2>,
+}
+
+impl<'a> GuestMemory<'a> {
+    pub fn new(ptable: &'a PageTable<Stage2>) -> Self {
+        Self { ptable }
+    }
+
+    /// Checks that `[begin, begin + len)` is mapped with all of the given mode.
+    fn check(&self, begin: IpaAddr, len: usize, mode: Mode) -> Result<(), MmError> {
+        self.for_each_chunk(begin, len, mode, |_, _, _| Ok(()))
+    }
+
+    /// Calls `f` for each physically contiguous chunk of `[begin, begin + len)`, with the offset
+    /// of the chunk in the range, its physical address, and its size. Fails with
+    /// `MmError::AccessDenied` if a chunk is not mapped with all of the given mode.
+    fn for_each_chunk<F>(
+        &self,
+        begin: IpaAddr,
+        len: usize,
+        mode: Mode,
+        mut f: F,
+    ) -> Result<(), MmError>
+    where
+        F: FnMut(usize, PhysAddr, usize) -> Result<(), MmError>,
+    {
+        let end = begin
+            .addr()
+            .checked_add(len)
+            .map(IpaAddr::new)
+            .ok_or(MmError::OutOfRange)?;
+        let mut ipa = begin;
+
+        while ipa < end {
+            let block = self.ptable.lookup(ipa).ok_or(MmError::AccessDenied)?;
+            let block_mode = Stage2::attrs_to_mode(block.attrs);
+
+            if block_mode.contains(Mode::INVALID) || !block_mode.contains(mode) {
+                return Err(MmError::AccessDenied);
+            }
+
+            let chunk_end = cmp::min(end, block.end);
+            f(ipa - begin, block.pa + (ipa - block.begin), chunk_end - ipa)?;
+            ipa = chunk_end;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the VM's memory starting at `src` into `dst`. The memory should be readable by the
+    /// VM. Nothing is copied on failure.
+    pub fn copy_from_vm(&self, dst: &mut [u8], src: IpaAddr, mpool: &MPool) -> Result<(), MmError> {
+        self.check(src, dst.len(), Mode::R)?;
+
+        self.for_each_chunk(src, dst.len(), Mode::R, |offset, pa, size| {
+            with_mapping(pa, size, Mode::R, mpool, |p| unsafe {
+                ptr::copy_nonoverlapping(p, dst[offset..].as_mut_ptr(), size);
+            })
+        })
+    }
+
+    /// Copies `src` into the VM's memory starting at `dst`. The memory should be writable by the
+    /// VM. The range is checked before anything is copied, but the memory may be partially
+    /// written if a temporary mapping fails.
+    pub fn copy_to_vm(&self, dst: IpaAddr, src: &[u8], mpool: &MPool) -> Result<(), MmError> {
+        self.check(dst, src.len(), Mode::W)?;
+
+        self.for_each_chunk(dst, src.len(), Mode::W, |offset, pa, size| {
+            with_mapping(pa, size, Mode::R | Mode::W, mpool, |p| unsafe {
+                ptr::copy_nonoverlapping(src[offset..].as_ptr(), p, size);
+            })
+        })
+    }
+}
+
+/// Calls `f` with a pointer to the given physical range in the hypervisor's address space. If the
+/// range is not mapped, it is mapped temporarily with the given mode. Fails with
+/// `MmError::AccessDenied` if it is mapped only partially or with a weaker mode.
+pub fn with_mapping<F>(
+    pa: PhysAddr,
+    size: usize,
+    mode: Mode,
+    mpool: &MPool,
+    f: F,
+) -> Result<(), MmError>
+where
+    F: FnOnce(*mut u8),
+{
+    match TempMapping::new(pa, size, mode, mpool) {
+        Ok(mapping) => {
+            let offset = pa.addr() - mapping.as_ptr() as usize;
+            f(unsafe { mapping.as_ptr().add(offset) });
+            Ok(())
+        }
+        Err(MmError::Overlap) => Err(MmError::AccessDenied),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Logging for VMs.
+//!
+//! VMs without a console of their own can write to the hypervisor's log with `hf_dlog()`, a few
+//! characters at a time.  The characters are buffered per VM until a newline or until the buffer
+//! is full, and each line is prefixed with the ID of the VM.  So that a VM cannot flood the log,
+//! the lines a VM writes beyond `MAX_LINES_PER_SECOND` in a second are dropped, and how many were
+//! is logged once it may write again.
+//!
+//! Every line is also kept in a ring of the VM's recent lines, which the primary VM drains with
+//! `hf_vm_log_drain()` to read the log of a VM apart from the others.  The oldest lines are
+//! overwritten when the ring is full.
+
+use core::cmp;
+
+use crate::spinlock::SpinLock;
+use crate::types::MAX_VMS;
+
+/// The maximum length of a line, beyond which it is split.
+const LINE_MAX: usize = 128;
+
+/// The size of the ring of a VM's recent lines.
+const RING_SIZE: usize = 512;
+
+/// The maximum number of lines a VM may write in a second.
+const MAX_LINES_PER_SECOND: u32 = 32;
+
+const NS_PER_SECOND: u64 = 1_000_000_000;
+
+extern "C" {
+    fn arch_timer_now_ticks() -> u64;
+    fn arch_timer_ticks_to_ns(ticks: u64) -> u64;
+}
+
+/// The recent lines of a VM, oldest first, overwritten when full.
+#[derive(Clone, Copy)]
+struct Ring {
+    buf: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RING_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if self.len == RING_SIZE {
+                self.start = (self.start + 1) % RING_SIZE;
+                self.len -= 1;
+            }
+
+            self.buf[(self.start + self.len) % RING_SIZE] = b;
+            self.len += 1;
+        }
+    }
+
+    /// Moves the oldest bytes to `out`, as many as fit, and returns how many were moved.
+    fn drain(&mut self, out: &mut [u8]) -> usize {
+        let count = cmp::min(self.len, out.len());
+
+        for (i, b) in out[..count].iter_mut().enumerate() {
+            *b = self.buf[(self.start + i) % RING_SIZE];
+        }
+
+        self.start = (self.start + count) % RING_SIZE;
+        self.len -= count;
+        count
+    }
+}
+
+#[derive(Clone, Copy)]
+struct GuestLog {
+    line: [u8; LINE_MAX],
+    len: usize,
+
+    /// When the current second of rate limiting started, in timer ticks.
+    window_start: u64,
+
+    /// The number of lines written in the current second.
+    lines: u32,
+
+    /// The number of lines dropped in the current second.
+    dropped: u32,
+
+    /// The recent lines, drained by the primary VM.
+    ring: Ring,
+}
+
+static LOGS: SpinLock<[GuestLog; MAX_VMS]> = SpinLock::new([GuestLog::new(); MAX_VMS]);
+
+impl GuestLog {
+    const fn new() -> Self {
+        Self {
+            line: [0; LINE_MAX],
+            len: 0,
+            window_start: 0,
+            lines: 0,
+            dropped: 0,
+            ring: Ring::new(),
+        }
+    }
+
+    /// Starts a new second of rate limiting if the current one is over.
+    fn update_window(&mut self, vm_id: u16) {
+        let now = unsafe { arch_timer_now_ticks() };
+        let elapsed = now.wrapping_sub(self.window_start);
+
+        // `u32::max_value()` ticks are more than a second for any counter frequency up to 4GHz,
+        // and checking it first keeps the conversion to nanoseconds from overflowing.
+        if elapsed <= u64::from(u32::max_value())
+            && unsafe { arch_timer_ticks_to_ns(elapsed) } < NS_PER_SECOND
+        {
+            return;
+        }
+
+        if self.dropped > 0 {
+            dlog!("VM {}: {} lines dropped\n", vm_id, self.dropped);
+        }
+
+        self.window_start = now;
+        self.lines = 0;
+        self.dropped = 0;
+    }
+
+    /// Writes the buffered line to the log, unless the VM has written too many lines recently.
+    fn flush(&mut self, vm_id: u16) {
+        // The ring keeps only the VM's own lines, so it is not rate limited.
+        self.ring.push(&self.line[..self.len]);
+        self.ring.push(b"\n");
+
+        self.update_window(vm_id);
+
+        if self.lines >= MAX_LINES_PER_SECOND {
+            self.dropped += 1;
+        } else {
+            self.lines += 1;
+
+            // Non-printable characters were replaced when buffered, so the line is ASCII.
+            let line = core::str::from_utf8(&self.line[..self.len]).unwrap_or("");
+            dlog!("VM {}: {}\n", vm_id, line);
+        }
+
+        self.len = 0;
+    }
+
+    fn push(&mut self, vm_id: u16, c: u8) {
+        if c == b'\n' {
+            self.flush(vm_id);
+            return;
+        }
+
+        if self.len == LINE_MAX {
+            self.flush(vm_id);
+        }
+
+        self.line[self.len] = if c == b' ' || c.is_ascii_graphic() {
+            c
+        } else {
+            b'?'
+        };
+        self.len += 1;
+    }
+}
+
+/// Writes the given characters to the log of the VM, up to the first NUL character if any.
+pub fn write(vm_id: u16, chars: &[u8]) {
+    let mut logs = LOGS.lock();
+    let log = match logs.get_mut(vm_id as usize) {
+        Some(log) => log,
+        None => return,
+    };
+
+    for &c in chars.iter().take_while(|&&c| c != 0) {
+        log.push(vm_id, c);
+    }
+}
+
+/// Moves the oldest lines of the log of the VM to `buf`, as much as fits, and returns the number of
+/// bytes written. Lines may be split if `buf` is too small.
+#[no_mangle]
+pub unsafe extern "C" fn guest_log_drain(vm_id: u16, buf: *mut u8, size: usize) -> usize {
+    let out = core::slice::from_raw_parts_mut(buf, size);
+
+    match LOGS.lock().get_mut(vm_id as usize) {
+        Some(log) => log.ring.drain(out),
+        None => 0,
+    }
+}
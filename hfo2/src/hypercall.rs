@@ -0,0 +1,576 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Hypercall dispatch.
+//!
+//! A hypercall is decoded from the registers of the HVC into a `Hypercall`, whose arguments are
+//! validated and converted to their types once and for all, e.g. VM IDs must fit in 16 bits.
+//! Calls that fail to decode return -1 without reaching the API.  The API handler's result is
+//! then encoded back into the register returned to the caller.
+
+use core::mem;
+
+use crate::abi::{HfVCpuRunReturn, HfVCpuRunReturnRaw, HF_SLEEP_INDEFINITE};
+use crate::api::HfShare;
+use crate::capability::Capabilities;
+use crate::cpu::CVCpu;
+use crate::guest_log;
+use crate::types::*;
+
+const SPCI_VERSION_32: u32 = 0x8400_0060;
+const SPCI_MSG_RECV_32: u32 = 0x8400_0062;
+const SPCI_MSG_SEND_32: u32 = 0x8400_0064;
+const SPCI_YIELD_32: u32 = 0x8400_0067;
+
+const HF_VM_GET_ID: u32 = 0xff00;
+const HF_VM_GET_COUNT: u32 = 0xff01;
+const HF_VCPU_GET_COUNT: u32 = 0xff02;
+const HF_VCPU_RUN: u32 = 0xff03;
+const HF_VM_CONFIGURE: u32 = 0xff05;
+const HF_MAILBOX_CLEAR: u32 = 0xff08;
+const HF_MAILBOX_WRITABLE_GET: u32 = 0xff09;
+const HF_MAILBOX_WAITER_GET: u32 = 0xff0a;
+const HF_INTERRUPT_ENABLE: u32 = 0xff0b;
+const HF_INTERRUPT_GET: u32 = 0xff0c;
+const HF_INTERRUPT_INJECT: u32 = 0xff0d;
+const HF_SHARE_MEMORY: u32 = 0xff0e;
+const HF_LOCK_STATS_DUMP: u32 = 0xff0f;
+const HF_MEMORY_RELINQUISH: u32 = 0xff10;
+const HF_MEMORY_RECLAIM: u32 = 0xff11;
+const HF_NOTIFICATION_SET: u32 = 0xff12;
+const HF_NOTIFICATION_GET: u32 = 0xff13;
+const HF_MAILBOX_PEEK: u32 = 0xff14;
+const HF_MAILBOX_BROADCAST: u32 = 0xff15;
+const HF_API_VERSION_GET: u32 = 0xff16;
+const HF_DLOG: u32 = 0xff17;
+const HF_VM_STATS_GET: u32 = 0xff18;
+const HF_VM_CREATE: u32 = 0xff19;
+const HF_VM_UNCONFIGURE: u32 = 0xff1a;
+const HF_VCPU_AFFINITY_SET: u32 = 0xff1b;
+const HF_CPU_TOPOLOGY_GET: u32 = 0xff1c;
+const HF_RUN_QUEUE_POP: u32 = 0xff1d;
+const HF_VM_DESTROY: u32 = 0xff1e;
+const HF_VM_LOG_DRAIN: u32 = 0xff1f;
+const HF_VM_SUSPEND: u32 = 0xff20;
+const HF_VM_RESUME: u32 = 0xff21;
+const HF_VM_FIND_UUID: u32 = 0xff22;
+const HF_INTERRUPT_OWNER_GET: u32 = 0xff23;
+const HF_DLOG_DRAIN: u32 = 0xff24;
+
+extern "C" {
+    fn api_spci_version() -> i32;
+    fn api_hf_version(requested: u32, current: *mut CVCpu) -> i64;
+    fn api_vm_get_id(current: *const CVCpu) -> u16;
+    fn api_vm_get_count() -> i64;
+    fn api_vcpu_get_count(vm_id: VmId, current: *const CVCpu) -> i64;
+    fn api_vcpu_run(
+        vm_id: VmId,
+        vcpu_idx: VCpuIndex,
+        current: *const CVCpu,
+        next: *mut *mut CVCpu,
+    ) -> HfVCpuRunReturnRaw;
+    fn api_spci_yield(current: *mut CVCpu, next: *mut *mut CVCpu) -> i32;
+    fn api_vm_configure(
+        send: IpaAddr,
+        recv: IpaAddr,
+        current: *mut CVCpu,
+        next: *mut *mut CVCpu,
+    ) -> i64;
+    fn api_spci_msg_send(attributes: u32, current: *mut CVCpu, next: *mut *mut CVCpu) -> i32;
+    fn api_spci_msg_recv(
+        attributes: u32,
+        timeout: u64,
+        current: *mut CVCpu,
+        next: *mut *mut CVCpu,
+    ) -> i32;
+    fn api_mailbox_clear(current: *mut CVCpu, next: *mut *mut CVCpu) -> i64;
+    fn api_mailbox_writable_get(current: *const CVCpu) -> i64;
+    fn api_mailbox_waiter_get(vm_id: VmId, current: *const CVCpu) -> i64;
+    fn api_mailbox_peek(current: *const CVCpu) -> i64;
+    fn api_vm_create(current: *mut CVCpu) -> i64;
+    fn api_vm_destroy(vm_id: VmId, current: *mut CVCpu) -> i64;
+    fn api_vm_suspend(vm_id: VmId, current: *mut CVCpu) -> i64;
+    fn api_vm_resume(vm_id: VmId, current: *mut CVCpu) -> i64;
+    fn api_vm_find_uuid(uuid: *const u32) -> i64;
+    fn api_vm_unconfigure(current: *mut CVCpu) -> i64;
+    fn api_vcpu_affinity_set(
+        vm_id: VmId,
+        vcpu_idx: VCpuIndex,
+        affinity: u64,
+        current: *const CVCpu,
+    ) -> i64;
+    fn api_vm_stats_get(vm_id: VmId, current: *mut CVCpu) -> i64;
+    fn api_cpu_topology_get(current: *mut CVCpu) -> i64;
+    fn api_vm_log_drain(vm_id: VmId, current: *mut CVCpu) -> i64;
+    fn api_dlog_drain(current: *mut CVCpu) -> i64;
+    fn api_run_queue_pop(current: *const CVCpu) -> i64;
+    fn api_mailbox_broadcast(current: *mut CVCpu) -> i64;
+    fn api_interrupt_enable(intid: u32, enable: bool, current: *mut CVCpu) -> i64;
+    fn api_interrupt_get(current: *mut CVCpu) -> u32;
+    fn api_interrupt_owner_get(intid: u32, current: *const CVCpu) -> i64;
+    fn api_interrupt_inject(
+        target_vm_id: VmId,
+        target_vcpu_idx: VCpuIndex,
+        intid: u32,
+        current: *mut CVCpu,
+        next: *mut *mut CVCpu,
+    ) -> i64;
+    fn api_share_memory(
+        vm_id: VmId,
+        addr: IpaAddr,
+        size: usize,
+        share: HfShare,
+        current: *mut CVCpu,
+    ) -> i64;
+    fn api_memory_relinquish(vm_id: VmId, addr: IpaAddr, size: usize, current: *mut CVCpu) -> i64;
+    fn api_memory_reclaim(vm_id: VmId, addr: IpaAddr, size: usize, current: *mut CVCpu) -> i64;
+    fn api_notification_set(
+        vm_id: VmId,
+        bits: u64,
+        current: *mut CVCpu,
+        next: *mut *mut CVCpu,
+    ) -> i64;
+    fn api_notification_get(current: *const CVCpu) -> u64;
+    fn api_lock_stats_dump(current: *const CVCpu) -> i64;
+}
+
+/// A hypercall whose arguments are validated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Hypercall {
+    SpciVersion,
+    ApiVersion {
+        requested: u32,
+    },
+    VmGetId,
+    VmGetCount,
+    VCpuGetCount {
+        vm_id: VmId,
+    },
+    VCpuRun {
+        /// The vCPU to run, or `None` if the VM or vCPU given doesn't exist.
+        target: Option<(VmId, VCpuIndex)>,
+    },
+    VCpuAffinitySet {
+        vm_id: VmId,
+        vcpu_idx: VCpuIndex,
+        affinity: u64,
+    },
+    SpciYield,
+    VmConfigure {
+        send: usize,
+        recv: usize,
+    },
+    VmUnconfigure,
+    SpciMsgSend {
+        attributes: u32,
+    },
+    SpciMsgRecv {
+        attributes: u32,
+        timeout: u64,
+    },
+    MailboxClear,
+    MailboxWritableGet,
+    MailboxWaiterGet {
+        vm_id: VmId,
+    },
+    MailboxPeek,
+    MailboxBroadcast,
+    InterruptEnable {
+        intid: u32,
+        enable: bool,
+    },
+    InterruptGet,
+    InterruptInject {
+        vm_id: VmId,
+        vcpu_idx: VCpuIndex,
+        intid: u32,
+    },
+    InterruptOwnerGet {
+        intid: u32,
+    },
+    ShareMemory {
+        vm_id: VmId,
+        addr: usize,
+        size: usize,
+        share: HfShare,
+    },
+    MemoryRelinquish {
+        vm_id: VmId,
+        addr: usize,
+        size: usize,
+    },
+    MemoryReclaim {
+        vm_id: VmId,
+        addr: usize,
+        size: usize,
+    },
+    NotificationSet {
+        vm_id: VmId,
+        bits: u64,
+    },
+    NotificationGet,
+    LockStatsDump,
+    VmCreate,
+    VmDestroy {
+        vm_id: VmId,
+    },
+    VmSuspend {
+        vm_id: VmId,
+    },
+    VmResume {
+        vm_id: VmId,
+    },
+    VmFindUuid {
+        uuid: [u32; 4],
+    },
+    VmStatsGet {
+        vm_id: VmId,
+    },
+    CpuTopologyGet,
+    VmLogDrain {
+        vm_id: VmId,
+    },
+    DlogDrain,
+    RunQueuePop,
+    Dlog {
+        chars: [uintreg_t; 3],
+    },
+}
+
+/// Why a hypercall failed to decode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// The function ID is not known.
+    UnknownCall,
+
+    /// An argument is out of range.
+    InvalidArgument,
+}
+
+/// The result of a hypercall, before it is encoded in the return register.
+pub enum HypercallResult {
+    /// A signed value, where negative values are errors.
+    Value(i64),
+
+    /// A value whose bits are all meaningful, e.g. notification bits.
+    Bits(u64),
+
+    /// The return value of `hf_vcpu_run`.
+    VCpuRun(HfVCpuRunReturn),
+}
+
+impl HypercallResult {
+    fn encode(&self) -> uintreg_t {
+        match self {
+            HypercallResult::Value(value) => *value as uintreg_t,
+            HypercallResult::Bits(bits) => *bits as uintreg_t,
+            HypercallResult::VCpuRun(run) => run.encode() as uintreg_t,
+        }
+    }
+}
+
+fn vm_id(arg: uintreg_t) -> Result<VmId, DecodeError> {
+    if arg > uintreg_t::from(u16::max_value()) {
+        return Err(DecodeError::InvalidArgument);
+    }
+
+    VmId::new(arg as u16).ok_or(DecodeError::InvalidArgument)
+}
+
+fn index(arg: uintreg_t) -> Result<u32, DecodeError> {
+    if arg > u32::max_value() as uintreg_t {
+        return Err(DecodeError::InvalidArgument);
+    }
+
+    Ok(arg as u32)
+}
+
+/// Decodes the index of a vCPU. No VM has more vCPUs than `MAX_CPUS`, and the callee checks the
+/// index against the vCPU count of the VM.
+fn vcpu_index(arg: uintreg_t) -> Result<VCpuIndex, DecodeError> {
+    VCpuIndex::new(index(arg)?, MAX_CPUS as u32).ok_or(DecodeError::InvalidArgument)
+}
+
+fn intid(arg: uintreg_t) -> Result<u32, DecodeError> {
+    if arg >= HF_NUM_INTIDS {
+        return Err(DecodeError::InvalidArgument);
+    }
+
+    Ok(arg as u32)
+}
+
+impl Hypercall {
+    /// Decodes a hypercall from the registers of the HVC.
+    pub fn decode(args: [uintreg_t; 4]) -> Result<Self, DecodeError> {
+        let [func, arg1, arg2, arg3] = args;
+
+        // Only the lower 32 bits of the function ID are meaningful.
+        let call = match func as u32 {
+            SPCI_VERSION_32 => Hypercall::SpciVersion,
+            HF_API_VERSION_GET => Hypercall::ApiVersion {
+                requested: index(arg1)?,
+            },
+            HF_VM_GET_ID => Hypercall::VmGetId,
+            HF_VM_GET_COUNT => Hypercall::VmGetCount,
+            HF_VCPU_GET_COUNT => Hypercall::VCpuGetCount {
+                vm_id: vm_id(arg1)?,
+            },
+            // The primary VM gets a return value of `hf_vcpu_run` even if there is no such vCPU.
+            HF_VCPU_RUN => Hypercall::VCpuRun {
+                target: vm_id(arg1)
+                    .and_then(|vm_id| Ok((vm_id, vcpu_index(arg2)?)))
+                    .ok(),
+            },
+            HF_VCPU_AFFINITY_SET => Hypercall::VCpuAffinitySet {
+                vm_id: vm_id(arg1)?,
+                vcpu_idx: vcpu_index(arg2)?,
+                affinity: arg3 as u64,
+            },
+            SPCI_YIELD_32 => Hypercall::SpciYield,
+            HF_VM_CONFIGURE => Hypercall::VmConfigure {
+                send: arg1,
+                recv: arg2,
+            },
+            HF_VM_UNCONFIGURE => Hypercall::VmUnconfigure,
+            SPCI_MSG_SEND_32 => Hypercall::SpciMsgSend {
+                attributes: arg1 as u32,
+            },
+            SPCI_MSG_RECV_32 => Hypercall::SpciMsgRecv {
+                attributes: arg1 as u32,
+                timeout: arg2 as u64,
+            },
+            HF_MAILBOX_CLEAR => Hypercall::MailboxClear,
+            HF_MAILBOX_WRITABLE_GET => Hypercall::MailboxWritableGet,
+            HF_MAILBOX_WAITER_GET => Hypercall::MailboxWaiterGet {
+                vm_id: vm_id(arg1)?,
+            },
+            HF_MAILBOX_PEEK => Hypercall::MailboxPeek,
+            HF_MAILBOX_BROADCAST => Hypercall::MailboxBroadcast,
+            HF_INTERRUPT_ENABLE => Hypercall::InterruptEnable {
+                intid: intid(arg1)?,
+                enable: arg2 != 0,
+            },
+            HF_INTERRUPT_GET => Hypercall::InterruptGet,
+            HF_INTERRUPT_INJECT => Hypercall::InterruptInject {
+                vm_id: vm_id(arg1)?,
+                vcpu_idx: vcpu_index(arg2)?,
+                intid: intid(arg3)?,
+            },
+            HF_INTERRUPT_OWNER_GET => Hypercall::InterruptOwnerGet {
+                intid: intid(arg1)?,
+            },
+            HF_SHARE_MEMORY => Hypercall::ShareMemory {
+                vm_id: vm_id(arg1 >> 32)?,
+                addr: arg2,
+                size: arg3,
+                share: HfShare::from_raw(arg1 as u32).ok_or(DecodeError::InvalidArgument)?,
+            },
+            HF_MEMORY_RELINQUISH => Hypercall::MemoryRelinquish {
+                vm_id: vm_id(arg1)?,
+                addr: arg2,
+                size: arg3,
+            },
+            HF_MEMORY_RECLAIM => Hypercall::MemoryReclaim {
+                vm_id: vm_id(arg1)?,
+                addr: arg2,
+                size: arg3,
+            },
+            HF_NOTIFICATION_SET => Hypercall::NotificationSet {
+                vm_id: vm_id(arg1)?,
+                bits: arg2 as u64,
+            },
+            HF_NOTIFICATION_GET => Hypercall::NotificationGet,
+            HF_LOCK_STATS_DUMP => Hypercall::LockStatsDump,
+            HF_VM_CREATE => Hypercall::VmCreate,
+            HF_VM_DESTROY => Hypercall::VmDestroy {
+                vm_id: vm_id(arg1)?,
+            },
+            HF_VM_SUSPEND => Hypercall::VmSuspend {
+                vm_id: vm_id(arg1)?,
+            },
+            HF_VM_RESUME => Hypercall::VmResume {
+                vm_id: vm_id(arg1)?,
+            },
+            HF_VM_FIND_UUID => Hypercall::VmFindUuid {
+                uuid: [
+                    arg1 as u32,
+                    (arg1 as u64 >> 32) as u32,
+                    arg2 as u32,
+                    (arg2 as u64 >> 32) as u32,
+                ],
+            },
+            HF_VM_STATS_GET => Hypercall::VmStatsGet {
+                vm_id: vm_id(arg1)?,
+            },
+            HF_CPU_TOPOLOGY_GET => Hypercall::CpuTopologyGet,
+            HF_VM_LOG_DRAIN => Hypercall::VmLogDrain {
+                vm_id: vm_id(arg1)?,
+            },
+            HF_DLOG_DRAIN => Hypercall::DlogDrain,
+            HF_RUN_QUEUE_POP => Hypercall::RunQueuePop,
+            HF_DLOG => Hypercall::Dlog {
+                chars: [arg1, arg2, arg3],
+            },
+            _ => return Err(DecodeError::UnknownCall),
+        };
+
+        Ok(call)
+    }
+
+    /// Returns the capabilities the caller needs to make the hypercall.
+    pub fn required_capabilities(&self) -> Capabilities {
+        match self {
+            Hypercall::ShareMemory { .. }
+            | Hypercall::MemoryRelinquish { .. }
+            | Hypercall::MemoryReclaim { .. } => Capabilities::MEMORY_SHARING,
+            Hypercall::LockStatsDump
+            | Hypercall::VmStatsGet { .. }
+            | Hypercall::VmLogDrain { .. }
+            | Hypercall::DlogDrain => Capabilities::INTROSPECTION,
+            _ => Capabilities::empty(),
+        }
+    }
+
+    /// Handles the hypercall made by `current`. If the vCPU to run next changes, it is written to
+    /// `next`.
+    pub unsafe fn dispatch(self, current: *mut CVCpu, next: *mut *mut CVCpu) -> HypercallResult {
+        use HypercallResult::*;
+
+        match self {
+            Hypercall::SpciVersion => Value(api_spci_version().into()),
+            Hypercall::ApiVersion { requested } => Value(api_hf_version(requested, current)),
+            Hypercall::VmGetId => Value(api_vm_get_id(current).into()),
+            Hypercall::VmGetCount => Value(api_vm_get_count()),
+            Hypercall::VCpuGetCount { vm_id } => Value(api_vcpu_get_count(vm_id, current)),
+            Hypercall::VCpuRun {
+                target: Some((vm_id, vcpu_idx)),
+            } => {
+                // An unknown code is a bug in the API, which is reported to the caller as an error
+                // rather than taking the hypervisor down.
+                let raw = api_vcpu_run(vm_id, vcpu_idx, current, next);
+                match HfVCpuRunReturn::from_raw(raw) {
+                    Some(run) => VCpuRun(run),
+                    None => Value(-1),
+                }
+            }
+            Hypercall::VCpuRun { target: None } => VCpuRun(HfVCpuRunReturn::WaitForInterrupt {
+                ns: HF_SLEEP_INDEFINITE,
+            }),
+            Hypercall::VCpuAffinitySet {
+                vm_id,
+                vcpu_idx,
+                affinity,
+            } => Value(api_vcpu_affinity_set(vm_id, vcpu_idx, affinity, current)),
+            Hypercall::SpciYield => Value(api_spci_yield(current, next).into()),
+            Hypercall::VmConfigure { send, recv } => Value(api_vm_configure(
+                IpaAddr::new(send),
+                IpaAddr::new(recv),
+                current,
+                next,
+            )),
+            Hypercall::VmUnconfigure => Value(api_vm_unconfigure(current)),
+            Hypercall::SpciMsgSend { attributes } => {
+                Value(api_spci_msg_send(attributes, current, next).into())
+            }
+            Hypercall::SpciMsgRecv {
+                attributes,
+                timeout,
+            } => Value(api_spci_msg_recv(attributes, timeout, current, next).into()),
+            Hypercall::MailboxClear => Value(api_mailbox_clear(current, next)),
+            Hypercall::MailboxWritableGet => Value(api_mailbox_writable_get(current)),
+            Hypercall::MailboxWaiterGet { vm_id } => Value(api_mailbox_waiter_get(vm_id, current)),
+            Hypercall::MailboxPeek => Value(api_mailbox_peek(current)),
+            Hypercall::MailboxBroadcast => Value(api_mailbox_broadcast(current)),
+            Hypercall::InterruptEnable { intid, enable } => {
+                Value(api_interrupt_enable(intid, enable, current))
+            }
+            Hypercall::InterruptGet => Value(api_interrupt_get(current).into()),
+            Hypercall::InterruptInject {
+                vm_id,
+                vcpu_idx,
+                intid,
+            } => Value(api_interrupt_inject(vm_id, vcpu_idx, intid, current, next)),
+            Hypercall::InterruptOwnerGet { intid } => {
+                Value(api_interrupt_owner_get(intid, current))
+            }
+            Hypercall::ShareMemory {
+                vm_id,
+                addr,
+                size,
+                share,
+            } => Value(api_share_memory(
+                vm_id,
+                IpaAddr::new(addr),
+                size,
+                share,
+                current,
+            )),
+            Hypercall::MemoryRelinquish { vm_id, addr, size } => Value(api_memory_relinquish(
+                vm_id,
+                IpaAddr::new(addr),
+                size,
+                current,
+            )),
+            Hypercall::MemoryReclaim { vm_id, addr, size } => {
+                Value(api_memory_reclaim(vm_id, IpaAddr::new(addr), size, current))
+            }
+            Hypercall::NotificationSet { vm_id, bits } => {
+                Value(api_notification_set(vm_id, bits, current, next))
+            }
+            Hypercall::NotificationGet => Bits(api_notification_get(current)),
+            Hypercall::LockStatsDump => Value(api_lock_stats_dump(current)),
+            Hypercall::VmCreate => Value(api_vm_create(current)),
+            Hypercall::VmDestroy { vm_id } => Value(api_vm_destroy(vm_id, current)),
+            Hypercall::VmSuspend { vm_id } => Value(api_vm_suspend(vm_id, current)),
+            Hypercall::VmResume { vm_id } => Value(api_vm_resume(vm_id, current)),
+            Hypercall::VmFindUuid { uuid } => Value(api_vm_find_uuid(uuid.as_ptr())),
+            Hypercall::VmStatsGet { vm_id } => Value(api_vm_stats_get(vm_id, current)),
+            Hypercall::CpuTopologyGet => Value(api_cpu_topology_get(current)),
+            Hypercall::VmLogDrain { vm_id } => Value(api_vm_log_drain(vm_id, current)),
+            Hypercall::DlogDrain => Value(api_dlog_drain(current)),
+            Hypercall::RunQueuePop => Value(api_run_queue_pop(current)),
+            Hypercall::Dlog { chars } => {
+                let mut bytes = [0u8; 3 * mem::size_of::<uintreg_t>()];
+                for (chunk, arg) in bytes.chunks_mut(mem::size_of::<uintreg_t>()).zip(&chars) {
+                    chunk.copy_from_slice(&arg.to_le_bytes());
+                }
+                guest_log::write(api_vm_get_id(current), &bytes);
+                Value(0)
+            }
+        }
+    }
+}
+
+/// Decodes and handles a hypercall that is not a PSCI call. Returns the value of the return
+/// register, which is -1 if the call is unknown, its arguments are invalid, or the caller lacks
+/// the capabilities for it.
+#[no_mangle]
+pub unsafe extern "C" fn hypercall_handler(
+    current: *mut CVCpu,
+    arg0: uintreg_t,
+    arg1: uintreg_t,
+    arg2: uintreg_t,
+    arg3: uintreg_t,
+    next: *mut *mut CVCpu,
+) -> uintreg_t {
+    match Hypercall::decode([arg0, arg1, arg2, arg3]) {
+        Ok(call) if Capabilities::of(current).contains(call.required_capabilities()) => {
+            call.dispatch(current, next).encode()
+        }
+        Ok(_) => -1i64 as uintreg_t,
+        Err(_) => -1i64 as uintreg_t,
+    }
+}
@@ -18,6 +18,11 @@
 #![feature(const_fn)]
 #![feature(const_panic)]
 #![feature(ptr_wrapping_offset_from)]
+#![feature(asm)]
+#![cfg_attr(
+    any(feature = "lockdep", feature = "lock_stats"),
+    feature(core_intrinsics)
+)]
 
 #[macro_use]
 extern crate bitflags;
@@ -31,15 +36,40 @@ mod cpio;
 mod utils;
 #[macro_use]
 mod dlog;
+mod abi;
 mod api;
+mod barriers;
+mod capability;
+mod context;
 mod cpu;
+mod dirty;
+mod ffa;
+mod ffa_memory;
+mod frame;
+mod hypercall;
+mod guest;
+mod guest_log;
+#[macro_use]
 mod list;
+#[cfg(feature = "lockdep")]
+mod lockdep;
+mod lockstat;
+mod mailbox;
+mod manifest;
 mod memiter;
 mod mm;
 mod mpool;
+mod notification;
+mod once;
 mod page;
+mod psci;
+mod refcount;
+mod run_queue;
 mod panic;
+mod smc_filter;
 mod spinlock;
 mod std;
+mod trap;
 mod types;
 mod vm;
+mod vmid;
@@ -148,6 +148,11 @@ impl<T, C: IsElement<T>> List<T, C> {
         self.head.push::<T, C>(element);
     }
 
+    /// Checks whether the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.head.next.get().is_null()
+    }
+
     pub unsafe fn pop(&mut self) -> Option<*mut T> {
         let head = self.head.next.get();
         if head.is_null() {
@@ -159,6 +164,14 @@ impl<T, C: IsElement<T>> List<T, C> {
         Some(C::element_of(&*head) as *const _ as *mut _)
     }
 
+    /// Returns an iterator over the elements of the list.
+    pub fn iter(&self) -> Iter<T, C> {
+        Iter {
+            curr: self.head.next.get(),
+            _marker: PhantomData,
+        }
+    }
+
     pub unsafe fn pop_if_some<R, F>(&mut self, cond: F) -> Option<(*mut T, R)>
     where
         F: Fn(&T) -> Option<R>,
@@ -179,3 +192,251 @@ impl<T, C: IsElement<T>> List<T, C> {
         None
     }
 }
+
+/// An iterator over the elements of a linked list.
+pub struct Iter<'a, T, C: IsElement<T> = T> {
+    curr: *const ListEntry,
+    _marker: PhantomData<(&'a T, C)>,
+}
+
+impl<'a, T: 'a, C: IsElement<T>> Iterator for Iter<'a, T, C> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.curr.is_null() {
+            return None;
+        }
+
+        let entry = unsafe { &*self.curr };
+        self.curr = entry.next.get();
+        Some(unsafe { C::element_of(entry) })
+    }
+}
+
+/// Returns the offset of the given field in the given struct, e.g. to implement `IsNode`.
+#[macro_export]
+macro_rules! offset_of {
+    ($ty:ty, $field:ident) => {{
+        let base = core::mem::MaybeUninit::<$ty>::uninit();
+        let base_ptr = base.as_ptr();
+        #[allow(unused_unsafe)]
+        let field_ptr = unsafe { &(*base_ptr).$field as *const _ };
+        field_ptr as usize - base_ptr as usize
+    }};
+}
+
+/// A link in an intrusive doubly-linked list. A link is in at most one list at a time.
+#[derive(Debug)]
+pub struct Link {
+    prev: Cell<*const Link>,
+    next: Cell<*const Link>,
+
+    /// Whether the link is in a list.
+    linked: Cell<bool>,
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Self {
+            prev: Cell::new(ptr::null()),
+            next: Cell::new(ptr::null()),
+            linked: Cell::new(false),
+        }
+    }
+
+    /// Checks whether the link is in a list.
+    pub fn is_linked(&self) -> bool {
+        self.linked.get()
+    }
+}
+
+impl Default for Link {
+    /// Returns the unlinked link.
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Implementing this trait asserts that `T` can be used as an element of `LinkedList<T, Self>`,
+/// through the `Link` returned by `link_of()`. Like `IsElement`, a type with multiple links has a
+/// separate implementation of `IsNode` for each of them.
+pub trait IsNode<T> {
+    /// Returns the element's link.
+    fn link_of(element: &T) -> &Link;
+
+    /// Returns the element of the given link.
+    ///
+    /// # Safety
+    ///
+    /// The link should be retrieved from an element with `link_of()`.
+    unsafe fn element_of(link: &Link) -> &T;
+}
+
+/// An intrusive doubly-linked list of type `T`, linked through the links `C` designates. Elements
+/// are removed in O(1) given the element.
+///
+/// The list does not point into itself, so it may be moved while it has elements.
+#[derive(Debug)]
+pub struct LinkedList<T, C: IsNode<T>> {
+    first: Cell<*const Link>,
+    last: Cell<*const Link>,
+    _marker: PhantomData<(T, C)>,
+}
+
+impl<T, C: IsNode<T>> LinkedList<T, C> {
+    /// Returns a new, empty linked list.
+    pub const fn new() -> Self {
+        Self {
+            first: Cell::new(ptr::null()),
+            last: Cell::new(ptr::null()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Checks whether the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.first.get().is_null()
+    }
+
+    /// Inserts `element` at the end of the list. Panics if it is already in a list.
+    ///
+    /// # Safety
+    ///
+    /// The element should neither move nor be dropped until it is removed from the list.
+    pub unsafe fn push_back(&mut self, element: &T) {
+        let link = C::link_of(element);
+        assert!(!link.is_linked(), "inserting an element already in a list");
+
+        let last = self.last.get();
+        link.prev.set(last);
+        link.next.set(ptr::null());
+        link.linked.set(true);
+
+        if last.is_null() {
+            self.first.set(link);
+        } else {
+            (*last).next.set(link);
+        }
+        self.last.set(link);
+    }
+
+    /// Inserts `element` at the beginning of the list. Panics if it is already in a list.
+    ///
+    /// # Safety
+    ///
+    /// The element should neither move nor be dropped until it is removed from the list.
+    pub unsafe fn push_front(&mut self, element: &T) {
+        let link = C::link_of(element);
+        assert!(!link.is_linked(), "inserting an element already in a list");
+
+        let first = self.first.get();
+        link.prev.set(ptr::null());
+        link.next.set(first);
+        link.linked.set(true);
+
+        if first.is_null() {
+            self.last.set(link);
+        } else {
+            (*first).prev.set(link);
+        }
+        self.first.set(link);
+    }
+
+    /// Returns the first element of the list.
+    pub fn front(&self) -> Option<&T> {
+        let first = self.first.get();
+        if first.is_null() {
+            None
+        } else {
+            Some(unsafe { C::element_of(&*first) })
+        }
+    }
+
+    /// Removes the first element of the list, and returns it.
+    pub fn pop_front(&mut self) -> Option<*const T> {
+        let element = self.front()? as *const T;
+        unsafe { self.remove(&*element) };
+        Some(element)
+    }
+
+    /// Removes `element` from the list. Panics if it is not in a list. Whether it is in this list
+    /// is checked in debug builds only, as it takes time linear to the length of the list.
+    ///
+    /// # Safety
+    ///
+    /// The element should be in this list.
+    pub unsafe fn remove(&mut self, element: &T) {
+        let link = C::link_of(element);
+        assert!(link.is_linked(), "removing an element not in a list");
+        debug_assert!(
+            self.contains(element),
+            "removing an element of another list"
+        );
+
+        let prev = link.prev.get();
+        let next = link.next.get();
+
+        if prev.is_null() {
+            self.first.set(next);
+        } else {
+            (*prev).next.set(next);
+        }
+
+        if next.is_null() {
+            self.last.set(prev);
+        } else {
+            (*next).prev.set(prev);
+        }
+
+        link.prev.set(ptr::null());
+        link.next.set(ptr::null());
+        link.linked.set(false);
+    }
+
+    /// Checks whether `element` is in the list. It takes time linear to the length of the list.
+    pub fn contains(&self, element: &T) -> bool {
+        let link = C::link_of(element) as *const Link;
+        self.links().any(|l| l == link)
+    }
+
+    /// Returns an iterator over the elements of the list.
+    pub fn iter(&self) -> LinkedIter<T, C> {
+        LinkedIter {
+            curr: self.first.get(),
+            _marker: PhantomData,
+        }
+    }
+
+    fn links(&self) -> impl Iterator<Item = *const Link> {
+        let mut curr = self.first.get();
+        core::iter::from_fn(move || {
+            if curr.is_null() {
+                return None;
+            }
+
+            let link = curr;
+            curr = unsafe { (*curr).next.get() };
+            Some(link)
+        })
+    }
+}
+
+/// An iterator over the elements of a doubly-linked list.
+pub struct LinkedIter<'a, T, C: IsNode<T>> {
+    curr: *const Link,
+    _marker: PhantomData<(&'a T, C)>,
+}
+
+impl<'a, T: 'a, C: IsNode<T>> Iterator for LinkedIter<'a, T, C> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.curr.is_null() {
+            return None;
+        }
+
+        let link = unsafe { &*self.curr };
+        self.curr = link.next.get();
+        Some(unsafe { C::element_of(link) })
+    }
+}
@@ -0,0 +1,239 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Lock order checking.
+//!
+//! With the `lockdep` feature, each CPU records the `SpinLock`s it holds, and every acquisition
+//! of a lock while holding another adds an edge between their classes to a global order graph.
+//! The class of a lock is the type of the data it protects, e.g. all `SpinLock<VmState>`s are of
+//! the same class.  An acquisition panics if the graph already has a path from the class of the
+//! lock being acquired to the class of a lock being held, i.e. if the acquisition closes a cycle
+//! that may deadlock, even if it does not deadlock this time.
+//!
+//! Locks of the same class may be nested, as they are ordered by their addresses (see
+//! `SpinLock::lock_both()`), but acquiring a lock already held by the CPU panics.  Locks acquired
+//! with `try_lock()` do not wait, so their acquisition is not checked, but they are recorded as
+//! held.  Locks used from C through `sl_lock()` are not tracked.
+
+use core::cell::UnsafeCell;
+
+use crate::cpu::Cpu;
+use crate::spinlock::RawSpinLock;
+use crate::types::*;
+
+extern "C" {
+    fn arch_cpu_id() -> u64;
+    fn cpu_find(id: u64) -> *const Cpu;
+    fn cpu_index(c: *const Cpu) -> usize;
+}
+
+/// The maximum number of lock classes. It is the number of bits of a row of the order graph.
+const MAX_CLASSES: usize = 64;
+
+/// The maximum number of locks a CPU holds at once.
+const MAX_HELD: usize = 16;
+
+#[derive(Clone, Copy)]
+struct HeldLock {
+    class: usize,
+    addr: usize,
+}
+
+struct Graph {
+    /// The names of the classes, indexed by class.
+    names: [&'static str; MAX_CLASSES],
+
+    /// The number of classes.
+    count: usize,
+
+    /// `after[a]` has the `b`-th bit set if a lock of class `b` has been acquired while holding a
+    /// lock of class `a`.
+    after: [u64; MAX_CLASSES],
+
+    /// The locks held by each CPU, in the order of their acquisition.
+    held: [[HeldLock; MAX_HELD]; MAX_CPUS],
+
+    /// The number of locks held by each CPU.
+    held_count: [usize; MAX_CPUS],
+}
+
+impl Graph {
+    /// Returns the class of the given name, registering it if it is new.
+    fn class_of(&mut self, name: &'static str) -> usize {
+        if let Some(class) = self.names[..self.count].iter().position(|n| *n == name) {
+            return class;
+        }
+
+        assert!(self.count < MAX_CLASSES, "lockdep: too many lock classes");
+        self.names[self.count] = name;
+        self.count += 1;
+        self.count - 1
+    }
+
+    /// Checks whether a lock of class `to` has been acquired while holding, possibly
+    /// transitively, a lock of class `from`.
+    fn reachable(&self, from: usize, to: usize) -> bool {
+        let mut reached = 1u64 << from;
+        loop {
+            let next = (0..self.count)
+                .filter(|class| reached & (1u64 << class) != 0)
+                .fold(reached, |next, class| next | self.after[class]);
+            if next == reached {
+                return reached & (1 << to) != 0;
+            }
+            reached = next;
+        }
+    }
+}
+
+/// A violation of the lock order, reported after the graph is unlocked.
+enum Violation {
+    Recursive {
+        name: &'static str,
+        addr: usize,
+    },
+    Inversion {
+        name: &'static str,
+        addr: usize,
+        held_name: &'static str,
+        held_addr: usize,
+    },
+    TooManyHeld,
+}
+
+struct Lockdep {
+    lock: RawSpinLock,
+    graph: UnsafeCell<Graph>,
+}
+
+unsafe impl Sync for Lockdep {}
+
+static LOCKDEP: Lockdep = Lockdep {
+    lock: RawSpinLock::new(),
+    graph: UnsafeCell::new(Graph {
+        names: [""; MAX_CLASSES],
+        count: 0,
+        after: [0; MAX_CLASSES],
+        held: [[HeldLock { class: 0, addr: 0 }; MAX_HELD]; MAX_CPUS],
+        held_count: [0; MAX_CPUS],
+    }),
+};
+
+/// Returns the index of the current CPU, or `None` if CPUs are not initialised yet.
+fn current_cpu() -> Option<usize> {
+    unsafe {
+        let cpu = cpu_find(arch_cpu_id());
+        if cpu.is_null() {
+            None
+        } else {
+            Some(cpu_index(cpu))
+        }
+    }
+}
+
+/// Runs `f` on the order graph and the locks held by the current CPU, and panics with the
+/// violation it reports, if any.
+fn with_graph<F>(f: F)
+where
+    F: FnOnce(&mut Graph, usize) -> Result<(), Violation>,
+{
+    let cpu = match current_cpu() {
+        Some(cpu) => cpu,
+        None => return,
+    };
+
+    LOCKDEP.lock.lock();
+    let result = f(unsafe { &mut *LOCKDEP.graph.get() }, cpu);
+    LOCKDEP.lock.unlock();
+
+    match result {
+        Ok(()) => (),
+        Err(Violation::Recursive { name, addr }) => panic!(
+            "lockdep: CPU {} acquiring {} lock {:#x} it already holds",
+            cpu, name, addr
+        ),
+        Err(Violation::Inversion {
+            name,
+            addr,
+            held_name,
+            held_addr,
+        }) => panic!(
+            "lockdep: CPU {} acquiring {} lock {:#x} while holding {} lock {:#x}, \
+             but the former has been held while acquiring the latter",
+            cpu, name, addr, held_name, held_addr
+        ),
+        Err(Violation::TooManyHeld) => panic!("lockdep: CPU {} holding too many locks", cpu),
+    }
+}
+
+/// Records that the current CPU is about to acquire the lock at `addr` of class `name`. If
+/// `check` is set, the acquisition is checked against the locks the CPU holds.
+pub fn acquire(name: &'static str, addr: usize, check: bool) {
+    with_graph(|graph, cpu| {
+        let class = graph.class_of(name);
+        let count = graph.held_count[cpu];
+
+        if check {
+            for held in &graph.held[cpu][..count] {
+                if held.addr == addr {
+                    return Err(Violation::Recursive { name, addr });
+                }
+
+                if held.class == class {
+                    continue;
+                }
+
+                if graph.reachable(class, held.class) {
+                    return Err(Violation::Inversion {
+                        name,
+                        addr,
+                        held_name: graph.names[held.class],
+                        held_addr: held.addr,
+                    });
+                }
+            }
+
+            for i in 0..count {
+                let held_class = graph.held[cpu][i].class;
+                if held_class != class {
+                    graph.after[held_class] |= 1 << class;
+                }
+            }
+        }
+
+        if count == MAX_HELD {
+            return Err(Violation::TooManyHeld);
+        }
+        graph.held[cpu][count] = HeldLock { class, addr };
+        graph.held_count[cpu] += 1;
+        Ok(())
+    })
+}
+
+/// Records that the current CPU released the lock at `addr`.
+pub fn release(addr: usize) {
+    with_graph(|graph, cpu| {
+        let count = graph.held_count[cpu];
+        let held = &mut graph.held[cpu][..count];
+        if let Some(index) = held.iter().rposition(|held| held.addr == addr) {
+            for i in index..count - 1 {
+                held[i] = held[i + 1];
+            }
+            graph.held_count[cpu] -= 1;
+        }
+        Ok(())
+    })
+}
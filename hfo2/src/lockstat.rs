@@ -0,0 +1,155 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Lock contention counters.
+//!
+//! With the `lock_stats` feature, every acquisition of a `SpinLock` is counted for the type of the
+//! data it protects, e.g. all `SpinLock<VmState>`s share the same counters.  An acquisition is
+//! contended if it spins waiting for another CPU to release the lock.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::spinlock::RawSpinLock;
+
+/// The maximum number of named locks.
+const MAX_LOCKS: usize = 64;
+
+/// The counters of a named lock.
+#[derive(Clone, Copy, Default)]
+pub struct LockStats {
+    /// The number of acquisitions.
+    pub acquisitions: usize,
+
+    /// The number of acquisitions that spun waiting for the lock.
+    pub contended: usize,
+
+    /// The number of iterations spun waiting for the lock.
+    pub spins: usize,
+}
+
+struct Registry {
+    /// Serialises the registration of names.
+    lock: RawSpinLock,
+
+    /// The number of registered names. The names and counters below it are initialised.
+    count: AtomicUsize,
+
+    names: UnsafeCell<[&'static str; MAX_LOCKS]>,
+
+    /// The counters of each name, updated atomically. See `LockStats` for their meaning.
+    counters: UnsafeCell<[[usize; 3]; MAX_LOCKS]>,
+}
+
+unsafe impl Sync for Registry {}
+
+static REGISTRY: Registry = Registry {
+    lock: RawSpinLock::new(),
+    count: AtomicUsize::new(0),
+    names: UnsafeCell::new([""; MAX_LOCKS]),
+    counters: UnsafeCell::new([[0; 3]; MAX_LOCKS]),
+};
+
+impl Registry {
+    fn names(&self) -> &[&'static str] {
+        let count = self.count.load(Ordering::Acquire);
+        unsafe { &(*self.names.get())[..count] }
+    }
+
+    /// Returns the index of the given name, registering it if it is new. Returns `None` if there
+    /// are too many names.
+    fn index_of(&self, name: &'static str) -> Option<usize> {
+        if let Some(index) = self.names().iter().position(|n| *n == name) {
+            return Some(index);
+        }
+
+        self.lock.lock();
+        let count = self.count.load(Ordering::Relaxed);
+        let index = match self.names().iter().position(|n| *n == name) {
+            Some(index) => Some(index),
+            None if count < MAX_LOCKS => {
+                unsafe { (*self.names.get())[count] = name };
+                self.count.store(count + 1, Ordering::Release);
+                Some(count)
+            }
+            None => None,
+        };
+        self.lock.unlock();
+        index
+    }
+
+    fn counter(&self, index: usize, field: usize) -> &AtomicUsize {
+        // `AtomicUsize` has the same in-memory representation as `usize`.
+        unsafe { &*(&(*self.counters.get())[index][field] as *const usize as *const AtomicUsize) }
+    }
+
+    fn stats(&self, index: usize) -> LockStats {
+        LockStats {
+            acquisitions: self.counter(index, 0).load(Ordering::Relaxed),
+            contended: self.counter(index, 1).load(Ordering::Relaxed),
+            spins: self.counter(index, 2).load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts an acquisition of a lock of the given name that spun `spins` iterations.
+pub fn record(name: &'static str, spins: usize) {
+    let index = match REGISTRY.index_of(name) {
+        Some(index) => index,
+        None => return,
+    };
+
+    REGISTRY.counter(index, 0).fetch_add(1, Ordering::Relaxed);
+    if spins != 0 {
+        REGISTRY.counter(index, 1).fetch_add(1, Ordering::Relaxed);
+        REGISTRY
+            .counter(index, 2)
+            .fetch_add(spins, Ordering::Relaxed);
+    }
+}
+
+/// Returns the counters of the lock of the given name, if any.
+pub fn stats(name: &str) -> Option<LockStats> {
+    let index = REGISTRY.names().iter().position(|n| *n == name)?;
+    Some(REGISTRY.stats(index))
+}
+
+/// Dumps the counters of all named locks to the log. Returns the number of named locks.
+pub fn dump() -> usize {
+    let names = REGISTRY.names();
+    for (index, name) in names.iter().enumerate() {
+        let stats = REGISTRY.stats(index);
+        dlog!(
+            "lock {}: {} acquisitions, {} contended, {} spins\n",
+            name,
+            stats.acquisitions,
+            stats.contended,
+            stats.spins
+        );
+    }
+    names.len()
+}
+
+/// Dumps the lock contention counters to the log. Returns the number of named locks, or -1 if
+/// the counters are not enabled.
+#[no_mangle]
+pub unsafe extern "C" fn lock_stats_dump() -> i64 {
+    if cfg!(feature = "lock_stats") {
+        dump() as i64
+    } else {
+        -1
+    }
+}
@@ -0,0 +1,230 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # The state machine of mailboxes.
+//!
+//! The receive side of a mailbox goes around `Empty -> Received -> Read -> Empty`: a message is
+//! delivered to an empty mailbox, its owner reads it, and then clears the mailbox for the next
+//! one. The hypervisor may also write to an empty mailbox on behalf of its owner, which goes
+//! straight to `Read`. The send side is `Free` unless the mailbox received a fragment of a message
+//! that is not the last, in which case it is `Pending` until the sender sends the last fragment.
+//!
+//! The state is only changed by the transitions below, each of which checks the state it starts
+//! from, so that the fields of the state can't be updated inconsistently. The C code calls them
+//! through the `mailbox_*` functions with the lock of the owner of the mailbox held.
+
+use core::mem;
+
+use crate::types::*;
+
+/// The state of the receive side of a mailbox, as `enum mailbox_state`.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MailboxState {
+    /// There is no message in the mailbox.
+    Empty,
+
+    /// There is a message in the mailbox that is waiting for a reader.
+    Received,
+
+    /// There is a message in the mailbox that has been read.
+    Read,
+}
+
+/// The state of the send side of a mailbox, i.e. which VMs may send to it.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SendState {
+    /// Any VM may send to the mailbox.
+    Free,
+
+    /// The mailbox received a fragment of a message that is not the last, so only the VM that
+    /// sent it may send until it sends the last fragment.
+    Pending(VmId),
+}
+
+/// The state of a mailbox. It has the same representation as `struct mailbox_protocol`.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MailboxProtocol {
+    recv: MailboxState,
+    send: SendState,
+}
+
+// The size of `struct mailbox_protocol`.
+const_assert!(mailbox_protocol_size; mem::size_of::<MailboxProtocol>() == 12);
+
+impl MailboxProtocol {
+    pub const fn new() -> Self {
+        Self {
+            recv: MailboxState::Empty,
+            send: SendState::Free,
+        }
+    }
+
+    /// Returns the state of a mailbox restored with the given receive side, e.g. from a snapshot.
+    /// No fragments are pending, as the snapshot doesn't record any.
+    pub const fn restored(recv: MailboxState) -> Self {
+        Self {
+            recv,
+            send: SendState::Free,
+        }
+    }
+
+    /// Returns the state of the receive side.
+    pub fn state(&self) -> MailboxState {
+        self.recv
+    }
+
+    /// Returns the VM whose message is being received in fragments, if any.
+    pub fn pending_sender(&self) -> Option<VmId> {
+        match self.send {
+            SendState::Free => None,
+            SendState::Pending(sender) => Some(sender),
+        }
+    }
+
+    /// Returns whether the mailbox holds nothing, neither a message nor fragments of one.
+    pub fn is_idle(&self) -> bool {
+        self.recv == MailboxState::Empty && self.send == SendState::Free
+    }
+
+    /// Returns whether `from` may send to the mailbox now.
+    pub fn accepts(&self, from: VmId) -> bool {
+        self.recv == MailboxState::Empty
+            && match self.send {
+                SendState::Free => true,
+                SendState::Pending(sender) => sender == from,
+            }
+    }
+
+    /// Receives a message, or a fragment of one if `more` fragments follow, from `from`. Fails if
+    /// the mailbox doesn't accept it.
+    pub fn receive(&mut self, from: VmId, more: bool) -> bool {
+        if !self.accepts(from) {
+            return false;
+        }
+
+        self.recv = MailboxState::Received;
+        self.send = if more {
+            SendState::Pending(from)
+        } else {
+            SendState::Free
+        };
+        true
+    }
+
+    /// Gives up on the rest of the pending message, e.g. because its sender aborted, so that
+    /// other VMs may send again.
+    pub fn abandon(&mut self) {
+        self.send = SendState::Free;
+    }
+
+    /// Marks the received message as read by the owner. Fails if there is none.
+    pub fn read(&mut self) -> bool {
+        if self.recv != MailboxState::Received {
+            return false;
+        }
+
+        self.recv = MailboxState::Read;
+        true
+    }
+
+    /// Marks the empty mailbox as written by the hypervisor for its owner, which owns it until it
+    /// clears the mailbox. Fails if the mailbox is not empty.
+    pub fn fill(&mut self) -> bool {
+        if self.recv != MailboxState::Empty {
+            return false;
+        }
+
+        self.recv = MailboxState::Read;
+        true
+    }
+
+    /// Clears the mailbox if its message has been read, and returns the state it had. Only a
+    /// mailbox that was `Read` becomes writable again, in which case the VMs waiting for it are
+    /// to be notified.
+    pub fn clear(&mut self) -> MailboxState {
+        let state = self.recv;
+
+        if state == MailboxState::Read {
+            self.recv = MailboxState::Empty;
+        }
+
+        state
+    }
+}
+
+/// Resets the mailbox to be empty with no pending fragments, e.g. when its VM is restarted.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_reset(protocol: *mut MailboxProtocol) {
+    *protocol = MailboxProtocol::new();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_state(protocol: *const MailboxProtocol) -> MailboxState {
+    (*protocol).state()
+}
+
+/// Writes the ID of the VM whose message is being received in fragments to `sender` and returns
+/// true, or returns false if there is none.
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_pending_sender(
+    protocol: *const MailboxProtocol,
+    sender: *mut u16,
+) -> bool {
+    match (*protocol).pending_sender() {
+        Some(id) => {
+            *sender = id.id();
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_is_idle(protocol: *const MailboxProtocol) -> bool {
+    (*protocol).is_idle()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_receive(
+    protocol: *mut MailboxProtocol,
+    from: u16,
+    more: bool,
+) -> bool {
+    VmId::new(from).map_or(false, |from| (*protocol).receive(from, more))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_abandon(protocol: *mut MailboxProtocol) {
+    (*protocol).abandon()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_read(protocol: *mut MailboxProtocol) -> bool {
+    (*protocol).read()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_fill(protocol: *mut MailboxProtocol) -> bool {
+    (*protocol).fill()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mailbox_clear(protocol: *mut MailboxProtocol) -> MailboxState {
+    (*protocol).clear()
+}
@@ -0,0 +1,778 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # The manifest of the secondary VMs.
+//!
+//! The VMs to load at boot are described by the `hypervisor` node of the device tree, with a
+//! child node `vm<N>` per secondary VM, and a child node `shared<N>` per memory region shared
+//! among them:
+//!
+//! ```text
+//! hypervisor {
+//!     vm1 {
+//!         debug_name = "linux_test";
+//!         kernel_filename = "vmlinuz_test";
+//!         mem_size = <0x100000>;
+//!         vcpu_count = <1>;
+//!         capabilities = <0x3>;  /* Optional, as in `caps.txt`. */
+//!         priority = <0>;        /* Optional, as in `priorities.txt`. */
+//!         mem_quota = <0x400000>; /* Optional, the most memory mapped into the VM. */
+//!         restart_policy = "on_abort"; /* Optional, "never" by default. */
+//!         uuid = <0x1e67b5b4 0xe14f904a 0x13fb1fb8 0xcbdae1da>; /* Optional. */
+//!         /* Optional, <base-hi base-lo size-hi size-lo intid> per device. */
+//!         device_regions = <0x0 0x9040000 0x0 0x1000 0x21>;
+//!         boot_order = <1>;      /* Optional, the position of the VM in the boot order. */
+//!         boot_hold;             /* Optional, the VM waits for the primary VM to release it. */
+//!     };
+//!     vm2 {
+//!         kernel_filename = "vmlinuz_other";
+//!         /* <base-hi base-lo size-hi size-lo mode> per region, rather than `mem_size`. */
+//!         mem_regions = <0x0 0x0 0x0 0x100000 0x7>,
+//!                       <0x1 0x0 0x0 0x200000 0x3>;
+//!         vcpu_count = <1>;
+//!     };
+//!     shared1 {
+//!         filename = "fw_table";
+//!         base = <0x0 0x88000000>;
+//!         mode = <0x1>;          /* Optional, `0x1` (read) by default. */
+//!         vms = "vm1", "vm2";
+//!     };
+//! };
+//! ```
+//!
+//! A VM is given the memory regions of `mem_regions`, or a single region of `mem_size` bytes with
+//! read, write and execute access without it. The mode of a region is a combination of `0x1`
+//! (read), `0x2` (write) and `0x4` (execute). A region at base 0 is taken from wherever memory is
+//! available; others must be available at the base. The kernel is loaded at the start of the first
+//! region.
+//!
+//! A VM whose `restart_policy` is `"on_abort"` is restarted from its kernel when it aborts, rather
+//! than staying aborted until the system is rebooted.
+//!
+//! The `uuid` of a VM identifies it to FF-A partition discovery. VMs without one have the null
+//! UUID, which no lookup matches.
+//!
+//! The MMIO ranges of `device_regions` are assigned to the VM: they are mapped into it as device
+//! memory and unmapped from the primary VM. The interrupt of a device, or 0 if it has none, is
+//! routed to the VM by the primary VM, which looks up the owner of the interrupt.
+//!
+//! The VMs are loaded, and so given their IDs, in increasing `boot_order`, followed by those
+//! without one; VMs of the same `boot_order` are loaded in the order of their nodes. A VM with
+//! `boot_hold` is loaded suspended, and is not run until the primary VM releases it by resuming it,
+//! e.g. once the VMs it depends on are ready.
+//!
+//! A shared region is loaded with a file of the RAM disk at `base`, and is owned by the hypervisor
+//! rather than by a VM. It is mapped at the same address into each of the VMs whose nodes `vms`
+//! names, which may read it and, if its `mode` has `0x4`, execute it, e.g. a firmware table or a
+//! shared library image, but may not write it or pass it on.
+//!
+//! Numbers are 32- or 64-bit.  Without the `hypervisor` node, the VMs are read from the legacy
+//! `vms.txt` in the RAM disk, which has an entry `<mem-size> <vcpu-count> <kernel-filename>` per
+//! VM.  The primary VM is not described, as its kernel is always `vmlinuz`.
+
+use core::convert::TryInto;
+use core::ptr;
+use core::slice;
+
+use crate::cpio;
+use crate::memiter::MemIter;
+use crate::mm::Mode;
+use crate::spinlock::SpinLock;
+use crate::types::*;
+
+/// The maximum size of a name in the manifest, including the null terminator.
+pub const MANIFEST_NAME_MAX: usize = 32;
+
+/// The value of a property the manifest doesn't set, in which case it is loaded as before the
+/// manifest, e.g. from `caps.txt`.
+pub const MANIFEST_UNSET: u32 = u32::max_value();
+
+/// The VM stays aborted once it aborts.
+pub const MANIFEST_RESTART_NEVER: u32 = 0;
+
+/// The VM is restarted from its kernel when it aborts.
+pub const MANIFEST_RESTART_ON_ABORT: u32 = 1;
+
+/// The maximum number of memory regions of a VM.
+pub const MANIFEST_MAX_REGIONS: usize = 4;
+
+/// The maximum number of devices assigned to a VM.
+pub const MANIFEST_MAX_DEVICES: usize = 4;
+
+/// The maximum number of memory regions shared among secondary VMs.
+pub const MANIFEST_MAX_SHARED: usize = 4;
+
+/// The maximum number of secondary VMs in the manifest.
+const MANIFEST_MAX_VMS: usize = MAX_VMS - 1;
+
+/// The size of an entry of `mem_regions`: a 64-bit base and size and a 32-bit mode.
+const REGION_ENTRY_SIZE: usize = 20;
+
+/// The size of an entry of `device_regions`: a 64-bit base and size and a 32-bit interrupt ID.
+const DEVICE_ENTRY_SIZE: usize = 20;
+
+/// A node of the device tree, as `struct fdt_node`.
+#[repr(C)]
+#[derive(Clone)]
+pub struct FdtNode {
+    hdr: *const u8,
+    begin: *const u8,
+    end: *const u8,
+    strs: *const u8,
+}
+
+extern "C" {
+    fn fdt_find_child(node: *mut FdtNode, child: *const u8) -> bool;
+    fn fdt_first_child(node: *mut FdtNode, child_name: *mut *const u8) -> bool;
+    fn fdt_next_sibling(node: *mut FdtNode, sibling_name: *mut *const u8) -> bool;
+    fn fdt_read_property(
+        node: *const FdtNode,
+        name: *const u8,
+        buf: *mut *const u8,
+        size: *mut u32,
+    ) -> bool;
+}
+
+/// A memory region of a secondary VM in the manifest.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ManifestRegion {
+    /// The first address of the region, or 0 to take it from wherever memory is available.
+    pub base: u64,
+
+    /// The size of the region in bytes.
+    pub size: u64,
+
+    /// The `MM_MODE_R`, `MM_MODE_W` and `MM_MODE_X` bits of the VM's access to the region.
+    pub mode: u32,
+}
+
+impl ManifestRegion {
+    const fn new() -> Self {
+        Self {
+            base: 0,
+            size: 0,
+            mode: 0,
+        }
+    }
+}
+
+/// A device assigned to a secondary VM in the manifest.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ManifestDevice {
+    /// The first address of the MMIO range of the device.
+    pub base: u64,
+
+    /// The size of the MMIO range in bytes.
+    pub size: u64,
+
+    /// The interrupt ID of the device, or 0 if it has none.
+    pub intid: u32,
+}
+
+impl ManifestDevice {
+    const fn new() -> Self {
+        Self {
+            base: 0,
+            size: 0,
+            intid: 0,
+        }
+    }
+}
+
+/// A read-only memory region the hypervisor owns and shares among secondary VMs.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ManifestShared {
+    /// The name of the file in the RAM disk the region is loaded with.
+    pub filename: [u8; MANIFEST_NAME_MAX],
+
+    /// The first address of the region, in both physical memory and the VMs' address spaces.
+    pub base: u64,
+
+    /// The `MM_MODE_R` and `MM_MODE_X` bits of the VMs' access to the region.
+    pub mode: u32,
+}
+
+impl ManifestShared {
+    const fn new() -> Self {
+        Self {
+            filename: [0; MANIFEST_NAME_MAX],
+            base: 0,
+            mode: 0,
+        }
+    }
+}
+
+/// A secondary VM in the manifest.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ManifestVm {
+    /// The name of the VM in logs, null-terminated.
+    pub debug_name: [u8; MANIFEST_NAME_MAX],
+
+    /// The name of the VM's kernel in the RAM disk, null-terminated.
+    pub kernel_filename: [u8; MANIFEST_NAME_MAX],
+
+    /// The size of the VM's memory in bytes, i.e. of all its regions.
+    pub mem_size: u64,
+
+    /// The memory regions of the VM, of which the first `region_count` are used.
+    pub regions: [ManifestRegion; MANIFEST_MAX_REGIONS],
+    pub region_count: u32,
+
+    pub vcpu_count: u32,
+
+    /// The `HF_CAPABILITY_*` bits of the VM, or `MANIFEST_UNSET`.
+    pub capabilities: u32,
+
+    /// The `HF_PRIORITY_*` class of the VM, or `MANIFEST_UNSET`.
+    pub priority: u32,
+
+    /// The most memory in bytes that may be mapped into the VM, or 0 if it is not limited.
+    pub mem_quota: u64,
+
+    /// What is done when the VM aborts, `MANIFEST_RESTART_NEVER` or `MANIFEST_RESTART_ON_ABORT`.
+    pub restart_policy: u32,
+
+    /// The UUID of the VM as four 32-bit words, in the order of the registers FF-A passes it in,
+    /// or all zeroes if it has none.
+    pub uuid: [u32; 4],
+
+    /// The devices assigned to the VM, of which the first `device_count` are used.
+    pub devices: [ManifestDevice; MANIFEST_MAX_DEVICES],
+    pub device_count: u32,
+
+    /// The position of the VM in the boot order, or `MANIFEST_UNSET` to boot after the others.
+    pub boot_order: u32,
+
+    /// Whether the VM is held suspended at boot until the primary VM resumes it.
+    pub boot_hold: bool,
+
+    /// The shared regions mapped into the VM, with bit N set for the Nth shared region.
+    pub shared_regions: u32,
+}
+
+impl ManifestVm {
+    const fn new() -> Self {
+        Self {
+            debug_name: [0; MANIFEST_NAME_MAX],
+            kernel_filename: [0; MANIFEST_NAME_MAX],
+            mem_size: 0,
+            regions: [ManifestRegion::new(); MANIFEST_MAX_REGIONS],
+            region_count: 0,
+            vcpu_count: 0,
+            capabilities: MANIFEST_UNSET,
+            priority: MANIFEST_UNSET,
+            mem_quota: 0,
+            restart_policy: MANIFEST_RESTART_NEVER,
+            uuid: [0; 4],
+            devices: [ManifestDevice::new(); MANIFEST_MAX_DEVICES],
+            device_count: 0,
+            boot_order: MANIFEST_UNSET,
+            boot_hold: false,
+            shared_regions: 0,
+        }
+    }
+
+    /// Gives the VM a single region of `mem_size` bytes, wherever memory is available, with full
+    /// access to it.
+    fn set_mem_size(&mut self, mem_size: u64) {
+        self.mem_size = mem_size;
+        self.regions[0] = ManifestRegion {
+            base: 0,
+            size: mem_size,
+            mode: (Mode::R | Mode::W | Mode::X).bits(),
+        };
+        self.region_count = 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ManifestError {
+    /// A property is missing or has the wrong size.
+    MalformedProperty(&'static str),
+
+    /// A name doesn't fit in `MANIFEST_NAME_MAX` bytes.
+    NameTooLong,
+
+    /// `vms.txt` is malformed.
+    MalformedVmsTxt,
+
+    /// There are more VMs than `MANIFEST_MAX_VMS`.
+    TooManyVms,
+
+    /// There are more shared regions than `MANIFEST_MAX_SHARED`.
+    TooManyShared,
+}
+
+/// The secondary VMs to load.
+pub struct Manifest {
+    vms: [ManifestVm; MANIFEST_MAX_VMS],
+    count: usize,
+
+    /// The memory regions shared among the VMs.
+    shared: [ManifestShared; MANIFEST_MAX_SHARED],
+    shared_count: usize,
+
+    /// Whether the manifest was read from the device tree, rather than to be read from `vms.txt`.
+    from_fdt: bool,
+}
+
+impl Manifest {
+    const fn new() -> Self {
+        Self {
+            vms: [ManifestVm::new(); MANIFEST_MAX_VMS],
+            count: 0,
+            shared: [ManifestShared::new(); MANIFEST_MAX_SHARED],
+            shared_count: 0,
+            from_fdt: false,
+        }
+    }
+
+    fn push(&mut self, vm: ManifestVm) -> Result<(), ManifestError> {
+        if self.count == MANIFEST_MAX_VMS {
+            return Err(ManifestError::TooManyVms);
+        }
+
+        self.vms[self.count] = vm;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns the secondary VMs, in the order they are to be loaded.
+    pub fn vms(&self) -> &[ManifestVm] {
+        &self.vms[..self.count]
+    }
+
+    /// Returns the memory regions shared among the secondary VMs.
+    pub fn shared(&self) -> &[ManifestShared] {
+        &self.shared[..self.shared_count]
+    }
+
+    /// Sorts the VMs by boot order, keeping the order of the manifest between VMs of the same boot
+    /// order.
+    fn sort_by_boot_order(&mut self) {
+        let vms = &mut self.vms[..self.count];
+
+        for i in 1..vms.len() {
+            let mut j = i;
+            while j > 0 && vms[j - 1].boot_order > vms[j].boot_order {
+                vms.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
+
+    /// Reads the manifest from the `hypervisor` node of the device tree, whose root node is
+    /// given. Returns `Ok(false)` if there is no such node.
+    unsafe fn parse_fdt(&mut self, root: &FdtNode) -> Result<bool, ManifestError> {
+        let mut node = root.clone();
+        let mut name = ptr::null();
+
+        self.count = 0;
+        self.shared_count = 0;
+        self.from_fdt = false;
+
+        if !fdt_find_child(&mut node, "hypervisor\0".as_ptr()) {
+            return Ok(false);
+        }
+
+        self.from_fdt = true;
+        let hypervisor = node.clone();
+
+        if !fdt_first_child(&mut node, &mut name) {
+            return Ok(true);
+        }
+
+        loop {
+            if c_str(name).starts_with(b"vm") {
+                self.push(parse_vm(&node)?)?;
+            }
+
+            if !fdt_next_sibling(&mut node, &mut name) {
+                break;
+            }
+        }
+
+        // The shared regions name the VMs they are shared with, so they are read after the VMs.
+        node = hypervisor.clone();
+        fdt_first_child(&mut node, &mut name);
+        loop {
+            if c_str(name).starts_with(b"shared") {
+                if self.shared_count == MANIFEST_MAX_SHARED {
+                    return Err(ManifestError::TooManyShared);
+                }
+
+                self.shared[self.shared_count] =
+                    parse_shared(&node, &hypervisor, self.shared_count, &mut self.vms)?;
+                self.shared_count += 1;
+            }
+
+            if !fdt_next_sibling(&mut node, &mut name) {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Reads the manifest from the entries of `vms.txt`.
+    unsafe fn parse_vms_txt(&mut self, it: &mut MemIter) -> Result<(), ManifestError> {
+        self.count = 0;
+
+        while let Some(mem_size) = it.parse_uint() {
+            let vcpu_count = it.parse_uint().ok_or(ManifestError::MalformedVmsTxt)?;
+            let name = it.parse_str().ok_or(ManifestError::MalformedVmsTxt)?;
+            let mut vm = ManifestVm::new();
+
+            copy_name(&mut vm.kernel_filename, name.as_slice())?;
+            vm.debug_name = vm.kernel_filename;
+            vm.set_mem_size(mem_size);
+            vm.vcpu_count = vcpu_count
+                .try_into()
+                .map_err(|_| ManifestError::MalformedVmsTxt)?;
+            self.push(vm)?;
+        }
+
+        Ok(())
+    }
+}
+
+static MANIFEST: SpinLock<Manifest> = SpinLock::new(Manifest::new());
+
+/// Returns the bytes of the given null-terminated string, without the terminator.
+unsafe fn c_str<'a>(s: *const u8) -> &'a [u8] {
+    let mut len = 0;
+    while *s.add(len) != 0 {
+        len += 1;
+    }
+    slice::from_raw_parts(s, len)
+}
+
+/// Copies the given name to a null-terminated name of the manifest.
+fn copy_name(dst: &mut [u8; MANIFEST_NAME_MAX], src: &[u8]) -> Result<(), ManifestError> {
+    if src.len() >= MANIFEST_NAME_MAX {
+        return Err(ManifestError::NameTooLong);
+    }
+
+    *dst = [0; MANIFEST_NAME_MAX];
+    dst[..src.len()].copy_from_slice(src);
+    Ok(())
+}
+
+/// Reads the given property of the node, whose name is null-terminated.
+unsafe fn read_property<'a>(node: &'a FdtNode, name: &'static str) -> Option<&'a [u8]> {
+    let mut buf = ptr::null();
+    let mut size = 0;
+
+    if !fdt_read_property(node, name.as_ptr(), &mut buf, &mut size) {
+        return None;
+    }
+
+    Some(slice::from_raw_parts(buf, size as usize))
+}
+
+/// Reads the given numeric property of the node, which is 32- or 64-bit big-endian.
+unsafe fn read_number(node: &FdtNode, name: &'static str) -> Result<Option<u64>, ManifestError> {
+    let buf = match read_property(node, name) {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+
+    match buf.len() {
+        4 => Ok(Some(u32::from_be_bytes(buf.try_into().unwrap()).into())),
+        8 => Ok(Some(u64::from_be_bytes(buf.try_into().unwrap()))),
+        _ => Err(ManifestError::MalformedProperty(name)),
+    }
+}
+
+/// Reads the given 32-bit property of the node, returning `MANIFEST_UNSET` if it is missing.
+unsafe fn read_u32_or_unset(node: &FdtNode, name: &'static str) -> Result<u32, ManifestError> {
+    match read_number(node, name)? {
+        Some(value) if value < u64::from(MANIFEST_UNSET) => Ok(value as u32),
+        Some(_) => Err(ManifestError::MalformedProperty(name)),
+        None => Ok(MANIFEST_UNSET),
+    }
+}
+
+/// Reads the given string property of the node to a name of the manifest.
+unsafe fn read_name(
+    node: &FdtNode,
+    name: &'static str,
+    dst: &mut [u8; MANIFEST_NAME_MAX],
+) -> Result<(), ManifestError> {
+    match read_property(node, name) {
+        Some(s) if s.last() == Some(&0) => copy_name(dst, &s[..s.len() - 1]),
+        _ => Err(ManifestError::MalformedProperty(name)),
+    }
+}
+
+/// Reads the value of a `uuid` property, which has four 32-bit cells.
+fn read_uuid(buf: &[u8]) -> Result<[u32; 4], ManifestError> {
+    if buf.len() != 16 {
+        return Err(ManifestError::MalformedProperty("uuid"));
+    }
+
+    let mut uuid = [0; 4];
+    for (word, cell) in uuid.iter_mut().zip(buf.chunks(4)) {
+        *word = u32::from_be_bytes(cell.try_into().unwrap());
+    }
+    Ok(uuid)
+}
+
+/// Reads the `restart_policy` property of the node, which is `MANIFEST_RESTART_NEVER` if missing.
+unsafe fn read_restart_policy(node: &FdtNode) -> Result<u32, ManifestError> {
+    match read_property(node, "restart_policy\0") {
+        None => Ok(MANIFEST_RESTART_NEVER),
+        Some(b"never\0") => Ok(MANIFEST_RESTART_NEVER),
+        Some(b"on_abort\0") => Ok(MANIFEST_RESTART_ON_ABORT),
+        Some(_) => Err(ManifestError::MalformedProperty("restart_policy")),
+    }
+}
+
+/// Reads the `mem_regions` property of the node to the regions of the VM.
+unsafe fn read_regions(node: &FdtNode, vm: &mut ManifestVm) -> Result<(), ManifestError> {
+    let err = ManifestError::MalformedProperty("mem_regions");
+    let buf = read_property(node, "mem_regions\0").ok_or(err)?;
+    let count = buf.len() / REGION_ENTRY_SIZE;
+    let modes = Mode::R | Mode::W | Mode::X;
+
+    if buf.len() % REGION_ENTRY_SIZE != 0 || count == 0 || count > MANIFEST_MAX_REGIONS {
+        return Err(err);
+    }
+
+    vm.mem_size = 0;
+    for (region, entry) in vm.regions.iter_mut().zip(buf.chunks(REGION_ENTRY_SIZE)) {
+        region.base = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+        region.size = u64::from_be_bytes(entry[8..16].try_into().unwrap());
+        region.mode = u32::from_be_bytes(entry[16..20].try_into().unwrap());
+
+        if region.size == 0 || region.mode == 0 || region.mode & !modes.bits() != 0 {
+            return Err(err);
+        }
+
+        vm.mem_size = vm.mem_size.checked_add(region.size).ok_or(err)?;
+    }
+    vm.region_count = count as u32;
+
+    Ok(())
+}
+
+/// Reads the value of a `device_regions` property to the devices of the VM.
+fn read_devices(buf: &[u8], vm: &mut ManifestVm) -> Result<(), ManifestError> {
+    let err = ManifestError::MalformedProperty("device_regions");
+    let count = buf.len() / DEVICE_ENTRY_SIZE;
+
+    if buf.len() % DEVICE_ENTRY_SIZE != 0 || count > MANIFEST_MAX_DEVICES {
+        return Err(err);
+    }
+
+    for (device, entry) in vm.devices.iter_mut().zip(buf.chunks(DEVICE_ENTRY_SIZE)) {
+        device.base = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+        device.size = u64::from_be_bytes(entry[8..16].try_into().unwrap());
+        device.intid = u32::from_be_bytes(entry[16..20].try_into().unwrap());
+
+        if device.size == 0
+            || device.base.checked_add(device.size).is_none()
+            || device.intid as usize >= HF_NUM_INTIDS
+        {
+            return Err(err);
+        }
+    }
+    vm.device_count = count as u32;
+
+    Ok(())
+}
+
+/// Parses a `vm<N>` node of the manifest.
+unsafe fn parse_vm(node: &FdtNode) -> Result<ManifestVm, ManifestError> {
+    let mut vm = ManifestVm::new();
+
+    read_name(node, "kernel_filename\0", &mut vm.kernel_filename)?;
+    if read_property(node, "debug_name\0").is_some() {
+        read_name(node, "debug_name\0", &mut vm.debug_name)?;
+    } else {
+        vm.debug_name = vm.kernel_filename;
+    }
+
+    if read_property(node, "mem_regions\0").is_some() {
+        read_regions(node, &mut vm)?;
+    } else {
+        vm.set_mem_size(
+            read_number(node, "mem_size\0")?.ok_or(ManifestError::MalformedProperty("mem_size"))?,
+        );
+    }
+    vm.vcpu_count = match read_number(node, "vcpu_count\0")? {
+        Some(count) if count > 0 && count <= MAX_CPUS as u64 => count as u32,
+        _ => return Err(ManifestError::MalformedProperty("vcpu_count")),
+    };
+    vm.capabilities = read_u32_or_unset(node, "capabilities\0")?;
+    vm.priority = read_u32_or_unset(node, "priority\0")?;
+    vm.mem_quota = read_number(node, "mem_quota\0")?.unwrap_or(0);
+    vm.restart_policy = read_restart_policy(node)?;
+    if let Some(buf) = read_property(node, "uuid\0") {
+        vm.uuid = read_uuid(buf)?;
+    }
+    if let Some(buf) = read_property(node, "device_regions\0") {
+        read_devices(buf, &mut vm)?;
+    }
+    vm.boot_order = read_u32_or_unset(node, "boot_order\0")?;
+    vm.boot_hold = read_property(node, "boot_hold\0").is_some();
+
+    Ok(vm)
+}
+
+/// Returns the index among the VMs of the manifest of the `vm<N>` child of `hypervisor` with the
+/// given name, as the VMs are in the order of their nodes until they are sorted by boot order.
+unsafe fn find_vm(hypervisor: &FdtNode, vm_name: &[u8]) -> Option<usize> {
+    let mut node = hypervisor.clone();
+    let mut name = ptr::null();
+    let mut index = 0;
+
+    if !fdt_first_child(&mut node, &mut name) {
+        return None;
+    }
+
+    loop {
+        let node_name = c_str(name);
+        if node_name.starts_with(b"vm") {
+            if node_name == vm_name {
+                return Some(index);
+            }
+            index += 1;
+        }
+
+        if !fdt_next_sibling(&mut node, &mut name) {
+            return None;
+        }
+    }
+}
+
+/// Parses the `shared<N>` node of the manifest, the `index`th shared region, and marks it in the
+/// VMs it is shared with.
+unsafe fn parse_shared(
+    node: &FdtNode,
+    hypervisor: &FdtNode,
+    index: usize,
+    vms: &mut [ManifestVm],
+) -> Result<ManifestShared, ManifestError> {
+    let mut shared = ManifestShared::new();
+
+    read_name(node, "filename\0", &mut shared.filename)?;
+    shared.base = read_number(node, "base\0")?.ok_or(ManifestError::MalformedProperty("base"))?;
+    // The region is always readable, and never writable.
+    let mode = read_number(node, "mode\0")?.unwrap_or_else(|| Mode::R.bits().into());
+    if mode != u64::from(Mode::R.bits()) && mode != u64::from((Mode::R | Mode::X).bits()) {
+        return Err(ManifestError::MalformedProperty("mode"));
+    }
+    shared.mode = mode as u32;
+
+    // The VMs are named by a list of null-terminated strings.
+    let err = ManifestError::MalformedProperty("vms");
+    let names = read_property(node, "vms\0").ok_or(err)?;
+    if names.last() != Some(&0) {
+        return Err(err);
+    }
+    for vm_name in names[..names.len() - 1].split(|c| *c == 0) {
+        let vm = find_vm(hypervisor, vm_name).ok_or(err)?;
+        vms[vm].shared_regions |= 1 << index;
+    }
+
+    Ok(shared)
+}
+
+/// Reads the manifest from the device tree, whose root node is given, while it is mapped. Returns
+/// false if the manifest is malformed; it is fine for the device tree not to have one.
+#[no_mangle]
+pub unsafe extern "C" fn manifest_init(root: *const FdtNode) -> bool {
+    let mut manifest = MANIFEST.lock();
+
+    match manifest.parse_fdt(&*root) {
+        Ok(_) => {
+            manifest.sort_by_boot_order();
+            true
+        }
+        Err(e) => {
+            dlog!("Malformed manifest: {:?}\n", e);
+            manifest.count = 0;
+            manifest.shared_count = 0;
+            false
+        }
+    }
+}
+
+/// Reads the manifest from `vms.txt` in the RAM disk unless it was read from the device tree.
+/// Returns false if `vms.txt` is missing or malformed.
+#[no_mangle]
+pub unsafe extern "C" fn manifest_load_vms_txt(cpio: *const MemIter) -> bool {
+    let mut manifest = MANIFEST.lock();
+
+    if manifest.from_fdt {
+        return true;
+    }
+
+    let mut cpio = (*cpio).clone();
+    let mut it = match cpio::find_file(&mut cpio, "vms.txt\0".as_ptr()) {
+        Some(it) => it,
+        None => return false,
+    };
+
+    match manifest.parse_vms_txt(&mut it) {
+        Ok(()) => true,
+        Err(e) => {
+            dlog!("Malformed vms.txt: {:?}\n", e);
+            false
+        }
+    }
+}
+
+/// Returns the number of secondary VMs in the manifest.
+#[no_mangle]
+pub extern "C" fn manifest_vm_count() -> usize {
+    MANIFEST.lock().vms().len()
+}
+
+/// Copies the secondary VM of the given index in the manifest to `vm`. Returns false if there is
+/// no such VM.
+#[no_mangle]
+pub unsafe extern "C" fn manifest_vm_get(index: usize, vm: *mut ManifestVm) -> bool {
+    match MANIFEST.lock().vms().get(index) {
+        Some(entry) => {
+            *vm = *entry;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the number of memory regions the manifest shares among secondary VMs.
+#[no_mangle]
+pub extern "C" fn manifest_shared_count() -> usize {
+    MANIFEST.lock().shared().len()
+}
+
+/// Copies the shared region of the given index in the manifest to `shared`. Returns false if there
+/// is no such region.
+#[no_mangle]
+pub unsafe extern "C" fn manifest_shared_get(index: usize, shared: *mut ManifestShared) -> bool {
+    match MANIFEST.lock().shared().get(index) {
+        Some(entry) => {
+            *shared = *entry;
+            true
+        }
+        None => false,
+    }
+}
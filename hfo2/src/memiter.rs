@@ -15,6 +15,7 @@
  */
 
 use core::ptr;
+use core::slice;
 
 use crate::std::*;
 use crate::types::*;
@@ -34,12 +35,8 @@ fn is_space(c: u8) -> bool {
     }
 }
 
-fn as_digit(c: u8) -> Option<u8> {
-    if '0' as u8 <= c && c <= '9' as u8 {
-        Some(c - '0' as u8)
-    } else {
-        None
-    }
+fn as_digit(c: u8, radix: u64) -> Option<u8> {
+    (c as char).to_digit(radix as u32).map(|d| d as u8)
 }
 
 impl MemIter {
@@ -69,6 +66,11 @@ impl MemIter {
         len == self_len && memcmp_rs(self.next as *const _, str as *const _, len) == 0
     }
 
+    /// Returns the remaining bytes.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        slice::from_raw_parts(self.next, self.limit as usize - self.next as usize)
+    }
+
     /// Peeks the first byte.
     unsafe fn peek(&self) -> Option<u8> {
         if self.next < self.limit {
@@ -102,16 +104,28 @@ impl MemIter {
         Some(MemIter::from_raw(next, size))
     }
 
-    /// Parses the next string that represents a 64-bit number.
+    /// Parses the next string that represents a 64-bit number, in hexadecimal if it is prefixed by
+    /// `0x` and in decimal otherwise.
     pub unsafe fn parse_uint(&mut self) -> Option<u64> {
         // Skip all white space.
         self.skip_space();
 
+        // Skip the hexadecimal prefix.
+        let mut radix = 10;
+        if self.limit as usize - self.next as usize > 2
+            && *self.next == b'0'
+            && *self.next.add(1) == b'x'
+            && as_digit(*self.next.add(2), 16).is_some()
+        {
+            radix = 16;
+            self.next = self.next.add(2);
+        }
+
         // Find the number.
         let next = self.next;
         let mut value = 0;
-        while let Some(d) = self.peek().and_then(as_digit) {
-            value = value * 10 + d as u64;
+        while let Some(d) = self.peek().and_then(|c| as_digit(c, radix)) {
+            value = value * radix + d as u64;
             self.next = self.next.add(1);
         }
 
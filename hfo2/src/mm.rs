@@ -17,24 +17,32 @@
 //! # Memory management via page tables.
 //!
 //! This file has functions for managing the level 1 and 2 page tables used by Hafnium.  There is a
-//! level 1 mapping used by Hafnium itself to access memory, and then a level 2 mapping per VM.  The
-//! design assumes that all page tables contain only 1-1 mappings, aligned on the block boundaries.
+//! level 1 mapping used by Hafnium itself to access memory, and then a level 2 mapping per VM.  Most
+//! mappings are 1-1, but `PageTable::map()` can also install an arbitrary translation.  Blocks are
+//! only installed where both the input and the output addresses are aligned on the block boundary.
 //!
 //! ## Assumptions
 //!
 //! We assume that the stage 1 and stage 2 page table addresses are `usize`.  It looks like that
 //! assumption might not be holding so we need to check that everything is going to be okay.
 
+use core::cell::{Cell, RefCell};
 use core::cmp;
 use core::marker::PhantomData;
 use core::mem;
 use core::ops::*;
 use core::ptr;
 use core::slice;
-use core::sync::atomic::{fence, AtomicBool, Ordering};
+use core::sync::atomic::{fence, spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+
+use arrayvec::ArrayVec;
 use reduce::Reduce;
 
-use crate::mpool::MPool;
+use crate::barriers;
+use crate::frame;
+use crate::guest::with_mapping;
+use crate::mpool::{MPool, PoolPage};
+use crate::once::Once;
 use crate::page::*;
 use crate::spinlock::SpinLock;
 use crate::types::*;
@@ -59,29 +67,35 @@ extern "C" {
     fn arch_mm_invalidate_stage1_range(begin: usize, end: usize);
     fn arch_mm_invalidate_stage2_range(begin: usize, end: usize);
 
+    fn arch_mm_invalidate_stage1_all();
+    fn arch_mm_invalidate_stage2_all();
+
+    fn arch_mm_write_back_dcache(base: *mut u8, size: usize);
+
     fn arch_mm_mode_to_stage1_attrs(mode: c_int) -> usize;
     fn arch_mm_mode_to_stage2_attrs(mode: c_int) -> usize;
 
+    fn arch_mm_stage1_attrs_to_mode(attrs: usize) -> c_int;
     fn arch_mm_stage2_attrs_to_mode(attrs: usize) -> c_int;
 
-    fn arch_mm_stage1_max_level() -> u8;
-    fn arch_mm_stage2_max_level() -> u8;
-
-    fn arch_mm_stage1_root_table_count() -> u8;
-    fn arch_mm_stage2_root_table_count() -> u8;
+    fn arch_mm_stage1_input_bits() -> u8;
+    fn arch_mm_stage2_input_bits() -> u8;
 
     fn arch_mm_init(table: usize, first: bool) -> bool;
 
     fn arch_mm_combine_table_entry_attrs(table_attrs: usize, block_attrs: usize) -> usize;
 
+    fn arch_mm_attrs_accessed(attrs: usize) -> bool;
+    fn arch_mm_attrs_set_accessed(attrs: usize, accessed: bool) -> usize;
+
     fn plat_console_mm_init(mpool: *const MPool);
 
-    fn layout_text_begin() -> usize;
-    fn layout_text_end() -> usize;
-    fn layout_rodata_begin() -> usize;
-    fn layout_rodata_end() -> usize;
-    fn layout_data_begin() -> usize;
-    fn layout_data_end() -> usize;
+    fn layout_text_begin() -> PhysAddr;
+    fn layout_text_end() -> PhysAddr;
+    fn layout_rodata_begin() -> PhysAddr;
+    fn layout_rodata_end() -> PhysAddr;
+    fn layout_data_begin() -> PhysAddr;
+    fn layout_data_end() -> PhysAddr;
 }
 
 bitflags! {
@@ -113,6 +127,14 @@ bitflags! {
     ///  - !V !O !X : Invalid memory. Memory is unrelated to the VM.
     ///
     ///  Modes are selected so that owner of exclusive memory is the default.
+    ///
+    /// Memory owned by the hypervisor rather than by a VM, e.g. a region the manifest shares among
+    /// VMs, is also marked H. It is only mapped as `V !O !X H`, read-only, into the VMs it is shared
+    /// with, none of which may pass it on to another VM or return it to an owner.
+    ///
+    /// The memory type is selected by at most one of D, NC and WC, and is write-back cacheable
+    /// normal memory by default. Stage-2 leaves the memory type of device and write-back memory to
+    /// stage-1.
     pub struct Mode: u32 {
         /// Read
         const R       = 0b00000001;
@@ -134,6 +156,15 @@ bitflags! {
 
         /// Shared
         const SHARED  = 0b01000000;
+
+        /// Non-cacheable normal memory
+        const NC      = 0b010000000;
+
+        /// Write-combining normal memory
+        const WC      = 0b100000000;
+
+        /// Owned by the hypervisor
+        const HYP     = 0b1000000000;
     }
 }
 
@@ -141,32 +172,166 @@ bitflags! {
     /// Flags for memory management operations.
     struct Flags: u32 {
         /// Commit
-        const COMMIT = 0b01;
+        const COMMIT     = 0b001;
 
         /// Unmap
-        const UNMAP  = 0b10;
+        const UNMAP      = 0b010;
+
+        /// Don't replace subtables that become equivalent to a block with the block
+        const NO_PROMOTE = 0b100;
+
+        /// Zero the memory the VM loses exclusive access to before the update returns
+        const SCRUB      = 0b1000;
+
+        /// Allow tables to be allocated from the emergency reserve of the memory pool
+        const RESERVE    = 0b10000;
+    }
+}
+
+impl Mode {
+    /// Returns whether memory mapped with this mode is zeroed when the VM loses access to it in an
+    /// update with `Flags::SCRUB`. That is the case if the VM has exclusive access to the memory;
+    /// shared memory is left intact as the other VM still has access to it. Device memory, e.g. of
+    /// a device assigned to the VM, is never written to.
+    pub fn needs_scrub(self) -> bool {
+        !self.intersects(Mode::INVALID | Mode::SHARED | Mode::D)
     }
 }
 
-/// The hypervisor page table.
-pub static HYPERVISOR_PAGE_TABLE: SpinLock<PageTable<Stage1>> =
-    SpinLock::new(unsafe { PageTable::null() });
+/// Errors of memory management operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmError {
+    /// The page pool ran out of pages while allocating a page table.
+    OutOfMemory,
+
+    /// The given address range is out of the page table's range.
+    OutOfRange,
+
+    /// The given address range is not mapped with the same attributes.
+    NonUniform,
+
+    /// The architecture refused the operation.
+    Arch,
+
+    /// The given address range overlaps with an existing mapping, or another range of the same
+    /// transaction.
+    Overlap,
+
+    /// The transaction already has `MAP_TRANSACTION_MAX_RANGES` ranges.
+    TooManyRanges,
+
+    /// The given address range is not mapped with the required mode.
+    AccessDenied,
+
+    /// The mapping would be both writable and executable outside of the JIT regions, while W^X is
+    /// enforced.
+    WriteExecute,
+
+    /// The hypervisor page table is already initialised.
+    AlreadyInitialized,
+}
+
+impl MmError {
+    /// Returns whether the operation may succeed if retried later, e.g. after pages are freed to
+    /// the page pool.
+    pub fn is_transient(self) -> bool {
+        match self {
+            MmError::OutOfMemory => true,
+            MmError::OutOfRange
+            | MmError::NonUniform
+            | MmError::Arch
+            | MmError::Overlap
+            | MmError::TooManyRanges
+            | MmError::AccessDenied
+            | MmError::WriteExecute
+            | MmError::AlreadyInitialized => false,
+        }
+    }
+}
+
+/// The hypervisor page table, initialised by `mm_init()`.
+static HYPERVISOR_PAGE_TABLE: Once<SpinLock<PageTable<Stage1>>> = Once::new();
+
+/// Returns the hypervisor page table. Panics if it is not initialised yet.
+pub fn hypervisor_page_table() -> &'static SpinLock<PageTable<Stage1>> {
+    HYPERVISOR_PAGE_TABLE
+        .get()
+        .expect("hypervisor page table is not initialised")
+}
+
+/// The size of the updated range above which the TLB is invalidated for the whole address space
+/// rather than page by page. Invalidating a page costs about as much as invalidating everything
+/// when there are more pages than entries in a last-level table.
+const INVALIDATE_VM_THRESHOLD: usize = PTE_PER_PAGE * PAGE_SIZE;
 
 /// Is stage2 invalidation enabled?
 pub static STAGE2_INVALIDATE: AtomicBool = AtomicBool::new(false);
 
+/// Is W^X enforced for stage-2 mappings? It is always enforced with the `wx_strict` feature.
+pub static WX_STRICT: AtomicBool = AtomicBool::new(cfg!(feature = "wx_strict"));
+
+/// The maximum number of JIT regions.
+pub const WX_WHITELIST_MAX_RANGES: usize = 8;
+
+/// The physical address ranges of the JIT regions, which may be mapped both writable and
+/// executable in stage-2 while W^X is enforced. Unused slots are empty ranges.
+static WX_WHITELIST: SpinLock<[(PhysAddr, PhysAddr); WX_WHITELIST_MAX_RANGES]> =
+    SpinLock::new([(PhysAddr::new(0), PhysAddr::new(0)); WX_WHITELIST_MAX_RANGES]);
+
+/// Whitelists the given physical address range as a JIT region. Fails with
+/// `MmError::TooManyRanges` if there are already `WX_WHITELIST_MAX_RANGES` JIT regions.
+pub fn wx_allow(begin: PhysAddr, end: PhysAddr) -> Result<(), MmError> {
+    let begin = addr::round_down_to_page(begin);
+    let end = addr::round_up_to_page(end);
+    if begin >= end {
+        return Ok(());
+    }
+
+    let mut whitelist = WX_WHITELIST.lock();
+    let slot = whitelist
+        .iter_mut()
+        .find(|(begin, end)| begin >= end)
+        .ok_or(MmError::TooManyRanges)?;
+    *slot = (begin, end);
+    Ok(())
+}
+
+/// Removes the JIT region previously whitelisted with the same range, and returns whether it was
+/// found. Existing mappings of the region are not changed.
+pub fn wx_disallow(begin: PhysAddr, end: PhysAddr) -> bool {
+    let range = (addr::round_down_to_page(begin), addr::round_up_to_page(end));
+    let mut whitelist = WX_WHITELIST.lock();
+
+    match whitelist.iter_mut().find(|slot| **slot == range) {
+        Some(slot) => {
+            *slot = (PhysAddr::new(0), PhysAddr::new(0));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Checks whether the given physical address range is in a JIT region.
+fn wx_allowed(begin: PhysAddr, end: PhysAddr) -> bool {
+    WX_WHITELIST
+        .lock()
+        .iter()
+        .any(|&(jit_begin, jit_end)| jit_begin < jit_end && jit_begin <= begin && end <= jit_end)
+}
+
 /// Utility functions for address manipulation.
 mod addr {
     use crate::page::*;
+    use crate::types::Address;
 
     /// Rounds an address down to a page boundary.
-    pub fn round_down_to_page(addr: usize) -> usize {
-        addr & !(PAGE_SIZE - 1)
+    pub fn round_down_to_page<A: Address>(addr: A) -> A {
+        A::new(addr.addr() & !(PAGE_SIZE - 1))
     }
 
     /// Rounds an address up to a page boundary.
-    pub fn round_up_to_page(addr: usize) -> usize {
-        round_down_to_page(addr + PAGE_SIZE - 1)
+    pub fn round_up_to_page<A: Address>(addr: A) -> A {
+        round_down_to_page(addr + (PAGE_SIZE - 1))
     }
 
     /// Calculates the size of the address space represented by a page table entry at the given
@@ -177,53 +342,101 @@ mod addr {
 
     /// Gets the address of the start of the next block of the given size. The size must be a power
     /// of two.
-    pub fn start_of_next_block(addr: usize, block_size: usize) -> usize {
-        (addr + block_size) & !(block_size - 1)
+    pub fn start_of_next_block<A: Address>(addr: A, block_size: usize) -> A {
+        A::new((addr.addr() + block_size) & !(block_size - 1))
     }
 
     /// For a given address, calculates the maximum (plus one) address that can be represented by
     /// the same table at the given level.
-    pub fn level_end(addr: usize, level: u8) -> usize {
+    pub fn level_end<A: Address>(addr: A, level: u8) -> A {
         let offset = PAGE_BITS + (level as usize + 1) * PAGE_LEVEL_BITS;
-        ((addr >> offset) + 1) << offset
+        A::new(((addr.addr() >> offset) + 1) << offset)
     }
 
     /// For a given address, calculates the index at which its entry is stored in a table at the
     /// given level.
-    pub fn index(addr: usize, level: u8) -> usize {
-        let v = addr >> (PAGE_BITS + level as usize * PAGE_LEVEL_BITS);
+    pub fn index<A: Address>(addr: A, level: u8) -> usize {
+        let v = addr.addr() >> (PAGE_BITS + level as usize * PAGE_LEVEL_BITS);
         v & ((1usize << PAGE_LEVEL_BITS) - 1)
     }
+
+    /// The maximum number of bits that are resolved by concatenating tables at the root instead of
+    /// adding a level.
+    const MAX_CONCATENATED_BITS: usize = 4;
+
+    /// Splits the bits of input addresses above the page offset into full levels and the bits
+    /// resolved by concatenated root tables, so that the page table is the shallowest.
+    fn split_bits(input_bits: u8) -> (usize, usize) {
+        let bits = input_bits as usize - PAGE_BITS;
+        let extend_bits = bits % PAGE_LEVEL_BITS;
+
+        if extend_bits <= MAX_CONCATENATED_BITS {
+            (bits / PAGE_LEVEL_BITS, extend_bits)
+        } else {
+            (bits / PAGE_LEVEL_BITS + 1, 0)
+        }
+    }
+
+    /// Calculates the maximum level of a page table for input addresses of the given number of
+    /// bits.
+    pub fn max_level(input_bits: u8) -> u8 {
+        (split_bits(input_bits).0 - 1) as u8
+    }
+
+    /// Calculates the number of concatenated root tables of a page table for input addresses of
+    /// the given number of bits.
+    pub fn root_table_count(input_bits: u8) -> u8 {
+        1 << split_bits(input_bits).1
+    }
 }
 
 /// Page table stage.
 pub trait Stage {
+    /// The input addresses of the page table.
+    type Addr: Address;
+
+    /// Returns the number of bits of the input addresses, which may be detected at runtime.
+    fn input_bits() -> u8;
+
     /// Returns the maximum level in the page table.
-    fn max_level() -> u8;
+    fn max_level() -> u8 {
+        addr::max_level(Self::input_bits())
+    }
 
     /// Returns the number of root-level tables.
-    fn root_table_count() -> u8;
+    fn root_table_count() -> u8 {
+        addr::root_table_count(Self::input_bits())
+    }
 
     /// Invalidates the TLB for the given address range.
     fn invalidate_tlb(begin: usize, end: usize);
 
+    /// Invalidates the TLB for the whole address space.
+    fn invalidate_vm();
+
     /// Converts the mode into attributes for a block PTE.
     fn mode_to_attrs(mode: Mode) -> usize;
 
     /// Converts the attributes back to the corresponding mode.
     fn attrs_to_mode(attrs: usize) -> Mode;
+
+    /// Applies the W^X policy to a mapping of the physical address range `[begin, end)` with the
+    /// given mode. Fails with `MmError::WriteExecute` if the mode is not allowed.
+    fn wx_mode(begin: PhysAddr, end: PhysAddr, mode: Mode) -> Result<Mode, MmError>;
+
+    /// Records in the frame table that the physical address range `[begin, end)` is mapped with
+    /// the given attributes in the page table of `owner`.
+    fn update_owners(owner: u32, begin: PhysAddr, end: PhysAddr, attrs: usize);
 }
 
 /// The page table stage for the hypervisor.
 pub struct Stage1 {}
 
 impl Stage for Stage1 {
-    fn max_level() -> u8 {
-        unsafe { arch_mm_stage1_max_level() }
-    }
+    type Addr = VirtAddr;
 
-    fn root_table_count() -> u8 {
-        unsafe { arch_mm_stage1_root_table_count() }
+    fn input_bits() -> u8 {
+        unsafe { arch_mm_stage1_input_bits() }
     }
 
     fn invalidate_tlb(begin: usize, end: usize) {
@@ -232,12 +445,26 @@ impl Stage for Stage1 {
         }
     }
 
+    fn invalidate_vm() {
+        unsafe {
+            arch_mm_invalidate_stage1_all();
+        }
+    }
+
     fn mode_to_attrs(mode: Mode) -> usize {
         unsafe { arch_mm_mode_to_stage1_attrs(mode.bits as c_int) }
     }
 
-    fn attrs_to_mode(_attrs: usize) -> Mode {
-        panic!("`arch_mm_stage2_attrs_to_mode()` doesn't exist");
+    fn attrs_to_mode(attrs: usize) -> Mode {
+        Mode::from_bits_truncate(unsafe { arch_mm_stage1_attrs_to_mode(attrs) } as u32)
+    }
+
+    fn wx_mode(_begin: PhysAddr, _end: PhysAddr, mode: Mode) -> Result<Mode, MmError> {
+        Ok(mode)
+    }
+
+    fn update_owners(_owner: u32, _begin: PhysAddr, _end: PhysAddr, _attrs: usize) {
+        // The hypervisor doesn't own memory in the frame table.
     }
 }
 
@@ -245,12 +472,10 @@ impl Stage for Stage1 {
 pub struct Stage2 {}
 
 impl Stage for Stage2 {
-    fn max_level() -> u8 {
-        unsafe { arch_mm_stage2_max_level() }
-    }
+    type Addr = IpaAddr;
 
-    fn root_table_count() -> u8 {
-        unsafe { arch_mm_stage2_root_table_count() }
+    fn input_bits() -> u8 {
+        unsafe { arch_mm_stage2_input_bits() }
     }
 
     fn invalidate_tlb(begin: usize, end: usize) {
@@ -261,6 +486,14 @@ impl Stage for Stage2 {
         }
     }
 
+    fn invalidate_vm() {
+        if STAGE2_INVALIDATE.load(Ordering::Relaxed) {
+            unsafe {
+                arch_mm_invalidate_stage2_all();
+            }
+        }
+    }
+
     fn mode_to_attrs(mode: Mode) -> usize {
         unsafe { arch_mm_mode_to_stage2_attrs(mode.bits as c_int) }
     }
@@ -268,6 +501,30 @@ impl Stage for Stage2 {
     fn attrs_to_mode(attrs: usize) -> Mode {
         Mode::from_bits_truncate(unsafe { arch_mm_stage2_attrs_to_mode(attrs) } as u32)
     }
+
+    fn wx_mode(begin: PhysAddr, end: PhysAddr, mode: Mode) -> Result<Mode, MmError> {
+        if !mode.contains(Mode::W | Mode::X)
+            || mode.contains(Mode::INVALID)
+            || !WX_STRICT.load(Ordering::Relaxed)
+            || wx_allowed(begin, end)
+        {
+            return Ok(mode);
+        }
+
+        Err(MmError::WriteExecute)
+    }
+
+    fn update_owners(owner: u32, begin: PhysAddr, end: PhysAddr, attrs: usize) {
+        if owner == frame::NO_OWNER {
+            return;
+        }
+
+        if Self::attrs_to_mode(attrs).contains(Mode::UNOWNED) {
+            frame::clear_owner(owner, begin, end);
+        } else {
+            frame::set_owner(owner, begin, end);
+        }
+    }
 }
 
 /// Page table entry.
@@ -322,6 +579,18 @@ impl PageTableEntry {
         unsafe { arch_mm_pte_attrs(self.inner, level) }
     }
 
+    /// Returns the attributes, regarding the block as accessed. The access flag is not a part of
+    /// the mapping's mode, and the hardware may set it at any time.
+    fn attrs_accessed(&self, level: u8) -> usize {
+        let attrs = self.attrs(level);
+
+        if self.is_block(level) {
+            unsafe { arch_mm_attrs_set_accessed(attrs, true) }
+        } else {
+            attrs
+        }
+    }
+
     fn as_block(&self, level: u8) -> Option<usize> {
         if self.is_block(level) {
             Some(unsafe { self.as_block_unchecked(level) })
@@ -358,15 +627,15 @@ impl PageTableEntry {
     /// # Safety
     ///
     /// After a page table entry is freed, it's value is undefined.
-    unsafe fn free(&mut self, level: u8, mpool: &MPool) {
+    unsafe fn free(&mut self, level: u8, pool: &TablePool) {
         if let Some(table) = self.as_table_mut(level) {
             // Recursively free any subtables.
             for pte in table.iter_mut() {
-                pte.free(level - 1, mpool);
+                pte.free(level - 1, pool);
             }
 
             // Free the table itself.
-            mpool.free(Page::from_raw(table as *mut _ as *mut _));
+            pool.free(Page::from_raw(table as *mut _ as *mut _));
         }
     }
 
@@ -380,7 +649,7 @@ impl PageTableEntry {
         new_pte: PageTableEntry,
         begin: usize,
         level: u8,
-        mpool: &MPool,
+        pool: &TablePool,
     ) {
         let inner = self.inner;
 
@@ -399,40 +668,89 @@ impl PageTableEntry {
         // Free pages that aren't in use anymore.
         unsafe {
             let mut old_pte = Self::from_raw(inner);
-            old_pte.free(level, mpool);
+            old_pte.free(level, pool);
             mem::forget(old_pte);
         }
     }
 
+    /// Replaces a page table entry with the given value like `replace()`, but defers the
+    /// break-before-make sequence to the pool's batch if any. The entry is broken at once, and made
+    /// when the batch is flushed.
+    fn replace_deferred<S: Stage>(
+        &mut self,
+        new_pte: PageTableEntry,
+        begin: usize,
+        level: u8,
+        pool: &TablePool,
+    ) {
+        let batch = match pool.batch {
+            Some(batch) => batch,
+            None => return self.replace::<S>(new_pte, begin, level, pool),
+        };
+
+        if !(self.is_valid(level) && new_pte.is_valid(level)) {
+            // The entries deferred so far may be in the table to be freed.
+            if self.is_table(level) {
+                batch.borrow_mut().flush::<S>(pool);
+            }
+            return self.replace::<S>(new_pte, begin, level, pool);
+        }
+
+        let mut batch = batch.borrow_mut();
+        if batch.deferred.is_full() {
+            batch.flush::<S>(pool);
+        }
+
+        let old = self.inner;
+        unsafe { ptr::write(self, Self::absent(level)) };
+        batch.begin = cmp::min(batch.begin, begin);
+        batch.end = cmp::max(batch.end, begin + addr::entry_size(level));
+        batch.deferred.push(DeferredPte {
+            pte: self,
+            old,
+            new: new_pte.inner,
+            level,
+        });
+        mem::forget(new_pte);
+    }
+
     /// Populates the provided page table entry with a reference to another table if needed, that
     /// is, if it does not yet point to another table.
     ///
-    /// Returns a pointer to the table the entry now points to.
-    fn populate_table<S: Stage>(&mut self, begin: usize, level: u8, mpool: &MPool) -> Option<()> {
+    /// Returns a pointer to the table the entry now points to. The table is allocated from the
+    /// emergency reserve of the memory pool if needed and `reserve` is set.
+    fn populate_table<S: Stage>(
+        &mut self,
+        begin: usize,
+        level: u8,
+        reserve: bool,
+        pool: &TablePool,
+    ) -> Result<(), MmError> {
         // Just return if it's already populated.
         if self.is_table(level) {
-            return Some(());
+            return Ok(());
         }
 
-        // Allocate a new table.
-        let mut page = mpool
-            .alloc()
-            .ok_or_else(|| dlog!("Failed to allocate memory for page table\n"))
-            .ok()?;
+        // Allocate a new table. It is freed if dropped before it is installed.
+        let mut page = pool.alloc(reserve).ok_or_else(|| {
+            dlog!("Failed to allocate memory for page table\n");
+            MmError::OutOfMemory
+        })?;
 
-        let table = unsafe { RawPageTable::deref_mut_page(&mut page) };
+        let table = unsafe { RawPageTable::deref_mut_raw_page(&mut page) };
 
         // Initialise entries in the new table.
         let level_below = level - 1;
         if self.is_block(level) {
             let attrs = self.attrs(level);
+            let block_address = unsafe { self.as_block_unchecked(level) };
             let entry_size = addr::entry_size(level_below);
 
             for (i, pte) in table.iter_mut().enumerate() {
                 unsafe {
                     ptr::write(
                         pte,
-                        Self::block(level_below, self.inner + i * entry_size, attrs),
+                        Self::block(level_below, block_address + i * entry_size, attrs),
                     );
                 }
             }
@@ -442,21 +760,20 @@ impl PageTableEntry {
             }
         }
 
-        // Ensure initialisation is visible before updating the pte.
-        //
-        // TODO(@jeehoonkang): very suspicious..
-        fence(Ordering::Release);
+        // Ensure initialisation is visible before updating the pte, including to the table walkers
+        // of other CPUs, which are not ordered by atomic fences.
+        barriers::dmb_ishst();
 
         // Replace the pte entry, doing a break-before-make if needed.
-        let table = unsafe { Self::table(level, page) };
-        self.replace::<S>(table, begin, level, mpool);
+        let table = unsafe { Self::table(level, pool.install(page)) };
+        self.replace::<S>(table, begin, level, pool);
 
-        Some(())
+        Ok(())
     }
 
     /// Defragments the given PTE by recursively replacing any tables with blocks or absent entries
     /// where possible.
-    fn defrag(&mut self, level: u8, mpool: &MPool) -> Option<usize> {
+    fn defrag(&mut self, level: u8, pool: &TablePool) -> Option<usize> {
         let attrs = self.attrs(level);
 
         if self.is_block(level) {
@@ -466,17 +783,16 @@ impl PageTableEntry {
         let table = self.as_table_mut(level)?;
 
         // First try to defrag the entry, in case it is a subtable. Then check if all entries are
-        // blocks with the same flags or are all absent. It assumes addresses are contiguous due to
-        // identity mapping.
+        // blocks with the same flags or are all absent.
         let children_attrs = table
             .iter_mut()
-            .map(|pte| pte.defrag(level - 1, mpool))
+            .map(|pte| pte.defrag(level - 1, pool))
             .reduce(|l, r| if l == r { l } else { None })??;
 
         // If the table's all the entries are absent, free the table and return an absent entry.
         unsafe {
             if !arch_mm_pte_is_present(children_attrs, level - 1) {
-                mpool.free(Page::from_raw(table as *mut _ as *mut _));
+                pool.free(Page::from_raw(table as *mut _ as *mut _));
                 ptr::write(self, Self::absent(level));
                 return Some(self.attrs(level));
             }
@@ -487,11 +803,14 @@ impl PageTableEntry {
             return None;
         }
 
+        // Bail out if the blocks are not physically contiguous, or the first one is not aligned on
+        // the current level's block boundary. This can happen for non-identity mappings.
+        let (block_address, _) = table.as_merged_block(level - 1)?;
+
         // Merge table into a single block with equivalent attributes.
-        let block_address = unsafe { table.get_unchecked(0).as_block_unchecked(level - 1) };
         let combined_attrs = unsafe { arch_mm_combine_table_entry_attrs(attrs, children_attrs) };
 
-        mpool.free(unsafe { Page::from_raw(table as *mut _ as *mut _) });
+        pool.free(unsafe { Page::from_raw(table as *mut _ as *mut _) });
         unsafe {
             ptr::write(
                 self,
@@ -569,10 +888,6 @@ impl RawPageTable {
         Self::deref_raw_page(page)
     }
 
-    unsafe fn deref_mut_page(page: &mut Page) -> &mut Self {
-        Self::deref_mut_raw_page(page)
-    }
-
     unsafe fn deref_raw_page(page: &RawPage) -> &Self {
         &*(page as *const _ as *const _)
     }
@@ -586,9 +901,33 @@ impl RawPageTable {
         self.iter().all(|pte| !pte.is_present(level))
     }
 
+    /// Returns the address and the attributes of the block of the level above that is equivalent to
+    /// this table of the given level, if all entries are blocks with the same attributes that are
+    /// physically contiguous and aligned on the level above's block boundary.
+    fn as_merged_block(&self, level: u8) -> Option<(usize, usize)> {
+        let first = &self[0];
+        let block_address = first.as_block(level)?;
+        let attrs = first.attrs(level);
+        let entry_size = addr::entry_size(level);
+
+        if block_address & (addr::entry_size(level + 1) - 1) != 0 {
+            return None;
+        }
+
+        let mergeable = self.iter().enumerate().all(|(i, pte)| {
+            pte.as_block(level) == Some(block_address + i * entry_size) && pte.attrs(level) == attrs
+        });
+
+        if mergeable {
+            Some((block_address, attrs))
+        } else {
+            None
+        }
+    }
+
     /// Updates the page table at the given level to map the given address range to a physical range
-    /// using the provided (architecture-specific) attributes. Or if MM_FLAG_UNMAP is set, unmap the
-    /// given range instead.
+    /// starting at `pa` using the provided (architecture-specific) attributes. Or if MM_FLAG_UNMAP
+    /// is set, unmap the given range instead.
     ///
     /// This function calls itself recursively if it needs to update additional levels, but the
     /// recursion is bound by the maximum number of levels in a page table.
@@ -596,16 +935,23 @@ impl RawPageTable {
         &mut self,
         begin: usize,
         end: usize,
+        pa: usize,
         attrs: usize,
         level: u8,
         flags: Flags,
-        mpool: &MPool,
-    ) -> Option<()> {
+        pool: &TablePool,
+    ) -> Result<(), MmError> {
         let entry_size = addr::entry_size(level);
         let commit = !(flags & Flags::COMMIT).is_empty();
         let unmap = !(flags & Flags::UNMAP).is_empty();
+        let promote = (flags & Flags::NO_PROMOTE).is_empty();
+
+        // Unmapping and committing should not fail for lack of memory, as the commit pass follows
+        // a successful populate pass and unmapping is how memory is revoked.
+        let reserve = commit || unmap || !(flags & Flags::RESERVE).is_empty();
 
         let ptes = self[addr::index(begin, level)..].iter_mut();
+        let va_begin = begin;
         let begins = BlockIter::new(
             begin,
             // Cap end so that we don't go over the current level max.
@@ -615,12 +961,14 @@ impl RawPageTable {
 
         // Fill each entry in the table.
         for (pte, begin) in ptes.zip(begins) {
+            let pa = pa + (begin - va_begin);
+
             // If the entry is already mapped with the right attributes, or already absent in the
             // case of unmapping, no need to do anything; carry on to the next entry.
             if unmap && !pte.is_present(level) {
                 continue;
             }
-            if !unmap && pte.is_block(level) && pte.attrs(level) == attrs {
+            if !unmap && pte.attrs(level) == attrs && pte.as_block(level) == Some(pa) {
                 continue;
             }
 
@@ -628,14 +976,15 @@ impl RawPageTable {
             if end - begin >= entry_size
                 && (unmap || unsafe { arch_mm_is_block_allowed(level) })
                 && (begin & (entry_size - 1) == 0)
+                && (unmap || pa & (entry_size - 1) == 0)
             {
                 if commit {
                     let new_pte = if unmap {
                         PageTableEntry::absent(level)
                     } else {
-                        PageTableEntry::block(level, begin, attrs)
+                        PageTableEntry::block(level, pa, attrs)
                     };
-                    pte.replace::<S>(new_pte, begin, level, mpool);
+                    pte.replace_deferred::<S>(new_pte, begin, level, pool);
                 }
 
                 continue;
@@ -643,24 +992,43 @@ impl RawPageTable {
 
             // If the entry is already a subtable get it; otherwise replace it with an equivalent
             // subtable and get that.
-            pte.populate_table::<S>(begin, level, mpool)?;
+            pte.populate_table::<S>(begin, level, reserve, pool)?;
 
             // Since `pte` is just populated, it should be a table.
             let new_table = pte.as_table_mut(level).unwrap();
 
             // Recurse to map/unmap the appropriate entries within the subtable.
-            new_table.map_level::<S>(begin, end, attrs, level - 1, flags, mpool)?;
+            new_table.map_level::<S>(begin, end, pa, attrs, level - 1, flags, pool)?;
 
             // If the subtable is now empty, replace it with an absent entry at this level. We never
             // need to do break-before-makes here because we are assigning an absent value.
             //
             // TODO(@jeehoonkang): I think we should do break-before-makes here due to reordering.
             if commit && unmap && new_table.is_empty(level - 1) {
-                pte.replace::<S>(PageTableEntry::absent(level), begin, level, mpool);
+                pte.replace_deferred::<S>(PageTableEntry::absent(level), begin, level, pool);
+                continue;
+            }
+
+            // If the subtable is now equivalent to a single block, replace it with the block. This
+            // saves a later defrag pass and TLB entries.
+            if commit && !unmap && promote && unsafe { arch_mm_is_block_allowed(level) } {
+                // The deferred entries of the subtable should be assigned before inspecting it.
+                if let Some(batch) = pool.batch {
+                    batch.borrow_mut().flush::<S>(pool);
+                }
+
+                if let Some((block_address, children_attrs)) = new_table.as_merged_block(level - 1)
+                {
+                    let attrs = unsafe {
+                        arch_mm_combine_table_entry_attrs(pte.attrs(level), children_attrs)
+                    };
+                    let block = PageTableEntry::block(level, block_address, attrs);
+                    pte.replace_deferred::<S>(block, begin, level, pool);
+                }
             }
         }
 
-        Some(())
+        Ok(())
     }
 
     /// Gets the attributes applied to the given range of stage-2 addresses at the given level.
@@ -686,13 +1054,85 @@ impl RawPageTable {
                 if let Some(table) = pte.as_table(level) {
                     table.get_attrs_level(begin, end, level - 1)
                 } else {
-                    Some(pte.attrs(level))
+                    Some(pte.attrs_accessed(level))
                 }
             })
             .opt_reduce(|l, r| if l == r { Some(l) } else { None })
     }
 
+    /// Copies the entries of `src` at the given level into this table, allocating new subtables
+    /// from the memory pool.
+    ///
+    /// This table should contain only absent entries. On failure, it may be left with a part of
+    /// the entries copied, which should be freed by the caller.
+    fn clone_level(&mut self, src: &Self, level: u8, pool: &TablePool) -> Result<(), MmError> {
+        for (pte, src_pte) in self.iter_mut().zip(src.iter()) {
+            let src_table = match src_pte.as_table(level) {
+                Some(src_table) => src_table,
+                None => {
+                    unsafe { ptr::write(pte, PageTableEntry::from_raw(src_pte.inner)) };
+                    continue;
+                }
+            };
+
+            let mut page = pool.alloc(false).ok_or(MmError::OutOfMemory)?;
+            let table = unsafe { RawPageTable::deref_mut_raw_page(&mut page) };
+            for entry in table.iter_mut() {
+                unsafe { ptr::write(entry, PageTableEntry::absent(level - 1)) };
+            }
+
+            // Ensure initialisation is visible before updating the pte.
+            barriers::dmb_ishst();
+            unsafe { ptr::write(pte, PageTableEntry::table(level, pool.install(page))) };
+
+            pte.as_table_mut(level)
+                .unwrap()
+                .clone_level(src_table, level - 1, pool)?;
+        }
+
+        Ok(())
+    }
+
     /// Writes the given table to the debug log, calling itself recursively to write sub-tables.
+    /// Checks the structural invariants of the table at the given level and its subtables, and
+    /// returns the number of its subtables. See `PageTable::check_invariants()`.
+    fn check_level(&self, level: u8, mpool: &MPool) -> usize {
+        let mut count = 0;
+
+        for (i, pte) in self.iter().enumerate() {
+            if !pte.is_present(level) {
+                continue;
+            }
+
+            if pte.is_block(level) {
+                assert!(
+                    unsafe { arch_mm_is_block_allowed(level) },
+                    "block at level {} where blocks are not allowed, index {}",
+                    level,
+                    i
+                );
+                continue;
+            }
+
+            let table = pte.as_table(level).unwrap_or_else(|| {
+                panic!(
+                    "present entry {:#x} is neither a block nor a table at level {}, index {}",
+                    pte.inner, level, i
+                )
+            });
+            assert!(
+                !mpool.contains(table as *const _ as usize),
+                "subtable at level {}, index {} is free in the memory pool",
+                level,
+                i
+            );
+
+            count += 1 + table.check_level(level - 1, mpool);
+        }
+
+        count
+    }
+
     fn dump(&self, level: u8, max_level: u8) {
         for (i, pte) in self.iter().enumerate() {
             if !pte.is_present(level) {
@@ -714,215 +1154,1712 @@ impl RawPageTable {
     }
 }
 
-/// Page table.
-pub struct PageTable<S: Stage> {
-    root: usize,
-    _marker: PhantomData<S>,
+/// Page table memory accounting.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageTableStats {
+    /// The number of pages allocated for the page table.
+    pub allocated: usize,
+
+    /// The number of pages of the page table that are freed.
+    pub freed: usize,
 }
 
-impl<S: Stage> PageTable<S> {
-    const unsafe fn from_raw(root: usize) -> Self {
+impl PageTableStats {
+    const fn new() -> Self {
         Self {
-            root,
-            _marker: PhantomData,
+            allocated: 0,
+            freed: 0,
         }
     }
 
-    const unsafe fn null() -> Self {
-        Self::from_raw(0)
+    /// Returns the number of pages the page table currently consumes.
+    pub fn footprint(&self) -> usize {
+        self.allocated - self.freed
     }
+}
 
-    /// Creates a new page table.
-    pub fn new(mpool: &MPool) -> Option<Self> {
-        let root_table_count = S::root_table_count();
-        let mut pages = mpool.alloc_pages(root_table_count as usize, root_table_count as usize)?;
+/// The maximum number of entries deferred in a `TlbBatch`.
+const TLB_BATCH_MAX_ENTRIES: usize = 16;
 
-        for page in pages.iter_mut() {
-            let table = unsafe { RawPageTable::deref_mut_raw_page(page) };
+/// A page table entry that is broken, and to be made with the new value.
+struct DeferredPte {
+    pte: *mut PageTableEntry,
+    old: usize,
+    new: usize,
+    level: u8,
+}
 
-            for pte in table.iter_mut() {
-                unsafe { ptr::write(pte, PageTableEntry::absent(S::max_level())) };
-            }
-        }
+/// A batch of break-before-make sequences. The entries are broken, i.e., made absent, as they are
+/// deferred to the batch, and made, i.e., assigned their new values, after the TLB is invalidated
+/// for all of them at once.
+struct TlbBatch {
+    /// The range covering all the deferred entries.
+    begin: usize,
+    end: usize,
 
-        // TODO: halloc could return a virtual or physical address if mm not enabled?
-        Some(Self {
-            root: pages.into_raw() as usize,
-            _marker: PhantomData,
-        })
+    deferred: ArrayVec<[DeferredPte; TLB_BATCH_MAX_ENTRIES]>,
+}
+
+impl TlbBatch {
+    fn new() -> Self {
+        Self {
+            begin: usize::max_value(),
+            end: 0,
+            deferred: ArrayVec::new(),
+        }
     }
 
-    /// Frees all memory associated with the give page table.
-    pub fn drop(mut self, mpool: &MPool) {
-        let level = S::max_level();
+    /// Invalidates the TLB for the deferred entries, then assigns their new values and frees the
+    /// pages no longer in use.
+    fn flush<S: Stage>(&mut self, pool: &TablePool) {
+        if self.deferred.is_empty() {
+            return;
+        }
 
-        for page_table in self.deref_mut().iter_mut() {
-            for pte in page_table.iter_mut() {
-                unsafe {
-                    pte.free(level, mpool);
-                }
+        if self.end - self.begin > INVALIDATE_VM_THRESHOLD {
+            S::invalidate_vm();
+        } else {
+            S::invalidate_tlb(self.begin, self.end);
+        }
+
+        for deferred in self.deferred.drain(..) {
+            unsafe {
+                ptr::write(deferred.pte, PageTableEntry::from_raw(deferred.new));
+
+                let mut old_pte = PageTableEntry::from_raw(deferred.old);
+                old_pte.free(deferred.level, pool);
+                mem::forget(old_pte);
             }
         }
 
-        mpool.free_pages(unsafe {
-            Pages::from_raw(self.root as *mut _, S::root_table_count() as usize)
-        });
-        mem::forget(self);
+        self.begin = usize::max_value();
+        self.end = 0;
     }
+}
 
-    fn get_raw(&self) -> *const RawPage {
-        self.root as *const RawPage
-    }
+/// The memory pool to allocate the tables of a page table from, which counts the allocated and
+/// freed tables in the page table's statistics.
+struct TablePool<'a> {
+    mpool: &'a MPool,
+    stats: &'a Cell<PageTableStats>,
 
-    fn deref(&self) -> &[RawPageTable] {
-        unsafe {
-            slice::from_raw_parts(
-                self.root as *const RawPageTable,
-                S::root_table_count() as usize,
-            )
+    /// The batch that break-before-make sequences are deferred to, if any.
+    batch: Option<&'a RefCell<TlbBatch>>,
+}
+
+impl<'a> TablePool<'a> {
+    /// Returns the pool with the given batch to defer break-before-make sequences to.
+    fn with_batch<'b>(&self, batch: &'b RefCell<TlbBatch>) -> TablePool<'b>
+    where
+        'a: 'b,
+    {
+        TablePool {
+            mpool: self.mpool,
+            stats: self.stats,
+            batch: Some(batch),
         }
     }
 
-    fn deref_mut(&mut self) -> &mut [RawPageTable] {
-        unsafe {
-            slice::from_raw_parts_mut(
-                self.root as *mut RawPageTable,
-                S::root_table_count() as usize,
-            )
+    /// Allocates a page for a table, which is freed back to the pool if dropped before it is
+    /// installed with `install()`. The emergency reserve is used if needed and `reserve` is set.
+    fn alloc(&self, reserve: bool) -> Option<PoolPage<'a>> {
+        if reserve {
+            PoolPage::alloc_reserved(self.mpool)
+        } else {
+            PoolPage::alloc(self.mpool)
         }
     }
 
-    /// Updates the page table from the root to map the given address range to a physical range
-    /// using the provided (architecture-specific) attributes. Or if MM_FLAG_UNMAP is set, unmap the
-    /// given range instead.
-    fn map_root(
-        &mut self,
-        begin: usize,
-        end: usize,
-        attrs: usize,
-        root_level: u8,
-        flags: Flags,
-        mpool: &MPool,
-    ) -> Option<()> {
-        let root_table_size = addr::entry_size(root_level);
+    /// Takes the page as a table of the page table, counting it in the statistics. The table
+    /// should be freed with `free()`.
+    fn install(&self, page: PoolPage<'a>) -> Page {
+        let mut stats = self.stats.get();
+        stats.allocated += 1;
+        self.stats.set(stats);
+        page.into_page()
+    }
 
-        let tables = self.deref_mut()[addr::index(begin, root_level)..].iter_mut();
-        let begins = BlockIter::new(begin, end, root_table_size);
+    fn free(&self, page: Page) {
+        self.mpool.free(page);
+        let mut stats = self.stats.get();
+        stats.freed += 1;
+        self.stats.set(stats);
+    }
+}
 
-        for (table, begin) in tables.zip(begins) {
-            table.map_level::<S>(begin, end, attrs, root_level - 1, flags, mpool)?;
-        }
+/// A block mapped in a page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block<A: Address> {
+    /// The first address of the block.
+    pub begin: A,
 
-        Some(())
-    }
+    /// The address one past the end of the block.
+    pub end: A,
 
-    /// Updates the given table such that the given physical address range is mapped or not mapped
-    /// into the address space with the architecture-agnostic mode provided.
-    fn identity_update(
-        &mut self,
-        begin: usize,
+    /// The physical address `begin` is mapped to.
+    pub pa: PhysAddr,
+
+    /// The architecture-specific attributes of the block.
+    pub attrs: usize,
+}
+
+/// The position of an incremental defragmentation of a page table. See `PageTable::defrag_step()`.
+#[derive(Debug, Default)]
+pub struct DefragCursor {
+    /// Index of the next root table entry to defragment.
+    index: usize,
+}
+
+impl DefragCursor {
+    pub const fn new() -> Self {
+        Self { index: 0 }
+    }
+}
+
+/// Maximum number of levels of a page table, not counting the concatenated root tables.
+const MAX_LEVELS: usize = 4;
+
+/// Iterator over the present blocks of a page table in the ascending order of addresses, returned
+/// by `PageTable::blocks()`.
+pub struct BlockWalker<'a, S: Stage> {
+    ptable: &'a PageTable<S>,
+
+    /// Index of the next root table to walk.
+    root_index: usize,
+
+    /// The tables being walked from the root, with their level, the index of the next entry and
+    /// their first address.
+    stack: ArrayVec<[(&'a RawPageTable, u8, usize, usize); MAX_LEVELS]>,
+}
+
+impl<'a, S: Stage> Iterator for BlockWalker<'a, S> {
+    type Item = Block<S::Addr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (table, level, index, begin) = match self.stack.last_mut() {
+                Some(top) => {
+                    let result = *top;
+                    top.2 += 1;
+                    result
+                }
+                None => {
+                    let root_level = S::max_level() + 1;
+                    let table = self.ptable.deref().get(self.root_index)?;
+                    let begin = self.root_index * addr::entry_size(root_level);
+
+                    self.stack.push((table, S::max_level(), 0, begin));
+                    self.root_index += 1;
+                    continue;
+                }
+            };
+
+            if index >= PTE_PER_PAGE {
+                self.stack.pop();
+                continue;
+            }
+
+            let pte = &table[index];
+            let entry_size = addr::entry_size(level);
+            let begin = begin + index * entry_size;
+
+            if let Some(subtable) = pte.as_table(level) {
+                self.stack.push((subtable, level - 1, 0, begin));
+                continue;
+            }
+
+            if let Some(pa) = pte.as_block(level) {
+                return Some(Block {
+                    begin: S::Addr::new(begin),
+                    end: S::Addr::new(begin + entry_size),
+                    pa: PhysAddr::new(pa),
+                    attrs: pte.attrs(level),
+                });
+            }
+        }
+    }
+}
+
+/// A maximal subrange of a range that is mapped with the same mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeSegment<A: Address> {
+    /// The first address of the subrange.
+    pub begin: A,
+
+    /// The address one past the end of the subrange.
+    pub end: A,
+
+    /// The mode of the subrange.
+    pub mode: Mode,
+}
+
+/// Iterator over the subranges of a range that are mapped with the same mode, returned by
+/// `PageTable::get_modes()`.
+pub struct ModeIter<'a, S: Stage> {
+    ptable: &'a PageTable<S>,
+    begin: usize,
+    end: usize,
+}
+
+impl<'a, S: Stage> Iterator for ModeIter<'a, S> {
+    type Item = ModeSegment<S::Addr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.begin >= self.end {
+            return None;
+        }
+
+        let begin = self.begin;
+        let (mode, mut end) = self.ptable.mode_at(begin);
+
+        // Merge the following entries with the same mode.
+        while end < self.end {
+            let (next_mode, next_end) = self.ptable.mode_at(end);
+            if next_mode != mode {
+                break;
+            }
+            end = next_end;
+        }
+
+        let end = cmp::min(end, self.end);
+        self.begin = end;
+        Some(ModeSegment {
+            begin: S::Addr::new(begin),
+            end: S::Addr::new(end),
+            mode,
+        })
+    }
+}
+
+/// The pages of a range that a dirty log write-protected, registered with the page table so that
+/// the protection of a page is dropped when it is remapped. See `dirty::DirtyLog`.
+///
+/// The page table owns the pages of the bitmap while it is registered, and frees them if it is
+/// dropped before they are taken back, so the bitmap lives as long as the page table uses it.
+#[repr(C)]
+struct WriteProtected {
+    begin: usize,
+    end: usize,
+
+    /// The pages of the bitmap of the protected pages of `[begin, end)`, or null if none is
+    /// registered.
+    bitmap: *mut RawPage,
+    bitmap_pages: usize,
+}
+
+// The bitmap is owned by the page table, and only accessed through it.
+unsafe impl Send for WriteProtected {}
+
+impl WriteProtected {
+    const fn none() -> Self {
+        Self {
+            begin: 0,
+            end: 0,
+            bitmap: ptr::null_mut(),
+            bitmap_pages: 0,
+        }
+    }
+
+    /// Returns the byte and the mask of the bit of the given page, or `None` if no bitmap is
+    /// registered or the page is outside its range.
+    fn bit(&self, page: usize) -> Option<(*mut u8, u8)> {
+        if self.bitmap.is_null() || page < self.begin || page >= self.end {
+            return None;
+        }
+
+        let i = (page - self.begin) / PAGE_SIZE;
+        Some((unsafe { (self.bitmap as *mut u8).add(i / 8) }, 1 << (i % 8)))
+    }
+
+    fn get(&self, page: usize) -> bool {
+        self.bit(page)
+            .map_or(false, |(byte, mask)| unsafe { *byte } & mask != 0)
+    }
+
+    fn set(&mut self, page: usize, value: bool) {
+        if let Some((byte, mask)) = self.bit(page) {
+            unsafe {
+                if value {
+                    *byte |= mask;
+                } else {
+                    *byte &= !mask;
+                }
+            }
+        }
+    }
+
+    /// Clears the bits of the pages in `[begin, end)`.
+    fn forget(&mut self, begin: usize, end: usize) {
+        let begin = cmp::max(begin, self.begin);
+        let end = cmp::min(end, self.end);
+        for page in (begin..end).step_by(PAGE_SIZE) {
+            self.set(page, false);
+        }
+    }
+
+    /// Unregisters the bitmap and returns its pages.
+    fn take(&mut self) -> Option<Pages> {
+        if self.bitmap.is_null() {
+            return None;
+        }
+
+        let pages = unsafe { Pages::from_raw(self.bitmap, self.bitmap_pages) };
+        *self = Self::none();
+        Some(pages)
+    }
+}
+
+/// Page table.
+#[repr(C)]
+pub struct PageTable<S: Stage> {
+    root: usize,
+    stats: Cell<PageTableStats>,
+
+    /// Sequence number for `PageTableView`, which is odd while the page table is being updated.
+    seq: AtomicUsize,
+
+    /// The ID of the VM whose memory is mapped by the page table, recorded in the frame table, or
+    /// `frame::NO_OWNER`.
+    owner: u32,
+
+    /// The pages write-protected by dirty page tracking.
+    write_protected: WriteProtected,
+
+    _marker: PhantomData<S>,
+}
+
+impl<S: Stage> PageTable<S> {
+    const unsafe fn from_raw(root: usize) -> Self {
+        Self {
+            root,
+            stats: Cell::new(PageTableStats::new()),
+            seq: AtomicUsize::new(0),
+            owner: frame::NO_OWNER,
+            write_protected: WriteProtected::none(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Creates a new page table.
+    pub fn new(mpool: &MPool) -> Result<Self, MmError> {
+        let root_table_count = S::root_table_count();
+        let mut pages = mpool
+            .alloc_aligned(root_table_count as usize, root_table_count as usize)
+            .ok_or(MmError::OutOfMemory)?;
+
+        for page in pages.iter_mut() {
+            let table = unsafe { RawPageTable::deref_mut_raw_page(page) };
+
+            for pte in table.iter_mut() {
+                unsafe { ptr::write(pte, PageTableEntry::absent(S::max_level())) };
+            }
+        }
+
+        // TODO: halloc could return a virtual or physical address if mm not enabled?
+        Ok(Self {
+            root: pages.into_raw() as usize,
+            stats: Cell::new(PageTableStats {
+                allocated: root_table_count as usize,
+                freed: 0,
+            }),
+            seq: AtomicUsize::new(0),
+            owner: frame::NO_OWNER,
+            write_protected: WriteProtected::none(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Creates a copy of this page table, allocating new tables from the memory pool. The copy has
+    /// the same mappings with the same attributes.
+    pub fn clone_into(&self, mpool: &MPool) -> Result<Self, MmError> {
+        let mut table = Self::new(mpool)?;
+        let level = S::max_level();
+
+        let (tables, pool) = table.deref_mut_pool(mpool);
+        let result = tables
+            .iter_mut()
+            .zip(self.deref().iter())
+            .map(|(dst, src)| dst.clone_level(src, level, &pool))
+            .collect::<Result<(), MmError>>();
+
+        if let Err(e) = result {
+            table.drop(mpool);
+            return Err(e);
+        }
+
+        Ok(table)
+    }
+
+    /// Sets the VM owning the memory mapped by the page table, so that the frame table is updated
+    /// along with the page table from now on.
+    pub fn set_owner(&mut self, owner: u32) {
+        self.owner = owner;
+    }
+
+    /// Returns the VM owning the memory mapped by the page table, or `frame::NO_OWNER`.
+    pub fn owner(&self) -> u32 {
+        self.owner
+    }
+
+    /// Registers the bitmap of the pages of `[begin, end)` write-protected by dirty page tracking,
+    /// whose pages the page table owns until `take_write_protected()` is called. The bit of a page
+    /// is cleared whenever the page is remapped, as it no longer has the mode it was protected
+    /// with. Gives the bitmap back if it has fewer bits than the range has pages, or another one
+    /// is registered.
+    pub fn set_write_protected(
+        &mut self,
+        begin: S::Addr,
+        end: S::Addr,
+        mut bitmap: Pages,
+    ) -> Result<(), Pages> {
+        let pages = (end.addr() - begin.addr()) / PAGE_SIZE;
+        if !self.write_protected.bitmap.is_null() || bitmap.len() * PAGE_SIZE * 8 < pages {
+            return Err(bitmap);
+        }
+
+        bitmap.clear();
+        self.write_protected = WriteProtected {
+            begin: begin.addr(),
+            end: end.addr(),
+            bitmap_pages: bitmap.len(),
+            bitmap: bitmap.into_raw(),
+        };
+        Ok(())
+    }
+
+    /// Unregisters the bitmap of the write-protected pages, and returns its pages to be freed.
+    pub fn take_write_protected(&mut self) -> Option<Pages> {
+        self.write_protected.take()
+    }
+
+    /// Returns whether the page at the given address is registered as write-protected.
+    pub fn is_write_protected(&self, page: S::Addr) -> bool {
+        self.write_protected.get(page.addr())
+    }
+
+    /// Records whether the page at the given address is write-protected, if it is in the range of
+    /// the registered bitmap.
+    pub fn mark_write_protected(&mut self, page: S::Addr, protected: bool) {
+        self.write_protected.set(page.addr(), protected);
+    }
+
+    /// Records that `[begin, end)` is now mapped to the physical address range starting at `pa`
+    /// with the given attributes, in the frame table and for dirty page tracking.
+    fn record_update(&mut self, begin: usize, end: usize, pa: usize, attrs: usize) {
+        S::update_owners(
+            self.owner,
+            PhysAddr::new(pa),
+            PhysAddr::new(pa + (end - begin)),
+            attrs,
+        );
+        self.write_protected.forget(begin, end);
+    }
+
+    /// Frees all memory associated with the give page table.
+    pub fn drop(mut self, mpool: &MPool) {
+        if let Some(bitmap) = self.write_protected.take() {
+            mpool.free_pages(bitmap);
+        }
+
+        let level = S::max_level();
+        let (tables, pool) = self.deref_mut_pool(mpool);
+
+        for page_table in tables.iter_mut() {
+            for pte in page_table.iter_mut() {
+                unsafe {
+                    pte.free(level, &pool);
+                }
+            }
+        }
+
+        mpool.free_pages(unsafe {
+            Pages::from_raw(self.root as *mut _, S::root_table_count() as usize)
+        });
+        mem::forget(self);
+    }
+
+    fn get_raw(&self) -> *const RawPage {
+        self.root as *const RawPage
+    }
+
+    fn deref(&self) -> &[RawPageTable] {
+        unsafe {
+            slice::from_raw_parts(
+                self.root as *const RawPageTable,
+                S::root_table_count() as usize,
+            )
+        }
+    }
+
+    fn deref_mut(&mut self) -> &mut [RawPageTable] {
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.root as *mut RawPageTable,
+                S::root_table_count() as usize,
+            )
+        }
+    }
+
+    /// Returns the root tables, and the memory pool to allocate their subtables from.
+    fn deref_mut_pool<'a>(
+        &'a mut self,
+        mpool: &'a MPool,
+    ) -> (&'a mut [RawPageTable], TablePool<'a>) {
+        let tables = unsafe {
+            slice::from_raw_parts_mut(
+                self.root as *mut RawPageTable,
+                S::root_table_count() as usize,
+            )
+        };
+
+        (
+            tables,
+            TablePool {
+                mpool,
+                stats: &self.stats,
+                batch: None,
+            },
+        )
+    }
+
+    /// Returns the memory accounting of the page table.
+    pub fn stats(&self) -> PageTableStats {
+        self.stats.get()
+    }
+
+    /// Marks the beginning of an update, so that concurrent `PageTableView` readers retry.
+    fn write_begin(&self) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+    }
+
+    /// Marks the end of an update.
+    fn write_end(&self) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Release);
+    }
+
+    /// Updates the page table from the root to map the given address range to a physical range
+    /// starting at `pa` using the provided (architecture-specific) attributes. Or if MM_FLAG_UNMAP
+    /// is set, unmap the given range instead.
+    fn map_root(
+        &mut self,
+        begin: usize,
         end: usize,
+        pa: usize,
         attrs: usize,
+        root_level: u8,
         flags: Flags,
         mpool: &MPool,
-    ) -> Option<()> {
+    ) -> Result<(), MmError> {
+        let root_table_size = addr::entry_size(root_level);
+        self.write_begin();
+
+        // The break-before-make sequences of the commit pass are batched, so that the TLB is
+        // invalidated once for all of them.
+        let batch = RefCell::new(TlbBatch::new());
+        let (tables, pool) = self.deref_mut_pool(mpool);
+        let pool = if !(flags & Flags::COMMIT).is_empty() {
+            pool.with_batch(&batch)
+        } else {
+            pool
+        };
+        let tables = tables[addr::index(begin, root_level)..].iter_mut();
+        let va_begin = begin;
+        let begins = BlockIter::new(begin, end, root_table_size);
+
+        let result = tables
+            .zip(begins)
+            .map(|(table, begin)| {
+                let pa = pa + (begin - va_begin);
+                table.map_level::<S>(begin, end, pa, attrs, root_level - 1, flags, &pool)
+            })
+            .collect::<Result<(), MmError>>();
+
+        batch.borrow_mut().flush::<S>(&pool);
+        self.write_end();
+        result
+    }
+
+    /// Aligns the given address range to pages, and caps its end to the end of the page table's
+    /// address space.
+    fn clamp_range(begin: usize, end: usize) -> (usize, usize) {
         let root_level = S::max_level() + 1;
         let ptable_end = S::root_table_count() as usize * addr::entry_size(root_level);
         let end = cmp::min(addr::round_up_to_page(end), ptable_end);
         let begin = unsafe { arch_mm_clear_pa(begin) };
+        (begin, end)
+    }
+
+    /// Frees the internal tables that a failed update of the given range allocated speculatively,
+    /// by defragmenting the entries of the maximum level overlapping with the range. The mapping
+    /// is not changed.
+    fn rollback(&mut self, begin: usize, end: usize, mpool: &MPool) {
+        let level = S::max_level();
+        let root_level = level + 1;
+        self.write_begin();
+
+        let (tables, pool) = self.deref_mut_pool(mpool);
+        let tables = tables[addr::index(begin, root_level)..].iter_mut();
+        let begins = BlockIter::new(begin, end, addr::entry_size(root_level));
+
+        for (table, begin) in tables.zip(begins) {
+            let ptes = table[addr::index(begin, level)..].iter_mut();
+            let begins = BlockIter::new(
+                begin,
+                cmp::min(end, addr::level_end(begin, level)),
+                addr::entry_size(level),
+            );
+
+            for (pte, _) in ptes.zip(begins) {
+                pte.defrag(level, &pool);
+            }
+        }
+
+        self.write_end();
+    }
+
+    /// Updates the given table such that the given address range is mapped or not mapped to the
+    /// physical address range starting at `pa` with the architecture-specific attributes provided.
+    fn update(
+        &mut self,
+        begin: usize,
+        end: usize,
+        pa: usize,
+        attrs: usize,
+        flags: Flags,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let root_level = S::max_level() + 1;
+        let (begin, end) = Self::clamp_range(begin, end);
+        let pa = unsafe { arch_mm_clear_pa(pa) };
+
+        // Revoking access may use the emergency reserve of the memory pool.
+        let revoked =
+            !(flags & Flags::UNMAP).is_empty() || S::attrs_to_mode(attrs).contains(Mode::INVALID);
+        let flags = if revoked {
+            flags | Flags::RESERVE
+        } else {
+            flags
+        };
+
+        // Do it in two steps to prevent leaving the table in a halfway updated state. The first
+        // step only allocates internal tables, which are freed on failure.
+        if let Err(e) = self.map_root(begin, end, pa, attrs, root_level, flags, mpool) {
+            self.rollback(begin, end, mpool);
+            return Err(e);
+        }
+
+        if !(flags & Flags::SCRUB).is_empty() && revoked {
+            let result = self.commit_scrub(begin, end, pa, attrs, flags, mpool);
+            self.record_update(begin, end, pa, attrs);
+            return result;
+        }
+
+        self.map_root(
+            begin,
+            end,
+            pa,
+            attrs,
+            root_level,
+            flags | Flags::COMMIT,
+            mpool,
+        )?;
+
+        // Invalidate the tlb.
+        Self::invalidate_range(begin, end);
+
+        self.record_update(begin, end, pa, attrs);
+        Ok(())
+    }
+
+    /// Invalidates the TLB for the given updated range, or for the whole address space if the
+    /// range is larger than `INVALIDATE_VM_THRESHOLD`.
+    fn invalidate_range(begin: usize, end: usize) {
+        if end - begin > INVALIDATE_VM_THRESHOLD {
+            S::invalidate_vm();
+        } else {
+            S::invalidate_tlb(begin, end);
+        }
+    }
+
+    /// Commits an update of `[begin, end)` that revokes the VM's access to it, segment by segment
+    /// so that the memory of each segment the VM had exclusive access to is zeroed as soon as the
+    /// TLB no longer allows the access. The tables should already be populated.
+    ///
+    /// The update is committed even if scrubbing fails, in which case the error is returned and
+    /// the memory must not be given to another VM. It must not be used for the hypervisor's page
+    /// table, as the memory is scrubbed through temporary mappings in it.
+    fn commit_scrub(
+        &mut self,
+        begin: usize,
+        end: usize,
+        pa: usize,
+        attrs: usize,
+        flags: Flags,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let root_level = S::max_level() + 1;
+        let flags = flags | Flags::COMMIT | Flags::NO_PROMOTE;
+        let mut result = Ok(());
+        let mut cur = begin;
+
+        while cur < end {
+            let segment = self
+                .get_modes(S::Addr::new(cur), S::Addr::new(end))?
+                .next()
+                .unwrap();
+            let mut segment_end = segment.end.addr();
+
+            // The old physical address is only known block by block, so cap the segment to the
+            // block if it is to be scrubbed.
+            let old_pa = if segment.mode.needs_scrub() {
+                self.lookup(segment.begin).map(|block| {
+                    segment_end = cmp::min(segment_end, block.end.addr());
+                    block.pa + (cur - block.begin.addr())
+                })
+            } else {
+                None
+            };
+
+            self.map_root(
+                cur,
+                segment_end,
+                pa + (cur - begin),
+                attrs,
+                root_level,
+                flags,
+                mpool,
+            )?;
+            S::invalidate_tlb(cur, segment_end);
+
+            if let Some(old_pa) = old_pa {
+                result = result.and_then(|_| scrub(old_pa, segment_end - cur, mpool));
+            }
+
+            cur = segment_end;
+        }
+
+        result
+    }
+
+    /// Updates the given table such that the given physical address range is mapped or not mapped
+    /// into the address space with the architecture-agnostic mode provided.
+    fn identity_update(
+        &mut self,
+        begin: usize,
+        end: usize,
+        attrs: usize,
+        flags: Flags,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let result = self.update(begin, end, begin, attrs, flags, mpool);
+
+        if cfg!(all(debug_assertions, feature = "test")) {
+            self.check_invariants(mpool);
+        }
+
+        result
+    }
+
+    /// Checks the structural invariants of the page table, and panics if any is violated:
+    ///
+    ///  - Tables are referenced only above level 0, and blocks only at the levels that allow them.
+    ///  - The table entries of the concatenated root tables have the same attributes.
+    ///  - No table is free in `mpool`, and the number of tables matches the page table's
+    ///    statistics.
+    ///
+    /// It walks the whole page table and the free lists of `mpool`, so it is meant for debugging.
+    pub fn check_invariants(&self, mpool: &MPool) {
+        let max_level = S::max_level();
+        let tables = self.deref();
+
+        let mut root_attrs = tables
+            .iter()
+            .flat_map(|table| table.iter())
+            .filter(|pte| pte.is_table(max_level))
+            .map(|pte| pte.attrs(max_level));
+        if let Some(attrs) = root_attrs.next() {
+            assert!(
+                root_attrs.all(|other| other == attrs),
+                "table entries of the root tables have different attributes"
+            );
+        }
+
+        let count = tables.len()
+            + tables
+                .iter()
+                .map(|table| table.check_level(max_level, mpool))
+                .sum::<usize>();
+        assert_eq!(
+            count,
+            self.stats.get().footprint(),
+            "number of tables does not match the statistics"
+        );
+    }
+
+    /// Writes the given table to the debug log.
+    pub fn dump(&self) {
+        let max_level = S::max_level();
+
+        for table in self.deref().iter() {
+            table.dump(max_level, max_level);
+        }
+    }
+
+    /// Defragments the given page table by converting page table references to blocks whenever
+    /// possible.
+    pub fn defrag(&mut self, mpool: &MPool) {
+        let mut cursor = DefragCursor::new();
+        while !self.defrag_step(&mut cursor, usize::max_value(), mpool) {}
+    }
+
+    /// Defragments at most `count` entries of the root tables, starting from the cursor, and
+    /// advances the cursor. Returns true if the whole page table is defragmented, in which case the
+    /// cursor is reset.
+    ///
+    /// It lets the caller interleave defragmentation of a large page table with other work, e.g.,
+    /// by releasing the page table lock between the steps.
+    pub fn defrag_step(&mut self, cursor: &mut DefragCursor, count: usize, mpool: &MPool) -> bool {
+        let level = S::max_level();
+        let total = S::root_table_count() as usize * PTE_PER_PAGE;
+        self.write_begin();
+
+        // Loop through each entry in the table. If it points to another table, check if that table
+        // can be replaced by a block or an absent entry.
+        let (tables, pool) = self.deref_mut_pool(mpool);
+        let ptes = tables
+            .iter_mut()
+            .flat_map(|page_table| page_table.iter_mut())
+            .skip(cursor.index)
+            .take(count);
+
+        for pte in ptes {
+            pte.defrag(level, &pool);
+            cursor.index += 1;
+        }
+
+        self.write_end();
+
+        if cursor.index >= total {
+            cursor.index = 0;
+            return true;
+        }
+
+        false
+    }
+
+    /// Updates the table such that the given physical address range is mapped 1-1 with the given
+    /// mode. Fails with `MmError::WriteExecute` if the mode violates the W^X policy.
+    pub fn identity_map(
+        &mut self,
+        begin: PhysAddr,
+        end: PhysAddr,
+        mode: Mode,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let mode = S::wx_mode(begin, end, mode)?;
+        self.identity_update(
+            begin.addr(),
+            end.addr(),
+            S::mode_to_attrs(mode),
+            Flags::empty(),
+            mpool,
+        )
+    }
+
+    /// Updates the table such that the address range `[va_begin, va_end)` is translated to the
+    /// physical address range starting at `pa_begin` with the given mode. Fails with
+    /// `MmError::WriteExecute` if the mode violates the W^X policy.
+    pub fn map(
+        &mut self,
+        va_begin: S::Addr,
+        va_end: S::Addr,
+        pa_begin: PhysAddr,
+        mode: Mode,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let mode = S::wx_mode(pa_begin, pa_begin + (va_end - va_begin), mode)?;
+        self.update(
+            va_begin.addr(),
+            va_end.addr(),
+            pa_begin.addr(),
+            S::mode_to_attrs(mode),
+            Flags::empty(),
+            mpool,
+        )
+    }
+
+    /// Updates the table such that the i-th page of the address range starting at `va_begin` is
+    /// translated to the physical page `pages[i]` with the given mode. Runs of physically
+    /// contiguous pages are mapped together, so that they can be mapped with blocks.
+    ///
+    /// As with the other updates, the tables are allocated for all the pages before any mapping
+    /// is changed, so the mapping is not changed on failure. Fails with `MmError::WriteExecute` if
+    /// the mode violates the W^X policy for any of the pages.
+    pub fn map_scatter(
+        &mut self,
+        va_begin: S::Addr,
+        pages: &[PhysAddr],
+        mode: Mode,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let root_level = S::max_level() + 1;
+        let mode = pages.iter().try_fold(mode, |mode, &pa| {
+            S::wx_mode(pa, pa + PAGE_SIZE, mode)
+        })?;
+        let attrs = S::mode_to_attrs(mode);
+        let va_begin = va_begin.addr();
+        let (begin, end) = Self::clamp_range(va_begin, va_begin + pages.len() * PAGE_SIZE);
+
+        // Calls `f` for each run of physically contiguous pages, with its address range and the
+        // physical address it is mapped to.
+        let for_each_run = |f: &mut dyn FnMut(usize, usize, usize) -> Result<(), MmError>| {
+            let mut i = 0;
+
+            while i < pages.len() {
+                let pa = unsafe { arch_mm_clear_pa(pages[i].addr()) };
+                let mut count = 1;
+                while i + count < pages.len() && pages[i + count].addr() == pa + count * PAGE_SIZE {
+                    count += 1;
+                }
+
+                let (run_begin, run_end) =
+                    Self::clamp_range(va_begin + i * PAGE_SIZE, va_begin + (i + count) * PAGE_SIZE);
+                if run_begin < run_end {
+                    f(run_begin, run_end, pa)?;
+                }
+
+                i += count;
+            }
+
+            Ok(())
+        };
+
+        // Allocate the tables for all the runs first. Subtables are not promoted to blocks while
+        // committing, as a block may cover the following runs and need to be split again.
+        if let Err(e) = for_each_run(&mut |run_begin, run_end, pa| {
+            self.map_root(
+                run_begin,
+                run_end,
+                pa,
+                attrs,
+                root_level,
+                Flags::empty(),
+                mpool,
+            )
+        }) {
+            self.rollback(begin, end, mpool);
+            return Err(e);
+        }
+
+        for_each_run(&mut |run_begin, run_end, pa| {
+            self.map_root(
+                run_begin,
+                run_end,
+                pa,
+                attrs,
+                root_level,
+                Flags::COMMIT | Flags::NO_PROMOTE,
+                mpool,
+            )?;
+            self.record_update(run_begin, run_end, pa, attrs);
+            Ok(())
+        })?;
+
+        // Invalidate the tlb.
+        Self::invalidate_range(begin, end);
+
+        Ok(())
+    }
+
+    /// nUpdates the VM's table such that the given physical address range has no connection to the
+    /// VM.
+    pub fn unmap(&mut self, begin: PhysAddr, end: PhysAddr, mpool: &MPool) -> Result<(), MmError> {
+        self.identity_update(
+            begin.addr(),
+            end.addr(),
+            S::mode_to_attrs(Mode::UNOWNED | Mode::INVALID | Mode::SHARED),
+            Flags::UNMAP,
+            mpool,
+        )
+    }
+
+    /// Gets the attributes applies to the given range of addresses in the stage-2 table.
+    ///
+    /// Fails with `MmError::NonUniform` if the whole range does not have the same attributes.
+    pub fn get_attrs(&self, begin: S::Addr, end: S::Addr) -> Result<usize, MmError> {
+        let max_level = S::max_level();
+        let root_level = max_level + 1;
+        let root_table_size = addr::entry_size(root_level);
+        let ptable_end = S::root_table_count() as usize * root_table_size;
+
+        let begin = addr::round_down_to_page(begin).addr();
+        let end = addr::round_up_to_page(end).addr();
+
+        // Fail if the addresses are out of range.
+        if !(begin <= end && end <= ptable_end) {
+            return Err(MmError::OutOfRange);
+        }
+
+        let tables = self.deref()[addr::index(begin, root_level)..].iter();
+        let begins = BlockIter::new(begin, end, root_table_size);
+
+        tables
+            .zip(begins)
+            .map(|(table, begin)| table.get_attrs_level(begin, end, max_level))
+            .opt_reduce(|l, r| if l == r { Some(l) } else { None })
+            .ok_or(MmError::NonUniform)
+    }
+
+    /// Gets the mode of the give range of intermediate physical addresses if they are mapped with
+    /// the same mode.
+    pub fn get_mode(&self, begin: S::Addr, end: S::Addr) -> Result<Mode, MmError> {
+        let attrs = self.get_attrs(begin, end)?;
+        Ok(S::attrs_to_mode(attrs))
+    }
+
+    /// Returns an iterator over all the present blocks of the page table.
+    pub fn blocks(&self) -> BlockWalker<S> {
+        BlockWalker {
+            ptable: self,
+            root_index: 0,
+            stack: ArrayVec::new(),
+        }
+    }
+
+    /// Returns the number of pages that are mapped valid in the page table.
+    pub fn mapped_pages(&self) -> usize {
+        self.blocks()
+            .filter(|block| !S::attrs_to_mode(block.attrs).contains(Mode::INVALID))
+            .map(|block| (block.end - block.begin) / PAGE_SIZE)
+            .sum()
+    }
+
+    /// Returns an iterator over the maximal subranges of the given range that are mapped with the
+    /// same mode. Unlike `get_mode()`, it doesn't fail if the range has mixed modes.
+    pub fn get_modes(&self, begin: S::Addr, end: S::Addr) -> Result<ModeIter<S>, MmError> {
+        let root_level = S::max_level() + 1;
+        let ptable_end = S::root_table_count() as usize * addr::entry_size(root_level);
+
+        let begin = addr::round_down_to_page(begin).addr();
+        let end = addr::round_up_to_page(end).addr();
+
+        // Fail if the addresses are out of range.
+        if !(begin <= end && end <= ptable_end) {
+            return Err(MmError::OutOfRange);
+        }
+
+        Ok(ModeIter {
+            ptable: self,
+            begin,
+            end,
+        })
+    }
+
+    /// Gets the mode of the non-table entry containing the given address, and the end of the
+    /// entry. The address should be in range.
+    fn mode_at(&self, addr: usize) -> (Mode, usize) {
+        let (pte, level) = self.leaf(addr).unwrap();
+        let entry_size = addr::entry_size(level);
+        let end = (addr & !(entry_size - 1)) + entry_size;
+
+        (S::attrs_to_mode(pte.attrs_accessed(level)), end)
+    }
+
+    /// Looks up the block containing the given address. Returns `None` if the address is out of
+    /// range or not present in the page table.
+    pub fn lookup(&self, addr: S::Addr) -> Option<Block<S::Addr>> {
+        let (pte, level) = self.leaf(addr.addr())?;
+        let entry_size = addr::entry_size(level);
+        let begin = addr.addr() & !(entry_size - 1);
+
+        Some(Block {
+            begin: S::Addr::new(begin),
+            end: S::Addr::new(begin + entry_size),
+            pa: PhysAddr::new(pte.as_block(level)?),
+            attrs: pte.attrs(level),
+        })
+    }
+
+    /// Splits the block containing the given address into subtables of smaller entries with the
+    /// same attributes, until the address is in an entry of `target_level` or lower. The mapping
+    /// is not changed. Does nothing if the address is not present in the page table.
+    pub fn split_block(
+        &mut self,
+        addr: S::Addr,
+        target_level: u8,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let addr = addr.addr();
+        let mut level = S::max_level();
+        let root_level = level + 1;
+        let ptable_end = S::root_table_count() as usize * addr::entry_size(root_level);
+
+        if addr >= ptable_end {
+            return Err(MmError::OutOfRange);
+        }
+
+        self.write_begin();
+        let (tables, pool) = self.deref_mut_pool(mpool);
+        let mut table = &mut tables[addr::index(addr, root_level)];
+        let mut result = Ok(());
+
+        while level > target_level {
+            let pte = &mut table[addr::index(addr, level)];
+
+            if !pte.is_present(level) {
+                break;
+            }
+
+            let begin = addr & !(addr::entry_size(level) - 1);
+            if let Err(e) = pte.populate_table::<S>(begin, level, false, &pool) {
+                result = Err(e);
+                break;
+            }
+
+            table = pte.as_table_mut(level).unwrap();
+            level -= 1;
+        }
+
+        self.write_end();
+        result
+    }
+
+    /// Finds the non-table page table entry containing the given address, and its level.
+    fn leaf(&self, addr: usize) -> Option<(&PageTableEntry, u8)> {
+        let mut level = S::max_level();
+        let root_level = level + 1;
+        let ptable_end = S::root_table_count() as usize * addr::entry_size(root_level);
+
+        if addr >= ptable_end {
+            return None;
+        }
+
+        let mut table = &self.deref()[addr::index(addr, root_level)];
+
+        loop {
+            let pte = &table[addr::index(addr, level)];
+
+            match pte.as_table(level) {
+                Some(subtable) => table = subtable,
+                None => return Some((pte, level)),
+            }
+
+            level -= 1;
+        }
+    }
+
+    /// Finds the non-table page table entry containing the given address, and its level.
+    fn leaf_mut(&mut self, addr: usize) -> Option<(&mut PageTableEntry, u8)> {
+        let mut level = S::max_level();
+        let root_level = level + 1;
+        let ptable_end = S::root_table_count() as usize * addr::entry_size(root_level);
+
+        if addr >= ptable_end {
+            return None;
+        }
+
+        let mut table = &mut self.deref_mut()[addr::index(addr, root_level)];
+
+        loop {
+            let pte = &mut table[addr::index(addr, level)];
+
+            if !pte.is_table(level) {
+                return Some((pte, level));
+            }
+
+            table = pte.as_table_mut(level).unwrap();
+            level -= 1;
+        }
+    }
+}
+
+impl PageTable<Stage2> {
+    /// Updates the VM's table such that the given physical address range is mapped 1-1 with the
+    /// given mode, and zeroes the memory the VM had exclusive access to if the mode revokes the
+    /// access. See `commit_scrub()` for the failure after the mapping is updated.
+    pub fn identity_map_scrub(
+        &mut self,
+        begin: PhysAddr,
+        end: PhysAddr,
+        mode: Mode,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let mode = Stage2::wx_mode(begin, end, mode)?;
+        self.identity_update(
+            begin.addr(),
+            end.addr(),
+            Stage2::mode_to_attrs(mode),
+            Flags::SCRUB,
+            mpool,
+        )
+    }
+
+    /// Like `unmap()`, but also zeroes the memory the VM had exclusive access to. See
+    /// `commit_scrub()` for the failure after the mapping is updated.
+    pub fn unmap_scrub(
+        &mut self,
+        begin: PhysAddr,
+        end: PhysAddr,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        self.identity_update(
+            begin.addr(),
+            end.addr(),
+            Stage2::mode_to_attrs(Mode::UNOWNED | Mode::INVALID | Mode::SHARED),
+            Flags::UNMAP | Flags::SCRUB,
+            mpool,
+        )
+    }
+
+    /// Gives the memory the VM owns exclusively, and `to` has no access to, to `to`, zeroing it
+    /// first unless it is device memory, e.g. to return the memory and devices of a VM being
+    /// destroyed to the primary VM. Memory the VM lent or borrowed, or that the hypervisor owns, is
+    /// left as it is, as is memory `to` already has access to, e.g. the console.
+    pub fn reclaim_into(
+        &mut self,
+        to: &mut PageTable<Stage2>,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        // Blocks are unmapped as they are reclaimed, so the walk is restarted each time.
+        loop {
+            let block = self.blocks().find(|block| {
+                let mode = Stage2::attrs_to_mode(block.attrs);
+                !mode.intersects(Mode::INVALID | Mode::UNOWNED | Mode::SHARED)
+                    && to
+                        .get_mode(block.begin, block.end)
+                        .ok()
+                        .map_or(false, |mode| mode.contains(Mode::INVALID))
+            });
+            let block = match block {
+                Some(block) => block,
+                None => return Ok(()),
+            };
+            let begin = block.pa;
+            let end = block.pa + (block.end - block.begin);
+
+            self.unmap_scrub(begin, end, mpool)?;
+            to.identity_map(begin, end, Mode::R | Mode::W | Mode::X, mpool)?;
+        }
+    }
+
+    /// Clears the access flag of the blocks in the given range, so that `get_accessed()` later
+    /// reports which of them were accessed since. Blocks partially in the range are split.
+    pub fn clear_accessed(
+        &mut self,
+        begin: IpaAddr,
+        end: IpaAddr,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        let begin = addr::round_down_to_page(begin);
+        let end = addr::round_up_to_page(end);
+        let mut addr = begin;
+
+        while addr < end {
+            let block = match self.lookup(addr) {
+                Some(block) => block,
+                None => {
+                    addr += PAGE_SIZE;
+                    continue;
+                }
+            };
+
+            let next = cmp::min(end, block.end);
+            let attrs = unsafe { arch_mm_attrs_set_accessed(block.attrs, false) };
+
+            if attrs != block.attrs {
+                let pa = block.pa + (addr - block.begin);
+                self.update(
+                    addr.addr(),
+                    next.addr(),
+                    pa.addr(),
+                    attrs,
+                    Flags::empty(),
+                    mpool,
+                )?;
+            }
+
+            addr = next;
+        }
+
+        Ok(())
+    }
+
+    /// Reports which pages in the given range were accessed since `clear_accessed()`.
+    ///
+    /// The i-th bit of `bitmap` is set if the i-th page of the range was accessed, and cleared
+    /// otherwise. Accesses are tracked per block, so all pages of an accessed block are reported.
+    /// Returns the number of accessed pages.
+    pub fn get_accessed(
+        &self,
+        begin: IpaAddr,
+        end: IpaAddr,
+        bitmap: &mut [u8],
+    ) -> Result<usize, MmError> {
+        let begin = addr::round_down_to_page(begin).addr();
+        let end = addr::round_up_to_page(end).addr();
+        if begin > end || bitmap.len() * 8 < (end - begin) / PAGE_SIZE {
+            return Err(MmError::OutOfRange);
+        }
+
+        let mut count = 0;
+
+        for (i, page) in (begin..end).step_by(PAGE_SIZE).enumerate() {
+            let accessed = self
+                .lookup(IpaAddr::new(page))
+                .map(|block| {
+                    !Stage2::attrs_to_mode(block.attrs).contains(Mode::INVALID)
+                        && unsafe { arch_mm_attrs_accessed(block.attrs) }
+                })
+                .unwrap_or(false);
+
+            if accessed {
+                bitmap[i / 8] |= 1 << (i % 8);
+                count += 1;
+            } else {
+                bitmap[i / 8] &= !(1 << (i % 8));
+            }
+        }
+
+        Ok(count)
+    }
 
-        // Do it in two steps to prevent leaving the table in a halfway updated state. In such a
-        // two-step implementation, the table may be left with extra internal tables, but no
-        // different mapping on failure.
-        self.map_root(begin, end, attrs, root_level, flags, mpool)?;
-        self.map_root(begin, end, attrs, root_level, flags | Flags::COMMIT, mpool)?;
+    /// Sets the access flag of the block containing the given address. It is called on an access
+    /// flag fault. Returns false if the address is not mapped.
+    pub fn mark_accessed(&mut self, addr: IpaAddr) -> bool {
+        let (pte, level) = some_or_return!(self.leaf_mut(addr.addr()), false);
+        let pa = some_or_return!(pte.as_block(level), false);
+        let attrs = pte.attrs(level);
 
-        // Invalidate the tlb.
-        S::invalidate_tlb(begin, end);
+        // Setting the access flag doesn't require break-before-make.
+        unsafe {
+            ptr::write(
+                pte,
+                PageTableEntry::block(level, pa, arch_mm_attrs_set_accessed(attrs, true)),
+            );
+        }
 
-        Some(())
+        true
     }
+}
 
-    /// Writes the given table to the debug log.
-    pub fn dump(&self) {
-        let max_level = S::max_level();
+/// A read-only view of a page table that doesn't need exclusive access to the page table. It can
+/// be used concurrently with updates of the page table, in which case the reads are retried.
+///
+/// Tables may be freed by a concurrent update while being read. Pages in the memory pool stay
+/// mapped in the hypervisor, so reading them is harmless; but a page table entry read from them is
+/// followed only after validating that no update happened in the meantime.
+pub struct PageTableView<'a, S: Stage> {
+    ptable: *const PageTable<S>,
+    _marker: PhantomData<&'a PageTable<S>>,
+}
 
-        for table in self.deref().iter() {
-            table.dump(max_level, max_level);
+impl<'a, S: Stage> PageTableView<'a, S> {
+    /// Creates a view of the given page table.
+    ///
+    /// # Safety
+    ///
+    /// The page table should not be dropped while the view is used.
+    pub unsafe fn new(ptable: *const PageTable<S>) -> Self {
+        Self {
+            ptable,
+            _marker: PhantomData,
         }
     }
 
-    /// Defragments the given page table by converting page table references to blocks whenever
-    /// possible.
-    pub fn defrag(&mut self, mpool: &MPool) {
-        let level = S::max_level();
+    /// Waits for a concurrent update to finish, and returns the sequence number to validate the
+    /// reads with.
+    fn read_begin(&self) -> usize {
+        let seq = unsafe { &(*self.ptable).seq };
 
-        // Loop through each entry in the table. If it points to another table, check if that table
-        // can be replaced by a block or an absent entry.
-        for page_table in self.deref_mut().iter_mut() {
-            for pte in page_table.iter_mut() {
-                pte.defrag(level, mpool);
+        loop {
+            let s = seq.load(Ordering::Acquire);
+            if s & 1 == 0 {
+                return s;
             }
+            spin_loop_hint();
         }
     }
 
-    pub fn identity_map(
+    /// Returns whether no update happened since `read_begin()` returned the given sequence number.
+    fn read_validate(&self, s: usize) -> bool {
+        fence(Ordering::Acquire);
+        unsafe { (*self.ptable).seq.load(Ordering::Relaxed) == s }
+    }
+
+    /// Reads the non-table entry containing the given address and its level. Returns `None` if an
+    /// update is detected. The address should be in range.
+    fn leaf(&self, addr: usize, s: usize) -> Option<(usize, u8)> {
+        let mut level = S::max_level();
+        let root_level = level + 1;
+        let root = unsafe { (*self.ptable).root };
+        let mut table = (root + addr::index(addr, root_level) * PAGE_SIZE) as *const usize;
+
+        loop {
+            let pte = unsafe { ptr::read_volatile(table.add(addr::index(addr, level))) };
+
+            if unsafe { !arch_mm_pte_is_table(pte, level) } {
+                return Some((pte, level));
+            }
+
+            // The table may be freed if an update happened.
+            if !self.read_validate(s) {
+                return None;
+            }
+
+            table = unsafe { arch_mm_table_from_pte(pte, level) } as *const usize;
+            level -= 1;
+        }
+    }
+
+    /// Reads the attributes of the given range once. Returns `None` if an update is detected.
+    fn get_attrs_once(&self, begin: usize, end: usize, s: usize) -> Option<Result<usize, MmError>> {
+        let mut attrs = None;
+        let mut addr = begin;
+
+        while addr < end {
+            let (pte, level) = self.leaf(addr, s)?;
+            let entry_size = addr::entry_size(level);
+
+            // The access flag is not a part of the mapping's mode. See
+            // `PageTableEntry::attrs_accessed()`.
+            let pte_attrs = unsafe {
+                let pte_attrs = arch_mm_pte_attrs(pte, level);
+                if arch_mm_pte_is_block(pte, level) {
+                    arch_mm_attrs_set_accessed(pte_attrs, true)
+                } else {
+                    pte_attrs
+                }
+            };
+
+            if attrs.map_or(false, |attrs| attrs != pte_attrs) {
+                return Some(Err(MmError::NonUniform));
+            }
+
+            attrs = Some(pte_attrs);
+            addr = (addr & !(entry_size - 1)) + entry_size;
+        }
+
+        Some(attrs.ok_or(MmError::NonUniform))
+    }
+
+    /// Gets the mode of the given range if it is mapped with the same mode. See
+    /// `PageTable::get_mode()`.
+    pub fn get_mode(&self, begin: S::Addr, end: S::Addr) -> Result<Mode, MmError> {
+        let root_level = S::max_level() + 1;
+        let ptable_end = S::root_table_count() as usize * addr::entry_size(root_level);
+
+        let begin = addr::round_down_to_page(begin).addr();
+        let end = addr::round_up_to_page(end).addr();
+
+        // Fail if the addresses are out of range.
+        if !(begin <= end && end <= ptable_end) {
+            return Err(MmError::OutOfRange);
+        }
+
+        loop {
+            let s = self.read_begin();
+
+            if let Some(result) = self.get_attrs_once(begin, end, s) {
+                if self.read_validate(s) {
+                    return result.map(S::attrs_to_mode);
+                }
+            }
+        }
+    }
+}
+
+/// Maximum number of ranges in a `MapTransaction`.
+pub const MAP_TRANSACTION_MAX_RANGES: usize = 8;
+
+/// An update of a single range in a `MapTransaction`.
+#[derive(Clone, Copy)]
+struct MapOp {
+    begin: usize,
+    end: usize,
+    pa: usize,
+    attrs: usize,
+    flags: Flags,
+}
+
+/// A set of updates of disjoint ranges in a page table that are applied all together or not at
+/// all.
+///
+/// `prepare()` allocates all the tables needed by the updates without changing any mapping, and
+/// the returned `PreparedTransaction` applies them without allocating. To update several page
+/// tables consistently, prepare a transaction for each of them first, and then commit them.
+pub struct MapTransaction<'a, S: Stage> {
+    ptable: &'a mut PageTable<S>,
+    ops: ArrayVec<[MapOp; MAP_TRANSACTION_MAX_RANGES]>,
+}
+
+impl<'a, S: Stage> MapTransaction<'a, S> {
+    pub fn new(ptable: &'a mut PageTable<S>) -> Self {
+        Self {
+            ptable,
+            ops: ArrayVec::new(),
+        }
+    }
+
+    fn push(
         &mut self,
         begin: usize,
         end: usize,
+        pa: usize,
+        attrs: usize,
+        flags: Flags,
+    ) -> Result<(), MmError> {
+        let (begin, end) = PageTable::<S>::clamp_range(begin, end);
+        let pa = unsafe { arch_mm_clear_pa(pa) };
+
+        if begin >= end {
+            return Ok(());
+        }
+
+        if self.ops.iter().any(|op| op.begin < end && begin < op.end) {
+            return Err(MmError::Overlap);
+        }
+
+        self.ops
+            .try_push(MapOp {
+                begin,
+                end,
+                pa,
+                attrs,
+                flags,
+            })
+            .map_err(|_| MmError::TooManyRanges)
+    }
+
+    /// Adds an update that translates `[va_begin, va_end)` to the physical address range starting
+    /// at `pa_begin` with the given mode. Fails with `MmError::WriteExecute` if the mode violates
+    /// the W^X policy.
+    pub fn map(
+        &mut self,
+        va_begin: S::Addr,
+        va_end: S::Addr,
+        pa_begin: PhysAddr,
         mode: Mode,
-        mpool: &MPool,
-    ) -> Option<()> {
-        self.identity_update(begin, end, S::mode_to_attrs(mode), Flags::empty(), mpool)
+    ) -> Result<(), MmError> {
+        let mode = S::wx_mode(pa_begin, pa_begin + (va_end - va_begin), mode)?;
+        self.push(
+            va_begin.addr(),
+            va_end.addr(),
+            pa_begin.addr(),
+            S::mode_to_attrs(mode),
+            Flags::empty(),
+        )
     }
 
-    /// nUpdates the VM's table such that the given physical address range has no connection to the
-    /// VM.
-    pub fn unmap(&mut self, begin: usize, end: usize, mpool: &MPool) -> Option<()> {
-        self.identity_update(
-            begin,
-            end,
+    /// Adds an update that maps the given physical address range 1-1 with the given mode. Fails
+    /// with `MmError::WriteExecute` if the mode violates the W^X policy.
+    pub fn identity_map(
+        &mut self,
+        begin: PhysAddr,
+        end: PhysAddr,
+        mode: Mode,
+    ) -> Result<(), MmError> {
+        let mode = S::wx_mode(begin, end, mode)?;
+        self.push(
+            begin.addr(),
+            end.addr(),
+            begin.addr(),
+            S::mode_to_attrs(mode),
+            Flags::empty(),
+        )
+    }
+
+    /// Adds an update that unmaps the given physical address range.
+    pub fn unmap(&mut self, begin: PhysAddr, end: PhysAddr) -> Result<(), MmError> {
+        self.push(
+            begin.addr(),
+            end.addr(),
+            begin.addr(),
             S::mode_to_attrs(Mode::UNOWNED | Mode::INVALID | Mode::SHARED),
             Flags::UNMAP,
-            mpool,
         )
     }
 
-    /// Gets the attributes applies to the given range of addresses in the stage-2 table.
-    ///
-    /// The value returned in `attrs` is only valid if the function returns true.
-    ///
-    /// Returns true if the whole range has the same attributes and false otherwise.
-    pub fn get_attrs(&self, begin: usize, end: usize) -> Option<usize> {
-        let max_level = S::max_level();
-        let root_level = max_level + 1;
-        let root_table_size = addr::entry_size(root_level);
-        let ptable_end = S::root_table_count() as usize * root_table_size;
+    /// Allocates the tables needed by all the updates. On failure, no mapping is changed and the
+    /// allocated tables are freed.
+    pub fn prepare(self, mpool: &MPool) -> Result<PreparedTransaction<'a, S>, MmError> {
+        let root_level = S::max_level() + 1;
 
-        let begin = addr::round_down_to_page(begin);
-        let end = addr::round_up_to_page(end);
+        for (i, op) in self.ops.iter().enumerate() {
+            let result = self.ptable.map_root(
+                op.begin, op.end, op.pa, op.attrs, root_level, op.flags, mpool,
+            );
 
-        // Fail if the addresses are out of range.
-        if !(begin <= end && end <= ptable_end) {
-            return None;
+            if let Err(e) = result {
+                for op in self.ops[..=i].iter() {
+                    self.ptable.rollback(op.begin, op.end, mpool);
+                }
+                return Err(e);
+            }
         }
 
-        let tables = self.deref()[addr::index(begin, root_level)..].iter();
-        let begins = BlockIter::new(begin, end, root_table_size);
+        Ok(PreparedTransaction {
+            ptable: self.ptable,
+            ops: self.ops,
+        })
+    }
+}
 
-        tables
-            .zip(begins)
-            .map(|(table, begin)| table.get_attrs_level(begin, end, max_level))
-            .opt_reduce(|l, r| if l == r { Some(l) } else { None })
+/// A `MapTransaction` whose tables are all allocated.
+pub struct PreparedTransaction<'a, S: Stage> {
+    ptable: &'a mut PageTable<S>,
+    ops: ArrayVec<[MapOp; MAP_TRANSACTION_MAX_RANGES]>,
+}
+
+impl<'a, S: Stage> PreparedTransaction<'a, S> {
+    /// Applies all the updates.
+    pub fn commit(self, mpool: &MPool) {
+        let root_level = S::max_level() + 1;
+
+        // Maps are committed before unmaps. Committing an unmap may free a subtable that became
+        // empty, which might have been prepared for a map of another range that is not committed
+        // yet; but it never frees a subtable with present entries. Unmapping doesn't need a
+        // subtable for a range that is already absent. For the same reason, subtables are not
+        // promoted to blocks while committing.
+        let maps = self
+            .ops
+            .iter()
+            .filter(|op| !op.flags.contains(Flags::UNMAP));
+        let unmaps = self.ops.iter().filter(|op| op.flags.contains(Flags::UNMAP));
+
+        for op in maps.chain(unmaps) {
+            self.ptable
+                .map_root(
+                    op.begin,
+                    op.end,
+                    op.pa,
+                    op.attrs,
+                    root_level,
+                    op.flags | Flags::COMMIT | Flags::NO_PROMOTE,
+                    mpool,
+                )
+                .expect("PreparedTransaction::commit: tables should have been prepared");
+            self.ptable.record_update(op.begin, op.end, op.pa, op.attrs);
+        }
+
+        let size = self.ops.iter().map(|op| op.end - op.begin).sum::<usize>();
+        if size > INVALIDATE_VM_THRESHOLD {
+            S::invalidate_vm();
+        } else {
+            for op in self.ops.iter() {
+                S::invalidate_tlb(op.begin, op.end);
+            }
+        }
     }
 
-    /// Gets the mode of the give range of intermediate physical addresses if they are mapped with
-    /// the same mode.
-    ///
-    /// Returns true if the range is mapped with the same mode and false otherwise.}
-    pub fn get_mode(&self, begin: usize, end: usize) -> Option<Mode> {
-        let attrs = self.get_attrs(begin, end)?;
-        Some(S::attrs_to_mode(attrs))
+    /// Discards all the updates, freeing the tables allocated for them. No mapping is changed.
+    pub fn abort(self, mpool: &MPool) {
+        for op in self.ops.iter() {
+            self.ptable.rollback(op.begin, op.end, mpool);
+        }
     }
 }
 
@@ -932,6 +2869,102 @@ impl<S: Stage> Drop for PageTable<S> {
     }
 }
 
+/// A temporary identity mapping in the hypervisor page table, which is unmapped when dropped.
+pub struct TempMapping<'a> {
+    begin: PhysAddr,
+    end: PhysAddr,
+    mpool: &'a MPool,
+
+    /// Whether the range was mapped by `new()`, rather than already mapped by the hypervisor, in
+    /// which case it is left mapped when dropped.
+    owned: bool,
+}
+
+impl<'a> TempMapping<'a> {
+    /// Maps `[pa, pa + len)` into the hypervisor page table with the given mode. If the whole
+    /// range is already mapped with at least that mode, the existing mapping is used and left as
+    /// it is. Fails with `MmError::Overlap` if only part of the range is mapped, or it is mapped
+    /// with a weaker mode, as the existing mapping would be changed.
+    pub fn new(pa: PhysAddr, len: usize, mode: Mode, mpool: &'a MPool) -> Result<Self, MmError> {
+        let begin = addr::round_down_to_page(pa);
+        let end = addr::round_up_to_page(pa + len);
+        let mut hypervisor_page_table = hypervisor_page_table().lock();
+
+        let mut mapped = false;
+        let mut unmapped = false;
+        let mut sufficient = true;
+        for segment in
+            hypervisor_page_table.get_modes(VirtAddr::from_pa(begin), VirtAddr::from_pa(end))?
+        {
+            if segment.mode.contains(Mode::INVALID) {
+                unmapped = true;
+            } else {
+                mapped = true;
+                sufficient &= segment.mode.contains(mode);
+            }
+        }
+
+        if mapped {
+            if unmapped || !sufficient {
+                return Err(MmError::Overlap);
+            }
+
+            return Ok(Self {
+                begin,
+                end,
+                mpool,
+                owned: false,
+            });
+        }
+
+        hypervisor_page_table.identity_map(begin, end, mode, mpool)?;
+
+        Ok(Self {
+            begin,
+            end,
+            mpool,
+            owned: true,
+        })
+    }
+
+    /// Returns a pointer to the beginning of the mapping.
+    pub fn as_ptr(&self) -> *mut u8 {
+        VirtAddr::from_pa(self.begin).as_ptr()
+    }
+
+    /// Returns the size of the mapping.
+    pub fn size(&self) -> usize {
+        self.end - self.begin
+    }
+}
+
+impl<'a> Drop for TempMapping<'a> {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+
+        // Unmapping invalidates the TLB for the range. It may fail only if a block should be
+        // split and the memory pool is exhausted, in which case the mapping is leaked.
+        if hypervisor_page_table()
+            .lock()
+            .unmap(self.begin, self.end, self.mpool)
+            .is_err()
+        {
+            dlog!("Failed to unmap a temporary mapping.\n");
+        }
+    }
+}
+
+/// Zeroes the given physical memory and writes it back from the data cache, so that no VM or
+/// device can see its previous contents.
+fn scrub(pa: PhysAddr, size: usize, mpool: &MPool) -> Result<(), MmError> {
+    with_mapping(pa, size, Mode::R | Mode::W, mpool, |p| unsafe {
+        ptr::write_bytes(p, 0, size);
+        arch_mm_write_back_dcache(p, size);
+    })
+}
+
 /// After calling this function, modifications to stage-2 page tables will use break-before-make and
 /// invalidate the TLB for the affected range.
 ///
@@ -943,17 +2976,41 @@ pub unsafe extern "C" fn mm_vm_enable_invalidation() {
     STAGE2_INVALIDATE.store(true, Ordering::Relaxed);
 }
 
+/// After calling this function, stage-2 mappings that are both writable and executable are only
+/// allowed in the whitelisted JIT regions.
+#[no_mangle]
+pub unsafe extern "C" fn mm_vm_enable_wx_strict() {
+    WX_STRICT.store(true, Ordering::Relaxed);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mm_vm_wx_allow(begin: PhysAddr, end: PhysAddr) -> bool {
+    wx_allow(begin, end).is_ok()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mm_vm_wx_disallow(begin: PhysAddr, end: PhysAddr) -> bool {
+    wx_disallow(begin, end)
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn mm_vm_init(t: *mut PageTable<Stage2>, mpool: *const MPool) -> bool {
     let mpool = &*mpool;
     PageTable::new(mpool)
         .map(|table| ptr::write(t, table))
-        .is_some()
+        .is_ok()
+}
+
+/// Records the memory mapped by the page table from now on as owned by the given VM in the frame
+/// table.
+#[no_mangle]
+pub unsafe extern "C" fn mm_vm_set_owner(t: *mut PageTable<Stage2>, owner: u32) {
+    (*t).set_owner(owner);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn mm_vm_fini(t: *mut PageTable<Stage2>, mpool: *const MPool) {
-    let t = PageTable::<Stage2>::from_raw((*t).root);
+    let t = ptr::read(t);
     let mpool = &*mpool;
     t.drop(mpool);
 }
@@ -961,10 +3018,10 @@ pub unsafe extern "C" fn mm_vm_fini(t: *mut PageTable<Stage2>, mpool: *const MPo
 #[no_mangle]
 pub unsafe extern "C" fn mm_vm_identity_map(
     t: *mut PageTable<Stage2>,
-    begin: usize,
-    end: usize,
+    begin: PhysAddr,
+    end: PhysAddr,
     mode: c_int,
-    ipa: *mut usize,
+    ipa: *mut IpaAddr,
     mpool: *const MPool,
 ) -> bool {
     let t = &mut *t;
@@ -973,29 +3030,46 @@ pub unsafe extern "C" fn mm_vm_identity_map(
     t.identity_map(begin, end, mode, mpool)
         .map(|_| {
             if !ipa.is_null() {
-                ptr::write(ipa, begin);
+                ptr::write(ipa, IpaAddr::from_pa(begin));
             }
         })
-        .is_some()
+        .is_ok()
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn mm_vm_unmap(
     t: *mut PageTable<Stage2>,
-    begin: usize,
-    end: usize,
+    begin: PhysAddr,
+    end: PhysAddr,
     mpool: *const MPool,
 ) -> bool {
     let t = &mut *t;
     let mpool = &*mpool;
-    t.identity_update(
-        begin,
-        end,
-        Stage2::mode_to_attrs(Mode::UNOWNED | Mode::INVALID | Mode::SHARED),
-        Flags::UNMAP,
-        mpool,
-    )
-    .is_some()
+    t.unmap(begin, end, mpool).is_ok()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mm_vm_unmap_scrub(
+    t: *mut PageTable<Stage2>,
+    begin: PhysAddr,
+    end: PhysAddr,
+    mpool: *const MPool,
+) -> bool {
+    let t = &mut *t;
+    let mpool = &*mpool;
+    t.unmap_scrub(begin, end, mpool).is_ok()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mm_vm_reclaim(
+    t: *mut PageTable<Stage2>,
+    to: *mut PageTable<Stage2>,
+    mpool: *const MPool,
+) -> bool {
+    let t = &mut *t;
+    let to = &mut *to;
+    let mpool = &*mpool;
+    t.reclaim_into(to, mpool).is_ok()
 }
 
 #[no_mangle]
@@ -1006,19 +3080,10 @@ pub unsafe extern "C" fn mm_vm_unmap_hypervisor(
     // TODO: If we add pages dynamically, they must be included here too.
     let t = &mut *t;
     let mpool = &*mpool;
-    some_or_return!(
-        t.unmap(layout_text_begin(), layout_text_end(), mpool),
-        false
-    );
-    some_or_return!(
-        t.unmap(layout_rodata_begin(), layout_rodata_end(), mpool),
-        false
-    );
-    some_or_return!(
-        t.unmap(layout_data_begin(), layout_data_end(), mpool),
-        false
-    );
-    true
+    t.unmap(layout_text_begin(), layout_text_end(), mpool)
+        .and_then(|_| t.unmap(layout_rodata_begin(), layout_rodata_end(), mpool))
+        .and_then(|_| t.unmap(layout_data_begin(), layout_data_end(), mpool))
+        .is_ok()
 }
 
 #[no_mangle]
@@ -1037,90 +3102,140 @@ pub unsafe extern "C" fn mm_vm_defrag(t: *mut PageTable<Stage2>, mpool: *const M
 #[no_mangle]
 pub unsafe extern "C" fn mm_vm_get_mode(
     t: *mut PageTable<Stage2>,
-    begin: usize,
-    end: usize,
+    begin: IpaAddr,
+    end: IpaAddr,
     mode: *mut c_int,
 ) -> bool {
-    let t = &mut *t;
-    t.get_mode(begin, end)
+    PageTableView::new(t)
+        .get_mode(begin, end)
         .map(|m| *mode = m.bits as c_int)
-        .is_some()
+        .is_ok()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mm_vm_get_stats(t: *const PageTable<Stage2>, stats: *mut PageTableStats) {
+    let t = &*t;
+    ptr::write(stats, t.stats());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mm_vm_mapped_pages(t: *const PageTable<Stage2>) -> usize {
+    (*t).mapped_pages()
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn mm_identity_map(
-    begin: usize,
-    end: usize,
+    begin: PhysAddr,
+    end: PhysAddr,
     mode: c_int,
     mpool: *const MPool,
 ) -> *mut usize {
     let mode = Mode::from_bits_truncate(mode as u32);
     let mpool = &*mpool;
-    HYPERVISOR_PAGE_TABLE
+    hypervisor_page_table()
         .lock()
         .identity_map(begin, end, mode, mpool)
-        .map(|_| begin as *mut _)
-        .unwrap_or_else(|| ptr::null_mut())
+        .map(|_| VirtAddr::from_pa(begin).as_ptr())
+        .unwrap_or_else(|_| ptr::null_mut())
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn mm_unmap(begin: usize, end: usize, mpool: *const MPool) -> bool {
+pub unsafe extern "C" fn mm_map(
+    va_begin: VirtAddr,
+    va_end: VirtAddr,
+    pa_begin: PhysAddr,
+    mode: c_int,
+    mpool: *const MPool,
+) -> *mut usize {
+    let mode = Mode::from_bits_truncate(mode as u32);
     let mpool = &*mpool;
-    HYPERVISOR_PAGE_TABLE
+    hypervisor_page_table()
         .lock()
-        .unmap(begin, end, mpool)
-        .is_some()
+        .map(va_begin, va_end, pa_begin, mode, mpool)
+        .map(|_| va_begin.as_ptr())
+        .unwrap_or_else(|_| ptr::null_mut())
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn mm_init(mpool: *const MPool) -> bool {
+pub unsafe extern "C" fn mm_unmap(begin: PhysAddr, end: PhysAddr, mpool: *const MPool) -> bool {
+    let mpool = &*mpool;
+    hypervisor_page_table()
+        .lock()
+        .unmap(begin, end, mpool)
+        .is_ok()
+}
+
+/// Initialises the hypervisor page table and enables the MMU. Fails if the hypervisor page table
+/// is already initialised.
+///
+/// # Safety
+///
+/// This function should be called before any other memory management function.
+unsafe fn init(mpool: &MPool) -> Result<(), MmError> {
     dlog!(
         "text: {:#x} - {:#x}\n",
-        layout_text_begin(),
-        layout_text_end()
+        layout_text_begin().addr(),
+        layout_text_end().addr()
     );
     dlog!(
         "rodata: {:#x} - {:#x}\n",
-        layout_rodata_begin(),
-        layout_rodata_end()
+        layout_rodata_begin().addr(),
+        layout_rodata_end().addr()
     );
     dlog!(
         "data: {:#x} - {:#x}\n",
-        layout_data_begin(),
-        layout_data_end()
+        layout_data_begin().addr(),
+        layout_data_end().addr()
     );
 
-    let mpool = &*mpool;
-    let page_table = PageTable::new(mpool)
-        .ok_or_else(|| dlog!("Unable to allocate memory for page table.\n"))
-        .ok();
-    let page_table = some_or_return!(page_table, false);
-    let hypervisor_page_table = HYPERVISOR_PAGE_TABLE.get_mut_unchecked();
-    ptr::write(hypervisor_page_table, page_table);
+    let page_table = PageTable::new(mpool).map_err(|e| {
+        dlog!("Unable to allocate memory for page table.\n");
+        e
+    })?;
+    if let Err(page_table) = HYPERVISOR_PAGE_TABLE.set(SpinLock::new(page_table)) {
+        dlog!("Hypervisor page table is already initialised.\n");
+        page_table.into_inner().drop(mpool);
+        return Err(MmError::AlreadyInitialized);
+    }
 
     // Let console driver map pages for itself.
     plat_console_mm_init(mpool);
 
-    hypervisor_page_table.identity_map(layout_text_begin(), layout_text_end(), Mode::X, mpool);
-    hypervisor_page_table.identity_map(layout_rodata_begin(), layout_rodata_end(), Mode::R, mpool);
+    let mut hypervisor_page_table = hypervisor_page_table().lock();
+    hypervisor_page_table.identity_map(layout_text_begin(), layout_text_end(), Mode::X, mpool)?;
+    hypervisor_page_table.identity_map(
+        layout_rodata_begin(),
+        layout_rodata_end(),
+        Mode::R,
+        mpool,
+    )?;
     hypervisor_page_table.identity_map(
         layout_data_begin(),
         layout_data_end(),
         Mode::R | Mode::W,
         mpool,
-    );
+    )?;
+
+    if !arch_mm_init(hypervisor_page_table.root, true) {
+        return Err(MmError::Arch);
+    }
 
-    arch_mm_init(hypervisor_page_table.root, true)
+    Ok(())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mm_init(mpool: *const MPool) -> bool {
+    init(&*mpool).is_ok()
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn mm_cpu_init() -> bool {
-    let ptable = HYPERVISOR_PAGE_TABLE.get_mut_unchecked().get_raw();
+    let ptable = hypervisor_page_table().lock().get_raw();
     arch_mm_init(ptable as usize, false)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn mm_defrag(mpool: *const MPool) {
     let mpool = &*mpool;
-    HYPERVISOR_PAGE_TABLE.lock().defrag(mpool);
+    hypervisor_page_table().lock().defrag(mpool);
 }
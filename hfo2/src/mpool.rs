@@ -14,9 +14,12 @@
  * limitations under the License.
  */
 
+use core::cmp;
 use core::mem;
-use core::ops::DerefMut;
+use core::ops::{Deref, DerefMut};
 use core::ptr;
+use core::slice;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
 
 use crate::list::{IsElement, List, ListEntry};
 use crate::page::*;
@@ -76,11 +79,124 @@ impl IsElement<Entry> for Entry {
     }
 }
 
+/// Page pool accounting.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MPoolStats {
+    /// The number of successful allocations.
+    pub allocs: usize,
+
+    /// The number of frees, including the chunks added to the pool.
+    pub frees: usize,
+
+    /// The number of allocations that failed, including from the fallbacks.
+    pub failures: usize,
+
+    /// The number of pages currently free in the pool.
+    pub free_pages: usize,
+
+    /// The minimum number of free pages observed right after an allocation, or `usize::MAX` if
+    /// nothing has been allocated from the pool yet.
+    pub min_free_pages: usize,
+}
+
+impl MPoolStats {
+    const fn new() -> Self {
+        Self {
+            allocs: 0,
+            frees: 0,
+            failures: 0,
+            free_pages: 0,
+            min_free_pages: usize::max_value(),
+        }
+    }
+
+    fn record_alloc(&mut self, count: usize) {
+        self.allocs += 1;
+        self.free_pages -= count;
+        if self.free_pages < self.min_free_pages {
+            self.min_free_pages = self.free_pages;
+        }
+    }
+
+    fn record_free(&mut self, count: usize) {
+        self.frees += 1;
+        self.free_pages += count;
+    }
+}
+
+/// The maximum order of the blocks of the buddy allocator, i.e., blocks have at most
+/// `1 << BUDDY_MAX_ORDER` pages.
+pub const BUDDY_MAX_ORDER: usize = 10;
+
+/// The handler run when the free pages of a memory pool drop below its watermark. It runs without
+/// the pool locked, so it may refill the pool.
+pub type LowMemoryHandler = extern "C" fn(&MPool);
+
 /// Page pool.
+///
+/// A page pool has two tiers. Chunks added to the pool are kept as they are in the chunk list, so
+/// that a run of any size and alignment can be allocated from a chunk large enough. Pages freed
+/// to the pool are kept in the buddy free lists instead: a run of `1 << order` pages aligned to
+/// its size is a block in `free_lists[order]`, and it is merged with its buddy, i.e., the other
+/// half of the block of the next order, as soon as both are free. Thus the freed pages are
+/// coalesced back into aligned contiguous runs no matter in which order they are freed.
 #[repr(C)]
 pub struct Pool {
     chunk_list: List<Chunk>,
-    entry_list: List<Entry>,
+    free_lists: [List<Entry>; BUDDY_MAX_ORDER + 1],
+    stats: MPoolStats,
+
+    /// The number of free pages below which `low_memory_handler` runs.
+    watermark: usize,
+    low_memory_handler: Option<LowMemoryHandler>,
+
+    /// Whether the free pages have dropped below the watermark and not been refilled since.
+    low: bool,
+
+    /// The number of free pages only `alloc_reserved()` may allocate.
+    reserved: usize,
+}
+
+/// The byte freed pages are filled with if the `mpool_poison` feature is enabled.
+const POISON: u8 = 0xa5;
+
+/// The number of bytes at the start of each freed page that are not checked for the poison, as
+/// they may hold the list entry of a free block.
+const POISON_HEADER_SIZE: usize = mem::size_of::<Chunk>();
+
+/// Fills the freed pages with the poison if the `mpool_poison` feature is enabled.
+fn poison(start: usize, count: usize) {
+    if cfg!(feature = "mpool_poison") {
+        unsafe { ptr::write_bytes(start as *mut u8, POISON, count * PAGE_SIZE) };
+    }
+}
+
+/// Checks that the pages being allocated are still filled with the poison if the `mpool_poison`
+/// feature is enabled, i.e., they are not written to while they are free.
+fn check_poison(start: usize, count: usize) {
+    if !cfg!(feature = "mpool_poison") {
+        return;
+    }
+
+    for page in (start..start + count * PAGE_SIZE).step_by(PAGE_SIZE) {
+        let bytes = unsafe { slice::from_raw_parts(page as *const u8, PAGE_SIZE) };
+        if let Some(offset) = bytes[POISON_HEADER_SIZE..]
+            .iter()
+            .position(|byte| *byte != POISON)
+        {
+            panic!(
+                "mpool: free page {:#x} is corrupted at offset {:#x}",
+                page,
+                POISON_HEADER_SIZE + offset
+            );
+        }
+    }
+}
+
+/// Returns the order of the smallest block with at least the given number of pages.
+fn order_of(count: usize) -> usize {
+    count.next_power_of_two().trailing_zeros() as usize
 }
 
 impl Pool {
@@ -88,43 +204,121 @@ impl Pool {
     pub const fn new() -> Self {
         Self {
             chunk_list: List::new(),
-            entry_list: List::new(),
+            free_lists: [
+                List::new(),
+                List::new(),
+                List::new(),
+                List::new(),
+                List::new(),
+                List::new(),
+                List::new(),
+                List::new(),
+                List::new(),
+                List::new(),
+                List::new(),
+            ],
+            stats: MPoolStats::new(),
+            watermark: 0,
+            low_memory_handler: None,
+            low: false,
+            reserved: 0,
         }
     }
 
+    /// Returns the low-memory handler to run if the free pages, including the given number of pages
+    /// cached outside the pool, have just dropped below the watermark. The handler runs once until
+    /// the pool is refilled above the watermark.
+    fn check_watermark(&mut self, cached: usize) -> Option<LowMemoryHandler> {
+        if self.stats.free_pages + cached >= self.watermark {
+            self.low = false;
+            return None;
+        }
+
+        if self.low {
+            return None;
+        }
+
+        self.low = true;
+        self.low_memory_handler
+    }
+
+    /// Rearms the low-memory handler if the free pages, including the given number of pages cached
+    /// outside the pool, are refilled up to the watermark.
+    fn reset_watermark(&mut self, cached: usize) {
+        if self.stats.free_pages + cached >= self.watermark {
+            self.low = false;
+        }
+    }
+
+    /// Checks whether the pool alone keeps the emergency reserve and stays at or above the
+    /// watermark, so that taking a page cached outside the pool affects neither.
+    fn is_above_floor(&self) -> bool {
+        self.stats.free_pages >= cmp::max(self.reserved, self.watermark)
+    }
+
     /// Allocates a page.
     pub fn alloc(&mut self) -> Option<Page> {
-        if let Some(entry) = unsafe { self.entry_list.pop() } {
-            return Some(unsafe { Page::from_raw(entry as *mut RawPage) });
+        self.alloc_pages(1, 1)
+            .map(|pages| unsafe { Page::from_raw(pages.into_raw()) })
+    }
+
+    /// Allocates a number of contiguous and aligned pages. Freed pages are reused first, and then
+    /// the chunks.
+    pub fn alloc_pages(&mut self, size: usize, align: usize) -> Option<Pages> {
+        if self.stats.free_pages < self.reserved + size {
+            return None;
         }
 
-        let chunk = unsafe { self.chunk_list.pop()? };
-        let size = unsafe { (*chunk).size };
-        assert_ne!(size, 0);
-        let page = unsafe { Page::from_raw(chunk as *mut RawPage) };
+        self.alloc_pages_unreserved(size, align)
+    }
 
-        if size == 2 {
-            let entry = unsafe { &mut *((chunk as usize + PAGE_SIZE) as *mut Entry) };
-            unsafe {
-                self.entry_list.push(entry);
+    /// Allocates a page, using the emergency reserve if needed.
+    pub fn alloc_reserved(&mut self) -> Option<Page> {
+        self.alloc_pages_unreserved(1, 1)
+            .map(|pages| unsafe { Page::from_raw(pages.into_raw()) })
+    }
+
+    fn alloc_pages_unreserved(&mut self, size: usize, align: usize) -> Option<Pages> {
+        // Only freed pages are poisoned, not the chunks added to the pool.
+        let start = match self.alloc_buddy(size, align) {
+            Some(start) => {
+                check_poison(start, size);
+                start
             }
-        } else if size > 2 {
-            let new_chunk = unsafe { &mut *((chunk as usize + PAGE_SIZE) as *mut Chunk) };
-            new_chunk.size = size - 1;
-            unsafe { self.chunk_list.push(new_chunk) };
-        }
+            None => self.alloc_chunk(size, align)?,
+        };
 
-        Some(page)
+        self.stats.record_alloc(size);
+        Some(unsafe { Pages::from_raw(start as *mut RawPage, size) })
     }
 
-    /// Allocates a number of contiguous and aligned pages.
-    pub fn alloc_pages(&mut self, size: usize, align: usize) -> Option<Pages> {
-        if size == 1 && align == 1 {
-            return self
-                .alloc()
-                .map(|page| unsafe { Pages::from_raw(page.into_raw(), 1) });
+    /// Allocates a run from the buddy free lists, splitting the smallest block large enough.
+    fn alloc_buddy(&mut self, size: usize, align: usize) -> Option<usize> {
+        if size == 0 || !align.is_power_of_two() {
+            return None;
         }
 
+        let order = order_of(cmp::max(size, align));
+        let mut block_order =
+            (order..=BUDDY_MAX_ORDER).find(|o| !self.free_lists[*o].is_empty())?;
+        let start = unsafe { self.free_lists[block_order].pop()? } as usize;
+
+        // Returns the upper halves to the free lists until the block is of the requested order.
+        // Their buddies are allocated, so they are not merged.
+        while block_order > order {
+            block_order -= 1;
+            let upper = unsafe { &*((start + (PAGE_SIZE << block_order)) as *const Entry) };
+            unsafe { self.free_lists[block_order].push(upper) };
+        }
+
+        // Returns the pages past the requested size.
+        self.free_range(start + size * PAGE_SIZE, (1 << order) - size);
+
+        Some(start)
+    }
+
+    /// Allocates a run from the first chunk large enough.
+    fn alloc_chunk(&mut self, size: usize, align: usize) -> Option<usize> {
         let (chunk, (chunk_start, chunk_end, start, end)) = unsafe {
             self.chunk_list.pop_if_some(|chunk| {
                 // Calculate where the new chunk would be if we consume the requested number of
@@ -157,7 +351,57 @@ impl Pool {
             unsafe { self.chunk_list.push(new_chunk) };
         }
 
-        Some(unsafe { Pages::from_raw(start as *mut RawPage, size) })
+        Some(start)
+    }
+
+    /// Allocates `count` contiguous pages whose start is aligned to `align_pages` pages, which
+    /// should be a power of two.
+    pub fn alloc_aligned(&mut self, count: usize, align_pages: usize) -> Option<Pages> {
+        assert!(align_pages.is_power_of_two());
+        self.alloc_pages(count, align_pages)
+    }
+
+    /// Inserts the block at `start` of the given order into the buddy free lists, merging it with
+    /// its buddy while the buddy is free.
+    fn insert_block(&mut self, mut start: usize, mut order: usize) {
+        while order < BUDDY_MAX_ORDER {
+            let buddy = start ^ (PAGE_SIZE << order);
+            let found = unsafe {
+                self.free_lists[order]
+                    .pop_if_some(|entry| {
+                        if entry as *const _ as usize == buddy {
+                            Some(())
+                        } else {
+                            None
+                        }
+                    })
+                    .is_some()
+            };
+
+            if !found {
+                break;
+            }
+
+            start = cmp::min(start, buddy);
+            order += 1;
+        }
+
+        let entry = unsafe { &*(start as *const Entry) };
+        unsafe { self.free_lists[order].push(entry) };
+    }
+
+    /// Inserts the `count` pages starting at `start` into the buddy free lists, as the largest
+    /// aligned blocks they consist of.
+    fn free_range(&mut self, mut start: usize, mut count: usize) {
+        while count > 0 {
+            let align_order = (start / PAGE_SIZE).trailing_zeros() as usize;
+            let size_order = mem::size_of::<usize>() * 8 - 1 - count.leading_zeros() as usize;
+            let order = cmp::min(cmp::min(align_order, size_order), BUDDY_MAX_ORDER);
+
+            self.insert_block(start, order);
+            start += PAGE_SIZE << order;
+            count -= 1 << order;
+        }
     }
 
     /// Frees a page back into the given page pool, making it available for reuse.
@@ -165,23 +409,257 @@ impl Pool {
     /// This is meant to be used for freeing single pages. To free multiple pages, call
     /// `free_pages()` instead.
     pub fn free(&mut self, mut page: Page) {
-        let entry = unsafe { &*(page.deref_mut() as *mut RawPage as *mut Entry) };
+        let start = page.deref_mut() as *mut RawPage as usize;
         mem::forget(page);
-        unsafe { self.entry_list.push(entry) };
+        poison(start, 1);
+        self.insert_block(start, 0);
+        self.stats.record_free(1);
+    }
+
+    /// Inserts a page that is already freed, e.g. cached in a `PageStack`, into the pool. It is
+    /// not counted as a free again.
+    fn insert_page(&mut self, page: usize) {
+        self.insert_block(page, 0);
+        self.stats.free_pages += 1;
     }
 
     /// Frees a number of contiguous pages to the given page pool.
     pub fn free_pages(&mut self, pages: Pages) {
+        let size = pages.len();
+        let start = pages.into_raw() as usize;
+        poison(start, size);
+        self.free_range(start, size);
+        self.stats.record_free(size);
+    }
+
+    /// Adds a chunk of contiguous pages to the given page pool. Unlike `free_pages()`, the chunk
+    /// is kept as a whole, so that runs that are not aligned to their size are allocated from it.
+    pub fn add_chunk(&mut self, pages: Pages) {
         let size = pages.len();
         let chunk = unsafe { &mut *(pages.into_raw() as *mut Chunk) };
         chunk.size = size;
         unsafe { self.chunk_list.push(chunk) };
+        self.stats.record_free(size);
+    }
+
+    /// Checks whether the page at the given address is free in the pool.
+    pub fn contains(&self, addr: usize) -> bool {
+        self.free_lists.iter().enumerate().any(|(order, list)| {
+            list.iter().any(|entry| {
+                let begin = entry as *const _ as usize;
+                begin <= addr && addr < begin + (PAGE_SIZE << order)
+            })
+        }) || self.chunk_list.iter().any(|chunk| {
+            let begin = chunk as *const _ as usize;
+            begin <= addr && addr < begin + chunk.size * PAGE_SIZE
+        })
+    }
+}
+
+/// The maximum number of pages cached in a `PageStack`. Further freed pages go to the pool, so
+/// that they can be coalesced.
+const PAGE_STACK_MAX_PAGES: usize = 64;
+
+/// The number of low bits of the head of a `PageStack` that hold its tag. The high bits hold the
+/// page frame number of the top page, which fits as addresses have at most 48 bits.
+const PAGE_STACK_TAG_BITS: usize = 28;
+
+const PAGE_STACK_TAG_MASK: usize = (1 << PAGE_STACK_TAG_BITS) - 1;
+
+/// The link at the start of a page in a `PageStack`.
+#[repr(C)]
+struct StackEntry {
+    /// The next page in the stack, or 0 if this is the bottom one.
+    next: AtomicUsize,
+
+    /// The number of pages in the stack from this page down.
+    len: AtomicUsize,
+}
+
+/// A lock-free stack of free single pages, which caches the pages freed to a memory pool so that
+/// single pages are allocated and freed without the pool's lock.
+///
+/// Each page in the stack starts with a `StackEntry`, so the length of the stack is read from the
+/// top page and is always consistent with the head. The head is tagged with a counter incremented
+/// on every update, so that a pop does not succeed if the head is popped and pushed again in the
+/// meantime (the ABA problem).
+#[repr(C)]
+struct PageStack {
+    head: AtomicUsize,
+
+    /// The number of pages allocated from and freed to the stack.
+    allocs: AtomicUsize,
+    frees: AtomicUsize,
+}
+
+impl PageStack {
+    const_assert!(stack_entry_size; mem::size_of::<StackEntry>() <= POISON_HEADER_SIZE);
+
+    const fn new() -> Self {
+        Self {
+            head: AtomicUsize::new(0),
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the link of the page in the stack.
+    ///
+    /// # Safety
+    ///
+    /// `page` should be a free page, which is never unmapped.
+    unsafe fn entry_of(page: usize) -> &'static StackEntry {
+        &*(page as *const StackEntry)
+    }
+
+    /// Returns the top page of the given head, or 0 if the stack is empty.
+    fn page_of(head: usize) -> usize {
+        (head >> PAGE_STACK_TAG_BITS) << PAGE_BITS
+    }
+
+    /// Returns the head that replaces `head` to put `page` on the top, or to empty the stack if
+    /// `page` is 0.
+    fn next_head(head: usize, page: usize) -> usize {
+        ((page >> PAGE_BITS) << PAGE_STACK_TAG_BITS) | (head.wrapping_add(1) & PAGE_STACK_TAG_MASK)
+    }
+
+    /// Returns the number of pages in the stack with the given head. If the head is stale, the top
+    /// page may be popped and reused concurrently, and the result is garbage.
+    fn len_of(head: usize) -> usize {
+        match Self::page_of(head) {
+            0 => 0,
+            page => unsafe { Self::entry_of(page) }.len.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the number of pages in the stack.
+    fn len(&self) -> usize {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let len = Self::len_of(head);
+
+            // The length is valid only if the head has not changed while reading it.
+            fence(Ordering::Acquire);
+            let current = self.head.load(Ordering::Acquire);
+            if current == head {
+                return len;
+            }
+            head = current;
+        }
+    }
+
+    /// Pushes the page to the stack, or returns it if the stack is full.
+    fn push(&self, page: Page) -> Result<(), Page> {
+        if self.len() >= PAGE_STACK_MAX_PAGES {
+            return Err(page);
+        }
+
+        let page = page.into_raw() as usize;
+        debug_assert_eq!(Self::page_of(Self::next_head(0, page)), page);
+        poison(page, 1);
+
+        let entry = unsafe { Self::entry_of(page) };
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            // If the head is stale, the length is garbage but the exchange fails.
+            entry.next.store(Self::page_of(head), Ordering::Relaxed);
+            entry.len.store(Self::len_of(head).wrapping_add(1), Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                head,
+                Self::next_head(head, page),
+                Ordering::Release,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Pops a page from the stack.
+    fn pop(&self) -> Option<Page> {
+        let mut head = self.head.load(Ordering::Acquire);
+        let page = loop {
+            let page = Self::page_of(head);
+            if page == 0 {
+                return None;
+            }
+
+            // The page may be popped and reused concurrently, in which case `next` is garbage but
+            // the head's tag has changed, so the exchange fails.
+            let next = unsafe { Self::entry_of(page) }.next.load(Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                head,
+                Self::next_head(head, next),
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break page,
+                Err(current) => head = current,
+            }
+        };
+
+        self.allocs.fetch_add(1, Ordering::Relaxed);
+        check_poison(page, 1);
+        Some(unsafe { Page::from_raw(page as *mut RawPage) })
+    }
+
+    /// Takes all the pages in the stack, and calls `f` for each of them.
+    fn drain<F: FnMut(usize)>(&self, mut f: F) {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            match self.head.compare_exchange_weak(
+                head,
+                Self::next_head(head, 0),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+
+        let mut page = Self::page_of(head);
+        while page != 0 {
+            let next = unsafe { Self::entry_of(page) }.next.load(Ordering::Relaxed);
+            f(page);
+            page = next;
+        }
+    }
+
+    /// Checks whether the page at the given address is in the stack. The result is meaningful
+    /// only if the stack is not updated concurrently.
+    fn contains(&self, addr: usize) -> bool {
+        let mut page = Self::page_of(self.head.load(Ordering::Acquire));
+        while page != 0 {
+            if page <= addr && addr < page + PAGE_SIZE {
+                return true;
+            }
+            page = unsafe { Self::entry_of(page) }.next.load(Ordering::Relaxed);
+        }
+        false
     }
 }
 
+/// Memory pool.
+///
+/// Single pages are allocated from and freed to a lock-free stack first. The lock of the page pool
+/// is taken only for multi-page allocations, carving chunks, and when the stack is empty or full.
+/// The low-memory handler and the emergency reserve concern the pages in both of them. Thus pages
+/// are popped from the stack without the lock only while the page pool alone keeps the reserve and
+/// stays at or above the watermark.
 #[repr(C)]
 pub struct MPool {
     pool: SpinLock<Pool>,
+    stack: PageStack,
+
+    /// Whether the page pool was above its floor (see `Pool::is_above_floor()`) when its lock was
+    /// last released.
+    unlocked_pop: AtomicBool,
+
     fallback: *const MPool,
 }
 
@@ -195,16 +673,51 @@ impl MPool {
     pub const fn new() -> Self {
         Self {
             pool: SpinLock::new(Pool::new()),
+            stack: PageStack::new(),
+            unlocked_pop: AtomicBool::new(true),
             fallback: ptr::null(),
         }
     }
 
+    /// Creates a memory pool with the whole pages in the byte range `[begin, end)`, which need not
+    /// be aligned. Returns the pool and the number of bytes added to it, which is 0 if the range
+    /// contains no whole page.
+    ///
+    /// # Safety
+    ///
+    /// The byte range should be memory that is not used for anything else.
+    pub unsafe fn init_from_byte_range(begin: usize, end: usize) -> (Self, usize) {
+        let pool = Self::new();
+        let size = if begin < end {
+            match Pages::from_raw_u8(begin as *mut u8, end - begin) {
+                Some(pages) => {
+                    let size = pages.len() * PAGE_SIZE;
+                    pool.add_chunk(pages);
+                    size
+                }
+                None => 0,
+            }
+        } else {
+            0
+        };
+
+        (pool, size)
+    }
+
     /// Initialises the given memory pool by replicating the properties of `from`. It also pulls the
     /// chunk and free lists from `from`, consuming all its resources and making them available via
     /// the new memory pool.
     pub fn new_from(from: &Self) -> Self {
+        let pool = from.with_pool(|from_pool| {
+            let mut pool = mem::replace(from_pool, Pool::new());
+            from.stack.drain(|page| pool.insert_page(page));
+            pool
+        });
+
         Self {
-            pool: SpinLock::new(mem::replace(&mut from.pool.lock(), Pool::new())),
+            unlocked_pop: AtomicBool::new(pool.is_above_floor()),
+            pool: SpinLock::new(pool),
+            stack: PageStack::new(),
             fallback: from.fallback,
         }
 
@@ -220,20 +733,123 @@ impl MPool {
         pool
     }
 
+    /// Runs `f` with the page pool locked. Afterwards, it rearms the low-memory handler if the free
+    /// pages are refilled up to the watermark, and updates whether pages may be popped from the
+    /// stack without the lock.
+    fn with_pool<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Pool) -> R,
+    {
+        let mut pool = self.pool.lock();
+        let result = f(&mut pool);
+        pool.reset_watermark(self.stack.len());
+        self.unlocked_pop.store(pool.is_above_floor(), Ordering::Release);
+        result
+    }
+
+    /// Pops a page from the stack without the lock of the page pool, if neither the emergency
+    /// reserve nor the watermark is affected.
+    fn pop_unlocked(&self) -> Option<Page> {
+        if !self.unlocked_pop.load(Ordering::Acquire) {
+            return None;
+        }
+
+        self.stack.pop()
+    }
+
+    /// Allocates from the memory pool itself with `f`, running the low-memory handler if the free
+    /// pages drop below the watermark. If the allocation failed, it is retried after the handler.
+    fn alloc_local<T, F>(&self, f: F) -> Option<T>
+    where
+        F: Fn(&mut Pool, &PageStack) -> Option<T>,
+    {
+        let (result, handler) = self.with_pool(|pool| {
+            let result = f(pool, &self.stack);
+            (result, pool.check_watermark(self.stack.len()))
+        });
+
+        let handler = match handler {
+            Some(handler) => handler,
+            None => return result,
+        };
+
+        handler(self);
+
+        match result {
+            Some(result) => Some(result),
+            None => self.with_pool(|pool| f(pool, &self.stack)),
+        }
+    }
+
+    /// Registers the handler run when the free pages of the memory pool, not including its
+    /// fallbacks, drop below `watermark`, e.g. to refill the pool or to warn. `None` unregisters
+    /// the handler.
+    pub fn set_low_memory_handler(&self, watermark: usize, handler: Option<LowMemoryHandler>) {
+        self.with_pool(|pool| {
+            pool.watermark = watermark;
+            pool.low_memory_handler = handler;
+            pool.low = false;
+        });
+    }
+
     /// Allocates an entry from the given memory pool, if one is available. If there isn't one
     /// available, try and allocate from the fallback if there is one.
     pub fn alloc(&self) -> Option<Page> {
-        if let Some(result) = self.pool.lock().alloc() {
+        if let Some(result) = self.pop_unlocked() {
+            return Some(result);
+        }
+
+        if let Some(result) = self.alloc_local(|pool, stack| {
+            // Pages are taken from the stack first, so that the page pool is used only if the
+            // stack is empty.
+            if pool.stats.free_pages + stack.len() <= pool.reserved {
+                return None;
+            }
+            stack.pop().or_else(|| pool.alloc_reserved())
+        }) {
             return Some(result);
         }
 
         if let Some(fallback) = unsafe { self.fallback.as_ref() } {
-            return fallback.alloc();
+            if let Some(result) = fallback.alloc() {
+                return Some(result);
+            }
         }
 
+        self.pool.lock().stats.failures += 1;
         None
     }
 
+    /// Allocates an entry like `alloc()`, but may use the emergency reserve of the memory pool and
+    /// its fallbacks. It is meant for operations that revoke memory, which should complete even
+    /// when the pool is otherwise exhausted.
+    pub fn alloc_reserved(&self) -> Option<Page> {
+        if let Some(result) = self.pop_unlocked() {
+            return Some(result);
+        }
+
+        if let Some(result) =
+            self.alloc_local(|pool, stack| stack.pop().or_else(|| pool.alloc_reserved()))
+        {
+            return Some(result);
+        }
+
+        if let Some(fallback) = unsafe { self.fallback.as_ref() } {
+            if let Some(result) = fallback.alloc_reserved() {
+                return Some(result);
+            }
+        }
+
+        self.pool.lock().stats.failures += 1;
+        None
+    }
+
+    /// Keeps the given number of free pages of the memory pool, not including its fallbacks, as
+    /// the emergency reserve, which only `alloc_reserved()` may allocate.
+    pub fn set_reserve(&self, pages: usize) {
+        self.with_pool(|pool| pool.reserved = pages);
+    }
+
     /// Allocates a number of contiguous and aligned entries. This is a best-effort operation and
     /// only succeeds if such entries can be found in the chunks list or the chunks of the fallbacks
     /// (i.e., the entry list is never used to satisfy these allocations).
@@ -243,14 +859,50 @@ impl MPool {
     ///
     /// The caller can enventually free the returned entries by calling mpool_add_chunk.
     pub fn alloc_pages(&self, count: usize, align: usize) -> Option<Pages> {
-        if let Some(result) = self.pool.lock().alloc_pages(count, align) {
+        if let Some(result) = self.alloc_local(|pool, _| pool.alloc_pages(count, align)) {
             return Some(result);
         }
 
+        // The pages cached in the stack may be coalesced into a run large enough.
+        if self.drain_stack() {
+            if let Some(result) = self.alloc_local(|pool, _| pool.alloc_pages(count, align)) {
+                return Some(result);
+            }
+        }
+
         if let Some(fallback) = unsafe { self.fallback.as_ref() } {
-            return fallback.alloc_pages(count, align);
+            if let Some(result) = fallback.alloc_pages(count, align) {
+                return Some(result);
+            }
         }
 
+        self.pool.lock().stats.failures += 1;
+        None
+    }
+
+    /// Allocates `count` contiguous pages aligned to `align_pages` pages, which should be a power
+    /// of two, from the memory pool or its fallbacks. See `Pool::alloc_aligned()`.
+    pub fn alloc_aligned(&self, count: usize, align_pages: usize) -> Option<Pages> {
+        if let Some(result) = self.alloc_local(|pool, _| pool.alloc_aligned(count, align_pages)) {
+            return Some(result);
+        }
+
+        // The pages cached in the stack may be coalesced into a run large enough.
+        if self.drain_stack() {
+            if let Some(result) =
+                self.alloc_local(|pool, _| pool.alloc_aligned(count, align_pages))
+            {
+                return Some(result);
+            }
+        }
+
+        if let Some(fallback) = unsafe { self.fallback.as_ref() } {
+            if let Some(result) = fallback.alloc_aligned(count, align_pages) {
+                return Some(result);
+            }
+        }
+
+        self.pool.lock().stats.failures += 1;
         None
     }
 
@@ -259,18 +911,141 @@ impl MPool {
     /// This is meant to be used for freeing single entries. To free multiple entries, one must call
     /// mpool_add_chunk instead.
     pub fn free(&self, page: Page) {
-        self.pool.lock().free(page);
+        if let Err(page) = self.stack.push(page) {
+            self.with_pool(|pool| pool.free(page));
+        }
+    }
+
+    /// Moves the pages cached in the stack to the page pool. Returns whether there was any.
+    fn drain_stack(&self) -> bool {
+        self.with_pool(|pool| {
+            let mut drained = false;
+            self.stack.drain(|page| {
+                pool.insert_page(page);
+                drained = true;
+            });
+            drained
+        })
+    }
+
+    /// Frees a number of contiguous pages back into the memory pool, where they are merged with
+    /// the adjacent free pages.
+    pub fn free_pages(&self, pages: Pages) {
+        self.with_pool(|pool| pool.free_pages(pages));
     }
 
     /// Adds a contiguous chunk of memory to the given memory pool. The chunk will eventually be
     /// broken up into entries of the size held by the memory pool.
+    pub fn add_chunk(&self, pages: Pages) {
+        self.with_pool(|pool| pool.add_chunk(pages));
+    }
+
+    /// Returns the accounting of the memory pool, not including its fallbacks.
+    pub fn stats(&self) -> MPoolStats {
+        let mut stats = self.pool.lock().stats;
+        stats.allocs += self.stack.allocs.load(Ordering::Relaxed);
+        stats.frees += self.stack.frees.load(Ordering::Relaxed);
+        stats.free_pages += self.stack.len();
+        stats
+    }
+
+    /// Prints the accounting of the memory pool to the debug log.
+    pub fn dump_stats(&self) {
+        let stats = self.stats();
+        dlog!(
+            "mpool: {} allocs, {} frees, {} failures, {} free pages",
+            stats.allocs,
+            stats.frees,
+            stats.failures,
+            stats.free_pages
+        );
+        if stats.min_free_pages != usize::max_value() {
+            dlog!(" (low: {})", stats.min_free_pages);
+        }
+        dlog!("\n");
+    }
+
+    /// Checks whether the page at the given address is free in the memory pool or its fallbacks.
+    /// It is meant for consistency checks, as it walks all the free entries and chunks.
+    pub fn contains(&self, addr: usize) -> bool {
+        if self.stack.contains(addr) || self.pool.lock().contains(addr) {
+            return true;
+        }
+
+        match unsafe { self.fallback.as_ref() } {
+            Some(fallback) => fallback.contains(addr),
+            None => false,
+        }
+    }
+}
+
+/// A page allocated from a memory pool, which is freed back to the pool when dropped.
+pub struct PoolPage<'pool> {
+    ptr: *mut RawPage,
+    mpool: &'pool MPool,
+}
+
+/// A page allocated from a static memory pool, which may be kept anywhere.
+pub type StaticPoolPage = PoolPage<'static>;
+
+impl<'pool> PoolPage<'pool> {
+    /// Allocates a page from the memory pool.
+    pub fn alloc(mpool: &'pool MPool) -> Option<Self> {
+        mpool
+            .alloc()
+            .map(|page| unsafe { Self::from_page(page, mpool) })
+    }
+
+    /// Allocates a page from the memory pool, using its emergency reserve if needed.
+    pub fn alloc_reserved(mpool: &'pool MPool) -> Option<Self> {
+        mpool
+            .alloc_reserved()
+            .map(|page| unsafe { Self::from_page(page, mpool) })
+    }
+
+    /// Takes ownership of the page so that it is freed to the memory pool when dropped.
     ///
-    /// Only the portions aligned to the entry size will be added to the pool.
+    /// # Safety
     ///
-    /// Returns true if at least a portion of the chunk was added to pool, or false if none of the
-    /// buffer was usable in the pool.
-    pub fn free_pages(&self, pages: Pages) {
-        self.pool.lock().free_pages(pages);
+    /// `page` should be allocated from `mpool`, or otherwise be fit to be added to it.
+    pub unsafe fn from_page(page: Page, mpool: &'pool MPool) -> Self {
+        Self {
+            ptr: page.into_raw(),
+            mpool,
+        }
+    }
+
+    /// Releases the page from the memory pool, e.g. to keep it in a data structure that frees it
+    /// explicitly.
+    pub fn into_page(self) -> Page {
+        let page = unsafe { Page::from_raw(self.ptr) };
+        mem::forget(self);
+        page
+    }
+
+    /// Returns the memory pool the page is freed to.
+    pub fn mpool(&self) -> &'pool MPool {
+        self.mpool
+    }
+}
+
+impl<'pool> Deref for PoolPage<'pool> {
+    type Target = RawPage;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<'pool> DerefMut for PoolPage<'pool> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.ptr }
+    }
+}
+
+impl<'pool> Drop for PoolPage<'pool> {
+    fn drop(&mut self) {
+        self.mpool.free(unsafe { Page::from_raw(self.ptr) });
     }
 }
 
@@ -278,13 +1053,17 @@ impl Drop for MPool {
     /// Finishes the given memory pool, giving all free memory to the fallback pool if there is one.
     fn drop(&mut self) {
         if let Some(fallback) = unsafe { self.fallback.as_ref() } {
-            let mut pool = self.pool.lock();
-            let mut pool_fallback = fallback.pool.lock();
+            let (mut pool, mut pool_fallback) = SpinLock::lock_both(&self.pool, &fallback.pool);
+
+            // Merge the stack into the fallback.
+            self.stack.drain(|page| pool_fallback.insert_page(page));
 
             unsafe {
-                // Merge the freelist into the fallback.
-                while let Some(entry) = pool.entry_list.pop() {
-                    pool_fallback.entry_list.push(&*entry);
+                // Merge the free lists into the fallback.
+                for order in 0..=BUDDY_MAX_ORDER {
+                    while let Some(entry) = pool.free_lists[order].pop() {
+                        pool_fallback.insert_block(entry as usize, order);
+                    }
                 }
 
                 // Merge the chunk list into the fallback.
@@ -293,6 +1072,12 @@ impl Drop for MPool {
                 }
             }
 
+            pool_fallback.stats.free_pages += pool.stats.free_pages;
+            pool.stats.free_pages = 0;
+
+            pool_fallback.reset_watermark(fallback.stack.len());
+            fallback.unlocked_pop.store(pool_fallback.is_above_floor(), Ordering::Release);
+
             // TODO(@jeehoonkang): it's different from the original C implementation, where
             // `self.pool.fallback` is re-initialized. But it seems the difference doesn't matter.
         }
@@ -310,6 +1095,19 @@ pub unsafe extern "C" fn mpool_init(p: *mut MPool, entry_size: size_t) {
     ptr::write(p, MPool::new());
 }
 
+/// Initialises the memory pool with the whole pages in `[begin, end)`, and returns the number of
+/// bytes added to it.
+#[no_mangle]
+pub unsafe extern "C" fn mpool_init_from_byte_range(
+    p: *mut MPool,
+    begin: *mut c_void,
+    end: *mut c_void,
+) -> size_t {
+    let (pool, size) = MPool::init_from_byte_range(begin as usize, end as usize);
+    ptr::write(p, pool);
+    size
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn mpool_init_from(p: *mut MPool, from: *mut MPool) {
     ptr::write(p, MPool::new_from(&*from));
@@ -330,7 +1128,7 @@ pub unsafe extern "C" fn mpool_fini(p: *mut MPool) {
 #[no_mangle]
 pub unsafe extern "C" fn mpool_add_chunk(p: *mut MPool, begin: *mut c_void, size: size_t) -> bool {
     Pages::from_raw_u8(begin as *mut u8, size)
-        .map(|pages| (*p).free_pages(pages))
+        .map(|pages| (*p).add_chunk(pages))
         .is_some()
 }
 
@@ -352,6 +1150,48 @@ pub unsafe extern "C" fn mpool_alloc_contiguous(
         .unwrap_or_else(|| ptr::null_mut())
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn mpool_alloc_aligned(
+    p: *mut MPool,
+    count: size_t,
+    align_pages: size_t,
+) -> *mut c_void {
+    (*p).alloc_aligned(count as usize, align_pages as usize)
+        .map(|pages| pages.into_raw() as *mut c_void)
+        .unwrap_or_else(|| ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mpool_set_low_memory_handler(
+    p: *const MPool,
+    watermark: size_t,
+    handler: Option<LowMemoryHandler>,
+) {
+    (*p).set_low_memory_handler(watermark as usize, handler);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mpool_alloc_reserved(p: *mut MPool) -> *mut c_void {
+    (*p).alloc_reserved()
+        .map(|page| page.into_raw() as *mut c_void)
+        .unwrap_or_else(|| ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mpool_set_reserve(p: *const MPool, pages: size_t) {
+    (*p).set_reserve(pages as usize);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mpool_get_stats(p: *const MPool, stats: *mut MPoolStats) {
+    ptr::write(stats, (*p).stats());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mpool_dump_stats(p: *const MPool) {
+    (*p).dump_stats();
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn mpool_free(p: *mut MPool, ptr: *mut c_void) {
     (*p).free(Page::from_raw(ptr as *mut RawPage));
@@ -0,0 +1,73 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Asynchronous notifications.
+//!
+//! Every VM has a 64-bit bitmap of pending notifications, which act as doorbells: any VM may set
+//! bits in another VM's bitmap, and the receiver takes all pending bits at once, clearing them.
+//! Setting a bit that is already pending has no further effect, so the sender learns which bits
+//! are newly pending and only those are worth delivering, e.g. by injecting a virtual interrupt.
+//!
+//! The bitmaps are updated atomically, without taking the VM locks.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::types::MAX_VMS;
+
+struct Bitmaps(UnsafeCell<[u64; MAX_VMS]>);
+
+unsafe impl Sync for Bitmaps {}
+
+static PENDING: Bitmaps = Bitmaps(UnsafeCell::new([0; MAX_VMS]));
+
+impl Bitmaps {
+    fn get(&self, vm_id: u16) -> Option<&AtomicU64> {
+        let index = vm_id as usize;
+        if index >= MAX_VMS {
+            return None;
+        }
+
+        // `AtomicU64` has the same in-memory representation as `u64`.
+        Some(unsafe { &*(&(*self.0.get())[index] as *const u64 as *const AtomicU64) })
+    }
+}
+
+/// Makes the given bits pending for the VM. Returns the bits that were not pending before.
+pub fn set(vm_id: u16, bits: u64) -> u64 {
+    match PENDING.get(vm_id) {
+        Some(pending) => !pending.fetch_or(bits, Ordering::AcqRel) & bits,
+        None => 0,
+    }
+}
+
+/// Takes the pending bits of the VM, so that they are no longer pending.
+pub fn take(vm_id: u16) -> u64 {
+    match PENDING.get(vm_id) {
+        Some(pending) => pending.swap(0, Ordering::AcqRel),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn notification_set(vm_id: u16, bits: u64) -> u64 {
+    set(vm_id, bits)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn notification_take(vm_id: u16) -> u64 {
+    take(vm_id)
+}
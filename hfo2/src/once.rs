@@ -0,0 +1,144 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # One-time initialisation of globals.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+/// The value is not initialised.
+const UNINIT: usize = 0;
+
+/// The value is being initialised by a CPU.
+const RUNNING: usize = 1;
+
+/// The value is initialised.
+const READY: usize = 2;
+
+/// A cell initialised at most once, e.g. a global initialised at boot time. Once initialised, the
+/// value is shared by all CPUs and never changes.
+pub struct Once<T> {
+    state: AtomicUsize,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicUsize::new(UNINIT),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the value, or `None` if it is not initialised yet.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == READY {
+            Some(unsafe { &*(*self.data.get()).as_ptr() })
+        } else {
+            None
+        }
+    }
+
+    /// Initialises the cell with the value returned by `f`, unless it is initialised already, and
+    /// returns the value. If another CPU is initialising it, waits until it is done.
+    pub fn call_once<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        match self.try_call_once(|| Ok::<T, ()>(f())) {
+            Ok(value) => value,
+            Err(()) => unreachable!(),
+        }
+    }
+
+    /// Initialises the cell with the value returned by `f`, unless it is initialised already, and
+    /// returns the value. If `f` fails, the cell is left uninitialised and the error is returned.
+    pub fn try_call_once<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        loop {
+            match self
+                .state
+                .compare_and_swap(UNINIT, RUNNING, Ordering::Acquire)
+            {
+                UNINIT => break,
+                READY => return Ok(self.get().unwrap()),
+                _ => spin_loop_hint(),
+            }
+        }
+
+        match f() {
+            Ok(value) => {
+                unsafe { ptr::write((*self.data.get()).as_mut_ptr(), value) };
+                self.state.store(READY, Ordering::Release);
+                Ok(self.get().unwrap())
+            }
+            Err(e) => {
+                self.state.store(UNINIT, Ordering::Release);
+                Err(e)
+            }
+        }
+    }
+
+    /// Initialises the cell with the given value. Fails with the value if the cell is initialised
+    /// or being initialised already.
+    pub fn set(&self, value: T) -> Result<&T, T> {
+        if self
+            .state
+            .compare_and_swap(UNINIT, RUNNING, Ordering::Acquire)
+            != UNINIT
+        {
+            return Err(value);
+        }
+
+        unsafe { ptr::write((*self.data.get()).as_mut_ptr(), value) };
+        self.state.store(READY, Ordering::Release);
+        Ok(self.get().unwrap())
+    }
+}
+
+impl<T> Once<T> {
+    /// Takes the value out of the cell, leaving it uninitialised, e.g. so that tests start afresh.
+    /// Returns `None` if the cell is not initialised.
+    ///
+    /// # Safety
+    ///
+    /// No reference to the value returned by `get()` may be alive, and the cell may not be used
+    /// concurrently.
+    pub unsafe fn take(&self) -> Option<T> {
+        if self.state.load(Ordering::Acquire) != READY {
+            return None;
+        }
+
+        let value = ptr::read((*self.data.get()).as_ptr());
+        self.state.store(UNINIT, Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { ptr::drop_in_place((*self.data.get()).as_mut_ptr()) };
+        }
+    }
+}
@@ -14,9 +14,28 @@
  * limitations under the License.
  */
 
+use crate::cpu::{Cpu, IpiRequests};
+
+extern "C" {
+    fn arch_cpu_id() -> u64;
+    fn cpu_find(id: u64) -> *const Cpu;
+}
+
 #[allow(unused)]
 fn abort_impl() -> ! {
-    // TODO: Block all CPUs.
+    // Write what has been logged, e.g. the reason for aborting, before the CPU that would write it
+    // is stopped.
+    crate::dlog::flush_sync();
+
+    // Stop the other CPUs, without waiting for them as they may be stuck, e.g. waiting for a lock
+    // held by this CPU.
+    unsafe {
+        let cpu = cpu_find(arch_cpu_id());
+        if !cpu.is_null() {
+            (*cpu).ipi_send_others(IpiRequests::STOP);
+        }
+    }
+
     crate::utils::spin_loop()
 }
 
@@ -0,0 +1,215 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # PSCI for the vCPUs of secondary VMs.
+//!
+//! Secondary VMs manage the power of their own vCPUs with PSCI calls, which are emulated here
+//! without involving the firmware.  A vCPU is turned on at the entry point and with the context ID
+//! given to `CPU_ON`, which are stored in its registers until it first runs, and is turned off by
+//! `CPU_OFF`.  `CPU_SUSPEND` is downgraded to waiting for an interrupt, as allowed by the
+//! specification.  The primary VM's calls act on the physical CPUs and are handled in C.
+
+use crate::cpu::{vcpu_lock, vcpu_unlock, CVCpu, VCpuLocked};
+use crate::types::*;
+
+const SMCCC_CONVENTION_MASK: u32 = 0x4000_0000;
+
+const PSCI_VERSION_1_1: uintreg_t = 0x0001_0001;
+
+const PSCI_VERSION: u32 = 0x8400_0000;
+const PSCI_CPU_SUSPEND: u32 = 0x8400_0001;
+const PSCI_CPU_OFF: u32 = 0x8400_0002;
+const PSCI_CPU_ON: u32 = 0x8400_0003;
+const PSCI_AFFINITY_INFO: u32 = 0x8400_0004;
+const PSCI_FEATURES: u32 = 0x8400_000a;
+const PSCI_MEM_PROTECT_CHECK_RANGE: u32 = 0x8400_0014;
+
+const PSCI_RETURN_OFF: uintreg_t = 1;
+const PSCI_RETURN_ON: uintreg_t = 0;
+const PSCI_RETURN_SUCCESS: uintreg_t = 0;
+
+/// The `CPU_SUSPEND` features: no OS-initiated mode, but the extended StateID format.
+const PSCI_CPU_SUSPEND_FEATURES: uintreg_t = 0x2;
+
+/// The reserved bits of a power state in the extended StateID format.
+const POWER_STATE_RESERVED_MASK: uintreg_t = 0xb000_0000;
+
+/// PSCI error codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+enum PsciError {
+    NotSupported = -1,
+    InvalidParameters = -2,
+    Denied = -3,
+    AlreadyOn = -4,
+    InvalidAddress = -9,
+}
+
+impl PsciError {
+    fn to_reg(self) -> uintreg_t {
+        self as i32 as i64 as uintreg_t
+    }
+}
+
+extern "C" {
+    fn vcpu_sibling(vcpu: *mut CVCpu, index: u32) -> *mut CVCpu;
+    fn vcpu_is_off(locked: VCpuLocked) -> bool;
+    fn vcpu_secondary_reset_and_start(vcpu: *mut CVCpu, entry: IpaAddr, arg: uintreg_t) -> bool;
+
+    fn api_wait_for_interrupt(current: *mut CVCpu) -> *mut CVCpu;
+    fn api_vcpu_off(current: *mut CVCpu) -> *mut CVCpu;
+    fn api_wake_up(current: *mut CVCpu, target_vcpu: *mut CVCpu) -> *mut CVCpu;
+}
+
+/// Converts a PSCI target CPU to the index of a vCPU. For now, the IDs of vCPUs are their
+/// indices.
+fn vcpu_id_to_index(vcpu_id: uintreg_t) -> Option<u32> {
+    if vcpu_id > u32::max_value() as uintreg_t {
+        return None;
+    }
+
+    Some(vcpu_id as u32)
+}
+
+/// Finds the vCPU of the current VM with the given PSCI target CPU.
+unsafe fn find_vcpu(current: *mut CVCpu, target_cpu: uintreg_t) -> Result<*mut CVCpu, PsciError> {
+    let index = vcpu_id_to_index(target_cpu).ok_or(PsciError::InvalidParameters)?;
+    let vcpu = vcpu_sibling(current, index);
+    if vcpu.is_null() {
+        return Err(PsciError::InvalidParameters);
+    }
+
+    Ok(vcpu)
+}
+
+fn features(func: u32) -> Result<uintreg_t, PsciError> {
+    match func & !SMCCC_CONVENTION_MASK {
+        PSCI_CPU_SUSPEND => Ok(PSCI_CPU_SUSPEND_FEATURES),
+        PSCI_VERSION | PSCI_FEATURES | PSCI_AFFINITY_INFO | PSCI_CPU_OFF | PSCI_CPU_ON => Ok(0),
+        _ => Err(PsciError::NotSupported),
+    }
+}
+
+unsafe fn affinity_info(
+    current: *mut CVCpu,
+    target_affinity: uintreg_t,
+    lowest_affinity_level: u32,
+) -> Result<uintreg_t, PsciError> {
+    if lowest_affinity_level != 0 {
+        // Affinity levels greater than 0 not supported.
+        return Err(PsciError::InvalidParameters);
+    }
+
+    let target = find_vcpu(current, target_affinity)?;
+    let mut locked = vcpu_lock(target);
+    let is_off = vcpu_is_off(locked);
+    vcpu_unlock(&mut locked);
+
+    Ok(if is_off {
+        PSCI_RETURN_OFF
+    } else {
+        PSCI_RETURN_ON
+    })
+}
+
+unsafe fn cpu_suspend(
+    current: *mut CVCpu,
+    power_state: uintreg_t,
+    next: *mut *mut CVCpu,
+) -> Result<uintreg_t, PsciError> {
+    if power_state & POWER_STATE_RESERVED_MASK != 0 {
+        return Err(PsciError::InvalidParameters);
+    }
+
+    // Downgrade the suspend request to WFI and return SUCCESS, as allowed by the specification.
+    // Even for a power down state, the vCPU resumes after the call rather than at the entry point.
+    *next = api_wait_for_interrupt(current);
+    Ok(PSCI_RETURN_SUCCESS)
+}
+
+unsafe fn cpu_on(
+    current: *mut CVCpu,
+    target_cpu: uintreg_t,
+    entry_point_address: uintreg_t,
+    context_id: uintreg_t,
+    next: *mut *mut CVCpu,
+) -> Result<uintreg_t, PsciError> {
+    let target = find_vcpu(current, target_cpu)?;
+
+    // The entry point is the address of an A64 instruction.
+    if entry_point_address % 4 != 0 {
+        return Err(PsciError::InvalidAddress);
+    }
+
+    if !vcpu_secondary_reset_and_start(target, IpaAddr::new(entry_point_address), context_id) {
+        return Err(PsciError::AlreadyOn);
+    }
+
+    // Tell the scheduler that it can start running the new vCPU now.
+    *next = api_wake_up(current, target);
+    Ok(PSCI_RETURN_SUCCESS)
+}
+
+/// Handles a PSCI call from a secondary VM. Returns `None` if the call is not a PSCI call.
+unsafe fn handle(
+    current: *mut CVCpu,
+    func: u32,
+    arg0: uintreg_t,
+    arg1: uintreg_t,
+    arg2: uintreg_t,
+    next: *mut *mut CVCpu,
+) -> Option<Result<uintreg_t, PsciError>> {
+    let result = match func & !SMCCC_CONVENTION_MASK {
+        PSCI_VERSION => Ok(PSCI_VERSION_1_1),
+        PSCI_FEATURES => features(arg0 as u32),
+        PSCI_AFFINITY_INFO => affinity_info(current, arg0, arg1 as u32),
+        PSCI_CPU_SUSPEND => cpu_suspend(current, arg0, next),
+        PSCI_CPU_OFF => {
+            // Tell the scheduler not to run the vCPU again. The call should never return to the
+            // caller, but in case it somehow does, it is denied.
+            *next = api_vcpu_off(current);
+            Err(PsciError::Denied)
+        }
+        PSCI_CPU_ON => cpu_on(current, arg0, arg1, arg2, next),
+        // Block all other known PSCI calls.
+        f if f >= PSCI_VERSION && f <= PSCI_MEM_PROTECT_CHECK_RANGE => Err(PsciError::NotSupported),
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+/// Handles a PSCI call from a vCPU of a secondary VM. Returns whether the call was a PSCI call,
+/// in which case its return value is written to `ret` and the vCPU to run next, if it changes, to
+/// `next`.
+#[no_mangle]
+pub unsafe extern "C" fn psci_secondary_vm_handler(
+    vcpu: *mut CVCpu,
+    func: u32,
+    arg0: uintreg_t,
+    arg1: uintreg_t,
+    arg2: uintreg_t,
+    ret: *mut uintreg_t,
+    next: *mut *mut CVCpu,
+) -> bool {
+    match handle(vcpu, func, arg0, arg1, arg2, next) {
+        Some(result) => {
+            *ret = result.unwrap_or_else(PsciError::to_reg);
+            true
+        }
+        None => false,
+    }
+}
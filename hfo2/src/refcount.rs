@@ -0,0 +1,156 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Reference counting without allocation.
+//!
+//! A `RefCount<T>` embeds a count of the references to the object it holds, e.g. a VM in the VM
+//! table.  References are taken with `RefCount::get()` and released when dropped, like `Arc`s,
+//! but the object is not freed when the last reference is dropped.  Instead, its owner destroys it
+//! with `RefCount::kill()`, which succeeds only if there is no reference, and prevents new
+//! references from being taken until the object is revived.
+
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The count of an object that is destroyed or being destroyed.
+const DEAD: usize = usize::max_value();
+
+/// An object with an intrusive reference count.
+pub struct RefCount<T> {
+    /// The number of references, or `DEAD`.
+    count: AtomicUsize,
+    data: T,
+}
+
+impl<T> RefCount<T> {
+    /// Creates a live object with no reference.
+    pub const fn new(data: T) -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            data,
+        }
+    }
+
+    /// Creates a dead object, which is to be revived before references are taken.
+    pub const fn dead(data: T) -> Self {
+        Self {
+            count: AtomicUsize::new(DEAD),
+            data,
+        }
+    }
+
+    /// Takes a reference to the object. Returns `None` if the object is dead.
+    pub fn get(&self) -> Option<Ref<T>> {
+        let mut count = self.count.load(Ordering::Relaxed);
+        loop {
+            if count == DEAD {
+                return None;
+            }
+
+            assert!(count < DEAD - 1, "reference count overflow");
+            match self.count.compare_exchange_weak(
+                count,
+                count + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(Ref { inner: self }),
+                Err(c) => count = c,
+            }
+        }
+    }
+
+    /// Returns the number of references, or `None` if the object is dead. It may change
+    /// concurrently, so it is meant for diagnostics.
+    pub fn count(&self) -> Option<usize> {
+        match self.count.load(Ordering::Relaxed) {
+            DEAD => None,
+            count => Some(count),
+        }
+    }
+
+    /// Returns whether the object is dead.
+    pub fn is_dead(&self) -> bool {
+        self.count.load(Ordering::Relaxed) == DEAD
+    }
+
+    /// Kills the object so that no reference is taken anymore, and returns the object to destroy
+    /// it. Fails if there are references to the object or it is already dead.
+    pub fn kill(&self) -> Option<&T> {
+        self.count
+            .compare_exchange(0, DEAD, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| &self.data)
+    }
+
+    /// Revives a dead object, e.g. after reinitialising it, so that references may be taken again.
+    pub fn revive(&self) {
+        let result = self
+            .count
+            .compare_exchange(DEAD, 0, Ordering::Release, Ordering::Relaxed);
+        assert!(result.is_ok(), "reviving a live object");
+    }
+
+    /// Returns a pointer to the object, without taking a reference.
+    pub fn as_ptr(&self) -> *const T {
+        &self.data
+    }
+
+    /// Returns the object. No reference is taken, as there may not be any other.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+}
+
+/// A reference to an object with an intrusive reference count. The object is kept alive while
+/// the reference exists.
+pub struct Ref<'a, T> {
+    inner: &'a RefCount<T>,
+}
+
+impl<'a, T> Ref<'a, T> {
+    /// Returns the reference count of the object.
+    pub fn ref_count(this: &Self) -> &'a RefCount<T> {
+        this.inner
+    }
+}
+
+impl<'a, T> Clone for Ref<'a, T> {
+    fn clone(&self) -> Self {
+        // The object is not dead as this reference exists.
+        let count = self.inner.count.fetch_add(1, Ordering::Relaxed);
+        assert!(count < DEAD - 1, "reference count overflow");
+        Self { inner: self.inner }
+    }
+}
+
+impl<'a, T> Drop for Ref<'a, T> {
+    fn drop(&mut self) {
+        self.inner.count.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for Ref<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner.data
+    }
+}
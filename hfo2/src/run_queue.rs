@@ -0,0 +1,212 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Priority-aware run queues of vCPUs.
+//!
+//! Each VM has a priority class, read from `priorities.txt` in the RAM disk, which has an entry
+//! `<kernel-filename> <class>` per VM, where the class is one of `HF_PRIORITY_*` in
+//! `inc/hf/run_queue.h`.  A VM without any entry is of the normal class.
+//!
+//! Each physical CPU has a run queue of the vCPUs which were woken up for the primary VM's vCPU on
+//! that CPU to run, e.g. because an interrupt was injected into them.  The queue hands them out
+//! highest class first, and in the order they were woken up within a class, so that a
+//! latency-sensitive VM is not starved by others woken up at the same time.  A vCPU leaves the
+//! queues once it is run.
+
+use crate::cpio;
+use crate::memiter::MemIter;
+use crate::spinlock::IrqSpinLock;
+use crate::types::*;
+
+/// The priority class of a VM, as `HF_PRIORITY_*`. Lower values are of higher priority.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(u32)]
+pub enum PriorityClass {
+    /// Latency-sensitive VMs, which are run before any other.
+    High = 0,
+
+    /// The class of a VM without an entry in `priorities.txt`.
+    Normal = 1,
+
+    /// Background VMs, which are run when no other is waiting.
+    Low = 2,
+}
+
+/// The number of priority classes.
+const PRIORITY_CLASSES: usize = 3;
+
+impl PriorityClass {
+    fn from_u64(class: u64) -> Option<Self> {
+        match class {
+            0 => Some(PriorityClass::High),
+            1 => Some(PriorityClass::Normal),
+            2 => Some(PriorityClass::Low),
+            _ => None,
+        }
+    }
+}
+
+/// The maximum number of vCPUs in a class of a run queue. vCPUs woken up when it is full are not
+/// queued, in which case the primary VM only learns about them from the return value of the
+/// hypercall that woke them.
+const RUN_QUEUE_LEN: usize = 16;
+
+/// A vCPU in a run queue, encoded as `vm_id << 16 | vcpu_idx`.
+type Entry = u32;
+
+fn entry(vm_id: u16, vcpu_idx: u16) -> Entry {
+    u32::from(vm_id) << 16 | u32::from(vcpu_idx)
+}
+
+/// The vCPUs of a class woken up on a physical CPU, in the order they were woken up.
+#[derive(Clone, Copy)]
+struct Class {
+    entries: [Entry; RUN_QUEUE_LEN],
+    len: usize,
+}
+
+impl Class {
+    const fn new() -> Self {
+        Self {
+            entries: [0; RUN_QUEUE_LEN],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, entry: Entry) {
+        if self.entries[..self.len].contains(&entry) || self.len == RUN_QUEUE_LEN {
+            return;
+        }
+
+        self.entries[self.len] = entry;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Entry> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let entry = self.entries[0];
+        self.remove_at(0);
+        Some(entry)
+    }
+
+    fn remove(&mut self, entry: Entry) {
+        if let Some(i) = self.entries[..self.len].iter().position(|e| *e == entry) {
+            self.remove_at(i);
+        }
+    }
+
+    fn remove_at(&mut self, i: usize) {
+        self.entries.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+    }
+}
+
+/// The run queue of a physical CPU, with a queue per priority class.
+#[derive(Clone, Copy)]
+struct RunQueue {
+    classes: [Class; PRIORITY_CLASSES],
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            classes: [Class::new(); PRIORITY_CLASSES],
+        }
+    }
+}
+
+/// The run queues of all physical CPUs, indexed by `cpu_index`. The lock is taken after any VM or
+/// vCPU lock, and no other lock is taken while holding it. vCPUs are woken up from interrupt
+/// handlers too, so local interrupts are disabled while it is held.
+static RUN_QUEUES: IrqSpinLock<[RunQueue; MAX_CPUS]> =
+    IrqSpinLock::new([RunQueue::new(); MAX_CPUS]);
+
+/// Parses the priority class of the given VM from the entries of `priorities.txt`. Fails if an
+/// entry is malformed.
+unsafe fn parse(it: &mut MemIter, name: &[u8]) -> Result<PriorityClass, ()> {
+    let mut priority = PriorityClass::Normal;
+
+    while let Some(entry_name) = it.parse_str() {
+        let class = it
+            .parse_uint()
+            .and_then(PriorityClass::from_u64)
+            .ok_or(())?;
+
+        if entry_name.as_slice() == name {
+            priority = class;
+        }
+    }
+
+    Ok(priority)
+}
+
+/// Loads the priority class of the VM whose kernel has the given name from the RAM disk to
+/// `priority`. Returns false if `priorities.txt` is malformed.
+#[no_mangle]
+pub unsafe extern "C" fn priority_load(
+    cpio: *const MemIter,
+    name: *const MemIter,
+    priority: *mut u32,
+) -> bool {
+    let mut cpio = (*cpio).clone();
+    let loaded = match cpio::find_file(&mut cpio, "priorities.txt\0".as_ptr()) {
+        Some(mut it) => match parse(&mut it, (*name).as_slice()) {
+            Ok(loaded) => loaded,
+            Err(()) => return false,
+        },
+        None => PriorityClass::Normal,
+    };
+
+    *priority = loaded as u32;
+    true
+}
+
+/// Queues the vCPU, of a VM of the given priority class, to be run by the primary VM on the given
+/// physical CPU. It is queued at most once per CPU.
+#[no_mangle]
+pub extern "C" fn run_queue_push(cpu_index: usize, vm_id: u16, vcpu_idx: u16, priority: u32) {
+    let class = PriorityClass::from_u64(u64::from(priority)).unwrap_or(PriorityClass::Normal);
+    RUN_QUEUES.lock()[cpu_index].classes[class as usize].push(entry(vm_id, vcpu_idx));
+}
+
+/// Takes the vCPU the primary VM should run next on the given physical CPU, and returns it as
+/// `vm_id << 16 | vcpu_idx`, or -1 if the run queue is empty.
+#[no_mangle]
+pub extern "C" fn run_queue_pop(cpu_index: usize) -> i64 {
+    let mut queues = RUN_QUEUES.lock();
+
+    queues[cpu_index]
+        .classes
+        .iter_mut()
+        .filter_map(Class::pop)
+        .next()
+        .map_or(-1, i64::from)
+}
+
+/// Removes the vCPU from the run queues of all physical CPUs, e.g. because it is being run.
+#[no_mangle]
+pub extern "C" fn run_queue_remove(vm_id: u16, vcpu_idx: u16) {
+    let mut queues = RUN_QUEUES.lock();
+
+    for queue in queues.iter_mut() {
+        for class in queue.classes.iter_mut() {
+            class.remove(entry(vm_id, vcpu_idx));
+        }
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # SMC forwarding allowlist.
+//!
+//! SMCs that Hafnium does not handle itself are forwarded to EL3 only if their function ID is in
+//! one of the ranges allowed for the calling VM, so that VMs cannot invoke platform firmware
+//! services they were not granted.  The ranges are read from `smc.txt` in the RAM disk, which has
+//! an entry `<kernel-filename> <first-function-id> <last-function-id>` per range.  A VM without
+//! any entry is not allowed to forward any SMC.
+//!
+//! The allowlists are written only when the VMs are loaded but read on every SMC trap, so they
+//! are kept in a `SeqLock` and the trap does not take a lock.
+
+use crate::cpio;
+use crate::memiter::MemIter;
+use crate::spinlock::SeqLock;
+use crate::types::*;
+
+/// The maximum number of function ID ranges allowed for a VM.
+const MAX_SMC_RANGES: usize = 8;
+
+/// An inclusive range of SMC function IDs.
+#[derive(Clone, Copy)]
+struct SmcRange {
+    first: u32,
+    last: u32,
+}
+
+impl SmcRange {
+    fn contains(&self, func: u32) -> bool {
+        self.first <= func && func <= self.last
+    }
+}
+
+/// The function ID ranges allowed for a VM.
+#[derive(Clone, Copy)]
+struct SmcAllowlist {
+    ranges: [SmcRange; MAX_SMC_RANGES],
+    count: usize,
+}
+
+impl SmcAllowlist {
+    const fn new() -> Self {
+        Self {
+            ranges: [SmcRange { first: 0, last: 0 }; MAX_SMC_RANGES],
+            count: 0,
+        }
+    }
+
+    fn ranges(&self) -> &[SmcRange] {
+        &self.ranges[..self.count]
+    }
+
+    fn push(&mut self, range: SmcRange) -> Result<(), ()> {
+        if self.count >= MAX_SMC_RANGES {
+            return Err(());
+        }
+
+        self.ranges[self.count] = range;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn allows(&self, func: u32) -> bool {
+        self.ranges().iter().any(|range| range.contains(func))
+    }
+}
+
+static ALLOWLISTS: SeqLock<[SmcAllowlist; MAX_VMS]> =
+    SeqLock::new([SmcAllowlist::new(); MAX_VMS]);
+
+/// Parses the ranges of the given VM from the entries of `smc.txt`. Fails if an entry is
+/// malformed or there are too many ranges for the VM.
+unsafe fn parse(it: &mut MemIter, name: &[u8]) -> Result<SmcAllowlist, ()> {
+    let mut allowlist = SmcAllowlist::new();
+
+    while let Some(entry_name) = it.parse_str() {
+        let first = it.parse_uint().ok_or(())?;
+        let last = it.parse_uint().ok_or(())?;
+        if first > last || last > u64::from(u32::max_value()) {
+            return Err(());
+        }
+
+        if entry_name.as_slice() == name {
+            allowlist.push(SmcRange {
+                first: first as u32,
+                last: last as u32,
+            })?;
+        }
+    }
+
+    Ok(allowlist)
+}
+
+/// Loads the allowlist of the given VM, whose kernel has the given name, from the RAM disk.
+/// Returns false if `smc.txt` is malformed.
+#[no_mangle]
+pub unsafe extern "C" fn smc_allowlist_load(
+    vm_id: u16,
+    cpio: *const MemIter,
+    name: *const MemIter,
+) -> bool {
+    let mut cpio = (*cpio).clone();
+    let allowlist = match cpio::find_file(&mut cpio, "smc.txt\0".as_ptr()) {
+        Some(mut it) => match parse(&mut it, (*name).as_slice()) {
+            Ok(allowlist) => allowlist,
+            Err(()) => return false,
+        },
+        None => SmcAllowlist::new(),
+    };
+
+    match ALLOWLISTS.write().get_mut(vm_id as usize) {
+        Some(entry) => {
+            *entry = allowlist;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns whether the given VM may forward an SMC with the given function ID to EL3.
+#[no_mangle]
+pub unsafe extern "C" fn smc_is_allowed(vm_id: u16, func: u32) -> bool {
+    let index = vm_id as usize;
+    if index >= MAX_VMS {
+        return false;
+    }
+
+    // Only the allowlist of the VM is copied, not the whole table.
+    ALLOWLISTS
+        .read_part(|allowlists| (allowlists as *const SmcAllowlist).add(index))
+        .allows(func)
+}
@@ -16,26 +16,69 @@
 
 use core::cell::UnsafeCell;
 use core::marker::PhantomData;
+use core::mem::{self, ManuallyDrop};
 use core::ops::{Deref, DerefMut};
 use core::ptr;
-use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+use core::sync::atomic::{fence, spin_loop_hint, AtomicU32, AtomicUsize, Ordering};
 
+#[cfg(feature = "lockdep")]
+use crate::lockdep;
+#[cfg(feature = "lock_stats")]
+use crate::lockstat;
+use crate::types::*;
+use crate::utils::*;
+
+extern "C" {
+    fn arch_irq_save() -> uintreg_t;
+    fn arch_irq_restore(state: uintreg_t);
+}
+
+/// A ticket lock. A CPU takes a ticket when it starts waiting for the lock, and the lock is handed
+/// over in the order of the tickets, so that no CPU starves under contention.
 #[repr(C)]
 pub struct RawSpinLock {
-    inner: AtomicBool,
+    /// The ticket to be taken next.
+    next: AtomicU32,
+
+    /// The ticket of the CPU holding the lock, or to be served next if the lock is free.
+    owner: AtomicU32,
 }
 
 impl RawSpinLock {
     pub const fn new() -> Self {
         Self {
-            inner: AtomicBool::new(false),
+            next: AtomicU32::new(0),
+            owner: AtomicU32::new(0),
         }
     }
 
     pub fn lock(&self) {
-        while self.inner.swap(true, Ordering::Acquire) {
-            spin_loop_hint();
+        self.lock_spins();
+    }
+
+    /// Acquires the lock, and returns the number of iterations spun waiting for it.
+    pub fn lock_spins(&self) -> usize {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut spins = 0;
+        let mut backoff = Backoff::new();
+        while self.owner.load(Ordering::Acquire) != ticket {
+            backoff.snooze();
+            spins += 1;
         }
+        spins
+    }
+
+    /// Acquires the lock if it is free, without spinning. Returns whether it is acquired.
+    pub fn try_lock(&self) -> bool {
+        let owner = self.owner.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(
+                owner,
+                owner.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
     }
 
     pub fn lock_both(lhs: &Self, rhs: &Self) {
@@ -49,7 +92,12 @@ impl RawSpinLock {
     }
 
     pub fn unlock(&self) {
-        self.inner.store(false, Ordering::Release);
+        // Only the CPU holding the lock updates the owner.
+        let owner = self.owner.load(Ordering::Relaxed);
+        self.owner.store(owner.wrapping_add(1), Ordering::Release);
+
+        // Wakes up the waiters that stopped spinning.
+        send_event();
     }
 }
 
@@ -74,14 +122,86 @@ impl<T> SpinLock<T> {
         self.data.into_inner()
     }
 
+    /// Returns the name of the class of the lock, i.e. the type of the data it protects.
+    #[cfg(any(feature = "lockdep", feature = "lock_stats"))]
+    fn class_name() -> &'static str {
+        unsafe { core::intrinsics::type_name::<T>() }
+    }
+
+    /// Records the acquisition of the lock for lock order checking. See `lockdep`.
+    #[cfg(feature = "lockdep")]
+    fn track_acquire(&self, check: bool) {
+        lockdep::acquire(Self::class_name(), self as *const _ as usize, check);
+    }
+
+    #[cfg(not(feature = "lockdep"))]
+    fn track_acquire(&self, _check: bool) {}
+
+    /// Counts an acquisition of the lock that spun `spins` iterations. See `lockstat`.
+    #[cfg(feature = "lock_stats")]
+    fn count_acquire(&self, spins: usize) {
+        lockstat::record(Self::class_name(), spins);
+    }
+
+    #[cfg(not(feature = "lock_stats"))]
+    fn count_acquire(&self, _spins: usize) {}
+
+    /// Acquires the raw lock, recording the acquisition.
+    fn acquire(&self) {
+        self.track_acquire(true);
+        let spins = self.lock.lock_spins();
+        self.count_acquire(spins);
+    }
+
     pub fn lock<'s>(&'s self) -> SpinLockGuard<'s, T> {
-        self.lock.lock();
+        self.acquire();
         SpinLockGuard {
             lock: self,
             _marker: PhantomData,
         }
     }
 
+    /// Acquires the lock if it is free, or returns `None` without spinning if it is held, e.g. so
+    /// that a caller holding another lock can back off instead of risking a deadlock.
+    pub fn try_lock<'s>(&'s self) -> Option<SpinLockGuard<'s, T>> {
+        if !self.lock.try_lock() {
+            return None;
+        }
+        self.track_acquire(false);
+        self.count_acquire(0);
+
+        Some(SpinLockGuard {
+            lock: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Acquires both locks, enforcing the lowest address first ordering for locks of the same
+    /// kind, and returns the guards in the order of the arguments. The locks should be distinct.
+    pub fn lock_both<'s>(
+        lhs: &'s Self,
+        rhs: &'s Self,
+    ) -> (SpinLockGuard<'s, T>, SpinLockGuard<'s, T>) {
+        assert!(!ptr::eq(lhs, rhs), "locking the same lock twice");
+        if (lhs as *const _) < (rhs as *const _) {
+            lhs.acquire();
+            rhs.acquire();
+        } else {
+            rhs.acquire();
+            lhs.acquire();
+        }
+        (
+            SpinLockGuard {
+                lock: lhs,
+                _marker: PhantomData,
+            },
+            SpinLockGuard {
+                lock: rhs,
+                _marker: PhantomData,
+            },
+        )
+    }
+
     pub fn get_mut(&mut self) -> &mut T {
         unsafe { &mut *self.data.get() }
     }
@@ -101,10 +221,67 @@ unsafe impl<'s, T: Send + Sync> Sync for SpinLockGuard<'s, T> {}
 
 impl<'s, T> Drop for SpinLockGuard<'s, T> {
     fn drop(&mut self) {
-        self.lock.lock.unlock();
+        release(&self.lock.lock);
     }
 }
 
+impl<'s, T> SpinLockGuard<'s, T> {
+    /// Narrows the guard to a part of the protected data, e.g. a field, so that it can be passed
+    /// to a function without giving it access to the rest. The lock is held until the returned
+    /// guard is dropped.
+    pub fn map<U, F>(guard: Self, f: F) -> MappedSpinLockGuard<'s, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let data = f(unsafe { &mut *guard.lock.data.get() }) as *mut U;
+        let lock = &guard.lock.lock;
+        mem::forget(guard);
+        MappedSpinLockGuard {
+            lock,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// A guard of a part of the data protected by a `SpinLock`. See `SpinLockGuard::map()`.
+pub struct MappedSpinLockGuard<'s, T> {
+    lock: &'s RawSpinLock,
+    data: *mut T,
+    _marker: PhantomData<(&'s mut T, *const ())>, // !Send + !Sync
+}
+
+unsafe impl<'s, T: Send> Send for MappedSpinLockGuard<'s, T> {}
+unsafe impl<'s, T: Send + Sync> Sync for MappedSpinLockGuard<'s, T> {}
+
+impl<'s, T> Drop for MappedSpinLockGuard<'s, T> {
+    fn drop(&mut self) {
+        release(self.lock);
+    }
+}
+
+impl<'s, T> Deref for MappedSpinLockGuard<'s, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'s, T> DerefMut for MappedSpinLockGuard<'s, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.data }
+    }
+}
+
+/// Releases the raw lock of a `SpinLock`, recording the release for lock order checking. The
+/// raw lock is the first field of the `SpinLock`, so they have the same address.
+fn release(lock: &RawSpinLock) {
+    lock.unlock();
+    #[cfg(feature = "lockdep")]
+    lockdep::release(lock as *const _ as usize);
+}
+
 impl<'s, T> Deref for SpinLockGuard<'s, T> {
     type Target = T;
 
@@ -119,6 +296,186 @@ impl<'s, T> DerefMut for SpinLockGuard<'s, T> {
     }
 }
 
+/// A spinlock that disables local interrupts while it is held, so that it is safe to acquire it
+/// both in interrupt handlers and in the code they interrupt on the same CPU.
+pub struct IrqSpinLock<T> {
+    inner: SpinLock<T>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: SpinLock::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+
+    pub fn lock<'s>(&'s self) -> IrqSpinLockGuard<'s, T> {
+        let irq = unsafe { arch_irq_save() };
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            irq,
+        }
+    }
+
+    /// Acquires the lock if it is free, or returns `None` without spinning if it is held. The
+    /// interrupt state is left intact in the latter case.
+    pub fn try_lock<'s>(&'s self) -> Option<IrqSpinLockGuard<'s, T>> {
+        let irq = unsafe { arch_irq_save() };
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinLockGuard {
+                guard: ManuallyDrop::new(guard),
+                irq,
+            }),
+            None => {
+                unsafe { arch_irq_restore(irq) };
+                None
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+pub struct IrqSpinLockGuard<'s, T> {
+    guard: ManuallyDrop<SpinLockGuard<'s, T>>,
+
+    /// The interrupt state before the lock is acquired.
+    irq: uintreg_t,
+}
+
+impl<'s, T> Drop for IrqSpinLockGuard<'s, T> {
+    fn drop(&mut self) {
+        // Releases the lock before interrupts are restored, so that an interrupt handler on this
+        // CPU does not spin for it.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            arch_irq_restore(self.irq);
+        }
+    }
+}
+
+impl<'s, T> Deref for IrqSpinLockGuard<'s, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'s, T> DerefMut for IrqSpinLockGuard<'s, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+/// A sequence lock for small read-mostly data, e.g. configuration read on hot paths. Readers do
+/// not write to the lock: they copy the data, and retry if a writer updated it in the meantime.
+/// Writers are serialised by a spinlock.
+pub struct SeqLock<T: Copy> {
+    /// The sequence number, which is odd while the data is being updated.
+    seq: AtomicUsize,
+    lock: RawSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            lock: RawSpinLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Reads a consistent copy of the data.
+    pub fn read(&self) -> T {
+        unsafe { self.read_part(|data| data) }
+    }
+
+    /// Reads a consistent copy of the part of the data `f` points to, e.g. an element of an array,
+    /// without copying the rest.
+    ///
+    /// # Safety
+    ///
+    /// `f` should only offset the given pointer to the data, and return a pointer into the data.
+    /// It should not read the data, which may be torn by a concurrent writer.
+    pub unsafe fn read_part<U: Copy, F: Fn(*const T) -> *const U>(&self, f: F) -> U {
+        let part = f(self.data.get() as *const T);
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 != 0 {
+                spin_loop_hint();
+                continue;
+            }
+
+            // The data may be torn by a concurrent writer, in which case the copy is discarded.
+            let data = ptr::read_volatile(part);
+
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return data;
+            }
+        }
+    }
+
+    /// Acquires the lock to update the data. Readers retry until the returned guard is dropped.
+    pub fn write<'s>(&'s self) -> SeqLockWriteGuard<'s, T> {
+        self.lock.lock();
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        SeqLockWriteGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Replaces the data.
+    pub fn set(&self, data: T) {
+        *self.write() = data;
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+pub struct SeqLockWriteGuard<'s, T: Copy> {
+    lock: &'s SeqLock<T>,
+    _marker: PhantomData<*const ()>, // !Send + !Sync
+}
+
+impl<'s, T: Copy> Drop for SeqLockWriteGuard<'s, T> {
+    fn drop(&mut self) {
+        let seq = self.lock.seq.load(Ordering::Relaxed);
+        self.lock.seq.store(seq.wrapping_add(1), Ordering::Release);
+        self.lock.lock.unlock();
+    }
+}
+
+impl<'s, T: Copy> Deref for SeqLockWriteGuard<'s, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'s, T: Copy> DerefMut for SeqLockWriteGuard<'s, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn sl_init(l: *mut RawSpinLock) {
     ptr::write(l, RawSpinLock::new());
@@ -129,6 +486,12 @@ pub unsafe extern "C" fn sl_lock(l: *const RawSpinLock) {
     (*l).lock();
 }
 
+/// Acquires the lock if it is free. Returns whether it is acquired.
+#[no_mangle]
+pub unsafe extern "C" fn sl_try_lock(l: *const RawSpinLock) -> bool {
+    (*l).try_lock()
+}
+
 /// Locks both locks, enforcing the lowest address first ordering for locks of the same kind.
 #[no_mangle]
 pub unsafe extern "C" fn sl_lock_both(a: *const RawSpinLock, b: *const RawSpinLock) {
@@ -0,0 +1,110 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Trap regions of emulated devices.
+//!
+//! A trap region is a range of intermediate physical addresses that is not mapped in the VM's
+//! stage-2 page table, but emulated, e.g. as the registers of a virtual device.  Accesses to it
+//! fault, and the fault handler looks up the region to forward the access to its owner instead of
+//! treating it as a genuine fault.
+
+use arrayvec::ArrayVec;
+
+use crate::mm::*;
+use crate::page::*;
+use crate::types::*;
+
+/// The maximum number of trap regions of a VM.
+pub const TRAP_REGIONS_MAX: usize = 16;
+
+/// A range of intermediate physical addresses emulated by its owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapRegion {
+    pub begin: IpaAddr,
+    pub end: IpaAddr,
+
+    /// The tag of the emulator of the region, e.g. a virtual device.
+    pub owner: u32,
+}
+
+/// The trap regions of a VM.
+pub struct TrapTable {
+    regions: ArrayVec<[TrapRegion; TRAP_REGIONS_MAX]>,
+}
+
+impl TrapTable {
+    pub fn new() -> Self {
+        Self {
+            regions: ArrayVec::new(),
+        }
+    }
+
+    /// Registers `[begin, end)` as a trap region emulated by `owner`. The range is aligned to
+    /// pages.
+    ///
+    /// Fails with `MmError::Overlap` if the range overlaps with another trap region or is mapped
+    /// in the page table, including memory the VM owns but lent to another VM, and with
+    /// `MmError::TooManyRanges` if there are already `TRAP_REGIONS_MAX` trap regions.
+    pub fn register(
+        &mut self,
+        ptable: &PageTable<Stage2>,
+        begin: IpaAddr,
+        end: IpaAddr,
+        owner: u32,
+    ) -> Result<(), MmError> {
+        let begin = IpaAddr::new(begin.addr() & !(PAGE_SIZE - 1));
+        let end = IpaAddr::new((end.addr() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+
+        if begin >= end {
+            return Err(MmError::OutOfRange);
+        }
+
+        if self
+            .regions
+            .iter()
+            .any(|region| region.begin < end && begin < region.end)
+        {
+            return Err(MmError::Overlap);
+        }
+
+        let mapped = ptable
+            .get_modes(begin, end)?
+            .any(|segment| !segment.mode.contains(Mode::INVALID | Mode::UNOWNED));
+        if mapped {
+            return Err(MmError::Overlap);
+        }
+
+        self.regions
+            .try_push(TrapRegion { begin, end, owner })
+            .map_err(|_| MmError::TooManyRanges)
+    }
+
+    /// Unregisters the trap region containing the given address, and returns it.
+    pub fn unregister(&mut self, ipa: IpaAddr) -> Option<TrapRegion> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.begin <= ipa && ipa < region.end)?;
+        Some(self.regions.swap_remove(index))
+    }
+
+    /// Looks up the trap region containing the given address.
+    pub fn lookup(&self, ipa: IpaAddr) -> Option<&TrapRegion> {
+        self.regions
+            .iter()
+            .find(|region| region.begin <= ipa && ipa < region.end)
+    }
+}
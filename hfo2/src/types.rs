@@ -17,6 +17,7 @@
 #![allow(non_camel_case_types)]
 
 use core::ffi;
+use core::ops::{Add, AddAssign, Sub};
 
 use crate::page::*;
 
@@ -36,3 +37,177 @@ pub const MAX_CPUS: usize = 32;
 pub const MAX_VMS: usize = 128;
 
 pub const HF_MAILBOX_SIZE: usize = PAGE_SIZE;
+
+/// The ID of the primary VM.
+pub const HF_PRIMARY_VM_ID: VmId = VmId(0);
+
+/// The ID of a VM, below `MAX_VMS`. It has the same representation as `spci_vm_id_t`.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VmId(u16);
+
+impl VmId {
+    /// Checks that the ID is below `MAX_VMS`, e.g. when it is given by a VM.
+    pub fn new(id: u16) -> Option<Self> {
+        if usize::from(id) < MAX_VMS {
+            Some(VmId(id))
+        } else {
+            None
+        }
+    }
+
+    pub const fn id(self) -> u16 {
+        self.0
+    }
+
+    /// Returns the ID as an index into arrays of `MAX_VMS` entries.
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// The index of a vCPU in its VM, below the VM's vCPU count. It has the same representation as the
+/// `uint32_t` vCPU indices of the C code.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VCpuIndex(u32);
+
+impl VCpuIndex {
+    /// Checks that the index is below the vCPU count of its VM, e.g. when it is given by a VM.
+    pub fn new(index: u32, vcpu_count: u32) -> Option<Self> {
+        if index < vcpu_count {
+            Some(VCpuIndex(index))
+        } else {
+            None
+        }
+    }
+
+    pub const fn index(self) -> u32 {
+        self.0
+    }
+}
+
+/// An address in one of the address spaces: physical, intermediate physical, or virtual.
+pub trait Address: Copy + Ord + Add<usize, Output = Self> + Sub<Self, Output = usize> {
+    /// Initializes an address.
+    fn new(addr: usize) -> Self;
+
+    /// Extracts the absolute address.
+    fn addr(self) -> usize;
+}
+
+/// Raw addresses, used inside the page table code below the typed interfaces.
+impl Address for usize {
+    fn new(addr: usize) -> Self {
+        addr
+    }
+
+    fn addr(self) -> usize {
+        self
+    }
+}
+
+macro_rules! address {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[repr(transparent)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+        pub struct $name(usize);
+
+        impl $name {
+            pub const fn new(addr: usize) -> Self {
+                $name(addr)
+            }
+
+            pub const fn addr(self) -> usize {
+                self.0
+            }
+        }
+
+        impl Address for $name {
+            fn new(addr: usize) -> Self {
+                $name(addr)
+            }
+
+            fn addr(self) -> usize {
+                self.0
+            }
+        }
+
+        /// Advances the address.
+        impl Add<usize> for $name {
+            type Output = Self;
+
+            fn add(self, n: usize) -> Self {
+                $name(self.0 + n)
+            }
+        }
+
+        impl AddAssign<usize> for $name {
+            fn add_assign(&mut self, n: usize) {
+                self.0 += n;
+            }
+        }
+
+        /// Returns the difference between two addresses.
+        impl Sub<$name> for $name {
+            type Output = usize;
+
+            fn sub(self, start: Self) -> usize {
+                self.0 - start.0
+            }
+        }
+    };
+}
+
+address! {
+    /// A physical address. It has the same representation as `paddr_t`.
+    PhysAddr
+}
+
+address! {
+    /// An intermediate physical address. It has the same representation as `ipaddr_t`.
+    IpaAddr
+}
+
+address! {
+    /// A virtual address. It has the same representation as `vaddr_t`.
+    VirtAddr
+}
+
+impl PhysAddr {
+    /// Casts a virtual address to a physical address.
+    pub const fn from_va(va: VirtAddr) -> Self {
+        Self::new(va.addr())
+    }
+
+    /// Casts an intermediate physical address to a physical address.
+    pub const fn from_ipa(ipa: IpaAddr) -> Self {
+        Self::new(ipa.addr())
+    }
+}
+
+impl IpaAddr {
+    /// Casts a physical address to an intermediate physical address.
+    pub const fn from_pa(pa: PhysAddr) -> Self {
+        Self::new(pa.addr())
+    }
+}
+
+impl VirtAddr {
+    /// Casts a physical address to a virtual address.
+    pub const fn from_pa(pa: PhysAddr) -> Self {
+        Self::new(pa.addr())
+    }
+
+    /// Casts a pointer to a virtual address.
+    pub fn from_ptr<T>(p: *const T) -> Self {
+        Self::new(p as usize)
+    }
+
+    /// Casts a virtual address to a pointer. Only use when the virtual address is mapped for the
+    /// calling context.
+    pub fn as_ptr<T>(self) -> *mut T {
+        self.addr() as *mut T
+    }
+}
@@ -26,9 +26,76 @@ macro_rules! some_or_return {
     }};
 }
 
+/// Parks the CPU forever.
 pub fn spin_loop() -> ! {
     loop {
-        spin_loop_hint();
+        wait_for_event();
+    }
+}
+
+/// Waits for an event sent by `send_event()` on another CPU, or an interrupt. It may also return
+/// spuriously, so the condition waited for should be checked in a loop.
+#[inline]
+pub fn wait_for_event() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("wfe" : : : "memory" : "volatile");
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    spin_loop_hint();
+}
+
+/// Wakes up the CPUs waiting in `wait_for_event()`. Memory writes before it are visible to them
+/// when they wake up.
+#[inline]
+pub fn send_event() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        crate::barriers::dsb_ishst();
+        asm!("sev" : : : "memory" : "volatile");
+    }
+}
+
+/// The number of steps the spinning time of `Backoff` doubles for.
+const BACKOFF_SPIN_LIMIT: u32 = 6;
+
+/// Exponential backoff for spin loops.
+pub struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    pub const fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    /// Backs off in a loop retrying an operation that failed due to contention, e.g. a
+    /// compare-and-swap. It spins twice as long as the previous time, up to a limit.
+    pub fn spin(&mut self) {
+        let step = if self.step < BACKOFF_SPIN_LIMIT {
+            self.step
+        } else {
+            BACKOFF_SPIN_LIMIT
+        };
+        for _ in 0..1 << step {
+            spin_loop_hint();
+        }
+
+        if self.step <= BACKOFF_SPIN_LIMIT {
+            self.step += 1;
+        }
+    }
+
+    /// Backs off in a loop waiting for another CPU to make progress, e.g. to release a lock. It
+    /// spins at first, and then waits for an event once it spun long enough, so the other CPU
+    /// should call `send_event()` when it makes progress.
+    pub fn snooze(&mut self) {
+        if self.step <= BACKOFF_SPIN_LIMIT {
+            self.spin();
+        } else {
+            wait_for_event();
+        }
     }
 }
 
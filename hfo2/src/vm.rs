@@ -14,107 +14,327 @@
  * limitations under the License.
  */
 
+use core::cell::Cell;
 use core::ptr;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, Ordering};
 use arrayvec::ArrayVec;
 
 use crate::cpu::*;
+use crate::dirty::*;
+use crate::guest::*;
 use crate::list::*;
+use crate::mailbox::*;
 use crate::mm::*;
 use crate::mpool::*;
+use crate::refcount::*;
 use crate::spinlock::*;
+use crate::trap::*;
 use crate::types::*;
+use crate::vmid::*;
 
-pub enum MailboxState {
-    /// There is no message in the mailbox.
-    Empty,
-
-    /// There is a message in the mailbox that is waiting for a reader.
-    Received,
-
-    /// There is a message in the mailbox that has been read.
-    Read,
-}
+pub mod snapshot;
 
 // TODO(@jeehoonkang)
 struct SpciMessage {}
 
 pub struct WaitEntry {
     /// The VM that is waiting for a mailbox to become writable.
-    waiting_vm: *const Vm,
-
-    /// Links used to add entry to a VM's waiter_list. This is protected by the notifying VM's lock.
-    wait_links: ListEntry,
+    waiting_vm: Cell<*const Vm>,
 
-    /// Links used to add entry to a VM's ready_list. This is protected by the waiting VM's lock.
-    ready_links: ListEntry,
+    /// Links the entry to the waiter list of the VM whose mailbox it waits for, or to the ready
+    /// list of the waiting VM. As there is only one link, the entry is in at most one list. The
+    /// link is protected by the lock of the VM whose list it is in.
+    links: Link,
 }
 
 impl Default for WaitEntry {
     fn default() -> Self {
         Self {
-            waiting_vm: ptr::null(),
-            wait_links: ListEntry::default(),
-            ready_links: ListEntry::default(),
+            waiting_vm: Cell::new(ptr::null()),
+            links: Link::default(),
         }
     }
 }
 
+impl WaitEntry {
+    /// Returns the VM that is waiting.
+    fn waiting_vm(&self) -> &Vm {
+        unsafe { &*self.waiting_vm.get() }
+    }
+
+    /// Returns the ID of the VM whose mailbox the entry waits for, i.e. the index of the entry in
+    /// the waiting VM's `wait_entries`.
+    pub fn target(&self) -> VmId {
+        let entries = &self.waiting_vm().wait_entries;
+        let index = (self as *const Self).wrapping_offset_from(&entries[0] as *const _);
+        VmId::new(index as u16).unwrap()
+    }
+}
+
+/// The links of wait entries in waiter and ready lists.
+pub struct WaitLinks;
+
+impl IsNode<WaitEntry> for WaitLinks {
+    fn link_of(element: &WaitEntry) -> &Link {
+        &element.links
+    }
+
+    unsafe fn element_of(link: &Link) -> &WaitEntry {
+        &*((link as *const _ as usize - offset_of!(WaitEntry, links)) as *const _)
+    }
+}
+
+/// The right to put a wait entry in a list. There is one token for each wait entry, and it is
+/// neither `Copy` nor `Clone`: it is consumed when the entry is put in a list, and given back when
+/// the entry is taken out. So an entry whose token is in hand is in no list, and the type system
+/// guarantees an entry is never in two lists at once, even while it is moved between the locks of
+/// two VMs.
+pub struct WaitToken {
+    entry: *const WaitEntry,
+}
+
+impl WaitToken {
+    /// Returns the wait entry.
+    pub fn entry(&self) -> &WaitEntry {
+        // Safe because wait entries are in VMs, which are never moved or freed once tokens are
+        // made for them.
+        unsafe { &*self.entry }
+    }
+
+    /// Puts the entry in the ready list of the waiting VM, taking its lock.
+    pub fn wake_up(self) {
+        // Safe because VMs are never freed. See `WaitToken::entry()`.
+        let vm = unsafe { &*self.entry().waiting_vm.get() };
+        vm.mailbox().add_ready(self);
+    }
+}
+
 pub struct Mailbox {
-    state: MailboxState,
+    state: MailboxProtocol,
     recv: *mut SpciMessage,
     send: *const SpciMessage,
 
     /// List of wait_entry structs representing VMs that want to be notified when the mailbox
     /// becomes writable. Once the mailbox does become writable, the entry is removed from this list
     /// and added to the waiting VM's ready_list.
-    waiter_list: ListEntry,
+    waiter_list: LinkedList<WaitEntry, WaitLinks>,
 
     /// List of wait_entry structs representing VMs whose mailboxes became writable since the owner
     /// of the mailbox registers for notification.
-    ready_list: ListEntry,
+    ready_list: LinkedList<WaitEntry, WaitLinks>,
+
+    /// The tokens of the VM's own wait entries that are in no list.
+    idle_waits: ArrayVec<[WaitToken; MAX_VMS]>,
 }
 
 impl Mailbox {
     pub fn new() -> Self {
         Self {
-            state: MailboxState::Empty,
+            state: MailboxProtocol::new(),
             recv: ptr::null_mut(),
             send: ptr::null(),
-            waiter_list: ListEntry::new(),
-            ready_list: ListEntry::new(),
+            waiter_list: LinkedList::new(),
+            ready_list: LinkedList::new(),
+            idle_waits: ArrayVec::new(),
         }
     }
+
+    /// Adds a VM to be notified when the mailbox becomes writable.
+    pub fn add_waiter(&mut self, token: WaitToken) {
+        // Safe because the token is given up, and the entry outlives the list. See
+        // `WaitToken::entry()`.
+        unsafe { self.waiter_list.push_back(token.entry()) };
+    }
+
+    /// Removes the first VM waiting for the mailbox to become writable, and returns the token of its
+    /// entry to wake it up with.
+    pub fn fetch_waiter(&mut self) -> Option<WaitToken> {
+        self.waiter_list
+            .pop_front()
+            .map(|entry| WaitToken { entry })
+    }
+
+    /// Removes all the VMs waiting for the mailbox to become writable, and returns the tokens of
+    /// their entries to wake them up with. The tokens are returned rather than used so that the
+    /// waiting VMs' locks are not taken while this one is held.
+    pub fn take_waiters(&mut self) -> ArrayVec<[WaitToken; MAX_VMS]> {
+        let mut waiters = ArrayVec::new();
+        while let Some(token) = self.fetch_waiter() {
+            // A VM has one entry for each VM, so there are at most `MAX_VMS` waiters.
+            waiters.push(token);
+        }
+        waiters
+    }
+
+    /// Removes `entry` from the waiter list if it is in there, and returns its token.
+    pub fn cancel_waiter(&mut self, entry: &WaitEntry) -> Option<WaitToken> {
+        if !self.waiter_list.contains(entry) {
+            return None;
+        }
+
+        // Safe because the entry is in this list.
+        unsafe { self.waiter_list.remove(entry) };
+        Some(WaitToken { entry })
+    }
+
+    /// Adds an entry of the owner of the mailbox to the ready list.
+    fn add_ready(&mut self, token: WaitToken) {
+        // Safe because the token is given up, and the entry outlives the list. See
+        // `WaitToken::entry()`.
+        unsafe { self.ready_list.push_back(token.entry()) };
+    }
+
+    /// Removes the first entry from the ready list, and returns the ID of the VM whose mailbox
+    /// became writable. The entry may be used to wait again.
+    pub fn fetch_ready(&mut self) -> Option<VmId> {
+        let token = WaitToken {
+            entry: self.ready_list.pop_front()?,
+        };
+        let target = token.entry().target();
+        self.idle_waits.push(token);
+        Some(target)
+    }
+
+    /// Removes `entry` from the ready list if it is in there, and returns its token.
+    fn cancel_ready(&mut self, entry: &WaitEntry) -> Option<WaitToken> {
+        if !self.ready_list.contains(entry) {
+            return None;
+        }
+
+        // Safe because the entry is in this list.
+        unsafe { self.ready_list.remove(entry) };
+        Some(WaitToken { entry })
+    }
+
+    /// Takes the token of the owner's entry for waiting for the given VM, if the entry is in no
+    /// list.
+    fn take_idle(&mut self, target: VmId) -> Option<WaitToken> {
+        let index = self
+            .idle_waits
+            .iter()
+            .position(|token| token.entry().target() == target)?;
+        Some(self.idle_waits.swap_remove(index))
+    }
 }
 
 // TODO(@jeehoonkang)
 pub struct VmState {
     pub ptable: PageTable<Stage2>,
     pub mailbox: Mailbox,
+
+    /// The log of pages written to, if dirty page tracking is enabled.
+    dirty_log: Option<DirtyLog>,
+
+    /// The ranges emulated rather than mapped.
+    traps: TrapTable,
 }
 
 impl VmState {
     pub fn new(ptable: PageTable<Stage2>, mailbox: Mailbox) -> Self {
-        Self { ptable, mailbox }
+        Self {
+            ptable,
+            mailbox,
+            dirty_log: None,
+            traps: TrapTable::new(),
+        }
+    }
+
+    /// Returns an accessor of the VM's memory.
+    pub fn guest_memory(&self) -> GuestMemory {
+        GuestMemory::new(&self.ptable)
+    }
+
+    /// Starts tracking writes to `[begin, end)`, stopping the previous tracking if any.
+    pub fn start_dirty_tracking(
+        &mut self,
+        begin: IpaAddr,
+        end: IpaAddr,
+        mpool: &MPool,
+    ) -> Result<(), MmError> {
+        self.stop_dirty_tracking(mpool);
+        self.dirty_log = Some(DirtyLog::new(&mut self.ptable, begin, end, mpool)?);
+        Ok(())
+    }
+
+    /// Stops tracking writes.
+    pub fn stop_dirty_tracking(&mut self, mpool: &MPool) {
+        if let Some(log) = self.dirty_log.take() {
+            log.drop(&mut self.ptable, mpool);
+        }
+    }
+
+    /// Reads and clears the dirty log of `[begin, end)`. See `DirtyLog::collect()`.
+    pub fn collect_dirty(
+        &mut self,
+        begin: IpaAddr,
+        end: IpaAddr,
+        bitmap: &mut [u8],
+        mpool: &MPool,
+    ) -> Result<usize, MmError> {
+        match self.dirty_log {
+            Some(ref mut log) => log.collect(&mut self.ptable, begin, end, bitmap, mpool),
+            None => Err(MmError::OutOfRange),
+        }
+    }
+
+    /// Registers `[begin, end)` as emulated by `owner`. See `TrapTable::register()`.
+    pub fn register_trap(
+        &mut self,
+        begin: IpaAddr,
+        end: IpaAddr,
+        owner: u32,
+    ) -> Result<(), MmError> {
+        self.traps.register(&self.ptable, begin, end, owner)
+    }
+
+    /// Unregisters the trap region containing the given address, and returns it.
+    pub fn unregister_trap(&mut self, ipa: IpaAddr) -> Option<TrapRegion> {
+        self.traps.unregister(ipa)
+    }
+
+    /// Looks up the trap region containing the given address.
+    pub fn find_trap(&self, ipa: IpaAddr) -> Option<TrapRegion> {
+        self.traps.lookup(ipa).cloned()
+    }
+
+    /// Handles a write fault at the given address if it was caused by dirty page tracking.
+    pub fn handle_dirty_fault(&mut self, ipa: IpaAddr, mpool: &MPool) -> bool {
+        match self.dirty_log {
+            Some(ref mut log) => log.handle_fault(&mut self.ptable, ipa, mpool),
+            None => false,
+        }
     }
 }
 
 // TODO(@jeehoonkang)
 pub struct Vm {
-    id: u32,
+    id: VmId,
+    vmid: u16,
     pub state: SpinLock<VmState>,
     vcpus: ArrayVec<[VCpu; MAX_CPUS]>,
 
     wait_entries: [WaitEntry; MAX_VMS],
     aborting: AtomicBool,
+
+    /// Whether the VM is suspended, in which case its vCPUs are not run.
+    suspended: AtomicBool,
 }
 
 impl Vm {
-    pub fn new(id: u32, vcpu_count: u32, mpool: &MPool) -> Option<Self> {
-        let ptable = PageTable::new(mpool)?;
+    pub fn new(id: VmId, vcpu_count: u32, mpool: &MPool) -> Option<Self> {
+        let vmid = VMID_ALLOCATOR.alloc()?;
+        let mut ptable = match PageTable::new(mpool) {
+            Ok(ptable) => ptable,
+            Err(_) => {
+                VMID_ALLOCATOR.free(vmid);
+                return None;
+            }
+        };
+        ptable.set_owner(id.id().into());
 
         Some(Self {
             id,
+            vmid,
             state: SpinLock::new(VmState::new(
                 ptable, 
                 Mailbox::new(),
@@ -122,16 +342,10 @@ impl Vm {
             vcpus: ArrayVec::new(), // vm->vcpu_count = vcpu_count;
             wait_entries: unimplemented!(),
             aborting: AtomicBool::new(false),
+            suspended: AtomicBool::new(false),
         })
     }
 
-	  // /* Initialise waiter entries. */
-	  // for (i = 0; i < MAX_VMS; i++) {
-	  // 	vm->wait_entries[i].waiting_vm = vm;
-	  // 	list_init(&vm->wait_entries[i].wait_links);
-	  // 	list_init(&vm->wait_entries[i].ready_links);
-	  // }
-
 	  // /* Do basic initialization of vcpus. */
 	  // for (i = 0; i < vcpu_count; i++) {
 	  // 	vcpu_init(vm_get_vcpu(vm, i), vm);
@@ -140,23 +354,123 @@ impl Vm {
 	  // ++vm_count;
 	  // *new_vm = vm;
 
-    pub unsafe fn get_index(&self, vcpu: &VCpu) -> usize {
-        (vcpu as *const VCpu).wrapping_offset_from(&self.vcpus[0] as *const _) as usize
+    /// Initialises the wait entries, making a token for each of them.
+    ///
+    /// # Safety
+    ///
+    /// It should be called once, after the VM is moved to where it stays until it is freed.
+    pub unsafe fn init_wait_entries(&self) {
+        let mut mailbox = self.mailbox();
+        for entry in self.wait_entries.iter() {
+            entry.waiting_vm.set(self);
+            mailbox.idle_waits.push(WaitToken { entry });
+        }
+    }
+
+    /// Locks the VM, giving access to its mailbox only.
+    pub fn mailbox(&self) -> MappedSpinLockGuard<Mailbox> {
+        SpinLockGuard::map(self.state.lock(), |state| &mut state.mailbox)
+    }
+
+    /// Registers the VM to be notified when the mailbox of `target` becomes writable. Returns
+    /// false if it is already waiting for `target`, or has been notified but hasn't fetched the
+    /// notification yet.
+    pub fn wait_for(&self, target: &Vm) -> bool {
+        let token = match self.mailbox().take_idle(target.id) {
+            Some(token) => token,
+            None => return false,
+        };
+
+        target.mailbox().add_waiter(token);
+        true
+    }
+
+    /// Wakes up all the VMs waiting for the VM's mailbox to become writable, by moving their
+    /// entries to their ready lists. Only one VM's lock is held at a time. Returns the number of
+    /// VMs woken up.
+    pub fn wake_up_all(&self) -> usize {
+        let waiters = self.mailbox().take_waiters();
+        let count = waiters.len();
+        for token in waiters {
+            token.wake_up();
+        }
+        count
+    }
+
+    /// Stops waiting for the mailbox of `target`, dropping the notification if it has been woken
+    /// up already. Returns false if the VM wasn't waiting for `target`, or the entry is being
+    /// moved to the ready list by another CPU, in which case the notification arrives later.
+    pub fn cancel_wait(&self, target: &Vm) -> bool {
+        let entry = &self.wait_entries[target.id.index()];
+
+        let token = target.mailbox().cancel_waiter(entry);
+
+        let mut mailbox = self.mailbox();
+        match token.or_else(|| mailbox.cancel_ready(entry)) {
+            Some(token) => {
+                mailbox.idle_waits.push(token);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the VMID the VM's stage-2 TLB entries are tagged with.
+    pub fn vmid(&self) -> u16 {
+        self.vmid
+    }
+
+    /// Returns whether the VM is suspended.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Relaxed)
+    }
+
+    /// Suspends or resumes the VM.
+    pub fn set_suspended(&self, suspended: bool) {
+        self.suspended.store(suspended, Ordering::Relaxed);
+    }
+
+    pub fn id(&self) -> VmId {
+        self.id
+    }
+
+    pub unsafe fn get_index(&self, vcpu: &VCpu) -> VCpuIndex {
+        let index = (vcpu as *const VCpu).wrapping_offset_from(&self.vcpus[0] as *const _) as u32;
+        VCpuIndex::new(index, self.vcpus.len() as u32).unwrap()
+    }
+
+    /// Returns the vCPU of the given index.
+    pub fn vcpu(&self, index: VCpuIndex) -> Option<&VCpu> {
+        self.vcpus.get(index.index() as usize)
     }
 }
 
 pub struct VmManager {
-    vms: ArrayVec<[Vm; MAX_VMS]>,
+    vms: ArrayVec<[RefCount<Vm>; MAX_VMS]>,
 }
 
 impl VmManager {
     pub fn insert(&mut self, vm: Vm) -> Result<(), Vm> {
-        self.vms.try_push(vm)
-            .map_err(|e| e.element())
+        self.vms.try_push(RefCount::new(vm))
+            .map_err(|e| e.element().into_inner())
+    }
+
+    /// Takes a reference to the VM of the given ID, which keeps it alive while it is used.
+    pub fn get(&self, id: VmId) -> Option<Ref<Vm>> {
+        self.vms.get(id.index())?.get()
+    }
+
+    /// Kills the VM of the given ID so that no reference to it is taken anymore, and returns it to
+    /// be torn down. Fails if the VM is still referenced, so it is never freed while it is used.
+    pub fn kill(&self, id: VmId) -> Option<&Vm> {
+        self.vms.get(id.index())?.kill()
     }
 
     pub unsafe fn get_index(&self, vm: &Vm) -> usize {
-        (vm as *const Vm).wrapping_offset_from(&self.vms[0] as *const _) as usize
+        self.vms
+            .iter()
+            .position(|entry| ptr::eq(entry.as_ptr(), vm))
+            .unwrap()
     }
 }
 
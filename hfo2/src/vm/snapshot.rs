@@ -0,0 +1,363 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Snapshots of suspended VMs for checkpointing and migration.
+//!
+//! A snapshot is written to a buffer provided by the primary VM, with all fields little-endian:
+//!
+//! - The header: `MAGIC`, `VERSION`, the VM ID and the vCPU count, as `u32`s.
+//! - For each vCPU, its state and interrupts. See `VCpu::save()`.
+//! - The mailbox state as a `u32`.
+//! - The memory description: a `u32` kind (`MEMORY_FULL` or `MEMORY_DIRTY`), a `u32` count of
+//!   ranges, and for each range its first and past-the-end IPAs as `u64`s and its mode as a `u32`.
+//!
+//! The memory description lists all the valid memory of the VM, or only the pages written to since
+//! the last snapshot if dirty page tracking is enabled, in which case taking a snapshot clears the
+//! dirty log. Copying the contents of the listed memory is up to the primary VM.
+
+use core::mem;
+
+use crate::mm::*;
+use crate::mpool::MPool;
+use crate::page::*;
+
+use super::*;
+
+/// Identifies a snapshot, "HFSN".
+const MAGIC: u32 = 0x4e53_4648;
+
+/// The version of the snapshot format.
+const VERSION: u32 = 1;
+
+/// The memory description lists all the valid memory of the VM.
+const MEMORY_FULL: u32 = 0;
+
+/// The memory description lists the pages written to since the last snapshot.
+const MEMORY_DIRTY: u32 = 1;
+
+/// The number of pages whose dirty bits are collected at once.
+const DIRTY_CHUNK_PAGES: usize = 64;
+
+/// Errors of taking or restoring a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The buffer is too small for the snapshot.
+    BufferTooSmall,
+
+    /// The VM is not suspended, or one of its vCPUs is still running.
+    NotSuspended,
+
+    /// The snapshot is malformed, or doesn't match the VM it is restored to.
+    BadFormat,
+
+    /// Reading the dirty log or checking the memory failed.
+    Mm(MmError),
+}
+
+impl From<MmError> for SnapshotError {
+    fn from(e: MmError) -> Self {
+        SnapshotError::Mm(e)
+    }
+}
+
+/// Writes the fields of a snapshot to a buffer.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Returns the number of bytes written.
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let end = self.pos + bytes.len();
+        if end > self.buf.len() {
+            return Err(SnapshotError::BufferTooSmall);
+        }
+
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    pub fn put_u32(&mut self, value: u32) -> Result<(), SnapshotError> {
+        self.put_bytes(&value.to_le_bytes())
+    }
+
+    pub fn put_u64(&mut self, value: u64) -> Result<(), SnapshotError> {
+        self.put_bytes(&value.to_le_bytes())
+    }
+
+    /// Overwrites the `u32` at the given position, which was written before.
+    fn patch_u32(&mut self, pos: usize, value: u32) {
+        self.buf[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Reads the fields of a snapshot from a buffer.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn get_bytes(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.pos + len;
+        if end > self.buf.len() {
+            return Err(SnapshotError::BadFormat);
+        }
+
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.get_bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.get_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+impl MailboxState {
+    fn save(&self, w: &mut Writer) -> Result<(), SnapshotError> {
+        w.put_u32(match self {
+            MailboxState::Empty => 0,
+            MailboxState::Received => 1,
+            MailboxState::Read => 2,
+        })
+    }
+
+    fn load(r: &mut Reader) -> Result<Self, SnapshotError> {
+        match r.get_u32()? {
+            0 => Ok(MailboxState::Empty),
+            1 => Ok(MailboxState::Received),
+            2 => Ok(MailboxState::Read),
+            _ => Err(SnapshotError::BadFormat),
+        }
+    }
+}
+
+/// Writes a range of the memory description.
+fn put_range(
+    w: &mut Writer,
+    begin: IpaAddr,
+    end: IpaAddr,
+    mode: Mode,
+) -> Result<(), SnapshotError> {
+    w.put_u64(begin.addr() as u64)?;
+    w.put_u64(end.addr() as u64)?;
+    w.put_u32(mode.bits())
+}
+
+/// Writes the subranges of `[begin, end)` that are mapped with the same mode. Returns their number.
+fn put_ranges(
+    w: &mut Writer,
+    ptable: &PageTable<Stage2>,
+    begin: IpaAddr,
+    end: IpaAddr,
+) -> Result<u32, SnapshotError> {
+    let mut count = 0;
+
+    for segment in ptable.get_modes(begin, end)? {
+        put_range(w, segment.begin, segment.end, segment.mode)?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Writes the ranges of valid memory of the VM. Returns the number of ranges.
+fn save_full_memory(state: &VmState, w: &mut Writer) -> Result<u32, SnapshotError> {
+    let mut count = 0;
+
+    for block in state.ptable.blocks() {
+        let mode = Stage2::attrs_to_mode(block.attrs);
+        if mode.contains(Mode::INVALID) {
+            continue;
+        }
+
+        put_range(w, block.begin, block.end, mode)?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Writes the ranges of pages written to since the last snapshot, clearing the dirty log. Returns
+/// the number of ranges.
+fn save_dirty_memory(
+    state: &mut VmState,
+    begin: IpaAddr,
+    end: IpaAddr,
+    w: &mut Writer,
+    mpool: &MPool,
+) -> Result<u32, SnapshotError> {
+    let mut count = 0;
+    let mut run: Option<IpaAddr> = None;
+    let mut chunk = begin;
+
+    while chunk < end {
+        let chunk_end = IpaAddr::new(core::cmp::min(
+            end.addr(),
+            chunk.addr() + DIRTY_CHUNK_PAGES * PAGE_SIZE,
+        ));
+        let mut bitmap = [0u8; DIRTY_CHUNK_PAGES / 8];
+        state.collect_dirty(chunk, chunk_end, &mut bitmap, mpool)?;
+
+        // Coalesce consecutive dirty pages into a range.
+        for (i, page) in (chunk.addr()..chunk_end.addr())
+            .step_by(PAGE_SIZE)
+            .enumerate()
+        {
+            let page = IpaAddr::new(page);
+            let dirty = bitmap[i / 8] & (1 << (i % 8)) != 0;
+
+            match (run, dirty) {
+                (None, true) => run = Some(page),
+                (Some(run_begin), false) => {
+                    count += put_ranges(w, &state.ptable, run_begin, page)?;
+                    run = None;
+                }
+                _ => {}
+            }
+        }
+
+        chunk = chunk_end;
+    }
+
+    if let Some(run_begin) = run {
+        count += put_ranges(w, &state.ptable, run_begin, end)?;
+    }
+
+    Ok(count)
+}
+
+/// Writes a snapshot of the given suspended VM to `buf`. Returns the number of bytes written.
+pub fn save(vm: &Vm, buf: &mut [u8], mpool: &MPool) -> Result<usize, SnapshotError> {
+    if !vm.is_suspended() || vm.vcpus.iter().any(|vcpu| vcpu.is_running()) {
+        return Err(SnapshotError::NotSuspended);
+    }
+
+    let mut w = Writer::new(buf);
+    w.put_u32(MAGIC)?;
+    w.put_u32(VERSION)?;
+    w.put_u32(vm.id.id().into())?;
+    w.put_u32(vm.vcpus.len() as u32)?;
+
+    for vcpu in vm.vcpus.iter() {
+        vcpu.save(&mut w)?;
+    }
+
+    let mut state = vm.state.lock();
+    state.mailbox.state.state().save(&mut w)?;
+
+    let dirty_range = state.dirty_log.as_ref().map(|log| log.range());
+    w.put_u32(if dirty_range.is_some() {
+        MEMORY_DIRTY
+    } else {
+        MEMORY_FULL
+    })?;
+
+    let count_pos = w.len();
+    w.put_u32(0)?;
+    let count = match dirty_range {
+        Some((begin, end)) => save_dirty_memory(&mut state, begin, end, &mut w, mpool)?,
+        None => save_full_memory(&state, &mut w)?,
+    };
+    w.patch_u32(count_pos, count);
+
+    Ok(w.len())
+}
+
+/// Restores the vCPU contexts, interrupts and mailbox state of the given suspended VM from a
+/// snapshot of a VM with as many vCPUs, and checks that the memory it describes is mapped in the
+/// VM. Copying the contents of the memory is up to the primary VM. Returns the number of ranges in
+/// the memory description.
+pub fn restore(vm: &Vm, buf: &[u8]) -> Result<u32, SnapshotError> {
+    if !vm.is_suspended() || vm.vcpus.iter().any(|vcpu| vcpu.is_running()) {
+        return Err(SnapshotError::NotSuspended);
+    }
+
+    let mut r = Reader::new(buf);
+    if r.get_u32()? != MAGIC || r.get_u32()? != VERSION {
+        return Err(SnapshotError::BadFormat);
+    }
+
+    // The snapshot may be of another VM, e.g. on another machine, but must have the same shape.
+    let _id = r.get_u32()?;
+    if r.get_u32()? as usize != vm.vcpus.len() {
+        return Err(SnapshotError::BadFormat);
+    }
+
+    // Check the whole snapshot before changing the VM.
+    let vcpus_pos = r.pos;
+    for vcpu in vm.vcpus.iter() {
+        vcpu.check(&mut r)?;
+    }
+    let mailbox_state = MailboxState::load(&mut r)?;
+
+    let state = vm.state.lock();
+    match r.get_u32()? {
+        MEMORY_FULL | MEMORY_DIRTY => {}
+        _ => return Err(SnapshotError::BadFormat),
+    }
+
+    let count = r.get_u32()?;
+    for _ in 0..count {
+        let begin = IpaAddr::new(r.get_u64()? as usize);
+        let end = IpaAddr::new(r.get_u64()? as usize);
+        let _mode = r.get_u32()?;
+
+        if begin >= end {
+            return Err(SnapshotError::BadFormat);
+        }
+        if state
+            .ptable
+            .get_modes(begin, end)?
+            .any(|segment| segment.mode.contains(Mode::INVALID))
+        {
+            return Err(SnapshotError::Mm(MmError::OutOfRange));
+        }
+    }
+    mem::drop(state);
+
+    let mut r = Reader::new(buf);
+    r.pos = vcpus_pos;
+    for vcpu in vm.vcpus.iter() {
+        vcpu.load(&mut r)?;
+    }
+    vm.mailbox().state = MailboxProtocol::restored(mailbox_state);
+
+    Ok(count)
+}
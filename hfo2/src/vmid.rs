@@ -0,0 +1,171 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! # Allocation of VMIDs and ASIDs.
+//!
+//! TLB entries are tagged with the VMID of the stage-2 translation (or the ASID of the stage-1
+//! translation) they come from, so an identifier may be reused only after the TLB no longer holds
+//! entries tagged with it. Freed identifiers are therefore not reused right away, but retired
+//! until the allocator runs out of fresh identifiers. Then it starts a new generation: the whole
+//! TLB is invalidated for all identifiers at once, and the retired identifiers become fresh again.
+//! Identifiers in use are kept across generations.
+
+use crate::spinlock::SpinLock;
+
+extern "C" {
+    fn arch_mm_invalidate_stage1_all();
+    fn arch_mm_invalidate_stage2_all_vmids();
+}
+
+/// The number of bits of an identifier. It is the minimum supported by the architecture for both
+/// VMIDs and ASIDs.
+pub const ID_BITS: usize = 8;
+
+/// The number of identifiers.
+const ID_COUNT: usize = 1 << ID_BITS;
+
+/// The number of words of an identifier bitmap.
+const ID_WORDS: usize = ID_COUNT / 64;
+
+/// A set of identifiers.
+#[derive(Clone, Copy)]
+struct IdSet {
+    bits: [u64; ID_WORDS],
+}
+
+impl IdSet {
+    const fn new() -> Self {
+        Self {
+            bits: [0; ID_WORDS],
+        }
+    }
+
+    fn contains(&self, id: u16) -> bool {
+        self.bits[id as usize / 64] & (1 << (id % 64)) != 0
+    }
+
+    fn insert(&mut self, id: u16) {
+        self.bits[id as usize / 64] |= 1 << (id % 64);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bits.iter().all(|word| *word == 0)
+    }
+
+    /// Returns the smallest identifier not in the set.
+    fn first_absent(&self) -> Option<u16> {
+        self.bits
+            .iter()
+            .enumerate()
+            .find(|(_, word)| **word != u64::max_value())
+            .map(|(i, word)| (i * 64) as u16 + (!word).trailing_zeros() as u16)
+    }
+}
+
+struct IdAllocatorInner {
+    /// The number of times the TLB is invalidated for retired identifiers.
+    generation: u64,
+
+    /// Identifiers allocated, or retired in the current generation.
+    used: IdSet,
+
+    /// Identifiers retired in the current generation.
+    retired: IdSet,
+}
+
+/// Allocator of the identifiers TLB entries are tagged with.
+pub struct IdAllocator {
+    inner: SpinLock<IdAllocatorInner>,
+
+    /// Invalidates the TLB for all identifiers.
+    invalidate_all: unsafe extern "C" fn(),
+}
+
+impl IdAllocator {
+    const fn new(invalidate_all: unsafe extern "C" fn()) -> Self {
+        Self {
+            inner: SpinLock::new(IdAllocatorInner {
+                generation: 0,
+                used: IdSet::new(),
+                retired: IdSet::new(),
+            }),
+            invalidate_all,
+        }
+    }
+
+    /// Allocates an identifier, starting a new generation if there are only retired ones left.
+    /// Returns `None` if all identifiers are in use.
+    pub fn alloc(&self) -> Option<u16> {
+        let mut inner = self.inner.lock();
+
+        // Identifier 0 is reserved for the hypervisor.
+        inner.used.insert(0);
+
+        let id = match inner.used.first_absent() {
+            Some(id) => id,
+            None => {
+                if inner.retired.is_empty() {
+                    return None;
+                }
+
+                // Invalidate the TLB entries tagged with the retired identifiers before reusing
+                // them.
+                unsafe { (self.invalidate_all)() };
+                let retired = inner.retired;
+                for (used, retired) in inner.used.bits.iter_mut().zip(retired.bits.iter()) {
+                    *used &= !retired;
+                }
+                inner.retired = IdSet::new();
+                inner.generation += 1;
+
+                inner.used.first_absent()?
+            }
+        };
+
+        inner.used.insert(id);
+        Some(id)
+    }
+
+    /// Frees the given identifier. It is retired until the next generation, so the TLB entries
+    /// tagged with it should not be used anymore, e.g. the VM should not run on any CPU.
+    pub fn free(&self, id: u16) {
+        let mut inner = self.inner.lock();
+        assert!(id != 0 && inner.used.contains(id) && !inner.retired.contains(id));
+        inner.retired.insert(id);
+    }
+
+    /// Returns the number of generations so far.
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().generation
+    }
+}
+
+/// The allocator of stage-2 VMIDs.
+pub static VMID_ALLOCATOR: IdAllocator = IdAllocator::new(arch_mm_invalidate_stage2_all_vmids);
+
+/// The allocator of stage-1 ASIDs.
+pub static ASID_ALLOCATOR: IdAllocator = IdAllocator::new(arch_mm_invalidate_stage1_all);
+
+/// Allocates a VMID. Returns 0 if all VMIDs are in use.
+#[no_mangle]
+pub unsafe extern "C" fn vmid_alloc() -> u16 {
+    VMID_ALLOCATOR.alloc().unwrap_or(0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vmid_free(vmid: u16) {
+    VMID_ALLOCATOR.free(vmid)
+}
@@ -22,21 +22,77 @@
 
 #include "vmapi/hf/call.h"
 
+/** A range of intermediate physical addresses, [begin, end). */
+struct mem_range {
+	ipaddr_t begin;
+	ipaddr_t end;
+};
+
 void api_init(struct mpool *ppool);
 spci_vm_id_t api_vm_get_id(const struct vcpu *current);
 int64_t api_vm_get_count(void);
+int64_t api_vm_create(struct vcpu *current);
+int64_t api_vm_destroy(spci_vm_id_t vm_id, struct vcpu *current);
+int64_t api_vm_suspend(spci_vm_id_t vm_id, struct vcpu *current);
+int64_t api_vm_resume(spci_vm_id_t vm_id, struct vcpu *current);
+int64_t api_vm_find_uuid(const uint32_t uuid[4]);
 int64_t api_vcpu_get_count(spci_vm_id_t vm_id, const struct vcpu *current);
+int64_t api_vcpu_affinity_set(spci_vm_id_t vm_id, uint32_t vcpu_idx,
+			      uint64_t affinity, const struct vcpu *current);
 void api_regs_state_saved(struct vcpu *vcpu);
 struct hf_vcpu_run_return api_vcpu_run(spci_vm_id_t vm_id, uint32_t vcpu_idx,
 				       const struct vcpu *current,
 				       struct vcpu **next);
 int64_t api_vm_configure(ipaddr_t send, ipaddr_t recv, struct vcpu *current,
 			 struct vcpu **next);
+int64_t api_vm_unconfigure(struct vcpu *current);
 int64_t api_mailbox_clear(struct vcpu *current, struct vcpu **next);
 int64_t api_mailbox_writable_get(const struct vcpu *current);
 int64_t api_mailbox_waiter_get(spci_vm_id_t vm_id, const struct vcpu *current);
+int64_t api_mailbox_peek(const struct vcpu *current);
+int64_t api_vm_stats_get(spci_vm_id_t vm_id, struct vcpu *current);
+int64_t api_cpu_topology_get(struct vcpu *current);
+int64_t api_vm_log_drain(spci_vm_id_t vm_id, struct vcpu *current);
+int64_t api_dlog_drain(struct vcpu *current);
+int64_t api_run_queue_pop(const struct vcpu *current);
 int64_t api_share_memory(spci_vm_id_t vm_id, ipaddr_t addr, size_t size,
 			 enum hf_share share, struct vcpu *current);
+bool api_share_memory_ptables(struct mm_ptable *from, struct mm_ptable *to,
+			      ipaddr_t begin, ipaddr_t end,
+			      enum hf_share share, struct mpool *ppool);
+int32_t api_share_memory_ranges(spci_vm_id_t vm_id,
+				const struct mem_range *ranges, size_t count,
+				enum hf_share share, int mode,
+				struct vcpu *current);
+int32_t api_share_memory_ranges_ptables(struct mm_ptable *from,
+					struct mm_ptable *to,
+					const struct mem_range *ranges,
+					size_t count, enum hf_share share,
+					int mode, struct mpool *ppool);
+int32_t api_ffa_msg_send_direct_req(spci_vm_id_t vm_id,
+				    const uintreg_t args[8],
+				    struct vcpu *current, struct vcpu **next);
+int32_t api_ffa_msg_send_direct_resp(spci_vm_id_t vm_id,
+				     const uintreg_t args[8],
+				     struct vcpu *current, struct vcpu **next);
+int64_t api_partition_info_get(struct vcpu *current, const uint32_t uuid[4]);
+bool api_mailbox_send_copy(struct vcpu *current, void *buf, size_t size);
+int64_t api_memory_relinquish(spci_vm_id_t vm_id, ipaddr_t addr, size_t size,
+			      struct vcpu *current);
+bool api_memory_relinquish_ptables(struct mm_ptable *from,
+				   const struct mm_ptable *owner,
+				   ipaddr_t begin, ipaddr_t end,
+				   struct mpool *ppool);
+int64_t api_memory_reclaim(spci_vm_id_t vm_id, ipaddr_t addr, size_t size,
+			   struct vcpu *current);
+bool api_memory_reclaim_ptables(struct mm_ptable *owner,
+				const struct mm_ptable *borrower,
+				ipaddr_t begin, ipaddr_t end,
+				struct mpool *ppool);
+int64_t api_notification_set(spci_vm_id_t vm_id, uint64_t bits,
+			     struct vcpu *current, struct vcpu **next);
+uint64_t api_notification_get(const struct vcpu *current);
+int64_t api_lock_stats_dump(const struct vcpu *current);
 
 struct vcpu *api_preempt(struct vcpu *current);
 struct vcpu *api_wait_for_interrupt(struct vcpu *current);
@@ -49,10 +105,13 @@ uint32_t api_interrupt_get(struct vcpu *current);
 int64_t api_interrupt_inject(spci_vm_id_t target_vm_id,
 			     uint32_t target_vcpu_idx, uint32_t intid,
 			     struct vcpu *current, struct vcpu **next);
+int64_t api_interrupt_owner_get(uint32_t intid, const struct vcpu *current);
 
 int32_t api_spci_msg_send(uint32_t attributes, struct vcpu *current,
 			  struct vcpu **next);
-int32_t api_spci_msg_recv(uint32_t attributes, struct vcpu *current,
-			  struct vcpu **next);
+int64_t api_mailbox_broadcast(struct vcpu *current);
+int32_t api_spci_msg_recv(uint32_t attributes, uint64_t timeout,
+			  struct vcpu *current, struct vcpu **next);
 int32_t api_spci_yield(struct vcpu *current, struct vcpu **next);
 int32_t api_spci_version(void);
+int64_t api_hf_version(uint32_t requested, struct vcpu *current);
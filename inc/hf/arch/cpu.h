@@ -36,11 +36,27 @@ void arch_irq_disable(void);
  */
 void arch_irq_enable(void);
 
+/**
+ * Disables interrupts, returning the previous interrupt state to be passed to
+ * `arch_irq_restore()`.
+ */
+uintreg_t arch_irq_save(void);
+
+/**
+ * Restores the interrupt state returned by `arch_irq_save()`.
+ */
+void arch_irq_restore(uintreg_t state);
+
+/**
+ * Returns the ID of the current CPU, as found by `cpu_find()`.
+ */
+uint64_t arch_cpu_id(void);
+
 /**
  * Reset the register values other than the PC and argument which are set with
  * `arch_regs_set_pc_arg()`.
  */
-void arch_regs_reset(struct arch_regs *r, bool is_primary, spci_vm_id_t vm_id,
+void arch_regs_reset(struct arch_regs *r, bool is_primary, uint16_t vmid,
 		     uint64_t vcpu_id, paddr_t table);
 
 /**
@@ -59,3 +75,13 @@ void arch_regs_set_pc_arg(struct arch_regs *r, ipaddr_t pc, uintreg_t arg);
  * by any other physical CPU.
  */
 void arch_regs_set_retval(struct arch_regs *r, uintreg_t v);
+
+/**
+ * Updates the registers holding the first `count` return values of a function,
+ * e.g. the results of an FF-A call.
+ *
+ * This function must only be called on an arch_regs that is known not be in use
+ * by any other physical CPU.
+ */
+void arch_regs_set_retvals(struct arch_regs *r, const uintreg_t *v,
+			   size_t count);
@@ -95,6 +95,16 @@ paddr_t arch_mm_table_from_pte(pte_t pte, uint8_t level);
  */
 uint64_t arch_mm_pte_attrs(pte_t pte, uint8_t level);
 
+/**
+ * Determines if the given block attributes have the access flag set.
+ */
+bool arch_mm_attrs_accessed(uint64_t attrs);
+
+/**
+ * Sets or clears the access flag of the given block attributes.
+ */
+uint64_t arch_mm_attrs_set_accessed(uint64_t attrs, bool accessed);
+
 /**
  * Merges the attributes of a block into those of its containing table.
  */
@@ -111,12 +121,40 @@ void arch_mm_invalidate_stage1_range(vaddr_t va_begin, vaddr_t va_end);
  */
 void arch_mm_invalidate_stage2_range(ipaddr_t va_begin, ipaddr_t va_end);
 
+/**
+ * Invalidates the whole stage-1 TLB.
+ */
+void arch_mm_invalidate_stage1_all(void);
+
+/**
+ * Invalidates the whole stage-2 TLB of the current VMID.
+ */
+void arch_mm_invalidate_stage2_all(void);
+
+/**
+ * Invalidates the whole stage-2 TLB of all VMIDs.
+ */
+void arch_mm_invalidate_stage2_all_vmids(void);
+
 /**
  * Writes the given range of virtual memory back to the point of unification so
  * all cores and devices will see the updated values.
  */
 void arch_mm_write_back_dcache(void *base, size_t size);
 
+/**
+ * Gets the number of bits of the input addresses of stage-1, i.e., the size of
+ * the hypervisor's virtual address space.
+ */
+uint8_t arch_mm_stage1_input_bits(void);
+
+/**
+ * Gets the number of bits of the input addresses of stage-2, i.e., the size of
+ * the intermediate physical address space, which is that of the physical
+ * address space detected in arch_mm_init().
+ */
+uint8_t arch_mm_stage2_input_bits(void);
+
 /**
  * Gets the maximum level allowed in the page table for stage-1.
  */
@@ -152,6 +190,11 @@ uint64_t arch_mm_mode_to_stage1_attrs(int mode);
  */
 uint64_t arch_mm_mode_to_stage2_attrs(int mode);
 
+/**
+ * Converts the stage-1 block attributes back to the corresponding mode.
+ */
+int arch_mm_stage1_attrs_to_mode(uint64_t attrs);
+
 /**
  * Converts the stage-2 block attributes back to the corresponding mode.
  */
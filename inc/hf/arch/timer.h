@@ -31,6 +31,22 @@ void arch_timer_mask(struct arch_regs *regs);
  */
 bool arch_timer_enabled(struct arch_regs *regs);
 
+/**
+ * Returns the current value of the virtual counter, in timer ticks.
+ */
+uint64_t arch_timer_now_ticks(void);
+
+/**
+ * Converts a number of timer ticks to the equivalent number of nanoseconds.
+ */
+uint64_t arch_timer_ticks_to_ns(uint64_t ticks);
+
+/**
+ * Converts a number of timer ticks to the equivalent number of microseconds,
+ * without overflowing for any value of the counter.
+ */
+uint64_t arch_timer_ticks_to_us(uint64_t ticks);
+
 /**
  * Returns the number of ticks remaining on the virtual timer as stored in
  * the given `arch_regs`, or 0 if it has already expired. This is undefined if
@@ -32,6 +32,9 @@ struct mem_range {
 
 struct boot_params {
 	uint64_t cpu_ids[MAX_CPUS];
+	/* The index of the cluster of each CPU, and of its core therein. */
+	uint32_t cpu_clusters[MAX_CPUS];
+	uint32_t cpu_cores[MAX_CPUS];
 	size_t cpu_count;
 	struct mem_range mem_ranges[MAX_MEM_RANGES];
 	size_t mem_ranges_count;
@@ -0,0 +1,44 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#include "hf/memiter.h"
+
+/* Keep macro alignment */
+/* clang-format off */
+
+/* The capabilities a VM needs for some hypercalls. */
+#define HF_CAPABILITY_MEMORY_SHARING      UINT32_C(0x1)
+#define HF_CAPABILITY_INTROSPECTION       UINT32_C(0x2)
+#define HF_CAPABILITY_SMC_FORWARDING      UINT32_C(0x4)
+#define HF_CAPABILITY_INTERRUPT_INJECTION UINT32_C(0x8)
+
+/* The capabilities of a VM unless `caps.txt` says otherwise. */
+#define HF_CAPABILITY_DEFAULT             UINT32_C(0x7)
+
+/* clang-format on */
+
+/**
+ * Loads the capabilities of the VM, whose kernel has the given name, from
+ * `caps.txt` in the RAM disk into `caps`. A VM without an entry has
+ * HF_CAPABILITY_DEFAULT. Returns false if `caps.txt` is malformed.
+ */
+bool capabilities_load(const struct memiter *cpio, const struct memiter *name,
+		       uint32_t *caps);
@@ -25,6 +25,7 @@
 #include "hf/addr.h"
 #include "hf/spinlock.h"
 
+#include "vmapi/hf/abi.h"
 #include "vmapi/hf/types.h"
 
 /** The number of bits in each element of the interrupt bitfields. */
@@ -63,6 +64,28 @@ struct interrupts {
 	uint32_t enabled_and_pending_count;
 };
 
+/**
+ * Counters of how a vCPU has run, reported to the primary VM. They are only
+ * updated by the CPU running the vCPU, except for the number of injected
+ * interrupts, which is updated with the vCPU's lock held.
+ */
+struct vcpu_stats {
+	/* The number of exits to the hypervisor, by reason. */
+	uint64_t hypercall_exits;
+	uint64_t wfi_exits;
+	uint64_t page_fault_exits;
+	uint64_t interrupt_exits;
+
+	/** The number of virtual interrupts injected into the vCPU. */
+	uint64_t injected_interrupts;
+
+	/** The time the vCPU has run for, in timer ticks. */
+	uint64_t run_ticks;
+
+	/** The value of the counter when the vCPU last started running. */
+	uint64_t run_start;
+};
+
 struct vcpu_fault_info {
 	ipaddr_t ipaddr;
 	vaddr_t vaddr;
@@ -91,6 +114,33 @@ struct vcpu {
 	 * back to true when it is descheduled.
 	 */
 	bool regs_available;
+
+	/**
+	 * Whether the vCPU is handling a direct request from the primary VM,
+	 * which it must respond to with FFA_MSG_SEND_DIRECT_RESP.
+	 */
+	bool direct_request;
+
+	/**
+	 * The value of the virtual counter at which a blocking mailbox receive
+	 * times out, or 0 if it waits indefinitely. Only meaningful while the
+	 * vCPU is in VCPU_STATE_BLOCKED_MAILBOX.
+	 */
+	uint64_t recv_deadline;
+
+	/**
+	 * Bitmap of the indices of the physical CPUs the vCPU is allowed to run
+	 * on. The scheduler can't run it on any other.
+	 */
+	uint64_t affinity;
+
+	/**
+	 * The physical CPU into whose FP/SIMD registers the state of the vCPU
+	 * was last loaded, or NULL if it hasn't been; see hfo2/src/cpu.rs.
+	 */
+	struct cpu *fp_cpu;
+
+	struct vcpu_stats stats;
 };
 
 /** Encapsulates a vCPU whose lock is held. */
@@ -115,17 +165,55 @@ struct cpu {
 	/** See api.c for the partial ordering on locks. */
 	struct spinlock lock;
 
-	/** Determines whether or not the cpu is currently on. */
+	/**
+	 * Determines whether or not the cpu is currently on. Only changed with
+	 * `lock` held; see hfo2/src/cpu.rs.
+	 */
 	bool is_on;
+
+	/**
+	 * The vCPU whose FP/SIMD state was last loaded into the registers of
+	 * the CPU, if any. Only accessed by the CPU itself.
+	 */
+	const struct vcpu *fp_owner;
+
+	/**
+	 * The CPU_IPI_* requests sent to the CPU which it has not acknowledged
+	 * yet; see hfo2/src/cpu.rs.
+	 */
+	uint32_t ipi_pending;
+
+	/** The index of the cluster of the CPU, and of its core therein. */
+	uint32_t cluster;
+	uint32_t core;
 };
 
-void cpu_module_init(const uint64_t *cpu_ids, size_t count);
+/** Return to the primary VM instead of resuming a secondary VM's vCPU. */
+#define CPU_IPI_RESCHEDULE (UINT32_C(1) << 0)
+
+/** Complete earlier TLB invalidations before memory is reused. */
+#define CPU_IPI_TLB_SYNC (UINT32_C(1) << 1)
+
+/** Stop running VMs because the hypervisor is panicking. */
+#define CPU_IPI_STOP (UINT32_C(1) << 2)
+
+void cpu_module_init(const uint64_t *cpu_ids, const uint32_t *cpu_clusters,
+		     const uint32_t *cpu_cores, size_t count);
 
 size_t cpu_index(struct cpu *c);
 void cpu_irq_enable(struct cpu *c);
 void cpu_irq_disable(struct cpu *c);
 bool cpu_on(struct cpu *c, ipaddr_t entry, uintreg_t arg);
 void cpu_off(struct cpu *c);
+bool cpu_is_on(const struct cpu *c);
+bool cpu_fp_is_live(const struct cpu *c, const struct vcpu *vcpu);
+void cpu_fp_take(struct cpu *c, struct vcpu *vcpu);
+void cpu_fp_forget(struct cpu *c);
+void cpu_ipi_send(const struct cpu *c, uint32_t requests);
+uint32_t cpu_ipi_handle(const struct cpu *c);
+void cpu_ipi_broadcast(const struct cpu *c, uint32_t requests);
+size_t cpu_get_count(void);
+size_t cpu_topology_get(struct hf_cpu_topology *topology, size_t max);
 struct cpu *cpu_find(uint64_t id);
 
 struct vcpu_locked vcpu_lock(struct vcpu *vcpu);
@@ -133,6 +221,10 @@ void vcpu_unlock(struct vcpu_locked *locked);
 void vcpu_init(struct vcpu *vcpu, struct vm *vm);
 void vcpu_on(struct vcpu_locked vcpu, ipaddr_t entry, uintreg_t arg);
 size_t vcpu_index(const struct vcpu *vcpu);
+struct vcpu *vcpu_sibling(struct vcpu *vcpu, uint32_t index);
+uint32_t vcpu_vm_capabilities(const struct vcpu *vcpu);
+struct cpu *vcpu_fp_cpu(const struct vcpu *vcpu);
+void vcpu_set_fp_cpu(struct vcpu *vcpu, struct cpu *c);
 bool vcpu_is_off(struct vcpu_locked vcpu);
 bool vcpu_secondary_reset_and_start(struct vcpu *vcpu, ipaddr_t entry,
 				    uintreg_t arg);
@@ -17,6 +17,8 @@
 #pragma once
 
 #include <stdarg.h>
+#include <stddef.h>
+#include <stdint.h>
 
 #if DEBUG
 void dlog_enable_lock(void);
@@ -27,3 +29,35 @@ void vdlog(const char *fmt, va_list args);
 #define dlog(...)
 #define vdlog(fmt, args)
 #endif
+
+/**
+ * Logs a character in the invocation of the debug log by the given CPU, as
+ * returned by `dlog_begin()`. It is buffered by the CPU until its line is
+ * complete. The buffers are implemented in dlog.rs.
+ */
+void dlog_putchar(size_t cpu, char c);
+void dlog_enable_buffers(void);
+
+/**
+ * Starts an invocation of the debug log, whose time prefixes the lines it
+ * starts. Returns the current CPU, to be passed to `dlog_putchar()` and
+ * `dlog_end()`.
+ */
+size_t dlog_begin(void);
+
+/**
+ * Ends the invocation of the debug log by the given CPU.
+ */
+void dlog_end(size_t cpu);
+
+/**
+ * Moves the oldest bytes of the hypervisor's log to `buf`, as many as fit, and
+ * returns the number of bytes written.
+ */
+size_t dlog_drain(uint8_t *buf, size_t size);
+
+/**
+ * Moves the oldest lines the given VM wrote with hf_dlog to `buf`, as much as
+ * fits, and returns the number of bytes written.
+ */
+size_t guest_log_drain(uint16_t vm_id, uint8_t *buf, size_t size);
@@ -24,8 +24,7 @@
 struct fdt_header *fdt_map(paddr_t fdt_addr, struct fdt_node *n,
 			   struct mpool *ppool);
 bool fdt_unmap(struct fdt_header *fdt, struct mpool *ppool);
-void fdt_find_cpus(const struct fdt_node *root, uint64_t *cpu_ids,
-		   size_t *cpu_count);
+void fdt_find_cpus(const struct fdt_node *root, struct boot_params *p);
 void fdt_find_memory_ranges(const struct fdt_node *root, struct boot_params *p);
 bool fdt_find_initrd(struct fdt_node *n, paddr_t *begin, paddr_t *end);
 
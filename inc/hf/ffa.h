@@ -0,0 +1,68 @@
+/*
+ * Copyright 2019 The Hafnium Authors.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#include "hf/arch/types.h"
+
+#include "hf/cpu.h"
+
+/**
+ * The arguments or results of an FF-A call, in x0-x7.
+ */
+struct ffa_value {
+	uintreg_t func;
+	uintreg_t arg1;
+	uintreg_t arg2;
+	uintreg_t arg3;
+	uintreg_t arg4;
+	uintreg_t arg5;
+	uintreg_t arg6;
+	uintreg_t arg7;
+};
+
+/** The partition supports receiving direct requests. */
+#define FFA_PARTITION_DIRECT_RECV  (UINT32_C(1) << 0)
+
+/** The partition can send direct requests. */
+#define FFA_PARTITION_DIRECT_SEND  (UINT32_C(1) << 1)
+
+/** The partition supports indirect messages through its RX/TX buffers. */
+#define FFA_PARTITION_INDIRECT_MSG (UINT32_C(1) << 2)
+
+/**
+ * The descriptor of a partition written to the RX buffer by
+ * FFA_PARTITION_INFO_GET, in the format of FF-A v1.1 which includes the UUID.
+ */
+struct ffa_partition_info {
+	uint16_t vm_id;
+	uint16_t vcpu_count;
+	uint32_t properties;
+	uint32_t uuid[4];
+};
+
+/**
+ * Handles an FF-A call of the current vCPU and writes the results to `ret`.
+ * `next` is set to the vCPU to run next if the current vCPU is to be switched
+ * out.
+ *
+ * Returns whether the function ID is in the FF-A range.
+ */
+bool ffa_handler(struct vcpu *current, const struct ffa_value *args,
+		 struct ffa_value *ret, struct vcpu **next);
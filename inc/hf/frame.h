@@ -0,0 +1,40 @@
+/*
+ * Copyright 2019 The Hafnium Authors.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#include "hf/addr.h"
+#include "hf/mpool.h"
+
+/**
+ * Allocates the table of the owners of the memory pages in [begin, end).
+ */
+bool frame_table_init(paddr_t begin, paddr_t end, struct mpool *ppool);
+
+/**
+ * Frees the table of the owners of the memory pages to the memory pool it was
+ * allocated from, e.g. so that each test starts with a table of its own.
+ */
+void frame_table_fini(struct mpool *ppool);
+
+/**
+ * Gets the ID of the VM owning the page containing the given address. Returns
+ * false if the page has no owner.
+ */
+bool frame_table_get_owner(paddr_t pa, uint32_t *owner);
@@ -0,0 +1,30 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include "hf/arch/types.h"
+
+#include "hf/cpu.h"
+
+/**
+ * Decodes and handles a hypercall that is not a PSCI call, updating `next` if
+ * the vCPU to run next changes. Returns the value of the return register, which
+ * is -1 if the call is unknown or its arguments are invalid.
+ */
+uintreg_t hypercall_handler(struct vcpu *current, uintreg_t arg0,
+			    uintreg_t arg1, uintreg_t arg2, uintreg_t arg3,
+			    struct vcpu **next);
@@ -0,0 +1,90 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include <stdbool.h>
+
+#include "hf/spci.h"
+
+enum mailbox_state {
+	/** There is no message in the mailbox. */
+	MAILBOX_STATE_EMPTY,
+
+	/** There is a message in the mailbox that is waiting for a reader. */
+	MAILBOX_STATE_RECEIVED,
+
+	/** There is a message in the mailbox that has been read. */
+	MAILBOX_STATE_READ,
+};
+
+/**
+ * The state of a mailbox. Its fields must not be accessed directly, but only
+ * through the transitions below, which are implemented in mailbox.rs and keep
+ * them consistent. The lock of the VM owning the mailbox must be held.
+ *
+ * Once a message is received or the mailbox is filled, the receive buffer is
+ * owned by the VM until it clears the mailbox.
+ */
+struct mailbox_protocol {
+	enum mailbox_state recv;
+	struct {
+		enum {
+			MAILBOX_SEND_FREE,
+			MAILBOX_SEND_PENDING,
+		} tag;
+		spci_vm_id_t sender;
+	} send;
+};
+
+void mailbox_reset(struct mailbox_protocol *protocol);
+enum mailbox_state mailbox_state(const struct mailbox_protocol *protocol);
+
+/**
+ * Writes the VM whose message is being received in fragments to `sender` and
+ * returns true, or returns false if there is none.
+ */
+bool mailbox_pending_sender(const struct mailbox_protocol *protocol,
+			    spci_vm_id_t *sender);
+
+/** Returns whether the mailbox is empty and no fragments are pending. */
+bool mailbox_is_idle(const struct mailbox_protocol *protocol);
+
+/**
+ * Moves the mailbox from empty to received for a message from `from`, which is
+ * pending until its last fragment unless `more` is false. Fails unless the
+ * mailbox is empty and no other VM's message is being received in fragments.
+ */
+bool mailbox_receive(struct mailbox_protocol *protocol, spci_vm_id_t from,
+		     bool more);
+
+/** Drops the pending fragments, e.g. because their sender aborted. */
+void mailbox_abandon(struct mailbox_protocol *protocol);
+
+/** Moves the mailbox from received to read. Fails if it is not received. */
+bool mailbox_read(struct mailbox_protocol *protocol);
+
+/**
+ * Moves the mailbox from empty to read, for the hypervisor to write to the
+ * buffer on behalf of its owner. Fails if it is not empty.
+ */
+bool mailbox_fill(struct mailbox_protocol *protocol);
+
+/**
+ * Moves the mailbox from read to empty, and returns the state it was in. It is
+ * left as it was unless it was read.
+ */
+enum mailbox_state mailbox_clear(struct mailbox_protocol *protocol);
@@ -0,0 +1,136 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#include "hf/fdt.h"
+#include "hf/memiter.h"
+
+/* Keep macro alignment */
+/* clang-format off */
+
+/* The maximum size of a name in the manifest, including the null terminator. */
+#define MANIFEST_NAME_MAX         32
+
+/* The maximum number of memory regions of a VM. */
+#define MANIFEST_MAX_REGIONS      4
+
+/* The maximum number of devices assigned to a VM. */
+#define MANIFEST_MAX_DEVICES      4
+
+/* The maximum number of memory regions shared among VMs. */
+#define MANIFEST_MAX_SHARED       4
+
+/* The restart policies of a VM, i.e. what is done when it aborts. */
+#define MANIFEST_RESTART_NEVER    0
+#define MANIFEST_RESTART_ON_ABORT 1
+
+/* The value of a property the manifest doesn't set. */
+#define MANIFEST_UNSET            UINT32_MAX
+
+/* clang-format on */
+
+/** A memory region of a secondary VM described by the manifest. */
+struct manifest_region {
+	/** The first address, or 0 to take it from any available memory. */
+	uint64_t base;
+	uint64_t size;
+
+	/** The MM_MODE_R, MM_MODE_W and MM_MODE_X bits of the VM's access. */
+	uint32_t mode;
+};
+
+/** A device assigned to a secondary VM by the manifest. */
+struct manifest_device {
+	/** The MMIO range of the device. */
+	uint64_t base;
+	uint64_t size;
+
+	/** The interrupt ID of the device, or 0 if it has none. */
+	uint32_t intid;
+};
+
+/** A read-only memory region the hypervisor owns and shares among VMs. */
+struct manifest_shared {
+	/** The name of the file in the RAM disk it is loaded with. */
+	char filename[MANIFEST_NAME_MAX];
+
+	/** The first address, the same for the VMs as in physical memory. */
+	uint64_t base;
+
+	/** The MM_MODE_R and MM_MODE_X bits of the VMs' access. */
+	uint32_t mode;
+};
+
+/**
+ * A secondary VM described by the manifest, which is read from the
+ * `hypervisor` node of the FDT, or from `vms.txt` without one.
+ */
+struct manifest_vm {
+	/** The name of the VM in logs. */
+	char debug_name[MANIFEST_NAME_MAX];
+
+	/** The name of the VM's kernel in the RAM disk. */
+	char kernel_filename[MANIFEST_NAME_MAX];
+
+	/** The size of all the memory regions of the VM. */
+	uint64_t mem_size;
+
+	/** The memory regions, the first of which the kernel is loaded in. */
+	struct manifest_region regions[MANIFEST_MAX_REGIONS];
+	uint32_t region_count;
+
+	uint32_t vcpu_count;
+
+	/** The HF_CAPABILITY_* bits of the VM, or MANIFEST_UNSET. */
+	uint32_t capabilities;
+
+	/** The HF_PRIORITY_* class of the VM, or MANIFEST_UNSET. */
+	uint32_t priority;
+
+	/** The most memory mapped into the VM in bytes, or 0 if not limited. */
+	uint64_t mem_quota;
+
+	/** MANIFEST_RESTART_NEVER or MANIFEST_RESTART_ON_ABORT. */
+	uint32_t restart_policy;
+
+	/** The UUID of the VM, in FF-A register order, or zero if it has none. */
+	uint32_t uuid[4];
+
+	/** The devices whose MMIO ranges are mapped into the VM. */
+	struct manifest_device devices[MANIFEST_MAX_DEVICES];
+	uint32_t device_count;
+
+	/** The position in the boot order, or MANIFEST_UNSET to boot last. */
+	uint32_t boot_order;
+
+	/** Whether the VM is held suspended until the primary VM resumes it. */
+	bool boot_hold;
+
+	/** The shared regions mapped into the VM, bit N for the Nth region. */
+	uint32_t shared_regions;
+};
+
+bool manifest_init(const struct fdt_node *root);
+bool manifest_load_vms_txt(const struct memiter *cpio);
+size_t manifest_vm_count(void);
+bool manifest_vm_get(size_t index, struct manifest_vm *vm);
+size_t manifest_shared_count(void);
+bool manifest_shared_get(size_t index, struct manifest_shared *shared);
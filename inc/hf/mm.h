@@ -18,6 +18,7 @@
 
 #include <stdalign.h>
 #include <stdbool.h>
+#include <stddef.h>
 #include <stdint.h>
 
 #include "hf/arch/mm.h"
@@ -38,6 +39,8 @@
 #define MM_MODE_W 0x0002 /* write */
 #define MM_MODE_X 0x0004 /* execute */
 #define MM_MODE_D 0x0008 /* device */
+#define MM_MODE_NC 0x0080 /* non-cacheable normal memory */
+#define MM_MODE_WC 0x0100 /* write-combining normal memory */
 
 /*
  * Memory in stage-1 is either valid (present) or invalid (absent).
@@ -66,10 +69,16 @@
  *  - !V !O !X : Invalid memory. Memory is unrelated to the VM.
  *
  *  Modes are selected so that owner of exclusive memory is the default.
+ *
+ * Memory owned by the hypervisor rather than by a VM, e.g. a region the
+ * manifest shares among VMs, is also marked H. It is only mapped as
+ * V !O !X H, read-only, into the VMs it is shared with, none of which may pass
+ * it on to another VM or return it to an owner.
  */
 #define MM_MODE_INVALID 0x0010
 #define MM_MODE_UNOWNED 0x0020
 #define MM_MODE_SHARED  0x0040
+#define MM_MODE_HYP     0x0200
 
 /* clang-format on */
 
@@ -81,28 +90,68 @@ static_assert(sizeof(struct mm_page_table) == PAGE_SIZE,
 static_assert(alignof(struct mm_page_table) == PAGE_SIZE,
 	      "A page table must be page aligned.");
 
+/** Page table memory accounting. */
+struct mm_ptable_stats {
+	/** The number of pages allocated for the page table. */
+	size_t allocated;
+	/** The number of pages of the page table that are freed. */
+	size_t freed;
+};
+
 struct mm_ptable {
 	/** Address of the root of the page table. */
 	paddr_t root;
+	/** Memory accounting of the page table. */
+	struct mm_ptable_stats stats;
+	/**
+	 * Sequence number for lock-free readers, which is odd while the page
+	 * table is being updated.
+	 */
+	size_t seq;
+	/** ID of the VM owning the mapped memory in the frame table. */
+	uint32_t owner;
+	/**
+	 * Pages write-protected for dirty page tracking, whose bits are cleared
+	 * when they are remapped. The page table owns the pages of the bitmap.
+	 */
+	struct {
+		uintpaddr_t begin;
+		uintpaddr_t end;
+		uint8_t *bitmap;
+		size_t bitmap_pages;
+	} write_protected;
 };
 
 void mm_vm_enable_invalidation(void);
+void mm_vm_enable_wx_strict(void);
+bool mm_vm_wx_allow(paddr_t begin, paddr_t end);
+bool mm_vm_wx_disallow(paddr_t begin, paddr_t end);
 
 bool mm_vm_init(struct mm_ptable *t, struct mpool *ppool);
+void mm_vm_set_owner(struct mm_ptable *t, uint32_t owner);
 void mm_vm_fini(struct mm_ptable *t, struct mpool *ppool);
 bool mm_vm_identity_map(struct mm_ptable *t, paddr_t begin, paddr_t end,
 			int mode, ipaddr_t *ipa, struct mpool *ppool);
 bool mm_vm_unmap(struct mm_ptable *t, paddr_t begin, paddr_t end,
 		 struct mpool *ppool);
+bool mm_vm_unmap_scrub(struct mm_ptable *t, paddr_t begin, paddr_t end,
+		       struct mpool *ppool);
+bool mm_vm_reclaim(struct mm_ptable *t, struct mm_ptable *to,
+		   struct mpool *ppool);
 bool mm_vm_unmap_hypervisor(struct mm_ptable *t, struct mpool *ppool);
 void mm_vm_defrag(struct mm_ptable *t, struct mpool *ppool);
 void mm_vm_dump(struct mm_ptable *t);
 bool mm_vm_get_mode(struct mm_ptable *t, ipaddr_t begin, ipaddr_t end,
 		    int *mode);
+void mm_vm_get_stats(const struct mm_ptable *t,
+		     struct mm_ptable_stats *stats);
+size_t mm_vm_mapped_pages(const struct mm_ptable *t);
 
 bool mm_init(struct mpool *ppool);
 bool mm_cpu_init(void);
 void *mm_identity_map(paddr_t begin, paddr_t end, int mode,
 		      struct mpool *ppool);
+void *mm_map(vaddr_t va_begin, vaddr_t va_end, paddr_t pa_begin, int mode,
+	     struct mpool *ppool);
 bool mm_unmap(paddr_t begin, paddr_t end, struct mpool *ppool);
 void mm_defrag(struct mpool *ppool);
@@ -18,22 +18,65 @@
 
 #include <stdbool.h>
 #include <stddef.h>
+#include <stdint.h>
 
 #include "hf/spinlock.h"
 
+/** Memory pool accounting. */
+struct mpool_stats {
+	/** The number of successful allocations. */
+	size_t allocs;
+	/** The number of frees, including the chunks added to the pool. */
+	size_t frees;
+	/** The number of failed allocations, including from the fallbacks. */
+	size_t failures;
+	/** The number of pages currently free in the pool. */
+	size_t free_pages;
+	/**
+	 * The minimum number of free pages observed right after an allocation,
+	 * or SIZE_MAX if nothing has been allocated yet.
+	 */
+	size_t min_free_pages;
+};
+
+/** The maximum order of the blocks of the buddy allocator. */
+#define MPOOL_BUDDY_MAX_ORDER 10
+
+/** Lock-free stack of free single pages. */
+struct mpool_page_stack {
+	uintptr_t head;
+	size_t allocs;
+	size_t frees;
+};
+
 struct mpool {
 	struct spinlock lock;
 	struct mpool_chunk *chunk_list;
-	struct mpool_entry *entry_list;
+	struct mpool_entry *free_lists[MPOOL_BUDDY_MAX_ORDER + 1];
+	struct mpool_stats stats;
+	size_t watermark;
+	void (*low_memory_handler)(struct mpool *p);
+	bool low;
+	size_t reserved;
+	struct mpool_page_stack stack;
+	bool unlocked_pop;
 	struct mpool *fallback;
 };
 
 void mpool_enable_locks(void);
 void mpool_init(struct mpool *p, size_t entry_size);
 void mpool_init_from(struct mpool *p, struct mpool *from);
+size_t mpool_init_from_byte_range(struct mpool *p, void *begin, void *end);
 void mpool_init_with_fallback(struct mpool *p, struct mpool *fallback);
 void mpool_fini(struct mpool *p);
 bool mpool_add_chunk(struct mpool *p, void *begin, size_t size);
 void *mpool_alloc(struct mpool *p);
 void *mpool_alloc_contiguous(struct mpool *p, size_t count, size_t align);
+void *mpool_alloc_aligned(struct mpool *p, size_t count, size_t align_pages);
+void *mpool_alloc_reserved(struct mpool *p);
 void mpool_free(struct mpool *p, void *ptr);
+void mpool_set_low_memory_handler(struct mpool *p, size_t watermark,
+				  void (*handler)(struct mpool *p));
+void mpool_set_reserve(struct mpool *p, size_t pages);
+void mpool_get_stats(const struct mpool *p, struct mpool_stats *stats);
+void mpool_dump_stats(const struct mpool *p);
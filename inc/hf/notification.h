@@ -0,0 +1,32 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include <stdint.h>
+
+#include "vmapi/hf/spci.h"
+
+/**
+ * Makes the given notification bits pending for the VM. Returns the bits that
+ * were not pending before.
+ */
+uint64_t notification_set(spci_vm_id_t vm_id, uint64_t bits);
+
+/**
+ * Takes the pending notification bits of the VM, clearing them.
+ */
+uint64_t notification_take(spci_vm_id_t vm_id);
@@ -0,0 +1,46 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#include "hf/memiter.h"
+
+/* Keep macro alignment */
+/* clang-format off */
+
+/* The priority classes of VMs, highest first. */
+#define HF_PRIORITY_HIGH   UINT32_C(0)
+#define HF_PRIORITY_NORMAL UINT32_C(1)
+#define HF_PRIORITY_LOW    UINT32_C(2)
+
+/* clang-format on */
+
+/**
+ * Loads the priority class of the VM, whose kernel has the given name, from
+ * `priorities.txt` in the RAM disk into `priority`. A VM without an entry has
+ * HF_PRIORITY_NORMAL. Returns false if `priorities.txt` is malformed.
+ */
+bool priority_load(const struct memiter *cpio, const struct memiter *name,
+		   uint32_t *priority);
+
+void run_queue_push(size_t cpu_index, uint16_t vm_id, uint16_t vcpu_idx,
+		    uint32_t priority);
+int64_t run_queue_pop(size_t cpu_index);
+void run_queue_remove(uint16_t vm_id, uint16_t vcpu_idx);
@@ -0,0 +1,37 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#include "hf/memiter.h"
+
+#include "vmapi/hf/spci.h"
+
+/**
+ * Loads the ranges of SMC function IDs that the VM, whose kernel has the given
+ * name, may forward to EL3 from `smc.txt` in the RAM disk. Returns false if
+ * `smc.txt` is malformed.
+ */
+bool smc_allowlist_load(spci_vm_id_t vm_id, const struct memiter *cpio,
+			const struct memiter *name);
+
+/**
+ * Returns whether the VM may forward an SMC with the given function ID to EL3.
+ */
+bool smc_is_allowed(spci_vm_id_t vm_id, uint32_t func);
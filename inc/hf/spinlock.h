@@ -16,18 +16,32 @@
 
 #pragma once
 
-#include <stdatomic.h>
+#include <stdbool.h>
+#include <stdint.h>
 
+/**
+ * A ticket lock, which is acquired in the order CPUs start waiting for it.
+ */
 struct spinlock {
-	atomic_flag v;
+	/** The ticket to be taken next. */
+	uint32_t next;
+	/** The ticket of the CPU holding the lock, or to be served next. */
+	uint32_t owner;
 };
 
 #define SPINLOCK_INIT                 \
 	{                             \
-		.v = ATOMIC_FLAG_INIT \
+		.next = 0, .owner = 0 \
 	}
 
 void sl_init(struct spinlock *l);
 void sl_lock(struct spinlock *l);
+bool sl_try_lock(struct spinlock *l);
 void sl_lock_both(struct spinlock *a, struct spinlock *b);
 void sl_unlock(struct spinlock *l);
+
+/**
+ * Dumps the lock contention counters to the log. Returns the number of named
+ * locks, or -1 if the counters are not enabled.
+ */
+int64_t lock_stats_dump(void);
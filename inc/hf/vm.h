@@ -22,21 +22,12 @@
 
 #include "hf/cpu.h"
 #include "hf/list.h"
+#include "hf/mailbox.h"
+#include "hf/manifest.h"
 #include "hf/mm.h"
 #include "hf/mpool.h"
 #include "hf/spci.h"
 
-enum mailbox_state {
-	/** There is no message in the mailbox. */
-	MAILBOX_STATE_EMPTY,
-
-	/** There is a message in the mailbox that is waiting for a reader. */
-	MAILBOX_STATE_RECEIVED,
-
-	/** There is a message in the mailbox that has been read. */
-	MAILBOX_STATE_READ,
-};
-
 struct wait_entry {
 	/** The VM that is waiting for a mailbox to become writable. */
 	struct vm *waiting_vm;
@@ -55,10 +46,18 @@ struct wait_entry {
 };
 
 struct mailbox {
-	enum mailbox_state state;
+	/** The state of the mailbox, changed only by the mailbox_* functions. */
+	struct mailbox_protocol protocol;
 	struct spci_message *recv;
 	const struct spci_message *send;
 
+	/**
+	 * The modes the VM had for the send and receive pages before they were
+	 * configured, which are restored when they are unmapped.
+	 */
+	int send_mode;
+	int recv_mode;
+
 	/**
 	 * List of wait_entry structs representing VMs that want to be notified
 	 * when the mailbox becomes writable. Once the mailbox does become
@@ -72,10 +71,58 @@ struct mailbox {
 	 * writable since the owner of the mailbox registers for notification.
 	 */
 	struct list_entry ready_list;
+
+	/**
+	 * Whether notifying the primary VM of messages delivered to the mailbox
+	 * is deferred because the VM is suspended. They are reported when it
+	 * is resumed.
+	 */
+	bool deferred;
+};
+
+/** A memory region of a VM and the mode it is mapped with when it is loaded. */
+struct vm_region {
+	paddr_t begin;
+	paddr_t end;
+	int mode;
+};
+
+/** A device assigned to a VM, whose MMIO range is mapped into it. */
+struct vm_device {
+	paddr_t begin;
+	paddr_t end;
+
+	/**
+	 * The interrupt ID of the device, or 0 if it has none, which the primary
+	 * VM routes to the VM.
+	 */
+	uint32_t intid;
+};
+
+/** What is needed to restart a VM from its kernel when it aborts. */
+struct vm_restart {
+	/** Whether the VM is restarted when it aborts. */
+	bool enabled;
+
+	/**
+	 * A copy of the VM's kernel, kept in memory reserved from the primary
+	 * VM as it may reuse the RAM disk once it boots.
+	 */
+	paddr_t image_begin;
+	paddr_t image_end;
+
+	/** The regions of the VM, the first of which the kernel is loaded in. */
+	struct vm_region regions[MANIFEST_MAX_REGIONS];
+	uint32_t region_count;
+
+	/** The number of times the VM has been restarted. */
+	uint32_t count;
 };
 
 struct vm {
 	spci_vm_id_t id;
+	/** The VMID the VM's stage-2 TLB entries are tagged with. */
+	uint16_t vmid;
 	/** See api.c for the partial ordering on locks. */
 	struct spinlock lock;
 	uint32_t vcpu_count;
@@ -88,6 +135,61 @@ struct vm {
 
 	atomic_bool aborting;
 
+	/**
+	 * Whether the VM has been destroyed, after which vm_find() no longer
+	 * returns it. It is set with the VM locked, before the page table is
+	 * freed, so a VM found before then must be checked with
+	 * vm_is_destroyed() once it is locked.
+	 */
+	atomic_bool destroyed;
+
+	/** Whether the VM is suspended, in which case its vCPUs are not run. */
+	atomic_bool suspended;
+
+	/** The HF_CAPABILITY_* bits of the hypercalls the VM may make. */
+	uint32_t capabilities;
+
+	/**
+	 * The HF_PRIORITY_* class of the VM, used to order its vCPUs in the run
+	 * queues and its waits on other VMs' mailboxes.
+	 */
+	uint32_t priority;
+
+	/**
+	 * The most memory in bytes that may be mapped into the VM's stage-2
+	 * page table, or 0 if it is not limited.
+	 */
+	uint64_t mem_quota;
+
+	/**
+	 * The UUID of the VM, which FF-A clients address it by, or zero if it
+	 * has none.
+	 */
+	uint32_t uuid[4];
+
+	/** The devices assigned to the VM when it is loaded. */
+	struct vm_device devices[MANIFEST_MAX_DEVICES];
+	uint32_t device_count;
+
+	/**
+	 * The version of the Hafnium API negotiated by the VM with
+	 * hf_api_version(), or 0 if it hasn't, so that incompatible changes can
+	 * be limited to the VMs that expect them. Protected by the VM's lock.
+	 */
+	uint32_t api_version;
+
+	/**
+	 * The number of stage-2 faults the VM could not be resumed from.
+	 * Protected by the VM's lock.
+	 */
+	uint64_t fault_count;
+
+	/**
+	 * How the VM is restarted when it aborts. It is set when the VM is
+	 * loaded, and `count` is protected by the VM's lock.
+	 */
+	struct vm_restart restart;
+
 	/** Arch-specific VM information. */
 	struct arch_vm arch;
 };
@@ -100,6 +202,15 @@ struct vm_locked {
 bool vm_init(uint32_t vcpu_count, struct mpool *ppool, struct vm **new_vm);
 uint32_t vm_get_count(void);
 struct vm *vm_find(spci_vm_id_t id);
+struct vm *vm_find_uuid(const uint32_t uuid[4]);
+bool vm_has_uuid(const struct vm *vm, const uint32_t uuid[4]);
+struct vm *vm_find_intid_owner(uint32_t intid);
 struct vm_locked vm_lock(struct vm *vm);
 void vm_unlock(struct vm_locked *locked);
+bool vm_is_destroyed(struct vm_locked locked);
 struct vcpu *vm_get_vcpu(struct vm *vm, uint32_t vcpu_index);
+bool vm_destroy(struct vm_locked locked, struct vm_locked primary,
+		struct mpool *ppool);
+bool vm_restart(struct vm_locked locked, struct vm_locked primary,
+		struct mpool *ppool);
+bool vm_quota_allows(struct vm_locked locked, uint64_t size);
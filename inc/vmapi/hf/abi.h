@@ -23,7 +23,9 @@ enum hf_vcpu_run_code {
 	/**
 	 * The vCPU has been preempted but still has work to do. If the
 	 * scheduling quantum has not expired, the scheduler MUST call
-	 * `hf_vcpu_run` on the vCPU to allow it to continue.
+	 * `hf_vcpu_run` on the vCPU to allow it to continue. This is also
+	 * returned when the vCPU has used up its time slice, provided the
+	 * scheduler has enabled the hypervisor timer interrupt.
 	 */
 	HF_VCPU_RUN_PREEMPTED = 0,
 
@@ -83,6 +85,23 @@ enum hf_vcpu_run_code {
 	 * `HF_VCPU_RUN_WAKE_UP` for all the other vCPUs of the VM.
 	 */
 	HF_VCPU_RUN_ABORTED = 7,
+
+	/**
+	 * The vCPU has signalled notifications to the primary VM, and
+	 * `hf_vcpu_run_return.notification` identifies the sending VM. The
+	 * scheduler SHOULD call `hf_notification_get` to take the pending
+	 * notifications and MUST call `hf_vcpu_run` on the vCPU at a later
+	 * point.
+	 */
+	HF_VCPU_RUN_NOTIFICATION = 8,
+
+	/**
+	 * The vCPU can't be run on this physical CPU because it is not in the
+	 * vCPU's affinity. The scheduler MUST call `hf_vcpu_run` on the vCPU
+	 * from a physical CPU it is allowed to run on, or change its affinity
+	 * with `hf_vcpu_affinity_set`.
+	 */
+	HF_VCPU_RUN_WRONG_CPU = 9,
 };
 
 struct hf_vcpu_run_return {
@@ -95,6 +114,9 @@ struct hf_vcpu_run_return {
 		struct {
 			spci_vm_id_t vm_id;
 		} message;
+		struct {
+			spci_vm_id_t vm_id;
+		} notification;
 		struct {
 			uint64_t ns;
 		} sleep;
@@ -121,6 +143,94 @@ enum hf_share {
 	HF_MEMORY_SHARE,
 };
 
+/** Describes a VM to be created with `hf_vm_create`. */
+struct hf_vm_create_desc {
+	/**
+	 * The page-aligned range of memory given to the VM, which is mapped at
+	 * the same addresses in it.
+	 */
+	uint64_t mem_begin;
+	uint64_t mem_size;
+
+	/** The address in the VM's memory its first vCPU starts at. */
+	uint64_t entry;
+
+	uint32_t vcpu_count;
+};
+
+/** The state of a vCPU, as reported in `struct hf_vm_stats`. */
+enum hf_vcpu_state {
+	HF_VCPU_STATE_OFF,
+	HF_VCPU_STATE_READY,
+	HF_VCPU_STATE_RUNNING,
+	HF_VCPU_STATE_BLOCKED_MAILBOX,
+	HF_VCPU_STATE_BLOCKED_INTERRUPT,
+	HF_VCPU_STATE_ABORTED,
+};
+
+/** The state of a mailbox, as reported in `struct hf_vm_stats`. */
+enum hf_mailbox_state {
+	HF_MAILBOX_STATE_EMPTY,
+	HF_MAILBOX_STATE_RECEIVED,
+	HF_MAILBOX_STATE_READ,
+};
+
+/** The maximum number of vCPUs whose state is reported for a VM. */
+#define HF_VM_STATS_MAX_VCPUS 64
+
+/** The counters of a vCPU, as reported in `struct hf_vm_stats`. */
+struct hf_vcpu_stats {
+	/** The number of exits to the hypervisor, by reason. */
+	uint64_t hypercall_exits;
+	uint64_t wfi_exits;
+	uint64_t page_fault_exits;
+	uint64_t interrupt_exits;
+
+	/** The number of virtual interrupts injected into the vCPU. */
+	uint64_t injected_interrupts;
+
+	/** The time the vCPU has run for, in nanoseconds. */
+	uint64_t run_ns;
+};
+
+/** The state and resource usage of a VM, as reported by `hf_vm_stats_get`. */
+struct hf_vm_stats {
+	uint32_t vcpu_count;
+
+	/** An `enum hf_mailbox_state`. */
+	uint32_t mailbox_state;
+
+	/** The number of pages mapped in the VM's stage-2 page table. */
+	uint64_t mapped_pages;
+
+	/** The number of pages used by the VM's stage-2 page table itself. */
+	uint64_t page_table_pages;
+
+	/** The number of stage-2 faults the VM could not be resumed from. */
+	uint64_t fault_count;
+
+	/** An `enum hf_vcpu_state` for each of the first vCPUs. */
+	uint8_t vcpu_states[HF_VM_STATS_MAX_VCPUS];
+
+	/** The counters of each of the first vCPUs. */
+	struct hf_vcpu_stats vcpu_stats[HF_VM_STATS_MAX_VCPUS];
+};
+
+/**
+ * Where a physical CPU is in the topology of the system, as reported by
+ * `hf_cpu_topology_get`.
+ */
+struct hf_cpu_topology {
+	/** The MPIDR of the CPU, from which its affinity levels can be read. */
+	uint64_t mpidr;
+
+	/** The index of the cluster of the CPU. */
+	uint32_t cluster;
+
+	/** The index of the core of the CPU in its cluster. */
+	uint32_t core;
+};
+
 /**
  * Encode an hf_vcpu_run_return struct in the 64-bit packing ABI.
  */
@@ -136,6 +246,9 @@ static inline uint64_t hf_vcpu_run_return_encode(struct hf_vcpu_run_return res)
 	case HF_VCPU_RUN_MESSAGE:
 		ret |= res.message.vm_id << 8;
 		break;
+	case HF_VCPU_RUN_NOTIFICATION:
+		ret |= (uint64_t)res.notification.vm_id << 8;
+		break;
 	case HF_VCPU_RUN_WAIT_FOR_INTERRUPT:
 	case HF_VCPU_RUN_WAIT_FOR_MESSAGE:
 		ret |= res.sleep.ns << 8;
@@ -165,6 +278,9 @@ static inline struct hf_vcpu_run_return hf_vcpu_run_return_decode(uint64_t res)
 	case HF_VCPU_RUN_MESSAGE:
 		ret.message.vm_id = res >> 8;
 		break;
+	case HF_VCPU_RUN_NOTIFICATION:
+		ret.notification.vm_id = res >> 8;
+		break;
 	case HF_VCPU_RUN_WAIT_FOR_INTERRUPT:
 	case HF_VCPU_RUN_WAIT_FOR_MESSAGE:
 		ret.sleep.ns = res >> 8;
@@ -36,6 +36,28 @@
 #define HF_INTERRUPT_GET        0xff0c
 #define HF_INTERRUPT_INJECT     0xff0d
 #define HF_SHARE_MEMORY         0xff0e
+#define HF_LOCK_STATS_DUMP      0xff0f
+#define HF_MEMORY_RELINQUISH    0xff10
+#define HF_MEMORY_RECLAIM       0xff11
+#define HF_NOTIFICATION_SET     0xff12
+#define HF_NOTIFICATION_GET     0xff13
+#define HF_MAILBOX_PEEK         0xff14
+#define HF_MAILBOX_BROADCAST    0xff15
+#define HF_API_VERSION_GET      0xff16
+#define HF_DLOG                 0xff17
+#define HF_VM_STATS_GET         0xff18
+#define HF_VM_CREATE            0xff19
+#define HF_VM_UNCONFIGURE       0xff1a
+#define HF_VCPU_AFFINITY_SET    0xff1b
+#define HF_CPU_TOPOLOGY_GET     0xff1c
+#define HF_RUN_QUEUE_POP        0xff1d
+#define HF_VM_DESTROY           0xff1e
+#define HF_VM_LOG_DRAIN         0xff1f
+#define HF_VM_SUSPEND           0xff20
+#define HF_VM_RESUME            0xff21
+#define HF_VM_FIND_UUID         0xff22
+#define HF_INTERRUPT_OWNER_GET  0xff23
+#define HF_DLOG_DRAIN           0xff24
 
 /* clang-format on */
 
@@ -81,6 +103,20 @@ static inline struct hf_vcpu_run_return hf_vcpu_run(spci_vm_id_t vm_id,
 		hf_call(HF_VCPU_RUN, vm_id, vcpu_idx, 0));
 }
 
+/**
+ * Restricts the physical CPUs the given vcpu of the given secondary VM can be
+ * run on to those whose index has its bit set in `affinity`. Running it on any
+ * other returns `HF_VCPU_RUN_WRONG_CPU`. Only the primary VM is allowed to call
+ * this.
+ *
+ * Returns -1 on failure or 0 on success.
+ */
+static inline int64_t hf_vcpu_affinity_set(spci_vm_id_t vm_id,
+					   uint32_t vcpu_idx, uint64_t affinity)
+{
+	return hf_call(HF_VCPU_AFFINITY_SET, vm_id, vcpu_idx, affinity);
+}
+
 /**
  * Hints that the vcpu is willing to yield its current use of the physical CPU.
  * This call always returns SPCI_SUCCESS.
@@ -105,6 +141,18 @@ static inline int64_t hf_vm_configure(hf_ipaddr_t send, hf_ipaddr_t recv)
 	return hf_call(HF_VM_CONFIGURE, send, recv, 0);
 }
 
+/**
+ * Unmaps the pages configured to send/receive data through, giving the VM back
+ * the access it had to them, so that other pages can be configured. The mailbox
+ * must be empty.
+ *
+ * Returns -1 on failure or 0 on success.
+ */
+static inline int64_t hf_vm_unconfigure(void)
+{
+	return hf_call(HF_VM_UNCONFIGURE, 0, 0, 0);
+}
+
 /**
  * Copies data from the sender's send buffer to the recipient's receive buffer.
  *
@@ -141,6 +189,16 @@ static inline int32_t spci_msg_recv(int32_t attributes)
 	return hf_call(SPCI_MSG_RECV_32, attributes, 0, 0);
 }
 
+/**
+ * Called by secondary VMs to receive a message, blocking for at most `timeout`
+ * ticks of the virtual counter. Returns SPCI_TIMEDOUT if no message arrived in
+ * that time. A `timeout` of 0 blocks as long as spci_msg_recv() does.
+ */
+static inline int32_t spci_msg_recv_timeout(uint64_t timeout)
+{
+	return hf_call(SPCI_MSG_RECV_32, SPCI_MSG_RECV_BLOCK, timeout, 0);
+}
+
 /**
  * Clears the caller's mailbox so a new message can be received.
  *
@@ -184,6 +242,33 @@ static inline int64_t hf_mailbox_waiter_get(spci_vm_id_t vm_id)
 	return hf_call(HF_MAILBOX_WAITER_GET, vm_id, 0, 0);
 }
 
+/**
+ * Checks whether a message is pending in the caller's mailbox without
+ * receiving it, so the mailbox is left as it was.
+ *
+ * Returns -1 if no message is pending; otherwise the length of the message in
+ * bits [31:0] and the ID of the VM that sent it in bits [47:32].
+ */
+static inline int64_t hf_mailbox_peek(void)
+{
+	return hf_call(HF_MAILBOX_PEEK, 0, 0, 0);
+}
+
+/**
+ * Called by the primary VM to send the message in its send buffer to every
+ * secondary VM whose mailbox is ready to receive it, rather than to the target
+ * in the message header. The primary VM will be notified when the mailboxes of
+ * the others become writable, as for spci_msg_send() with
+ * SPCI_MSG_SEND_NOTIFY. Only VMs with an ID below 64 are sent to.
+ *
+ * Returns -1 on failure; otherwise a bitmap with bit N set if the message was
+ * delivered to the VM with ID N.
+ */
+static inline int64_t hf_mailbox_broadcast(void)
+{
+	return hf_call(HF_MAILBOX_BROADCAST, 0, 0, 0);
+}
+
 /**
  * Enables or disables a given interrupt ID.
  *
@@ -209,6 +294,10 @@ static inline uint32_t hf_interrupt_get(void)
  * This doesn't cause the vCPU to actually be run immediately; it will be taken
  * when the vCPU is next run, which is up to the scheduler.
  *
+ * The primary VM may inject interrupts into any VM, and so may secondary VMs
+ * that back devices for others and are given the capability to. Other VMs may
+ * only inject interrupts into their own vCPUs.
+ *
  * Returns:
  *  - -1 on failure because the target VM or vCPU doesn't exist, the interrupt
  *    ID is invalid, or the current VM is not allowed to inject interrupts to
@@ -225,6 +314,19 @@ static inline int64_t hf_interrupt_inject(spci_vm_id_t target_vm_id,
 		       intid);
 }
 
+/**
+ * Called by the primary VM to find the VM that is assigned the device with the
+ * given interrupt by the manifest, to route the interrupt to it with
+ * hf_interrupt_inject().
+ *
+ * Returns the ID of the VM, or -1 if the interrupt is not of a device assigned
+ * to a VM.
+ */
+static inline int64_t hf_interrupt_owner_get(uint32_t intid)
+{
+	return hf_call(HF_INTERRUPT_OWNER_GET, intid, 0, 0);
+}
+
 /**
  * Shares a region of memory with another VM.
  *
@@ -240,6 +342,248 @@ static inline int64_t hf_share_memory(spci_vm_id_t vm_id, hf_ipaddr_t addr,
 		       size);
 }
 
+/**
+ * Relinquishes a region of memory lent or shared by the given VM, which owns
+ * it. The memory is no longer accessible by the calling VM, and the owner may
+ * reclaim it.
+ *
+ * Returns 0 on success or -1 if the memory is not borrowed from the given VM.
+ */
+static inline int64_t hf_memory_relinquish(spci_vm_id_t vm_id,
+					   hf_ipaddr_t addr, size_t size)
+{
+	return hf_call(HF_MEMORY_RELINQUISH, vm_id, addr, size);
+}
+
+/**
+ * Reclaims a region of memory lent or shared with the given VM. The memory is
+ * cleared before the calling VM regains exclusive access to it.
+ *
+ * Returns 0 on success or -1 if the memory is not lent or shared with the given
+ * VM, or that VM has not relinquished it yet.
+ */
+static inline int64_t hf_memory_reclaim(spci_vm_id_t vm_id, hf_ipaddr_t addr,
+					size_t size)
+{
+	return hf_call(HF_MEMORY_RECLAIM, vm_id, addr, size);
+}
+
+/**
+ * Signals the given notification bits to another VM. A secondary VM is
+ * notified with an HF_NOTIFICATION_INTID interrupt on its first vCPU, and the
+ * primary VM with an HF_VCPU_RUN_NOTIFICATION return from `hf_vcpu_run`, if any
+ * of the bits was not already pending.
+ *
+ * Returns:
+ *  - -1 on failure, e.g. if the VM does not exist, is the caller, or no bits are
+ *    given.
+ *  - 0 on success if no further action is needed.
+ *  - 1 if it was called by the primary VM and the primary VM now needs to wake
+ *    up or kick the target VM's first vCPU.
+ */
+static inline int64_t hf_notification_set(spci_vm_id_t vm_id, uint64_t bits)
+{
+	return hf_call(HF_NOTIFICATION_SET, vm_id, bits, 0);
+}
+
+/**
+ * Takes the notification bits pending for the calling VM, clearing them.
+ *
+ * Returns the bits that were pending, which may be none.
+ */
+static inline uint64_t hf_notification_get(void)
+{
+	return hf_call(HF_NOTIFICATION_GET, 0, 0, 0);
+}
+
+/**
+ * Dumps the hypervisor's lock contention counters to its log. Only the primary
+ * VM may do so.
+ *
+ * Returns the number of locks dumped, or -1 if the caller is not the primary
+ * VM or the hypervisor does not count lock contention.
+ */
+static inline int64_t hf_lock_stats_dump(void)
+{
+	return hf_call(HF_LOCK_STATS_DUMP, 0, 0, 0);
+}
+
+/**
+ * Negotiates the version of the Hafnium API, given the version the caller
+ * supports, which is usually HF_API_VERSION. If the major revisions match, the
+ * lower of the two versions is used for the caller's VM from then on.
+ *
+ * Returns the version implemented by Hafnium.
+ */
+static inline int64_t hf_api_version(uint32_t requested)
+{
+	return hf_call(HF_API_VERSION_GET, requested, 0, 0);
+}
+
+/**
+ * Called by the primary VM to create a secondary VM as described by the
+ * `struct hf_vm_create_desc` in its send buffer. The memory of the new VM is
+ * taken from the primary VM, which must own it exclusively and is denied access
+ * to it from then on. The first vCPU of the new VM starts at the entry point,
+ * with the size of its memory as the argument.
+ *
+ * Returns the ID of the new VM, or -1 on failure.
+ */
+static inline int64_t hf_vm_create(void)
+{
+	return hf_call(HF_VM_CREATE, 0, 0, 0);
+}
+
+/**
+ * Called by the primary VM to destroy a secondary VM. Its vCPUs are stopped and
+ * the memory it owns exclusively is zeroed and given back to the primary VM.
+ * The VM ID is not reused.
+ *
+ * Returns -1 on failure or 0 on success.
+ */
+static inline int64_t hf_vm_destroy(spci_vm_id_t vm_id)
+{
+	return hf_call(HF_VM_DESTROY, vm_id, 0, 0);
+}
+
+/**
+ * Called by the primary VM to suspend a secondary VM. Its vCPUs are not run
+ * until it is resumed, and messages sent to it are not reported until then.
+ * Pending messages and interrupts are kept.
+ *
+ * Returns -1 on failure or 0 on success.
+ */
+static inline int64_t hf_vm_suspend(spci_vm_id_t vm_id)
+{
+	return hf_call(HF_VM_SUSPEND, vm_id, 0, 0);
+}
+
+/**
+ * Called by the primary VM to resume a suspended secondary VM. This also
+ * releases a VM the manifest holds at boot, once the VMs it depends on are
+ * ready.
+ *
+ * Returns:
+ *  - -1 on failure.
+ *  - 0 on success if no further action is needed.
+ *  - 1 if the VM has pending messages or interrupts, in which case its vCPUs
+ *    that can take them have been queued to be run on this CPU.
+ */
+static inline int64_t hf_vm_resume(spci_vm_id_t vm_id)
+{
+	return hf_call(HF_VM_RESUME, vm_id, 0, 0);
+}
+
+/**
+ * Looks up the VM with the given UUID, e.g. to send direct messages to it. The
+ * words of the UUID are in the order FF-A passes them in registers.
+ *
+ * Returns the ID of the VM, or -1 if no VM has the UUID.
+ */
+static inline int64_t hf_vm_find_uuid(const uint32_t uuid[4])
+{
+	return hf_call(HF_VM_FIND_UUID,
+		       uuid[0] | ((uint64_t)uuid[1] << 32),
+		       uuid[2] | ((uint64_t)uuid[3] << 32), 0);
+}
+
+/**
+ * Called by the primary VM to get the state and resource usage of the given
+ * VM, which are written to its RX buffer as a `struct hf_vm_stats`. The
+ * mailbox must be cleared afterwards.
+ *
+ * Returns -1 on failure, e.g. if the VM doesn't exist or the RX buffer is in
+ * use, or 0 on success.
+ */
+static inline int64_t hf_vm_stats_get(spci_vm_id_t vm_id)
+{
+	return hf_call(HF_VM_STATS_GET, vm_id, 0, 0);
+}
+
+/**
+ * Called by the primary VM to get the topology of the physical CPUs, which is
+ * written to its RX buffer as an array of `struct hf_cpu_topology`, in the
+ * order of the CPUs' indices, i.e. of the primary's vCPUs running on them. The
+ * mailbox must be cleared afterwards.
+ *
+ * Returns -1 on failure, e.g. if the RX buffer is in use, or the number of
+ * CPUs otherwise.
+ */
+static inline int64_t hf_cpu_topology_get(void)
+{
+	return hf_call(HF_CPU_TOPOLOGY_GET, 0, 0, 0);
+}
+
+/**
+ * Called by the primary VM to take the vCPU it should run next on the current
+ * physical CPU from the hypervisor's run queue. vCPUs are queued when they are
+ * woken up, e.g. by an interrupt, and are handed out highest priority class
+ * first.
+ *
+ * Returns -1 if the run queue is empty, or `vm_id << 16 | vcpu_idx` otherwise.
+ */
+static inline int64_t hf_run_queue_pop(void)
+{
+	return hf_call(HF_RUN_QUEUE_POP, 0, 0, 0);
+}
+
+/**
+ * Called by the primary VM to read the recent lines the given VM wrote with
+ * `hf_dlog`, which are moved to its RX buffer, oldest first, as many as fit.
+ * The hypervisor keeps a limited number of lines per VM, overwriting the oldest.
+ * The mailbox must be cleared afterwards.
+ *
+ * Returns -1 on failure, e.g. if the RX buffer is in use, or the number of
+ * bytes written otherwise.
+ */
+static inline int64_t hf_vm_log_drain(spci_vm_id_t vm_id)
+{
+	return hf_call(HF_VM_LOG_DRAIN, vm_id, 0, 0);
+}
+
+/**
+ * Called by the primary VM to read the hypervisor's own log, which is moved to
+ * its RX buffer, oldest first, as much as fits. The hypervisor keeps the most
+ * recent 4096 bytes it logged, including those logged before the console
+ * worked, overwriting the oldest. The mailbox must be cleared afterwards.
+ *
+ * Returns -1 on failure, e.g. if the RX buffer is in use, or the number of
+ * bytes written otherwise.
+ */
+static inline int64_t hf_dlog_drain(void)
+{
+	return hf_call(HF_DLOG_DRAIN, 0, 0, 0);
+}
+
+/**
+ * Writes the given string to the hypervisor's log, in lines prefixed with the
+ * ID of the caller's VM. A line is only logged once its newline is written, and
+ * lines are dropped if the VM writes too many of them in a short time.
+ *
+ * Returns -1 on failure, 0 otherwise.
+ */
+static inline int64_t hf_dlog(const char *str)
+{
+	int64_t ret = 0;
+
+	while (*str != '\0') {
+		uint64_t args[3] = {0, 0, 0};
+		size_t i;
+
+		/* Pack up to 24 characters, the first in the lowest byte. */
+		for (i = 0; i < sizeof(args) && *str != '\0'; i++, str++) {
+			args[i / 8] |= (uint64_t)(unsigned char)*str << (i % 8 * 8);
+		}
+
+		ret = hf_call(HF_DLOG, args[0], args[1], args[2]);
+		if (ret != 0) {
+			break;
+		}
+	}
+
+	return ret;
+}
+
 /** Obtains the Hafnium's version of the implemented SPCI specification. */
 static inline int64_t spci_version(void)
 {
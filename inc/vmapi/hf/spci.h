@@ -45,12 +45,15 @@
 #define SPCI_DENIED             INT32_C(-6)
 /* TODO: return code currently undefined in SPCI alpha2. */
 #define SPCI_RETRY              INT32_C(-7)
+/* TODO: return code not defined in SPCI. */
+#define SPCI_TIMEDOUT           INT32_C(-9)
 
 /* SPCI function specific constants. */
 #define SPCI_MSG_RECV_BLOCK_MASK  0x1
 #define SPCI_MSG_SEND_NOTIFY_MASK 0x1
 
-#define SPCI_MESSAGE_IMPDEF_MASK 0x1
+#define SPCI_MESSAGE_IMPDEF_MASK         0x1
+#define SPCI_MESSAGE_MORE_FRAGMENTS_MASK 0x2
 
 #define SPCI_MSG_SEND_NOTIFY 0x1
 #define SPCI_MSG_RECV_BLOCK  0x1
@@ -74,7 +77,10 @@ struct spci_message {
 	 * flags[0]:
 	 *     0: Architected message payload;
 	 *     1: Implementation defined message payload.
-	 * flags[15:1] reserved (MBZ).
+	 * flags[1]:
+	 *     0: Last or only fragment of the message;
+	 *     1: More fragments of the message follow.
+	 * flags[15:2] reserved (MBZ).
 	 */
 	uint16_t flags;
 
@@ -119,3 +125,18 @@ static inline void spci_message_init(struct spci_message *message,
 	message->reserved_1 = 0;
 	message->reserved_2 = 0;
 }
+
+/**
+ * Marks whether more fragments of the message follow. Until the last fragment
+ * is sent, the recipient's mailbox only accepts fragments from the sender so
+ * that the recipient can reassemble the message from consecutive fragments.
+ */
+static inline void spci_message_set_more_fragments(struct spci_message *message,
+						   bool more)
+{
+	if (more) {
+		message->flags |= SPCI_MESSAGE_MORE_FRAGMENTS_MASK;
+	} else {
+		message->flags &= ~SPCI_MESSAGE_MORE_FRAGMENTS_MASK;
+	}
+}
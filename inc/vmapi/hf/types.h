@@ -39,6 +39,18 @@ typedef uintptr_t hf_ipaddr_t;
 /* Invalid values for fields to indicate absence or errors. */
 #define HF_INVALID_VM_ID 0xffffffff
 
+/**
+ * The version of the Hafnium API, with the major revision in bits [30:16] and
+ * the minor revision in bits [15:0]. Revisions with the same major revision are
+ * compatible.
+ */
+#define HF_API_VERSION_MAJOR 1
+#define HF_API_VERSION_MINOR 0
+#define HF_API_VERSION_MAJOR_OFFSET 16
+#define HF_API_VERSION                                          \
+	((HF_API_VERSION_MAJOR << HF_API_VERSION_MAJOR_OFFSET) | \
+	 HF_API_VERSION_MINOR)
+
 /* Sleep value for an indefinite period of time. */
 #define HF_SLEEP_INDEFINITE 0xffffffffffffff
 
@@ -59,3 +71,6 @@ typedef uintptr_t hf_ipaddr_t;
 
 /** The virtual interrupt ID used for the virtual timer. */
 #define HF_VIRTUAL_TIMER_INTID 3
+
+/** Interrupt ID indicating that notifications are pending. */
+#define HF_NOTIFICATION_INTID 4
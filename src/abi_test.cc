@@ -16,10 +16,16 @@
 
 extern "C" {
 #include "vmapi/hf/abi.h"
+
+/* The Rust implementation of the encoding, to check it against the C one. */
+uint64_t hf_vcpu_run_return_pack(struct hf_vcpu_run_return res);
+struct hf_vcpu_run_return hf_vcpu_run_return_unpack(uint64_t res);
 }
 
 #include <gmock/gmock.h>
 
+#include <vector>
+
 namespace
 {
 using ::testing::Eq;
@@ -230,4 +236,145 @@ TEST(abi, hf_vcpu_run_return_decode_aborted)
 	EXPECT_THAT(res.code, Eq(HF_VCPU_RUN_ABORTED));
 }
 
+/**
+ * Encode a notification response without leaking.
+ */
+TEST(abi, hf_vcpu_run_return_encode_notification)
+{
+	struct hf_vcpu_run_return res = dirty_vcpu_run_return();
+	res.code = HF_VCPU_RUN_NOTIFICATION;
+	res.notification.vm_id = 0xbeef;
+	EXPECT_THAT(hf_vcpu_run_return_encode(res), Eq(0x0000000000beef08));
+}
+
+/**
+ * Decode a notification response ignoring the irrelevant bits.
+ */
+TEST(abi, hf_vcpu_run_return_decode_notification)
+{
+	struct hf_vcpu_run_return res =
+		hf_vcpu_run_return_decode(0x2718281828459008);
+	EXPECT_THAT(res.code, Eq(HF_VCPU_RUN_NOTIFICATION));
+	EXPECT_THAT(res.notification.vm_id, Eq(0x4590));
+}
+
+/**
+ * Encode a wrong CPU response without leaking.
+ */
+TEST(abi, hf_vcpu_run_return_encode_wrong_cpu)
+{
+	struct hf_vcpu_run_return res = dirty_vcpu_run_return();
+	res.code = HF_VCPU_RUN_WRONG_CPU;
+	EXPECT_THAT(hf_vcpu_run_return_encode(res), Eq(9));
+}
+
+/**
+ * Decode a wrong CPU response ignoring the irrelevant bits.
+ */
+TEST(abi, hf_vcpu_run_return_decode_wrong_cpu)
+{
+	struct hf_vcpu_run_return res =
+		hf_vcpu_run_return_decode(0x1414213562373109);
+	EXPECT_THAT(res.code, Eq(HF_VCPU_RUN_WRONG_CPU));
+}
+
+/**
+ * Returns a dirty response of each code, with its fields set.
+ */
+std::vector<struct hf_vcpu_run_return> all_vcpu_run_returns()
+{
+	std::vector<struct hf_vcpu_run_return> all;
+
+	for (int code = HF_VCPU_RUN_PREEMPTED; code <= HF_VCPU_RUN_WRONG_CPU;
+	     code++) {
+		struct hf_vcpu_run_return res = dirty_vcpu_run_return();
+		res.code = (enum hf_vcpu_run_code)code;
+		switch (res.code) {
+		case HF_VCPU_RUN_WAKE_UP:
+			res.wake_up.vm_id = 0x1234;
+			res.wake_up.vcpu = 0xabcd;
+			break;
+		case HF_VCPU_RUN_MESSAGE:
+			res.message.vm_id = 0xf007;
+			break;
+		case HF_VCPU_RUN_NOTIFICATION:
+			res.notification.vm_id = 0xbeef;
+			break;
+		case HF_VCPU_RUN_WAIT_FOR_INTERRUPT:
+		case HF_VCPU_RUN_WAIT_FOR_MESSAGE:
+			res.sleep.ns = HF_SLEEP_INDEFINITE;
+			break;
+		default:
+			break;
+		}
+		all.push_back(res);
+	}
+
+	return all;
+}
+
+/**
+ * The Rust encoding of every code matches the C one.
+ */
+TEST(abi, hf_vcpu_run_return_pack_matches_encode)
+{
+	for (struct hf_vcpu_run_return res : all_vcpu_run_returns()) {
+		EXPECT_THAT(hf_vcpu_run_return_pack(res),
+			    Eq(hf_vcpu_run_return_encode(res)))
+			<< "code " << res.code;
+	}
+}
+
+/**
+ * The Rust decoding of every code matches the C one, ignoring the irrelevant
+ * bits.
+ */
+TEST(abi, hf_vcpu_run_return_unpack_matches_decode)
+{
+	for (uint64_t code = 0; code <= 0xff; code++) {
+		uint64_t encoded = 0x1123581314916200 | code;
+		struct hf_vcpu_run_return expected =
+			hf_vcpu_run_return_decode(encoded);
+		struct hf_vcpu_run_return res =
+			hf_vcpu_run_return_unpack(encoded);
+
+		EXPECT_THAT(res.code, Eq(expected.code));
+		switch (expected.code) {
+		case HF_VCPU_RUN_WAKE_UP:
+			EXPECT_THAT(res.wake_up.vm_id,
+				    Eq(expected.wake_up.vm_id));
+			EXPECT_THAT(res.wake_up.vcpu, Eq(expected.wake_up.vcpu));
+			break;
+		case HF_VCPU_RUN_MESSAGE:
+			EXPECT_THAT(res.message.vm_id,
+				    Eq(expected.message.vm_id));
+			break;
+		case HF_VCPU_RUN_NOTIFICATION:
+			EXPECT_THAT(res.notification.vm_id,
+				    Eq(expected.notification.vm_id));
+			break;
+		case HF_VCPU_RUN_WAIT_FOR_INTERRUPT:
+		case HF_VCPU_RUN_WAIT_FOR_MESSAGE:
+			EXPECT_THAT(res.sleep.ns, Eq(expected.sleep.ns));
+			break;
+		default:
+			break;
+		}
+	}
+}
+
+/**
+ * Every code survives a round trip through the Rust encoding.
+ */
+TEST(abi, hf_vcpu_run_return_pack_unpack_round_trip)
+{
+	for (struct hf_vcpu_run_return res : all_vcpu_run_returns()) {
+		uint64_t encoded = hf_vcpu_run_return_pack(res);
+		EXPECT_THAT(hf_vcpu_run_return_pack(
+				    hf_vcpu_run_return_unpack(encoded)),
+			    Eq(encoded))
+			<< "code " << res.code;
+	}
+}
+
 } /* namespace */
@@ -20,8 +20,13 @@
 #include "hf/arch/timer.h"
 
 #include "hf/assert.h"
+#include "hf/capability.h"
 #include "hf/dlog.h"
+#include "hf/ffa.h"
 #include "hf/mm.h"
+#include "hf/notification.h"
+#include "hf/plat/console.h"
+#include "hf/run_queue.h"
 #include "hf/spinlock.h"
 #include "hf/std.h"
 #include "hf/vm.h"
@@ -34,7 +39,9 @@
  * acquisition of locks held concurrently by the same physical CPU. Our current
  * ordering requirements are as follows:
  *
- * vm::lock -> vcpu::lock
+ * api_vm_create_lock -> vm::lock -> vcpu::lock
+ *
+ * The run queues' lock is taken last and nothing is locked while holding it.
  *
  * Locks of the same kind require the lock of lowest address to be locked first,
  * see `sl_lock_both()`.
@@ -44,8 +51,47 @@ static_assert(HF_MAILBOX_SIZE == PAGE_SIZE,
 	      "Currently, a page is mapped for the send and receive buffers so "
 	      "the maximum request is the size of a page.");
 
+static_assert(sizeof(struct ffa_partition_info) == 24,
+	      "The partition descriptor must match the size expected by "
+	      "hfo2/src/ffa.rs.");
+
+static_assert(MAX_CPUS <= 64,
+	      "The affinity of a vCPU must have a bit for every physical CPU.");
+
+static_assert(sizeof(struct hf_vm_stats) <= HF_MAILBOX_SIZE,
+	      "The VM stats must fit in the RX buffer.");
+
 static struct mpool api_page_pool;
 
+/** Serialises the creation of VMs. */
+static struct spinlock api_vm_create_lock = SPINLOCK_INIT;
+
+/**
+ * The number of free pages in the API page pool below which a warning is
+ * logged.
+ */
+#define API_PAGE_POOL_WATERMARK 16
+
+/**
+ * The number of pages of the API page pool reserved for unmapping memory, so
+ * that memory can be revoked even when the pool is otherwise exhausted. It
+ * covers splitting blocks at both ends of a range at every level.
+ */
+#define API_PAGE_POOL_RESERVE 8
+
+/**
+ * Warns that the API page pool is running out of memory, as page table updates
+ * will start failing.
+ */
+static void api_page_pool_low(struct mpool *p)
+{
+	struct mpool_stats stats;
+
+	mpool_get_stats(p, &stats);
+	dlog("Warning: API page pool is low on memory, %d pages free\n",
+	     stats.free_pages);
+}
+
 /**
  * Initialises the API page pool by taking ownership of the contents of the
  * given page pool.
@@ -53,6 +99,32 @@ static struct mpool api_page_pool;
 void api_init(struct mpool *ppool)
 {
 	mpool_init_from(&api_page_pool, ppool);
+	mpool_set_low_memory_handler(&api_page_pool, API_PAGE_POOL_WATERMARK,
+				     api_page_pool_low);
+	mpool_set_reserve(&api_page_pool, API_PAGE_POOL_RESERVE);
+}
+
+/**
+ * Returns the number of nanoseconds until the blocking mailbox receive of the
+ * given vCPU times out, 0 if it already has, or HF_SLEEP_INDEFINITE if it has
+ * no timeout.
+ */
+static uint64_t api_recv_remaining_ns(const struct vcpu *vcpu)
+{
+	uint64_t now;
+	uint64_t ns;
+
+	if (vcpu->recv_deadline == 0) {
+		return HF_SLEEP_INDEFINITE;
+	}
+
+	now = arch_timer_now_ticks();
+	if (now >= vcpu->recv_deadline) {
+		return 0;
+	}
+
+	ns = arch_timer_ticks_to_ns(vcpu->recv_deadline - now);
+	return ns < HF_SLEEP_INDEFINITE ? ns : HF_SLEEP_INDEFINITE;
 }
 
 /**
@@ -80,6 +152,15 @@ static struct vcpu *api_switch_to_primary(struct vcpu *current,
 			arch_timer_enabled_current()
 				? arch_timer_remaining_ns_current()
 				: HF_SLEEP_INDEFINITE;
+
+		/* Wake up in time for a receive to time out. */
+		if (primary_ret.code == HF_VCPU_RUN_WAIT_FOR_MESSAGE) {
+			uint64_t recv_ns = api_recv_remaining_ns(current);
+
+			if (recv_ns < primary_ret.sleep.ns) {
+				primary_ret.sleep.ns = recv_ns;
+			}
+		}
 		break;
 
 	default:
@@ -176,6 +257,9 @@ struct vcpu *api_wake_up(struct vcpu *current, struct vcpu *target_vcpu)
 		.wake_up.vm_id = target_vcpu->vm->id,
 		.wake_up.vcpu = vcpu_index(target_vcpu),
 	};
+
+	run_queue_push(cpu_index(current->cpu), target_vcpu->vm->id,
+		       vcpu_index(target_vcpu), target_vcpu->vm->priority);
 	return api_switch_to_primary(current, ret, VCPU_STATE_READY);
 }
 
@@ -200,7 +284,10 @@ struct vcpu *api_abort(struct vcpu *current)
 	atomic_store_explicit(&current->vm->aborting, true,
 			      memory_order_relaxed);
 
-	/* TODO: free resources once all vCPUs abort. */
+	/*
+	 * TODO: free resources once all vCPUs abort. A VM with a restart policy
+	 * is restarted instead when the primary VM next runs one of its vCPUs.
+	 */
 
 	return api_switch_to_primary(current, ret, VCPU_STATE_ABORTED);
 }
@@ -242,1136 +329,2506 @@ int64_t api_vcpu_get_count(spci_vm_id_t vm_id, const struct vcpu *current)
 }
 
 /**
- * This function is called by the architecture-specific context switching
- * function to indicate that register state for the given vcpu has been saved
- * and can therefore be used by other pcpus.
+ * Restricts the physical CPUs the given vcpu of the given secondary VM can be
+ * run on to those whose index has its bit set in `affinity`. A vcpu that is
+ * already running elsewhere carries on until it next returns to the scheduler.
+ * Only the primary VM is allowed to call this.
+ *
+ * Returns -1 on failure or 0 on success.
  */
-void api_regs_state_saved(struct vcpu *vcpu)
+int64_t api_vcpu_affinity_set(spci_vm_id_t vm_id, uint32_t vcpu_idx,
+			      uint64_t affinity, const struct vcpu *current)
 {
+	struct vm *vm;
+	struct vcpu *vcpu;
+
+	/* Only the primary VM schedules vcpus. */
+	if (current->vm->id != HF_PRIMARY_VM_ID) {
+		return -1;
+	}
+
+	/* The vcpus of the primary VM are bound to their physical CPUs. */
+	if (vm_id == HF_PRIMARY_VM_ID) {
+		return -1;
+	}
+
+	vm = vm_find(vm_id);
+	if (vm == NULL || vcpu_idx >= vm->vcpu_count) {
+		return -1;
+	}
+
+	/* The vcpu must be allowed to run somewhere. */
+	if (affinity == 0) {
+		return -1;
+	}
+
+	vcpu = vm_get_vcpu(vm, vcpu_idx);
 	sl_lock(&vcpu->lock);
-	vcpu->regs_available = true;
+	vcpu->affinity = affinity;
 	sl_unlock(&vcpu->lock);
+
+	return 0;
 }
 
 /**
- * Retrieves the next waiter and removes it from the wait list if the VM's
- * mailbox is in a writable state.
+ * Creates a secondary VM as described by the `struct hf_vm_create_desc` in the
+ * primary VM's send buffer. The memory is taken from the primary VM, which must
+ * own it exclusively, and the first vCPU of the new VM is started at the entry
+ * point with the size of the memory as its argument, as for the VMs loaded at
+ * boot. Only the primary VM is allowed to call this.
+ *
+ * Returns the ID of the new VM, or -1 on failure.
  */
-static struct wait_entry *api_fetch_waiter(struct vm_locked locked_vm)
+int64_t api_vm_create(struct vcpu *current)
 {
-	struct wait_entry *entry;
-	struct vm *vm = locked_vm.vm;
+	struct vm *primary = current->vm;
+	const struct hf_vm_create_desc *send;
+	struct hf_vm_create_desc desc;
+	paddr_t begin;
+	paddr_t end;
+	struct vm *vm;
+	int mode;
+	int64_t ret = -1;
 
-	if (vm->mailbox.state != MAILBOX_STATE_EMPTY ||
-	    vm->mailbox.recv == NULL || list_empty(&vm->mailbox.waiter_list)) {
-		/* The mailbox is not writable or there are no waiters. */
-		return NULL;
+	/* Only the primary VM is allowed to call this function. */
+	if (primary->id != HF_PRIMARY_VM_ID) {
+		return -1;
 	}
 
-	/* Remove waiter from the wait list. */
-	entry = CONTAINER_OF(vm->mailbox.waiter_list.next, struct wait_entry,
-			     wait_links);
-	list_remove(&entry->wait_links);
-	return entry;
-}
+	sl_lock(&primary->lock);
+	send = (const struct hf_vm_create_desc *)primary->mailbox.send;
+	if (send != NULL) {
+		desc = *send;
+	}
+	sl_unlock(&primary->lock);
 
-/**
- * Assuming that the arguments have already been checked by the caller, injects
- * a virtual interrupt of the given ID into the given target vCPU. This doesn't
- * cause the vCPU to actually be run immediately; it will be taken when the vCPU
- * is next run, which is up to the scheduler.
- *
- * Returns:
- *  - 0 on success if no further action is needed.
- *  - 1 if it was called by the primary VM and the primary VM now needs to wake
- *    up or kick the target vCPU.
- */
-static int64_t internal_interrupt_inject(struct vcpu *target_vcpu,
-					 uint32_t intid, struct vcpu *current,
-					 struct vcpu **next)
-{
-	uint32_t intid_index = intid / INTERRUPT_REGISTER_BITS;
-	uint32_t intid_mask = 1u << (intid % INTERRUPT_REGISTER_BITS);
-	int64_t ret = 0;
+	if (send == NULL) {
+		return -1;
+	}
 
-	sl_lock(&target_vcpu->lock);
+	if (desc.vcpu_count == 0 || desc.vcpu_count > MAX_CPUS ||
+	    desc.mem_size == 0 || (desc.mem_begin & (PAGE_SIZE - 1)) != 0 ||
+	    (desc.mem_size & (PAGE_SIZE - 1)) != 0 ||
+	    desc.mem_begin + desc.mem_size < desc.mem_begin) {
+		return -1;
+	}
 
-	/*
-	 * We only need to change state and (maybe) trigger a virtual IRQ if it
-	 * is enabled and was not previously pending. Otherwise we can skip
-	 * everything except setting the pending bit.
-	 *
-	 * If you change this logic make sure to update the need_vm_lock logic
-	 * above to match.
-	 */
-	if (!(target_vcpu->interrupts.interrupt_enabled[intid_index] &
-	      ~target_vcpu->interrupts.interrupt_pending[intid_index] &
-	      intid_mask)) {
+	/* The entry point must be in the VM's memory. */
+	if (desc.entry < desc.mem_begin ||
+	    desc.entry >= desc.mem_begin + desc.mem_size) {
+		return -1;
+	}
+
+	begin = pa_init(desc.mem_begin);
+	end = pa_init(desc.mem_begin + desc.mem_size);
+
+	sl_lock(&api_vm_create_lock);
+	sl_lock(&primary->lock);
+
+	/* The primary VM can only give memory it owns exclusively. */
+	if (!mm_vm_get_mode(&primary->ptable, ipa_from_pa(begin),
+			    ipa_from_pa(end), &mode) ||
+	    (mode & (MM_MODE_INVALID | MM_MODE_UNOWNED | MM_MODE_SHARED)) !=
+		    0) {
 		goto out;
 	}
 
-	/* Increment the count. */
-	target_vcpu->interrupts.enabled_and_pending_count++;
+	if (!vm_init(desc.vcpu_count, &api_page_pool, &vm)) {
+		dlog("Unable to initialise VM\n");
+		goto out;
+	}
 
 	/*
-	 * Only need to update state if there was not already an
-	 * interrupt enabled and pending.
+	 * From here on, the VM exists even if giving it its memory fails, but
+	 * its vCPUs are off so it never runs.
 	 */
-	if (target_vcpu->interrupts.enabled_and_pending_count != 1) {
+	plat_console_vm_mm_init(vm, &api_page_pool);
+
+	/* Grant the VM access to the memory. */
+	if (!mm_vm_identity_map(&vm->ptable, begin, end,
+				MM_MODE_R | MM_MODE_W | MM_MODE_X, NULL,
+				&api_page_pool)) {
+		dlog("Unable to initialise memory of VM %u\n", vm->id);
 		goto out;
 	}
 
-	if (current->vm->id == HF_PRIMARY_VM_ID) {
-		/*
-		 * If the call came from the primary VM, let it know that it
-		 * should run or kick the target vCPU.
-		 */
-		ret = 1;
-	} else if (current != target_vcpu && next != NULL) {
-		*next = api_wake_up(current, target_vcpu);
+	/* Deny the primary VM access to this memory. */
+	if (!mm_vm_unmap(&primary->ptable, begin, end, &api_page_pool)) {
+		dlog("Unable to unmap VM %u from primary VM\n", vm->id);
+		mm_vm_unmap(&vm->ptable, begin, end, &api_page_pool);
+		goto out;
 	}
 
-out:
-	/* Either way, make it pending. */
-	target_vcpu->interrupts.interrupt_pending[intid_index] |= intid_mask;
+	dlog("Created VM %u with %u vcpus, entry at 0x%x\n", vm->id,
+	     desc.vcpu_count, desc.entry);
 
-	sl_unlock(&target_vcpu->lock);
+	vcpu_secondary_reset_and_start(vm_get_vcpu(vm, 0),
+				       ipa_init(desc.entry), desc.mem_size);
+	ret = vm->id;
+
+out:
+	sl_unlock(&primary->lock);
+	sl_unlock(&api_vm_create_lock);
 
 	return ret;
 }
 
 /**
- * Prepares the vcpu to run by updating its state and fetching whether a return
- * value needs to be forced onto the vCPU.
+ * Waits for the given vCPU to stop running, returning with its lock held. A CPU
+ * running it is asked to preempt it, and requests from other CPUs are served
+ * while waiting so that they don't wait for this one in turn. The caller must
+ * have already kept the vCPU from being run again.
  */
-static bool api_vcpu_prepare_run(const struct vcpu *current, struct vcpu *vcpu,
-				 struct hf_vcpu_run_return *run_ret)
+static void api_vcpu_preempt(struct vcpu *vcpu, const struct vcpu *current)
 {
-	bool need_vm_lock;
-	bool ret;
+	struct cpu *c;
 
-	/*
-	 * Wait until the registers become available. All locks must be
-	 * released between iterations of this loop to avoid potential deadlocks
-	 * if, on any path, a lock needs to be taken after taking the decision
-	 * to switch context but before the registers have been saved.
-	 *
-	 * The VM lock is not needed in the common case so it must only be taken
-	 * when it is going to be needed. This ensures there are no inter-vCPU
-	 * dependencies in the common run case meaning the sensitive context
-	 * switch performance is consistent.
-	 */
 	for (;;) {
 		sl_lock(&vcpu->lock);
-
-		/* The VM needs to be locked to deliver mailbox messages. */
-		need_vm_lock = vcpu->state == VCPU_STATE_BLOCKED_MAILBOX;
-		if (need_vm_lock) {
-			sl_unlock(&vcpu->lock);
-			sl_lock(&vcpu->vm->lock);
-			sl_lock(&vcpu->lock);
-		}
-
 		if (vcpu->regs_available) {
 			break;
 		}
+		c = vcpu->cpu;
+		sl_unlock(&vcpu->lock);
 
-		if (vcpu->state == VCPU_STATE_RUNNING) {
-			/*
-			 * vCPU is running on another pCPU.
-			 *
-			 * It's ok not to return the sleep duration here because
-			 * the other physical CPU that is currently running this
-			 * vCPU will return the sleep duration if needed. The
-			 * default return value is
-			 * HF_VCPU_RUN_WAIT_FOR_INTERRUPT, so no need to set it
-			 * explicitly.
-			 */
-			ret = false;
-			goto out;
-		}
+		cpu_ipi_send(c, CPU_IPI_RESCHEDULE);
+		cpu_ipi_handle(current->cpu);
+	}
+}
 
-		sl_unlock(&vcpu->lock);
-		if (need_vm_lock) {
-			sl_unlock(&vcpu->vm->lock);
+/**
+ * Waits for the given vCPU, whose VM is aborting, to stop running and turns it
+ * off.
+ */
+static void api_vcpu_stop(struct vcpu *vcpu, const struct vcpu *current)
+{
+	api_vcpu_preempt(vcpu, current);
+	vcpu->state = VCPU_STATE_OFF;
+	sl_unlock(&vcpu->lock);
+
+	run_queue_remove(vcpu->vm->id, vcpu_index(vcpu));
+}
+
+/**
+ * Removes the given destroyed VM from the mailbox wait lists. Its waits on
+ * other VMs' mailboxes are cancelled, and the VMs waiting on its mailbox are
+ * notified as if it became writable, so that they retry and fail rather than
+ * wait forever.
+ */
+static void api_vm_waiters_remove(struct vm *vm)
+{
+	uint32_t count = vm_get_count();
+	struct wait_entry *entry;
+	struct vm *waiting_vm;
+	spci_vm_id_t id;
+
+	for (id = 0; id < count; ++id) {
+		struct vm *other = vm_find(id);
+
+		if (other == NULL) {
+			continue;
 		}
-	}
 
-	if (atomic_load_explicit(&vcpu->vm->aborting, memory_order_relaxed)) {
-		if (vcpu->state != VCPU_STATE_ABORTED) {
-			dlog("Aborting VM %u vCPU %u\n", vcpu->vm->id,
-			     vcpu_index(vcpu));
-			vcpu->state = VCPU_STATE_ABORTED;
+		entry = &vm->wait_entries[id];
+		sl_lock(&other->lock);
+		if (!list_empty(&entry->wait_links)) {
+			list_remove(&entry->wait_links);
 		}
-		ret = false;
-		goto out;
+		sl_unlock(&other->lock);
 	}
 
-	switch (vcpu->state) {
-	case VCPU_STATE_RUNNING:
-	case VCPU_STATE_OFF:
-	case VCPU_STATE_ABORTED:
-		ret = false;
-		goto out;
-
-	case VCPU_STATE_BLOCKED_MAILBOX:
-		/*
-		 * A pending message allows the vCPU to run so the message can
-		 * be delivered directly.
-		 */
-		if (vcpu->vm->mailbox.state == MAILBOX_STATE_RECEIVED) {
-			arch_regs_set_retval(&vcpu->regs, SPCI_SUCCESS);
-			vcpu->vm->mailbox.state = MAILBOX_STATE_READ;
+	for (;;) {
+		sl_lock(&vm->lock);
+		if (list_empty(&vm->mailbox.waiter_list)) {
+			sl_unlock(&vm->lock);
 			break;
 		}
-		/* Fall through. */
-	case VCPU_STATE_BLOCKED_INTERRUPT:
-		/* Allow virtual interrupts to be delivered. */
-		if (vcpu->interrupts.enabled_and_pending_count > 0) {
-			break;
+		entry = CONTAINER_OF(vm->mailbox.waiter_list.next,
+				     struct wait_entry, wait_links);
+		list_remove(&entry->wait_links);
+		sl_unlock(&vm->lock);
+
+		waiting_vm = entry->waiting_vm;
+		sl_lock(&waiting_vm->lock);
+		if (list_empty(&entry->ready_links)) {
+			list_append(&waiting_vm->mailbox.ready_list,
+				    &entry->ready_links);
 		}
+		sl_unlock(&waiting_vm->lock);
+	}
+}
 
-		/* The timer expired so allow the interrupt to be delivered. */
-		if (arch_timer_pending(&vcpu->regs)) {
-			break;
-		}
+/**
+ * Puts the vCPUs of the given VM back in the states they had before a failed
+ * attempt to destroy it, and queues the runnable ones to be run on this CPU
+ * unless the VM is suspended.
+ */
+static void api_vm_destroy_rollback(struct vm *vm,
+				    const enum vcpu_state states[],
+				    bool aborting, const struct vcpu *current)
+{
+	bool suspended =
+		atomic_load_explicit(&vm->suspended, memory_order_relaxed);
+	uint32_t i;
 
-		/*
-		 * The vCPU is not ready to run, return the appropriate code to
-		 * the primary which called vcpu_run.
-		 */
-		if (arch_timer_enabled(&vcpu->regs)) {
-			run_ret->code =
-				vcpu->state == VCPU_STATE_BLOCKED_MAILBOX
-					? HF_VCPU_RUN_WAIT_FOR_MESSAGE
-					: HF_VCPU_RUN_WAIT_FOR_INTERRUPT;
-			run_ret->sleep.ns =
-				arch_timer_remaining_ns(&vcpu->regs);
-		}
+	atomic_store_explicit(&vm->aborting, aborting, memory_order_relaxed);
 
-		ret = false;
-		goto out;
+	for (i = 0; i < vm->vcpu_count; ++i) {
+		struct vcpu *vcpu = vm_get_vcpu(vm, i);
+		bool runnable;
 
-	case VCPU_STATE_READY:
-		break;
+		sl_lock(&vcpu->lock);
+		vcpu->state = states[i];
+		runnable = vcpu->state == VCPU_STATE_READY ||
+			   (vcpu->state != VCPU_STATE_OFF &&
+			    vcpu->state != VCPU_STATE_ABORTED &&
+			    vcpu->interrupts.enabled_and_pending_count > 0);
+		sl_unlock(&vcpu->lock);
+
+		if (runnable && !suspended) {
+			run_queue_push(cpu_index(current->cpu), vm->id, i,
+				       vm->priority);
+		}
 	}
+}
 
-	/* It has been decided that the vCPU should be run. */
-	vcpu->cpu = current->cpu;
-	vcpu->state = VCPU_STATE_RUNNING;
+/**
+ * Destroys the given secondary VM. Its vCPUs are stopped, the memory it owns
+ * exclusively is zeroed and given back to the primary VM, its page table and
+ * VMID are freed and it is removed from the mailbox wait lists. Only the
+ * primary VM is allowed to call this.
+ *
+ * If freeing its resources fails, its vCPUs are put back as they were so that
+ * it keeps running. Memory already given back is unmapped from it, so it aborts
+ * if it touches it, and destroying it can be tried again.
+ *
+ * Returns -1 on failure or 0 on success.
+ */
+int64_t api_vm_destroy(spci_vm_id_t vm_id, struct vcpu *current)
+{
+	struct vm *primary = current->vm;
+	struct vm *vm;
+	struct vm_locked locked;
+	struct vm_locked primary_locked;
+	enum vcpu_state states[MAX_CPUS];
+	bool aborting;
+	uint32_t i;
+	int64_t ret = -1;
+
+	/* Only the primary VM is allowed to call this function. */
+	if (primary->id != HF_PRIMARY_VM_ID || vm_id == HF_PRIMARY_VM_ID) {
+		return -1;
+	}
+
+	sl_lock(&api_vm_create_lock);
+
+	vm = vm_find(vm_id);
+	if (vm == NULL) {
+		goto out;
+	}
 
 	/*
-	 * Mark the registers as unavailable now that we're about to reflect
-	 * them onto the real registers. This will also prevent another physical
-	 * CPU from trying to read these registers.
+	 * Keep the vCPUs from being run again, then wait for them to stop,
+	 * remembering their states in case the VM can't be destroyed.
 	 */
-	vcpu->regs_available = false;
+	aborting = atomic_exchange_explicit(&vm->aborting, true,
+					    memory_order_relaxed);
+	for (i = 0; i < vm->vcpu_count; ++i) {
+		struct vcpu *vcpu = vm_get_vcpu(vm, i);
+
+		api_vcpu_preempt(vcpu, current);
+		states[i] = vcpu->state;
+		vcpu->state = VCPU_STATE_OFF;
+		sl_unlock(&vcpu->lock);
+		run_queue_remove(vm->id, i);
+	}
 
-	ret = true;
+	sl_lock_both(&primary->lock, &vm->lock);
+	locked.vm = vm;
+	primary_locked.vm = primary;
+	if (vm_destroy(locked, primary_locked, &api_page_pool)) {
+		ret = 0;
+	}
+	sl_unlock(&vm->lock);
+	sl_unlock(&primary->lock);
 
-out:
-	sl_unlock(&vcpu->lock);
-	if (need_vm_lock) {
-		sl_unlock(&vcpu->vm->lock);
+	if (ret == 0) {
+		api_vm_waiters_remove(vm);
+		dlog("Destroyed VM %u\n", vm_id);
+	} else {
+		api_vm_destroy_rollback(vm, states, aborting, current);
 	}
 
+out:
+	sl_unlock(&api_vm_create_lock);
+
 	return ret;
 }
 
 /**
- * Runs the given vcpu of the given vm.
+ * Restarts the given aborted VM from its kernel, as its manifest asks for. Its
+ * vCPUs are stopped and it is reset to how it was loaded, see vm_restart(),
+ * then it is removed from the mailbox wait lists and its first vCPU is started
+ * at the entry point again. Nothing is done if it has been restarted or
+ * destroyed by another CPU in the meantime.
  */
-struct hf_vcpu_run_return api_vcpu_run(spci_vm_id_t vm_id, uint32_t vcpu_idx,
-				       const struct vcpu *current,
-				       struct vcpu **next)
+static void api_vm_restart(struct vm *vm, const struct vcpu *current)
 {
-	struct vm *vm;
-	struct vcpu *vcpu;
-	struct hf_vcpu_run_return ret = {
-		.code = HF_VCPU_RUN_WAIT_FOR_INTERRUPT,
-		.sleep.ns = HF_SLEEP_INDEFINITE,
-	};
+	struct vm *primary = vm_find(HF_PRIMARY_VM_ID);
+	struct vm_region *region = &vm->restart.regions[0];
+	struct vm_locked locked;
+	struct vm_locked primary_locked;
+	uint32_t i;
+	bool restarted;
 
-	/* Only the primary VM can switch vcpus. */
-	if (current->vm->id != HF_PRIMARY_VM_ID) {
-		goto out;
-	}
+	sl_lock(&api_vm_create_lock);
 
-	/* Only secondary VM vcpus can be run. */
-	if (vm_id == HF_PRIMARY_VM_ID) {
+	if (!atomic_load_explicit(&vm->aborting, memory_order_relaxed) ||
+	    atomic_load_explicit(&vm->destroyed, memory_order_relaxed)) {
 		goto out;
 	}
 
-	/* The requested VM must exist. */
-	vm = vm_find(vm_id);
-	if (vm == NULL) {
-		goto out;
+	for (i = 0; i < vm->vcpu_count; ++i) {
+		api_vcpu_stop(vm_get_vcpu(vm, i), current);
 	}
 
-	/* The requested vcpu must exist. */
-	if (vcpu_idx >= vm->vcpu_count) {
-		goto out;
-	}
+	sl_lock_both(&primary->lock, &vm->lock);
+	locked.vm = vm;
+	primary_locked.vm = primary;
+	restarted = vm_restart(locked, primary_locked, &api_page_pool);
+	sl_unlock(&vm->lock);
+	sl_unlock(&primary->lock);
 
-	/* Update state if allowed. */
-	vcpu = vm_get_vcpu(vm, vcpu_idx);
-	if (!api_vcpu_prepare_run(current, vcpu, &ret)) {
+	if (!restarted) {
+		dlog("Unable to restart VM %u\n", vm->id);
 		goto out;
 	}
 
-	/*
-	 * Inject timer interrupt if timer has expired. It's safe to access
-	 * vcpu->regs here because api_vcpu_prepare_run already made sure that
-	 * regs_available was true (and then set it to false) before returning
-	 * true.
-	 */
-	if (arch_timer_pending(&vcpu->regs)) {
-		/* Make virtual timer interrupt pending. */
-		internal_interrupt_inject(vcpu, HF_VIRTUAL_TIMER_INTID, vcpu,
-					  NULL);
-
-		/*
-		 * Set the mask bit so the hardware interrupt doesn't fire
-		 * again. Ideally we wouldn't do this because it affects what
-		 * the secondary vCPU sees, but if we don't then we end up with
-		 * a loop of the interrupt firing each time we try to return to
-		 * the secondary vCPU.
-		 */
-		arch_timer_mask(&vcpu->regs);
-	}
+	api_vm_waiters_remove(vm);
+	atomic_store_explicit(&vm->aborting, false, memory_order_relaxed);
 
-	/* Switch to the vcpu. */
-	*next = vcpu;
+	/* The entry point and argument are as when the VM was loaded. */
+	vcpu_secondary_reset_and_start(
+		vm_get_vcpu(vm, 0), ipa_from_pa(region->begin),
+		pa_difference(region->begin, region->end));
+	run_queue_push(cpu_index(current->cpu), vm->id, 0, vm->priority);
 
-	/*
-	 * Set a placeholder return code to the scheduler. This will be
-	 * overwritten when the switch back to the primary occurs.
-	 */
-	ret.code = HF_VCPU_RUN_PREEMPTED;
+	dlog("Restarted VM %u\n", vm->id);
 
 out:
-	return ret;
+	sl_unlock(&api_vm_create_lock);
 }
 
 /**
- * Check that the mode indicates memory that is valid, owned and exclusive.
+ * Suspends the given secondary VM. Its vCPUs are preempted and not run again
+ * until it is resumed, and notifying the primary VM of messages delivered to it
+ * is deferred until then. Pending messages and interrupts are kept. Only the
+ * primary VM is allowed to call this.
+ *
+ * Returns -1 on failure or 0 on success.
  */
-static bool api_mode_valid_owned_and_exclusive(int mode)
+int64_t api_vm_suspend(spci_vm_id_t vm_id, struct vcpu *current)
 {
-	return (mode & (MM_MODE_INVALID | MM_MODE_UNOWNED | MM_MODE_SHARED)) ==
-	       0;
-}
+	struct vm *vm;
+	struct vm_locked locked;
+	uint32_t i;
 
-/**
- * Determines the value to be returned by api_vm_configure and api_mailbox_clear
- * after they've succeeded. If a secondary VM is running and there are waiters,
- * it also switches back to the primary VM for it to wake waiters up.
- */
-static int64_t api_waiter_result(struct vm_locked locked_vm,
-				 struct vcpu *current, struct vcpu **next)
-{
-	struct vm *vm = locked_vm.vm;
-	struct hf_vcpu_run_return ret = {
-		.code = HF_VCPU_RUN_NOTIFY_WAITERS,
-	};
+	/* Only the primary VM is allowed to call this function. */
+	if (current->vm->id != HF_PRIMARY_VM_ID || vm_id == HF_PRIMARY_VM_ID) {
+		return -1;
+	}
 
-	if (list_empty(&vm->mailbox.waiter_list)) {
-		/* No waiters, nothing else to do. */
-		return 0;
+	vm = vm_find(vm_id);
+	if (vm == NULL) {
+		return -1;
 	}
 
-	if (vm->id == HF_PRIMARY_VM_ID) {
-		/* The caller is the primary VM. Tell it to wake up waiters. */
-		return 1;
+	locked = vm_lock(vm);
+	if (vm_is_destroyed(locked) ||
+	    atomic_load_explicit(&vm->suspended, memory_order_relaxed)) {
+		vm_unlock(&locked);
+		return -1;
 	}
+	atomic_store_explicit(&vm->suspended, true, memory_order_relaxed);
+	vm->mailbox.deferred = true;
+	vm_unlock(&locked);
 
-	/*
-	 * Switch back to the primary VM, informing it that there are waiters
-	 * that need to be notified.
-	 */
-	*next = api_switch_to_primary(current, ret, VCPU_STATE_READY);
+	/* Park the vCPUs, leaving their state to resume from. */
+	for (i = 0; i < vm->vcpu_count; ++i) {
+		struct vcpu *vcpu = vm_get_vcpu(vm, i);
+
+		api_vcpu_preempt(vcpu, current);
+		sl_unlock(&vcpu->lock);
+		run_queue_remove(vm->id, i);
+	}
+
+	dlog("Suspended VM %u\n", vm_id);
 
 	return 0;
 }
 
 /**
- * Configures the VM to send/receive data through the specified pages. The pages
- * must not be shared.
+ * Resumes the given suspended secondary VM. Its vCPUs that have pending
+ * interrupts, or that can take a message delivered while it was suspended, are
+ * queued to be run on this CPU. Only the primary VM is allowed to call this.
  *
  * Returns:
  *  - -1 on failure.
  *  - 0 on success if no further action is needed.
- *  - 1 if it was called by the primary VM and the primary VM now needs to wake
- *    up or kick waiters. Waiters should be retrieved by calling
- *    hf_mailbox_waiter_get.
+ *  - 1 if the primary VM now needs to run the VM's vCPUs.
  */
-int64_t api_vm_configure(ipaddr_t send, ipaddr_t recv, struct vcpu *current,
-			 struct vcpu **next)
+int64_t api_vm_resume(spci_vm_id_t vm_id, struct vcpu *current)
 {
-	struct vm *vm = current->vm;
+	struct vm *vm;
 	struct vm_locked locked;
-	paddr_t pa_send_begin;
-	paddr_t pa_send_end;
-	paddr_t pa_recv_begin;
-	paddr_t pa_recv_end;
-	int orig_send_mode;
-	int orig_recv_mode;
-	struct mpool local_page_pool;
-	int64_t ret;
+	bool received;
+	uint32_t i;
+	int64_t ret = 0;
 
-	/* Fail if addresses are not page-aligned. */
-	if (!is_aligned(ipa_addr(send), PAGE_SIZE) ||
-	    !is_aligned(ipa_addr(recv), PAGE_SIZE)) {
+	/* Only the primary VM is allowed to call this function. */
+	if (current->vm->id != HF_PRIMARY_VM_ID || vm_id == HF_PRIMARY_VM_ID) {
 		return -1;
 	}
 
-	/* Convert to physical addresses. */
-	pa_send_begin = pa_from_ipa(send);
-	pa_send_end = pa_add(pa_send_begin, PAGE_SIZE);
-
-	pa_recv_begin = pa_from_ipa(recv);
-	pa_recv_end = pa_add(pa_recv_begin, PAGE_SIZE);
-
-	/* Fail if the same page is used for the send and receive pages. */
-	if (pa_addr(pa_send_begin) == pa_addr(pa_recv_begin)) {
+	vm = vm_find(vm_id);
+	if (vm == NULL) {
 		return -1;
 	}
 
 	locked = vm_lock(vm);
-
-	/* We only allow these to be setup once. */
-	if (vm->mailbox.send || vm->mailbox.recv) {
-		goto fail;
+	if (vm_is_destroyed(locked) ||
+	    !atomic_load_explicit(&vm->suspended, memory_order_relaxed)) {
+		vm_unlock(&locked);
+		return -1;
 	}
+	received = mailbox_state(&vm->mailbox.protocol) ==
+		   MAILBOX_STATE_RECEIVED;
+	vm->mailbox.deferred = false;
+	atomic_store_explicit(&vm->suspended, false, memory_order_relaxed);
 
-	/*
-	 * Ensure the pages are valid, owned and exclusive to the VM and that
-	 * the VM has the required access to the memory.
-	 */
-	if (!mm_vm_get_mode(&vm->ptable, send, ipa_add(send, PAGE_SIZE),
-			    &orig_send_mode) ||
-	    !api_mode_valid_owned_and_exclusive(orig_send_mode) ||
-	    (orig_send_mode & MM_MODE_R) == 0 ||
-	    (orig_send_mode & MM_MODE_W) == 0) {
-		goto fail;
-	}
+	for (i = 0; i < vm->vcpu_count; ++i) {
+		struct vcpu *vcpu = vm_get_vcpu(vm, i);
+		bool runnable;
 
-	if (!mm_vm_get_mode(&vm->ptable, recv, ipa_add(recv, PAGE_SIZE),
-			    &orig_recv_mode) ||
-	    !api_mode_valid_owned_and_exclusive(orig_recv_mode) ||
-	    (orig_recv_mode & MM_MODE_R) == 0) {
-		goto fail;
+		sl_lock(&vcpu->lock);
+		runnable = vcpu->interrupts.enabled_and_pending_count > 0 ||
+			   vcpu->state == VCPU_STATE_READY ||
+			   (received &&
+			    vcpu->state == VCPU_STATE_BLOCKED_MAILBOX);
+		sl_unlock(&vcpu->lock);
+
+		if (runnable) {
+			run_queue_push(cpu_index(current->cpu), vm->id, i,
+				       vm->priority);
+			ret = 1;
+		}
 	}
+	vm_unlock(&locked);
 
-	/*
-	 * Create a local pool so any freed memory can't be used by another
-	 * thread. This is to ensure the original mapping can be restored if any
-	 * stage of the process fails.
-	 */
-	mpool_init_with_fallback(&local_page_pool, &api_page_pool);
+	dlog("Resumed VM %u\n", vm_id);
 
-	/* Take memory ownership away from the VM and mark as shared. */
-	if (!mm_vm_identity_map(
-		    &vm->ptable, pa_send_begin, pa_send_end,
-		    MM_MODE_UNOWNED | MM_MODE_SHARED | MM_MODE_R | MM_MODE_W,
-		    NULL, &local_page_pool)) {
-		goto fail_free_pool;
-	}
+	return ret;
+}
 
-	if (!mm_vm_identity_map(&vm->ptable, pa_recv_begin, pa_recv_end,
-				MM_MODE_UNOWNED | MM_MODE_SHARED | MM_MODE_R,
-				NULL, &local_page_pool)) {
-		/* TODO: partial defrag of failed range. */
-		/* Recover any memory consumed in failed mapping. */
-		mm_vm_defrag(&vm->ptable, &local_page_pool);
-		goto fail_undo_send;
+/**
+ * Looks up the VM with the given UUID, which FF-A clients address partitions
+ * by rather than by ID.
+ *
+ * Returns the ID of the VM, or -1 if no VM has the UUID.
+ */
+int64_t api_vm_find_uuid(const uint32_t uuid[4])
+{
+	struct vm *vm = vm_find_uuid(uuid);
+
+	if (vm == NULL) {
+		return -1;
 	}
 
-	/* Map the send page as read-only in the hypervisor address space. */
-	vm->mailbox.send = mm_identity_map(pa_send_begin, pa_send_end,
-					   MM_MODE_R, &local_page_pool);
-	if (!vm->mailbox.send) {
-		/* TODO: partial defrag of failed range. */
-		/* Recover any memory consumed in failed mapping. */
-		mm_defrag(&local_page_pool);
-		goto fail_undo_send_and_recv;
+	return vm->id;
+}
+
+/**
+ * This function is called by the architecture-specific context switching
+ * function to indicate that register state for the given vcpu has been saved
+ * and can therefore be used by other pcpus.
+ */
+void api_regs_state_saved(struct vcpu *vcpu)
+{
+	sl_lock(&vcpu->lock);
+	vcpu->regs_available = true;
+	sl_unlock(&vcpu->lock);
+}
+
+/**
+ * Retrieves the next waiter and removes it from the wait list if the VM's
+ * mailbox is in a writable state.
+ */
+static struct wait_entry *api_fetch_waiter(struct vm_locked locked_vm)
+{
+	struct wait_entry *entry = NULL;
+	struct vm *vm = locked_vm.vm;
+	struct list_entry *it;
+
+	if (mailbox_state(&vm->mailbox.protocol) != MAILBOX_STATE_EMPTY ||
+	    vm->mailbox.recv == NULL || list_empty(&vm->mailbox.waiter_list)) {
+		/* The mailbox is not writable or there are no waiters. */
+		return NULL;
+	}
+
+	/*
+	 * Take the first waiter of the highest priority class so that waiters
+	 * of the same class are notified in the order they started waiting.
+	 */
+	for (it = vm->mailbox.waiter_list.next; it != &vm->mailbox.waiter_list;
+	     it = it->next) {
+		struct wait_entry *candidate =
+			CONTAINER_OF(it, struct wait_entry, wait_links);
+
+		if (entry == NULL ||
+		    candidate->waiting_vm->priority <
+			    entry->waiting_vm->priority) {
+			entry = candidate;
+		}
+	}
+
+	/* Remove waiter from the wait list. */
+	list_remove(&entry->wait_links);
+	return entry;
+}
+
+/**
+ * Assuming that the arguments have already been checked by the caller, injects
+ * a virtual interrupt of the given ID into the given target vCPU. This doesn't
+ * cause the vCPU to actually be run immediately; it will be taken when the vCPU
+ * is next run, which is up to the scheduler.
+ *
+ * Returns:
+ *  - 0 on success if no further action is needed.
+ *  - 1 if it was called by the primary VM and the primary VM now needs to wake
+ *    up or kick the target vCPU.
+ */
+static int64_t internal_interrupt_inject(struct vcpu *target_vcpu,
+					 uint32_t intid, struct vcpu *current,
+					 struct vcpu **next)
+{
+	uint32_t intid_index = intid / INTERRUPT_REGISTER_BITS;
+	uint32_t intid_mask = 1u << (intid % INTERRUPT_REGISTER_BITS);
+	int64_t ret = 0;
+
+	sl_lock(&target_vcpu->lock);
+
+	/*
+	 * We only need to change state and (maybe) trigger a virtual IRQ if it
+	 * is enabled and was not previously pending. Otherwise we can skip
+	 * everything except setting the pending bit.
+	 *
+	 * If you change this logic make sure to update the need_vm_lock logic
+	 * above to match.
+	 */
+	if (!(target_vcpu->interrupts.interrupt_enabled[intid_index] &
+	      ~target_vcpu->interrupts.interrupt_pending[intid_index] &
+	      intid_mask)) {
+		goto out;
+	}
+
+	/* Increment the count. */
+	target_vcpu->interrupts.enabled_and_pending_count++;
+
+	/*
+	 * Only need to update state if there was not already an
+	 * interrupt enabled and pending.
+	 */
+	if (target_vcpu->interrupts.enabled_and_pending_count != 1) {
+		goto out;
+	}
+
+	/* The interrupt is taken once the VM is resumed. */
+	if (atomic_load_explicit(&target_vcpu->vm->suspended,
+				 memory_order_relaxed)) {
+		goto out;
+	}
+
+	if (current->vm->id == HF_PRIMARY_VM_ID) {
+		/*
+		 * If the call came from the primary VM, let it know that it
+		 * should run or kick the target vCPU, and queue it to be run
+		 * on this CPU.
+		 */
+		ret = 1;
+		run_queue_push(cpu_index(current->cpu), target_vcpu->vm->id,
+			       vcpu_index(target_vcpu),
+			       target_vcpu->vm->priority);
+	} else if (current != target_vcpu && next != NULL) {
+		*next = api_wake_up(current, target_vcpu);
+	}
+
+out:
+	/* Either way, make it pending. */
+	target_vcpu->interrupts.interrupt_pending[intid_index] |= intid_mask;
+	target_vcpu->stats.injected_interrupts++;
+
+	sl_unlock(&target_vcpu->lock);
+
+	return ret;
+}
+
+/**
+ * Prepares the vcpu to run by updating its state and fetching whether a return
+ * value needs to be forced onto the vCPU.
+ */
+static bool api_vcpu_prepare_run(const struct vcpu *current, struct vcpu *vcpu,
+				 struct hf_vcpu_run_return *run_ret)
+{
+	bool need_vm_lock;
+	bool ret;
+
+	/*
+	 * Wait until the registers become available. All locks must be
+	 * released between iterations of this loop to avoid potential deadlocks
+	 * if, on any path, a lock needs to be taken after taking the decision
+	 * to switch context but before the registers have been saved.
+	 *
+	 * The VM lock is not needed in the common case so it must only be taken
+	 * when it is going to be needed. This ensures there are no inter-vCPU
+	 * dependencies in the common run case meaning the sensitive context
+	 * switch performance is consistent.
+	 */
+	for (;;) {
+		sl_lock(&vcpu->lock);
+
+		/* The VM needs to be locked to deliver mailbox messages. */
+		need_vm_lock = vcpu->state == VCPU_STATE_BLOCKED_MAILBOX;
+		if (need_vm_lock) {
+			sl_unlock(&vcpu->lock);
+			sl_lock(&vcpu->vm->lock);
+			sl_lock(&vcpu->lock);
+		}
+
+		if (vcpu->regs_available) {
+			break;
+		}
+
+		if (vcpu->state == VCPU_STATE_RUNNING) {
+			/*
+			 * vCPU is running on another pCPU.
+			 *
+			 * It's ok not to return the sleep duration here because
+			 * the other physical CPU that is currently running this
+			 * vCPU will return the sleep duration if needed. The
+			 * default return value is
+			 * HF_VCPU_RUN_WAIT_FOR_INTERRUPT, so no need to set it
+			 * explicitly.
+			 */
+			ret = false;
+			goto out;
+		}
+
+		sl_unlock(&vcpu->lock);
+		if (need_vm_lock) {
+			sl_unlock(&vcpu->vm->lock);
+		}
+	}
+
+	if (atomic_load_explicit(&vcpu->vm->aborting, memory_order_relaxed)) {
+		if (vcpu->state != VCPU_STATE_ABORTED) {
+			dlog("Aborting VM %u vCPU %u\n", vcpu->vm->id,
+			     vcpu_index(vcpu));
+			vcpu->state = VCPU_STATE_ABORTED;
+		}
+		ret = false;
+		goto out;
+	}
+
+	/* A suspended VM is not run until it is resumed. */
+	if (atomic_load_explicit(&vcpu->vm->suspended, memory_order_relaxed)) {
+		ret = false;
+		goto out;
+	}
+
+	/*
+	 * The vCPU can't be run on this physical CPU. If it last ran on another,
+	 * its registers have been saved so it can be run on any CPU it is
+	 * allowed to.
+	 */
+	if ((vcpu->affinity & (UINT64_C(1) << cpu_index(current->cpu))) == 0) {
+		run_ret->code = HF_VCPU_RUN_WRONG_CPU;
+		ret = false;
+		goto out;
+	}
+
+	switch (vcpu->state) {
+	case VCPU_STATE_RUNNING:
+	case VCPU_STATE_OFF:
+	case VCPU_STATE_ABORTED:
+		ret = false;
+		goto out;
+
+	case VCPU_STATE_BLOCKED_MAILBOX:
+		/*
+		 * A pending message allows the vCPU to run so the message can
+		 * be delivered directly.
+		 */
+		if (mailbox_read(&vcpu->vm->mailbox.protocol)) {
+			arch_regs_set_retval(&vcpu->regs, SPCI_SUCCESS);
+			vcpu->recv_deadline = 0;
+			break;
+		}
+
+		/* The receive timed out so let the vCPU know. */
+		if (vcpu->recv_deadline != 0 &&
+		    arch_timer_now_ticks() >= vcpu->recv_deadline) {
+			arch_regs_set_retval(&vcpu->regs, SPCI_TIMEDOUT);
+			vcpu->recv_deadline = 0;
+			break;
+		}
+		/* Fall through. */
+	case VCPU_STATE_BLOCKED_INTERRUPT:
+		/* Allow virtual interrupts to be delivered. */
+		if (vcpu->interrupts.enabled_and_pending_count > 0) {
+			break;
+		}
+
+		/* The timer expired so allow the interrupt to be delivered. */
+		if (arch_timer_pending(&vcpu->regs)) {
+			break;
+		}
+
+		/*
+		 * The vCPU is not ready to run, return the appropriate code to
+		 * the primary which called vcpu_run.
+		 */
+		if (arch_timer_enabled(&vcpu->regs)) {
+			run_ret->code =
+				vcpu->state == VCPU_STATE_BLOCKED_MAILBOX
+					? HF_VCPU_RUN_WAIT_FOR_MESSAGE
+					: HF_VCPU_RUN_WAIT_FOR_INTERRUPT;
+			run_ret->sleep.ns =
+				arch_timer_remaining_ns(&vcpu->regs);
+		}
+
+		/* Wake up in time for a receive to time out. */
+		if (vcpu->state == VCPU_STATE_BLOCKED_MAILBOX &&
+		    vcpu->recv_deadline != 0) {
+			uint64_t recv_ns = api_recv_remaining_ns(vcpu);
+
+			if (run_ret->code != HF_VCPU_RUN_WAIT_FOR_MESSAGE ||
+			    recv_ns < run_ret->sleep.ns) {
+				run_ret->code = HF_VCPU_RUN_WAIT_FOR_MESSAGE;
+				run_ret->sleep.ns = recv_ns;
+			}
+		}
+
+		ret = false;
+		goto out;
+
+	case VCPU_STATE_READY:
+		break;
+	}
+
+	/* It has been decided that the vCPU should be run. */
+	run_queue_remove(vcpu->vm->id, vcpu_index(vcpu));
+	vcpu->cpu = current->cpu;
+	vcpu->state = VCPU_STATE_RUNNING;
+
+	/*
+	 * Mark the registers as unavailable now that we're about to reflect
+	 * them onto the real registers. This will also prevent another physical
+	 * CPU from trying to read these registers.
+	 */
+	vcpu->regs_available = false;
+
+	ret = true;
+
+out:
+	sl_unlock(&vcpu->lock);
+	if (need_vm_lock) {
+		sl_unlock(&vcpu->vm->lock);
+	}
+
+	return ret;
+}
+
+/**
+ * Runs the given vcpu of the given vm.
+ */
+struct hf_vcpu_run_return api_vcpu_run(spci_vm_id_t vm_id, uint32_t vcpu_idx,
+				       const struct vcpu *current,
+				       struct vcpu **next)
+{
+	struct vm *vm;
+	struct vcpu *vcpu;
+	struct hf_vcpu_run_return ret = {
+		.code = HF_VCPU_RUN_WAIT_FOR_INTERRUPT,
+		.sleep.ns = HF_SLEEP_INDEFINITE,
+	};
+
+	/* Only the primary VM can switch vcpus. */
+	if (current->vm->id != HF_PRIMARY_VM_ID) {
+		goto out;
+	}
+
+	/* Only secondary VM vcpus can be run. */
+	if (vm_id == HF_PRIMARY_VM_ID) {
+		goto out;
+	}
+
+	/* The requested VM must exist. */
+	vm = vm_find(vm_id);
+	if (vm == NULL) {
+		goto out;
+	}
+
+	/* The requested vcpu must exist. */
+	if (vcpu_idx >= vm->vcpu_count) {
+		goto out;
+	}
+
+	/* An aborted VM is restarted if its manifest asks for it. */
+	if (vm->restart.enabled &&
+	    atomic_load_explicit(&vm->aborting, memory_order_relaxed)) {
+		api_vm_restart(vm, current);
+	}
+
+	/* Update state if allowed. */
+	vcpu = vm_get_vcpu(vm, vcpu_idx);
+	if (!api_vcpu_prepare_run(current, vcpu, &ret)) {
+		goto out;
+	}
+
+	/*
+	 * Inject timer interrupt if timer has expired. It's safe to access
+	 * vcpu->regs here because api_vcpu_prepare_run already made sure that
+	 * regs_available was true (and then set it to false) before returning
+	 * true.
+	 */
+	if (arch_timer_pending(&vcpu->regs)) {
+		/* Make virtual timer interrupt pending. */
+		internal_interrupt_inject(vcpu, HF_VIRTUAL_TIMER_INTID, vcpu,
+					  NULL);
+
+		/*
+		 * Set the mask bit so the hardware interrupt doesn't fire
+		 * again. Ideally we wouldn't do this because it affects what
+		 * the secondary vCPU sees, but if we don't then we end up with
+		 * a loop of the interrupt firing each time we try to return to
+		 * the secondary vCPU.
+		 */
+		arch_timer_mask(&vcpu->regs);
+	}
+
+	/* Switch to the vcpu. */
+	*next = vcpu;
+
+	/*
+	 * Set a placeholder return code to the scheduler. This will be
+	 * overwritten when the switch back to the primary occurs.
+	 */
+	ret.code = HF_VCPU_RUN_PREEMPTED;
+
+out:
+	return ret;
+}
+
+/**
+ * Check that the mode indicates memory that is valid, owned and exclusive.
+ */
+static bool api_mode_valid_owned_and_exclusive(int mode)
+{
+	return (mode & (MM_MODE_INVALID | MM_MODE_UNOWNED | MM_MODE_SHARED)) ==
+	       0;
+}
+
+/**
+ * Determines the value to be returned by api_vm_configure and api_mailbox_clear
+ * after they've succeeded. If a secondary VM is running and there are waiters,
+ * it also switches back to the primary VM for it to wake waiters up.
+ */
+static int64_t api_waiter_result(struct vm_locked locked_vm,
+				 struct vcpu *current, struct vcpu **next)
+{
+	struct vm *vm = locked_vm.vm;
+	struct hf_vcpu_run_return ret = {
+		.code = HF_VCPU_RUN_NOTIFY_WAITERS,
+	};
+
+	if (list_empty(&vm->mailbox.waiter_list)) {
+		/* No waiters, nothing else to do. */
+		return 0;
+	}
+
+	if (vm->id == HF_PRIMARY_VM_ID) {
+		/* The caller is the primary VM. Tell it to wake up waiters. */
+		return 1;
+	}
+
+	/*
+	 * Switch back to the primary VM, informing it that there are waiters
+	 * that need to be notified.
+	 */
+	*next = api_switch_to_primary(current, ret, VCPU_STATE_READY);
+
+	return 0;
+}
+
+/**
+ * Configures the VM to send/receive data through the specified pages. The pages
+ * must not be shared.
+ *
+ * Returns:
+ *  - -1 on failure.
+ *  - 0 on success if no further action is needed.
+ *  - 1 if it was called by the primary VM and the primary VM now needs to wake
+ *    up or kick waiters. Waiters should be retrieved by calling
+ *    hf_mailbox_waiter_get.
+ */
+int64_t api_vm_configure(ipaddr_t send, ipaddr_t recv, struct vcpu *current,
+			 struct vcpu **next)
+{
+	struct vm *vm = current->vm;
+	struct vm_locked locked;
+	paddr_t pa_send_begin;
+	paddr_t pa_send_end;
+	paddr_t pa_recv_begin;
+	paddr_t pa_recv_end;
+	int orig_send_mode;
+	int orig_recv_mode;
+	struct mpool local_page_pool;
+	int64_t ret;
+
+	/* Fail if addresses are not page-aligned. */
+	if (!is_aligned(ipa_addr(send), PAGE_SIZE) ||
+	    !is_aligned(ipa_addr(recv), PAGE_SIZE)) {
+		return -1;
+	}
+
+	/* Convert to physical addresses. */
+	pa_send_begin = pa_from_ipa(send);
+	pa_send_end = pa_add(pa_send_begin, PAGE_SIZE);
+
+	pa_recv_begin = pa_from_ipa(recv);
+	pa_recv_end = pa_add(pa_recv_begin, PAGE_SIZE);
+
+	/* Fail if the same page is used for the send and receive pages. */
+	if (pa_addr(pa_send_begin) == pa_addr(pa_recv_begin)) {
+		return -1;
+	}
+
+	locked = vm_lock(vm);
+
+	/* We only allow these to be setup once. */
+	if (vm->mailbox.send || vm->mailbox.recv) {
+		goto fail;
+	}
+
+	/*
+	 * Ensure the pages are valid, owned and exclusive to the VM and that
+	 * the VM has the required access to the memory.
+	 */
+	if (!mm_vm_get_mode(&vm->ptable, send, ipa_add(send, PAGE_SIZE),
+			    &orig_send_mode) ||
+	    !api_mode_valid_owned_and_exclusive(orig_send_mode) ||
+	    (orig_send_mode & MM_MODE_R) == 0 ||
+	    (orig_send_mode & MM_MODE_W) == 0) {
+		goto fail;
+	}
+
+	if (!mm_vm_get_mode(&vm->ptable, recv, ipa_add(recv, PAGE_SIZE),
+			    &orig_recv_mode) ||
+	    !api_mode_valid_owned_and_exclusive(orig_recv_mode) ||
+	    (orig_recv_mode & MM_MODE_R) == 0) {
+		goto fail;
+	}
+
+	/*
+	 * Create a local pool so any freed memory can't be used by another
+	 * thread. This is to ensure the original mapping can be restored if any
+	 * stage of the process fails.
+	 */
+	mpool_init_with_fallback(&local_page_pool, &api_page_pool);
+
+	/* Take memory ownership away from the VM and mark as shared. */
+	if (!mm_vm_identity_map(
+		    &vm->ptable, pa_send_begin, pa_send_end,
+		    MM_MODE_UNOWNED | MM_MODE_SHARED | MM_MODE_R | MM_MODE_W,
+		    NULL, &local_page_pool)) {
+		goto fail_free_pool;
+	}
+
+	if (!mm_vm_identity_map(&vm->ptable, pa_recv_begin, pa_recv_end,
+				MM_MODE_UNOWNED | MM_MODE_SHARED | MM_MODE_R,
+				NULL, &local_page_pool)) {
+		/* TODO: partial defrag of failed range. */
+		/* Recover any memory consumed in failed mapping. */
+		mm_vm_defrag(&vm->ptable, &local_page_pool);
+		goto fail_undo_send;
+	}
+
+	/* Map the send page as read-only in the hypervisor address space. */
+	vm->mailbox.send = mm_identity_map(pa_send_begin, pa_send_end,
+					   MM_MODE_R, &local_page_pool);
+	if (!vm->mailbox.send) {
+		/* TODO: partial defrag of failed range. */
+		/* Recover any memory consumed in failed mapping. */
+		mm_defrag(&local_page_pool);
+		goto fail_undo_send_and_recv;
 	}
 
 	/*
 	 * Map the receive page as writable in the hypervisor address space. On
 	 * failure, unmap the send page before returning.
 	 */
-	vm->mailbox.recv = mm_identity_map(pa_recv_begin, pa_recv_end,
-					   MM_MODE_W, &local_page_pool);
-	if (!vm->mailbox.recv) {
-		/* TODO: partial defrag of failed range. */
-		/* Recover any memory consumed in failed mapping. */
-		mm_defrag(&local_page_pool);
-		goto fail_undo_all;
+	vm->mailbox.recv = mm_identity_map(pa_recv_begin, pa_recv_end,
+					   MM_MODE_W, &local_page_pool);
+	if (!vm->mailbox.recv) {
+		/* TODO: partial defrag of failed range. */
+		/* Recover any memory consumed in failed mapping. */
+		mm_defrag(&local_page_pool);
+		goto fail_undo_all;
+	}
+
+	vm->mailbox.send_mode = orig_send_mode;
+	vm->mailbox.recv_mode = orig_recv_mode;
+
+	/* Tell caller about waiters, if any. */
+	ret = api_waiter_result(locked, current, next);
+	goto exit;
+
+	/*
+	 * The following mappings will not require more memory than is available
+	 * in the local pool.
+	 */
+fail_undo_all:
+	vm->mailbox.send = NULL;
+	mm_unmap(pa_send_begin, pa_send_end, &local_page_pool);
+
+fail_undo_send_and_recv:
+	mm_vm_identity_map(&vm->ptable, pa_recv_begin, pa_recv_end,
+			   orig_recv_mode, NULL, &local_page_pool);
+
+fail_undo_send:
+	mm_vm_identity_map(&vm->ptable, pa_send_begin, pa_send_end,
+			   orig_send_mode, NULL, &local_page_pool);
+
+fail_free_pool:
+	mpool_fini(&local_page_pool);
+
+fail:
+	ret = -1;
+
+exit:
+	vm_unlock(&locked);
+
+	return ret;
+}
+
+/**
+ * Unmaps the send and receive pages of the calling VM from the hypervisor and
+ * gives the VM back the access it had to them before they were configured, so
+ * that it can configure other pages.
+ *
+ * Fails if the pages are not configured or a message is still in the mailbox.
+ *
+ * Returns -1 on failure or 0 on success.
+ */
+int64_t api_vm_unconfigure(struct vcpu *current)
+{
+	struct vm *vm = current->vm;
+	struct vm_locked locked;
+	paddr_t pa_send_begin;
+	paddr_t pa_recv_begin;
+	struct mpool local_page_pool;
+	int64_t ret = -1;
+
+	locked = vm_lock(vm);
+
+	if (vm->mailbox.send == NULL || vm->mailbox.recv == NULL ||
+	    !mailbox_is_idle(&vm->mailbox.protocol)) {
+		goto out;
+	}
+
+	pa_send_begin = pa_from_va(va_from_ptr(vm->mailbox.send));
+	pa_recv_begin = pa_from_va(va_from_ptr(vm->mailbox.recv));
+
+	mpool_init_with_fallback(&local_page_pool, &api_page_pool);
+
+	/* Give the pages back to the VM before anything is unmapped. */
+	if (!mm_vm_identity_map(&vm->ptable, pa_send_begin,
+				pa_add(pa_send_begin, PAGE_SIZE),
+				vm->mailbox.send_mode, NULL,
+				&local_page_pool)) {
+		goto out_free_pool;
+	}
+
+	if (!mm_vm_identity_map(&vm->ptable, pa_recv_begin,
+				pa_add(pa_recv_begin, PAGE_SIZE),
+				vm->mailbox.recv_mode, NULL,
+				&local_page_pool)) {
+		/* Recover any memory consumed in failed mapping. */
+		mm_vm_defrag(&vm->ptable, &local_page_pool);
+		mm_vm_identity_map(&vm->ptable, pa_send_begin,
+				   pa_add(pa_send_begin, PAGE_SIZE),
+				   MM_MODE_UNOWNED | MM_MODE_SHARED |
+					   MM_MODE_R | MM_MODE_W,
+				   NULL, &local_page_pool);
+		goto out_free_pool;
+	}
+
+	/*
+	 * The hypervisor no longer uses the pages so failing to unmap them only
+	 * leaves them mapped for longer than needed.
+	 */
+	mm_unmap(pa_send_begin, pa_add(pa_send_begin, PAGE_SIZE),
+		 &local_page_pool);
+	mm_unmap(pa_recv_begin, pa_add(pa_recv_begin, PAGE_SIZE),
+		 &local_page_pool);
+
+	vm->mailbox.send = NULL;
+	vm->mailbox.recv = NULL;
+	ret = 0;
+
+out_free_pool:
+	mpool_fini(&local_page_pool);
+
+out:
+	vm_unlock(&locked);
+
+	return ret;
+}
+
+/**
+ * Drops the fragments pending in the mailbox of the given VM, whose lock must
+ * be held, if their sender is aborting or destroyed. Once a fragment that is
+ * not the last is received, only its sender may send until it sends the last
+ * fragment, so this lets the others send again.
+ */
+static void api_mailbox_drop_aborted_fragments(struct vm *to)
+{
+	spci_vm_id_t sender_id;
+	struct vm *sender;
+
+	if (!mailbox_pending_sender(&to->mailbox.protocol, &sender_id)) {
+		return;
+	}
+
+	sender = vm_find(sender_id);
+	if (sender == NULL ||
+	    atomic_load_explicit(&sender->aborting, memory_order_relaxed)) {
+		mailbox_abandon(&to->mailbox.protocol);
+	}
+}
+
+/**
+ * Copies a message from the send buffer of `from` to the mailbox of `to`, the
+ * locks of both of which must be held, if it is ready to receive data, and
+ * moves the mailbox to the received state. Otherwise, sets up for `from` to be
+ * notified when it is, if requested, and returns false.
+ */
+static bool api_mailbox_deliver(struct vm *to, struct vm *from,
+				const struct spci_message *header, bool notify)
+{
+	struct spci_message *to_msg;
+	bool more = (header->flags & SPCI_MESSAGE_MORE_FRAGMENTS_MASK) != 0;
+
+	api_mailbox_drop_aborted_fragments(to);
+
+	if (to->mailbox.recv == NULL ||
+	    !mailbox_receive(&to->mailbox.protocol, from->id, more)) {
+		if (notify) {
+			struct wait_entry *entry = &from->wait_entries[to->id];
+
+			/* Append waiter only if it's not there yet. */
+			if (list_empty(&entry->wait_links)) {
+				list_append(&to->mailbox.waiter_list,
+					    &entry->wait_links);
+			}
+		}
+
+		return false;
+	}
+
+	/* Copy data. */
+	to_msg = to->mailbox.recv;
+	*to_msg = *header;
+	to_msg->target_vm_id = to->id;
+	memcpy_s(to_msg->payload, SPCI_MSG_PAYLOAD_MAX,
+		 from->mailbox.send->payload, header->length);
+
+	return true;
+}
+
+/**
+ * Copies data from the sender's send buffer to the recipient's receive buffer
+ * and notifies the recipient.
+ *
+ * If the recipient's receive buffer is busy, it can optionally register the
+ * caller to be notified when the recipient's receive buffer becomes available.
+ */
+int32_t api_spci_msg_send(uint32_t attributes, struct vcpu *current,
+			  struct vcpu **next)
+{
+	struct vm *from = current->vm;
+	struct vm *to;
+	struct vm_locked to_locked;
+	struct hf_vcpu_run_return primary_ret = {
+		.code = HF_VCPU_RUN_MESSAGE,
+	};
+	struct spci_message from_msg_replica;
+	const struct spci_message *from_msg;
+
+	uint32_t size;
+
+	int64_t ret;
+	bool notify = (attributes & SPCI_MSG_SEND_NOTIFY_MASK) ==
+		      SPCI_MSG_SEND_NOTIFY;
+
+	/*
+	 * Check that the sender has configured its send buffer and copy the
+	 * message header. The buffer can be unmapped once the lock is released
+	 * so it is checked again before the payload is copied.
+	 */
+	sl_lock(&from->lock);
+	from_msg = from->mailbox.send;
+	if (from_msg != NULL) {
+		/*
+		 * Note that the payload is not copied when the message header
+		 * is.
+		 */
+		from_msg_replica = *from_msg;
+	}
+	sl_unlock(&from->lock);
+
+	if (from_msg == NULL) {
+		return SPCI_INVALID_PARAMETERS;
+	}
+
+	/* Ensure source VM id corresponds to the current VM. */
+	if (from_msg_replica.source_vm_id != from->id) {
+		return SPCI_INVALID_PARAMETERS;
+	}
+
+	size = from_msg_replica.length;
+	/* Limit the size of transfer. */
+	if (size > SPCI_MSG_PAYLOAD_MAX) {
+		return SPCI_INVALID_PARAMETERS;
+	}
+
+	/* Disallow reflexive requests as this suggests an error in the VM. */
+	if (from_msg_replica.target_vm_id == from->id) {
+		return SPCI_INVALID_PARAMETERS;
+	}
+
+	/* Ensure the target VM exists. */
+	to = vm_find(from_msg_replica.target_vm_id);
+	if (to == NULL) {
+		return SPCI_INVALID_PARAMETERS;
+	}
+
+	sl_lock_both(&from->lock, &to->lock);
+	to_locked.vm = to;
+
+	if (vm_is_destroyed(to_locked) || from->mailbox.send == NULL) {
+		ret = SPCI_INVALID_PARAMETERS;
+		goto out;
+	}
+
+	if (!api_mailbox_deliver(to, from, &from_msg_replica, notify)) {
+		ret = SPCI_BUSY;
+		goto out;
+	}
+
+	primary_ret.message.vm_id = to->id;
+	ret = SPCI_SUCCESS;
+
+	/* Messages for the primary VM are delivered directly. */
+	if (to->id == HF_PRIMARY_VM_ID) {
+		mailbox_read(&to->mailbox.protocol);
+		*next = api_switch_to_primary(current, primary_ret,
+					      VCPU_STATE_READY);
+		goto out;
+	}
+
+	/*
+	 * Return to the primary VM directly or with a switch, unless notifying
+	 * it is deferred until the recipient is resumed.
+	 */
+	if (from->id != HF_PRIMARY_VM_ID && !to->mailbox.deferred) {
+		*next = api_switch_to_primary(current, primary_ret,
+					      VCPU_STATE_READY);
+	}
+
+out:
+	sl_unlock(&to->lock);
+	sl_unlock(&from->lock);
+
+	return ret;
+}
+
+/**
+ * Delivers the message in the primary VM's send buffer to every secondary VM
+ * whose mailbox is ready to receive data. The primary VM is set up to be
+ * notified when the mailboxes of the others become writable.
+ *
+ * Only the primary VM is allowed to call this, and the target of the message is
+ * ignored. Only VMs with an ID below 64 are delivered to.
+ *
+ * Returns -1 on failure; otherwise a bitmap with bit N set if the message was
+ * delivered to the VM with ID N. The bit of the primary VM is never set.
+ */
+int64_t api_mailbox_broadcast(struct vcpu *current)
+{
+	struct vm *from = current->vm;
+	struct spci_message from_msg_replica;
+	const struct spci_message *from_msg;
+	uint32_t count = vm_get_count();
+	spci_vm_id_t id;
+	int64_t ret = 0;
+
+	/* Only the primary VM is allowed to call this function. */
+	if (from->id != HF_PRIMARY_VM_ID) {
+		return -1;
+	}
+
+	sl_lock(&from->lock);
+	from_msg = from->mailbox.send;
+	if (from_msg != NULL) {
+		from_msg_replica = *from_msg;
+	}
+	sl_unlock(&from->lock);
+
+	if (from_msg == NULL) {
+		return -1;
+	}
+	if (from_msg_replica.source_vm_id != from->id ||
+	    from_msg_replica.length > SPCI_MSG_PAYLOAD_MAX) {
+		return -1;
+	}
+
+	for (id = 0; id < count && id < 64; id++) {
+		struct vm *to;
+		struct vm_locked to_locked;
+
+		if (id == from->id) {
+			continue;
+		}
+
+		to = vm_find(id);
+		if (to == NULL) {
+			continue;
+		}
+
+		sl_lock_both(&from->lock, &to->lock);
+		to_locked.vm = to;
+		if (!vm_is_destroyed(to_locked) && from->mailbox.send != NULL &&
+		    api_mailbox_deliver(to, from, &from_msg_replica, true)) {
+			if (!to->mailbox.deferred) {
+				ret |= INT64_C(1) << id;
+			}
+		}
+		sl_unlock(&to->lock);
+		sl_unlock(&from->lock);
+	}
+
+	return ret;
+}
+
+/**
+ * Receives a message from the mailbox. If one isn't available, this function
+ * can optionally block the caller until one becomes available.
+ *
+ * A blocking receive with a non-zero `timeout`, in timer ticks, returns
+ * SPCI_TIMEDOUT if no message arrives in that time.
+ *
+ * No new messages can be received until the mailbox has been cleared.
+ */
+int32_t api_spci_msg_recv(uint32_t attributes, uint64_t timeout,
+			  struct vcpu *current, struct vcpu **next)
+{
+	struct vm *vm = current->vm;
+	int32_t return_code;
+	bool block =
+		(attributes & SPCI_MSG_RECV_BLOCK_MASK) == SPCI_MSG_RECV_BLOCK;
+
+	/*
+	 * The primary VM will receive messages as a status code from running
+	 * vcpus and must not call this function.
+	 */
+	if (vm->id == HF_PRIMARY_VM_ID) {
+		return SPCI_INTERRUPTED;
+	}
+
+	sl_lock(&vm->lock);
+
+	/* Return pending messages without blocking. */
+	if (mailbox_read(&vm->mailbox.protocol)) {
+		return_code = SPCI_SUCCESS;
+		goto out;
+	}
+
+	/* No pending message so fail if not allowed to block. */
+	if (!block) {
+		return_code = SPCI_RETRY;
+		goto out;
+	}
+
+	/*
+	 * From this point onward this call can only be interrupted or a message
+	 * received. If a message is received the return value will be set at
+	 * that time to SPCI_SUCCESS.
+	 */
+	return_code = SPCI_INTERRUPTED;
+
+	/*
+	 * Don't block if there are enabled and pending interrupts, to match
+	 * behaviour of wait_for_interrupt.
+	 */
+	if (current->interrupts.enabled_and_pending_count > 0) {
+		goto out;
+	}
+
+	/*
+	 * Switch back to primary vm to block. The vCPU is woken with
+	 * SPCI_TIMEDOUT once the deadline passes.
+	 */
+	current->recv_deadline =
+		timeout == 0 ? 0 : arch_timer_now_ticks() + timeout;
+	{
+		struct hf_vcpu_run_return run_return = {
+			.code = HF_VCPU_RUN_WAIT_FOR_MESSAGE,
+		};
+
+		*next = api_switch_to_primary(current, run_return,
+					      VCPU_STATE_BLOCKED_MAILBOX);
+	}
+out:
+	sl_unlock(&vm->lock);
+
+	return return_code;
+}
+
+/**
+ * Retrieves the next VM whose mailbox became writable. For a VM to be notified
+ * by this function, the caller must have called api_mailbox_send before with
+ * the notify argument set to true, and this call must have failed because the
+ * mailbox was not available.
+ *
+ * It should be called repeatedly to retrieve a list of VMs.
+ *
+ * Returns -1 if no VM became writable, or the id of the VM whose mailbox
+ * became writable.
+ */
+int64_t api_mailbox_writable_get(const struct vcpu *current)
+{
+	struct vm *vm = current->vm;
+	struct wait_entry *entry;
+	int64_t ret;
+
+	sl_lock(&vm->lock);
+	if (list_empty(&vm->mailbox.ready_list)) {
+		ret = -1;
+		goto exit;
+	}
+
+	entry = CONTAINER_OF(vm->mailbox.ready_list.next, struct wait_entry,
+			     ready_links);
+	list_remove(&entry->ready_links);
+	ret = entry - vm->wait_entries;
+
+exit:
+	sl_unlock(&vm->lock);
+	return ret;
+}
+
+/**
+ * Checks whether a message is pending in the caller's mailbox without
+ * receiving it, so the mailbox is left as it was.
+ *
+ * Returns -1 if no message is pending; otherwise the length of the message in
+ * bits [31:0] and the ID of the VM that sent it in bits [47:32].
+ */
+int64_t api_mailbox_peek(const struct vcpu *current)
+{
+	struct vm *vm = current->vm;
+	int64_t ret = -1;
+
+	sl_lock(&vm->lock);
+	if (mailbox_state(&vm->mailbox.protocol) == MAILBOX_STATE_RECEIVED) {
+		ret = ((int64_t)vm->mailbox.recv->source_vm_id << 32) |
+		      vm->mailbox.recv->length;
+	}
+	sl_unlock(&vm->lock);
+
+	return ret;
+}
+
+/**
+ * Retrieves the next VM waiting to be notified that the mailbox of the
+ * specified VM became writable. Only primary VMs are allowed to call this.
+ *
+ * Returns -1 on failure or if there are no waiters; the VM id of the next
+ * waiter otherwise.
+ */
+int64_t api_mailbox_waiter_get(spci_vm_id_t vm_id, const struct vcpu *current)
+{
+	struct vm *vm;
+	struct vm_locked locked;
+	struct wait_entry *entry;
+	struct vm *waiting_vm;
+
+	/* Only primary VMs are allowed to call this function. */
+	if (current->vm->id != HF_PRIMARY_VM_ID) {
+		return -1;
+	}
+
+	vm = vm_find(vm_id);
+	if (vm == NULL) {
+		return -1;
+	}
+
+	/* Check if there are outstanding notifications from given vm. */
+	locked = vm_lock(vm);
+	entry = vm_is_destroyed(locked) ? NULL : api_fetch_waiter(locked);
+	vm_unlock(&locked);
+
+	if (entry == NULL) {
+		return -1;
+	}
+
+	/* Enqueue notification to waiting VM. */
+	waiting_vm = entry->waiting_vm;
+
+	sl_lock(&waiting_vm->lock);
+	if (list_empty(&entry->ready_links)) {
+		list_append(&waiting_vm->mailbox.ready_list,
+			    &entry->ready_links);
+	}
+	sl_unlock(&waiting_vm->lock);
+
+	return waiting_vm->id;
+}
+
+/**
+ * Clears the caller's mailbox so that a new message can be received. The caller
+ * must have copied out all data they wish to preserve as new messages will
+ * overwrite the old and will arrive asynchronously.
+ *
+ * Returns:
+ *  - -1 on failure, if the mailbox hasn't been read.
+ *  - 0 on success if no further action is needed.
+ *  - 1 if it was called by the primary VM and the primary VM now needs to wake
+ *    up or kick waiters. Waiters should be retrieved by calling
+ *    hf_mailbox_waiter_get.
+ */
+int64_t api_mailbox_clear(struct vcpu *current, struct vcpu **next)
+{
+	struct vm *vm = current->vm;
+	struct vm_locked locked;
+	int64_t ret;
+
+	locked = vm_lock(vm);
+	switch (mailbox_clear(&vm->mailbox.protocol)) {
+	case MAILBOX_STATE_EMPTY:
+		ret = 0;
+		break;
+
+	case MAILBOX_STATE_RECEIVED:
+		ret = -1;
+		break;
+
+	case MAILBOX_STATE_READ:
+		ret = api_waiter_result(locked, current, next);
+		break;
+	}
+	vm_unlock(&locked);
+
+	return ret;
+}
+
+/**
+ * Enables or disables a given interrupt ID for the calling vCPU.
+ *
+ * Returns 0 on success, or -1 if the intid is invalid.
+ */
+int64_t api_interrupt_enable(uint32_t intid, bool enable, struct vcpu *current)
+{
+	uint32_t intid_index = intid / INTERRUPT_REGISTER_BITS;
+	uint32_t intid_mask = 1u << (intid % INTERRUPT_REGISTER_BITS);
+
+	if (intid >= HF_NUM_INTIDS) {
+		return -1;
+	}
+
+	sl_lock(&current->lock);
+	if (enable) {
+		/*
+		 * If it is pending and was not enabled before, increment the
+		 * count.
+		 */
+		if (current->interrupts.interrupt_pending[intid_index] &
+		    ~current->interrupts.interrupt_enabled[intid_index] &
+		    intid_mask) {
+			current->interrupts.enabled_and_pending_count++;
+		}
+		current->interrupts.interrupt_enabled[intid_index] |=
+			intid_mask;
+	} else {
+		/*
+		 * If it is pending and was enabled before, decrement the count.
+		 */
+		if (current->interrupts.interrupt_pending[intid_index] &
+		    current->interrupts.interrupt_enabled[intid_index] &
+		    intid_mask) {
+			current->interrupts.enabled_and_pending_count--;
+		}
+		current->interrupts.interrupt_enabled[intid_index] &=
+			~intid_mask;
+	}
+
+	sl_unlock(&current->lock);
+	return 0;
+}
+
+/**
+ * Returns the ID of the next pending interrupt for the calling vCPU, and
+ * acknowledges it (i.e. marks it as no longer pending). Returns
+ * HF_INVALID_INTID if there are no pending interrupts.
+ */
+uint32_t api_interrupt_get(struct vcpu *current)
+{
+	uint8_t i;
+	uint32_t first_interrupt = HF_INVALID_INTID;
+
+	/*
+	 * Find the first enabled and pending interrupt ID, return it, and
+	 * deactivate it.
+	 */
+	sl_lock(&current->lock);
+	for (i = 0; i < HF_NUM_INTIDS / INTERRUPT_REGISTER_BITS; ++i) {
+		uint32_t enabled_and_pending =
+			current->interrupts.interrupt_enabled[i] &
+			current->interrupts.interrupt_pending[i];
+
+		if (enabled_and_pending != 0) {
+			uint8_t bit_index = ctz(enabled_and_pending);
+			/*
+			 * Mark it as no longer pending and decrement the count.
+			 */
+			current->interrupts.interrupt_pending[i] &=
+				~(1u << bit_index);
+			current->interrupts.enabled_and_pending_count--;
+			first_interrupt =
+				i * INTERRUPT_REGISTER_BITS + bit_index;
+			break;
+		}
 	}
 
-	/* Tell caller about waiters, if any. */
-	ret = api_waiter_result(locked, current, next);
-	goto exit;
+	sl_unlock(&current->lock);
+	return first_interrupt;
+}
+
+/**
+ * Returns whether the current vCPU is allowed to inject an interrupt into the
+ * given VM and vCPU.
+ */
+static inline bool is_injection_allowed(uint32_t target_vm_id,
+					struct vcpu *current)
+{
+	uint32_t current_vm_id = current->vm->id;
 
 	/*
-	 * The following mappings will not require more memory than is available
-	 * in the local pool.
+	 * The primary VM is allowed to inject interrupts into any VM, and so are
+	 * VMs that back devices for others. Other secondary VMs are only allowed
+	 * to inject interrupts into their own vCPUs.
 	 */
-fail_undo_all:
-	vm->mailbox.send = NULL;
-	mm_unmap(pa_send_begin, pa_send_end, &local_page_pool);
+	return current_vm_id == HF_PRIMARY_VM_ID ||
+	       current_vm_id == target_vm_id ||
+	       (current->vm->capabilities & HF_CAPABILITY_INTERRUPT_INJECTION);
+}
 
-fail_undo_send_and_recv:
-	mm_vm_identity_map(&vm->ptable, pa_recv_begin, pa_recv_end,
-			   orig_recv_mode, NULL, &local_page_pool);
+/**
+ * Injects a virtual interrupt of the given ID into the given target vCPU.
+ * This doesn't cause the vCPU to actually be run immediately; it will be taken
+ * when the vCPU is next run, which is up to the scheduler.
+ *
+ * Returns:
+ *  - -1 on failure because the target VM or vCPU doesn't exist, the interrupt
+ *    ID is invalid, or the current VM is not allowed to inject interrupts to
+ *    the target VM.
+ *  - 0 on success if no further action is needed.
+ *  - 1 if it was called by the primary VM and the primary VM now needs to wake
+ *    up or kick the target vCPU.
+ */
+int64_t api_interrupt_inject(spci_vm_id_t target_vm_id,
+			     uint32_t target_vcpu_idx, uint32_t intid,
+			     struct vcpu *current, struct vcpu **next)
+{
+	struct vcpu *target_vcpu;
+	struct vm *target_vm = vm_find(target_vm_id);
 
-fail_undo_send:
-	mm_vm_identity_map(&vm->ptable, pa_send_begin, pa_send_end,
-			   orig_send_mode, NULL, &local_page_pool);
+	if (intid >= HF_NUM_INTIDS) {
+		return -1;
+	}
 
-fail_free_pool:
-	mpool_fini(&local_page_pool);
+	if (target_vm == NULL) {
+		return -1;
+	}
 
-fail:
-	ret = -1;
+	if (target_vcpu_idx >= target_vm->vcpu_count) {
+		/* The requested vcpu must exist. */
+		return -1;
+	}
 
-exit:
-	vm_unlock(&locked);
+	if (!is_injection_allowed(target_vm_id, current)) {
+		return -1;
+	}
 
-	return ret;
+	target_vcpu = vm_get_vcpu(target_vm, target_vcpu_idx);
+
+	dlog("Injecting IRQ %d for VM %d VCPU %d from VM %d VCPU %d\n", intid,
+	     target_vm_id, target_vcpu_idx, current->vm->id, current->cpu->id);
+	return internal_interrupt_inject(target_vcpu, intid, current, next);
 }
 
 /**
- * Copies data from the sender's send buffer to the recipient's receive buffer
- * and notifies the recipient.
+ * Returns the ID of the VM that is assigned the device with the given
+ * interrupt, so that the primary VM routes the interrupt to it. Only the
+ * primary VM is allowed to call this.
  *
- * If the recipient's receive buffer is busy, it can optionally register the
- * caller to be notified when the recipient's receive buffer becomes available.
+ * Returns -1 if the interrupt is not of a device assigned to a VM.
  */
-int32_t api_spci_msg_send(uint32_t attributes, struct vcpu *current,
-			  struct vcpu **next)
+int64_t api_interrupt_owner_get(uint32_t intid, const struct vcpu *current)
+{
+	struct vm *vm;
+
+	if (current->vm->id != HF_PRIMARY_VM_ID) {
+		return -1;
+	}
+
+	vm = vm_find_intid_owner(intid);
+	if (vm == NULL) {
+		return -1;
+	}
+
+	return vm->id;
+}
+
+/**
+ * Waits until no other CPU can use a translation removed from a stage-2 page
+ * table before the call, so that the memory it mapped can be reused. No lock
+ * may be held by the caller.
+ */
+static void api_tlb_sync(struct vcpu *current)
+{
+	cpu_ipi_broadcast(current->cpu, CPU_IPI_TLB_SYNC);
+}
+
+/**
+ * Shares memory from the calling VM with another. The memory can be shared in
+ * different modes.
+ *
+ * TODO: the interface for sharing memory will need to be enhanced to allow
+ *       sharing with different modes e.g. read-only, informing the recipient
+ *       of the memory they have been given, opting to not wipe the memory and
+ *       possibly allowing multiple blocks to be transferred. What this will
+ *       look like is TBD.
+ */
+int64_t api_share_memory(spci_vm_id_t vm_id, ipaddr_t addr, size_t size,
+			 enum hf_share share, struct vcpu *current)
 {
 	struct vm *from = current->vm;
 	struct vm *to;
-	struct hf_vcpu_run_return primary_ret = {
-		.code = HF_VCPU_RUN_MESSAGE,
-	};
-	struct spci_message from_msg_replica;
-	struct spci_message *to_msg;
-	const struct spci_message *from_msg;
+	struct vm_locked locked;
+	bool ret;
 
-	uint32_t size;
+	/* Disallow reflexive shares as this suggests an error in the VM. */
+	if (vm_id == from->id) {
+		return -1;
+	}
 
-	int64_t ret;
-	bool notify = (attributes & SPCI_MSG_SEND_NOTIFY_MASK) ==
-		      SPCI_MSG_SEND_NOTIFY;
+	/* Ensure the target VM exists. */
+	to = vm_find(vm_id);
+	if (to == NULL) {
+		return -1;
+	}
 
-	/*
-	 * Check that the sender has configured its send buffer. Copy the
-	 * message header. If the tx mailbox at from_msg is configured (i.e.
-	 * from_msg != NULL) then it can be safely accessed after releasing the
-	 * lock since the tx mailbox address can only be configured once.
-	 */
-	sl_lock(&from->lock);
-	from_msg = from->mailbox.send;
+	sl_lock_both(&from->lock, &to->lock);
+	locked.vm = to;
+	ret = !vm_is_destroyed(locked) && vm_quota_allows(locked, size) &&
+	      api_share_memory_ptables(&from->ptable, &to->ptable, addr,
+				       ipa_add(addr, size), share,
+				       &api_page_pool);
 	sl_unlock(&from->lock);
+	sl_unlock(&to->lock);
 
-	if (from_msg == NULL) {
-		return SPCI_INVALID_PARAMETERS;
+	if (ret) {
+		api_tlb_sync(current);
 	}
 
-	/*
-	 * Note that the payload is not copied when the message header is.
-	 */
-	from_msg_replica = *from_msg;
+	return ret ? 0 : -1;
+}
 
-	/* Ensure source VM id corresponds to the current VM. */
-	if (from_msg_replica.source_vm_id != from->id) {
-		return SPCI_INVALID_PARAMETERS;
+/**
+ * Sends a direct request of the primary VM to the given secondary VM, which
+ * must be waiting for a message. The receiving vCPU is run right away with the
+ * request in its registers, and the primary's vCPU is switched back to when it
+ * responds, with the response in its registers.
+ *
+ * The vCPU of the receiver with the index of the current CPU receives the
+ * request, unless the receiver has a single vCPU. If it is preempted before it
+ * responds, the primary gets the return value of HF_VCPU_RUN for that vCPU,
+ * and gets the response from the HF_VCPU_RUN that resumes it.
+ *
+ * Returns SPCI_SUCCESS if the request is being delivered, or the SPCI error
+ * code of the failure.
+ */
+int32_t api_ffa_msg_send_direct_req(spci_vm_id_t vm_id,
+				    const uintreg_t args[8],
+				    struct vcpu *current, struct vcpu **next)
+{
+	struct vm *receiver;
+	struct vcpu *vcpu;
+	uint32_t vcpu_idx;
+	int32_t ret;
+
+	/* Only the primary VM, which schedules the others, sends requests. */
+	if (current->vm->id != HF_PRIMARY_VM_ID) {
+		return SPCI_NOT_SUPPORTED;
 	}
 
-	size = from_msg_replica.length;
-	/* Limit the size of transfer. */
-	if (size > SPCI_MSG_PAYLOAD_MAX) {
+	if (vm_id == HF_PRIMARY_VM_ID) {
 		return SPCI_INVALID_PARAMETERS;
 	}
 
-	/* Disallow reflexive requests as this suggests an error in the VM. */
-	if (from_msg_replica.target_vm_id == from->id) {
+	receiver = vm_find(vm_id);
+	if (receiver == NULL) {
 		return SPCI_INVALID_PARAMETERS;
 	}
 
-	/* Ensure the target VM exists. */
-	to = vm_find(from_msg_replica.target_vm_id);
-	if (to == NULL) {
+	vcpu_idx = receiver->vcpu_count == 1 ? 0 : cpu_index(current->cpu);
+	if (vcpu_idx >= receiver->vcpu_count) {
 		return SPCI_INVALID_PARAMETERS;
 	}
 
-	sl_lock(&to->lock);
+	vcpu = vm_get_vcpu(receiver, vcpu_idx);
 
-	if (to->mailbox.state != MAILBOX_STATE_EMPTY ||
-	    to->mailbox.recv == NULL) {
-		/*
-		 * Fail if the target isn't currently ready to receive data,
-		 * setting up for notification if requested.
-		 */
-		if (notify) {
-			struct wait_entry *entry =
-				&current->vm->wait_entries
-					 [from_msg_replica.target_vm_id];
+	sl_lock(&vcpu->lock);
 
-			/* Append waiter only if it's not there yet. */
-			if (list_empty(&entry->wait_links)) {
-				list_append(&to->mailbox.waiter_list,
-					    &entry->wait_links);
-			}
-		}
+	if (atomic_load_explicit(&receiver->aborting, memory_order_relaxed)) {
+		ret = SPCI_DENIED;
+		goto out;
+	}
 
+	if (!vcpu->regs_available ||
+	    vcpu->state != VCPU_STATE_BLOCKED_MAILBOX || vcpu->direct_request) {
 		ret = SPCI_BUSY;
 		goto out;
 	}
 
-	/* Copy data. */
-	to_msg = to->mailbox.recv;
-	*to_msg = from_msg_replica;
-	memcpy_s(to_msg->payload, SPCI_MSG_PAYLOAD_MAX,
-		 from->mailbox.send->payload, size);
-	primary_ret.message.vm_id = to->id;
+	arch_regs_set_retvals(&vcpu->regs, args, 8);
+	vcpu->direct_request = true;
+	vcpu->cpu = current->cpu;
+	vcpu->state = VCPU_STATE_RUNNING;
+	vcpu->regs_available = false;
+
+	*next = vcpu;
 	ret = SPCI_SUCCESS;
 
-	/* Messages for the primary VM are delivered directly. */
-	if (to->id == HF_PRIMARY_VM_ID) {
-		to->mailbox.state = MAILBOX_STATE_READ;
-		*next = api_switch_to_primary(current, primary_ret,
-					      VCPU_STATE_READY);
-		goto out;
-	}
+out:
+	sl_unlock(&vcpu->lock);
 
-	to->mailbox.state = MAILBOX_STATE_RECEIVED;
+	return ret;
+}
 
-	/* Return to the primary VM directly or with a switch. */
-	if (from->id != HF_PRIMARY_VM_ID) {
-		*next = api_switch_to_primary(current, primary_ret,
-					      VCPU_STATE_READY);
+/**
+ * Responds to the direct request the calling vCPU is handling. The primary VM's
+ * vCPU of the current CPU is switched to with the response in its registers,
+ * and the calling vCPU waits for a message again.
+ *
+ * Returns SPCI_SUCCESS if the response is being delivered, or the SPCI error
+ * code of the failure.
+ */
+int32_t api_ffa_msg_send_direct_resp(spci_vm_id_t vm_id,
+				     const uintreg_t args[8],
+				     struct vcpu *current, struct vcpu **next)
+{
+	struct vm *primary = vm_find(HF_PRIMARY_VM_ID);
+	struct vcpu *primary_vcpu;
+	bool direct_request;
+
+	sl_lock(&current->lock);
+	direct_request = current->direct_request;
+	if (direct_request && vm_id == HF_PRIMARY_VM_ID) {
+		current->direct_request = false;
+		current->state = VCPU_STATE_BLOCKED_MAILBOX;
 	}
+	sl_unlock(&current->lock);
 
-out:
-	sl_unlock(&to->lock);
+	if (!direct_request || vm_id != HF_PRIMARY_VM_ID) {
+		return SPCI_DENIED;
+	}
 
-	return ret;
+	primary_vcpu = vm_get_vcpu(primary, cpu_index(current->cpu));
+	arch_regs_set_retvals(&primary_vcpu->regs, args, 8);
+	*next = primary_vcpu;
+
+	return SPCI_SUCCESS;
 }
 
 /**
- * Receives a message from the mailbox. If one isn't available, this function
- * can optionally block the caller until one becomes available.
+ * Writes the descriptors of the partitions with the given UUID to the calling
+ * VM's RX buffer, or of all partitions if the UUID is null. The calling VM owns
+ * the RX buffer until it releases it.
  *
- * No new messages can be received until the mailbox has been cleared.
+ * Returns the number of descriptors written, or the SPCI error code of the
+ * failure.
  */
-int32_t api_spci_msg_recv(uint32_t attributes, struct vcpu *current,
-			  struct vcpu **next)
+int64_t api_partition_info_get(struct vcpu *current, const uint32_t uuid[4])
 {
 	struct vm *vm = current->vm;
-	int32_t return_code;
-	bool block =
-		(attributes & SPCI_MSG_RECV_BLOCK_MASK) == SPCI_MSG_RECV_BLOCK;
+	struct vm_locked locked;
+	struct ffa_partition_info *info;
+	uint32_t count = vm_get_count();
+	uint32_t written = 0;
+	uint32_t i;
+	bool all;
+	int64_t ret;
 
-	/*
-	 * The primary VM will receive messages as a status code from running
-	 * vcpus and must not call this function.
-	 */
-	if (vm->id == HF_PRIMARY_VM_ID) {
-		return SPCI_INTERRUPTED;
+	/* The null UUID asks for all partitions. */
+	all = uuid[0] == 0 && uuid[1] == 0 && uuid[2] == 0 && uuid[3] == 0;
+
+	/* No partition is reported for a UUID none of them has. */
+	if (!all && vm_find_uuid(uuid) == NULL) {
+		return SPCI_INVALID_PARAMETERS;
 	}
 
-	sl_lock(&vm->lock);
+	if (count * sizeof(struct ffa_partition_info) > HF_MAILBOX_SIZE) {
+		return SPCI_NO_MEMORY;
+	}
 
-	/* Return pending messages without blocking. */
-	if (vm->mailbox.state == MAILBOX_STATE_RECEIVED) {
-		vm->mailbox.state = MAILBOX_STATE_READ;
-		return_code = SPCI_SUCCESS;
+	locked = vm_lock(vm);
+
+	if (vm->mailbox.recv == NULL) {
+		ret = SPCI_DENIED;
 		goto out;
 	}
 
-	/* No pending message so fail if not allowed to block. */
-	if (!block) {
-		return_code = SPCI_RETRY;
+	if (!mailbox_fill(&vm->mailbox.protocol)) {
+		ret = SPCI_BUSY;
 		goto out;
 	}
 
-	/*
-	 * From this point onward this call can only be interrupted or a message
-	 * received. If a message is received the return value will be set at
-	 * that time to SPCI_SUCCESS.
-	 */
-	return_code = SPCI_INTERRUPTED;
+	info = (struct ffa_partition_info *)vm->mailbox.recv;
+	for (i = 0; i < count; ++i) {
+		struct vm *partition = vm_find(i);
 
-	/*
-	 * Don't block if there are enabled and pending interrupts, to match
-	 * behaviour of wait_for_interrupt.
-	 */
-	if (current->interrupts.enabled_and_pending_count > 0) {
-		goto out;
-	}
+		/* Destroyed VMs, and those without the UUID, are not reported. */
+		if (partition == NULL ||
+		    (!all && !vm_has_uuid(partition, uuid))) {
+			continue;
+		}
 
-	/* Switch back to primary vm to block. */
-	{
-		struct hf_vcpu_run_return run_return = {
-			.code = HF_VCPU_RUN_WAIT_FOR_MESSAGE,
+		info[written] = (struct ffa_partition_info){
+			.vm_id = partition->id,
+			.vcpu_count = partition->vcpu_count,
+			.properties = FFA_PARTITION_INDIRECT_MSG,
 		};
+		memcpy_s(info[written].uuid, sizeof(info[written].uuid),
+			 partition->uuid, sizeof(partition->uuid));
 
-		*next = api_switch_to_primary(current, run_return,
-					      VCPU_STATE_BLOCKED_MAILBOX);
+		/* The primary VM sends direct requests to the others. */
+		info[written].properties |= partition->id == HF_PRIMARY_VM_ID
+						    ? FFA_PARTITION_DIRECT_SEND
+						    : FFA_PARTITION_DIRECT_RECV;
+		written++;
 	}
+
+	ret = written;
+
 out:
-	sl_unlock(&vm->lock);
+	vm_unlock(&locked);
 
-	return return_code;
+	return ret;
 }
 
 /**
- * Retrieves the next VM whose mailbox became writable. For a VM to be notified
- * by this function, the caller must have called api_mailbox_send before with
- * the notify argument set to true, and this call must have failed because the
- * mailbox was not available.
- *
- * It should be called repeatedly to retrieve a list of VMs.
- *
- * Returns -1 if no VM became writable, or the id of the VM whose mailbox
- * became writable.
+ * Converts the state of a vCPU to how it is reported to VMs.
  */
-int64_t api_mailbox_writable_get(const struct vcpu *current)
+static uint8_t api_vcpu_state_report(enum vcpu_state state)
 {
-	struct vm *vm = current->vm;
-	struct wait_entry *entry;
-	int64_t ret;
-
-	sl_lock(&vm->lock);
-	if (list_empty(&vm->mailbox.ready_list)) {
-		ret = -1;
-		goto exit;
+	switch (state) {
+	case VCPU_STATE_OFF:
+		return HF_VCPU_STATE_OFF;
+	case VCPU_STATE_READY:
+		return HF_VCPU_STATE_READY;
+	case VCPU_STATE_RUNNING:
+		return HF_VCPU_STATE_RUNNING;
+	case VCPU_STATE_BLOCKED_MAILBOX:
+		return HF_VCPU_STATE_BLOCKED_MAILBOX;
+	case VCPU_STATE_BLOCKED_INTERRUPT:
+		return HF_VCPU_STATE_BLOCKED_INTERRUPT;
+	case VCPU_STATE_ABORTED:
+		return HF_VCPU_STATE_ABORTED;
 	}
 
-	entry = CONTAINER_OF(vm->mailbox.ready_list.next, struct wait_entry,
-			     ready_links);
-	list_remove(&entry->ready_links);
-	ret = entry - vm->wait_entries;
+	return HF_VCPU_STATE_OFF;
+}
 
-exit:
-	sl_unlock(&vm->lock);
-	return ret;
+/**
+ * Converts the state of a mailbox to how it is reported to VMs.
+ */
+static uint32_t api_mailbox_state_report(enum mailbox_state state)
+{
+	switch (state) {
+	case MAILBOX_STATE_EMPTY:
+		return HF_MAILBOX_STATE_EMPTY;
+	case MAILBOX_STATE_RECEIVED:
+		return HF_MAILBOX_STATE_RECEIVED;
+	case MAILBOX_STATE_READ:
+		return HF_MAILBOX_STATE_READ;
+	}
+
+	return HF_MAILBOX_STATE_EMPTY;
 }
 
 /**
- * Retrieves the next VM waiting to be notified that the mailbox of the
- * specified VM became writable. Only primary VMs are allowed to call this.
+ * Writes the state and resource usage of the given VM to the calling VM's RX
+ * buffer as a `struct hf_vm_stats`. Only the primary VM is allowed to call
+ * this. The calling VM owns the RX buffer until it clears the mailbox.
  *
- * Returns -1 on failure or if there are no waiters; the VM id of the next
- * waiter otherwise.
+ * Returns -1 on failure, or 0 on success.
  */
-int64_t api_mailbox_waiter_get(spci_vm_id_t vm_id, const struct vcpu *current)
+int64_t api_vm_stats_get(spci_vm_id_t vm_id, struct vcpu *current)
 {
-	struct vm *vm;
+	struct vm *vm = current->vm;
+	struct vm *target;
 	struct vm_locked locked;
-	struct wait_entry *entry;
-	struct vm *waiting_vm;
+	struct mm_ptable_stats ptable_stats;
+	struct hf_vm_stats stats;
+	uint32_t i;
+	int64_t ret;
 
-	/* Only primary VMs are allowed to call this function. */
-	if (current->vm->id != HF_PRIMARY_VM_ID) {
+	/* Only the primary VM is allowed to call this function. */
+	if (vm->id != HF_PRIMARY_VM_ID) {
 		return -1;
 	}
 
-	vm = vm_find(vm_id);
-	if (vm == NULL) {
+	target = vm_find(vm_id);
+	if (target == NULL) {
 		return -1;
 	}
 
-	/* Check if there are outstanding notifications from given vm. */
-	locked = vm_lock(vm);
-	entry = api_fetch_waiter(locked);
-	vm_unlock(&locked);
+	memset_s(&stats, sizeof(stats), 0, sizeof(stats));
+	stats.vcpu_count = target->vcpu_count;
 
-	if (entry == NULL) {
+	locked = vm_lock(target);
+	if (vm_is_destroyed(locked)) {
+		vm_unlock(&locked);
 		return -1;
 	}
+	stats.mailbox_state = api_mailbox_state_report(
+		mailbox_state(&target->mailbox.protocol));
+	stats.mapped_pages = mm_vm_mapped_pages(&target->ptable);
+	mm_vm_get_stats(&target->ptable, &ptable_stats);
+	stats.page_table_pages = ptable_stats.allocated - ptable_stats.freed;
+	stats.fault_count = target->fault_count;
+	vm_unlock(&locked);
 
-	/* Enqueue notification to waiting VM. */
-	waiting_vm = entry->waiting_vm;
+	for (i = 0; i < target->vcpu_count && i < HF_VM_STATS_MAX_VCPUS; ++i) {
+		struct vcpu *vcpu = vm_get_vcpu(target, i);
 
-	sl_lock(&waiting_vm->lock);
-	if (list_empty(&entry->ready_links)) {
-		list_append(&waiting_vm->mailbox.ready_list,
-			    &entry->ready_links);
+		sl_lock(&vcpu->lock);
+		stats.vcpu_states[i] = api_vcpu_state_report(vcpu->state);
+		stats.vcpu_stats[i] = (struct hf_vcpu_stats){
+			.hypercall_exits = vcpu->stats.hypercall_exits,
+			.wfi_exits = vcpu->stats.wfi_exits,
+			.page_fault_exits = vcpu->stats.page_fault_exits,
+			.interrupt_exits = vcpu->stats.interrupt_exits,
+			.injected_interrupts = vcpu->stats.injected_interrupts,
+			.run_ns = arch_timer_ticks_to_ns(vcpu->stats.run_ticks),
+		};
+		sl_unlock(&vcpu->lock);
 	}
-	sl_unlock(&waiting_vm->lock);
 
-	return waiting_vm->id;
+	locked = vm_lock(vm);
+
+	if (vm->mailbox.recv == NULL || !mailbox_fill(&vm->mailbox.protocol)) {
+		ret = -1;
+		goto out;
+	}
+
+	memcpy_s(vm->mailbox.recv, HF_MAILBOX_SIZE, &stats, sizeof(stats));
+	ret = 0;
+
+out:
+	vm_unlock(&locked);
+
+	return ret;
 }
 
 /**
- * Clears the caller's mailbox so that a new message can be received. The caller
- * must have copied out all data they wish to preserve as new messages will
- * overwrite the old and will arrive asynchronously.
+ * Writes the topology of the physical CPUs to the calling VM's RX buffer as an
+ * array of `struct hf_cpu_topology`, for the primary VM to place vCPUs. Only
+ * the primary VM is allowed to call this. The calling VM owns the RX buffer
+ * until it clears the mailbox.
  *
- * Returns:
- *  - -1 on failure, if the mailbox hasn't been read.
- *  - 0 on success if no further action is needed.
- *  - 1 if it was called by the primary VM and the primary VM now needs to wake
- *    up or kick waiters. Waiters should be retrieved by calling
- *    hf_mailbox_waiter_get.
+ * Returns -1 on failure, or the number of CPUs on success.
  */
-int64_t api_mailbox_clear(struct vcpu *current, struct vcpu **next)
+int64_t api_cpu_topology_get(struct vcpu *current)
 {
 	struct vm *vm = current->vm;
 	struct vm_locked locked;
 	int64_t ret;
 
+	/* Only the primary VM is allowed to call this function. */
+	if (vm->id != HF_PRIMARY_VM_ID) {
+		return -1;
+	}
+
 	locked = vm_lock(vm);
-	switch (vm->mailbox.state) {
-	case MAILBOX_STATE_EMPTY:
-		ret = 0;
-		break;
 
-	case MAILBOX_STATE_RECEIVED:
+	if (vm->mailbox.recv == NULL || !mailbox_fill(&vm->mailbox.protocol)) {
 		ret = -1;
-		break;
+		goto out;
+	}
+
+	ret = cpu_topology_get(
+		(struct hf_cpu_topology *)vm->mailbox.recv,
+		HF_MAILBOX_SIZE / sizeof(struct hf_cpu_topology));
+
+out:
+	vm_unlock(&locked);
+
+	return ret;
+}
+
+/**
+ * Moves the recent lines of the log of the given VM to the calling VM's RX
+ * buffer, so that the primary VM can read each VM's log apart from the others.
+ * Only the primary VM is allowed to call this. The calling VM owns the RX buffer
+ * until it clears the mailbox.
+ *
+ * Returns -1 on failure, or the number of bytes written on success.
+ */
+int64_t api_vm_log_drain(spci_vm_id_t vm_id, struct vcpu *current)
+{
+	struct vm *vm = current->vm;
+	struct vm_locked locked;
+	int64_t ret;
+
+	/* Only the primary VM is allowed to call this function. */
+	if (vm->id != HF_PRIMARY_VM_ID) {
+		return -1;
+	}
+
+	if (vm_find(vm_id) == NULL) {
+		return -1;
+	}
+
+	locked = vm_lock(vm);
 
-	case MAILBOX_STATE_READ:
-		ret = api_waiter_result(locked, current, next);
-		vm->mailbox.state = MAILBOX_STATE_EMPTY;
-		break;
+	if (vm->mailbox.recv == NULL || !mailbox_fill(&vm->mailbox.protocol)) {
+		ret = -1;
+		goto out;
 	}
+
+	ret = guest_log_drain(vm_id, (uint8_t *)vm->mailbox.recv,
+			      HF_MAILBOX_SIZE);
+
+out:
 	vm_unlock(&locked);
 
 	return ret;
 }
 
 /**
- * Enables or disables a given interrupt ID for the calling vCPU.
+ * Moves the oldest bytes of the hypervisor's own log to the calling VM's RX
+ * buffer, so that the primary VM can read messages that may not have reached
+ * the console, e.g. from early boot. Only the primary VM is allowed to call
+ * this. The calling VM owns the RX buffer until it clears the mailbox.
  *
- * Returns 0 on success, or -1 if the intid is invalid.
+ * Returns -1 on failure, or the number of bytes written on success.
  */
-int64_t api_interrupt_enable(uint32_t intid, bool enable, struct vcpu *current)
+int64_t api_dlog_drain(struct vcpu *current)
 {
-	uint32_t intid_index = intid / INTERRUPT_REGISTER_BITS;
-	uint32_t intid_mask = 1u << (intid % INTERRUPT_REGISTER_BITS);
+	struct vm *vm = current->vm;
+	struct vm_locked locked;
+	int64_t ret;
 
-	if (intid >= HF_NUM_INTIDS) {
+	/* Only the primary VM is allowed to call this function. */
+	if (vm->id != HF_PRIMARY_VM_ID) {
 		return -1;
 	}
 
-	sl_lock(&current->lock);
-	if (enable) {
-		/*
-		 * If it is pending and was not enabled before, increment the
-		 * count.
-		 */
-		if (current->interrupts.interrupt_pending[intid_index] &
-		    ~current->interrupts.interrupt_enabled[intid_index] &
-		    intid_mask) {
-			current->interrupts.enabled_and_pending_count++;
-		}
-		current->interrupts.interrupt_enabled[intid_index] |=
-			intid_mask;
-	} else {
-		/*
-		 * If it is pending and was enabled before, decrement the count.
-		 */
-		if (current->interrupts.interrupt_pending[intid_index] &
-		    current->interrupts.interrupt_enabled[intid_index] &
-		    intid_mask) {
-			current->interrupts.enabled_and_pending_count--;
-		}
-		current->interrupts.interrupt_enabled[intid_index] &=
-			~intid_mask;
-	}
-
-	sl_unlock(&current->lock);
-	return 0;
-}
+	locked = vm_lock(vm);
 
-/**
- * Returns the ID of the next pending interrupt for the calling vCPU, and
- * acknowledges it (i.e. marks it as no longer pending). Returns
- * HF_INVALID_INTID if there are no pending interrupts.
- */
-uint32_t api_interrupt_get(struct vcpu *current)
-{
-	uint8_t i;
-	uint32_t first_interrupt = HF_INVALID_INTID;
+	if (vm->mailbox.recv == NULL || !mailbox_fill(&vm->mailbox.protocol)) {
+		ret = -1;
+		goto out;
+	}
 
-	/*
-	 * Find the first enabled and pending interrupt ID, return it, and
-	 * deactivate it.
-	 */
-	sl_lock(&current->lock);
-	for (i = 0; i < HF_NUM_INTIDS / INTERRUPT_REGISTER_BITS; ++i) {
-		uint32_t enabled_and_pending =
-			current->interrupts.interrupt_enabled[i] &
-			current->interrupts.interrupt_pending[i];
+	ret = dlog_drain((uint8_t *)vm->mailbox.recv, HF_MAILBOX_SIZE);
 
-		if (enabled_and_pending != 0) {
-			uint8_t bit_index = ctz(enabled_and_pending);
-			/*
-			 * Mark it as no longer pending and decrement the count.
-			 */
-			current->interrupts.interrupt_pending[i] &=
-				~(1u << bit_index);
-			current->interrupts.enabled_and_pending_count--;
-			first_interrupt =
-				i * INTERRUPT_REGISTER_BITS + bit_index;
-			break;
-		}
-	}
+out:
+	vm_unlock(&locked);
 
-	sl_unlock(&current->lock);
-	return first_interrupt;
+	return ret;
 }
 
 /**
- * Returns whether the current vCPU is allowed to inject an interrupt into the
- * given VM and vCPU.
+ * Takes the vCPU that the primary VM should run next on the current physical
+ * CPU from the CPU's run queue. Only the primary VM is allowed to call this.
+ *
+ * Returns -1 if the run queue is empty or the caller is not the primary VM,
+ * or `vm_id << 16 | vcpu_idx` otherwise.
  */
-static inline bool is_injection_allowed(uint32_t target_vm_id,
-					struct vcpu *current)
+int64_t api_run_queue_pop(const struct vcpu *current)
 {
-	uint32_t current_vm_id = current->vm->id;
+	/* Only the primary VM is allowed to call this function. */
+	if (current->vm->id != HF_PRIMARY_VM_ID) {
+		return -1;
+	}
 
-	/*
-	 * The primary VM is allowed to inject interrupts into any VM. Secondary
-	 * VMs are only allowed to inject interrupts into their own vCPUs.
-	 */
-	return current_vm_id == HF_PRIMARY_VM_ID ||
-	       current_vm_id == target_vm_id;
+	return run_queue_pop(cpu_index(current->cpu));
 }
 
 /**
- * Injects a virtual interrupt of the given ID into the given target vCPU.
- * This doesn't cause the vCPU to actually be run immediately; it will be taken
- * when the vCPU is next run, which is up to the scheduler.
+ * Shares the given ranges of memory of the calling VM with another, giving it
+ * the access `mode` to them. Either all the ranges are shared, or none is.
  *
- * Returns:
- *  - -1 on failure because the target VM or vCPU doesn't exist, the interrupt
- *    ID is invalid, or the current VM is not allowed to inject interrupts to
- *    the target VM.
- *  - 0 on success if no further action is needed.
- *  - 1 if it was called by the primary VM and the primary VM now needs to wake
- *    up or kick the target vCPU.
+ * Returns SPCI_SUCCESS on success, or the SPCI error code of the failure.
  */
-int64_t api_interrupt_inject(spci_vm_id_t target_vm_id,
-			     uint32_t target_vcpu_idx, uint32_t intid,
-			     struct vcpu *current, struct vcpu **next)
+int32_t api_share_memory_ranges(spci_vm_id_t vm_id,
+				const struct mem_range *ranges, size_t count,
+				enum hf_share share, int mode,
+				struct vcpu *current)
 {
-	struct vcpu *target_vcpu;
-	struct vm *target_vm = vm_find(target_vm_id);
+	struct vm *from = current->vm;
+	struct vm *to;
+	struct vm_locked locked;
+	uint64_t size = 0;
+	size_t i;
+	int32_t ret;
 
-	if (intid >= HF_NUM_INTIDS) {
-		return -1;
+	/* Disallow reflexive shares as this suggests an error in the VM. */
+	if (vm_id == from->id) {
+		return SPCI_INVALID_PARAMETERS;
 	}
 
-	if (target_vm == NULL) {
-		return -1;
+	to = vm_find(vm_id);
+	if (to == NULL) {
+		return SPCI_INVALID_PARAMETERS;
 	}
 
-	if (target_vcpu_idx >= target_vm->vcpu_count) {
-		/* The requested vcpu must exist. */
-		return -1;
+	/* The memory mapped into the recipient must stay within its quota. */
+	for (i = 0; i < count; ++i) {
+		uint64_t range_size =
+			ipa_addr(ranges[i].end) - ipa_addr(ranges[i].begin);
+
+		if (ipa_addr(ranges[i].end) < ipa_addr(ranges[i].begin) ||
+		    size + range_size < size) {
+			return SPCI_INVALID_PARAMETERS;
+		}
+		size += range_size;
 	}
 
-	if (!is_injection_allowed(target_vm_id, current)) {
-		return -1;
+	sl_lock_both(&from->lock, &to->lock);
+	locked.vm = to;
+	if (vm_is_destroyed(locked)) {
+		ret = SPCI_INVALID_PARAMETERS;
+	} else if (vm_quota_allows(locked, size)) {
+		ret = api_share_memory_ranges_ptables(
+			&from->ptable, &to->ptable, ranges, count, share, mode,
+			&api_page_pool);
+	} else {
+		ret = SPCI_NO_MEMORY;
 	}
+	sl_unlock(&from->lock);
+	sl_unlock(&to->lock);
 
-	target_vcpu = vm_get_vcpu(target_vm, target_vcpu_idx);
+	if (ret == SPCI_SUCCESS) {
+		api_tlb_sync(current);
+	}
 
-	dlog("Injecting IRQ %d for VM %d VCPU %d from VM %d VCPU %d\n", intid,
-	     target_vm_id, target_vcpu_idx, current->vm->id, current->cpu->id);
-	return internal_interrupt_inject(target_vcpu, intid, current, next);
+	return ret;
 }
 
 /**
- * Clears a region of physical memory by overwriting it with zeros. The data is
- * flushed from the cache so the memory has been cleared across the system.
+ * Copies the first `size` bytes of the calling VM's send buffer to `buf`.
+ *
+ * Returns false if the send buffer is not configured or smaller than `size`.
  */
-static bool api_clear_memory(paddr_t begin, paddr_t end, struct mpool *ppool)
+bool api_mailbox_send_copy(struct vcpu *current, void *buf, size_t size)
 {
-	/*
-	 * TODO: change this to a cpu local single page window rather than a
-	 *       global mapping of the whole range. Such an approach will limit
-	 *       the changes to stage-1 tables and will allow only local
-	 *       invalidation.
-	 */
-	void *ptr = mm_identity_map(begin, end, MM_MODE_W, ppool);
-	size_t size = pa_difference(begin, end);
+	struct vm *vm = current->vm;
+	struct vm_locked locked;
+	bool ret = false;
 
-	if (!ptr) {
-		/* TODO: partial defrag of failed range. */
-		/* Recover any memory consumed in failed mapping. */
-		mm_defrag(ppool);
+	if (size > HF_MAILBOX_SIZE) {
 		return false;
 	}
 
-	memset_s(ptr, size, 0, size);
-	arch_mm_write_back_dcache(ptr, size);
-	mm_unmap(begin, end, ppool);
+	locked = vm_lock(vm);
+	if (vm->mailbox.send != NULL) {
+		memcpy_s(buf, size, vm->mailbox.send, size);
+		ret = true;
+	}
+	vm_unlock(&locked);
 
-	return true;
+	return ret;
 }
 
 /**
- * Shares memory from the calling VM with another. The memory can be shared in
- * different modes.
- *
- * TODO: the interface for sharing memory will need to be enhanced to allow
- *       sharing with different modes e.g. read-only, informing the recipient
- *       of the memory they have been given, opting to not wipe the memory and
- *       possibly allowing multiple blocks to be transferred. What this will
- *       look like is TBD.
+ * Relinquishes memory the calling VM borrowed from another VM, so that the
+ * owner can reclaim it.
  */
-int64_t api_share_memory(spci_vm_id_t vm_id, ipaddr_t addr, size_t size,
-			 enum hf_share share, struct vcpu *current)
+int64_t api_memory_relinquish(spci_vm_id_t vm_id, ipaddr_t addr, size_t size,
+			      struct vcpu *current)
 {
 	struct vm *from = current->vm;
-	struct vm *to;
-	int orig_from_mode;
-	int from_mode;
-	int to_mode;
-	ipaddr_t begin;
-	ipaddr_t end;
-	paddr_t pa_begin;
-	paddr_t pa_end;
-	struct mpool local_page_pool;
-	int64_t ret;
+	struct vm *owner;
+	struct vm_locked owner_locked;
+	bool ret;
 
-	/* Disallow reflexive shares as this suggests an error in the VM. */
 	if (vm_id == from->id) {
 		return -1;
 	}
 
-	/* Ensure the target VM exists. */
-	to = vm_find(vm_id);
-	if (to == NULL) {
+	owner = vm_find(vm_id);
+	if (owner == NULL) {
 		return -1;
 	}
 
-	begin = addr;
-	end = ipa_add(addr, size);
+	sl_lock_both(&from->lock, &owner->lock);
+	owner_locked.vm = owner;
+	ret = !vm_is_destroyed(owner_locked) &&
+	      api_memory_relinquish_ptables(&from->ptable, &owner->ptable, addr,
+					    ipa_add(addr, size),
+					    &api_page_pool);
+	sl_unlock(&from->lock);
+	sl_unlock(&owner->lock);
 
-	/* Fail if addresses are not page-aligned. */
-	if (!is_aligned(ipa_addr(begin), PAGE_SIZE) ||
-	    !is_aligned(ipa_addr(end), PAGE_SIZE)) {
-		return -1;
+	if (ret) {
+		api_tlb_sync(current);
 	}
 
-	/* Convert the sharing request to memory management modes. */
-	switch (share) {
-	case HF_MEMORY_GIVE:
-		from_mode = MM_MODE_INVALID | MM_MODE_UNOWNED;
-		to_mode = MM_MODE_R | MM_MODE_W | MM_MODE_X;
-		break;
-
-	case HF_MEMORY_LEND:
-		from_mode = MM_MODE_INVALID;
-		to_mode = MM_MODE_R | MM_MODE_W | MM_MODE_X | MM_MODE_UNOWNED;
-		break;
+	return ret ? 0 : -1;
+}
 
-	case HF_MEMORY_SHARE:
-		from_mode = MM_MODE_R | MM_MODE_W | MM_MODE_X | MM_MODE_SHARED;
-		to_mode = MM_MODE_R | MM_MODE_W | MM_MODE_X | MM_MODE_UNOWNED |
-			  MM_MODE_SHARED;
-		break;
+/**
+ * Reclaims memory the calling VM lent or shared with another VM. Fails until
+ * the other VM has relinquished the memory. The memory is cleared before the
+ * calling VM regains access to it.
+ */
+int64_t api_memory_reclaim(spci_vm_id_t vm_id, ipaddr_t addr, size_t size,
+			   struct vcpu *current)
+{
+	struct vm *owner = current->vm;
+	struct vm *borrower;
+	struct vm_locked borrower_locked;
+	bool ret;
 
-	default:
-		/* The input is untrusted so might not be a valid value. */
+	if (vm_id == owner->id) {
 		return -1;
 	}
 
-	/*
-	 * Create a local pool so any freed memory can't be used by another
-	 * thread. This is to ensure the original mapping can be restored if any
-	 * stage of the process fails.
-	 */
-	mpool_init_with_fallback(&local_page_pool, &api_page_pool);
+	borrower = vm_find(vm_id);
+	if (borrower == NULL) {
+		return -1;
+	}
 
-	sl_lock_both(&from->lock, &to->lock);
+	sl_lock_both(&owner->lock, &borrower->lock);
+	borrower_locked.vm = borrower;
+	ret = !vm_is_destroyed(borrower_locked) &&
+	      api_memory_reclaim_ptables(&owner->ptable, &borrower->ptable,
+					 addr, ipa_add(addr, size),
+					 &api_page_pool);
+	sl_unlock(&owner->lock);
+	sl_unlock(&borrower->lock);
 
-	/*
-	 * Ensure that the memory range is mapped with the same mode so that
-	 * changes can be reverted if the process fails.
-	 */
-	if (!mm_vm_get_mode(&from->ptable, begin, end, &orig_from_mode)) {
-		goto fail;
+	if (ret) {
+		api_tlb_sync(current);
 	}
 
-	/*
-	 * Ensure the memory range is valid for the sender. If it isn't, the
-	 * sender has either shared it with another VM already or has no claim
-	 * to the memory.
-	 */
-	if (orig_from_mode & MM_MODE_INVALID) {
-		goto fail;
-	}
+	return ret ? 0 : -1;
+}
 
-	/*
-	 * The sender must own the memory and have exclusive access to it in
-	 * order to share it. Alternatively, it is giving memory back to the
-	 * owning VM.
-	 */
-	if (orig_from_mode & MM_MODE_UNOWNED) {
-		int orig_to_mode;
+/**
+ * Dumps the lock contention counters to the log. Only the primary VM may do
+ * so.
+ */
+/**
+ * Signals the given notification bits to another VM. Bits that were not
+ * already pending are delivered by injecting HF_NOTIFICATION_INTID into the
+ * first vCPU of a secondary VM, or by switching to the primary VM with an
+ * HF_VCPU_RUN_NOTIFICATION return.
+ *
+ * Returns -1 on failure, 1 if the primary VM must run or kick the target vCPU,
+ * or 0 otherwise.
+ */
+int64_t api_notification_set(spci_vm_id_t vm_id, uint64_t bits,
+			     struct vcpu *current, struct vcpu **next)
+{
+	struct vm *to = vm_find(vm_id);
 
-		if (share != HF_MEMORY_GIVE ||
-		    !mm_vm_get_mode(&to->ptable, begin, end, &orig_to_mode) ||
-		    orig_to_mode & MM_MODE_UNOWNED) {
-			goto fail;
-		}
-	} else if (orig_from_mode & MM_MODE_SHARED) {
-		goto fail;
+	if (to == NULL || to == current->vm || bits == 0) {
+		return -1;
 	}
 
-	pa_begin = pa_from_ipa(begin);
-	pa_end = pa_from_ipa(end);
-
-	/*
-	 * First update the mapping for the sender so there is not overlap with
-	 * the recipient.
-	 */
-	if (!mm_vm_identity_map(&from->ptable, pa_begin, pa_end, from_mode,
-				NULL, &local_page_pool)) {
-		goto fail;
+	if (notification_set(vm_id, bits) == 0) {
+		/* All the bits were already pending, so already delivered. */
+		return 0;
 	}
 
-	/* Clear the memory so no VM or device can see the previous contents. */
-	if (!api_clear_memory(pa_begin, pa_end, &local_page_pool)) {
-		goto fail_return_to_sender;
-	}
+	if (vm_id == HF_PRIMARY_VM_ID) {
+		struct hf_vcpu_run_return primary_ret = {
+			.code = HF_VCPU_RUN_NOTIFICATION,
+			.notification.vm_id = current->vm->id,
+		};
 
-	/* Complete the transfer by mapping the memory into the recipient. */
-	if (!mm_vm_identity_map(&to->ptable, pa_begin, pa_end, to_mode, NULL,
-				&local_page_pool)) {
-		/* TODO: partial defrag of failed range. */
-		/* Recover any memory consumed in failed mapping. */
-		mm_vm_defrag(&from->ptable, &local_page_pool);
-		goto fail_return_to_sender;
+		*next = api_switch_to_primary(current, primary_ret,
+					      VCPU_STATE_READY);
+		return 0;
 	}
 
-	ret = 0;
-	goto out;
+	return internal_interrupt_inject(vm_get_vcpu(to, 0),
+					 HF_NOTIFICATION_INTID, current, next);
+}
 
-fail_return_to_sender:
-	mm_vm_identity_map(&from->ptable, pa_begin, pa_end, orig_from_mode,
-			   NULL, &local_page_pool);
+/**
+ * Takes the notification bits pending for the calling VM.
+ */
+uint64_t api_notification_get(const struct vcpu *current)
+{
+	return notification_take(current->vm->id);
+}
 
-fail:
-	ret = -1;
+int64_t api_lock_stats_dump(const struct vcpu *current)
+{
+	if (current->vm->id != HF_PRIMARY_VM_ID) {
+		return -1;
+	}
 
-out:
-	sl_unlock(&from->lock);
-	sl_unlock(&to->lock);
+	return lock_stats_dump();
+}
 
-	mpool_fini(&local_page_pool);
+/**
+ * Negotiates the version of the Hafnium API with the caller, which supports
+ * the `requested` version. If the major revisions match, the lower of the
+ * two versions is recorded for the caller's VM.
+ *
+ * Returns the version implemented by Hafnium.
+ */
+int64_t api_hf_version(uint32_t requested, struct vcpu *current)
+{
+	struct vm *vm = current->vm;
 
-	return ret;
+	if ((requested >> HF_API_VERSION_MAJOR_OFFSET) == HF_API_VERSION_MAJOR) {
+		sl_lock(&vm->lock);
+		vm->api_version = requested < HF_API_VERSION ? requested
+							     : HF_API_VERSION;
+		sl_unlock(&vm->lock);
+	}
+
+	return HF_API_VERSION;
 }
 
 /** Returns the version of the implemented SPCI specification. */
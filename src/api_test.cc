@@ -22,15 +22,95 @@
 
 extern "C" {
 #include "hf/api.h"
+#include "hf/frame.h"
+#include "hf/mm.h"
 }
 
+#include <memory>
+
 namespace
 {
 using ::testing::Eq;
 
+constexpr size_t TEST_HEAP_SIZE = PAGE_SIZE * 32;
+
+struct alignas(PAGE_SIZE) raw_page {
+	char data[PAGE_SIZE];
+};
+
 TEST(api, vm_get_count)
 {
 	EXPECT_THAT(api_vm_get_count(), Eq(0));
 }
 
+/**
+ * Tests of the checks against the frame table, which is initialised for each
+ * test from a heap of its own, and freed after it.
+ */
+class api_frames : public ::testing::Test
+{
+	void SetUp() override
+	{
+		test_heap = std::make_unique<raw_page[]>(TEST_HEAP_SIZE / PAGE_SIZE);
+		mpool_init(&ppool, sizeof(struct mm_page_table));
+		mpool_add_chunk(&ppool, test_heap.get(), TEST_HEAP_SIZE);
+		ASSERT_TRUE(frame_table_init(begin, end, &ppool));
+	}
+
+	void TearDown() override
+	{
+		frame_table_fini(&ppool);
+	}
+
+	std::unique_ptr<raw_page[]> test_heap;
+
+       protected:
+	const paddr_t begin = pa_init(0x4000'0000);
+	const paddr_t end = pa_add(begin, PAGE_SIZE);
+	struct mpool ppool;
+};
+
+/**
+ * A VM can't share memory its page table maps as owned if the frame table
+ * records another VM as its owner.
+ */
+TEST_F(api_frames, share_memory_not_owned_fails)
+{
+	struct mm_ptable from;
+	struct mm_ptable owner;
+	struct mm_ptable to;
+	int mode;
+
+	ASSERT_TRUE(mm_vm_init(&from, &ppool));
+	ASSERT_TRUE(mm_vm_init(&owner, &ppool));
+	ASSERT_TRUE(mm_vm_init(&to, &ppool));
+	mm_vm_set_owner(&from, 1);
+	mm_vm_set_owner(&owner, 2);
+	mm_vm_set_owner(&to, 3);
+
+	/* The last VM the page is mapped into as owned becomes its owner. */
+	ASSERT_TRUE(mm_vm_identity_map(&from, begin, end,
+				       MM_MODE_R | MM_MODE_W | MM_MODE_X,
+				       nullptr, &ppool));
+	ASSERT_TRUE(mm_vm_identity_map(&owner, begin, end,
+				       MM_MODE_R | MM_MODE_W | MM_MODE_X,
+				       nullptr, &ppool));
+
+	EXPECT_FALSE(api_share_memory_ptables(&from, &to, ipa_from_pa(begin),
+					      ipa_from_pa(end), HF_MEMORY_GIVE,
+					      &ppool));
+
+	/* Neither VM's mapping has changed. */
+	ASSERT_TRUE(mm_vm_get_mode(&from, ipa_from_pa(begin), ipa_from_pa(end),
+				   &mode));
+	EXPECT_THAT(mode & MM_MODE_INVALID, Eq(0));
+	ASSERT_TRUE(mm_vm_get_mode(&to, ipa_from_pa(begin), ipa_from_pa(end),
+				   &mode));
+	EXPECT_THAT(mode & MM_MODE_INVALID, Eq(MM_MODE_INVALID));
+
+	mm_vm_fini(&from, &ppool);
+	mm_vm_fini(&owner, &ppool);
+	mm_vm_fini(&to, &ppool);
+}
+
 } /* namespace */
@@ -23,6 +23,8 @@
 #include "hf/addr.h"
 #include "hf/std.h"
 
+#include "msr.h"
+
 void arch_irq_disable(void)
 {
 	__asm__ volatile("msr DAIFSet, #0xf");
@@ -33,6 +35,25 @@ void arch_irq_enable(void)
 	__asm__ volatile("msr DAIFClr, #0xf");
 }
 
+uintreg_t arch_irq_save(void)
+{
+	uintreg_t daif = read_msr(DAIF);
+
+	arch_irq_disable();
+	return daif;
+}
+
+void arch_irq_restore(uintreg_t state)
+{
+	write_msr(DAIF, state);
+}
+
+uint64_t arch_cpu_id(void)
+{
+	/* The affinity fields of MPIDR_EL1. */
+	return read_msr(MPIDR_EL1) & UINT64_C(0xff00ffffff);
+}
+
 static void gic_regs_reset(struct arch_regs *r, bool is_primary)
 {
 #if GIC_VERSION == 3 || GIC_VERSION == 4
@@ -47,7 +68,7 @@ static void gic_regs_reset(struct arch_regs *r, bool is_primary)
 #endif
 }
 
-void arch_regs_reset(struct arch_regs *r, bool is_primary, spci_vm_id_t vm_id,
+void arch_regs_reset(struct arch_regs *r, bool is_primary, uint16_t vmid,
 		     uint64_t vcpu_id, paddr_t table)
 {
 	uintreg_t pc = r->pc;
@@ -69,6 +90,10 @@ void arch_regs_reset(struct arch_regs *r, bool is_primary, spci_vm_id_t vm_id,
 	      (1u << 2) |  /* PTW, Protected Table Walk. */
 	      (1u << 0);   /* VM: enable stage-2 translation. */
 
+	/*
+	 * FP/SIMD accesses are trapped as needed when the vCPU is run, see
+	 * begin_restoring_state().
+	 */
 	cptr = 0;
 	cnthctl = 0;
 
@@ -82,15 +107,13 @@ void arch_regs_reset(struct arch_regs *r, bool is_primary, spci_vm_id_t vm_id,
 		       (1u << 10) | /* BSU bits set to inner-sh. */
 		       (3u << 13);  /* TWI, TWE bits. */
 
-		/* TODO: Trap fp access once handler logic is in place. */
-
 		/* TODO: Investigate fpexc32_el2 for 32bit EL0 support. */
 	}
 
 	r->lazy.hcr_el2 = hcr;
 	r->lazy.cptr_el2 = cptr;
 	r->lazy.cnthctl_el2 = cnthctl;
-	r->lazy.vttbr_el2 = pa_addr(table) | ((uint64_t)vm_id << 48);
+	r->lazy.vttbr_el2 = pa_addr(table) | ((uint64_t)vmid << 48);
 	r->lazy.vmpidr_el2 = vcpu_id;
 	/* TODO: Use constant here. */
 	r->spsr = 5 |	 /* M bits, set to EL1h. */
@@ -109,3 +132,13 @@ void arch_regs_set_retval(struct arch_regs *r, uintreg_t v)
 {
 	r->r[0] = v;
 }
+
+void arch_regs_set_retvals(struct arch_regs *r, const uintreg_t *v,
+			   size_t count)
+{
+	size_t i;
+
+	for (i = 0; i < count && i < ARRAY_SIZE(r->r); ++i) {
+		r->r[i] = v[i];
+	}
+}
@@ -20,9 +20,13 @@
 #include "hf/arch/init.h"
 
 #include "hf/api.h"
+#include "hf/capability.h"
 #include "hf/cpu.h"
 #include "hf/dlog.h"
+#include "hf/ffa.h"
+#include "hf/hypercall.h"
 #include "hf/panic.h"
+#include "hf/smc_filter.h"
 #include "hf/spci.h"
 #include "hf/vm.h"
 
@@ -35,6 +39,21 @@
 
 #define HCR_EL2_VI (1u << 7)
 
+#define CNTV_CTL_EL0_ENABLE (1u << 0)
+#define CNTV_CTL_EL0_IMASK (1u << 1)
+#define CNTHP_CTL_EL2_ENABLE (1u << 0)
+#define CPTR_EL2_TFP (1u << 10)
+
+/**
+ * The longest a secondary vCPU runs before it is preempted and control returns
+ * to the primary VM's scheduler, in milliseconds.
+ */
+#define VCPU_TIME_SLICE_MS 10
+
+/* Saves and restores the floating point registers, see exceptions.S. */
+void fp_save(struct vcpu *vcpu);
+void fp_restore(struct vcpu *vcpu);
+
 struct hvc_handler_return {
 	uintreg_t user_ret;
 	struct vcpu *new;
@@ -52,18 +71,41 @@ static struct vcpu *current(void)
  */
 void complete_saving_state(struct vcpu *vcpu)
 {
+	vcpu->stats.run_ticks += read_msr(cntpct_el0) - vcpu->stats.run_start;
+
 	vcpu->regs.peripherals.cntv_cval_el0 = read_msr(cntv_cval_el0);
 	vcpu->regs.peripherals.cntv_ctl_el0 = read_msr(cntv_ctl_el0);
 
+	/*
+	 * Floating point accesses are only untrapped once the vCPU has used
+	 * the registers, in which case they must be saved. They stay loaded in
+	 * case the vCPU is run here next.
+	 */
+	if (!(read_msr(cptr_el2) & CPTR_EL2_TFP)) {
+		fp_save(vcpu);
+	}
+
 	api_regs_state_saved(vcpu);
 
 	/*
-	 * If switching away from the primary, copy the current EL0 virtual
-	 * timer registers to the corresponding EL2 physical timer registers.
-	 * This is used to emulate the virtual timer for the primary in case it
-	 * should fire while the secondary is running.
+	 * If switching away from the primary, program the EL2 physical timer to
+	 * fire at the end of the secondary's time slice, or earlier if the
+	 * primary's EL0 virtual timer would fire before then. The latter
+	 * emulates the virtual timer for the primary in case it should fire
+	 * while the secondary is running. Either way, the interrupt preempts
+	 * the secondary and returns control to the primary.
 	 */
 	if (vcpu->vm->id == HF_PRIMARY_VM_ID) {
+		uintreg_t cntv_ctl_el0 = read_msr(cntv_ctl_el0);
+		uintreg_t cval = read_msr(cntpct_el0) +
+				 read_msr(cntfrq_el0) * VCPU_TIME_SLICE_MS / 1000;
+
+		if ((cntv_ctl_el0 & CNTV_CTL_EL0_ENABLE) &&
+		    !(cntv_ctl_el0 & CNTV_CTL_EL0_IMASK) &&
+		    read_msr(cntv_cval_el0) < cval) {
+			cval = read_msr(cntv_cval_el0);
+		}
+
 		/*
 		 * Clear timer control register before copying compare value, to
 		 * avoid a spurious timer interrupt. This could be a problem if
@@ -71,8 +113,8 @@ void complete_saving_state(struct vcpu *vcpu)
 		 * then be latched in.
 		 */
 		write_msr(cnthp_ctl_el2, 0);
-		write_msr(cnthp_cval_el2, read_msr(cntv_cval_el0));
-		write_msr(cnthp_ctl_el2, read_msr(cntv_ctl_el0));
+		write_msr(cnthp_cval_el2, cval);
+		write_msr(cnthp_ctl_el2, CNTHP_CTL_EL2_ENABLE);
 	}
 }
 
@@ -90,15 +132,28 @@ void begin_restoring_state(struct vcpu *vcpu)
 	write_msr(cntv_cval_el0, vcpu->regs.peripherals.cntv_cval_el0);
 	write_msr(cntv_ctl_el0, vcpu->regs.peripherals.cntv_ctl_el0);
 
+	/*
+	 * Trap floating point accesses unless the registers still hold the
+	 * vCPU's state, so that they are only restored if the vCPU uses them.
+	 */
+	if (cpu_fp_is_live(vcpu->cpu, vcpu)) {
+		vcpu->regs.lazy.cptr_el2 &= ~CPTR_EL2_TFP;
+	} else {
+		vcpu->regs.lazy.cptr_el2 |= CPTR_EL2_TFP;
+	}
+
 	/*
 	 * If we are switching (back) to the primary, disable the EL2 physical
-	 * timer which was being used to emulate the EL0 virtual timer, as the
-	 * virtual timer is now running for the primary again.
+	 * timer which was being used to preempt the secondary and emulate the
+	 * EL0 virtual timer, as the virtual timer is now running for the
+	 * primary again.
 	 */
 	if (vcpu->vm->id == HF_PRIMARY_VM_ID) {
 		write_msr(cnthp_ctl_el2, 0);
 		write_msr(cnthp_cval_el2, 0);
 	}
+
+	vcpu->stats.run_start = read_msr(cntpct_el0);
 }
 
 /**
@@ -174,13 +229,37 @@ noreturn void serr_current_exception(uintreg_t elr, uintreg_t spsr)
 	panic("SERR from current");
 }
 
-noreturn void sync_current_exception(uintreg_t elr, uintreg_t spsr)
+/**
+ * Untraps floating point accesses and loads the state of the given vCPU, which
+ * is running on the current CPU, into the registers unless they still hold it.
+ */
+static void fp_take(struct vcpu *vcpu)
+{
+	write_msr(cptr_el2, read_msr(cptr_el2) & ~CPTR_EL2_TFP);
+	isb();
+
+	if (!cpu_fp_is_live(vcpu->cpu, vcpu)) {
+		fp_restore(vcpu);
+		cpu_fp_take(vcpu->cpu, vcpu);
+	}
+}
+
+void sync_current_exception(uintreg_t elr, uintreg_t spsr)
 {
 	uintreg_t esr = read_msr(esr_el2);
 
 	(void)spsr;
 
 	switch (esr >> 26) {
+	case 0x07: /* EC = 000111, Access to SIMD or floating-point registers. */
+		/*
+		 * The hypervisor doesn't use the registers itself, but if it
+		 * does then it accesses those of the current vCPU, as it would
+		 * if they weren't switched lazily.
+		 */
+		fp_take(current());
+		return;
+
 	case 0x25: /* EC = 100101, Data abort. */
 		dlog("Data abort: pc=0x%x, esr=0x%x, ec=0x%x", elr, esr,
 		     esr >> 26);
@@ -231,91 +310,41 @@ static void set_virtual_interrupt_current(bool enable)
 	write_msr(hcr_el2, hcr_el2);
 }
 
+/**
+ * Services the requests other CPUs sent to the current one, before the vCPU
+ * `next` is switched to, or the current vCPU resumed if it is NULL. The current
+ * vCPU is preempted rather than resumed if a reschedule was requested and it
+ * belongs to a secondary VM.
+ */
+static struct vcpu *ipi_handle(struct vcpu *next)
+{
+	struct vcpu *vcpu = current();
+
+	if (!(cpu_ipi_handle(vcpu->cpu) & CPU_IPI_RESCHEDULE) ||
+	    next != NULL || vcpu->vm->id == HF_PRIMARY_VM_ID) {
+		return next;
+	}
+
+	return api_preempt(vcpu);
+}
+
 struct hvc_handler_return hvc_handler(uintreg_t arg0, uintreg_t arg1,
 				      uintreg_t arg2, uintreg_t arg3)
 {
 	struct hvc_handler_return ret;
 
 	ret.new = NULL;
+	current()->stats.hypercall_exits++;
 
 	if (psci_handler(current(), arg0, arg1, arg2, arg3, &ret.user_ret,
 			 &ret.new)) {
+		ret.new = ipi_handle(ret.new);
 		return ret;
 	}
 
-	switch ((uint32_t)arg0) {
-	case SPCI_VERSION_32:
-		ret.user_ret = api_spci_version();
-		break;
-
-	case HF_VM_GET_ID:
-		ret.user_ret = api_vm_get_id(current());
-		break;
-
-	case HF_VM_GET_COUNT:
-		ret.user_ret = api_vm_get_count();
-		break;
-
-	case HF_VCPU_GET_COUNT:
-		ret.user_ret = api_vcpu_get_count(arg1, current());
-		break;
-
-	case HF_VCPU_RUN:
-		ret.user_ret = hf_vcpu_run_return_encode(
-			api_vcpu_run(arg1, arg2, current(), &ret.new));
-		break;
-
-	case SPCI_YIELD_32:
-		ret.user_ret = api_spci_yield(current(), &ret.new);
-		break;
-
-	case HF_VM_CONFIGURE:
-		ret.user_ret = api_vm_configure(ipa_init(arg1), ipa_init(arg2),
-						current(), &ret.new);
-		break;
-
-	case SPCI_MSG_SEND_32:
-		ret.user_ret = api_spci_msg_send(arg1, current(), &ret.new);
-		break;
-
-	case SPCI_MSG_RECV_32:
-		ret.user_ret = api_spci_msg_recv(arg1, current(), &ret.new);
-		break;
-
-	case HF_MAILBOX_CLEAR:
-		ret.user_ret = api_mailbox_clear(current(), &ret.new);
-		break;
-
-	case HF_MAILBOX_WRITABLE_GET:
-		ret.user_ret = api_mailbox_writable_get(current());
-		break;
-
-	case HF_MAILBOX_WAITER_GET:
-		ret.user_ret = api_mailbox_waiter_get(arg1, current());
-		break;
-
-	case HF_INTERRUPT_ENABLE:
-		ret.user_ret = api_interrupt_enable(arg1, arg2, current());
-		break;
-
-	case HF_INTERRUPT_GET:
-		ret.user_ret = api_interrupt_get(current());
-		break;
-
-	case HF_INTERRUPT_INJECT:
-		ret.user_ret = api_interrupt_inject(arg1, arg2, arg3, current(),
-						    &ret.new);
-		break;
-
-	case HF_SHARE_MEMORY:
-		ret.user_ret =
-			api_share_memory(arg1 >> 32, ipa_init(arg2), arg3,
-					 arg1 & 0xffffffff, current());
-		break;
-
-	default:
-		ret.user_ret = -1;
-	}
+	ret.user_ret =
+		hypercall_handler(current(), arg0, arg1, arg2, arg3, &ret.new);
+	ret.new = ipi_handle(ret.new);
 
 	/* Set or clear VI bit. */
 	if (ret.new == NULL) {
@@ -349,6 +378,8 @@ struct vcpu *irq_lower(void)
 	 *
 	 * TODO: Only switch when the interrupt isn't for the current VM.
 	 */
+	current()->stats.interrupt_exits++;
+	cpu_ipi_handle(current()->cpu);
 	return api_preempt(current());
 }
 
@@ -394,7 +425,7 @@ static struct vcpu_fault_info fault_info_init(uintreg_t esr,
 	return r;
 }
 
-struct vcpu *sync_lower_exception(uintreg_t esr)
+static struct vcpu *sync_lower_exception_handle(uintreg_t esr)
 {
 	struct vcpu *vcpu = current();
 	struct vcpu_fault_info info;
@@ -402,6 +433,7 @@ struct vcpu *sync_lower_exception(uintreg_t esr)
 
 	switch (esr >> 26) {
 	case 0x01: /* EC = 000001, WFI or WFE. */
+		vcpu->stats.wfi_exits++;
 		/* Skip the instruction. */
 		vcpu->regs.pc += (esr & (1u << 25)) ? 4 : 2;
 		/* Check TI bit of ISS, 0 = WFI, 1 = WFE. */
@@ -417,7 +449,13 @@ struct vcpu *sync_lower_exception(uintreg_t esr)
 		/* WFI */
 		return api_wait_for_interrupt(vcpu);
 
+	case 0x07: /* EC = 000111, Access to SIMD or floating-point registers. */
+		/* Retry the access once the registers are loaded. */
+		fp_take(vcpu);
+		return NULL;
+
 	case 0x24: /* EC = 100100, Data abort. */
+		vcpu->stats.page_fault_exits++;
 		info = fault_info_init(
 			esr, vcpu, (esr & (1u << 6)) ? MM_MODE_W : MM_MODE_R);
 		if (vcpu_handle_page_fault(vcpu, &info)) {
@@ -426,6 +464,7 @@ struct vcpu *sync_lower_exception(uintreg_t esr)
 		break;
 
 	case 0x20: /* EC = 100000, Instruction abort. */
+		vcpu->stats.page_fault_exits++;
 		info = fault_info_init(esr, vcpu, MM_MODE_X);
 		if (vcpu_handle_page_fault(vcpu, &info)) {
 			return NULL;
@@ -436,16 +475,50 @@ struct vcpu *sync_lower_exception(uintreg_t esr)
 		uintreg_t smc_pc = vcpu->regs.pc;
 		uintreg_t ret;
 		struct vcpu *next = NULL;
+		struct ffa_value ffa_args = {
+			.func = vcpu->regs.r[0],
+			.arg1 = vcpu->regs.r[1],
+			.arg2 = vcpu->regs.r[2],
+			.arg3 = vcpu->regs.r[3],
+			.arg4 = vcpu->regs.r[4],
+			.arg5 = vcpu->regs.r[5],
+			.arg6 = vcpu->regs.r[6],
+			.arg7 = vcpu->regs.r[7],
+		};
+		struct ffa_value ffa_ret;
+
+		vcpu->stats.hypercall_exits++;
 
-		if (!psci_handler(vcpu, vcpu->regs.r[0], vcpu->regs.r[1],
-				  vcpu->regs.r[2], vcpu->regs.r[3], &ret,
-				  &next)) {
+		/* Skip the SMC instruction. */
+		vcpu->regs.pc = smc_pc + (esr & (1u << 25) ? 4 : 2);
+
+		if (ffa_handler(vcpu, &ffa_args, &ffa_ret, &next)) {
+			vcpu->regs.r[0] = ffa_ret.func;
+			vcpu->regs.r[1] = ffa_ret.arg1;
+			vcpu->regs.r[2] = ffa_ret.arg2;
+			vcpu->regs.r[3] = ffa_ret.arg3;
+			vcpu->regs.r[4] = ffa_ret.arg4;
+			vcpu->regs.r[5] = ffa_ret.arg5;
+			vcpu->regs.r[6] = ffa_ret.arg6;
+			vcpu->regs.r[7] = ffa_ret.arg7;
+			return next;
+		}
+
+		if (psci_handler(vcpu, vcpu->regs.r[0], vcpu->regs.r[1],
+				 vcpu->regs.r[2], vcpu->regs.r[3], &ret,
+				 &next)) {
+			/* Handled by Hafnium. */
+		} else if ((vcpu->vm->capabilities &
+			    HF_CAPABILITY_SMC_FORWARDING) &&
+			   smc_is_allowed(vcpu->vm->id, vcpu->regs.r[0])) {
+			/* Forward the call to EL3. */
+			ret = smc(vcpu->regs.r[0], vcpu->regs.r[1],
+				  vcpu->regs.r[2], vcpu->regs.r[3]);
+		} else {
 			dlog("Unsupported SMC call: 0x%x\n", vcpu->regs.r[0]);
 			ret = PSCI_ERROR_NOT_SUPPORTED;
 		}
 
-		/* Skip the SMC instruction. */
-		vcpu->regs.pc = smc_pc + (esr & (1u << 25) ? 4 : 2);
 		vcpu->regs.r[0] = ret;
 		return next;
 	}
@@ -460,3 +533,8 @@ struct vcpu *sync_lower_exception(uintreg_t esr)
 	/* The exception wasn't handled so abort the VM. */
 	return api_abort(vcpu);
 }
+
+struct vcpu *sync_lower_exception(uintreg_t esr)
+{
+	return ipi_handle(sync_lower_exception_handle(esr));
+}
@@ -143,13 +143,7 @@ bool psci_primary_vm_handler(struct vcpu *vcpu, uint32_t func, uintreg_t arg0,
 			break;
 		}
 
-		sl_lock(&c->lock);
-		if (c->is_on) {
-			*ret = PSCI_RETURN_ON;
-		} else {
-			*ret = PSCI_RETURN_OFF;
-		}
-		sl_unlock(&c->lock);
+		*ret = cpu_is_on(c) ? PSCI_RETURN_ON : PSCI_RETURN_OFF;
 		break;
 
 	case PSCI_CPU_SUSPEND: {
@@ -223,162 +217,6 @@ bool psci_primary_vm_handler(struct vcpu *vcpu, uint32_t func, uintreg_t arg0,
 	return true;
 }
 
-/**
- * Convert a PSCI CPU / affinity ID for a secondary VM to the corresponding vCPU
- * index.
- */
-uint32_t vcpu_id_to_index(uint64_t vcpu_id)
-{
-	/* For now we use indices as IDs for the purposes of PSCI. */
-	return vcpu_id;
-}
-
-/**
- * Handles PSCI requests received via HVC or SMC instructions from a secondary
- * VM.
- *
- * A minimal PSCI 1.1 interface is offered which can start and stop vCPUs in
- * collaboration with the scheduler in the primary VM.
- *
- * Returns true if the request was a PSCI one, false otherwise.
- */
-bool psci_secondary_vm_handler(struct vcpu *vcpu, uint32_t func, uintreg_t arg0,
-			       uintreg_t arg1, uintreg_t arg2, uintreg_t *ret,
-			       struct vcpu **next)
-{
-	switch (func & ~SMCCC_CONVENTION_MASK) {
-	case PSCI_VERSION:
-		*ret = PSCI_VERSION_1_1;
-		break;
-
-	case PSCI_FEATURES:
-		switch (arg0 & ~SMCCC_CONVENTION_MASK) {
-		case PSCI_CPU_SUSPEND:
-			/*
-			 * Does not offer OS-initiated mode but does use
-			 * extended StateID Format.
-			 */
-			*ret = 0x2;
-			break;
-
-		case PSCI_VERSION:
-		case PSCI_FEATURES:
-		case PSCI_AFFINITY_INFO:
-		case PSCI_CPU_OFF:
-		case PSCI_CPU_ON:
-			/* These are supported without special features. */
-			*ret = 0;
-			break;
-
-		default:
-			/* Everything else is unsupported. */
-			*ret = PSCI_ERROR_NOT_SUPPORTED;
-			break;
-		}
-		break;
-
-	case PSCI_AFFINITY_INFO: {
-		uint64_t target_affinity = arg0;
-		uint32_t lowest_affinity_level = arg1;
-		struct vm *vm = vcpu->vm;
-		struct vcpu_locked target_vcpu;
-		uint32_t target_vcpu_index = vcpu_id_to_index(target_affinity);
-
-		if (lowest_affinity_level != 0) {
-			/* Affinity levels greater than 0 not supported. */
-			*ret = PSCI_ERROR_INVALID_PARAMETERS;
-			break;
-		}
-
-		if (target_vcpu_index >= vm->vcpu_count) {
-			*ret = PSCI_ERROR_INVALID_PARAMETERS;
-			break;
-		}
-
-		target_vcpu = vcpu_lock(vm_get_vcpu(vm, target_vcpu_index));
-		*ret = vcpu_is_off(target_vcpu) ? PSCI_RETURN_OFF
-						: PSCI_RETURN_ON;
-		vcpu_unlock(&target_vcpu);
-		break;
-	}
-
-	case PSCI_CPU_SUSPEND: {
-		/*
-		 * Downgrade suspend request to WFI and return SUCCESS, as
-		 * allowed by the specification.
-		 */
-		*next = api_wait_for_interrupt(vcpu);
-		*ret = PSCI_RETURN_SUCCESS;
-		break;
-	}
-
-	case PSCI_CPU_OFF:
-		/*
-		 * Should never return to the caller, but in case it somehow
-		 * does.
-		 */
-		*ret = PSCI_ERROR_DENIED;
-		/* Tell the scheduler not to run the vCPU again. */
-		*next = api_vcpu_off(vcpu);
-		break;
-
-	case PSCI_CPU_ON: {
-		/* Parameter names as per PSCI specification. */
-		uint64_t target_cpu = arg0;
-		ipaddr_t entry_point_address = ipa_init(arg1);
-		uint64_t context_id = arg2;
-		uint32_t target_vcpu_index = vcpu_id_to_index(target_cpu);
-		struct vm *vm = vcpu->vm;
-		struct vcpu *target_vcpu;
-
-		if (target_vcpu_index >= vm->vcpu_count) {
-			*ret = PSCI_ERROR_INVALID_PARAMETERS;
-			break;
-		}
-
-		target_vcpu = vm_get_vcpu(vm, target_vcpu_index);
-
-		if (vcpu_secondary_reset_and_start(
-			    target_vcpu, entry_point_address, context_id)) {
-			/*
-			 * Tell the scheduler that it can start running the new
-			 * vCPU now.
-			 */
-			*next = api_wake_up(vcpu, target_vcpu);
-			*ret = PSCI_RETURN_SUCCESS;
-		} else {
-			*ret = PSCI_ERROR_ALREADY_ON;
-		}
-
-		break;
-	}
-
-	case PSCI_SYSTEM_OFF:
-	case PSCI_SYSTEM_RESET:
-	case PSCI_MIGRATE:
-	case PSCI_MIGRATE_INFO_TYPE:
-	case PSCI_MIGRATE_INFO_UP_CPU:
-	case PSCI_CPU_FREEZE:
-	case PSCI_CPU_DEFAULT_SUSPEND:
-	case PSCI_NODE_HW_STATE:
-	case PSCI_SYSTEM_SUSPEND:
-	case PSCI_SET_SYSPEND_MODE:
-	case PSCI_STAT_RESIDENCY:
-	case PSCI_STAT_COUNT:
-	case PSCI_SYSTEM_RESET2:
-	case PSCI_MEM_PROTECT:
-	case PSCI_MEM_PROTECT_CHECK_RANGE:
-		/* Block all other known PSCI calls. */
-		*ret = PSCI_ERROR_NOT_SUPPORTED;
-		break;
-
-	default:
-		return false;
-	}
-
-	return true;
-}
-
 /**
  * Handles PSCI requests received via HVC or SMC instructions from a VM.
  * Requests from primary and secondary VMs are dealt with differently.
@@ -22,6 +22,17 @@
 
 #include "hf/cpu.h"
 
+/**
+ * Handles PSCI requests received via HVC or SMC instructions from a secondary
+ * VM, implemented in Rust. It can start and stop vCPUs in collaboration with
+ * the scheduler in the primary VM.
+ *
+ * Returns true if the request was a PSCI one, false otherwise.
+ */
+bool psci_secondary_vm_handler(struct vcpu *vcpu, uint32_t func, uintreg_t arg0,
+			       uintreg_t arg1, uintreg_t arg2, uintreg_t *ret,
+			       struct vcpu **next);
+
 bool psci_handler(struct vcpu *vcpu, uint32_t func, uintreg_t arg0,
 		  uintreg_t arg1, uintreg_t arg2, uintreg_t *ret,
 		  struct vcpu **next);
@@ -51,6 +51,8 @@
 
 #define STAGE1_DEVICEINDX UINT64_C(0)
 #define STAGE1_NORMALINDX UINT64_C(1)
+#define STAGE1_NCINDX     UINT64_C(2)
+#define STAGE1_WCINDX     UINT64_C(3)
 
 #define STAGE2_XN(x)      ((x) << 53)
 #define STAGE2_CONTIGUOUS (UINT64_C(1) << 52)
@@ -76,6 +78,8 @@
 /* The following are stage-2 software defined attributes. */
 #define STAGE2_SW_OWNED     (UINT64_C(1) << 55)
 #define STAGE2_SW_EXCLUSIVE (UINT64_C(1) << 56)
+#define STAGE2_SW_WC        (UINT64_C(1) << 57)
+#define STAGE2_SW_HYP       (UINT64_C(1) << 58)
 
 /* The following are stage-2 memory attributes for normal memory. */
 #define STAGE2_NONCACHEABLE UINT64_C(1)
@@ -93,12 +97,43 @@
 #define PTE_ADDR_MASK \
 	(((UINT64_C(1) << 48) - 1) & ~((UINT64_C(1) << PAGE_BITS) - 1))
 
+/**
+ * Masks for the address bits of the pte with LPA2, where bits [51:50] of the
+ * address are stored in bits [9:8] of the pte in place of the shareability
+ * field.
+ */
+#define PTE_ADDR_MASK_LPA2 \
+	(((UINT64_C(1) << 50) - 1) & ~((UINT64_C(1) << PAGE_BITS) - 1))
+#define PTE_ADDR_HIGH_MASK_LPA2 (UINT64_C(3) << 8)
+#define PTE_ADDR_HIGH_SHIFT_LPA2 (50 - 8)
+
 /** Mask for the attribute bits of the pte. */
 #define PTE_ATTR_MASK (~(PTE_ADDR_MASK | (UINT64_C(1) << 1)))
 
+/** Mask for the attribute bits of the pte with LPA2. */
+#define PTE_ATTR_MASK_LPA2                                             \
+	(~(PTE_ADDR_MASK_LPA2 | PTE_ADDR_HIGH_MASK_LPA2 |              \
+	   (UINT64_C(1) << 1)))
+
+static uint8_t mm_pa_bits;
+static bool mm_lpa2;
 static uint8_t mm_s2_max_level;
 static uint8_t mm_s2_root_table_count;
 
+/**
+ * Encodes the given page-aligned physical address in the address bits of a
+ * pte.
+ */
+static uint64_t pte_from_addr(uint64_t addr)
+{
+	if (!mm_lpa2) {
+		return addr;
+	}
+
+	return (addr & PTE_ADDR_MASK_LPA2) |
+	       ((addr >> PTE_ADDR_HIGH_SHIFT_LPA2) & PTE_ADDR_HIGH_MASK_LPA2);
+}
+
 /**
  * Returns the encoding of a page table entry that isn't present.
  */
@@ -118,7 +153,7 @@ pte_t arch_mm_table_pte(uint8_t level, paddr_t pa)
 {
 	/* This is the same for all levels on aarch64. */
 	(void)level;
-	return pa_addr(pa) | PTE_TABLE | PTE_VALID;
+	return pte_from_addr(pa_addr(pa)) | PTE_TABLE | PTE_VALID;
 }
 
 /**
@@ -128,7 +163,7 @@ pte_t arch_mm_table_pte(uint8_t level, paddr_t pa)
  */
 pte_t arch_mm_block_pte(uint8_t level, paddr_t pa, uint64_t attrs)
 {
-	pte_t pte = pa_addr(pa) | attrs;
+	pte_t pte = pte_from_addr(pa_addr(pa)) | attrs;
 
 	if (level == 0) {
 		/* A level 0 'block' is actually a page entry. */
@@ -189,7 +224,12 @@ bool arch_mm_pte_is_table(pte_t pte, uint8_t level)
 
 static uint64_t pte_addr(pte_t pte)
 {
-	return pte & PTE_ADDR_MASK;
+	if (!mm_lpa2) {
+		return pte & PTE_ADDR_MASK;
+	}
+
+	return (pte & PTE_ADDR_MASK_LPA2) |
+	       ((pte & PTE_ADDR_HIGH_MASK_LPA2) << PTE_ADDR_HIGH_SHIFT_LPA2);
 }
 
 /**
@@ -198,7 +238,10 @@ static uint64_t pte_addr(pte_t pte)
  */
 paddr_t arch_mm_clear_pa(paddr_t pa)
 {
-	return pa_init(pte_addr(pa_addr(pa)));
+	uint8_t bits = mm_lpa2 ? 52 : 48;
+
+	return pa_init(pa_addr(pa) & ((UINT64_C(1) << bits) - 1) &
+		       ~((UINT64_C(1) << PAGE_BITS) - 1));
 }
 
 /**
@@ -228,7 +271,28 @@ paddr_t arch_mm_table_from_pte(pte_t pte, uint8_t level)
 uint64_t arch_mm_pte_attrs(pte_t pte, uint8_t level)
 {
 	(void)level;
-	return pte & PTE_ATTR_MASK;
+	return pte & (mm_lpa2 ? PTE_ATTR_MASK_LPA2 : PTE_ATTR_MASK);
+}
+
+/**
+ * Determines if the given block attributes have the access flag set. The flag is
+ * at the same position for stage-1 and stage-2.
+ */
+bool arch_mm_attrs_accessed(uint64_t attrs)
+{
+	return (attrs & STAGE2_AF) != 0;
+}
+
+/**
+ * Sets or clears the access flag of the given block attributes.
+ */
+uint64_t arch_mm_attrs_set_accessed(uint64_t attrs, bool accessed)
+{
+	if (accessed) {
+		return attrs | STAGE2_AF;
+	}
+
+	return attrs & ~STAGE2_AF;
 }
 
 /**
@@ -279,6 +343,40 @@ void arch_mm_invalidate_stage2_range(ipaddr_t va_begin, ipaddr_t va_end)
 		"dsb ish\n");
 }
 
+/**
+ * Invalidates all stage-1 TLB entries of EL2.
+ */
+void arch_mm_invalidate_stage1_all(void)
+{
+	__asm__ volatile(
+		"dsb ishst\n"
+		"tlbi alle2is\n"
+		"dsb ish\n");
+}
+
+/**
+ * Invalidates all stage-1 and stage-2 TLB entries of the current VMID.
+ */
+void arch_mm_invalidate_stage2_all(void)
+{
+	/* TODO: This only applies to the current VMID. */
+	__asm__ volatile(
+		"dsb ishst\n"
+		"tlbi vmalls12e1is\n"
+		"dsb ish\n");
+}
+
+/**
+ * Invalidates all stage-1 and stage-2 TLB entries of EL1, for all VMIDs.
+ */
+void arch_mm_invalidate_stage2_all_vmids(void)
+{
+	__asm__ volatile(
+		"dsb ishst\n"
+		"tlbi alle1is\n"
+		"dsb ish\n");
+}
+
 /**
  * Ensures that the range of data in the cache is written back so that it is
  * visible to all cores in the system.
@@ -302,7 +400,12 @@ uint64_t arch_mm_mode_to_stage1_attrs(int mode)
 {
 	uint64_t attrs = 0;
 
-	attrs |= STAGE1_AF | STAGE1_SH(OUTER_SHAREABLE);
+	attrs |= STAGE1_AF;
+
+	/* With LPA2, the shareability is set in tcr_el2 instead. */
+	if (!mm_lpa2) {
+		attrs |= STAGE1_SH(OUTER_SHAREABLE);
+	}
 
 	/* Define the execute bits. */
 	if (!(mode & MM_MODE_X)) {
@@ -319,6 +422,10 @@ uint64_t arch_mm_mode_to_stage1_attrs(int mode)
 	/* Define the memory attribute bits. */
 	if (mode & MM_MODE_D) {
 		attrs |= STAGE1_ATTRINDX(STAGE1_DEVICEINDX);
+	} else if (mode & MM_MODE_NC) {
+		attrs |= STAGE1_ATTRINDX(STAGE1_NCINDX);
+	} else if (mode & MM_MODE_WC) {
+		attrs |= STAGE1_ATTRINDX(STAGE1_WCINDX);
 	} else {
 		attrs |= STAGE1_ATTRINDX(STAGE1_NORMALINDX);
 	}
@@ -362,11 +469,22 @@ uint64_t arch_mm_mode_to_stage2_attrs(int mode)
 	}
 
 	/*
-	 * Define the memory attribute bits, using the "neutral" values
-	 * which give the stage-1 attributes full control of the
-	 * attributes.
+	 * Define the memory attribute bits. Non-cacheable and write-combining
+	 * memory is normal non-cacheable memory, which allows gathering writes,
+	 * so the two are only distinguished by a software bit. Otherwise, use
+	 * the "neutral" values which give the stage-1 attributes full control
+	 * of the attributes.
 	 */
-	attrs |= STAGE2_MEMATTR_NORMAL(STAGE2_WRITEBACK, STAGE2_WRITEBACK);
+	if (mode & (MM_MODE_NC | MM_MODE_WC)) {
+		attrs |= STAGE2_MEMATTR_NORMAL(STAGE2_NONCACHEABLE,
+					       STAGE2_NONCACHEABLE);
+		if (!(mode & MM_MODE_NC)) {
+			attrs |= STAGE2_SW_WC;
+		}
+	} else {
+		attrs |= STAGE2_MEMATTR_NORMAL(STAGE2_WRITEBACK,
+					       STAGE2_WRITEBACK);
+	}
 
 	/* Define the ownership bit. */
 	if (!(mode & MM_MODE_UNOWNED)) {
@@ -378,6 +496,11 @@ uint64_t arch_mm_mode_to_stage2_attrs(int mode)
 		attrs |= STAGE2_SW_EXCLUSIVE;
 	}
 
+	/* Define the hypervisor ownership bit. */
+	if (mode & MM_MODE_HYP) {
+		attrs |= STAGE2_SW_HYP;
+	}
+
 	/* Define the valid bit. */
 	if (!(mode & MM_MODE_INVALID)) {
 		attrs |= PTE_VALID;
@@ -386,6 +509,41 @@ uint64_t arch_mm_mode_to_stage2_attrs(int mode)
 	return attrs;
 }
 
+int arch_mm_stage1_attrs_to_mode(uint64_t attrs)
+{
+	int mode = 0;
+
+	/* Memory in stage-1 is either valid or invalid. */
+	if (!(attrs & PTE_VALID)) {
+		return MM_MODE_INVALID;
+	}
+
+	/* Valid memory is always readable by the hypervisor. */
+	mode |= MM_MODE_R;
+
+	if ((attrs & STAGE1_AP(STAGE1_READONLY)) == 0) {
+		mode |= MM_MODE_W;
+	}
+
+	if (!(attrs & STAGE1_XN)) {
+		mode |= MM_MODE_X;
+	}
+
+	switch (attrs & STAGE1_ATTRINDX(UINT64_C(7))) {
+	case STAGE1_ATTRINDX(STAGE1_DEVICEINDX):
+		mode |= MM_MODE_D;
+		break;
+	case STAGE1_ATTRINDX(STAGE1_NCINDX):
+		mode |= MM_MODE_NC;
+		break;
+	case STAGE1_ATTRINDX(STAGE1_WCINDX):
+		mode |= MM_MODE_WC;
+		break;
+	}
+
+	return mode;
+}
+
 int arch_mm_stage2_attrs_to_mode(uint64_t attrs)
 {
 	int mode = 0;
@@ -403,6 +561,11 @@ int arch_mm_stage2_attrs_to_mode(uint64_t attrs)
 		mode |= MM_MODE_X;
 	}
 
+	if ((attrs & STAGE2_MEMATTR(UINT64_C(0xf))) ==
+	    STAGE2_MEMATTR_NORMAL(STAGE2_NONCACHEABLE, STAGE2_NONCACHEABLE)) {
+		mode |= (attrs & STAGE2_SW_WC) ? MM_MODE_WC : MM_MODE_NC;
+	}
+
 	if (!(attrs & STAGE2_SW_OWNED)) {
 		mode |= MM_MODE_UNOWNED;
 	}
@@ -411,6 +574,10 @@ int arch_mm_stage2_attrs_to_mode(uint64_t attrs)
 		mode |= MM_MODE_SHARED;
 	}
 
+	if (attrs & STAGE2_SW_HYP) {
+		mode |= MM_MODE_HYP;
+	}
+
 	if (!(attrs & PTE_VALID)) {
 		mode |= MM_MODE_INVALID;
 	}
@@ -418,6 +585,17 @@ int arch_mm_stage2_attrs_to_mode(uint64_t attrs)
 	return mode;
 }
 
+uint8_t arch_mm_stage1_input_bits(void)
+{
+	/* See T0SZ of tcr_el2. */
+	return 39;
+}
+
+uint8_t arch_mm_stage2_input_bits(void)
+{
+	return mm_pa_bits;
+}
+
 uint8_t arch_mm_stage1_max_level(void)
 {
 	/*
@@ -446,15 +624,17 @@ uint8_t arch_mm_stage2_root_table_count(void)
 
 bool arch_mm_init(paddr_t table, bool first)
 {
-	static const int pa_bits_table[16] = {32, 36, 40, 42, 44, 48};
+	static const int pa_bits_table[16] = {32, 36, 40, 42, 44, 48, 52};
 	uint64_t features = read_msr(id_aa64mmfr0_el1);
+	uint64_t tgran4 = (features >> 28) & 0xf;
+	uint64_t pa_range = features & 0xf;
 	uint64_t v;
-	int pa_bits = pa_bits_table[features & 0xf];
+	int pa_bits = pa_bits_table[pa_range];
 	int extend_bits;
 	int sl0;
 
 	/* Check that 4KB granules are supported. */
-	if ((features >> 28) & 0xf) {
+	if (tgran4 == 0xf) {
 		dlog("4KB granules are not supported\n");
 		return false;
 	}
@@ -462,10 +642,21 @@ bool arch_mm_init(paddr_t table, bool first)
 	/* Check the physical address range. */
 	if (!pa_bits) {
 		dlog("Unsupported value of id_aa64mmfr0_el1.PARange: %x\n",
-		     features & 0xf);
+		     pa_range);
 		return false;
 	}
 
+	/*
+	 * 52-bit addresses with 4KB granules need LPA2. Without it, only the
+	 * low 48 bits are used.
+	 */
+	if (pa_bits == 52 && tgran4 != 1) {
+		pa_bits = 48;
+		pa_range = 5;
+	}
+	mm_pa_bits = pa_bits;
+	mm_lpa2 = pa_bits == 52;
+
 	if (first) {
 		dlog("Supported bits in physical address: %d\n", pa_bits);
 	}
@@ -508,8 +699,9 @@ bool arch_mm_init(paddr_t table, bool first)
 		     mm_s2_max_level + 1, mm_s2_root_table_count);
 	}
 
-	v = (1u << 31) |	       /* RES1. */
-	    ((features & 0xf) << 16) | /* PS, matching features. */
+	v = (mm_lpa2 ? UINT64_C(1) << 32 : 0) | /* DS: LPA2. */
+	    (1u << 31) |	       /* RES1. */
+	    (pa_range << 16) |	       /* PS, matching features. */
 	    (0 << 14) |		       /* TG0: 4 KB granule. */
 	    (3 << 12) |		       /* SH0: inner shareable. */
 	    (1 << 10) |		       /* ORGN0: normal, cacheable ... */
@@ -522,17 +714,22 @@ bool arch_mm_init(paddr_t table, bool first)
 	 * 0    -> Device-nGnRnE memory
 	 * 0xff -> Normal memory, Inner/Outer Write-Back Non-transient,
 	 *         Write-Alloc, Read-Alloc.
+	 * 0x44 -> Normal memory, Inner/Outer Non-Cacheable, for both
+	 *         non-cacheable and write-combining memory.
 	 */
 	write_msr(mair_el2, (0 << (8 * STAGE1_DEVICEINDX)) |
-				    (0xff << (8 * STAGE1_NORMALINDX)));
+				    (0xff << (8 * STAGE1_NORMALINDX)) |
+				    (0x44 << (8 * STAGE1_NCINDX)) |
+				    (0x44 << (8 * STAGE1_WCINDX)));
 
 	write_msr(ttbr0_el2, pa_addr(table));
 
 	/*
 	 * Configure tcr_el2.
 	 */
-	v = (1 << 20) |		       /* TBI, top byte ignored. */
-	    ((features & 0xf) << 16) | /* PS. */
+	v = (mm_lpa2 ? UINT64_C(1) << 32 : 0) | /* DS: LPA2. */
+	    (1 << 20) |		       /* TBI, top byte ignored. */
+	    (pa_range << 16) |	       /* PS. */
 	    (0 << 14) |		       /* TG0, granule size, 4KB. */
 	    (3 << 12) |		       /* SH0, inner shareable. */
 	    (1 << 10) | /* ORGN0, normal mem, WB RA WA Cacheable. */
@@ -31,6 +31,7 @@
 #define CNTV_CTL_EL0_ISTATUS (1u << 2)
 
 #define NANOS_PER_UNIT 1000000000
+#define MICROS_PER_UNIT 1000000
 
 /**
  * Sets the bit to mask virtual timer interrupts.
@@ -51,14 +52,35 @@ bool arch_timer_enabled(struct arch_regs *regs)
 	       !(cntv_ctl_el0 & CNTV_CTL_EL0_IMASK);
 }
 
+/**
+ * Returns the current value of the virtual counter, in timer ticks.
+ */
+uint64_t arch_timer_now_ticks(void)
+{
+	return read_msr(cntvct_el0);
+}
+
 /**
  * Converts a number of timer ticks to the equivalent number of nanoseconds.
  */
-static uint64_t ticks_to_ns(uint64_t ticks)
+uint64_t arch_timer_ticks_to_ns(uint64_t ticks)
 {
 	return (ticks * NANOS_PER_UNIT) / read_msr(cntfrq_el0);
 }
 
+/**
+ * Converts a number of timer ticks to the equivalent number of microseconds,
+ * e.g. to timestamp the log. The whole seconds are converted apart from the
+ * rest so that the multiplication doesn't overflow.
+ */
+uint64_t arch_timer_ticks_to_us(uint64_t ticks)
+{
+	uint64_t freq = read_msr(cntfrq_el0);
+
+	return (ticks / freq) * MICROS_PER_UNIT +
+	       ((ticks % freq) * MICROS_PER_UNIT) / freq;
+}
+
 /**
  * Returns the number of ticks remaining on the virtual timer as stored in
  * the given `arch_regs`, or 0 if it has already expired. This is undefined if
@@ -87,7 +109,7 @@ uint64_t arch_timer_remaining_ticks(struct arch_regs *regs)
  */
 uint64_t arch_timer_remaining_ns(struct arch_regs *regs)
 {
-	return ticks_to_ns(arch_timer_remaining_ticks(regs));
+	return arch_timer_ticks_to_ns(arch_timer_remaining_ticks(regs));
 }
 
 /**
@@ -160,5 +182,5 @@ uint64_t arch_timer_remaining_ticks_current(void)
  */
 uint64_t arch_timer_remaining_ns_current(void)
 {
-	return ticks_to_ns(arch_timer_remaining_ticks_current());
+	return arch_timer_ticks_to_ns(arch_timer_remaining_ticks_current());
 }
@@ -16,6 +16,8 @@
 
 #include "hf/arch/cpu.h"
 
+#include "hf/std.h"
+
 void arch_irq_disable(void)
 {
 	/* TODO */
@@ -26,12 +28,30 @@ void arch_irq_enable(void)
 	/* TODO */
 }
 
-void arch_regs_reset(struct arch_regs *r, bool is_primary, spci_vm_id_t vm_id,
+uintreg_t arch_irq_save(void)
+{
+	/* TODO */
+	return 0;
+}
+
+void arch_irq_restore(uintreg_t state)
+{
+	/* TODO */
+	(void)state;
+}
+
+uint64_t arch_cpu_id(void)
+{
+	/* TODO */
+	return 0;
+}
+
+void arch_regs_reset(struct arch_regs *r, bool is_primary, uint16_t vmid,
 		     uint64_t vcpu_id, paddr_t table)
 {
 	/* TODO */
 	(void)is_primary;
-	(void)vm_id;
+	(void)vmid;
 	(void)table;
 	r->vcpu_id = vcpu_id;
 }
@@ -46,3 +66,13 @@ void arch_regs_set_retval(struct arch_regs *r, uintreg_t v)
 {
 	r->r[0] = v;
 }
+
+void arch_regs_set_retvals(struct arch_regs *r, const uintreg_t *v,
+			   size_t count)
+{
+	size_t i;
+
+	for (i = 0; i < count && i < ARRAY_SIZE(r->r); ++i) {
+		r->r[i] = v[i];
+	}
+}
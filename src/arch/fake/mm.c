@@ -25,7 +25,8 @@
 #define PTE_ATTR_MODE_SHIFT 48
 #define PTE_ATTR_MODE_MASK                                              \
 	((uint64_t)(MM_MODE_R | MM_MODE_W | MM_MODE_X | MM_MODE_D |     \
-		    MM_MODE_INVALID | MM_MODE_UNOWNED | MM_MODE_SHARED) \
+		    MM_MODE_NC | MM_MODE_WC | MM_MODE_INVALID |         \
+		    MM_MODE_UNOWNED | MM_MODE_SHARED | MM_MODE_HYP)     \
 	 << PTE_ATTR_MODE_SHIFT)
 
 /* The bit to distinguish a table from a block is the highest of the page bits.
@@ -105,6 +106,20 @@ uint64_t arch_mm_pte_attrs(pte_t pte, uint8_t level)
 	return (pte << PTE_LEVEL_SHIFT(level)) & PTE_ATTR_MODE_MASK;
 }
 
+bool arch_mm_attrs_accessed(uint64_t attrs)
+{
+	/* There's no modelling of the access flag. */
+	(void)attrs;
+	return true;
+}
+
+uint64_t arch_mm_attrs_set_accessed(uint64_t attrs, bool accessed)
+{
+	/* There's no modelling of the access flag. */
+	(void)accessed;
+	return attrs;
+}
+
 uint64_t arch_mm_combine_table_entry_attrs(uint64_t table_attrs,
 					   uint64_t block_attrs)
 {
@@ -121,11 +136,38 @@ void arch_mm_invalidate_stage2_range(ipaddr_t va_begin, ipaddr_t va_end)
 	/* There's no modelling of the stage-2 TLB. */
 }
 
+void arch_mm_invalidate_stage1_all(void)
+{
+	/* There's no modelling of the stage-1 TLB. */
+}
+
+void arch_mm_invalidate_stage2_all(void)
+{
+	/* There's no modelling of the stage-2 TLB. */
+}
+
+void arch_mm_invalidate_stage2_all_vmids(void)
+{
+	/* There's no modelling of the stage-2 TLB. */
+}
+
 void arch_mm_write_back_dcache(void *base, size_t size)
 {
 	/* There's no modelling of the cache. */
 }
 
+uint8_t arch_mm_stage1_input_bits(void)
+{
+	/* Three full levels. */
+	return 39;
+}
+
+uint8_t arch_mm_stage2_input_bits(void)
+{
+	/* Three full levels with four concatenated tables at the root. */
+	return 41;
+}
+
 uint8_t arch_mm_stage1_max_level(void)
 {
 	return 2;
@@ -160,6 +202,11 @@ uint64_t arch_mm_mode_to_stage2_attrs(int mode)
 	return ((uint64_t)mode << PTE_ATTR_MODE_SHIFT) & PTE_ATTR_MODE_MASK;
 }
 
+int arch_mm_stage1_attrs_to_mode(uint64_t attrs)
+{
+	return attrs >> PTE_ATTR_MODE_SHIFT;
+}
+
 int arch_mm_stage2_attrs_to_mode(uint64_t attrs)
 {
 	return attrs >> PTE_ATTR_MODE_SHIFT;
@@ -48,6 +48,24 @@ uint64_t arch_timer_remaining_ns(struct arch_regs *regs)
 	return 0;
 }
 
+uint64_t arch_timer_now_ticks(void)
+{
+	/* TODO */
+	return 0;
+}
+
+uint64_t arch_timer_ticks_to_ns(uint64_t ticks)
+{
+	/* TODO */
+	return ticks;
+}
+
+uint64_t arch_timer_ticks_to_us(uint64_t ticks)
+{
+	/* TODO */
+	return ticks;
+}
+
 bool arch_timer_enabled_current(void)
 {
 	/* TODO */
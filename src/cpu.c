@@ -49,7 +49,8 @@ static void cpu_init(struct cpu *c)
 	c->irq_disable_count = 1;
 }
 
-void cpu_module_init(const uint64_t *cpu_ids, size_t count)
+void cpu_module_init(const uint64_t *cpu_ids, const uint32_t *cpu_clusters,
+		     const uint32_t *cpu_cores, size_t count)
 {
 	uint32_t i;
 	uint32_t j;
@@ -78,6 +79,8 @@ void cpu_module_init(const uint64_t *cpu_ids, size_t count)
 
 		cpu_init(c);
 		c->id = id;
+		c->cluster = cpu_clusters[i];
+		c->core = cpu_cores[i];
 		c->stack_bottom = &callstacks[i][STACK_SIZE];
 	}
 
@@ -93,6 +96,14 @@ size_t cpu_index(struct cpu *c)
 	return c - cpus;
 }
 
+/**
+ * Returns the number of CPUs, which are the first ones in `cpus`.
+ */
+size_t cpu_get_count(void)
+{
+	return cpu_count;
+}
+
 void cpu_irq_enable(struct cpu *c)
 {
 	c->irq_disable_count--;
@@ -109,41 +120,6 @@ void cpu_irq_disable(struct cpu *c)
 	c->irq_disable_count++;
 }
 
-/**
- * Turns CPU on and returns the previous state.
- */
-bool cpu_on(struct cpu *c, ipaddr_t entry, uintreg_t arg)
-{
-	bool prev;
-
-	sl_lock(&c->lock);
-	prev = c->is_on;
-	c->is_on = true;
-	sl_unlock(&c->lock);
-
-	if (!prev) {
-		struct vm *vm = vm_find(HF_PRIMARY_VM_ID);
-		struct vcpu *vcpu = vm_get_vcpu(vm, cpu_index(c));
-		struct vcpu_locked vcpu_locked;
-
-		vcpu_locked = vcpu_lock(vcpu);
-		vcpu_on(vcpu_locked, entry, arg);
-		vcpu_unlock(&vcpu_locked);
-	}
-
-	return prev;
-}
-
-/**
- * Prepares the CPU for turning itself off.
- */
-void cpu_off(struct cpu *c)
-{
-	sl_lock(&c->lock);
-	c->is_on = false;
-	sl_unlock(&c->lock);
-}
-
 /**
  * Searches for a CPU based on its id.
  */
@@ -191,6 +167,7 @@ void vcpu_init(struct vcpu *vcpu, struct vm *vm)
 	vcpu->regs_available = true;
 	vcpu->vm = vm;
 	vcpu->state = VCPU_STATE_OFF;
+	vcpu->affinity = UINT64_MAX;
 }
 
 /**
@@ -208,6 +185,47 @@ size_t vcpu_index(const struct vcpu *vcpu)
 	return vcpu - vcpu->vm->vcpus;
 }
 
+/**
+ * Returns the vCPU with the given index of the same VM as the given vCPU, or
+ * NULL if the VM has no such vCPU.
+ */
+struct vcpu *vcpu_sibling(struct vcpu *vcpu, uint32_t index)
+{
+	struct vm *vm = vcpu->vm;
+
+	if (index >= vm->vcpu_count) {
+		return NULL;
+	}
+
+	return vm_get_vcpu(vm, index);
+}
+
+/**
+ * Returns the HF_CAPABILITY_* bits of the VM of the given vCPU.
+ */
+uint32_t vcpu_vm_capabilities(const struct vcpu *vcpu)
+{
+	return vcpu->vm->capabilities;
+}
+
+/**
+ * Returns the physical CPU into whose FP/SIMD registers the state of the given
+ * vCPU was last loaded, or NULL if it hasn't been.
+ */
+struct cpu *vcpu_fp_cpu(const struct vcpu *vcpu)
+{
+	return vcpu->fp_cpu;
+}
+
+/**
+ * Records the physical CPU into whose FP/SIMD registers the state of the given
+ * vCPU has been loaded.
+ */
+void vcpu_set_fp_cpu(struct vcpu *vcpu, struct cpu *c)
+{
+	vcpu->fp_cpu = c;
+}
+
 /**
  * Check whether the given vcpu_state is an off state, for the purpose of
  * turning vCPUs on and off. Note that aborted still counts as on in this
@@ -258,8 +276,14 @@ bool vcpu_secondary_reset_and_start(struct vcpu *vcpu, ipaddr_t entry,
 		 * vCPU is defined as the index and does not match the ID of the
 		 * pCPU it is running on.
 		 */
-		arch_regs_reset(&vcpu->regs, false, vm->id, vcpu_index(vcpu),
+		arch_regs_reset(&vcpu->regs, false, vm->vmid, vcpu_index(vcpu),
 				vm->ptable.root);
+
+		/*
+		 * The FP/SIMD state was reset too, so whatever was last loaded
+		 * into a physical CPU's registers is stale.
+		 */
+		vcpu_set_fp_cpu(vcpu, NULL);
 		vcpu_on(vcpu_locked, entry, arg);
 	}
 	vcpu_unlock(&vcpu_locked);
@@ -297,6 +321,9 @@ bool vcpu_handle_page_fault(const struct vcpu *current,
 	resume = mm_vm_get_mode(&vm->ptable, f->ipaddr, ipa_add(f->ipaddr, 1),
 				&mode) &&
 		 (mode & mask) == f->mode;
+	if (!resume) {
+		vm->fault_count++;
+	}
 
 	sl_unlock(&vm->lock);
 
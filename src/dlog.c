@@ -16,11 +16,8 @@
 
 #include "hf/dlog.h"
 
-#include <stdbool.h>
 #include <stddef.h>
 
-#include "hf/plat/console.h"
-#include "hf/spinlock.h"
 #include "hf/std.h"
 
 /* Keep macro alignment */
@@ -38,25 +35,24 @@
 
 /* clang-format on */
 
-static bool dlog_lock_enabled = false;
-
 /**
- * Enables the lock protecting the serial device.
+ * Enables the per-CPU buffers of the debug log, which keep CPUs from waiting for
+ * each other to log.
  */
 void dlog_enable_lock(void)
 {
-	dlog_lock_enabled = true;
+	dlog_enable_buffers();
 }
 
 /**
  * Prints a raw string to the debug log and returns its length.
  */
-static size_t print_raw_string(const char *str)
+static size_t print_raw_string(size_t cpu, const char *str)
 {
 	const char *c = str;
 
 	while (*c != '\0') {
-		plat_console_putchar(*c++);
+		dlog_putchar(cpu, *c++);
 	}
 
 	return c - str;
@@ -71,21 +67,21 @@ static size_t print_raw_string(const char *str)
  * with a zero fill; for example, -10 with width 4 should be padded to -010,
  * so suffix would point to index one of the "-10" string .
  */
-static void print_string(const char *str, const char *suffix, size_t width,
-			 int flags, char fill)
+static void print_string(size_t cpu, const char *str, const char *suffix,
+			 size_t width, int flags, char fill)
 {
 	size_t len = suffix - str;
 
 	/* Print the string up to the beginning of the suffix. */
 	while (str != suffix) {
-		plat_console_putchar(*str++);
+		dlog_putchar(cpu, *str++);
 	}
 
 	if (flags & FLAG_MINUS) {
 		/* Left-aligned. Print suffix, then print padding if needed. */
-		len += print_raw_string(suffix);
+		len += print_raw_string(cpu, suffix);
 		while (len < width) {
-			plat_console_putchar(' ');
+			dlog_putchar(cpu, ' ');
 			len++;
 		}
 		return;
@@ -94,19 +90,20 @@ static void print_string(const char *str, const char *suffix, size_t width,
 	/* Fill until we reach the desired length. */
 	len += strnlen_s(suffix, DLOG_MAX_STRING_LENGTH);
 	while (len < width) {
-		plat_console_putchar(fill);
+		dlog_putchar(cpu, fill);
 		len++;
 	}
 
 	/* Now print the rest of the string. */
-	print_raw_string(suffix);
+	print_raw_string(cpu, suffix);
 }
 
 /**
  * Prints a number to the debug log. The caller specifies the base, its minimum
  * width and printf-style flags.
  */
-static void print_num(size_t v, size_t base, size_t width, int flags)
+static void print_num(size_t cpu, size_t v, size_t base, size_t width,
+		      int flags)
 {
 	static const char *digits_lower = "0123456789abcdefx";
 	static const char *digits_upper = "0123456789ABCDEFX";
@@ -149,9 +146,9 @@ static void print_num(size_t v, size_t base, size_t width, int flags)
 		*--ptr = ' ';
 	}
 	if (flags & FLAG_ZERO) {
-		print_string(ptr, num, width, flags, '0');
+		print_string(cpu, ptr, num, width, flags, '0');
 	} else {
-		print_string(ptr, ptr, width, flags, ' ');
+		print_string(cpu, ptr, ptr, width, flags, ' ');
 	}
 }
 
@@ -194,20 +191,16 @@ static const char *parse_flags(const char *p, int *flags)
  */
 void vdlog(const char *fmt, va_list args)
 {
-	static struct spinlock sl = SPINLOCK_INIT;
 	const char *p;
 	size_t w;
 	int flags;
 	char buf[2];
-
-	if (dlog_lock_enabled) {
-		sl_lock(&sl);
-	}
+	size_t cpu = dlog_begin();
 
 	for (p = fmt; *p; p++) {
 		switch (*p) {
 		default:
-			plat_console_putchar(*p);
+			dlog_putchar(cpu, *p);
 			break;
 
 		case '%':
@@ -240,7 +233,7 @@ void vdlog(const char *fmt, va_list args)
 			case 's': {
 				char *str = va_arg(args, char *);
 
-				print_string(str, str, w, flags, ' ');
+				print_string(cpu, str, str, w, flags, ' ');
 				p++;
 			} break;
 
@@ -253,41 +246,45 @@ void vdlog(const char *fmt, va_list args)
 					v = -v;
 				}
 
-				print_num((size_t)v, 10, w, flags);
+				print_num(cpu, (size_t)v, 10, w, flags);
 				p++;
 			} break;
 
 			case 'X':
 				flags |= FLAG_UPPER;
-				print_num(va_arg(args, size_t), 16, w, flags);
+				print_num(cpu, va_arg(args, size_t), 16, w,
+					  flags);
 				p++;
 				break;
 
 			case 'p':
-				print_num(va_arg(args, size_t), 16,
+				print_num(cpu, va_arg(args, size_t), 16,
 					  sizeof(size_t) * 2, FLAG_ZERO);
 				p++;
 				break;
 
 			case 'x':
-				print_num(va_arg(args, size_t), 16, w, flags);
+				print_num(cpu, va_arg(args, size_t), 16, w,
+					  flags);
 				p++;
 				break;
 
 			case 'u':
-				print_num(va_arg(args, size_t), 10, w, flags);
+				print_num(cpu, va_arg(args, size_t), 10, w,
+					  flags);
 				p++;
 				break;
 
 			case 'o':
-				print_num(va_arg(args, size_t), 8, w, flags);
+				print_num(cpu, va_arg(args, size_t), 8, w,
+					  flags);
 				p++;
 				break;
 
 			case 'c':
 				buf[1] = 0;
 				buf[0] = va_arg(args, int);
-				print_string(buf, buf, w, flags, ' ');
+				print_string(cpu, buf, buf, w, flags, ' ');
 				p++;
 				break;
 
@@ -295,16 +292,14 @@ void vdlog(const char *fmt, va_list args)
 				break;
 
 			default:
-				plat_console_putchar('%');
+				dlog_putchar(cpu, '%');
 			}
 
 			break;
 		}
 	}
 
-	if (dlog_lock_enabled) {
-		sl_unlock(&sl);
-	}
+	dlog_end(cpu);
 }
 
 /**
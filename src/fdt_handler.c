@@ -24,6 +24,9 @@
 #include "hf/mm.h"
 #include "hf/std.h"
 
+/** The maximum depth of the clusters nested in the cpu-map. */
+#define MAX_CPU_MAP_DEPTH 4
+
 static uint64_t convert_number(const char *data, uint32_t size)
 {
 	union {
@@ -126,14 +129,105 @@ bool fdt_find_initrd(struct fdt_node *n, paddr_t *begin, paddr_t *end)
 	return true;
 }
 
-void fdt_find_cpus(const struct fdt_node *root, uint64_t *cpu_ids,
-		   size_t *cpu_count)
+/**
+ * Returns whether the name of a node starts with the given prefix, e.g. whether
+ * "cluster0" starts with "cluster".
+ */
+static bool fdt_name_has_prefix(const char *name, const char *prefix)
+{
+	size_t len = strnlen_s(prefix, 32);
+
+	return strnlen_s(name, len) == len && memcmp(name, prefix, len) == 0;
+}
+
+/**
+ * Sets the cluster and core of the CPUs referred to by the given node of the
+ * cpu-map, which is a core or one of its threads.
+ */
+static void fdt_set_cpu_topology(const struct fdt_node *n,
+				 const uint32_t *phandles, uint32_t cluster,
+				 uint32_t core, struct boot_params *p)
+{
+	uint64_t phandle;
+	size_t i;
+
+	if (!fdt_read_number(n, "cpu", &phandle)) {
+		return;
+	}
+
+	for (i = 0; i < p->cpu_count; i++) {
+		if (phandles[i] == phandle) {
+			p->cpu_clusters[i] = cluster;
+			p->cpu_cores[i] = core;
+		}
+	}
+}
+
+/**
+ * Numbers the clusters under the given node of the cpu-map, and their cores, in
+ * the order they appear. Only the innermost clusters are numbered if they are
+ * nested.
+ */
+static void fdt_find_clusters(const struct fdt_node *parent,
+			      const uint32_t *phandles, uint32_t *cluster,
+			      uint32_t depth, struct boot_params *p)
+{
+	struct fdt_node n = *parent;
+	const char *name;
+
+	if (!fdt_first_child(&n, &name)) {
+		return;
+	}
+
+	do {
+		struct fdt_node core = n;
+		uint32_t core_index = 0;
+
+		if (!fdt_name_has_prefix(name, "cluster")) {
+			continue;
+		}
+
+		if (fdt_first_child(&core, &name)) {
+			do {
+				struct fdt_node thread = core;
+
+				if (!fdt_name_has_prefix(name, "core")) {
+					continue;
+				}
+
+				fdt_set_cpu_topology(&core, phandles, *cluster,
+						     core_index, p);
+				if (fdt_first_child(&thread, &name)) {
+					do {
+						fdt_set_cpu_topology(
+							&thread, phandles,
+							*cluster, core_index,
+							p);
+					} while (fdt_next_sibling(&thread,
+								  &name));
+				}
+				core_index++;
+			} while (fdt_next_sibling(&core, &name));
+		}
+
+		if (core_index > 0) {
+			(*cluster)++;
+		} else if (depth < MAX_CPU_MAP_DEPTH) {
+			fdt_find_clusters(&n, phandles, cluster, depth + 1, p);
+		}
+	} while (fdt_next_sibling(&n, &name));
+}
+
+void fdt_find_cpus(const struct fdt_node *root, struct boot_params *p)
 {
 	struct fdt_node n = *root;
 	const char *name;
 	uint64_t address_size;
+	uint32_t phandles[MAX_CPUS];
+	size_t i;
+	size_t j;
 
-	*cpu_count = 0;
+	p->cpu_count = 0;
 
 	if (!fdt_find_child(&n, "cpus")) {
 		dlog("Unable to find 'cpus'\n");
@@ -153,6 +247,7 @@ void fdt_find_cpus(const struct fdt_node *root, uint64_t *cpu_ids,
 	do {
 		const char *data;
 		uint32_t size;
+		uint64_t phandle;
 
 		if (!fdt_read_property(&n, "device_type", &data, &size) ||
 		    size != sizeof("cpu") ||
@@ -161,20 +256,59 @@ void fdt_find_cpus(const struct fdt_node *root, uint64_t *cpu_ids,
 			continue;
 		}
 
+		if (!fdt_read_number(&n, "phandle", &phandle)) {
+			phandle = 0;
+		}
+
 		/* Get all entries for this CPU. */
 		while (size >= address_size) {
-			if (*cpu_count >= MAX_CPUS) {
+			if (p->cpu_count >= MAX_CPUS) {
 				dlog("Found more than %d CPUs\n", MAX_CPUS);
-				return;
+				goto topology;
 			}
 
-			cpu_ids[(*cpu_count)++] =
+			phandles[p->cpu_count] = phandle;
+			p->cpu_ids[p->cpu_count++] =
 				convert_number(data, address_size);
 
 			size -= address_size;
 			data += address_size;
 		}
 	} while (fdt_next_sibling(&n, &name));
+
+topology:
+	/*
+	 * Without a cpu-map, the CPUs whose MPIDRs differ only in affinity
+	 * level 0 are taken to be the cores of a cluster.
+	 */
+	for (i = 0; i < p->cpu_count; i++) {
+		uint32_t next_cluster = 0;
+
+		p->cpu_clusters[i] = UINT32_MAX;
+		p->cpu_cores[i] = 0;
+		for (j = 0; j < i; j++) {
+			if ((p->cpu_ids[j] & ~UINT64_C(0xff)) ==
+			    (p->cpu_ids[i] & ~UINT64_C(0xff))) {
+				p->cpu_clusters[i] = p->cpu_clusters[j];
+				p->cpu_cores[i]++;
+			}
+			if (p->cpu_clusters[j] >= next_cluster) {
+				next_cluster = p->cpu_clusters[j] + 1;
+			}
+		}
+
+		if (p->cpu_clusters[i] == UINT32_MAX) {
+			p->cpu_clusters[i] = next_cluster;
+		}
+	}
+
+	/* The cpu-map describes the topology if there is one. */
+	n = *root;
+	if (fdt_find_child(&n, "cpus") && fdt_find_child(&n, "cpu-map")) {
+		uint32_t cluster = 0;
+
+		fdt_find_clusters(&n, phandles, &cluster, 0, p);
+	}
 }
 
 void fdt_find_memory_ranges(const struct fdt_node *root, struct boot_params *p)
@@ -21,11 +21,15 @@
 #include "hf/api.h"
 #include "hf/assert.h"
 #include "hf/boot_params.h"
+#include "hf/capability.h"
 #include "hf/dlog.h"
 #include "hf/layout.h"
+#include "hf/manifest.h"
 #include "hf/memiter.h"
 #include "hf/mm.h"
 #include "hf/plat/console.h"
+#include "hf/run_queue.h"
+#include "hf/smc_filter.h"
 #include "hf/std.h"
 #include "hf/vm.h"
 
@@ -98,6 +102,22 @@ bool load_primary(const struct memiter *cpio, uintreg_t kernel_arg,
 			return false;
 		}
 
+		memiter_init(&it, "vmlinuz", sizeof("vmlinuz") - 1);
+		if (!smc_allowlist_load(vm->id, cpio, &it)) {
+			dlog("Unable to load SMC allowlist for primary vm\n");
+			return false;
+		}
+
+		if (!capabilities_load(cpio, &it, &vm->capabilities)) {
+			dlog("Unable to load capabilities for primary vm\n");
+			return false;
+		}
+
+		if (!priority_load(cpio, &it, &vm->priority)) {
+			dlog("Unable to load priority for primary vm\n");
+			return false;
+		}
+
 		/* Map the 1TB of memory. */
 		/* TODO: We should do a whitelist rather than a blacklist. */
 		if (!mm_vm_identity_map(
@@ -153,6 +173,284 @@ static bool carve_out_mem_range(struct mem_range *mem_ranges,
 	return false;
 }
 
+/**
+ * Remove the given memory range from the given ranges, if it is at the start or
+ * the end of one of them. Return true on success, or false if the range is not
+ * available or would split one of the ranges in two.
+ */
+static bool carve_out_fixed_mem_range(struct mem_range *mem_ranges,
+				      size_t mem_ranges_count, paddr_t begin,
+				      paddr_t end)
+{
+	size_t i;
+
+	for (i = 0; i < mem_ranges_count; ++i) {
+		if (pa_addr(begin) < pa_addr(mem_ranges[i].begin) ||
+		    pa_addr(end) > pa_addr(mem_ranges[i].end)) {
+			continue;
+		}
+
+		if (pa_addr(begin) == pa_addr(mem_ranges[i].begin)) {
+			mem_ranges[i].begin = end;
+			return true;
+		}
+
+		if (pa_addr(end) == pa_addr(mem_ranges[i].end)) {
+			mem_ranges[i].end = begin;
+			return true;
+		}
+
+		return false;
+	}
+
+	return false;
+}
+
+/**
+ * Carve out the memory regions of the given secondary VM from the given ranges,
+ * rounding their sizes up to the page size. Return true on success, or false if
+ * one of them is not available.
+ */
+static bool carve_out_regions(struct mem_range *mem_ranges,
+			      size_t mem_ranges_count,
+			      const struct manifest_vm *manifest_vm,
+			      struct mem_range *regions)
+{
+	uint32_t i;
+
+	for (i = 0; i < manifest_vm->region_count; ++i) {
+		const struct manifest_region *region = &manifest_vm->regions[i];
+		uint64_t size = (region->size + PAGE_SIZE - 1) & ~(PAGE_SIZE - 1);
+
+		if (region->base == 0) {
+			if (!carve_out_mem_range(mem_ranges, mem_ranges_count,
+						 size, &regions[i].begin,
+						 &regions[i].end)) {
+				dlog("Not enough memory (%u bytes)\n", size);
+				return false;
+			}
+			continue;
+		}
+
+		regions[i].begin = pa_init(region->base);
+		regions[i].end = pa_init(region->base + size);
+		if ((region->base & (PAGE_SIZE - 1)) != 0 ||
+		    region->base + size < region->base ||
+		    !carve_out_fixed_mem_range(mem_ranges, mem_ranges_count,
+					       regions[i].begin,
+					       regions[i].end)) {
+			dlog("Memory at 0x%x is not available\n", region->base);
+			return false;
+		}
+	}
+
+	return true;
+}
+
+/**
+ * Assigns the devices of the given secondary VM to it: their MMIO ranges are
+ * mapped into it as device memory and unmapped from the primary VM, so that a
+ * device is assigned to a single VM. Return true on success, or false if a
+ * range is not page-aligned or the primary VM no longer has it.
+ */
+static bool load_devices(struct vm *vm, const struct manifest_vm *manifest_vm,
+			 struct vm *primary, struct mpool *ppool)
+{
+	uint32_t i;
+
+	for (i = 0; i < manifest_vm->device_count; ++i) {
+		const struct manifest_device *device = &manifest_vm->devices[i];
+		paddr_t begin = pa_init(device->base);
+		paddr_t end = pa_add(begin, device->size);
+		int mode;
+
+		if (((device->base | device->size) & (PAGE_SIZE - 1)) != 0) {
+			dlog("Device at 0x%x is not page-aligned\n",
+			     device->base);
+			return false;
+		}
+
+		/* The primary VM has access to devices until they are assigned. */
+		if (!mm_vm_get_mode(&primary->ptable, ipa_from_pa(begin),
+				    ipa_from_pa(end), &mode) ||
+		    (mode & MM_MODE_INVALID) != 0) {
+			dlog("Device at 0x%x is not available\n", device->base);
+			return false;
+		}
+
+		if (!mm_vm_identity_map(&vm->ptable, begin, end,
+					MM_MODE_R | MM_MODE_W | MM_MODE_D, NULL,
+					ppool) ||
+		    !mm_vm_unmap(&primary->ptable, begin, end, ppool)) {
+			dlog("Unable to map device at 0x%x\n", device->base);
+			return false;
+		}
+
+		vm->devices[i].begin = begin;
+		vm->devices[i].end = end;
+		vm->devices[i].intid = device->intid;
+		vm->device_count = i + 1;
+	}
+
+	return true;
+}
+
+/**
+ * Loads the memory regions the manifest shares among secondary VMs from their
+ * files in the RAM disk, at their bases, which are carved out of the given
+ * ranges. The hypervisor owns the regions: they are unmapped from the primary
+ * VM, and only mapped read-only into the VMs they are shared with. The ranges
+ * and modes the regions are mapped with are written to `shared`, with empty
+ * ranges for those that fail to load.
+ */
+static void load_shared_regions(const struct memiter *cpio,
+				struct mem_range *mem_ranges,
+				size_t mem_ranges_count, struct vm *primary,
+				struct mpool *ppool,
+				struct vm_region shared[MANIFEST_MAX_SHARED])
+{
+	size_t count = manifest_shared_count();
+	size_t i;
+
+	memset_s(shared, sizeof(shared[0]) * MANIFEST_MAX_SHARED, 0,
+		 sizeof(shared[0]) * MANIFEST_MAX_SHARED);
+
+	for (i = 0; i < count; ++i) {
+		struct manifest_shared manifest_shared;
+		struct memiter name;
+		struct memiter file;
+		paddr_t begin;
+		paddr_t end;
+		size_t size;
+		void *ptr;
+
+		if (!manifest_shared_get(i, &manifest_shared)) {
+			break;
+		}
+
+		memiter_init(&name, manifest_shared.filename,
+			     strnlen_s(manifest_shared.filename,
+				       MANIFEST_NAME_MAX));
+		if (!cpio_find_file_memiter(cpio, &name, &file)) {
+			dlog("Unable to find shared region %s\n",
+			     manifest_shared.filename);
+			continue;
+		}
+
+		size = file.limit - file.next;
+		begin = pa_init(manifest_shared.base);
+		end = pa_add(begin, align_up(size, PAGE_SIZE));
+		if ((manifest_shared.base & (PAGE_SIZE - 1)) != 0 ||
+		    size == 0 ||
+		    !carve_out_fixed_mem_range(mem_ranges, mem_ranges_count,
+					       begin, end)) {
+			dlog("Memory for shared region %s is not available\n",
+			     manifest_shared.filename);
+			continue;
+		}
+
+		/* The rest of the last page is zeroed so no data is leaked. */
+		ptr = mm_identity_map(begin, end, MM_MODE_W, ppool);
+		if (ptr == NULL) {
+			dlog("Unable to copy shared region %s\n",
+			     manifest_shared.filename);
+			continue;
+		}
+		memset_s(ptr, pa_difference(begin, end), 0,
+			 pa_difference(begin, end));
+		memcpy_s(ptr, size, file.next, size);
+		arch_mm_write_back_dcache(ptr, pa_difference(begin, end));
+		mm_unmap(begin, end, ppool);
+
+		if (!mm_vm_unmap(&primary->ptable, begin, end, ppool)) {
+			dlog("Unable to unmap shared region %s\n",
+			     manifest_shared.filename);
+			continue;
+		}
+
+		shared[i].begin = begin;
+		shared[i].end = end;
+		shared[i].mode = manifest_shared.mode | MM_MODE_UNOWNED |
+				 MM_MODE_SHARED | MM_MODE_HYP;
+
+		dlog("Loaded shared region %s at 0x%x\n",
+		     manifest_shared.filename, pa_addr(begin));
+	}
+}
+
+/**
+ * Maps the shared regions that the manifest shares with the given secondary VM
+ * into it. Return true on success, or false if one of them failed to load or
+ * can't be mapped.
+ */
+static bool load_shared(struct vm *vm, const struct manifest_vm *manifest_vm,
+			const struct vm_region shared[MANIFEST_MAX_SHARED],
+			struct mpool *ppool)
+{
+	uint32_t i;
+
+	for (i = 0; i < MANIFEST_MAX_SHARED; ++i) {
+		if ((manifest_vm->shared_regions & (UINT32_C(1) << i)) == 0) {
+			continue;
+		}
+
+		if (pa_addr(shared[i].begin) == pa_addr(shared[i].end) ||
+		    !mm_vm_identity_map(&vm->ptable, shared[i].begin,
+					shared[i].end, shared[i].mode, NULL,
+					ppool)) {
+			dlog("Unable to map shared region %u\n", i);
+			return false;
+		}
+	}
+
+	return true;
+}
+
+/**
+ * Prepares the given secondary VM to be restarted when it aborts, by keeping a
+ * copy of its kernel in memory carved out of the given ranges, which the
+ * primary VM is denied access to, and recording its memory regions. Return true
+ * on success, or false if there is not enough memory for the copy.
+ */
+static bool load_restart_info(struct vm *vm, const struct memiter *kernel,
+			      const struct manifest_vm *manifest_vm,
+			      const struct mem_range *regions,
+			      struct mem_range *mem_ranges,
+			      size_t mem_ranges_count, struct vm *primary,
+			      struct mpool *ppool)
+{
+	struct vm_restart *restart = &vm->restart;
+	size_t size = kernel->limit - kernel->next;
+	uint32_t i;
+
+	if (!carve_out_mem_range(
+		    mem_ranges, mem_ranges_count,
+		    (size + PAGE_SIZE - 1) & ~(PAGE_SIZE - 1),
+		    &restart->image_begin, &restart->image_end)) {
+		return false;
+	}
+
+	if (!copy_to_unmapped(restart->image_begin, kernel->next, size,
+			      ppool) ||
+	    !mm_vm_unmap(&primary->ptable, restart->image_begin,
+			 restart->image_end, ppool)) {
+		return false;
+	}
+
+	/* Only the kernel is copied back, not the padding up to a page. */
+	restart->image_end = pa_add(restart->image_begin, size);
+
+	for (i = 0; i < manifest_vm->region_count; ++i) {
+		restart->regions[i].begin = regions[i].begin;
+		restart->regions[i].end = regions[i].end;
+		restart->regions[i].mode = manifest_vm->regions[i].mode;
+	}
+	restart->region_count = manifest_vm->region_count;
+	restart->enabled = true;
+
+	return true;
+}
+
 /**
  * Given arrays of memory ranges before and after memory was removed for
  * secondary VMs, add the difference to the reserved ranges of the given update.
@@ -206,12 +504,13 @@ bool load_secondary(const struct memiter *cpio,
 		    struct boot_params_update *update, struct mpool *ppool)
 {
 	struct vm *primary;
-	struct memiter it;
 	struct memiter name;
-	uint64_t mem;
 	uint64_t cpu;
 	struct mem_range mem_ranges_available[MAX_MEM_RANGES];
+	struct vm_region shared[MANIFEST_MAX_SHARED];
+	size_t count;
 	size_t i;
+	size_t j;
 
 	static_assert(
 		sizeof(mem_ranges_available) == sizeof(params->mem_ranges),
@@ -224,8 +523,9 @@ bool load_secondary(const struct memiter *cpio,
 
 	primary = vm_find(HF_PRIMARY_VM_ID);
 
-	if (!cpio_find_file(cpio, "vms.txt", &it)) {
-		dlog("vms.txt is missing\n");
+	/* The VMs are listed in vms.txt unless the FDT has a manifest. */
+	if (!manifest_load_vms_txt(cpio)) {
+		dlog("vms.txt is missing or malformed\n");
 		return true;
 	}
 
@@ -235,43 +535,50 @@ bool load_secondary(const struct memiter *cpio,
 			pa_addr(mem_ranges_available[i].end), PAGE_SIZE));
 	}
 
-	while (memiter_parse_uint(&it, &mem) && memiter_parse_uint(&it, &cpu) &&
-	       memiter_parse_str(&it, &name)) {
+	/* Shared regions are at fixed bases, so they are carved out first. */
+	load_shared_regions(cpio, mem_ranges_available,
+			    params->mem_ranges_count, primary, ppool, shared);
+
+	count = manifest_vm_count();
+	for (j = 0; j < count; ++j) {
+		struct manifest_vm manifest_vm;
 		struct memiter kernel;
-		paddr_t secondary_mem_begin;
-		paddr_t secondary_mem_end;
+		struct mem_range regions[MANIFEST_MAX_REGIONS];
 		ipaddr_t secondary_entry;
-		const char *p;
 		struct vm *vm;
 		struct vcpu *vcpu;
+		uint32_t k;
 
-		dlog("Loading ");
-		for (p = name.next; p != name.limit; ++p) {
-			dlog("%c", *p);
+		if (!manifest_vm_get(j, &manifest_vm)) {
+			break;
 		}
-		dlog("\n");
+
+		memiter_init(&name, manifest_vm.kernel_filename,
+			     strnlen_s(manifest_vm.kernel_filename,
+				       MANIFEST_NAME_MAX));
+		cpu = manifest_vm.vcpu_count;
+
+		dlog("Loading %s\n", manifest_vm.debug_name);
 
 		if (!cpio_find_file_memiter(cpio, &name, &kernel)) {
 			dlog("Unable to load kernel\n");
 			continue;
 		}
 
-		/* Round up to page size. */
-		mem = (mem + PAGE_SIZE - 1) & ~(PAGE_SIZE - 1);
-
-		if (mem < kernel.limit - kernel.next) {
+		/* The kernel is loaded at the start of the first region. */
+		if (manifest_vm.region_count == 0 ||
+		    manifest_vm.regions[0].size < kernel.limit - kernel.next) {
 			dlog("Kernel is larger than available memory\n");
 			continue;
 		}
 
-		if (!carve_out_mem_range(
-			    mem_ranges_available, params->mem_ranges_count, mem,
-			    &secondary_mem_begin, &secondary_mem_end)) {
-			dlog("Not enough memory (%u bytes)\n", mem);
+		if (!carve_out_regions(mem_ranges_available,
+				       params->mem_ranges_count, &manifest_vm,
+				       regions)) {
 			continue;
 		}
 
-		if (!copy_to_unmapped(secondary_mem_begin, kernel.next,
+		if (!copy_to_unmapped(regions[0].begin, kernel.next,
 				      kernel.limit - kernel.next, ppool)) {
 			dlog("Unable to copy kernel\n");
 			continue;
@@ -282,31 +589,96 @@ bool load_secondary(const struct memiter *cpio,
 			continue;
 		}
 
+		if (!smc_allowlist_load(vm->id, cpio, &name)) {
+			dlog("Unable to load SMC allowlist\n");
+			continue;
+		}
+
+		if (!capabilities_load(cpio, &name, &vm->capabilities)) {
+			dlog("Unable to load capabilities\n");
+			continue;
+		}
+
+		if (!priority_load(cpio, &name, &vm->priority)) {
+			dlog("Unable to load priority\n");
+			continue;
+		}
+
+		/* The manifest takes precedence over caps.txt and the like. */
+		if (manifest_vm.capabilities != MANIFEST_UNSET) {
+			vm->capabilities = manifest_vm.capabilities;
+		}
+
+		if (manifest_vm.priority != MANIFEST_UNSET) {
+			vm->priority = manifest_vm.priority;
+		}
+
+		vm->mem_quota = manifest_vm.mem_quota;
+		memcpy_s(vm->uuid, sizeof(vm->uuid), manifest_vm.uuid,
+			 sizeof(manifest_vm.uuid));
+
 		plat_console_vm_mm_init(vm, ppool);
 
-		/* Grant the VM access to the memory. */
-		if (!mm_vm_identity_map(&vm->ptable, secondary_mem_begin,
-					secondary_mem_end,
-					MM_MODE_R | MM_MODE_W | MM_MODE_X,
-					&secondary_entry, ppool)) {
-			dlog("Unable to initialise memory\n");
+		/* Grant the VM access to the memory of each region. */
+		for (k = 0; k < manifest_vm.region_count; ++k) {
+			ipaddr_t ipa;
+
+			if (!mm_vm_identity_map(&vm->ptable, regions[k].begin,
+						regions[k].end,
+						manifest_vm.regions[k].mode,
+						&ipa, ppool)) {
+				dlog("Unable to initialise memory\n");
+				break;
+			}
+
+			if (k == 0) {
+				secondary_entry = ipa;
+			}
+
+			/* Deny the primary VM access to this memory. */
+			if (!mm_vm_unmap(&primary->ptable, regions[k].begin,
+					 regions[k].end, ppool)) {
+				dlog("Unable to unmap secondary VM from "
+				     "primary VM\n");
+				return false;
+			}
+		}
+
+		if (k != manifest_vm.region_count) {
 			continue;
 		}
 
-		/* Deny the primary VM access to this memory. */
-		if (!mm_vm_unmap(&primary->ptable, secondary_mem_begin,
-				 secondary_mem_end, ppool)) {
-			dlog("Unable to unmap secondary VM from primary VM\n");
-			return false;
+		if (!load_devices(vm, &manifest_vm, primary, ppool)) {
+			continue;
+		}
+
+		if (!load_shared(vm, &manifest_vm, shared, ppool)) {
+			continue;
 		}
 
-		dlog("Loaded with %u vcpus, entry at 0x%x\n", cpu,
-		     pa_addr(secondary_mem_begin));
+		if (manifest_vm.restart_policy == MANIFEST_RESTART_ON_ABORT &&
+		    !load_restart_info(vm, &kernel, &manifest_vm, regions,
+				       mem_ranges_available,
+				       params->mem_ranges_count, primary,
+				       ppool)) {
+			dlog("Unable to keep the kernel to restart the VM\n");
+		}
+
+		dlog("Loaded with %u vcpus and %u memory regions, entry at "
+		     "0x%x\n",
+		     cpu, manifest_vm.region_count, pa_addr(regions[0].begin));
+
+		if (manifest_vm.boot_hold) {
+			/* The primary VM releases it by resuming it. */
+			atomic_store_explicit(&vm->suspended, true,
+					      memory_order_relaxed);
+			dlog("Held until released by the primary VM\n");
+		}
 
 		vcpu = vm_get_vcpu(vm, 0);
 		vcpu_secondary_reset_and_start(
 			vcpu, secondary_entry,
-			pa_difference(secondary_mem_begin, secondary_mem_end));
+			pa_difference(regions[0].begin, regions[0].end));
 	}
 
 	/*
@@ -0,0 +1,86 @@
+/*
+ * Copyright 2019 Jeehoon Kang
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <gmock/gmock.h>
+
+extern "C" {
+#include "hf/mailbox.h"
+}
+
+namespace
+{
+using ::testing::Eq;
+
+TEST(mailbox, goes_around_empty_received_read)
+{
+	struct mailbox_protocol protocol;
+
+	mailbox_reset(&protocol);
+	EXPECT_TRUE(mailbox_is_idle(&protocol));
+	EXPECT_FALSE(mailbox_read(&protocol));
+	EXPECT_THAT(mailbox_clear(&protocol), Eq(MAILBOX_STATE_EMPTY));
+
+	ASSERT_TRUE(mailbox_receive(&protocol, 1, false));
+	EXPECT_THAT(mailbox_state(&protocol), Eq(MAILBOX_STATE_RECEIVED));
+	EXPECT_FALSE(mailbox_receive(&protocol, 1, false));
+	EXPECT_FALSE(mailbox_fill(&protocol));
+	EXPECT_THAT(mailbox_clear(&protocol), Eq(MAILBOX_STATE_RECEIVED));
+
+	ASSERT_TRUE(mailbox_read(&protocol));
+	EXPECT_THAT(mailbox_state(&protocol), Eq(MAILBOX_STATE_READ));
+	EXPECT_THAT(mailbox_clear(&protocol), Eq(MAILBOX_STATE_READ));
+	EXPECT_TRUE(mailbox_is_idle(&protocol));
+
+	ASSERT_TRUE(mailbox_fill(&protocol));
+	EXPECT_THAT(mailbox_state(&protocol), Eq(MAILBOX_STATE_READ));
+	EXPECT_THAT(mailbox_clear(&protocol), Eq(MAILBOX_STATE_READ));
+}
+
+TEST(mailbox, only_takes_fragments_from_their_sender)
+{
+	struct mailbox_protocol protocol;
+	spci_vm_id_t sender;
+
+	mailbox_reset(&protocol);
+	ASSERT_TRUE(mailbox_receive(&protocol, 1, true));
+	ASSERT_TRUE(mailbox_read(&protocol));
+	ASSERT_THAT(mailbox_clear(&protocol), Eq(MAILBOX_STATE_READ));
+	EXPECT_FALSE(mailbox_is_idle(&protocol));
+	ASSERT_TRUE(mailbox_pending_sender(&protocol, &sender));
+	EXPECT_THAT(sender, Eq(1));
+
+	EXPECT_FALSE(mailbox_receive(&protocol, 2, false));
+	ASSERT_TRUE(mailbox_receive(&protocol, 1, false));
+	EXPECT_FALSE(mailbox_pending_sender(&protocol, &sender));
+}
+
+TEST(mailbox, abandons_fragments)
+{
+	struct mailbox_protocol protocol;
+	spci_vm_id_t sender;
+
+	mailbox_reset(&protocol);
+	ASSERT_TRUE(mailbox_receive(&protocol, 1, true));
+	ASSERT_TRUE(mailbox_read(&protocol));
+	ASSERT_THAT(mailbox_clear(&protocol), Eq(MAILBOX_STATE_READ));
+
+	mailbox_abandon(&protocol);
+	EXPECT_FALSE(mailbox_pending_sender(&protocol, &sender));
+	EXPECT_TRUE(mailbox_is_idle(&protocol));
+	EXPECT_TRUE(mailbox_receive(&protocol, 2, false));
+}
+
+} /* namespace */
@@ -24,6 +24,7 @@
 #include "hf/cpio.h"
 #include "hf/cpu.h"
 #include "hf/dlog.h"
+#include "hf/frame.h"
 #include "hf/load.h"
 #include "hf/mm.h"
 #include "hf/mpool.h"
@@ -48,6 +49,8 @@ static void one_time_init(void)
 	struct memiter primary_initrd;
 	struct memiter cpio;
 	void *initrd;
+	paddr_t mem_begin;
+	paddr_t mem_end;
 	size_t i;
 	struct mpool ppool;
 
@@ -58,8 +61,10 @@ static void one_time_init(void)
 
 	arch_one_time_init();
 
-	mpool_init(&ppool, sizeof(struct mm_page_table));
-	mpool_add_chunk(&ppool, ptable_buf, sizeof(ptable_buf));
+	if (!mpool_init_from_byte_range(&ppool, ptable_buf,
+					ptable_buf + sizeof(ptable_buf))) {
+		panic("no memory for the boot page pool");
+	}
 
 	if (!mm_init(&ppool)) {
 		panic("mm_init failed");
@@ -73,12 +78,28 @@ static void one_time_init(void)
 		panic("unable to retrieve boot params");
 	}
 
-	cpu_module_init(params.cpu_ids, params.cpu_count);
+	cpu_module_init(params.cpu_ids, params.cpu_clusters, params.cpu_cores,
+			params.cpu_count);
 
+	mem_begin = pa_init(UINTPTR_MAX);
+	mem_end = pa_init(0);
 	for (i = 0; i < params.mem_ranges_count; ++i) {
 		dlog("Memory range:  0x%x - 0x%x\n",
 		     pa_addr(params.mem_ranges[i].begin),
 		     pa_addr(params.mem_ranges[i].end) - 1);
+		if (pa_addr(params.mem_ranges[i].begin) < pa_addr(mem_begin)) {
+			mem_begin = params.mem_ranges[i].begin;
+		}
+		if (pa_addr(params.mem_ranges[i].end) > pa_addr(mem_end)) {
+			mem_end = params.mem_ranges[i].end;
+		}
+	}
+
+	/* Track the owners of memory pages before any VM is loaded. */
+	if (pa_addr(mem_begin) < pa_addr(mem_end) &&
+	    !frame_table_init(mem_begin, mem_end, &ppool)) {
+		dlog("Unable to allocate the frame table, memory ownership is "
+		     "not tracked\n");
 	}
 
 	dlog("Ramdisk range: 0x%x - 0x%x\n", pa_addr(params.initrd_begin),
@@ -116,6 +137,7 @@ static void one_time_init(void)
 	}
 
 	mm_defrag(&ppool);
+	mpool_dump_stats(&ppool);
 
 	/* Initialise the API page pool. ppool will be empty from now on. */
 	api_init(&ppool);
@@ -150,12 +172,16 @@ struct vcpu *cpu_main(struct cpu *c)
 		panic("mm_cpu_init failed");
 	}
 
+	/* The FP/SIMD registers don't survive the CPU being powered down. */
+	cpu_fp_forget(c);
+
 	vcpu = vm_get_vcpu(vm_find(HF_PRIMARY_VM_ID), cpu_index(c));
 	vm = vcpu->vm;
 	vcpu->cpu = c;
 
 	/* Reset the registers to give a clean start for the primary's vCPU. */
-	arch_regs_reset(&vcpu->regs, true, vm->id, c->id, vm->ptable.root);
+	arch_regs_reset(&vcpu->regs, true, vm->vmid, c->id, vm->ptable.root);
+	vcpu_set_fp_cpu(vcpu, NULL);
 
 	return vcpu;
 }
@@ -0,0 +1,431 @@
+/*
+ * Copyright 2018 The Hafnium Authors.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <gmock/gmock.h>
+
+extern "C" {
+#include "hf/fdt.h"
+#include "hf/manifest.h"
+#include "hf/mm.h"
+}
+
+namespace
+{
+using ::testing::ElementsAre;
+using ::testing::Eq;
+using ::testing::StrEq;
+
+/*
+ * /dts-v1/;
+ *
+ * / {
+ *       #address-cells = <2>;
+ *       #size-cells = <2>;
+ *
+ *       hypervisor {
+ *           vm1 {
+ *               debug_name = "first";
+ *               kernel_filename = "vmlinuz1";
+ *               mem_size = <0x100000>;
+ *               vcpu_count = <2>;
+ *               uuid = <0x1e67b5b4 0xe14f904a 0x13fb1fb8 0xcbdae1da>;
+ *           };
+ *           vm2 {
+ *               kernel_filename = "vmlinuz2";
+ *               mem_size = <0x0 0x200000>;
+ *               vcpu_count = <1>;
+ *               capabilities = <0x3>;
+ *               priority = <0>;
+ *               mem_quota = <0x400000>;
+ *               device_regions = <0x0 0x9040000 0x0 0x1000 0x21>,
+ *                                <0x0 0x9050000 0x0 0x2000 0x0>;
+ *           };
+ *           vm3 {
+ *               kernel_filename = "vmlinuz3";
+ *               mem_regions = <0x0 0x0 0x0 0x100000 0x7>,
+ *                             <0x1 0x0 0x0 0x200000 0x3>;
+ *               vcpu_count = <1>;
+ *               restart_policy = "on_abort";
+ *           };
+ *       };
+ * };
+ *
+ * $ dtc --boot-cpu 0 --in-format dts --out-format dtb --out-version 17 test.dts
+ * | xxd -i
+ */
+
+alignas(8) constexpr uint8_t test_manifest_dtb[] = {
+	0xd0, 0x0d, 0xfe, 0xed, 0x00, 0x00, 0x02, 0xb5, 0x00, 0x00, 0x00, 0x38,
+	0x00, 0x00, 0x02, 0x1c, 0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x11,
+	0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x99,
+	0x00, 0x00, 0x01, 0xe4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x7e, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x8d, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x01, 0x68, 0x79, 0x70, 0x65, 0x72, 0x76, 0x69, 0x73,
+	0x6f, 0x72, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x76, 0x6d, 0x31, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00,
+	0x66, 0x69, 0x72, 0x73, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x0b, 0x76, 0x6d, 0x6c, 0x69,
+	0x6e, 0x75, 0x7a, 0x31, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x1b, 0x00, 0x10, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x24,
+	0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x10,
+	0x00, 0x00, 0x00, 0x2f, 0x1e, 0x67, 0xb5, 0xb4, 0xe1, 0x4f, 0x90, 0x4a,
+	0x13, 0xfb, 0x1f, 0xb8, 0xcb, 0xda, 0xe1, 0xda, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x01, 0x76, 0x6d, 0x32, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x0b, 0x76, 0x6d, 0x6c, 0x69,
+	0x6e, 0x75, 0x7a, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x1b, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x41,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x4a, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x54, 0x00, 0x00, 0x00, 0x00,
+	0x09, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00,
+	0x00, 0x00, 0x00, 0x21, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x76, 0x6d, 0x33, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x0b,
+	0x76, 0x6d, 0x6c, 0x69, 0x6e, 0x75, 0x7a, 0x33, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x63,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x01,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x6f, 0x6f, 0x6e, 0x5f, 0x61,
+	0x62, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x09,
+	0x64, 0x65, 0x62, 0x75, 0x67, 0x5f, 0x6e, 0x61, 0x6d, 0x65, 0x00, 0x6b,
+	0x65, 0x72, 0x6e, 0x65, 0x6c, 0x5f, 0x66, 0x69, 0x6c, 0x65, 0x6e, 0x61,
+	0x6d, 0x65, 0x00, 0x6d, 0x65, 0x6d, 0x5f, 0x73, 0x69, 0x7a, 0x65, 0x00,
+	0x76, 0x63, 0x70, 0x75, 0x5f, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x00, 0x75,
+	0x75, 0x69, 0x64, 0x00, 0x63, 0x61, 0x70, 0x61, 0x62, 0x69, 0x6c, 0x69,
+	0x74, 0x69, 0x65, 0x73, 0x00, 0x70, 0x72, 0x69, 0x6f, 0x72, 0x69, 0x74,
+	0x79, 0x00, 0x6d, 0x65, 0x6d, 0x5f, 0x71, 0x75, 0x6f, 0x74, 0x61, 0x00,
+	0x64, 0x65, 0x76, 0x69, 0x63, 0x65, 0x5f, 0x72, 0x65, 0x67, 0x69, 0x6f,
+	0x6e, 0x73, 0x00, 0x6d, 0x65, 0x6d, 0x5f, 0x72, 0x65, 0x67, 0x69, 0x6f,
+	0x6e, 0x73, 0x00, 0x72, 0x65, 0x73, 0x74, 0x61, 0x72, 0x74, 0x5f, 0x70,
+	0x6f, 0x6c, 0x69, 0x63, 0x79, 0x00, 0x23, 0x61, 0x64, 0x64, 0x72, 0x65,
+	0x73, 0x73, 0x2d, 0x63, 0x65, 0x6c, 0x6c, 0x73, 0x00, 0x23, 0x73, 0x69,
+	0x7a, 0x65, 0x2d, 0x63, 0x65, 0x6c, 0x6c, 0x73, 0x00};
+
+TEST(manifest, reads_vms_from_fdt)
+{
+	struct fdt_node n;
+	struct manifest_vm vm;
+
+	ASSERT_TRUE(fdt_root_node(
+		&n, reinterpret_cast<const struct fdt_header *>(
+			    test_manifest_dtb)));
+	ASSERT_TRUE(fdt_find_child(&n, ""));
+	ASSERT_TRUE(manifest_init(&n));
+	ASSERT_THAT(manifest_vm_count(), Eq(3));
+
+	ASSERT_TRUE(manifest_vm_get(0, &vm));
+	EXPECT_THAT(vm.debug_name, StrEq("first"));
+	EXPECT_THAT(vm.kernel_filename, StrEq("vmlinuz1"));
+	EXPECT_THAT(vm.mem_size, Eq(0x100000));
+	ASSERT_THAT(vm.region_count, Eq(1));
+	EXPECT_THAT(vm.regions[0].base, Eq(0));
+	EXPECT_THAT(vm.regions[0].size, Eq(0x100000));
+	EXPECT_THAT(vm.regions[0].mode, Eq(MM_MODE_R | MM_MODE_W | MM_MODE_X));
+	EXPECT_THAT(vm.vcpu_count, Eq(2));
+	EXPECT_THAT(vm.capabilities, Eq(MANIFEST_UNSET));
+	EXPECT_THAT(vm.priority, Eq(MANIFEST_UNSET));
+	EXPECT_THAT(vm.mem_quota, Eq(0));
+	EXPECT_THAT(vm.restart_policy, Eq(MANIFEST_RESTART_NEVER));
+	EXPECT_THAT(vm.uuid, ElementsAre(0x1e67b5b4, 0xe14f904a, 0x13fb1fb8,
+					 0xcbdae1da));
+	EXPECT_THAT(vm.device_count, Eq(0));
+	EXPECT_THAT(vm.boot_order, Eq(MANIFEST_UNSET));
+	EXPECT_FALSE(vm.boot_hold);
+	EXPECT_THAT(vm.shared_regions, Eq(0));
+
+	/* The debug name defaults to the kernel's. */
+	ASSERT_TRUE(manifest_vm_get(1, &vm));
+	EXPECT_THAT(vm.debug_name, StrEq("vmlinuz2"));
+	EXPECT_THAT(vm.kernel_filename, StrEq("vmlinuz2"));
+	EXPECT_THAT(vm.mem_size, Eq(0x200000));
+	EXPECT_THAT(vm.vcpu_count, Eq(1));
+	EXPECT_THAT(vm.capabilities, Eq(0x3));
+	EXPECT_THAT(vm.priority, Eq(0));
+	EXPECT_THAT(vm.mem_quota, Eq(0x400000));
+	EXPECT_THAT(vm.uuid, ElementsAre(0, 0, 0, 0));
+	ASSERT_THAT(vm.device_count, Eq(2));
+	EXPECT_THAT(vm.devices[0].base, Eq(0x9040000));
+	EXPECT_THAT(vm.devices[0].size, Eq(0x1000));
+	EXPECT_THAT(vm.devices[0].intid, Eq(0x21));
+	EXPECT_THAT(vm.devices[1].base, Eq(0x9050000));
+	EXPECT_THAT(vm.devices[1].size, Eq(0x2000));
+	EXPECT_THAT(vm.devices[1].intid, Eq(0));
+
+	/* The memory may be split in regions. */
+	ASSERT_TRUE(manifest_vm_get(2, &vm));
+	EXPECT_THAT(vm.kernel_filename, StrEq("vmlinuz3"));
+	EXPECT_THAT(vm.mem_size, Eq(0x300000));
+	ASSERT_THAT(vm.region_count, Eq(2));
+	EXPECT_THAT(vm.regions[0].base, Eq(0));
+	EXPECT_THAT(vm.regions[0].size, Eq(0x100000));
+	EXPECT_THAT(vm.regions[0].mode, Eq(MM_MODE_R | MM_MODE_W | MM_MODE_X));
+	EXPECT_THAT(vm.regions[1].base, Eq(0x100000000));
+	EXPECT_THAT(vm.regions[1].size, Eq(0x200000));
+	EXPECT_THAT(vm.regions[1].mode, Eq(MM_MODE_R | MM_MODE_W));
+	EXPECT_THAT(vm.restart_policy, Eq(MANIFEST_RESTART_ON_ABORT));
+
+	EXPECT_FALSE(manifest_vm_get(3, &vm));
+}
+
+/*
+ * /dts-v1/;
+ *
+ * / {
+ *       #address-cells = <2>;
+ *       #size-cells = <2>;
+ *
+ *       hypervisor {
+ *           vm1 {
+ *               kernel_filename = "vmlinuz1";
+ *               mem_size = <0x100000>;
+ *               vcpu_count = <1>;
+ *           };
+ *           vm2 {
+ *               kernel_filename = "vmlinuz2";
+ *               mem_size = <0x100000>;
+ *               vcpu_count = <1>;
+ *               boot_order = <2>;
+ *               boot_hold;
+ *           };
+ *           vm3 {
+ *               kernel_filename = "vmlinuz3";
+ *               mem_size = <0x100000>;
+ *               vcpu_count = <1>;
+ *               boot_order = <1>;
+ *           };
+ *           vm4 {
+ *               kernel_filename = "vmlinuz4";
+ *               mem_size = <0x100000>;
+ *               vcpu_count = <1>;
+ *               boot_order = <2>;
+ *           };
+ *       };
+ * };
+ *
+ * $ dtc --boot-cpu 0 --in-format dts --out-format dtb --out-version 17 test.dts
+ * | xxd -i
+ */
+
+alignas(8) constexpr uint8_t test_boot_order_dtb[] = {
+	0xd0, 0x0d, 0xfe, 0xed, 0x00, 0x00, 0x02, 0x1c, 0x00, 0x00, 0x00, 0x38,
+	0x00, 0x00, 0x01, 0xc8, 0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x11,
+	0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x54,
+	0x00, 0x00, 0x01, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x39, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x48, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x01, 0x68, 0x79, 0x70, 0x65, 0x72, 0x76, 0x69, 0x73,
+	0x6f, 0x72, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x76, 0x6d, 0x31, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00,
+	0x76, 0x6d, 0x6c, 0x69, 0x6e, 0x75, 0x7a, 0x31, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x10,
+	0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x01, 0x76, 0x6d, 0x32, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x76, 0x6d, 0x6c, 0x69,
+	0x6e, 0x75, 0x7a, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x10, 0x00, 0x10, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x19,
+	0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2f, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x01, 0x76, 0x6d, 0x33, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x76, 0x6d, 0x6c, 0x69,
+	0x6e, 0x75, 0x7a, 0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x10, 0x00, 0x10, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x19,
+	0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x01, 0x76, 0x6d, 0x34, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x76, 0x6d, 0x6c, 0x69,
+	0x6e, 0x75, 0x7a, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x10, 0x00, 0x10, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x19,
+	0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x09,
+	0x6b, 0x65, 0x72, 0x6e, 0x65, 0x6c, 0x5f, 0x66, 0x69, 0x6c, 0x65, 0x6e,
+	0x61, 0x6d, 0x65, 0x00, 0x6d, 0x65, 0x6d, 0x5f, 0x73, 0x69, 0x7a, 0x65,
+	0x00, 0x76, 0x63, 0x70, 0x75, 0x5f, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x00,
+	0x62, 0x6f, 0x6f, 0x74, 0x5f, 0x6f, 0x72, 0x64, 0x65, 0x72, 0x00, 0x62,
+	0x6f, 0x6f, 0x74, 0x5f, 0x68, 0x6f, 0x6c, 0x64, 0x00, 0x23, 0x61, 0x64,
+	0x64, 0x72, 0x65, 0x73, 0x73, 0x2d, 0x63, 0x65, 0x6c, 0x6c, 0x73, 0x00,
+	0x23, 0x73, 0x69, 0x7a, 0x65, 0x2d, 0x63, 0x65, 0x6c, 0x6c, 0x73, 0x00};
+
+TEST(manifest, sorts_vms_by_boot_order)
+{
+	struct fdt_node n;
+	struct manifest_vm vm;
+
+	ASSERT_TRUE(fdt_root_node(
+		&n, reinterpret_cast<const struct fdt_header *>(
+			    test_boot_order_dtb)));
+	ASSERT_TRUE(fdt_find_child(&n, ""));
+	ASSERT_TRUE(manifest_init(&n));
+	ASSERT_THAT(manifest_vm_count(), Eq(4));
+
+	ASSERT_TRUE(manifest_vm_get(0, &vm));
+	EXPECT_THAT(vm.kernel_filename, StrEq("vmlinuz3"));
+	EXPECT_THAT(vm.boot_order, Eq(1));
+	EXPECT_FALSE(vm.boot_hold);
+
+	ASSERT_TRUE(manifest_vm_get(1, &vm));
+	EXPECT_THAT(vm.kernel_filename, StrEq("vmlinuz2"));
+	EXPECT_THAT(vm.boot_order, Eq(2));
+	EXPECT_TRUE(vm.boot_hold);
+
+	ASSERT_TRUE(manifest_vm_get(2, &vm));
+	EXPECT_THAT(vm.kernel_filename, StrEq("vmlinuz4"));
+	EXPECT_THAT(vm.boot_order, Eq(2));
+	EXPECT_FALSE(vm.boot_hold);
+
+	ASSERT_TRUE(manifest_vm_get(3, &vm));
+	EXPECT_THAT(vm.kernel_filename, StrEq("vmlinuz1"));
+	EXPECT_THAT(vm.boot_order, Eq(MANIFEST_UNSET));
+	EXPECT_FALSE(vm.boot_hold);
+}
+
+/*
+ * DTB generated from:
+ *
+ * /dts-v1/;
+ *
+ * / {
+ *       #address-cells = <2>;
+ *       #size-cells = <2>;
+ *
+ *       hypervisor {
+ *           vm1 {
+ *               kernel_filename = "vmlinuz1";
+ *               mem_size = <0x100000>;
+ *               vcpu_count = <1>;
+ *           };
+ *           vm2 {
+ *               kernel_filename = "vmlinuz2";
+ *               mem_size = <0x100000>;
+ *               vcpu_count = <1>;
+ *           };
+ *           shared1 {
+ *               filename = "acpi";
+ *               base = <0x0 0x80000000>;
+ *               vms = "vm1", "vm2";
+ *           };
+ *           shared2 {
+ *               filename = "libc";
+ *               base = <0x0 0x80100000>;
+ *               mode = <0x5>;
+ *               vms = "vm2";
+ *           };
+ *       };
+ * };
+ *
+ * $ dtc --boot-cpu 0 --in-format dts --out-format dtb --out-version 17 test.dts
+ * | xxd -i
+ */
+
+alignas(8) constexpr uint8_t test_shared_dtb[] = {
+	0xd0, 0x0d, 0xfe, 0xed, 0x00, 0x00, 0x01, 0xfe, 0x00, 0x00, 0x00, 0x38,
+	0x00, 0x00, 0x01, 0xa8, 0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x11,
+	0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x56,
+	0x00, 0x00, 0x01, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x3b, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x4a, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x01, 0x68, 0x79, 0x70, 0x65, 0x72, 0x76, 0x69, 0x73,
+	0x6f, 0x72, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x76, 0x6d, 0x31, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00,
+	0x76, 0x6d, 0x6c, 0x69, 0x6e, 0x75, 0x7a, 0x31, 0x00, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x10,
+	0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x01, 0x76, 0x6d, 0x32, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x76, 0x6d, 0x6c, 0x69,
+	0x6e, 0x75, 0x7a, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x10, 0x00, 0x10, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x19,
+	0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01,
+	0x73, 0x68, 0x61, 0x72, 0x65, 0x64, 0x31, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x24, 0x61, 0x63, 0x70, 0x69,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x08,
+	0x00, 0x00, 0x00, 0x2d, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x32,
+	0x76, 0x6d, 0x31, 0x00, 0x76, 0x6d, 0x32, 0x00, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x01, 0x73, 0x68, 0x61, 0x72, 0x65, 0x64, 0x32, 0x00,
+	0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x24,
+	0x6c, 0x69, 0x62, 0x63, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x2d, 0x00, 0x00, 0x00, 0x00,
+	0x80, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04,
+	0x00, 0x00, 0x00, 0x36, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x03,
+	0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x32, 0x76, 0x6d, 0x32, 0x00,
+	0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02,
+	0x00, 0x00, 0x00, 0x09, 0x6b, 0x65, 0x72, 0x6e, 0x65, 0x6c, 0x5f, 0x66,
+	0x69, 0x6c, 0x65, 0x6e, 0x61, 0x6d, 0x65, 0x00, 0x6d, 0x65, 0x6d, 0x5f,
+	0x73, 0x69, 0x7a, 0x65, 0x00, 0x76, 0x63, 0x70, 0x75, 0x5f, 0x63, 0x6f,
+	0x75, 0x6e, 0x74, 0x00, 0x66, 0x69, 0x6c, 0x65, 0x6e, 0x61, 0x6d, 0x65,
+	0x00, 0x62, 0x61, 0x73, 0x65, 0x00, 0x76, 0x6d, 0x73, 0x00, 0x6d, 0x6f,
+	0x64, 0x65, 0x00, 0x23, 0x61, 0x64, 0x64, 0x72, 0x65, 0x73, 0x73, 0x2d,
+	0x63, 0x65, 0x6c, 0x6c, 0x73, 0x00, 0x23, 0x73, 0x69, 0x7a, 0x65, 0x2d,
+	0x63, 0x65, 0x6c, 0x6c, 0x73, 0x00};
+
+TEST(manifest, reads_shared_regions)
+{
+	struct fdt_node n;
+	struct manifest_vm vm;
+	struct manifest_shared shared;
+
+	ASSERT_TRUE(fdt_root_node(
+		&n, reinterpret_cast<const struct fdt_header *>(
+			    test_shared_dtb)));
+	ASSERT_TRUE(fdt_find_child(&n, ""));
+	ASSERT_TRUE(manifest_init(&n));
+	ASSERT_THAT(manifest_vm_count(), Eq(2));
+	ASSERT_THAT(manifest_shared_count(), Eq(2));
+
+	ASSERT_TRUE(manifest_shared_get(0, &shared));
+	EXPECT_THAT(shared.filename, StrEq("acpi"));
+	EXPECT_THAT(shared.base, Eq(0x80000000));
+	EXPECT_THAT(shared.mode, Eq(MM_MODE_R));
+
+	ASSERT_TRUE(manifest_shared_get(1, &shared));
+	EXPECT_THAT(shared.filename, StrEq("libc"));
+	EXPECT_THAT(shared.base, Eq(0x80100000));
+	EXPECT_THAT(shared.mode, Eq(MM_MODE_R | MM_MODE_X));
+
+	EXPECT_FALSE(manifest_shared_get(2, &shared));
+
+	ASSERT_TRUE(manifest_vm_get(0, &vm));
+	EXPECT_THAT(vm.shared_regions, Eq(0x1));
+
+	ASSERT_TRUE(manifest_vm_get(1, &vm));
+	EXPECT_THAT(vm.shared_regions, Eq(0x3));
+}
+
+} /* namespace */
@@ -463,9 +463,10 @@ TEST_F(mm, map_block_replaces_table)
 
 /**
  * Map all memory at the top level, unmapping a page and remapping at a lower
- * level does not result in all memory being mapped at the top level again.
+ * level results in all memory being mapped at the top level again, as the
+ * subtables become equivalent to blocks.
  */
-TEST_F(mm, map_does_not_defrag)
+TEST_F(mm, map_promotes_to_block)
 {
 	constexpr int mode = 0;
 	const paddr_t page_begin = pa_init(12000 * PAGE_SIZE);
@@ -477,14 +478,10 @@ TEST_F(mm, map_does_not_defrag)
 	ASSERT_TRUE(mm_vm_unmap(&ptable, page_begin, page_end, &ppool));
 	ASSERT_TRUE(mm_vm_identity_map(&ptable, page_begin, page_end, mode,
 				       nullptr, &ppool));
-	EXPECT_THAT(get_ptable(ptable),
-		    AllOf(SizeIs(4),
-			  Each(Each(Truly(std::bind(arch_mm_pte_is_present, _1,
-						    TOP_LEVEL)))),
-			  Contains(Contains(Truly(std::bind(
-				  arch_mm_pte_is_block, _1, TOP_LEVEL)))),
-			  Contains(Contains(Truly(std::bind(
-				  arch_mm_pte_is_table, _1, TOP_LEVEL))))));
+	EXPECT_THAT(
+		get_ptable(ptable),
+		AllOf(SizeIs(4), Each(Each(Truly(std::bind(arch_mm_pte_is_block,
+							   _1, TOP_LEVEL))))));
 	mm_vm_fini(&ptable, &ppool);
 }
 
@@ -295,6 +295,50 @@ TEST(mpool, alloc_contiguous)
 		    true);
 }
 
+/**
+ * Allocates aligned runs of pages that were freed one by one.
+ */
+TEST(mpool, alloc_aligned)
+{
+	struct mpool p;
+	constexpr size_t entry_size = PAGE_SIZE;
+	constexpr size_t entries_per_chunk = 16;
+	constexpr size_t chunk_count = 1;
+	std::vector<std::unique_ptr<raw_page[]>> chunks;
+	std::vector<uintptr_t> allocs;
+	void* ret;
+	size_t i;
+
+	mpool_init(&p, entry_size);
+	add_chunks(chunks, &p, chunk_count, entries_per_chunk);
+
+	/* Allocate all the pages one by one and free them back. */
+	while ((ret = mpool_alloc(&p))) {
+		allocs.push_back((uintptr_t)ret);
+	}
+	for (i = 0; i < allocs.size(); i++) {
+		mpool_free(&p, (void*)allocs[i]);
+	}
+	allocs.clear();
+
+	/* The free pages are coalesced to satisfy an aligned run. */
+	ret = mpool_alloc_aligned(&p, 4, 4);
+	ASSERT_THAT(ret, NotNull());
+	EXPECT_THAT((uintptr_t)ret % (4 * entry_size), Eq(0));
+	for (i = 0; i < 4; i++) {
+		allocs.push_back((uintptr_t)ret + i * entry_size);
+	}
+
+	/* Allocate from p until we run out of memory. */
+	while ((ret = mpool_alloc(&p))) {
+		allocs.push_back((uintptr_t)ret);
+	}
+
+	/* Check that returned entries are within chunks that were added. */
+	ASSERT_THAT(check_allocs(chunks, allocs, entries_per_chunk, entry_size),
+		    true);
+}
+
 TEST(mpool, allocation_with_fallback)
 {
 	struct mpool fallback;
@@ -360,4 +404,187 @@ TEST(mpool, free_with_fallback)
 	EXPECT_THAT(mpool_alloc(&fallback), Eq(ret));
 }
 
+/**
+ * Initialises a memory pool with the whole pages of an unaligned byte range.
+ */
+TEST(mpool, init_from_byte_range)
+{
+	struct mpool p;
+	auto pages = std::make_unique<raw_page[]>(4);
+	char* begin = pages[0].data + 1;
+	char* end = pages[3].data + PAGE_SIZE - 1;
+
+	/* Only the two pages in the middle are whole. */
+	EXPECT_THAT(mpool_init_from_byte_range(&p, begin, end),
+		    Eq(2 * PAGE_SIZE));
+	EXPECT_THAT(mpool_alloc(&p), Eq((void*)&pages[1]));
+	EXPECT_THAT(mpool_alloc(&p), Eq((void*)&pages[2]));
+	EXPECT_THAT(mpool_alloc(&p), IsNull());
+
+	/* A range without a whole page adds nothing. */
+	EXPECT_THAT(mpool_init_from_byte_range(&p, begin, begin + PAGE_SIZE),
+		    Eq(0));
+	EXPECT_THAT(mpool_alloc(&p), IsNull());
+}
+
+/**
+ * Only reserved allocations may take the emergency reserve.
+ */
+TEST(mpool, reserve)
+{
+	struct mpool p;
+	constexpr size_t entry_size = PAGE_SIZE;
+	constexpr size_t entries_per_chunk = 4;
+	constexpr size_t chunk_count = 1;
+	std::vector<std::unique_ptr<raw_page[]>> chunks;
+
+	mpool_init(&p, entry_size);
+	add_chunks(chunks, &p, chunk_count, entries_per_chunk);
+	mpool_set_reserve(&p, 2);
+
+	EXPECT_THAT(mpool_alloc(&p), NotNull());
+	EXPECT_THAT(mpool_alloc(&p), NotNull());
+	EXPECT_THAT(mpool_alloc(&p), IsNull());
+	EXPECT_THAT(mpool_alloc_contiguous(&p, 1, 1), IsNull());
+
+	EXPECT_THAT(mpool_alloc_reserved(&p), NotNull());
+	EXPECT_THAT(mpool_alloc_reserved(&p), NotNull());
+	EXPECT_THAT(mpool_alloc_reserved(&p), IsNull());
+}
+
+/**
+ * Freed single pages cached outside the pool count towards the emergency
+ * reserve, so they are not handed out to unreserved allocations either.
+ */
+TEST(mpool, reserve_includes_cached_pages)
+{
+	struct mpool p;
+	constexpr size_t entry_size = PAGE_SIZE;
+	constexpr size_t entries_per_chunk = 4;
+	constexpr size_t chunk_count = 1;
+	std::vector<std::unique_ptr<raw_page[]>> chunks;
+	void* first;
+	void* second;
+
+	mpool_init(&p, entry_size);
+	add_chunks(chunks, &p, chunk_count, entries_per_chunk);
+	mpool_set_reserve(&p, 2);
+
+	EXPECT_THAT(mpool_alloc(&p), NotNull());
+	EXPECT_THAT(mpool_alloc(&p), NotNull());
+	first = mpool_alloc_reserved(&p);
+	ASSERT_THAT(first, NotNull());
+	second = mpool_alloc_reserved(&p);
+	ASSERT_THAT(second, NotNull());
+
+	/* Only the reserve is free again, even though it is cached. */
+	mpool_free(&p, first);
+	mpool_free(&p, second);
+	EXPECT_THAT(mpool_alloc(&p), IsNull());
+
+	EXPECT_THAT(mpool_alloc_reserved(&p), NotNull());
+	EXPECT_THAT(mpool_alloc_reserved(&p), NotNull());
+	EXPECT_THAT(mpool_alloc_reserved(&p), IsNull());
+}
+
+size_t low_memory_calls;
+
+void count_low_memory(struct mpool* p)
+{
+	(void)p;
+	low_memory_calls++;
+}
+
+/**
+ * The low-memory handler runs once when the free pages drop below the
+ * watermark, and again only after the pool is refilled.
+ */
+TEST(mpool, low_memory_handler)
+{
+	struct mpool p;
+	constexpr size_t entry_size = PAGE_SIZE;
+	constexpr size_t entries_per_chunk = 8;
+	constexpr size_t chunk_count = 1;
+	std::vector<std::unique_ptr<raw_page[]>> chunks;
+	void* first;
+	void* second;
+	void* third;
+
+	mpool_init(&p, entry_size);
+	add_chunks(chunks, &p, chunk_count, entries_per_chunk);
+	low_memory_calls = 0;
+	mpool_set_low_memory_handler(&p, entries_per_chunk - 1,
+				     count_low_memory);
+
+	first = mpool_alloc(&p);
+	ASSERT_THAT(first, NotNull());
+	EXPECT_THAT(low_memory_calls, Eq(0));
+
+	second = mpool_alloc(&p);
+	ASSERT_THAT(second, NotNull());
+	EXPECT_THAT(low_memory_calls, Eq(1));
+
+	/* Still below the watermark, so the handler doesn't run again. */
+	third = mpool_alloc(&p);
+	ASSERT_THAT(third, NotNull());
+	EXPECT_THAT(low_memory_calls, Eq(1));
+
+	/*
+	 * Refill up to the watermark and drop below again. Freed single pages
+	 * are cached outside the pool, so refill it with a chunk.
+	 */
+	add_chunks(chunks, &p, 1, 2);
+	EXPECT_THAT(low_memory_calls, Eq(1));
+	EXPECT_THAT(mpool_alloc(&p), NotNull());
+	EXPECT_THAT(low_memory_calls, Eq(2));
+}
+
+/**
+ * Validates the accounting of allocations, frees and failures.
+ */
+TEST(mpool, stats)
+{
+	struct mpool p;
+	struct mpool_stats stats;
+	constexpr size_t entry_size = PAGE_SIZE;
+	constexpr size_t entries_per_chunk = 8;
+	constexpr size_t chunk_count = 1;
+	std::vector<std::unique_ptr<raw_page[]>> chunks;
+	void* ret;
+	void* contiguous;
+
+	mpool_init(&p, entry_size);
+
+	/* Nothing is allocated yet. */
+	mpool_get_stats(&p, &stats);
+	EXPECT_THAT(stats.allocs, Eq(0));
+	EXPECT_THAT(stats.free_pages, Eq(0));
+	EXPECT_THAT(stats.min_free_pages, Eq(SIZE_MAX));
+
+	/* Adding a chunk counts as a free. */
+	add_chunks(chunks, &p, chunk_count, entries_per_chunk);
+	mpool_get_stats(&p, &stats);
+	EXPECT_THAT(stats.frees, Eq(1));
+	EXPECT_THAT(stats.free_pages, Eq(entries_per_chunk));
+
+	ret = mpool_alloc(&p);
+	ASSERT_THAT(ret, NotNull());
+	contiguous = mpool_alloc_contiguous(&p, 4, 1);
+	ASSERT_THAT(contiguous, NotNull());
+	mpool_free(&p, ret);
+
+	/* The low watermark is kept after the free. */
+	mpool_get_stats(&p, &stats);
+	EXPECT_THAT(stats.allocs, Eq(2));
+	EXPECT_THAT(stats.frees, Eq(2));
+	EXPECT_THAT(stats.free_pages, Eq(entries_per_chunk - 4));
+	EXPECT_THAT(stats.min_free_pages, Eq(entries_per_chunk - 5));
+
+	/* Failed allocations are counted. */
+	EXPECT_THAT(mpool_alloc_contiguous(&p, entries_per_chunk, 1), IsNull());
+	mpool_get_stats(&p, &stats);
+	EXPECT_THAT(stats.failures, Eq(1));
+	EXPECT_THAT(stats.allocs, Eq(2));
+}
+
 } /* namespace */
@@ -18,6 +18,7 @@
 #include "hf/dlog.h"
 #include "hf/fdt_handler.h"
 #include "hf/layout.h"
+#include "hf/manifest.h"
 
 /**
  * Default implementation assumes the FDT has been linked into the image.
@@ -83,11 +84,15 @@ bool plat_get_boot_params(struct boot_params *p, struct mpool *ppool)
 		goto out_unmap_fdt;
 	}
 
-	fdt_find_cpus(&n, p->cpu_ids, &p->cpu_count);
+	fdt_find_cpus(&n, p);
 
 	p->mem_ranges_count = 0;
 	fdt_find_memory_ranges(&n, p);
 
+	if (!manifest_init(&n)) {
+		goto out_unmap_fdt;
+	}
+
 	ret = true;
 
 out_unmap_fdt:
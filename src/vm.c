@@ -16,25 +16,36 @@
 
 #include "hf/vm.h"
 
+#include "hf/arch/mm.h"
+
 #include "hf/api.h"
+#include "hf/capability.h"
 #include "hf/cpu.h"
 #include "hf/std.h"
+#include "hf/vmid.h"
 
 #include "vmapi/hf/call.h"
 
 static struct vm vms[MAX_VMS];
-static uint32_t vm_count;
+
+/*
+ * VMs can be created while others run, so the count is only incremented once
+ * the new VM is initialised, and read with acquire semantics to match. Creating
+ * VMs must be serialised by the caller.
+ */
+static _Atomic uint32_t vm_count;
 
 bool vm_init(uint32_t vcpu_count, struct mpool *ppool, struct vm **new_vm)
 {
+	uint32_t id = atomic_load_explicit(&vm_count, memory_order_relaxed);
 	uint32_t i;
 	struct vm *vm;
 
-	if (vm_count >= MAX_VMS) {
+	if (id >= MAX_VMS) {
 		return false;
 	}
 
-	vm = &vms[vm_count];
+	vm = &vms[id];
 
 	memset_s(vm, sizeof(*vm), 0, sizeof(*vm));
 
@@ -42,14 +53,24 @@ bool vm_init(uint32_t vcpu_count, struct mpool *ppool, struct vm **new_vm)
 	list_init(&vm->mailbox.ready_list);
 	sl_init(&vm->lock);
 
-	vm->id = vm_count;
+	vm->id = id;
 	vm->vcpu_count = vcpu_count;
-	vm->mailbox.state = MAILBOX_STATE_EMPTY;
+	mailbox_reset(&vm->mailbox.protocol);
 	atomic_init(&vm->aborting, false);
+	atomic_init(&vm->destroyed, false);
+	atomic_init(&vm->suspended, false);
+	vm->capabilities = HF_CAPABILITY_DEFAULT;
+
+	vm->vmid = vmid_alloc();
+	if (vm->vmid == 0) {
+		return false;
+	}
 
 	if (!mm_vm_init(&vm->ptable, ppool)) {
+		vmid_free(vm->vmid);
 		return false;
 	}
+	mm_vm_set_owner(&vm->ptable, vm->id);
 
 	/* Initialise waiter entries. */
 	for (i = 0; i < MAX_VMS; i++) {
@@ -63,7 +84,7 @@ bool vm_init(uint32_t vcpu_count, struct mpool *ppool, struct vm **new_vm)
 		vcpu_init(vm_get_vcpu(vm, i), vm);
 	}
 
-	++vm_count;
+	atomic_store_explicit(&vm_count, id + 1, memory_order_release);
 	*new_vm = vm;
 
 	return true;
@@ -71,19 +92,95 @@ bool vm_init(uint32_t vcpu_count, struct mpool *ppool, struct vm **new_vm)
 
 uint32_t vm_get_count(void)
 {
-	return vm_count;
+	return atomic_load_explicit(&vm_count, memory_order_acquire);
 }
 
 struct vm *vm_find(spci_vm_id_t id)
 {
 	/* Ensure the VM is initialized. */
-	if (id >= vm_count) {
+	if (id >= vm_get_count()) {
+		return NULL;
+	}
+
+	if (atomic_load_explicit(&vms[id].destroyed, memory_order_relaxed)) {
 		return NULL;
 	}
 
 	return &vms[id];
 }
 
+/**
+ * Checks whether the given VM has the given UUID. The null UUID is not that of
+ * any VM.
+ */
+bool vm_has_uuid(const struct vm *vm, const uint32_t uuid[4])
+{
+	uint32_t i;
+
+	if (uuid[0] == 0 && uuid[1] == 0 && uuid[2] == 0 && uuid[3] == 0) {
+		return false;
+	}
+
+	for (i = 0; i < 4; ++i) {
+		if (vm->uuid[i] != uuid[i]) {
+			return false;
+		}
+	}
+
+	return true;
+}
+
+/**
+ * Returns the VM with the given UUID, or the one with the lowest ID if several
+ * have it, or NULL if none does.
+ */
+struct vm *vm_find_uuid(const uint32_t uuid[4])
+{
+	uint32_t count = vm_get_count();
+	spci_vm_id_t id;
+
+	for (id = 0; id < count; ++id) {
+		struct vm *vm = vm_find(id);
+
+		if (vm != NULL && vm_has_uuid(vm, uuid)) {
+			return vm;
+		}
+	}
+
+	return NULL;
+}
+
+/**
+ * Returns the VM that is assigned the device with the given interrupt ID, or
+ * NULL if no device assigned to a VM has it.
+ */
+struct vm *vm_find_intid_owner(uint32_t intid)
+{
+	uint32_t count = vm_get_count();
+	spci_vm_id_t id;
+	uint32_t i;
+
+	if (intid == 0) {
+		return NULL;
+	}
+
+	for (id = 0; id < count; ++id) {
+		struct vm *vm = vm_find(id);
+
+		if (vm == NULL) {
+			continue;
+		}
+
+		for (i = 0; i < vm->device_count; ++i) {
+			if (vm->devices[i].intid == intid) {
+				return vm;
+			}
+		}
+	}
+
+	return NULL;
+}
+
 /**
  * Locks the given VM and updates `locked` to hold the newly locked vm.
  */
@@ -98,6 +195,17 @@ struct vm_locked vm_lock(struct vm *vm)
 	return locked;
 }
 
+/**
+ * Returns whether the given locked VM has been destroyed. Another CPU can
+ * destroy a VM returned by vm_find() until it is locked, after which its page
+ * table and mailbox must not be used.
+ */
+bool vm_is_destroyed(struct vm_locked locked)
+{
+	return atomic_load_explicit(&locked.vm->destroyed,
+				    memory_order_relaxed);
+}
+
 /**
  * Unlocks a VM previously locked with vm_lock, and updates `locked` to reflect
  * the fact that the VM is no longer locked.
@@ -117,3 +225,178 @@ struct vcpu *vm_get_vcpu(struct vm *vm, uint32_t vcpu_index)
 	assert(vcpu_index < vm->vcpu_count);
 	return &vm->vcpus[vcpu_index];
 }
+
+/**
+ * Gives the mailbox pages of the given VM back to it, with the modes it had
+ * before they were configured, and unmaps them from the hypervisor.
+ */
+static bool vm_mailbox_release(struct vm *vm, struct mpool *ppool)
+{
+	paddr_t pa;
+
+	if (vm->mailbox.send != NULL) {
+		pa = pa_from_va(va_from_ptr(vm->mailbox.send));
+		if (!mm_vm_identity_map(&vm->ptable, pa, pa_add(pa, PAGE_SIZE),
+					vm->mailbox.send_mode, NULL, ppool)) {
+			return false;
+		}
+		mm_unmap(pa, pa_add(pa, PAGE_SIZE), ppool);
+		vm->mailbox.send = NULL;
+	}
+
+	if (vm->mailbox.recv != NULL) {
+		pa = pa_from_va(va_from_ptr(vm->mailbox.recv));
+		if (!mm_vm_identity_map(&vm->ptable, pa, pa_add(pa, PAGE_SIZE),
+					vm->mailbox.recv_mode, NULL, ppool)) {
+			return false;
+		}
+		mm_unmap(pa, pa_add(pa, PAGE_SIZE), ppool);
+		vm->mailbox.recv = NULL;
+	}
+
+	return true;
+}
+
+/**
+ * Frees the resources of the given VM, whose vCPUs must all be stopped. The
+ * memory it owns exclusively, including its mailbox pages, is zeroed and given
+ * back to the primary VM, then its page table and VMID are freed. Both VMs must
+ * be locked.
+ *
+ * On success, vm_find() no longer returns the VM. Its slot is not reused, as
+ * other CPUs may still be looking at it, and vm_is_destroyed() tells them not to
+ * use it once they lock it. On failure, it can be tried again.
+ */
+bool vm_destroy(struct vm_locked locked, struct vm_locked primary,
+		struct mpool *ppool)
+{
+	struct vm *vm = locked.vm;
+
+	/* Give the mailbox pages back to the VM so they are reclaimed too. */
+	if (!vm_mailbox_release(vm, ppool)) {
+		return false;
+	}
+
+	/* Assigned devices are given back to the primary VM too. */
+	if (!mm_vm_reclaim(&vm->ptable, &primary.vm->ptable, ppool)) {
+		return false;
+	}
+
+	atomic_store_explicit(&vm->destroyed, true, memory_order_relaxed);
+	mm_vm_fini(&vm->ptable, ppool);
+	vmid_free(vm->vmid);
+
+	return true;
+}
+
+/**
+ * Resets the given aborted VM, whose vCPUs must all be stopped, to how it was
+ * loaded: its mailbox is unconfigured and emptied, the memory of its regions is
+ * zeroed and mapped with the modes it was loaded with, the kernel is copied
+ * back to its first region and the interrupts of its vCPUs are cleared. Memory
+ * donated to it is given back to the primary VM, and its devices stay assigned
+ * to it. Both VMs must be locked.
+ *
+ * Memory the VM lent or borrowed is left as it is, so the VMs it was shared
+ * with should reclaim it.
+ */
+bool vm_restart(struct vm_locked locked, struct vm_locked primary,
+		struct mpool *ppool)
+{
+	struct vm *vm = locked.vm;
+	struct vm_restart *restart = &vm->restart;
+	size_t image_size =
+		pa_difference(restart->image_begin, restart->image_end);
+	const void *image;
+	void *to;
+	uint32_t i;
+
+	if (!vm_mailbox_release(vm, ppool)) {
+		return false;
+	}
+	mailbox_reset(&vm->mailbox.protocol);
+	vm->mailbox.deferred = false;
+
+	for (i = 0; i < restart->region_count; ++i) {
+		if (!mm_vm_unmap_scrub(&vm->ptable, restart->regions[i].begin,
+				       restart->regions[i].end, ppool)) {
+			return false;
+		}
+	}
+
+	if (!mm_vm_reclaim(&vm->ptable, &primary.vm->ptable, ppool)) {
+		return false;
+	}
+
+	for (i = 0; i < restart->region_count; ++i) {
+		if (!mm_vm_identity_map(&vm->ptable, restart->regions[i].begin,
+					restart->regions[i].end,
+					restart->regions[i].mode, NULL,
+					ppool)) {
+			return false;
+		}
+	}
+
+	/* The devices assigned to the VM were given to the primary VM too. */
+	for (i = 0; i < vm->device_count; ++i) {
+		if (!mm_vm_unmap(&primary.vm->ptable, vm->devices[i].begin,
+				 vm->devices[i].end, ppool) ||
+		    !mm_vm_identity_map(&vm->ptable, vm->devices[i].begin,
+					vm->devices[i].end,
+					MM_MODE_R | MM_MODE_W | MM_MODE_D,
+					NULL, ppool)) {
+			return false;
+		}
+	}
+
+	image = mm_identity_map(restart->image_begin, restart->image_end,
+				MM_MODE_R, ppool);
+	if (image == NULL) {
+		return false;
+	}
+
+	to = mm_identity_map(restart->regions[0].begin,
+			     pa_add(restart->regions[0].begin, image_size),
+			     MM_MODE_W, ppool);
+	if (to == NULL) {
+		mm_unmap(restart->image_begin, restart->image_end, ppool);
+		return false;
+	}
+
+	memcpy_s(to, image_size, image, image_size);
+	arch_mm_write_back_dcache(to, image_size);
+	mm_unmap(restart->regions[0].begin,
+		 pa_add(restart->regions[0].begin, image_size), ppool);
+	mm_unmap(restart->image_begin, restart->image_end, ppool);
+
+	for (i = 0; i < vm->vcpu_count; ++i) {
+		struct vcpu_locked vcpu = vcpu_lock(&vm->vcpus[i]);
+
+		memset_s(&vcpu.vcpu->interrupts, sizeof(vcpu.vcpu->interrupts),
+			 0, sizeof(vcpu.vcpu->interrupts));
+		vcpu_unlock(&vcpu);
+	}
+
+	++restart->count;
+
+	return true;
+}
+
+/**
+ * Checks whether `size` more bytes of memory can be mapped into the given VM
+ * without exceeding its quota. The memory currently mapped into it, including
+ * memory shared with or lent to it, is counted from its page table.
+ */
+bool vm_quota_allows(struct vm_locked locked, uint64_t size)
+{
+	struct vm *vm = locked.vm;
+	uint64_t mapped;
+
+	if (vm->mem_quota == 0) {
+		return true;
+	}
+
+	mapped = (uint64_t)mm_vm_mapped_pages(&vm->ptable) * PAGE_SIZE;
+
+	return mapped <= vm->mem_quota && size <= vm->mem_quota - mapped;
+}
@@ -279,7 +279,7 @@ uintptr_t hftest_get_cpu_id(size_t index)
 	if (!fdt_find_child(&n, "")) {
 		FAIL("Unable to find FDT root node.");
 	}
-	fdt_find_cpus(&n, params.cpu_ids, &params.cpu_count);
+	fdt_find_cpus(&n, &params);
 
 	return params.cpu_ids[index];
 }
@@ -165,3 +165,37 @@ TEST(busy_secondary, physical_timer)
 	EXPECT_EQ(io_read32_array(GICD_ISACTIVER, 0), 0);
 	EXPECT_EQ(io_read32(GICR_ISACTIVER0), 0);
 }
+
+/**
+ * A secondary that never yields is preempted once its time slice is used up,
+ * without the primary setting any timer.
+ */
+TEST(busy_secondary, time_slice)
+{
+	const char message[] = "loop";
+	struct hf_vcpu_run_return run_res;
+
+	/* Hafnium needs the hypervisor timer IRQ to preempt the secondary. */
+	interrupt_enable(HYPERVISOR_TIMER_IRQ, true);
+	interrupt_set_priority(HYPERVISOR_TIMER_IRQ, 0x80);
+	interrupt_set_edge_triggered(HYPERVISOR_TIMER_IRQ, true);
+	interrupt_set_priority_mask(0xff);
+	arch_irq_enable();
+
+	/* Let the secondary get started and wait for our message. */
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+
+	/* Let secondary start looping. */
+	memcpy_s(send_buffer->payload, SPCI_MSG_PAYLOAD_MAX, message,
+		 sizeof(message));
+	spci_message_init(send_buffer, 0, SERVICE_VM0,
+			  recv_buffer->target_vm_id);
+	EXPECT_EQ(spci_msg_send(0), 0);
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_PREEMPTED);
+
+	/* It is preempted again each time it runs. */
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_PREEMPTED);
+}
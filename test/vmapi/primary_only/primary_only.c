@@ -18,12 +18,20 @@
 
 #include "hf/arch/vm/power_mgmt.h"
 
+#include "hf/mm.h"
 #include "hf/spinlock.h"
+#include "hf/std.h"
 
 #include "vmapi/hf/call.h"
 
 #include "hftest.h"
 
+static alignas(PAGE_SIZE) uint8_t send_page[PAGE_SIZE];
+static alignas(PAGE_SIZE) uint8_t recv_page[PAGE_SIZE];
+static alignas(PAGE_SIZE) uint8_t vm_memory[PAGE_SIZE];
+static alignas(PAGE_SIZE) uint8_t other_send_page[PAGE_SIZE];
+static alignas(PAGE_SIZE) uint8_t other_recv_page[PAGE_SIZE];
+
 /**
  * Confirms the primary VM has the primary ID.
  */
@@ -137,3 +145,202 @@ TEST(spci, spci_version)
 
 	EXPECT_EQ(spci_version(), current_version);
 }
+
+/** Ensures that the Hafnium API version is reported whatever is requested. */
+TEST(hf_api_version, reports_current_version)
+{
+	EXPECT_EQ(hf_api_version(HF_API_VERSION), HF_API_VERSION);
+	EXPECT_EQ(hf_api_version(0), HF_API_VERSION);
+	EXPECT_EQ(hf_api_version((HF_API_VERSION_MAJOR + 1)
+				 << HF_API_VERSION_MAJOR_OFFSET),
+		  HF_API_VERSION);
+}
+
+/** Ensures that VMs can write to the hypervisor's log. */
+TEST(hf_dlog, write_lines)
+{
+	EXPECT_EQ(hf_dlog("A line written a few characters at a time\n"), 0);
+	EXPECT_EQ(hf_dlog("A line "), 0);
+	EXPECT_EQ(hf_dlog("written in parts\n"), 0);
+}
+
+/** Ensures that the primary VM can read the lines a VM wrote to its log. */
+TEST(hf_vm_log_drain, reads_lines_once)
+{
+	const char expected[] = "First line\nSecond line\n";
+
+	EXPECT_EQ(hf_dlog("First line\nSecond "), 0);
+
+	/* The lines are written to the RX buffer, which must be configured. */
+	EXPECT_EQ(hf_vm_log_drain(HF_PRIMARY_VM_ID), -1);
+	EXPECT_EQ(hf_vm_configure((hf_ipaddr_t)send_page,
+				  (hf_ipaddr_t)recv_page),
+		  0);
+
+	/* Only whole lines are kept. */
+	EXPECT_EQ(hf_dlog("line\n"), 0);
+	EXPECT_EQ(hf_vm_log_drain(HF_PRIMARY_VM_ID), sizeof(expected) - 1);
+	EXPECT_EQ(memcmp(recv_page, expected, sizeof(expected) - 1), 0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+
+	/* The lines are drained. */
+	EXPECT_EQ(hf_vm_log_drain(HF_PRIMARY_VM_ID), 0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+	EXPECT_EQ(hf_vm_log_drain(0xffff), -1);
+}
+
+/** Ensures that the primary VM can read the hypervisor's own log. */
+TEST(hf_dlog_drain, reads_hypervisor_log)
+{
+	const char expected[] = "VM 0: Logged by the primary\n";
+	int64_t ret;
+
+	/* The log is written to the RX buffer, which must be configured. */
+	EXPECT_EQ(hf_dlog_drain(), -1);
+	EXPECT_EQ(hf_vm_configure((hf_ipaddr_t)send_page,
+				  (hf_ipaddr_t)recv_page),
+		  0);
+
+	/* The hypervisor has logged its boot. */
+	EXPECT_GT(hf_dlog_drain(), 0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+
+	/* The lines VMs write are logged by the hypervisor after a timestamp. */
+	EXPECT_EQ(hf_dlog("Logged by the primary\n"), 0);
+	ret = hf_dlog_drain();
+	ASSERT_GT(ret, sizeof(expected) - 1);
+	EXPECT_EQ(recv_page[0], '[');
+	EXPECT_EQ(memcmp(recv_page + ret - (sizeof(expected) - 1), expected,
+			 sizeof(expected) - 1),
+		  0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+
+	/* The log is drained. */
+	EXPECT_EQ(hf_dlog_drain(), 0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+}
+
+/** Ensures that a VM cannot be created without a valid description. */
+TEST(hf_vm_create, fails_with_invalid_description)
+{
+	struct hf_vm_create_desc *desc = (struct hf_vm_create_desc *)send_page;
+
+	/* The description is in the send buffer, which must be configured. */
+	EXPECT_EQ(hf_vm_create(), -1);
+	EXPECT_EQ(hf_vm_configure((hf_ipaddr_t)send_page,
+				  (hf_ipaddr_t)recv_page),
+		  0);
+
+	*desc = (struct hf_vm_create_desc){
+		.mem_begin = (uint64_t)vm_memory,
+		.mem_size = sizeof(vm_memory),
+		.entry = (uint64_t)vm_memory,
+		.vcpu_count = 0,
+	};
+	EXPECT_EQ(hf_vm_create(), -1);
+
+	/* The entry point must be in the memory of the VM. */
+	desc->vcpu_count = 1;
+	desc->entry = (uint64_t)vm_memory + sizeof(vm_memory);
+	EXPECT_EQ(hf_vm_create(), -1);
+
+	/* The memory must be page-aligned. */
+	desc->entry = (uint64_t)vm_memory;
+	desc->mem_size = sizeof(vm_memory) - 1;
+	EXPECT_EQ(hf_vm_create(), -1);
+
+	EXPECT_EQ(hf_vm_get_count(), 1);
+}
+
+/**
+ * Ensures that destroying a VM gives its memory back to the primary VM, zeroed,
+ * and that the VM can't be found afterwards.
+ */
+TEST(hf_vm_destroy, gives_memory_back)
+{
+	struct hf_vm_create_desc *desc = (struct hf_vm_create_desc *)send_page;
+	int64_t id;
+
+	EXPECT_EQ(hf_vm_destroy(1), -1);
+	EXPECT_EQ(hf_vm_configure((hf_ipaddr_t)send_page,
+				  (hf_ipaddr_t)recv_page),
+		  0);
+
+	vm_memory[0] = 'a';
+	*desc = (struct hf_vm_create_desc){
+		.mem_begin = (uint64_t)vm_memory,
+		.mem_size = sizeof(vm_memory),
+		.entry = (uint64_t)vm_memory,
+		.vcpu_count = 1,
+	};
+	id = hf_vm_create();
+	EXPECT_EQ(id, 1);
+
+	/* The primary VM can't be destroyed. */
+	EXPECT_EQ(hf_vm_destroy(HF_PRIMARY_VM_ID), -1);
+
+	EXPECT_EQ(hf_vm_destroy(id), 0);
+	EXPECT_EQ(hf_vm_destroy(id), -1);
+	EXPECT_EQ(hf_vcpu_get_count(id), -1);
+	EXPECT_EQ(vm_memory[0], 0);
+}
+
+/**
+ * Ensures that the send and receive pages can be unmapped and other pages
+ * configured in their place.
+ */
+TEST(hf_vm_unconfigure, allows_other_pages_to_be_configured)
+{
+	EXPECT_EQ(hf_vm_unconfigure(), -1);
+	EXPECT_EQ(hf_vm_configure((hf_ipaddr_t)send_page,
+				  (hf_ipaddr_t)recv_page),
+		  0);
+
+	/* The pages can only be configured once until they are unmapped. */
+	EXPECT_EQ(hf_vm_configure((hf_ipaddr_t)other_send_page,
+				  (hf_ipaddr_t)other_recv_page),
+		  -1);
+	EXPECT_EQ(hf_vm_unconfigure(), 0);
+	EXPECT_EQ(hf_vm_unconfigure(), -1);
+
+	/* The VM has its original access to the pages again. */
+	send_page[0] = 'a';
+	recv_page[0] = 'b';
+	EXPECT_EQ(send_page[0], 'a');
+	EXPECT_EQ(recv_page[0], 'b');
+
+	EXPECT_EQ(hf_vm_configure((hf_ipaddr_t)other_send_page,
+				  (hf_ipaddr_t)other_recv_page),
+		  0);
+}
+
+/**
+ * Ensures that the primary VM gets an entry of the CPU topology for each
+ * physical CPU, on which its vCPU with the same index runs.
+ */
+TEST(hf_cpu_topology_get, reports_each_cpu)
+{
+	const struct hf_cpu_topology *topology =
+		(const struct hf_cpu_topology *)recv_page;
+	int64_t count;
+	int64_t i;
+
+	/* The topology is written to the RX buffer, which must be configured. */
+	EXPECT_EQ(hf_cpu_topology_get(), -1);
+	EXPECT_EQ(hf_vm_configure((hf_ipaddr_t)send_page,
+				  (hf_ipaddr_t)recv_page),
+		  0);
+
+	count = hf_cpu_topology_get();
+	EXPECT_GT(count, 0);
+	EXPECT_LE(count, hf_vcpu_get_count(HF_PRIMARY_VM_ID));
+	for (i = 0; i < count; ++i) {
+		EXPECT_LT(topology[i].cluster, count);
+		EXPECT_LT(topology[i].core, count);
+	}
+
+	/* The RX buffer is in use until the mailbox is cleared. */
+	EXPECT_EQ(hf_cpu_topology_get(), -1);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+	EXPECT_EQ(hf_cpu_topology_get(), count);
+}
@@ -94,6 +94,82 @@ TEST(interrupts, inject_interrupt_twice)
 	EXPECT_EQ(hf_mailbox_clear(), 0);
 }
 
+/**
+ * Inject an interrupt to the interrupt VM, which queues its vCPU to be run on
+ * this CPU, and make sure it leaves the run queue once it is run.
+ */
+TEST(interrupts, inject_interrupt_queues_vcpu)
+{
+	const char expected_response[] = "Got IRQ 07.";
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+
+	SERVICE_SELECT(SERVICE_VM0, "interruptible", mb.send);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+	EXPECT_EQ(run_res.sleep.ns, HF_SLEEP_INDEFINITE);
+	EXPECT_EQ(hf_run_queue_pop(), -1);
+
+	/* The woken up vCPU is handed out once. */
+	EXPECT_EQ(hf_interrupt_inject(SERVICE_VM0, 0, EXTERNAL_INTERRUPT_ID_A),
+		  1);
+	EXPECT_EQ(hf_run_queue_pop(), (int64_t)SERVICE_VM0 << 16);
+	EXPECT_EQ(hf_run_queue_pop(), -1);
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(memcmp(mb.recv->payload, expected_response,
+			 sizeof(expected_response)),
+		  0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+
+	/* Running the vCPU takes it off the run queue. */
+	EXPECT_EQ(hf_interrupt_inject(SERVICE_VM0, 0, EXTERNAL_INTERRUPT_ID_A),
+		  1);
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+	EXPECT_EQ(hf_run_queue_pop(), -1);
+}
+
+/**
+ * Inject an interrupt to the interrupt VM while it is suspended, and make sure
+ * it is only taken once the VM is resumed.
+ */
+TEST(interrupts, inject_interrupt_while_suspended)
+{
+	const char expected_response[] = "Got IRQ 07.";
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+
+	SERVICE_SELECT(SERVICE_VM0, "interruptible", mb.send);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+	EXPECT_EQ(run_res.sleep.ns, HF_SLEEP_INDEFINITE);
+
+	EXPECT_EQ(hf_vm_suspend(SERVICE_VM0), 0);
+	EXPECT_EQ(hf_vm_suspend(SERVICE_VM0), -1);
+
+	/* The interrupt is kept pending but the vCPU is not run. */
+	EXPECT_EQ(hf_interrupt_inject(SERVICE_VM0, 0, EXTERNAL_INTERRUPT_ID_A),
+		  0);
+	EXPECT_EQ(hf_run_queue_pop(), -1);
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_INTERRUPT);
+
+	/* Resuming queues the vCPU to take the interrupt. */
+	EXPECT_EQ(hf_vm_resume(SERVICE_VM0), 1);
+	EXPECT_EQ(hf_vm_resume(SERVICE_VM0), -1);
+	EXPECT_EQ(hf_run_queue_pop(), (int64_t)SERVICE_VM0 << 16);
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(memcmp(mb.recv->payload, expected_response,
+			 sizeof(expected_response)),
+		  0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+}
+
 /**
  * Inject two different interrupts to the interrupt VM, which will send a
  * message back each time.
@@ -302,3 +378,59 @@ TEST(interrupts, deliver_interrupt_and_message)
 	EXPECT_EQ(memcmp(mb.recv->payload, message, sizeof(message)), 0);
 	EXPECT_EQ(hf_mailbox_clear(), 0);
 }
+
+/**
+ * Signal notifications to a secondary VM, which takes them when it is
+ * interrupted and signals them back to the primary VM.
+ */
+TEST(interrupts, notification_round_trip)
+{
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+
+	SERVICE_SELECT(SERVICE_VM0, "notification_forward", mb.send);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+
+	/* The secondary VM is blocked so needs to be woken up. */
+	EXPECT_EQ(hf_notification_set(SERVICE_VM0, 0x5), 1);
+
+	/* Bits already pending are not delivered again. */
+	EXPECT_EQ(hf_notification_set(SERVICE_VM0, 0x4), 0);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_NOTIFICATION);
+	EXPECT_EQ(run_res.notification.vm_id, SERVICE_VM0);
+	EXPECT_EQ(hf_notification_get(), 0x5);
+	EXPECT_EQ(hf_notification_get(), 0);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+}
+
+/**
+ * Notifications cannot be signalled to the caller itself, to a VM that does not
+ * exist, or without any bits.
+ */
+TEST(interrupts, notification_set_invalid)
+{
+	EXPECT_EQ(hf_notification_set(HF_PRIMARY_VM_ID, 0x1), -1);
+	EXPECT_EQ(hf_notification_set(SERVICE_VM0, 0), -1);
+	EXPECT_EQ(hf_notification_set(0xffff, 0x1), -1);
+}
+
+/**
+ * A secondary VM cannot inject interrupts into other VMs unless it is given
+ * the capability to.
+ */
+TEST(interrupts, secondary_cannot_inject_into_other_vm)
+{
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+
+	SERVICE_SELECT(SERVICE_VM0, "inject_other_vm", mb.send);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_YIELD);
+}
@@ -100,6 +100,115 @@ TEST(mailbox, echo)
 	EXPECT_EQ(hf_mailbox_clear(), 0);
 }
 
+/**
+ * A broadcast message is delivered to all the VMs waiting for one.
+ */
+TEST(mailbox, broadcast)
+{
+	const char message[] = "Echo this back to me!";
+	const int64_t recipients = (INT64_C(1) << SERVICE_VM0) |
+				   (INT64_C(1) << SERVICE_VM1);
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+
+	SERVICE_SELECT(SERVICE_VM0, "echo", mb.send);
+	SERVICE_SELECT(SERVICE_VM1, "echo", mb.send);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+	run_res = hf_vcpu_run(SERVICE_VM1, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+
+	memcpy_s(mb.send->payload, SPCI_MSG_PAYLOAD_MAX, message,
+		 sizeof(message));
+	spci_message_init(mb.send, sizeof(message), HF_INVALID_VM_ID,
+			  HF_PRIMARY_VM_ID);
+	EXPECT_EQ(hf_mailbox_broadcast() & recipients, recipients);
+
+	/* Both VMs echo the message back. */
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(mb.recv->source_vm_id, SERVICE_VM0);
+	EXPECT_EQ(memcmp(mb.recv->payload, message, sizeof(message)), 0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+
+	run_res = hf_vcpu_run(SERVICE_VM1, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(mb.recv->source_vm_id, SERVICE_VM1);
+	EXPECT_EQ(memcmp(mb.recv->payload, message, sizeof(message)), 0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+}
+
+/**
+ * Only the primary VM may broadcast.
+ */
+TEST(mailbox, broadcast_secondary_fails)
+{
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+
+	SERVICE_SELECT(SERVICE_VM0, "broadcast_fails", mb.send);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_YIELD);
+}
+
+/**
+ * A VM can poll for a message without receiving it, and then receive it.
+ */
+TEST(mailbox, peek)
+{
+	const char message[] = "Peek at this!";
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+
+	SERVICE_SELECT(SERVICE_VM0, "peek_echo", mb.send);
+
+	/* There is no message yet, so the VM yields. */
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_YIELD);
+
+	memcpy_s(mb.send->payload, SPCI_MSG_PAYLOAD_MAX, message,
+		 sizeof(message));
+	spci_message_init(mb.send, sizeof(message), SERVICE_VM0,
+			  HF_PRIMARY_VM_ID);
+	EXPECT_EQ(spci_msg_send(0), 0);
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(mb.recv->length, sizeof(message));
+	EXPECT_EQ(memcmp(mb.recv->payload, message, sizeof(message)), 0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+}
+
+/**
+ * A receive with a timeout asks to be woken when it times out, and returns
+ * SPCI_TIMEDOUT when no message arrived in time.
+ */
+TEST(mailbox, receive_timeout)
+{
+	const char expected_response[] = "Timed out";
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+
+	SERVICE_SELECT(SERVICE_VM0, "receive_timeout", mb.send);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+	EXPECT_NE(run_res.sleep.ns, HF_SLEEP_INDEFINITE);
+
+	/* Keep running the VM until the receive times out. */
+	while (run_res.code == HF_VCPU_RUN_WAIT_FOR_MESSAGE) {
+		run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	}
+
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(mb.recv->length, sizeof(expected_response));
+	EXPECT_EQ(memcmp(mb.recv->payload, expected_response,
+			 sizeof(expected_response)),
+		  0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+}
+
 /**
  * Repeatedly send a message and receive it back from the echo VM.
  */
@@ -345,3 +454,102 @@ TEST(mailbox, secondary_to_primary_notification)
 	/* Send should now succeed. */
 	EXPECT_EQ(spci_msg_send(0), SPCI_SUCCESS);
 }
+
+/**
+ * While a message is being sent in fragments, the recipient's mailbox only
+ * accepts fragments from the same sender until the last one is sent.
+ */
+TEST(mailbox, fragments_reserve_mailbox)
+{
+	const char first[] = "Hello, ";
+	const char last[] = "World!";
+	const char relayed[] = "Interloper";
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+	spci_vm_id_t *chain;
+
+	SERVICE_SELECT(SERVICE_VM0, "echo", mb.send);
+	SERVICE_SELECT(SERVICE_VM1, "relay", mb.send);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+	run_res = hf_vcpu_run(SERVICE_VM1, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+
+	/* Send the first fragment, which is echoed back. */
+	memcpy_s(mb.send->payload, SPCI_MSG_PAYLOAD_MAX, first, sizeof(first));
+	spci_message_init(mb.send, sizeof(first), SERVICE_VM0,
+			  HF_PRIMARY_VM_ID);
+	spci_message_set_more_fragments(mb.send, true);
+	EXPECT_EQ(spci_msg_send(0), SPCI_SUCCESS);
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(memcmp(mb.recv->payload, first, sizeof(first)), 0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+
+	/* SERVICE_VM1 cannot send to SERVICE_VM0 in the middle of the message. */
+	chain = (spci_vm_id_t *)mb.send->payload;
+	*chain++ = htole32(SERVICE_VM0);
+	memcpy_s(chain, SPCI_MSG_PAYLOAD_MAX - sizeof(spci_vm_id_t), relayed,
+		 sizeof(relayed));
+	spci_message_init(mb.send, sizeof(relayed) + sizeof(spci_vm_id_t),
+			  SERVICE_VM1, HF_PRIMARY_VM_ID);
+	EXPECT_EQ(spci_msg_send(0), SPCI_SUCCESS);
+	run_res = hf_vcpu_run(SERVICE_VM1, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+
+	/* Send the last fragment, which is echoed back. */
+	memcpy_s(mb.send->payload, SPCI_MSG_PAYLOAD_MAX, last, sizeof(last));
+	spci_message_init(mb.send, sizeof(last), SERVICE_VM0,
+			  HF_PRIMARY_VM_ID);
+	EXPECT_EQ(spci_msg_send(0), SPCI_SUCCESS);
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(memcmp(mb.recv->payload, last, sizeof(last)), 0);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+
+	/* Now SERVICE_VM1 can send to SERVICE_VM0. */
+	chain = (spci_vm_id_t *)mb.send->payload;
+	*chain++ = htole32(SERVICE_VM0);
+	memcpy_s(chain, SPCI_MSG_PAYLOAD_MAX - sizeof(spci_vm_id_t), relayed,
+		 sizeof(relayed));
+	spci_message_init(mb.send, sizeof(relayed) + sizeof(spci_vm_id_t),
+			  SERVICE_VM1, HF_PRIMARY_VM_ID);
+	EXPECT_EQ(spci_msg_send(0), SPCI_SUCCESS);
+	run_res = hf_vcpu_run(SERVICE_VM1, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+	EXPECT_EQ(run_res.message.vm_id, SERVICE_VM0);
+}
+
+/**
+ * The primary VM can get the state of a secondary VM.
+ */
+TEST(hf_vm_stats_get, reports_blocked_vcpu)
+{
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+	const struct hf_vm_stats *stats = (const struct hf_vm_stats *)mb.recv;
+
+	SERVICE_SELECT(SERVICE_VM0, "echo", mb.send);
+
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+
+	EXPECT_EQ(hf_vm_stats_get(SERVICE_VM0), 0);
+	EXPECT_EQ(stats->vcpu_count, 1);
+	EXPECT_EQ(stats->vcpu_states[0], HF_VCPU_STATE_BLOCKED_MAILBOX);
+	EXPECT_EQ(stats->mailbox_state, HF_MAILBOX_STATE_EMPTY);
+	EXPECT_GT(stats->mapped_pages, 0);
+	EXPECT_GT(stats->page_table_pages, 0);
+	EXPECT_EQ(stats->fault_count, 0);
+
+	/* The vCPU blocked on a hypercall after running for a while. */
+	EXPECT_GT(stats->vcpu_stats[0].hypercall_exits, 0);
+	EXPECT_GT(stats->vcpu_stats[0].run_ns, 0);
+
+	/* The RX buffer is in use until the mailbox is cleared. */
+	EXPECT_EQ(hf_vm_stats_get(SERVICE_VM0), -1);
+	EXPECT_EQ(hf_mailbox_clear(), 0);
+
+	EXPECT_EQ(hf_vm_stats_get(0xffff), -1);
+}
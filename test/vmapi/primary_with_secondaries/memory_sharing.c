@@ -238,6 +238,60 @@ TEST(memory_sharing, lend_and_get_back)
 	EXPECT_EQ(run_res.code, HF_VCPU_RUN_ABORTED);
 }
 
+/**
+ * Memory that has been lent can be reclaimed once it is relinquished, and it is
+ * cleared.
+ */
+TEST(memory_sharing, lend_relinquish_and_reclaim)
+{
+	struct hf_vcpu_run_return run_res;
+	struct mailbox_buffers mb = set_up_mailbox();
+	uint8_t *ptr = page;
+
+	SERVICE_SELECT(SERVICE_VM0, "memory_relinquish", mb.send);
+
+	ASSERT_EQ(hf_share_memory(SERVICE_VM0, (hf_ipaddr_t)&page, PAGE_SIZE,
+				  HF_MEMORY_LEND),
+		  0);
+
+	/* The memory can't be reclaimed while it is still borrowed. */
+	EXPECT_EQ(hf_memory_reclaim(SERVICE_VM0, (hf_ipaddr_t)&page, PAGE_SIZE),
+		  -1);
+
+	memcpy_s(mb.send->payload, SPCI_MSG_PAYLOAD_MAX, &ptr, sizeof(ptr));
+	spci_message_init(mb.send, sizeof(ptr), SERVICE_VM0, HF_PRIMARY_VM_ID);
+	EXPECT_EQ(spci_msg_send(0), SPCI_SUCCESS);
+
+	/* Let the memory be relinquished. */
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_MESSAGE);
+
+	/* Only the owner can reclaim the memory, and only once. */
+	EXPECT_EQ(hf_memory_reclaim(SERVICE_VM1, (hf_ipaddr_t)&page, PAGE_SIZE),
+		  -1);
+	ASSERT_EQ(hf_memory_reclaim(SERVICE_VM0, (hf_ipaddr_t)&page, PAGE_SIZE),
+		  0);
+	EXPECT_EQ(hf_memory_reclaim(SERVICE_VM0, (hf_ipaddr_t)&page, PAGE_SIZE),
+		  -1);
+	for (int i = 0; i < PAGE_SIZE; ++i) {
+		ASSERT_EQ(ptr[i], 0);
+	}
+
+	/* Observe the service faulting when accessing the memory. */
+	run_res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(run_res.code, HF_VCPU_RUN_ABORTED);
+}
+
+/**
+ * Memory can't be relinquished by a VM that doesn't borrow it.
+ */
+TEST(memory_sharing, cannot_relinquish_owned_memory)
+{
+	EXPECT_EQ(hf_memory_relinquish(SERVICE_VM0, (hf_ipaddr_t)&page,
+				       PAGE_SIZE),
+		  -1);
+}
+
 /**
  * After memory has been returned, it is free to be shared again.
  */
@@ -97,6 +97,35 @@ TEST(hf_vcpu_run, cannot_run_absent_vcpu)
 	EXPECT_EQ(res.sleep.ns, HF_SLEEP_INDEFINITE);
 }
 
+/**
+ * A vcpu can't be run on a physical CPU outside its affinity.
+ */
+TEST(hf_vcpu_run, cannot_run_outside_affinity)
+{
+	struct hf_vcpu_run_return res;
+
+	/* The test runs on the first physical CPU. */
+	EXPECT_EQ(hf_vcpu_affinity_set(SERVICE_VM0, 0, ~UINT64_C(1)), 0);
+	res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(res.code, HF_VCPU_RUN_WRONG_CPU);
+
+	EXPECT_EQ(hf_vcpu_affinity_set(SERVICE_VM0, 0, UINT64_C(1)), 0);
+	res = hf_vcpu_run(SERVICE_VM0, 0);
+	EXPECT_EQ(res.code, HF_VCPU_RUN_WAIT_FOR_MESSAGE);
+}
+
+/**
+ * The affinity can only be set for a vcpu of a secondary VM that exists, and
+ * must allow it to run somewhere.
+ */
+TEST(hf_vcpu_affinity_set, fails_with_invalid_arguments)
+{
+	EXPECT_EQ(hf_vcpu_affinity_set(HF_PRIMARY_VM_ID, 0, 1), -1);
+	EXPECT_EQ(hf_vcpu_affinity_set(1234, 0, 1), -1);
+	EXPECT_EQ(hf_vcpu_affinity_set(SERVICE_VM0, 1234, 1), -1);
+	EXPECT_EQ(hf_vcpu_affinity_set(SERVICE_VM0, 0, 0), -1);
+}
+
 /**
  * The configured send/receive addresses can't be unaligned.
  */
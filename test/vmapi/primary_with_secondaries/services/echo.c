@@ -39,3 +39,38 @@ TEST_SERVICE(echo)
 		spci_msg_send(0);
 	}
 }
+
+TEST_SERVICE(peek_echo)
+{
+	struct spci_message *send_buf = SERVICE_SEND_BUFFER();
+	struct spci_message *recv_buf = SERVICE_RECV_BUFFER();
+	int64_t peeked;
+
+	/* Poll for a message, yielding while there is none. */
+	while ((peeked = hf_mailbox_peek()) == -1) {
+		spci_yield();
+	}
+
+	/* Peeking leaves the message to be received. */
+	EXPECT_EQ(hf_mailbox_peek(), peeked);
+	EXPECT_EQ(spci_msg_recv(SPCI_MSG_RECV_BLOCK), SPCI_SUCCESS);
+	EXPECT_EQ(peeked & 0xffffffff, recv_buf->length);
+	EXPECT_EQ(peeked >> 32, recv_buf->source_vm_id);
+	EXPECT_EQ(hf_mailbox_peek(), -1);
+
+	memcpy_s(send_buf->payload, SPCI_MSG_PAYLOAD_MAX, recv_buf->payload,
+		 recv_buf->length);
+	spci_message_init(send_buf, recv_buf->length, recv_buf->source_vm_id,
+			  recv_buf->target_vm_id);
+
+	hf_mailbox_clear();
+	spci_msg_send(0);
+}
+
+TEST_SERVICE(broadcast_fails)
+{
+	spci_message_init(SERVICE_SEND_BUFFER(), 0, HF_INVALID_VM_ID,
+			  hf_vm_get_id());
+	EXPECT_EQ(hf_mailbox_broadcast(), -1);
+	spci_yield();
+}
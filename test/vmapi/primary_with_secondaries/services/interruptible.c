@@ -98,3 +98,37 @@ TEST_SERVICE(interruptible)
 		hf_mailbox_clear();
 	}
 }
+
+/**
+ * Takes the pending notifications and signals them back to the primary VM.
+ */
+static void notification_irq(void)
+{
+	uint32_t interrupt_id = hf_interrupt_get();
+
+	ASSERT_EQ(interrupt_id, HF_NOTIFICATION_INTID);
+	hf_notification_set(HF_PRIMARY_VM_ID, hf_notification_get());
+}
+
+TEST_SERVICE(notification_forward)
+{
+	exception_setup(notification_irq);
+	hf_interrupt_enable(HF_NOTIFICATION_INTID, true);
+	arch_irq_enable();
+
+	for (;;) {
+		mailbox_receive_retry();
+		FAIL("Unexpected message");
+	}
+}
+
+/*
+ * Tries to inject an interrupt into another VM, which it is not allowed to
+ * without the capability, and yields.
+ */
+TEST_SERVICE(inject_other_vm)
+{
+	EXPECT_EQ(hf_interrupt_inject(SERVICE_VM1, 0, EXTERNAL_INTERRUPT_ID_A),
+		  -1);
+	spci_yield();
+}
@@ -88,6 +88,39 @@ TEST_SERVICE(memory_return)
 	}
 }
 
+TEST_SERVICE(memory_relinquish)
+{
+	/* Loop, relinquishing memory borrowed from the sender. */
+	for (;;) {
+		spci_msg_recv(SPCI_MSG_RECV_BLOCK);
+		uint8_t *ptr;
+
+		/* Check the memory was cleared. */
+		struct spci_message *recv_buf = SERVICE_RECV_BUFFER();
+		ptr = *(uint8_t **)recv_buf->payload;
+		spci_message_init(SERVICE_SEND_BUFFER(), sizeof(ptr),
+				  recv_buf->source_vm_id, hf_vm_get_id());
+
+		for (int i = 0; i < PAGE_SIZE; ++i) {
+			ASSERT_EQ(ptr[i], 0);
+		}
+
+		/* Dirty the memory, relinquish it and notify the sender. */
+		memset_s(ptr, PAGE_SIZE, 'd', PAGE_SIZE);
+		ASSERT_EQ(hf_memory_relinquish(recv_buf->source_vm_id,
+					       (hf_ipaddr_t)ptr, PAGE_SIZE),
+			  0);
+		hf_mailbox_clear();
+		spci_msg_send(0);
+
+		/*
+		 * Try and access the memory which will cause a fault unless the
+		 * memory has been shared again.
+		 */
+		ptr[0] = 123;
+	}
+}
+
 TEST_SERVICE(give_memory_and_fault)
 {
 	uint8_t *ptr = page;